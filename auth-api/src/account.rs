@@ -0,0 +1,338 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::users::{avatar_path, AVATAR_SIZES};
+
+use crate::{authenticate, json_error, password, webhooks, AppState};
+
+/// How long a deleted account's row is kept before it is removed.
+const HARD_DELETE_AFTER_DAYS: i32 = 30;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// What anonymized messages read as; tombstones stay empty.
+const ERASED_CONTENT: &str = "[deleted]";
+
+#[derive(Deserialize)]
+struct DeleteAccountReq {
+    password: String,
+}
+
+/// DELETE /users/me
+///
+/// Soft-deletes the caller's account, once they confirm it with their
+/// password, and erases what it left behind: their messages lose their
+/// author and content, and their prekey bundle, E2EE backup, memberships,
+/// reactions, read markers, refresh tokens, uploads, avatar and data
+/// exports are removed, the stored objects by channels-api shortly after.
+/// `/login` is refused from now on, and the gateway is told to refuse
+/// access tokens already issued; channels-api checks for itself.
+pub async fn handle_delete_me(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(user_id) = authenticate(&state, &req).and_then(|claims| claims.sub.parse::<UserId>().ok()) else {
+        return Ok(json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "unauthorized"));
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let confirm: DeleteAccountReq = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+    };
+    if confirm.password.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "password required"));
+    }
+    match password::check(&state.db, &user_id, &confirm.password, true).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(json_error(StatusCode::FORBIDDEN, ErrorCode::Forbidden, "wrong password")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to check password");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+
+    match erase(&state.db, &user_id).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "no such user")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to delete account");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+    state.public_info.remove(user_id.as_str());
+    if let Some(gateway) = &state.gateway {
+        gateway.user_deleted(&user_id).await;
+    }
+    tracing::info!(user_id = %user_id, "account deleted");
+    state.webhooks.notify(&state.db, webhooks::Event::Deleted, &user_id);
+
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap())
+}
+
+/// Marks the account deleted and erases its data in one transaction,
+/// queueing its objects in file storage for channels-api to delete.
+/// `false` when there is no such account or it is already deleted.
+async fn erase(pool: &PgPool, user_id: &UserId) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let avatar: Option<Option<String>> = sqlx::query_scalar(
+        "UPDATE users
+         SET deleted_at = now(), display_name = NULL, bio = NULL, email = NULL, avatar_version = NULL,
+             password_hash = NULL
+         FROM (SELECT avatar_version FROM users WHERE id = $1 FOR UPDATE) old
+         WHERE id = $1 AND deleted_at IS NULL
+         RETURNING old.avatar_version",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(avatar) = avatar else {
+        return Ok(false);
+    };
+
+    // Earlier versions of edited messages hold content too.
+    sqlx::query("DELETE FROM message_edits WHERE message_id IN (SELECT id FROM messages WHERE sender_id = $1)")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "UPDATE messages
+         SET sender_id = $2, encrypted = false,
+             content = CASE WHEN deleted_at IS NULL THEN $3 END
+         WHERE sender_id = $1",
+    )
+    .bind(user_id)
+    .bind(UserId::deleted())
+    .bind(ERASED_CONTENT)
+    .execute(&mut *tx)
+    .await?;
+
+    for table in [
+        "user_keys",
+        "e2ee_backups",
+        "channel_members",
+        "message_reactions",
+        "channel_read_markers",
+        "refresh_tokens",
+        "push_tokens",
+    ] {
+        sqlx::query(&format!("DELETE FROM {} WHERE user_id = $1", table))
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let mut objects: Vec<String> = sqlx::query_scalar("DELETE FROM file_uploads WHERE uploader_id = $1 RETURNING storage_path")
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    let exports: Vec<Option<String>> = sqlx::query_scalar("DELETE FROM data_exports WHERE user_id = $1 RETURNING storage_path")
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    objects.extend(exports.into_iter().flatten());
+    if let Some(version) = avatar {
+        objects.extend(AVATAR_SIZES.iter().map(|&size| avatar_path(user_id, &version, size)));
+    }
+    sqlx::query("INSERT INTO storage_deletions (path) SELECT unnest($1::text[]) ON CONFLICT DO NOTHING")
+        .bind(&objects)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Removes the rows of accounts deleted more than 30 days ago, returning
+/// how many went.
+pub async fn purge_deleted(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM users WHERE deleted_at < now() - make_interval(days => $1)")
+        .bind(HARD_DELETE_AFTER_DAYS)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Runs `purge_deleted` hourly for as long as the service is up.
+pub fn spawn_purge(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match purge_deleted(&pool).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!(accounts = n, "purged deleted accounts"),
+                Err(e) => tracing::warn!(error = %e, "deleted account purge failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, test_state};
+    use hyper::Method;
+    use serde_json::json;
+    use uchat_proto::ids::{ChannelId, MessageId};
+    use uchat_proto::jwt::create_token;
+
+    async fn delete_me(state: &Arc<AppState>, caller: &UserId, body: &str) -> StatusCode {
+        let token = create_token(&state.jwt_secret, caller.as_str());
+        let req = Request::builder()
+            .method(Method::DELETE)
+            .uri("/users/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        crate::handle_request(state.clone(), req).await.unwrap().status()
+    }
+
+    async fn count(pool: &PgPool, query: &str, user_id: &UserId) -> i64 {
+        sqlx::query_scalar(query).bind(user_id).fetch_one(pool).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn erases_the_account_and_refuses_login() {
+        let Some(state) = test_state().await else { return };
+        let name = format!("user-{}", UserId::new());
+        let user_id = db::get_or_create_user(&state.db, &name).await.unwrap().0;
+        sqlx::query(
+            "UPDATE users SET display_name = 'Ada', bio = 'Counts things.', email = 'ada@example.com', avatar_version = 'v1',
+                              password_hash = $2
+             WHERE id = $1",
+        )
+        .bind(&user_id)
+        .bind(password::hash("x"))
+        .execute(&state.db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO user_keys (user_id, identity_key, signed_prekey, signed_prekey_sig) VALUES ($1, 'i', 's', 'g')")
+            .bind(&user_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO e2ee_backups (user_id, blob, version) VALUES ($1, '\\x00', 1)")
+            .bind(&user_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO push_tokens (token, user_id, platform) VALUES ($1, $2, 'fcm')")
+            .bind(format!("device-{}", user_id))
+            .bind(&user_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let (kept, tombstone) = (MessageId::new(), MessageId::new());
+        sqlx::query(
+            "INSERT INTO messages (id, channel_id, sender_id, content, deleted_at)
+             VALUES ($1, $3, $4, 'secret', NULL), ($2, $3, $4, NULL, now())",
+        )
+        .bind(kept.as_str())
+        .bind(tombstone.as_str())
+        .bind(ChannelId::new().as_str())
+        .bind(&user_id)
+        .execute(&state.db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO message_edits (message_id, content) VALUES ($1, 'older secret')")
+            .bind(kept.as_str())
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let upload = format!("c/{}", MessageId::new());
+        sqlx::query(
+            "INSERT INTO file_uploads (id, channel_id, uploader_id, filename, mime_type, size_bytes, checksum, storage_backend, storage_path)
+             VALUES ($1, 'c', $2, 'a.txt', 'text/plain', 1, 'x', 'local', $3)",
+        )
+        .bind(MessageId::new().as_str())
+        .bind(&user_id)
+        .bind(&upload)
+        .execute(&state.db)
+        .await
+        .unwrap();
+        let archive = format!("exports/{}.zip", MessageId::new());
+        sqlx::query("INSERT INTO data_exports (id, user_id, status, storage_path) VALUES ($1, $2, 'ready', $3)")
+            .bind(MessageId::new().as_str())
+            .bind(&user_id)
+            .bind(&archive)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let req = Request::post("/login").body(Body::from(json!({ "username": name, "password": "x" }).to_string()));
+        assert_eq!(crate::handle_request(state.clone(), req.unwrap()).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(delete_me(&state, &user_id, "{}").await, StatusCode::BAD_REQUEST);
+        assert_eq!(delete_me(&state, &user_id, r#"{"password":""}"#).await, StatusCode::BAD_REQUEST);
+        assert_eq!(delete_me(&state, &user_id, r#"{"password":"y"}"#).await, StatusCode::FORBIDDEN);
+        assert_eq!(delete_me(&state, &user_id, r#"{"password":"x"}"#).await, StatusCode::NO_CONTENT);
+        assert_eq!(delete_me(&state, &user_id, r#"{"password":"x"}"#).await, StatusCode::NOT_FOUND);
+
+        let rows: Vec<(String, String, Option<String>)> =
+            sqlx::query_as("SELECT id, sender_id, content FROM messages WHERE id = ANY($1) ORDER BY id = $2 DESC")
+                .bind(vec![kept.as_str(), tombstone.as_str()])
+                .bind(kept.as_str())
+                .fetch_all(&state.db)
+                .await
+                .unwrap();
+        let deleted = UserId::deleted().to_string();
+        assert_eq!(rows[0], (kept.to_string(), deleted.clone(), Some(ERASED_CONTENT.into())));
+        assert_eq!(rows[1], (tombstone.to_string(), deleted, None));
+        let edits: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_edits WHERE message_id = $1")
+            .bind(kept.as_str())
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(edits, 0);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM user_keys WHERE user_id = $1", &user_id).await, 0);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM e2ee_backups WHERE user_id = $1", &user_id).await, 0);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM push_tokens WHERE user_id = $1", &user_id).await, 0);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM file_uploads WHERE uploader_id = $1", &user_id).await, 0);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM data_exports WHERE user_id = $1", &user_id).await, 0);
+        let queued: Vec<String> = sqlx::query_scalar("SELECT path FROM storage_deletions WHERE path = ANY($1) ORDER BY path")
+            .bind(vec![
+                upload.clone(),
+                archive.clone(),
+                avatar_path(&user_id, "v1", 64),
+                avatar_path(&user_id, "v1", 256),
+            ])
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(queued.len(), 4, "{:?}", queued);
+        assert_eq!(
+            count(&state.db, "SELECT COUNT(*) FROM users WHERE id = $1 AND deleted_at IS NOT NULL AND bio IS NULL AND email IS NULL", &user_id).await,
+            1
+        );
+
+        let req = Request::post("/login").body(Body::from(json!({ "username": name, "password": "x" }).to_string()));
+        let resp = crate::handle_request(state.clone(), req.unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = crate::handle_request(state, Request::get(format!("/users/{}/public-info", user_id)).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn purges_rows_after_thirty_days() {
+        let Some(state) = test_state().await else { return };
+        let old = db::get_or_create_user(&state.db, &format!("user-{}", UserId::new())).await.unwrap().0;
+        let recent = db::get_or_create_user(&state.db, &format!("user-{}", UserId::new())).await.unwrap().0;
+        for (id, days) in [(&old, 31), (&recent, 29)] {
+            sqlx::query("UPDATE users SET deleted_at = now() - make_interval(days => $2) WHERE id = $1")
+                .bind(id)
+                .bind(days)
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+
+        assert!(purge_deleted(&state.db).await.unwrap() >= 1);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM users WHERE id = $1", &old).await, 0);
+        assert_eq!(count(&state.db, "SELECT COUNT(*) FROM users WHERE id = $1", &recent).await, 1);
+    }
+}
@@ -0,0 +1,460 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::users::{AdminUser, UserRole};
+
+use crate::{authenticate, db, json_error, json_ok, json_response, AppState};
+
+#[derive(Deserialize, Default)]
+struct SuspendReq {
+    /// Omit for an indefinite suspension.
+    #[serde(default)]
+    until: Option<DateTime<Utc>>,
+    /// Moderator's reason, kept in `admin_notes`.
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateUserReq {
+    username: String,
+    #[serde(default)]
+    role: UserRole,
+}
+
+#[derive(Deserialize)]
+struct SetRoleReq {
+    role: UserRole,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct SuspensionStatus {
+    user_id: UserId,
+    suspended: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suspended_until: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    admin_notes: Option<String>,
+}
+
+/// Checks that the caller is an admin (`users.is_admin`), or returns the
+/// error response to send.
+pub async fn require_admin(state: &AppState, req: &Request<Body>) -> Result<UserId, Response<Body>> {
+    let caller = authenticate(state, req)
+        .and_then(|claims| claims.sub.parse::<UserId>().ok())
+        .ok_or_else(|| json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "unauthorized"))?;
+
+    match db::is_admin(&state.db, &caller).await {
+        Ok(true) => {}
+        Ok(false) => return Err(json_error(StatusCode::FORBIDDEN, ErrorCode::Forbidden, "forbidden")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to check admin flag");
+            return Err(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+    Ok(caller)
+}
+
+/// `require_admin`, then parses the target user id.
+async fn authorize(state: &AppState, req: &Request<Body>, user_id: &str) -> Result<UserId, Response<Body>> {
+    require_admin(state, req).await?;
+    user_id
+        .parse()
+        .map_err(|_| json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid user id"))
+}
+
+/// POST /admin/users/{user_id}/suspend
+pub async fn handle_suspend(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let target = match authorize(&state, &req, user_id).await {
+        Ok(target) => target,
+        Err(resp) => return Ok(resp),
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let suspend: SuspendReq = if body.is_empty() {
+        SuspendReq::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(v) => v,
+            Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+        }
+    };
+
+    let result = sqlx::query(
+        "UPDATE users SET suspended = true, suspended_until = $2, admin_notes = COALESCE($3, admin_notes)
+         WHERE id = $1",
+    )
+    .bind(&target)
+    .bind(suspend.until)
+    .bind(&suspend.notes)
+    .execute(&state.db)
+    .await;
+
+    respond(&state, result, target).await
+}
+
+/// POST /admin/users/{user_id}/unsuspend
+///
+/// Clears the suspension; `admin_notes` is kept as a record.
+pub async fn handle_unsuspend(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let target = match authorize(&state, &req, user_id).await {
+        Ok(target) => target,
+        Err(resp) => return Ok(resp),
+    };
+
+    let result = sqlx::query("UPDATE users SET suspended = false, suspended_until = NULL WHERE id = $1")
+        .bind(&target)
+        .execute(&state.db)
+        .await;
+
+    respond(&state, result, target).await
+}
+
+async fn respond(
+    state: &AppState,
+    result: Result<sqlx::postgres::PgQueryResult, sqlx::Error>,
+    user_id: UserId,
+) -> Result<Response<Body>, hyper::Error> {
+    match result {
+        Ok(r) if r.rows_affected() == 0 => return Ok(json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "no such user")),
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!(error = %e, "failed to update suspension");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+
+    let status: Result<SuspensionStatus, _> = sqlx::query_as(
+        "SELECT id AS user_id, suspended, suspended_until, admin_notes FROM users WHERE id = $1",
+    )
+    .bind(&user_id)
+    .fetch_one(&state.db)
+    .await;
+
+    match status {
+        Ok(status) => Ok(json_ok(serde_json::to_string(&status).unwrap())),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to read suspension");
+            Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"))
+        }
+    }
+}
+
+fn database_error(e: sqlx::Error) -> Response<Body> {
+    tracing::error!(error = %e, "admin query failed");
+    json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error")
+}
+
+fn user_response(user: Option<AdminUser>) -> Response<Body> {
+    match user {
+        Some(user) => json_ok(serde_json::to_string(&user).unwrap()),
+        None => json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "no such user"),
+    }
+}
+
+/// GET /admin/users
+pub async fn handle_list_users(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if let Err(resp) = require_admin(&state, &req).await {
+        return Ok(resp);
+    }
+    Ok(match uchat_db::admin::list_users(&state.db).await {
+        Ok(users) => json_ok(serde_json::to_string(&users).unwrap()),
+        Err(e) => database_error(e),
+    })
+}
+
+/// POST /admin/users
+///
+/// Registers `{"username", "role"?}` as a provisioned account, answering
+/// it with the one-time `activation_token` its owner sets a password with
+/// at `POST /activate`; 409 when the name is taken.
+pub async fn handle_create_user(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if let Err(resp) = require_admin(&state, &req).await {
+        return Ok(resp);
+    }
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let create: CreateUserReq = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+    };
+    if create.username.trim().is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "username required"));
+    }
+
+    Ok(match uchat_db::admin::create_user(&state.db, &create.username, create.role).await {
+        Ok(Some(user)) => json_response(StatusCode::CREATED, serde_json::to_string(&user).unwrap()),
+        Ok(None) => json_error(StatusCode::CONFLICT, ErrorCode::Conflict, "username taken"),
+        Err(e) => database_error(e),
+    })
+}
+
+/// GET /admin/users/{user_id}
+pub async fn handle_get_user(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let target = match authorize(&state, &req, user_id).await {
+        Ok(target) => target,
+        Err(resp) => return Ok(resp),
+    };
+    Ok(match uchat_db::admin::get_user(&state.db, &target).await {
+        Ok(user) => user_response(user),
+        Err(e) => database_error(e),
+    })
+}
+
+/// PUT /admin/users/{user_id}/role
+///
+/// Sets `{"role": "user" | "admin" | "compliance"}`.
+pub async fn handle_set_role(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let target = match authorize(&state, &req, user_id).await {
+        Ok(target) => target,
+        Err(resp) => return Ok(resp),
+    };
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let set: SetRoleReq = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+    };
+
+    Ok(match uchat_db::admin::set_role(&state.db, &target, set.role).await {
+        Ok(user) => user_response(user),
+        Err(e) => database_error(e),
+    })
+}
+
+/// DELETE /admin/users/{user_id}/refresh-tokens
+///
+/// Signs the user out everywhere: none of their refresh tokens can be
+/// traded in again. Answers `{"revoked": n}`.
+pub async fn handle_revoke_tokens(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let target = match authorize(&state, &req, user_id).await {
+        Ok(target) => target,
+        Err(resp) => return Ok(resp),
+    };
+    Ok(match uchat_db::admin::revoke_refresh_tokens(&state.db, &target).await {
+        Ok(revoked) => json_ok(serde_json::json!({ "revoked": revoked }).to_string()),
+        Err(e) => database_error(e),
+    })
+}
+
+/// POST /admin/users/{user_id}/activation
+///
+/// A new `activation_token` for an account without a password, replacing
+/// any earlier one; 409 when the account has a password or is deleted.
+pub async fn handle_reissue_activation(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let target = match authorize(&state, &req, user_id).await {
+        Ok(target) => target,
+        Err(resp) => return Ok(resp),
+    };
+    match uchat_db::admin::reissue_activation(&state.db, &target).await {
+        Ok(Some(user)) => return Ok(json_ok(serde_json::to_string(&user).unwrap())),
+        Ok(None) => {}
+        Err(e) => return Ok(database_error(e)),
+    }
+    Ok(match uchat_db::admin::get_user(&state.db, &target).await {
+        Ok(Some(_)) => json_error(StatusCode::CONFLICT, ErrorCode::Conflict, "account has a password or is deleted"),
+        Ok(None) => json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "no such user"),
+        Err(e) => database_error(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_state;
+    use hyper::Method;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use uchat_proto::jwt::create_token;
+
+    async fn user(pool: &PgPool, admin: bool) -> UserId {
+        let id = db::get_or_create_user(pool, &format!("user-{}", UserId::new())).await.unwrap().0;
+        sqlx::query("UPDATE users SET is_admin = $2, password_hash = $3 WHERE id = $1")
+            .bind(&id)
+            .bind(admin)
+            .bind(crate::password::hash("x"))
+            .execute(pool)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn username(pool: &PgPool, id: &UserId) -> String {
+        sqlx::query_scalar("SELECT username FROM users WHERE id = $1")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn post(state: &Arc<AppState>, path: &str, caller: Option<&UserId>, body: Value) -> (StatusCode, Value) {
+        send(state, Method::POST, path, caller, body).await
+    }
+
+    async fn send(
+        state: &Arc<AppState>,
+        method: Method,
+        path: &str,
+        caller: Option<&UserId>,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut builder = Request::builder().method(method).uri(path);
+        if let Some(caller) = caller {
+            let token = create_token(&state.jwt_secret, caller.as_str());
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        let req = builder.body(Body::from(body.to_string())).unwrap();
+
+        let resp = crate::handle_request(state.clone(), req).await.unwrap();
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    async fn login(state: &Arc<AppState>, name: &str) -> (StatusCode, Value) {
+        post(state, "/login", None, json!({ "username": name, "password": "x" })).await
+    }
+
+    #[tokio::test]
+    async fn only_admins_suspend() {
+        let Some(state) = test_state().await else { return };
+        let target = user(&state.db, false).await;
+        let path = format!("/admin/users/{}/suspend", target);
+
+        let (status, _) = post(&state, &path, None, json!({})).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let someone = user(&state.db, false).await;
+        let (status, _) = post(&state, &path, Some(&someone), json!({})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let admin = user(&state.db, true).await;
+        let (status, _) = post(&state, &format!("/admin/users/{}/suspend", UserId::new()), Some(&admin), json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn suspension_blocks_login_until_lifted() {
+        let Some(state) = test_state().await else { return };
+        let admin = user(&state.db, true).await;
+        let target = user(&state.db, false).await;
+        let name = username(&state.db, &target).await;
+
+        let (status, body) = post(
+            &state,
+            &format!("/admin/users/{}/suspend", target),
+            Some(&admin),
+            json!({ "notes": "spam" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["suspended"], true);
+        assert_eq!(body["admin_notes"], "spam");
+
+        let (status, body) = login(&state, &name).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["Error"]["code"], "account_suspended");
+        assert!(body["Error"].get("until").is_none());
+
+        let (status, body) = post(&state, &format!("/admin/users/{}/unsuspend", target), Some(&admin), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["suspended"], false);
+        assert_eq!(body["admin_notes"], "spam");
+
+        let (status, body) = login(&state, &name).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["LoginOk"]["token"].is_string());
+    }
+
+    #[tokio::test]
+    async fn timed_suspension_expires() {
+        let Some(state) = test_state().await else { return };
+        let admin = user(&state.db, true).await;
+        let target = user(&state.db, false).await;
+        let name = username(&state.db, &target).await;
+        let path = format!("/admin/users/{}/suspend", target);
+
+        let until = Utc::now() + chrono::Duration::hours(1);
+        post(&state, &path, Some(&admin), json!({ "until": until })).await;
+        let (status, body) = login(&state, &name).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let reported: DateTime<Utc> = body["Error"]["until"].as_str().unwrap().parse().unwrap();
+        assert_eq!(reported.timestamp(), until.timestamp());
+
+        let past = Utc::now() - chrono::Duration::hours(1);
+        post(&state, &path, Some(&admin), json!({ "until": past })).await;
+        let (status, _) = login(&state, &name).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admins_manage_users_and_their_sessions() {
+        let Some(state) = test_state().await else { return };
+        let admin = user(&state.db, true).await;
+        let someone = user(&state.db, false).await;
+        let (status, _) = send(&state, Method::GET, "/admin/users", Some(&someone), Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let name = format!("user-{}", UserId::new());
+        let (status, created) =
+            post(&state, "/admin/users", Some(&admin), json!({ "username": name, "role": "compliance" })).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(created["role"], "compliance");
+        let (status, _) = post(&state, "/admin/users", Some(&admin), json!({ "username": name })).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let id = created["id"].as_str().unwrap();
+        let (status, users) = send(&state, Method::GET, "/admin/users", Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(users.as_array().unwrap().iter().any(|u| u["id"] == id));
+        let path = format!("/admin/users/{}/role", id);
+        let (status, updated) = send(&state, Method::PUT, &path, Some(&admin), json!({ "role": "admin" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated["role"], "admin");
+
+        let (status, _) = login(&state, &name).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let token = created["activation_token"].clone();
+        let (status, _) = post(&state, "/activate", None, json!({ "token": token, "password": "x" })).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = post(&state, "/activate", None, json!({ "token": token, "password": "y" })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post(&state, &format!("/admin/users/{}/activation", id), Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (_, body) = login(&state, &name).await;
+        let refresh_token = body["LoginOk"]["refresh_token"].clone();
+        let path = format!("/admin/users/{}/refresh-tokens", id);
+        let (status, body) = send(&state, Method::DELETE, &path, Some(&admin), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["revoked"], 1);
+        let (status, _) = post(&state, "/refresh", None, json!({ "refresh_token": refresh_token })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,111 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use uchat_proto::ids::UserId;
+use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+/// Looks up the id for `username`, registering the user on first login,
+/// and whether this call registered them.
+pub async fn get_or_create_user(pool: &PgPool, username: &str) -> Result<(UserId, bool), sqlx::Error> {
+    uchat_metrics::time_db_query("get_or_create_user", async {
+        let inserted = sqlx::query("INSERT INTO users (id, username) VALUES ($1, $2) ON CONFLICT (username) DO NOTHING")
+            .bind(UserId::new())
+            .bind(username)
+            .execute(pool)
+            .await?;
+
+        let id = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_one(pool)
+            .await?;
+        Ok((id, inserted.rows_affected() == 1))
+    })
+    .await
+}
+
+/// Builds the room permissions embedded in a user's token from their
+/// `channel_members` rows. Channel admins get write access to the room.
+pub async fn load_room_permissions(
+    pool: &PgPool,
+    user_id: &UserId,
+) -> Result<RoomPermissions, sqlx::Error> {
+    uchat_metrics::time_db_query("load_room_permissions", async {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT channel_id, role FROM channel_members WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+
+        let mut perms = RoomPermissions::new();
+        for (channel_id, role) in rows {
+            match role.as_str() {
+                "read" => perms.grant(&channel_id, RoomRole::Read),
+                "write" | "admin" => perms.grant(&channel_id, RoomRole::Write),
+                _ => {}
+            }
+        }
+
+        Ok(perms)
+    })
+    .await
+}
+
+/// Why a login is refused: the user is suspended, until the given time or
+/// indefinitely. Suspensions whose `suspended_until` has passed no longer
+/// count.
+pub async fn active_suspension(
+    pool: &PgPool,
+    user_id: &UserId,
+) -> Result<Option<Option<DateTime<Utc>>>, sqlx::Error> {
+    uchat_metrics::time_db_query("active_suspension", async {
+        let row: Option<(bool, Option<DateTime<Utc>>)> =
+            sqlx::query_as("SELECT suspended, suspended_until FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?;
+
+        Ok(match row {
+            Some((true, None)) => Some(None),
+            Some((true, Some(until))) if until > Utc::now() => Some(Some(until)),
+            _ => None,
+        })
+    })
+    .await
+}
+
+/// Whether the account was deleted and is waiting to be purged.
+pub async fn is_deleted(pool: &PgPool, user_id: &UserId) -> Result<bool, sqlx::Error> {
+    uchat_metrics::time_db_query("is_deleted", async {
+        let deleted: Option<bool> = sqlx::query_scalar("SELECT deleted_at IS NOT NULL FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(deleted.unwrap_or(false))
+    })
+    .await
+}
+
+pub async fn is_admin(pool: &PgPool, user_id: &UserId) -> Result<bool, sqlx::Error> {
+    uchat_metrics::time_db_query("is_admin", async {
+        let admin: Option<bool> = sqlx::query_scalar("SELECT is_admin FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(admin.unwrap_or(false))
+    })
+    .await
+}
+
+/// Whether the user is an admin and when they registered, as their access
+/// tokens carry.
+pub async fn token_profile(pool: &PgPool, user_id: &UserId) -> Result<(bool, DateTime<Utc>), sqlx::Error> {
+    uchat_metrics::time_db_query("token_profile", async {
+        sqlx::query_as("SELECT is_admin, created_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+    })
+    .await
+}
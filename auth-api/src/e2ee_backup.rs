@@ -0,0 +1,188 @@
+//! Server-side copies of users' E2EE session stores, so a second device
+//! can pick up their sessions. Clients encrypt the store with a
+//! passphrase before uploading; the server keeps the bytes as they are.
+
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_TYPE, ETAG, IF_MATCH};
+use hyper::{Body, Request, Response, StatusCode};
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+
+use crate::{authenticate, json_error, AppState};
+
+/// Largest blob accepted.
+const MAX_BACKUP_BYTES: usize = 1024 * 1024;
+
+fn caller(state: &AppState, req: &Request<Body>) -> Option<UserId> {
+    authenticate(state, req).and_then(|claims| claims.sub.parse().ok())
+}
+
+/// GET /users/me/e2ee-backup
+///
+/// The caller's latest blob as `application/octet-stream`, with its
+/// version as the `ETag`; 404 before the first upload.
+pub async fn handle_get(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(user_id) = caller(&state, &req) else {
+        return Ok(json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "unauthorized"));
+    };
+
+    let row: Result<Option<(Vec<u8>, i64)>, sqlx::Error> = uchat_metrics::time_db_query("get_e2ee_backup", async {
+        sqlx::query_as("SELECT blob, version FROM e2ee_backups WHERE user_id = $1")
+            .bind(&user_id)
+            .fetch_optional(&state.db)
+            .await
+    })
+    .await;
+    match row {
+        Ok(Some((blob, version))) => Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(ETAG, format!("\"{}\"", version))
+            .body(Body::from(blob))
+            .unwrap()),
+        Ok(None) => Ok(json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "no backup")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load e2ee backup");
+            Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"))
+        }
+    }
+}
+
+/// PUT /users/me/e2ee-backup
+///
+/// Replaces the caller's blob with the request body, up to 1 MiB. With
+/// `If-Match: "<version>"` the upload is refused with 412 unless that is
+/// still the stored version, so a device can't overwrite sessions another
+/// one uploaded since it last synced. Answers 204 with the new `ETag`.
+pub async fn handle_put(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(user_id) = caller(&state, &req) else {
+        return Ok(json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "unauthorized"));
+    };
+    let expected = match req.headers().get(IF_MATCH).map(|v| v.to_str().ok().and_then(parse_etag)) {
+        None => None,
+        Some(Some(version)) => Some(version),
+        Some(None) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid If-Match")),
+    };
+
+    let mut body = req.into_body();
+    let mut blob = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if blob.len() + chunk.len() > MAX_BACKUP_BYTES {
+            return Ok(json_error(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, "backup too large"));
+        }
+        blob.extend_from_slice(&chunk);
+    }
+    if blob.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "empty backup"));
+    }
+
+    match store(&state.db, &user_id, &blob, expected).await {
+        Ok(Some(version)) => {
+            tracing::info!(user_id = %user_id, version, bytes = blob.len(), "e2ee backup stored");
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(ETAG, format!("\"{}\"", version))
+                .body(Body::empty())
+                .unwrap())
+        }
+        Ok(None) => Ok(json_error(StatusCode::PRECONDITION_FAILED, ErrorCode::Conflict, "backup changed since If-Match")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to store e2ee backup");
+            Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"))
+        }
+    }
+}
+
+/// `"3"` as 3; weak and wildcard tags aren't versions.
+fn parse_etag(value: &str) -> Option<i64> {
+    value.trim().strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+}
+
+/// The new version, or `None` when `expected` isn't the stored one. No
+/// backup yet counts as version 0.
+async fn store(pool: &PgPool, user_id: &UserId, blob: &[u8], expected: Option<i64>) -> Result<Option<i64>, sqlx::Error> {
+    uchat_metrics::time_db_query("put_e2ee_backup", async {
+        let mut tx = pool.begin().await?;
+        let current: Option<i64> = sqlx::query_scalar("SELECT version FROM e2ee_backups WHERE user_id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let current = current.unwrap_or(0);
+        if expected.is_some_and(|expected| expected != current) {
+            return Ok(None);
+        }
+
+        sqlx::query(
+            "INSERT INTO e2ee_backups (user_id, blob, version) VALUES ($1, $2, $3)
+             ON CONFLICT (user_id) DO UPDATE SET blob = EXCLUDED.blob, version = EXCLUDED.version, updated_at = now()",
+        )
+        .bind(user_id)
+        .bind(blob)
+        .bind(current + 1)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(current + 1))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handle_request, test_state};
+    use hyper::Method;
+    use uchat_proto::jwt::create_token;
+
+    fn request(method: Method, token: &str, if_match: Option<&str>, body: Vec<u8>) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri("/users/me/e2ee-backup")
+            .header("Authorization", format!("Bearer {}", token));
+        if let Some(tag) = if_match {
+            builder = builder.header(IF_MATCH, tag);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_blobs_opaquely_and_refuses_stale_uploads() {
+        let Some(state) = test_state().await else { return };
+        let (owner, other) = (UserId::new(), UserId::new());
+        let token = create_token(&state.jwt_secret, owner.as_str());
+
+        let resp = handle_request(state.clone(), request(Method::GET, &token, None, vec![])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let blob = vec![0u8, 159, 146, 150, 255];
+        let resp = handle_request(state.clone(), request(Method::PUT, &token, Some("\"0\""), blob.clone())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[ETAG], "\"1\"");
+
+        let resp = handle_request(state.clone(), request(Method::GET, &token, None, vec![])).await.unwrap();
+        assert_eq!((resp.status(), resp.headers()[ETAG].to_str().unwrap()), (StatusCode::OK, "\"1\""));
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), blob);
+
+        // Another device that last saw version 0 must sync first.
+        let resp = handle_request(state.clone(), request(Method::PUT, &token, Some("\"0\""), vec![1])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        let resp = handle_request(state.clone(), request(Method::PUT, &token, None, vec![2])).await.unwrap();
+        assert_eq!(resp.headers()[ETAG], "\"2\"");
+
+        let resp = handle_request(state.clone(), request(Method::PUT, &token, None, vec![0; MAX_BACKUP_BYTES + 1])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = handle_request(state.clone(), request(Method::PUT, &token, Some("*"), vec![3])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Each user sees only their own.
+        let other_token = create_token(&state.jwt_secret, other.as_str());
+        let resp = handle_request(state.clone(), request(Method::GET, &other_token, None, vec![])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = handle_request(state, request(Method::GET, "forged", None, vec![])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
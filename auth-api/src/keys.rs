@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response, StatusCode};
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::keys::PrekeyBundleJson;
+
+use crate::{authenticate, json_error, json_ok, AppState};
+
+/// Owners are asked to replenish once fewer than this many one-time
+/// prekeys remain on the server.
+const LOW_PREKEY_THRESHOLD: usize = 5;
+
+/// GET /users/{user_id}/keys
+///
+/// Any authenticated user may fetch any bundle. Each fetch consumes one
+/// one-time prekey so two initiators never share it.
+pub async fn handle_get_keys(
+    state: Arc<AppState>,
+    req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    if authenticate(&state, &req).is_none() {
+        return Ok(json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "unauthorized"));
+    }
+
+    let Ok(user_id) = user_id.parse::<UserId>() else {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid user id"));
+    };
+
+    match take_bundle(&state.db, &user_id).await {
+        Ok(Some(bundle)) => Ok(json_ok(serde_json::to_string(&bundle).unwrap())),
+        Ok(None) => Ok(json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "no keys for user")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load prekey bundle");
+            Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"))
+        }
+    }
+}
+
+async fn take_bundle(pool: &PgPool, user_id: &UserId) -> Result<Option<PrekeyBundleJson>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let row: Option<(String, String, String, String)> = sqlx::query_as(
+        "SELECT identity_key, signed_prekey, signed_prekey_sig, one_time_prekeys_json
+         FROM user_keys WHERE user_id = $1 FOR UPDATE",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((identity_key, signed_prekey, signed_prekey_sig, prekeys_json)) = row else {
+        return Ok(None);
+    };
+
+    let mut prekeys: Vec<String> = serde_json::from_str(&prekeys_json).unwrap_or_default();
+    let one_time_prekey = if prekeys.is_empty() {
+        None
+    } else {
+        Some(prekeys.remove(0))
+    };
+
+    if one_time_prekey.is_some() {
+        sqlx::query("UPDATE user_keys SET one_time_prekeys_json = $2 WHERE user_id = $1")
+            .bind(user_id)
+            .bind(serde_json::to_string(&prekeys).unwrap())
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(Some(PrekeyBundleJson {
+        user_id: user_id.to_string(),
+        identity_key,
+        signed_prekey,
+        signed_prekey_sig,
+        one_time_prekey,
+        low_prekeys: prekeys.len() < LOW_PREKEY_THRESHOLD,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_state;
+    use hyper::Method;
+    use uchat_proto::jwt::create_token;
+
+    async fn insert_keys(pool: &PgPool, user_id: &UserId, prekeys: &[&str]) {
+        sqlx::query(
+            "INSERT INTO user_keys
+             (user_id, identity_key, signed_prekey, signed_prekey_sig, one_time_prekeys_json)
+             VALUES ($1, 'ik', 'spk', 'sig', $2)",
+        )
+        .bind(user_id)
+        .bind(serde_json::to_string(prekeys).unwrap())
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    fn get(path: &str, token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(Method::GET).uri(path);
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    async fn body_json(resp: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_bearer_token() {
+        let Some(state) = test_state().await else { return };
+
+        let path = format!("/users/{}/keys", UserId::new());
+        let resp = crate::handle_request(state, get(&path, None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn consumes_one_prekey_per_fetch_and_flags_low() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        insert_keys(&state.db, &owner, &["p1", "p2", "p3", "p4", "p5", "p6"]).await;
+
+        let token = create_token(&state.jwt_secret, UserId::new().as_str());
+        let path = format!("/users/{}/keys", owner);
+
+        let first = body_json(
+            crate::handle_request(state.clone(), get(&path, Some(&token))).await.unwrap(),
+        )
+        .await;
+        assert_eq!(first["one_time_prekey"], "p1");
+        assert_eq!(first["identity_key"], "ik");
+        assert!(first.get("low_prekeys").is_none());
+
+        let second = body_json(
+            crate::handle_request(state.clone(), get(&path, Some(&token))).await.unwrap(),
+        )
+        .await;
+        assert_eq!(second["one_time_prekey"], "p2");
+        assert_eq!(second["low_prekeys"], true);
+    }
+
+    #[tokio::test]
+    async fn unknown_user_is_not_found() {
+        let Some(state) = test_state().await else { return };
+        let token = create_token(&state.jwt_secret, UserId::new().as_str());
+        let path = format!("/users/{}/keys", UserId::new());
+
+        let resp = crate::handle_request(state, get(&path, Some(&token))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn malformed_user_id_is_rejected() {
+        let Some(state) = test_state().await else { return };
+        let token = create_token(&state.jwt_secret, UserId::new().as_str());
+
+        let resp = crate::handle_request(state, get("/users/alice/keys", Some(&token)))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
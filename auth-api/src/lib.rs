@@ -0,0 +1,481 @@
+mod account;
+mod admin;
+mod db;
+mod e2ee_backup;
+mod keys;
+mod oidc;
+mod password;
+mod public_info;
+mod refresh;
+mod throttle;
+mod user_import;
+mod webhooks;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response, Server, Method, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::Instrument;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::jwt::{secret_from_env, verify_claims, Claims, SigningKey};
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::UserId;
+use uchat_proto::users::UserPublicInfo;
+use uchat_telemetry::{CORRELATION_ID_HEADER, REQUEST_ID_HEADER, TRACEPARENT_HEADER};
+
+use anyhow::Result;
+
+pub struct AppState {
+    db: PgPool,
+    /// Signs and checks user sessions from `/login` and `/refresh` (HS256).
+    /// channels-api and the gateway check those tokens themselves with
+    /// the same secret, so they stay on it; the OIDC provider key is only
+    /// for service tokens from `/token`, which only the gateway accepts.
+    jwt_secret: String,
+    /// Answers to `/users/{id}/public-info` by user id, with when they
+    /// were looked up.
+    public_info: DashMap<String, (UserPublicInfo, Instant)>,
+    /// Unset when `GATEWAY_INTERNAL_URL` is not configured.
+    gateway: Option<public_info::GatewayClient>,
+    webhooks: webhooks::Notifier,
+    /// Failed logins by username, slowing down the next attempt.
+    login_throttle: throttle::LoginThrottle,
+    oidc: oidc::Provider,
+}
+
+#[derive(Deserialize)]
+struct LoginReq {
+    username: String,
+    password: String,
+}
+
+impl AppState {
+    /// State with no presence lookups, webhook retries starting at one
+    /// second and an OIDC signing key that lasts as long as it does.
+    pub fn new(db: PgPool, jwt_secret: String) -> Arc<Self> {
+        Arc::new(AppState {
+            db,
+            jwt_secret,
+            public_info: DashMap::new(),
+            gateway: None,
+            webhooks: webhooks::Notifier::new(Duration::from_secs(1)),
+            login_throttle: throttle::LoginThrottle::default(),
+            oidc: oidc::Provider::new("http://127.0.0.1:9200", SigningKey::generate(), Vec::new()),
+        })
+    }
+
+    /// State configured from the environment, as the service runs. Starts
+    /// purging deleted accounts.
+    pub async fn from_env() -> Result<Arc<Self>> {
+        let database_url = std::env::var("DATABASE_URL")
+            .unwrap_or_else(|_| "postgres://postgres@localhost/uchat".into());
+
+        let state = Arc::new(AppState {
+            db: uchat_db::connect(&database_url).await?,
+            jwt_secret: secret_from_env(),
+            public_info: DashMap::new(),
+            gateway: public_info::GatewayClient::from_env(),
+            webhooks: webhooks::Notifier::new(Duration::from_secs(1)),
+            login_throttle: throttle::LoginThrottle::default(),
+            oidc: oidc::Provider::from_env()?,
+        });
+        account::spawn_purge(state.db.clone());
+        Ok(state)
+    }
+}
+
+/// Serves the API on `listener`.
+pub async fn serve(listener: std::net::TcpListener, state: Arc<AppState>) -> Result<()> {
+    let addr = listener.local_addr()?;
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| handle_request(state.clone(), req)))
+        }
+    });
+
+    tracing::info!("auth-api running on http://{}", addr);
+
+    Server::from_tcp(listener)?
+        .serve(make_svc)
+        .await?;
+
+    Ok(())
+}
+
+/// Runs `route` inside a span carrying the request's correlation id and
+/// trace, and echoes the id back to the caller.
+async fn handle_request(
+    state: Arc<AppState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let incoming = [CORRELATION_ID_HEADER, REQUEST_ID_HEADER]
+        .iter()
+        .find_map(|name| req.headers().get(*name)?.to_str().ok());
+    let correlation_id = uchat_telemetry::correlation_id(incoming);
+    let traceparent = req.headers().get(TRACEPARENT_HEADER).and_then(|v| v.to_str().ok());
+    let span = uchat_telemetry::request_span(&correlation_id, req.method().as_str(), req.uri().path(), traceparent);
+
+    let mut resp = route(state, req).instrument(span).await?;
+    if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+        resp.headers_mut().insert(CORRELATION_ID_HEADER, value);
+    }
+    Ok(resp)
+}
+
+async fn route(
+    state: Arc<AppState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (req.method(), segments.as_slice()) {
+        (&Method::POST, ["login"]) => handle_login(state, req).await,
+        (&Method::POST, ["refresh"]) => refresh::handle_refresh(state, req).await,
+        (&Method::POST, ["logout"]) => refresh::handle_logout(state, req).await,
+        (&Method::POST, ["activate"]) => password::handle_activate(state, req).await,
+        (&Method::DELETE, ["users", "me"]) => account::handle_delete_me(state, req).await,
+        (&Method::GET, ["users", "me", "e2ee-backup"]) => e2ee_backup::handle_get(state, req).await,
+        (&Method::PUT, ["users", "me", "e2ee-backup"]) => e2ee_backup::handle_put(state, req).await,
+        (&Method::GET, ["users", user_id, "keys"]) => keys::handle_get_keys(state, req, user_id).await,
+        (&Method::GET, ["users", user_id, "public-info"]) => {
+            public_info::handle_get_public_info(state, req, user_id).await
+        }
+        (&Method::GET, ["admin", "users"]) => admin::handle_list_users(state, req).await,
+        (&Method::POST, ["admin", "users"]) => admin::handle_create_user(state, req).await,
+        (&Method::POST, ["admin", "users", "import"]) => user_import::handle_import(state, req).await,
+        (&Method::GET, ["admin", "users", user_id]) => admin::handle_get_user(state, req, user_id).await,
+        (&Method::PUT, ["admin", "users", user_id, "role"]) => admin::handle_set_role(state, req, user_id).await,
+        (&Method::DELETE, ["admin", "users", user_id, "refresh-tokens"]) => {
+            admin::handle_revoke_tokens(state, req, user_id).await
+        }
+        (&Method::POST, ["admin", "users", user_id, "activation"]) => {
+            admin::handle_reissue_activation(state, req, user_id).await
+        }
+        (&Method::POST, ["admin", "users", user_id, "suspend"]) => admin::handle_suspend(state, req, user_id).await,
+        (&Method::POST, ["admin", "users", user_id, "unsuspend"]) => {
+            admin::handle_unsuspend(state, req, user_id).await
+        }
+        (&Method::POST, ["admin", "webhooks"]) => webhooks::handle_create(state, req).await,
+        (&Method::GET, [".well-known", "openid-configuration"]) => oidc::handle_discovery(state).await,
+        (&Method::GET, ["jwks.json"]) => oidc::handle_jwks(state).await,
+        (&Method::POST, ["token"]) => oidc::handle_token(state, req).await,
+        _ => Ok(not_found()),
+    }
+}
+
+/// Claims from a valid `Authorization: Bearer` token, if any.
+fn authenticate(state: &AppState, req: &Request<Body>) -> Option<Claims> {
+    let Some(token) = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        uchat_metrics::auth_failure("missing_token");
+        return None;
+    };
+
+    let claims = verify_claims(&state.jwt_secret, token);
+    if claims.is_none() {
+        uchat_metrics::auth_failure("invalid_token");
+    }
+    claims
+}
+
+async fn handle_login(
+    state: Arc<AppState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let login: LoginReq = match serde_json::from_slice(&whole_body) {
+        Ok(v) => v,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+    };
+
+    if let Err(locked_for) = state.login_throttle.admit(&login.username).await {
+        uchat_metrics::auth_failure("locked");
+        return Ok(with_reset(json_error(StatusCode::TOO_MANY_REQUESTS, ErrorCode::RateLimited, "too many failed logins"), locked_for));
+    }
+    // Refusals count towards the username's next delay.
+    let refused = |resp| with_reset(resp, state.login_throttle.failed(&login.username));
+
+    let (user_id, registered) = match db::get_or_create_user(&state.db, &login.username).await {
+        Ok(found) => found,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to look up user");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+
+    if let Some(resp) = account_refusal(&state, &user_id).await {
+        let suspended_or_deleted = resp.status() == StatusCode::FORBIDDEN;
+        return Ok(if suspended_or_deleted { refused(resp) } else { resp });
+    }
+    match password::check(&state.db, &user_id, &login.password, registered).await {
+        Ok(true) => {}
+        Ok(false) => {
+            uchat_metrics::auth_failure("wrong_password");
+            return Ok(refused(json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "wrong username or password")));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to check password");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+
+    let rooms = match db::load_room_permissions(&state.db, &user_id).await {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load channel memberships");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+
+    let token = match refresh::access_token(&state, &user_id, rooms).await {
+        Ok(token) => token,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load token profile");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+    let refresh_token = match refresh::issue(&state.db, &user_id).await {
+        Ok(token) => token,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to issue refresh token");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+    state.login_throttle.succeeded(&login.username);
+    tracing::info!(user_id = %user_id, "login");
+    if registered {
+        state.webhooks.notify(&state.db, webhooks::Event::Registered, &user_id);
+    }
+    state.webhooks.notify(&state.db, webhooks::Event::Login, &user_id);
+
+    let response = ServerEvent::LoginOk { token, refresh_token: Some(refresh_token) };
+    let json = serde_json::to_string(&response).unwrap();
+
+    Ok(json_ok(json))
+}
+
+/// Why `user_id` may not have a token, as the response to send: their
+/// account is deleted or suspended (403), or checking failed.
+async fn account_refusal(state: &AppState, user_id: &UserId) -> Option<Response<Body>> {
+    match db::is_deleted(&state.db, user_id).await {
+        Ok(false) => {}
+        Ok(true) => {
+            uchat_metrics::auth_failure("deleted");
+            return Some(json_error(StatusCode::FORBIDDEN, ErrorCode::AccountDeleted, "account deleted"));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to check account deletion");
+            return Some(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+
+    match db::active_suspension(&state.db, user_id).await {
+        Ok(None) => {}
+        Ok(Some(until)) => {
+            uchat_metrics::auth_failure("suspended");
+            let err = ServerEvent::Error {
+                code: ErrorCode::AccountSuspended,
+                message: "account suspended".into(),
+                until: until.map(|t| t.to_rfc3339()),
+            };
+            return Some(json_response(StatusCode::FORBIDDEN, serde_json::to_string(&err).unwrap()));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to check suspension");
+            return Some(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    }
+    None
+}
+
+/// Adds `X-RateLimit-Reset`: whole seconds until the caller's failed
+/// logins stop slowing them down.
+fn with_reset(mut resp: Response<Body>, reset: Duration) -> Response<Body> {
+    let secs = reset.as_secs() + u64::from(reset.subsec_nanos() > 0);
+    resp.headers_mut().insert("X-RateLimit-Reset", HeaderValue::from(secs));
+    resp
+}
+
+fn json_ok(body: String) -> Response<Body> {
+    json_response(StatusCode::OK, body)
+}
+
+fn json_error(status: StatusCode, code: ErrorCode, msg: &str) -> Response<Body> {
+    let err = ServerEvent::error(code, msg);
+    json_response(status, serde_json::to_string(&err).unwrap())
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("not found"))
+        .unwrap()
+}
+
+/// State backed by `TEST_DATABASE_URL`; DB tests skip when it is unset.
+#[cfg(test)]
+async fn test_state() -> Option<Arc<AppState>> {
+    Some(Arc::new(AppState {
+        db: uchat_db::connect_test().await?,
+        jwt_secret: "test-secret".into(),
+        public_info: DashMap::new(),
+        gateway: None,
+        webhooks: webhooks::Notifier::new(Duration::from_millis(10)),
+        login_throttle: throttle::LoginThrottle::default(),
+        oidc: oidc::Provider::new("http://127.0.0.1:9200", SigningKey::generate(), Vec::new()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn echoes_the_callers_correlation_id() {
+        let Some(state) = test_state().await else { return };
+
+        let req = Request::get("/nowhere").header(REQUEST_ID_HEADER, "req-42").body(Body::empty()).unwrap();
+        let resp = handle_request(state.clone(), req).await.unwrap();
+        assert_eq!(resp.headers()[CORRELATION_ID_HEADER], "req-42");
+
+        let req = Request::get("/nowhere").body(Body::empty()).unwrap();
+        let resp = handle_request(state, req).await.unwrap();
+        assert!(!resp.headers()[CORRELATION_ID_HEADER].is_empty());
+    }
+
+    fn login(username: &str) -> Request<Body> {
+        let body = serde_json::json!({ "username": username, "password": "x" });
+        Request::post("/login").body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn locked_usernames_are_refused_before_the_db() {
+        let db = sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://nowhere.invalid/uchat").unwrap();
+        let state = AppState::new(db, "test-secret".into());
+        for _ in 0..10 {
+            state.login_throttle.failed("mallory");
+        }
+
+        let resp = handle_request(state, login("mallory")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["X-RateLimit-Reset"], "900");
+    }
+
+    #[tokio::test]
+    async fn refused_logins_slow_down_the_next() {
+        let Some(state) = test_state().await else { return };
+        let name = format!("user-{}", uchat_proto::ids::UserId::new());
+        let (user_id, _) = db::get_or_create_user(&state.db, &name).await.unwrap();
+        sqlx::query("UPDATE users SET suspended = true, password_hash = $2 WHERE id = $1")
+            .bind(&user_id)
+            .bind(password::hash("x"))
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let resp = handle_request(state.clone(), login(&name)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()["X-RateLimit-Reset"], "900");
+
+        sqlx::query("UPDATE users SET suspended = false WHERE id = $1").bind(&user_id).execute(&state.db).await.unwrap();
+        let start = Instant::now();
+        let resp = handle_request(state.clone(), login(&name)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(resp.headers().get("X-RateLimit-Reset").is_none());
+
+        let start = Instant::now();
+        handle_request(state, login(&name)).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn exports_auth_and_query_metrics() {
+        let Some(state) = test_state().await else { return };
+        let addr = uchat_metrics::init("auth-api", "127.0.0.1:0").await.unwrap();
+
+        let login = Request::post("/login").body(Body::from(r#"{"username":"metrics-smoke","password":"x"}"#));
+        handle_request(state.clone(), login.unwrap()).await.unwrap();
+        let keys = Request::get(format!("/users/{}/keys", uchat_proto::ids::UserId::new()))
+            .header("Authorization", "Bearer not-a-token")
+            .body(Body::empty())
+            .unwrap();
+        handle_request(state, keys).await.unwrap();
+
+        let body = uchat_metrics::scrape(addr).await.unwrap();
+        for family in ["uchat_auth_failures_total", "uchat_db_query_duration_seconds", "process_start_time_seconds"] {
+            assert!(body.contains(&format!("# TYPE {} ", family)), "{} missing", family);
+        }
+        assert!(body.contains("uchat_auth_failures_total{service=\"auth-api\",reason=\"invalid_token\"}"));
+        assert!(body.contains("uchat_db_query_duration_seconds_count{service=\"auth-api\",query=\"get_or_create_user\"}"));
+    }
+
+    #[tokio::test]
+    async fn the_first_login_sets_the_password() {
+        let Some(state) = test_state().await else { return };
+        let name = format!("user-{}", uchat_proto::ids::UserId::new());
+        let login_with = |password: &str| {
+            let body = serde_json::json!({ "username": name, "password": password });
+            Request::post("/login").body(Body::from(body.to_string())).unwrap()
+        };
+
+        assert_eq!(handle_request(state.clone(), login_with("first")).await.unwrap().status(), StatusCode::OK);
+        let resp = handle_request(state.clone(), login_with("second")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(resp.headers().get("X-RateLimit-Reset").is_some());
+        assert_eq!(handle_request(state, login_with("first")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn only_known_owners_claim_an_account_without_a_password() {
+        let Some(state) = test_state().await else { return };
+        let login_as = |name: &str, password: &str| {
+            let body = serde_json::json!({ "username": name, "password": password });
+            Request::post("/login").body(Body::from(body.to_string())).unwrap()
+        };
+
+        let admin = format!("user-{}", uchat_proto::ids::UserId::new());
+        let created = uchat_db::admin::create_user(&state.db, &admin, uchat_proto::users::UserRole::Admin).await.unwrap().unwrap();
+        let resp = handle_request(state.clone(), login_as(&admin, "mine now")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let stored: Option<String> = sqlx::query_scalar("SELECT password_hash FROM users WHERE id = $1")
+            .bind(&created.user.id)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(stored, None);
+
+        // From before passwords were stored: only with an earlier session.
+        let legacy = format!("user-{}", uchat_proto::ids::UserId::new());
+        let (user_id, _) = db::get_or_create_user(&state.db, &legacy).await.unwrap();
+        let resp = handle_request(state.clone(), login_as(&legacy, "mine now")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        refresh::issue(&state.db, &user_id).await.unwrap();
+        let resp = handle_request(state.clone(), login_as(&legacy, "theirs")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = handle_request(state, login_as(&legacy, "mine now")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
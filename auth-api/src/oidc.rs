@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Request, Response, StatusCode};
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use uchat_proto::jwt::{Claims, JwkSet, SigningKey};
+use uchat_proto::permissions::RoomPermissions;
+
+use crate::{json_ok, json_response, AppState};
+
+/// How long `/token` access tokens last.
+const TOKEN_TTL: Duration = Duration::hours(1);
+
+/// A service allowed the client credentials grant. Its tokens are for the
+/// user `client_id`.
+#[derive(Deserialize)]
+pub struct Client {
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub role: Option<String>,
+}
+
+/// The OpenID provider side of auth-api: discovery, the JWKS the gateway
+/// checks tokens against, and tokens for services.
+pub struct Provider {
+    issuer: String,
+    key: SigningKey,
+    clients: HashMap<String, Client>,
+}
+
+impl Provider {
+    pub fn new(issuer: &str, key: SigningKey, clients: Vec<Client>) -> Self {
+        let clients = clients.into_iter().map(|c| (c.client_id.clone(), c)).collect();
+        Self { issuer: issuer.trim_end_matches('/').to_string(), key, clients }
+    }
+
+    /// `OIDC_ISSUER` (the URL auth-api is reached at), the signing key
+    /// (see `load_key`) and the clients listed in `OIDC_CLIENTS_PATH`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let issuer = std::env::var("OIDC_ISSUER").unwrap_or_else(|_| "http://127.0.0.1:9200".into());
+        let key = load_key(
+            std::env::var("OIDC_SIGNING_KEY").ok().filter(|v| !v.is_empty()),
+            std::env::var("OIDC_SIGNING_KEY_PATH").ok().filter(|v| !v.is_empty()),
+        )?;
+        let clients = match std::env::var("OIDC_CLIENTS_PATH") {
+            Ok(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+            Err(_) => Vec::new(),
+        };
+        Ok(Self::new(&issuer, key, clients))
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet { keys: vec![self.key.jwk()] }
+    }
+
+    /// The client `id`, if `secret` is its secret.
+    fn client(&self, id: &str, secret: &str) -> Option<&Client> {
+        let client = self.clients.get(id)?;
+        // Compared as digests so the time taken says nothing about the secret.
+        (Sha256::digest(&client.client_secret) == Sha256::digest(secret)).then_some(client)
+    }
+}
+
+/// The PKCS#8 Ed25519 signing key: `inline` in base64, as a secret
+/// manager hands it over in `OIDC_SIGNING_KEY`, or else the file at
+/// `path` (`OIDC_SIGNING_KEY_PATH`), generated there on first start. The
+/// key has to outlive restarts, or every token issued so far would stop
+/// verifying, so one of the two is required.
+fn load_key(inline: Option<String>, path: Option<String>) -> anyhow::Result<SigningKey> {
+    let invalid = |from: &str| anyhow::anyhow!("{} is not a PKCS#8 Ed25519 key", from);
+    if let Some(inline) = inline {
+        let der = STANDARD.decode(inline.trim()).map_err(|_| invalid("OIDC_SIGNING_KEY"))?;
+        return SigningKey::from_pkcs8(&der).ok_or_else(|| invalid("OIDC_SIGNING_KEY"));
+    }
+    let Some(path) = path else {
+        anyhow::bail!("set OIDC_SIGNING_KEY or OIDC_SIGNING_KEY_PATH");
+    };
+
+    match std::fs::read(&path) {
+        Ok(der) => SigningKey::from_pkcs8(&der).ok_or_else(|| invalid(&path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            use std::io::Write;
+            use std::os::unix::fs::OpenOptionsExt;
+
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| anyhow::anyhow!("failed to generate a signing key"))?;
+            std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?.write_all(pkcs8.as_ref())?;
+            tracing::info!(path, "generated a new OIDC signing key");
+            SigningKey::from_pkcs8(pkcs8.as_ref()).ok_or_else(|| invalid(&path))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenReq {
+    grant_type: String,
+    #[serde(default)]
+    client_id: Option<String>,
+    #[serde(default)]
+    client_secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IssuedClaims<'a> {
+    #[serde(flatten)]
+    claims: Claims,
+    iss: &'a str,
+    iat: i64,
+}
+
+/// GET /.well-known/openid-configuration
+pub async fn handle_discovery(state: Arc<AppState>) -> Result<Response<Body>, hyper::Error> {
+    let issuer = &state.oidc.issuer;
+    let doc = serde_json::json!({
+        "issuer": issuer,
+        "jwks_uri": format!("{}/jwks.json", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "grant_types_supported": ["client_credentials"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+        "response_types_supported": ["token"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+    });
+    Ok(json_ok(doc.to_string()))
+}
+
+/// GET /jwks.json
+pub async fn handle_jwks(state: Arc<AppState>) -> Result<Response<Body>, hyper::Error> {
+    Ok(json_ok(serde_json::to_string(&state.oidc.jwks()).unwrap()))
+}
+
+/// POST /token, form-encoded. Only the client credentials grant, with the
+/// credentials in a Basic header or the form.
+pub async fn handle_token(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let basic = basic_credentials(&req);
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let Ok(form) = serde_urlencoded::from_bytes::<TokenReq>(&body) else {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request"));
+    };
+    if form.grant_type != "client_credentials" {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type"));
+    }
+
+    let (id, secret) = match (basic, form.client_id, form.client_secret) {
+        (Some(basic), _, _) => basic,
+        (None, Some(id), Some(secret)) => (id, secret),
+        _ => return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client")),
+    };
+    let Some(client) = state.oidc.client(&id, &secret) else {
+        uchat_metrics::auth_failure("invalid_client");
+        return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"));
+    };
+
+    let now = Utc::now();
+    let claims = Claims {
+        sub: client.client_id.clone(),
+        exp: (now + TOKEN_TTL).timestamp() as usize,
+        rooms: RoomPermissions::default(),
+        bot: true,
+        role: client.role.clone(),
+        joined: None,
+    };
+    let token = state.oidc.key.sign(&IssuedClaims { claims, iss: &state.oidc.issuer, iat: now.timestamp() });
+    let body = serde_json::json!({
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": TOKEN_TTL.num_seconds(),
+    });
+    Ok(json_ok(body.to_string()))
+}
+
+fn basic_credentials(req: &Request<Body>) -> Option<(String, String)> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let decoded = STANDARD.decode(header.strip_prefix("Basic ")?).ok()?;
+    let (id, secret) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((id.to_string(), secret.to_string()))
+}
+
+/// Token endpoint errors take RFC 6749's shape rather than ours, for the
+/// OAuth clients that call it.
+fn oauth_error(status: StatusCode, error: &str) -> Response<Body> {
+    json_response(status, serde_json::json!({ "error": error }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle_request;
+    use uchat_proto::jwt::{verify_claims_jwks, Jwk};
+
+    fn state() -> Arc<AppState> {
+        let db = sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://nowhere.invalid/uchat").unwrap();
+        let mut state = Arc::try_unwrap(AppState::new(db, "test-secret".into())).ok().unwrap();
+        let client = Client { client_id: "svc-1".into(), client_secret: "s3cret".into(), role: Some("bridge".into()) };
+        state.oidc = Provider::new("http://auth.test/", SigningKey::generate(), vec![client]);
+        Arc::new(state)
+    }
+
+    async fn call(state: &Arc<AppState>, req: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let resp = handle_request(state.clone(), req).await.unwrap();
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    fn token_req(form: &str) -> Request<Body> {
+        Request::post("/token")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from(form.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn the_signing_key_survives_restarts() {
+        assert!(load_key(None, None).is_err());
+
+        let path = std::env::temp_dir().join(format!("oidc-key-{}.der", uchat_proto::ids::UserId::new()));
+        let path_str = path.to_str().unwrap().to_string();
+        let first = load_key(None, Some(path_str.clone())).unwrap();
+        let again = load_key(None, Some(path_str)).unwrap();
+        assert_eq!(first.kid(), again.kid());
+
+        let inline = STANDARD.encode(std::fs::read(&path).unwrap());
+        assert_eq!(load_key(Some(inline), None).unwrap().kid(), first.kid());
+        assert!(load_key(Some("not a key".into()), None).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn discovery_points_at_the_jwks_and_token_endpoint() {
+        let state = state();
+        let (status, doc) = call(&state, Request::get("/.well-known/openid-configuration").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(doc["issuer"], "http://auth.test");
+        assert_eq!(doc["jwks_uri"], "http://auth.test/jwks.json");
+        assert_eq!(doc["token_endpoint"], "http://auth.test/token");
+    }
+
+    #[tokio::test]
+    async fn jwks_is_valid_jwk_format_and_verifies_issued_tokens() {
+        let state = state();
+        let (status, body) = call(&state, Request::get("/jwks.json").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let key = &body["keys"][0];
+        assert_eq!(key["kty"], "OKP");
+        assert_eq!(key["crv"], "Ed25519");
+        assert_eq!(key["alg"], "EdDSA");
+        assert_eq!(key["use"], "sig");
+        assert!(key["kid"].is_string() && key["x"].is_string());
+        serde_json::from_value::<Jwk>(key.clone()).unwrap();
+        let jwks: JwkSet = serde_json::from_value(body).unwrap();
+
+        let basic = format!("Basic {}", STANDARD.encode("svc-1:s3cret"));
+        let mut req = token_req("grant_type=client_credentials");
+        req.headers_mut().insert(AUTHORIZATION, basic.parse().unwrap());
+        let (status, body) = call(&state, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["token_type"], "Bearer");
+        let claims = verify_claims_jwks(&jwks, body["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.sub, "svc-1");
+        assert!(claims.bot && claims.is_bridge());
+    }
+
+    #[tokio::test]
+    async fn token_endpoint_refuses_other_grants_and_bad_secrets() {
+        let state = state();
+        let (status, body) = call(&state, token_req("grant_type=password&username=a&password=b")).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::BAD_REQUEST, Some("unsupported_grant_type")));
+
+        let form = "grant_type=client_credentials&client_id=svc-1&client_secret=wrong";
+        let (status, body) = call(&state, token_req(form)).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("invalid_client")));
+
+        let form = "grant_type=client_credentials&client_id=svc-1&client_secret=s3cret";
+        let (status, body) = call(&state, token_req(form)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["expires_in"], 3600);
+    }
+}
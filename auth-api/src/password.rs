@@ -0,0 +1,178 @@
+//! Account passwords, stored as `pbkdf2-sha256$<iterations>$<salt>$<hash>`
+//! with the salt and hash in base64.
+//!
+//! Accounts made by a first login, or before passwords were stored, have no
+//! hash until a password is checked against them by someone known to own
+//! them; that password becomes theirs. Provisioned accounts, made by an
+//! admin or an import for someone else, are never claimed that way: their
+//! owner sets a password with the activation token at `POST /activate`.
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{Body, Request, Response, StatusCode};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+
+use crate::{json_error, AppState};
+
+const SCHEME: &str = "pbkdf2-sha256";
+/// Work factor for new hashes; stored ones keep theirs.
+const ITERATIONS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+#[derive(Deserialize)]
+struct ActivateReq {
+    token: String,
+    password: String,
+}
+
+fn derive(password: &str, salt: &[u8], iterations: NonZeroU32) -> [u8; HASH_LEN] {
+    let mut out = [0u8; HASH_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut out);
+    out
+}
+
+/// A new hash of `password` with a random salt.
+pub fn hash(password: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).expect("system randomness");
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    let hash = derive(password, &salt, iterations);
+    format!("{}${}${}${}", SCHEME, iterations, STANDARD.encode(salt), STANDARD.encode(hash))
+}
+
+/// Whether `password` matches `stored`; `false` for anything that isn't
+/// a hash this module wrote.
+pub fn verify(password: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (Some(SCHEME), Some(iterations), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(hash)) =
+        (iterations.parse::<NonZeroU32>(), STANDARD.decode(salt), STANDARD.decode(hash))
+    else {
+        return false;
+    };
+    pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+/// Checks `password` against the account's hash. An account without one
+/// adopts `password` only when it isn't provisioned and is known to be the
+/// caller's: `owned`, because this very login registered it or the caller
+/// is signed in to it, or holding a refresh token from an earlier login.
+/// Hashing runs off the async workers.
+pub async fn check(pool: &PgPool, user_id: &UserId, password: &str, owned: bool) -> Result<bool, sqlx::Error> {
+    uchat_metrics::time_db_query("check_password", async {
+        let account: Option<(Option<String>, bool, bool)> = sqlx::query_as(
+            "SELECT password_hash, provisioned,
+                    EXISTS (SELECT 1 FROM refresh_tokens r WHERE r.user_id = users.id)
+             FROM users WHERE id = $1",
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        let stored = match account {
+            None => return Ok(false),
+            Some((Some(stored), _, _)) => stored,
+            Some((None, provisioned, signed_in)) => {
+                if provisioned || !(owned || signed_in) {
+                    return Ok(false);
+                }
+                let new = blocking(password, |password| hash(&password)).await;
+                sqlx::query(
+                    "UPDATE users SET password_hash = $2 WHERE id = $1 AND password_hash IS NULL AND NOT provisioned",
+                )
+                .bind(user_id)
+                .bind(&new)
+                .execute(pool)
+                .await?;
+                // A concurrent first login may have stored its own.
+                let stored: Option<String> = sqlx::query_scalar("SELECT password_hash FROM users WHERE id = $1")
+                    .bind(user_id)
+                    .fetch_optional(pool)
+                    .await?
+                    .flatten();
+                match stored {
+                    Some(stored) => stored,
+                    None => return Ok(false),
+                }
+            }
+        };
+
+        Ok(blocking(password, move |password| verify(&password, &stored)).await)
+    })
+    .await
+}
+
+/// POST /activate
+///
+/// Sets `{"password"}` as the password of the account `{"token"}` was
+/// issued for, using the token up; 401 for one that is unknown, used or
+/// expired.
+pub async fn handle_activate(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let activate: ActivateReq = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+    };
+    if activate.password.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "password required"));
+    }
+
+    let new = blocking(&activate.password, |password| hash(&password)).await;
+    let activated = uchat_metrics::time_db_query("activate_account", async {
+        sqlx::query(
+            "UPDATE users SET password_hash = $2, activation_token_hash = NULL, activation_expires_at = NULL
+             WHERE activation_token_hash = $1 AND activation_expires_at > now()
+               AND password_hash IS NULL AND deleted_at IS NULL",
+        )
+        .bind(uchat_db::admin::activation_token_hash(&activate.token))
+        .bind(&new)
+        .execute(&state.db)
+        .await
+    })
+    .await;
+    Ok(match activated {
+        Ok(done) if done.rows_affected() == 1 => Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap(),
+        Ok(_) => {
+            uchat_metrics::auth_failure("invalid_activation_token");
+            json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "invalid activation token")
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to activate account");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error")
+        }
+    })
+}
+
+async fn blocking<T: Send + 'static>(password: &str, f: impl FnOnce(String) -> T + Send + 'static) -> T {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || f(password)).await.expect("password hashing panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_verify_only_their_password() {
+        let stored = hash("correct horse");
+        assert!(stored.starts_with("pbkdf2-sha256$1000$"));
+        assert!(verify("correct horse", &stored));
+        assert!(!verify("battery staple", &stored));
+        assert_ne!(hash("correct horse"), stored, "salts are random");
+        assert!(!verify("correct horse", "plain text"));
+        assert!(!verify("correct horse", &format!("{}$extra", stored)));
+    }
+}
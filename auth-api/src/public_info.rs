@@ -0,0 +1,218 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Response, StatusCode};
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::users::{avatar_url, OnlineStatus, UserDeleted, UserPresence, UserPublicInfo};
+
+use crate::{json_error, json_ok, AppState};
+
+/// How long a looked-up user is served from the cache.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+/// Cache size past which expired entries are swept out.
+const CACHE_SWEEP_LEN: usize = 10_000;
+
+/// GET /users/{user_id}/public-info
+///
+/// Needs no token, so clients can show who they are about to talk to
+/// before signing in. Only fields in `UserPublicInfo` are ever returned.
+pub async fn handle_get_public_info(
+    state: Arc<AppState>,
+    _req: Request<Body>,
+    user_id: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let Ok(user_id) = user_id.parse::<UserId>() else {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid user id"));
+    };
+
+    if let Some(entry) = state.public_info.get(user_id.as_str()) {
+        let (info, fetched_at) = entry.value();
+        if fetched_at.elapsed() < CACHE_TTL {
+            return Ok(json_ok(serde_json::to_string(info).unwrap()));
+        }
+    }
+
+    let mut info = match load(&state.db, &user_id).await {
+        Ok(Some(info)) => info,
+        Ok(None) => return Ok(json_error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "user not found")),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load public info");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+    if let Some(gateway) = &state.gateway {
+        info.online_status = gateway.online(&user_id).await.map(OnlineStatus::from);
+    }
+
+    if state.public_info.len() >= CACHE_SWEEP_LEN {
+        state.public_info.retain(|_, (_, fetched_at)| fetched_at.elapsed() < CACHE_TTL);
+    }
+    let json = serde_json::to_string(&info).unwrap();
+    state.public_info.insert(user_id.to_string(), (info, Instant::now()));
+    Ok(json_ok(json))
+}
+
+#[derive(sqlx::FromRow)]
+struct PublicRow {
+    username: String,
+    display_name: Option<String>,
+    avatar_version: Option<String>,
+    bio: Option<String>,
+}
+
+async fn load(pool: &PgPool, user_id: &UserId) -> Result<Option<UserPublicInfo>, sqlx::Error> {
+    let row: Option<PublicRow> =
+        sqlx::query_as("SELECT username, display_name, avatar_version, bio FROM users WHERE id = $1 AND deleted_at IS NULL")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(row.map(|row| UserPublicInfo {
+        user_id: user_id.clone(),
+        username: row.username,
+        display_name: row.display_name,
+        avatar_url: row.avatar_version.map(|v| avatar_url(user_id, &v)),
+        bio: row.bio,
+        online_status: None,
+    }))
+}
+
+/// Calls gateway-service's internal API: `/internal/presence`, to ask
+/// whether users are connected, and `/internal/user-deleted`.
+pub struct GatewayClient {
+    client: Client<HttpConnector>,
+    base_url: String,
+    token: String,
+}
+
+impl GatewayClient {
+    pub fn new(base_url: &str, token: String) -> Self {
+        Self { client: Client::new(), base_url: base_url.trim_end_matches('/').to_string(), token }
+    }
+
+    /// Reads `GATEWAY_INTERNAL_URL` and `GATEWAY_INTERNAL_TOKEN`; `None`
+    /// when either is unset.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("GATEWAY_INTERNAL_URL").ok().filter(|v| !v.is_empty())?;
+        let token = std::env::var("GATEWAY_INTERNAL_TOKEN").ok().filter(|v| !v.is_empty())?;
+        Some(Self::new(&url, token))
+    }
+
+    /// Whether `user_id` has a live gateway connection, or `None` when the
+    /// gateway can't be reached.
+    pub async fn online(&self, user_id: &UserId) -> Option<bool> {
+        let req = Request::get(format!("{}/internal/presence?ids={}", self.base_url, user_id))
+            .header("x-internal-token", &self.token)
+            .body(Body::empty())
+            .ok()?;
+
+        let fetch = async {
+            let resp = self.client.request(req).await.ok()?;
+            if !resp.status().is_success() {
+                return None;
+            }
+            let body = hyper::body::to_bytes(resp.into_body()).await.ok()?;
+            serde_json::from_slice::<UserPresence>(&body).ok()
+        };
+        match tokio::time::timeout(Duration::from_secs(2), fetch).await {
+            Ok(Some(presence)) => Some(presence.online.contains(user_id)),
+            _ => {
+                tracing::warn!("presence lookup failed");
+                None
+            }
+        }
+    }
+
+    /// Tells the gateway `user_id`'s account was deleted, so it refuses
+    /// their tokens and closes their sockets. Failures are only logged;
+    /// the tokens then stop working when they expire.
+    pub async fn user_deleted(&self, user_id: &UserId) {
+        let body = serde_json::to_string(&UserDeleted { user_id: user_id.clone() }).unwrap();
+        let Ok(req) = Request::post(format!("{}/internal/user-deleted", self.base_url))
+            .header("x-internal-token", &self.token)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+        else {
+            return;
+        };
+
+        match tokio::time::timeout(Duration::from_secs(2), self.client.request(req)).await {
+            Ok(Ok(resp)) if resp.status().is_success() => {}
+            _ => tracing::warn!(user_id = %user_id, "telling the gateway about a deleted account failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_state;
+    use hyper::Method;
+
+    fn get(path: &str) -> Request<Body> {
+        Request::builder().method(Method::GET).uri(path).body(Body::empty()).unwrap()
+    }
+
+    async fn body_json(resp: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn returns_only_public_fields_without_a_token() {
+        let Some(state) = test_state().await else { return };
+        let user_id = UserId::new();
+        sqlx::query(
+            "INSERT INTO users (id, username, display_name, bio, avatar_version, is_admin, admin_notes)
+             VALUES ($1, $2, 'Ada', 'Counts things.', 'v1', true, 'private')",
+        )
+        .bind(&user_id)
+        .bind(format!("user-{}", user_id))
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        let path = format!("/users/{}/public-info", user_id);
+        let resp = crate::handle_request(state.clone(), get(&path)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let info = body_json(resp).await;
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "user_id": user_id.as_str(),
+                "username": format!("user-{}", user_id),
+                "display_name": "Ada",
+                "avatar_url": format!("/api/users/{}/avatar?size=256&v=v1", user_id),
+                "bio": "Counts things.",
+            })
+        );
+
+        // Served from the cache until it expires.
+        sqlx::query("UPDATE users SET display_name = 'Renamed' WHERE id = $1")
+            .bind(&user_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let info = body_json(crate::handle_request(state.clone(), get(&path)).await.unwrap()).await;
+        assert_eq!(info["display_name"], "Ada");
+
+        state.public_info.alter(user_id.as_str(), |_, (info, _)| (info, Instant::now() - CACHE_TTL));
+        let info = body_json(crate::handle_request(state.clone(), get(&path)).await.unwrap()).await;
+        assert_eq!(info["display_name"], "Renamed");
+    }
+
+    #[tokio::test]
+    async fn unknown_and_malformed_ids() {
+        let Some(state) = test_state().await else { return };
+
+        let path = format!("/users/{}/public-info", UserId::new());
+        let resp = crate::handle_request(state.clone(), get(&path)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = crate::handle_request(state, get("/users/alice/public-info")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
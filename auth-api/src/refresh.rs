@@ -0,0 +1,237 @@
+//! Refresh tokens: 32 random bytes handed out once at login and kept only
+//! as their SHA-256, so neither forging one nor reading the table yields a
+//! usable token. Signing a session out is deleting its row.
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::UserId;
+use uchat_proto::jwt::create_access_token;
+use uchat_proto::permissions::RoomPermissions;
+
+use crate::{account_refusal, db, json_error, json_ok, AppState};
+
+/// How long access tokens from `/login` and `/refresh` last.
+pub const ACCESS_TOKEN_TTL: Duration = Duration::minutes(15);
+/// How long a refresh token can be traded in, counted from login.
+const REFRESH_TOKEN_TTL: Duration = Duration::days(30);
+
+#[derive(Deserialize)]
+struct RefreshReq {
+    refresh_token: String,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Stores a new refresh token for `user_id` and returns it, base64url
+/// encoded. The token itself is not kept.
+pub async fn issue(pool: &PgPool, user_id: &UserId) -> Result<String, sqlx::Error> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).expect("system randomness");
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+
+    uchat_metrics::time_db_query("issue_refresh_token", async {
+        sqlx::query("INSERT INTO refresh_tokens (token_hash, user_id, expires_at) VALUES ($1, $2, $3)")
+            .bind(hash_token(&token))
+            .bind(user_id)
+            .bind(Utc::now() + REFRESH_TOKEN_TTL)
+            .execute(pool)
+            .await
+    })
+    .await?;
+    Ok(token)
+}
+
+/// The user an unexpired refresh token belongs to.
+async fn lookup(pool: &PgPool, token: &str) -> Result<Option<UserId>, sqlx::Error> {
+    uchat_metrics::time_db_query("lookup_refresh_token", async {
+        sqlx::query_scalar("SELECT user_id FROM refresh_tokens WHERE token_hash = $1 AND expires_at > now()")
+            .bind(hash_token(token))
+            .fetch_optional(pool)
+            .await
+    })
+    .await
+}
+
+/// A new access token for `user_id`, as `/login` and `/refresh` issue.
+pub async fn access_token(state: &AppState, user_id: &UserId, rooms: RoomPermissions) -> Result<String, sqlx::Error> {
+    let (admin, joined) = db::token_profile(&state.db, user_id).await?;
+    Ok(create_access_token(&state.jwt_secret, user_id.as_str(), rooms, ACCESS_TOKEN_TTL, admin, joined))
+}
+
+async fn parse(req: Request<Body>) -> Result<Result<RefreshReq, Response<Body>>, hyper::Error> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    Ok(serde_json::from_slice(&body)
+        .map_err(|_| json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")))
+}
+
+/// POST /refresh
+///
+/// Trades `{"refresh_token"}` for a new access token, as a `LoginOk`
+/// without a refresh token. Room permissions are read afresh, and
+/// suspended or deleted accounts are refused as at login.
+pub async fn handle_refresh(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let refresh = match parse(req).await? {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+
+    let user_id = match lookup(&state.db, &refresh.refresh_token).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            uchat_metrics::auth_failure("invalid_refresh_token");
+            return Ok(json_error(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "invalid refresh token"));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to look up refresh token");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+
+    if let Some(resp) = account_refusal(&state, &user_id).await {
+        return Ok(resp);
+    }
+    let rooms = match db::load_room_permissions(&state.db, &user_id).await {
+        Ok(rooms) => rooms,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load channel memberships");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+
+    let token = match access_token(&state, &user_id, rooms).await {
+        Ok(token) => token,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to load token profile");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+        }
+    };
+    let response = ServerEvent::LoginOk { token, refresh_token: None };
+    Ok(json_ok(serde_json::to_string(&response).unwrap()))
+}
+
+/// POST /logout
+///
+/// Deletes `{"refresh_token"}`, so it can no longer be traded in. Access
+/// tokens already issued lapse when they expire.
+pub async fn handle_logout(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let refresh = match parse(req).await? {
+        Ok(v) => v,
+        Err(resp) => return Ok(resp),
+    };
+
+    let deleted = uchat_metrics::time_db_query("revoke_refresh_token", async {
+        sqlx::query("DELETE FROM refresh_tokens WHERE token_hash = $1")
+            .bind(hash_token(&refresh.refresh_token))
+            .execute(&state.db)
+            .await
+    })
+    .await;
+    if let Err(e) = deleted {
+        tracing::error!(error = %e, "failed to revoke refresh token");
+        return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"));
+    }
+    Ok(Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handle_request, test_state};
+    use serde_json::{json, Value};
+    use uchat_proto::jwt::verify_claims;
+
+    async fn post(state: &Arc<AppState>, path: &str, body: Value) -> (StatusCode, Value) {
+        let req = Request::post(path).body(Body::from(body.to_string())).unwrap();
+        let resp = handle_request(state.clone(), req).await.unwrap();
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    async fn login(state: &Arc<AppState>) -> (UserId, String) {
+        let name = format!("user-{}", UserId::new());
+        let (status, body) = post(state, "/login", json!({ "username": name, "password": "x" })).await;
+        assert_eq!(status, StatusCode::OK);
+        let claims = verify_claims("test-secret", body["LoginOk"]["token"].as_str().unwrap()).unwrap();
+        assert!(claims.exp as i64 <= (Utc::now() + ACCESS_TOKEN_TTL).timestamp());
+        (claims.sub.parse().unwrap(), body["LoginOk"]["refresh_token"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_are_random_and_stored_hashed() {
+        let Some(state) = test_state().await else { return };
+        let (user_id, token) = login(&state).await;
+        let (_, other) = login(&state).await;
+        assert_ne!(token, other);
+        assert_eq!(URL_SAFE_NO_PAD.decode(&token).unwrap().len(), 32);
+
+        let stored: Vec<String> = sqlx::query_scalar("SELECT token_hash FROM refresh_tokens WHERE user_id = $1")
+            .bind(&user_id)
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(stored, [hash_token(&token)]);
+    }
+
+    #[tokio::test]
+    async fn refresh_issues_an_access_token_until_logout() {
+        let Some(state) = test_state().await else { return };
+        let (user_id, token) = login(&state).await;
+
+        let (status, body) = post(&state, "/refresh", json!({ "refresh_token": token })).await;
+        assert_eq!(status, StatusCode::OK);
+        let claims = verify_claims("test-secret", body["LoginOk"]["token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.sub, user_id.as_str());
+        assert_eq!(claims.role, None);
+        assert!(claims.joined.is_some_and(|joined| joined <= Utc::now().timestamp()));
+        assert!(body["LoginOk"].get("refresh_token").is_none());
+
+        // Admins are told apart by their tokens' role.
+        sqlx::query("UPDATE users SET is_admin = true WHERE id = $1").bind(&user_id).execute(&state.db).await.unwrap();
+        let (_, body) = post(&state, "/refresh", json!({ "refresh_token": token })).await;
+        let claims = verify_claims("test-secret", body["LoginOk"]["token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.role.as_deref(), Some(uchat_proto::jwt::ADMIN_ROLE));
+
+        let (status, _) = post(&state, "/logout", json!({ "refresh_token": token })).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, body) = post(&state, "/refresh", json!({ "refresh_token": token })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["Error"]["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_expired_and_suspended() {
+        let Some(state) = test_state().await else { return };
+        let (status, _) = post(&state, "/refresh", json!({ "refresh_token": "not-a-token" })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post(&state, "/refresh", json!({})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (user_id, token) = login(&state).await;
+        sqlx::query("UPDATE users SET suspended = true WHERE id = $1").bind(&user_id).execute(&state.db).await.unwrap();
+        let (status, body) = post(&state, "/refresh", json!({ "refresh_token": token })).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["Error"]["code"], "account_suspended");
+
+        sqlx::query("UPDATE users SET suspended = false WHERE id = $1").bind(&user_id).execute(&state.db).await.unwrap();
+        sqlx::query("UPDATE refresh_tokens SET expires_at = now() WHERE user_id = $1")
+            .bind(&user_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let (status, _) = post(&state, "/refresh", json!({ "refresh_token": token })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
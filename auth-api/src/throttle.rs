@@ -0,0 +1,138 @@
+//! Progressive delays on failed logins. Each refused login for a username
+//! doubles the wait before its next attempt is answered, from 200ms up to
+//! 30 seconds; one more failure after that locks the username for 15
+//! minutes. Waiting rather than locking straight away keeps a stranger
+//! from locking someone out with a handful of bad attempts.
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+const FIRST_DELAY: Duration = Duration::from_millis(200);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Usernames tracked before ones whose failures have lapsed are swept out.
+const MAX_TRACKED: usize = 10_000;
+
+/// A username's recent failed logins.
+pub struct FailureState {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailureState {
+    /// The wait before the next attempt is answered.
+    fn delay(&self) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(16);
+        (FIRST_DELAY * 2u32.pow(doublings)).min(MAX_DELAY)
+    }
+
+    /// When the failures are forgotten: the end of a lockout, or as long
+    /// after the last failure as a lockout would last.
+    fn resets_at(&self) -> Instant {
+        self.locked_until.unwrap_or(self.last_failure + LOCKOUT)
+    }
+}
+
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: DashMap<String, FailureState>,
+}
+
+impl LoginThrottle {
+    /// Waits out `username`'s delay, if it has one. `Err` while it is
+    /// locked, with how long is left.
+    pub async fn admit(&self, username: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let delay = {
+            let Some(state) = self.failures.get(username) else { return Ok(()) };
+            match state.locked_until {
+                _ if state.resets_at() <= now => None,
+                Some(until) => return Err(until - now),
+                None => Some(state.delay()),
+            }
+        };
+        match delay {
+            Some(delay) => tokio::time::sleep(delay).await,
+            None => {
+                self.failures.remove_if(username, |_, state| state.resets_at() <= now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a refused login for `username`, returning how long until its
+    /// failures are forgotten.
+    pub fn failed(&self, username: &str) -> Duration {
+        let now = Instant::now();
+        if self.failures.len() >= MAX_TRACKED {
+            self.failures.retain(|_, state| state.resets_at() > now);
+        }
+        let mut state = self.failures.entry(username.to_string()).or_insert(FailureState {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if state.resets_at() <= now {
+            *state = FailureState { failures: 0, last_failure: now, locked_until: None };
+        }
+        if state.failures > 0 && state.delay() >= MAX_DELAY {
+            state.locked_until = Some(now + LOCKOUT);
+        } else {
+            state.failures += 1;
+        }
+        state.last_failure = now;
+        state.resets_at() - now
+    }
+
+    /// Forgets `username`'s failures after a login goes through.
+    pub fn succeeded(&self, username: &str) {
+        self.failures.remove(username);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn waited(throttle: &LoginThrottle, username: &str) -> Result<Duration, Duration> {
+        let start = Instant::now();
+        throttle.admit(username).await?;
+        Ok(start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_double_up_to_a_lockout() {
+        let throttle = LoginThrottle::default();
+        assert_eq!(waited(&throttle, "mallory").await, Ok(Duration::ZERO));
+
+        let mut expected = FIRST_DELAY;
+        for _ in 0..9 {
+            throttle.failed("mallory");
+            assert_eq!(waited(&throttle, "mallory").await, Ok(expected));
+            expected = (expected * 2).min(MAX_DELAY);
+        }
+        assert_eq!(waited(&throttle, "alice").await, Ok(Duration::ZERO));
+
+        assert_eq!(throttle.failed("mallory"), LOCKOUT);
+        assert_eq!(waited(&throttle, "mallory").await, Err(LOCKOUT));
+        tokio::time::advance(LOCKOUT).await;
+        assert_eq!(waited(&throttle, "mallory").await, Ok(Duration::ZERO));
+        assert!(throttle.failures.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_lapse_and_success_clears_them() {
+        let throttle = LoginThrottle::default();
+        throttle.failed("mallory");
+        throttle.failed("mallory");
+        tokio::time::advance(LOCKOUT).await;
+        assert_eq!(throttle.failed("mallory"), LOCKOUT);
+        assert_eq!(waited(&throttle, "mallory").await, Ok(FIRST_DELAY));
+
+        throttle.succeeded("mallory");
+        assert_eq!(waited(&throttle, "mallory").await, Ok(Duration::ZERO));
+    }
+}
@@ -0,0 +1,520 @@
+//! Bulk user import for operators moving users over from an HR system:
+//! a CSV of `username,email,display_name,role` uploaded as
+//! `multipart/form-data`, registered as provisioned accounts that each
+//! user activates with their own one-time token.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+
+use futures_util::TryStreamExt;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use sqlx::{Postgres, Transaction};
+use tokio::sync::mpsc;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::users::{ImportedUser, UserImport, UserImportError, UserRole};
+
+use crate::admin::require_admin;
+use crate::{json_error, json_response, webhooks, AppState};
+
+/// Largest upload accepted.
+const MAX_IMPORT_BYTES: u64 = 10 * 1024 * 1024;
+/// Users parsed ahead of the database, and inserted per statement.
+const BATCH_SIZE: usize = 1000;
+/// Failed rows listed in the answer; `failed` counts them all.
+const MAX_REPORTED_ERRORS: usize = 100;
+const MAX_USERNAME_CHARS: usize = 64;
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+const MAX_EMAIL_LEN: usize = 254;
+
+#[derive(Deserialize, Default)]
+struct ImportQuery {
+    #[serde(default)]
+    fail_on_error: bool,
+}
+
+/// A row as written; empty cells are `None`.
+#[derive(Deserialize)]
+struct CsvRow {
+    username: String,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    role: Option<String>,
+}
+
+/// A row that passed validation.
+struct NewUser {
+    line: u64,
+    username: String,
+    email: Option<String>,
+    display_name: Option<String>,
+    role: UserRole,
+}
+
+/// POST /admin/users/import[?fail_on_error=true]
+///
+/// Takes the CSV in a form field named `file`, with a header row naming
+/// its columns; only `username` is required. Every valid row whose
+/// username is free is inserted in one transaction, and the answer is a
+/// `UserImport`. With `fail_on_error=true` a single bad or taken row
+/// rolls the whole import back, answered with 422. The CSV is parsed as
+/// it arrives and inserted a batch at a time, so a large file is never
+/// held in memory whole. The answer lists each imported account with the
+/// one-time activation token its owner sets a password with at
+/// `POST /activate`; no login claims one by its first password. Imported
+/// users are announced as `user.registered`.
+pub async fn handle_import(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if let Err(resp) = require_admin(&state, &req).await {
+        return Ok(resp);
+    }
+    let query: ImportQuery = match serde_urlencoded::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(query) => query,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid query")),
+    };
+    let field = match file_field(req).await {
+        Ok(field) => field,
+        Err(resp) => return Ok(resp),
+    };
+
+    // The parser blocks on the upload, so it gets a thread of its own and
+    // hands over a batch at a time.
+    let (batch_tx, mut batches) = mpsc::channel(1);
+    let upload = SyncIoBridge::new(StreamReader::new(field.map_err(io::Error::other)));
+    let parser = tokio::task::spawn_blocking(move || parse(upload, &batch_tx));
+
+    let mut report = UserImport::default();
+    let mut errors = Vec::new();
+    let mut tx = match state.db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return Ok(database_error(e)),
+    };
+    // Returning drops `batches`, which stops the parser.
+    while let Some(users) = batches.recv().await {
+        if let Err(e) = insert(&mut tx, &users, &mut report.users, &mut errors).await {
+            return Ok(database_error(e));
+        }
+    }
+    let parsed = match parser.await {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            tracing::error!(error = %e, "user import parser failed");
+            return Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "import failed"));
+        }
+    };
+    match parsed {
+        Ok(parse_errors) => errors.extend(parse_errors),
+        Err(e) => return Ok(upload_error(e)),
+    }
+    report.imported = report.users.len() as u64;
+
+    errors.sort_by_key(|e| e.line);
+    report.failed = errors.len() as u64;
+    report.errors = errors.into_iter().take(MAX_REPORTED_ERRORS).collect();
+    if query.fail_on_error && report.failed > 0 {
+        if let Err(e) = tx.rollback().await {
+            return Ok(database_error(e));
+        }
+        report.imported = 0;
+        report.users.clear();
+        report.rolled_back = true;
+        return Ok(json_response(StatusCode::UNPROCESSABLE_ENTITY, serde_json::to_string(&report).unwrap()));
+    }
+    if let Err(e) = tx.commit().await {
+        return Ok(database_error(e));
+    }
+
+    tracing::info!(imported = report.imported, failed = report.failed, "users imported");
+    let imported: Vec<UserId> = report.users.iter().map(|u| u.id.clone()).collect();
+    state.webhooks.notify_all(&state.db, webhooks::Event::Registered, &imported);
+    Ok(json_response(StatusCode::OK, serde_json::to_string(&report).unwrap()))
+}
+
+/// The `file` field, to be read as it arrives, or the response to send.
+async fn file_field(req: Request<Body>) -> Result<multer::Field<'static>, Response<Body>> {
+    let invalid = |msg: &str| json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, msg);
+    let boundary = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| multer::parse_boundary(v).ok())
+        .ok_or_else(|| invalid("expected multipart/form-data"))?;
+
+    let limits = multer::Constraints::new().size_limit(multer::SizeLimit::new().whole_stream(MAX_IMPORT_BYTES));
+    let mut multipart = multer::Multipart::with_constraints(req.into_body(), boundary, limits);
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        if field.name() == Some("file") {
+            return Ok(field);
+        }
+    }
+    Err(invalid("missing file field"))
+}
+
+fn multipart_error(e: multer::Error) -> Response<Body> {
+    match e {
+        multer::Error::StreamSizeExceeded { .. } => {
+            json_error(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, "import exceeds 10 MiB")
+        }
+        _ => json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid multipart body"),
+    }
+}
+
+/// The response for an upload that broke off while being parsed.
+fn upload_error(e: io::Error) -> Response<Body> {
+    match e.into_inner().map(|inner| inner.downcast::<multer::Error>()) {
+        Some(Ok(e)) => multipart_error(*e),
+        _ => json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid multipart body"),
+    }
+}
+
+/// Reads the CSV row by row, sending valid rows on in batches and
+/// returning the bad ones. `Err` when reading the upload failed.
+fn parse(csv: impl io::Read, batches: &mpsc::Sender<Vec<NewUser>>) -> io::Result<Vec<UserImportError>> {
+    let mut errors = Vec::new();
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(csv);
+    let headers = match reader.headers() {
+        Ok(headers) if headers.iter().any(|h| h == "username") => headers.clone(),
+        Err(e) if e.is_io_error() => return Err(into_io_error(e)),
+        _ => {
+            errors.push(UserImportError { line: 1, username: None, error: "header must name a username column".into() });
+            return Ok(errors);
+        }
+    };
+
+    let mut seen = HashSet::new();
+    let mut users = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) if e.is_io_error() => return Err(into_io_error(e)),
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                errors.push(UserImportError { line, username: None, error: e.to_string() });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line());
+        let row: CsvRow = match record.deserialize(Some(&headers)) {
+            Ok(row) => row,
+            Err(e) => {
+                errors.push(UserImportError { line, username: None, error: e.to_string() });
+                continue;
+            }
+        };
+        let username = row.username.clone();
+        match validate(row) {
+            Ok(user) if !seen.insert(user.username.clone()) => errors.push(UserImportError {
+                line,
+                username: Some(username),
+                error: "username appears earlier in the file".into(),
+            }),
+            Ok(user) => users.push(NewUser { line, ..user }),
+            Err(error) => errors.push(UserImportError { line, username: Some(username), error }),
+        }
+        if users.len() == BATCH_SIZE && batches.blocking_send(std::mem::take(&mut users)).is_err() {
+            // The import gave up.
+            return Ok(errors);
+        }
+    }
+    if !users.is_empty() {
+        let _ = batches.blocking_send(users);
+    }
+    Ok(errors)
+}
+
+fn into_io_error(e: csv::Error) -> io::Error {
+    match e.into_kind() {
+        csv::ErrorKind::Io(e) => e,
+        kind => io::Error::other(format!("{:?}", kind)),
+    }
+}
+
+fn validate(row: CsvRow) -> Result<NewUser, String> {
+    let username = row.username;
+    if username.is_empty() || username.chars().count() > MAX_USERNAME_CHARS {
+        return Err(format!("username must be 1-{} characters", MAX_USERNAME_CHARS));
+    }
+    if username.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("username must not contain spaces".into());
+    }
+    if row.email.as_deref().is_some_and(|email| !valid_email(email)) {
+        return Err("invalid email".into());
+    }
+    if row.display_name.as_deref().is_some_and(|name| name.chars().count() > MAX_DISPLAY_NAME_CHARS) {
+        return Err(format!("display_name must be at most {} characters", MAX_DISPLAY_NAME_CHARS));
+    }
+    let role = match row.role.as_deref() {
+        None => UserRole::User,
+        Some(role) => role.to_lowercase().parse()?,
+    };
+    Ok(NewUser { line: 0, username, email: row.email, display_name: row.display_name, role })
+}
+
+/// A single `local@domain` address, as channels-api accepts for
+/// notifications.
+fn valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else { return false };
+    email.len() <= MAX_EMAIL_LEN
+        && !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | ',' | ';' | '"'))
+        && !domain.contains('@')
+}
+
+/// Inserts a batch of `users` as provisioned accounts, each with its own
+/// activation token, adding them to `imported`; those whose username is
+/// already registered are skipped and recorded in `errors`.
+async fn insert(
+    tx: &mut Transaction<'_, Postgres>,
+    users: &[NewUser],
+    imported: &mut Vec<ImportedUser>,
+    errors: &mut Vec<UserImportError>,
+) -> Result<(), sqlx::Error> {
+    let ids: Vec<UserId> = users.iter().map(|_| UserId::new()).collect();
+    let mut tokens: HashMap<&str, String> = HashMap::new();
+    let mut token_hashes = Vec::new();
+    for user in users {
+        let (token, hash) = uchat_db::admin::activation_token();
+        tokens.insert(&user.username, token);
+        token_hashes.push(hash);
+    }
+    let usernames: Vec<&str> = users.iter().map(|u| u.username.as_str()).collect();
+    let emails: Vec<Option<&str>> = users.iter().map(|u| u.email.as_deref()).collect();
+    let display_names: Vec<Option<&str>> = users.iter().map(|u| u.display_name.as_deref()).collect();
+    let (admins, compliance): (Vec<bool>, Vec<bool>) = users.iter().map(|u| u.role.flags()).unzip();
+
+    let inserted: Vec<(UserId, String)> = sqlx::query_as(
+        "INSERT INTO users (id, username, email, display_name, is_admin, is_compliance, activation_token_hash,
+                            provisioned, activation_expires_at)
+         SELECT *, true, now() + make_interval(days => $8)
+         FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::bool[], $6::bool[], $7::text[])
+         ON CONFLICT (username) DO NOTHING
+         RETURNING id, username",
+    )
+    .bind(&ids)
+    .bind(&usernames)
+    .bind(&emails)
+    .bind(&display_names)
+    .bind(&admins)
+    .bind(&compliance)
+    .bind(&token_hashes)
+    .bind(uchat_db::admin::ACTIVATION_TTL_DAYS)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for (id, username) in inserted {
+        let activation_token = tokens.remove(username.as_str()).expect("a token for every row");
+        imported.push(ImportedUser { id, username, activation_token });
+    }
+    // Those left over were not inserted.
+    for user in users.iter().filter(|u| tokens.contains_key(u.username.as_str())) {
+        errors.push(UserImportError {
+            line: user.line,
+            username: Some(user.username.clone()),
+            error: "username taken".into(),
+        });
+    }
+    Ok(())
+}
+
+fn database_error(e: sqlx::Error) -> Response<Body> {
+    tracing::error!(error = %e, "user import failed");
+    json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, handle_request, test_state};
+    use hyper::Method;
+    use serde_json::Value;
+    use sqlx::PgPool;
+    use uchat_proto::jwt::create_token;
+
+    const BOUNDARY: &str = "uchat-import-boundary";
+
+    async fn user(pool: &PgPool, admin: bool) -> UserId {
+        let id = db::get_or_create_user(pool, &format!("user-{}", UserId::new())).await.unwrap().0;
+        sqlx::query("UPDATE users SET is_admin = $2 WHERE id = $1").bind(&id).bind(admin).execute(pool).await.unwrap();
+        id
+    }
+
+    async fn import(state: &Arc<AppState>, caller: &UserId, query: &str, csv: &str) -> (StatusCode, Value) {
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"users.csv\"\r\n\
+             Content-Type: text/csv\r\n\r\n{csv}\r\n--{b}--\r\n",
+            b = BOUNDARY
+        );
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/admin/users/import{}", query))
+            .header("Authorization", format!("Bearer {}", create_token(&state.jwt_secret, caller.as_str())))
+            .header(CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(Body::from(body))
+            .unwrap();
+        let resp = handle_request(state.clone(), req).await.unwrap();
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    async fn registered(pool: &PgPool, username: &str) -> Option<(Option<String>, Option<String>, bool)> {
+        sqlx::query_as("SELECT email, display_name, is_compliance FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn imports_valid_rows_and_reports_the_rest() {
+        let Some(state) = test_state().await else { return };
+        let admin = user(&state.db, true).await;
+        let someone = user(&state.db, false).await;
+        let taken = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE id = $1")
+            .bind(&someone)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        let tag = UserId::new();
+        let csv = format!(
+            "username,email,display_name,role\n\
+             ada-{tag},ada@example.com,Ada Lovelace,compliance\n\
+             bob-{tag},,,\n\
+             eve-{tag},not-an-email,Eve,user\n\
+             ada-{tag},ada2@example.com,Ada Again,user\n\
+             {taken},x@example.com,Taken,user\n\
+             kim-{tag},kim@example.com,Kim,owner\n"
+        );
+
+        let (status, _) = import(&state, &someone, "", &csv).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // One bad row undoes everything when asked to.
+        let (status, report) = import(&state, &admin, "?fail_on_error=true", &csv).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!((report["imported"].as_u64(), report["failed"].as_u64()), (Some(0), Some(4)));
+        assert_eq!(report["rolled_back"], true);
+        assert!(registered(&state.db, &format!("ada-{}", tag)).await.is_none());
+
+        let (status, report) = import(&state, &admin, "", &csv).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((report["imported"].as_u64(), report["failed"].as_u64()), (Some(2), Some(4)));
+        let lines: Vec<(u64, &str)> = report["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["line"].as_u64().unwrap(), e["error"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (4, "invalid email"),
+                (5, "username appears earlier in the file"),
+                (6, "username taken"),
+                (7, "unknown user role \"owner\""),
+            ]
+        );
+        assert_eq!(
+            registered(&state.db, &format!("ada-{}", tag)).await,
+            Some((Some("ada@example.com".into()), Some("Ada Lovelace".into()), true))
+        );
+        assert_eq!(registered(&state.db, &format!("bob-{}", tag)).await, Some((None, None, false)));
+
+        // Nobody claims an imported account but the holder of its token.
+        let ada = format!("ada-{}", tag);
+        let users = report["users"].as_array().unwrap();
+        assert_eq!(users.len(), 2);
+        let token = users.iter().find(|u| u["username"] == ada.as_str()).unwrap()["activation_token"].clone();
+        let send = |path: &str, body: Value| {
+            let req = Request::post(path).body(Body::from(body.to_string())).unwrap();
+            handle_request(state.clone(), req)
+        };
+        let login = serde_json::json!({ "username": ada, "password": "first!" });
+        assert_eq!(send("/login", login.clone()).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        let activate = serde_json::json!({ "token": token, "password": "first!" });
+        assert_eq!(send("/activate", activate).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(send("/login", login).await.unwrap().status(), StatusCode::OK);
+
+        let (status, report) = import(&state, &admin, "", "name,email\nzed,zed@example.com\n").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["errors"][0]["error"], "header must name a username column");
+    }
+
+    #[tokio::test]
+    async fn reads_uploads_as_they_arrive_and_announces_the_users() {
+        let Some(state) = test_state().await else { return };
+        let (url, mut deliveries) = crate::webhooks::tests::receiver().await;
+        let admin = user(&state.db, true).await;
+        let subscribe = serde_json::json!({ "url": url, "events": ["user.registered"], "secret": "hush" });
+        let (status, webhook) = crate::webhooks::tests::post(&state, "/admin/webhooks", Some(&admin), subscribe).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // Rows split across chunks, quoted newlines included.
+        let tag = UserId::new();
+        let csv = format!("username,display_name\nana-{tag},\"Ana\nB\"\nraj-{tag},Raj\n");
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"users.csv\"\r\n\r\n{csv}\r\n--{b}--\r\n",
+            b = BOUNDARY
+        );
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = body.into_bytes().chunks(7).map(|c| Ok(c.to_vec())).collect();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/admin/users/import")
+            .header("Authorization", format!("Bearer {}", create_token(&state.jwt_secret, admin.as_str())))
+            .header(CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(Body::wrap_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+        let resp = handle_request(state.clone(), req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let report: Value = serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap();
+        assert_eq!((report["imported"].as_u64(), report["failed"].as_u64()), (Some(2), Some(0)));
+        assert_eq!(registered(&state.db, &format!("ana-{}", tag)).await, Some((None, Some("Ana\nB".into()), false)));
+
+        // Without waiting for a first login; other tests register users too.
+        let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM users WHERE username = ANY($1)")
+            .bind([format!("ana-{}", tag), format!("raj-{}", tag)])
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        let mut announced = HashSet::new();
+        while announced.len() < ids.len() {
+            let (_, event, body) = deliveries.recv().await.unwrap();
+            let payload: Value = serde_json::from_slice(&body).unwrap();
+            if ids.iter().any(|id| payload["user_id"] == id.as_str()) {
+                assert_eq!(event, "user.registered");
+                announced.insert(payload["user_id"].to_string());
+            }
+        }
+
+        sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(webhook["id"].as_str().unwrap())
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_bodies_that_are_not_a_file_upload() {
+        let Some(state) = test_state().await else { return };
+        let admin = user(&state.db, true).await;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/admin/users/import")
+            .header("Authorization", format!("Bearer {}", create_token(&state.jwt_secret, admin.as_str())))
+            .header(CONTENT_TYPE, "text/csv")
+            .body(Body::from("username\nsomeone\n"))
+            .unwrap();
+        let resp = handle_request(state.clone(), req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,367 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::{UserId, WebhookId};
+use uchat_proto::signing;
+
+use crate::admin::require_admin;
+use crate::{json_error, json_response, AppState};
+
+/// `sha256=` and the hex HMAC-SHA256 of the body, keyed with the
+/// webhook's secret.
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+pub const EVENT_HEADER: &str = "X-Uchat-Event";
+/// Tries per delivery; the wait between them starts at the notifier's
+/// backoff and doubles.
+const ATTEMPTS: u32 = 3;
+
+/// A user lifecycle event webhooks can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Event {
+    /// The first login of a username, which creates the account, or its
+    /// import.
+    #[serde(rename = "user.registered")]
+    Registered,
+    #[serde(rename = "user.login")]
+    Login,
+    #[serde(rename = "user.deleted")]
+    Deleted,
+}
+
+impl Event {
+    const ALL: [Event; 3] = [Event::Registered, Event::Login, Event::Deleted];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Event::Registered => "user.registered",
+            Event::Login => "user.login",
+            Event::Deleted => "user.deleted",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.as_str() == name)
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateWebhookReq {
+    url: String,
+    events: Vec<String>,
+    secret: String,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct Webhook {
+    id: WebhookId,
+    url: String,
+    events: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+/// POST /admin/webhooks
+///
+/// Registers `url` for the listed events. The secret signs deliveries and
+/// is never returned.
+pub async fn handle_create(state: Arc<AppState>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let admin = match require_admin(&state, &req).await {
+        Ok(admin) => admin,
+        Err(resp) => return Ok(resp),
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let create: CreateWebhookReq = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid json")),
+    };
+    if !reqwest::Url::parse(&create.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "invalid url"));
+    }
+    if create.secret.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "secret required"));
+    }
+    let mut events = Vec::new();
+    for name in &create.events {
+        let Some(event) = Event::parse(name) else {
+            return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "unknown event"));
+        };
+        if !events.contains(&event.as_str()) {
+            events.push(event.as_str());
+        }
+    }
+    if events.is_empty() {
+        return Ok(json_error(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "events required"));
+    }
+
+    let result = sqlx::query_as::<_, Webhook>(
+        "INSERT INTO webhooks (id, url, events, secret, created_by) VALUES ($1, $2, $3, $4, $5)
+         RETURNING id, url, events, created_at",
+    )
+    .bind(WebhookId::new())
+    .bind(&create.url)
+    .bind(&events)
+    .bind(&create.secret)
+    .bind(&admin)
+    .fetch_one(&state.db)
+    .await;
+
+    match result {
+        Ok(webhook) => {
+            tracing::info!(webhook_id = %webhook.id, url = %webhook.url, "webhook registered");
+            Ok(json_response(StatusCode::CREATED, serde_json::to_string(&webhook).unwrap()))
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "failed to register webhook");
+            Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "database error"))
+        }
+    }
+}
+
+/// What a delivery's body holds.
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: Event,
+    user_id: &'a UserId,
+    occurred_at: DateTime<Utc>,
+}
+
+/// Delivers lifecycle events to the webhooks subscribed to them.
+#[derive(Clone)]
+pub struct Notifier {
+    client: reqwest::Client,
+    backoff: Duration,
+}
+
+impl Notifier {
+    pub fn new(backoff: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("build webhook client");
+        Self { client, backoff }
+    }
+
+    /// POSTs `event` to each webhook subscribed to it, in the background.
+    /// Deliveries are independent, so a receiver may see a user's
+    /// `user.login` before their `user.registered`.
+    pub fn notify(&self, pool: &PgPool, event: Event, user_id: &UserId) {
+        self.notify_all(pool, event, std::slice::from_ref(user_id));
+    }
+
+    /// `notify` for each of `user_ids`, as for an import. Each webhook
+    /// gets them one after another.
+    pub fn notify_all(&self, pool: &PgPool, event: Event, user_ids: &[UserId]) {
+        if user_ids.is_empty() {
+            return;
+        }
+        let occurred_at = Utc::now();
+        let bodies: Arc<[String]> = user_ids
+            .iter()
+            .map(|user_id| serde_json::to_string(&Payload { event, user_id, occurred_at }).unwrap())
+            .collect();
+        let (notifier, pool) = (self.clone(), pool.clone());
+
+        uchat_metrics::spawn_task("webhook_fanout", async move {
+            let webhooks = match subscribed(&pool, event).await {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    tracing::error!(error = %e, event = event.as_str(), "failed to load webhooks");
+                    return;
+                }
+            };
+            for (url, secret) in webhooks {
+                let (notifier, bodies) = (notifier.clone(), bodies.clone());
+                uchat_metrics::spawn_task("webhook_delivery", async move {
+                    for body in bodies.iter() {
+                        notifier.deliver(&url, &secret, event, body.clone()).await;
+                    }
+                });
+            }
+        });
+    }
+
+    /// Sends one delivery, retrying failures and non-2xx answers. `false`
+    /// once every attempt has failed.
+    async fn deliver(&self, url: &str, secret: &str, event: Event, body: String) -> bool {
+        let signature = sign(secret, body.as_bytes());
+        let mut delay = self.backoff;
+        for attempt in 1..=ATTEMPTS {
+            let sent = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(EVENT_HEADER, event.as_str())
+                .body(body.clone())
+                .send()
+                .await;
+            match sent {
+                Ok(resp) if resp.status().is_success() => return true,
+                Ok(resp) => tracing::warn!(url, attempt, status = %resp.status(), "webhook delivery refused"),
+                Err(e) => tracing::warn!(url, attempt, error = %e, "webhook delivery failed"),
+            }
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        tracing::error!(url, event = event.as_str(), "giving up on webhook delivery");
+        false
+    }
+}
+
+async fn subscribed(pool: &PgPool, event: Event) -> Result<Vec<(String, String)>, sqlx::Error> {
+    uchat_metrics::time_db_query("subscribed_webhooks", async {
+        sqlx::query_as("SELECT url, secret FROM webhooks WHERE $1 = ANY(events)")
+            .bind(event.as_str())
+            .fetch_all(pool)
+            .await
+    })
+    .await
+}
+
+/// The `X-Hub-Signature-256` value for `payload`.
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    format!("sha256={}", signing::sign(secret.as_bytes(), payload))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_state;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+    use uchat_proto::jwt::create_token;
+
+    #[test]
+    fn signs_like_github() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    /// A receiver that refuses the first delivery of each body and passes
+    /// on every attempt as (signature, event header, body).
+    pub(crate) async fn receiver() -> (String, mpsc::UnboundedReceiver<(String, String, Vec<u8>)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let make_svc = make_service_fn(move |_conn| {
+            let (tx, seen) = (tx.clone(), seen.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let (tx, seen) = (tx.clone(), seen.clone());
+                    async move {
+                        let header = |name| req.headers()[name].to_str().unwrap().to_string();
+                        let (signature, event) = (header(SIGNATURE_HEADER), header(EVENT_HEADER));
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap().to_vec();
+                        let first = seen.lock().unwrap().insert(body.clone());
+                        let _ = tx.send((signature, event, body));
+                        let status = if first { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK };
+                        Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    async fn admin(pool: &PgPool) -> UserId {
+        let (id, _) = crate::db::get_or_create_user(pool, &format!("admin-{}", UserId::new())).await.unwrap();
+        sqlx::query("UPDATE users SET is_admin = true WHERE id = $1").bind(&id).execute(pool).await.unwrap();
+        id
+    }
+
+    pub(crate) async fn post(state: &Arc<AppState>, path: &str, caller: Option<&UserId>, body: Value) -> (StatusCode, Value) {
+        let mut builder = Request::post(path);
+        if let Some(caller) = caller {
+            let token = create_token(&state.jwt_secret, caller.as_str());
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        let resp = crate::handle_request(state.clone(), builder.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn only_admins_register_valid_webhooks() {
+        let Some(state) = test_state().await else { return };
+        let valid = json!({ "url": "https://example.com/hook", "events": ["user.login"], "secret": "s" });
+
+        let (status, _) = post(&state, "/admin/webhooks", None, valid.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (someone, _) = crate::db::get_or_create_user(&state.db, &format!("user-{}", UserId::new())).await.unwrap();
+        let (status, _) = post(&state, "/admin/webhooks", Some(&someone), valid).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let admin = admin(&state.db).await;
+        for (body, error) in [
+            (json!({ "url": "ftp://example.com", "events": ["user.login"], "secret": "s" }), "invalid url"),
+            (json!({ "url": "https://example.com", "events": ["user.renamed"], "secret": "s" }), "unknown event"),
+            (json!({ "url": "https://example.com", "events": [], "secret": "s" }), "events required"),
+            (json!({ "url": "https://example.com", "events": ["user.login"], "secret": "" }), "secret required"),
+        ] {
+            let (status, resp) = post(&state, "/admin/webhooks", Some(&admin), body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(resp["Error"]["code"], "invalid_request");
+            assert_eq!(resp["Error"]["message"], error);
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events_with_retries() {
+        let Some(state) = test_state().await else { return };
+        let (url, mut rx) = receiver().await;
+        let admin = admin(&state.db).await;
+
+        let body = json!({ "url": url, "events": ["user.registered", "user.registered"], "secret": "hush" });
+        let (status, webhook) = post(&state, "/admin/webhooks", Some(&admin), body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(webhook["events"], json!(["user.registered"]));
+        assert!(webhook.get("secret").is_none());
+
+        let name = format!("user-{}", UserId::new());
+        let (status, _) = post(&state, "/login", None, json!({ "username": name, "password": "x" })).await;
+        assert_eq!(status, StatusCode::OK);
+        let user_id: String = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(&name)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+
+        // Other tests register users too; find this one's deliveries.
+        let mut attempts = Vec::new();
+        while attempts.len() < 2 {
+            let (signature, event, body) = rx.recv().await.unwrap();
+            let payload: Value = serde_json::from_slice(&body).unwrap();
+            if payload["user_id"] == user_id.as_str() {
+                assert_eq!(signature, sign("hush", &body));
+                assert_eq!(event, "user.registered");
+                assert_eq!(payload["event"], "user.registered");
+                attempts.push(body);
+            }
+        }
+        assert_eq!(attempts[0], attempts[1]);
+
+        sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(webhook["id"].as_str().unwrap())
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+}
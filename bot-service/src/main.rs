@@ -1,4 +1,3 @@
-use proto::Envelope;
 use tokio_tungstenite::connect_async;
 use tokio::time::{sleep, Duration};
 
@@ -6,7 +5,7 @@ use tokio::time::{sleep, Duration};
 async fn main() {
     println!("Bot Service starting...");
 
-    let mut ws = loop {
+    let _ws = loop {
         match connect_async("ws://127.0.0.1:9000/ws").await {
             Ok((ws, _)) => {
                 println!("Bot Service connected to Gateway");
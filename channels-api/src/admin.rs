@@ -0,0 +1,191 @@
+//! Channel management for operators, users with `users.is_admin`, who
+//! need not be members of the channels they manage.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::PgPool;
+
+use uchat_proto::channels::{Channel, MembershipChange};
+use uchat_proto::ids::{ChannelId, UserId};
+
+use crate::auth::AuthUser;
+use crate::channels::{all_channels, change_archived, find_channel, parse_channel_id};
+use crate::error::AppError;
+use crate::{retention, AppState};
+
+/// What `DELETE /api/admin/channels/{id}` removed.
+#[derive(Debug, Serialize)]
+pub struct ChannelPurged {
+    pub channel_id: ChannelId,
+    pub members: u64,
+    pub messages: u64,
+    pub files: u64,
+}
+
+pub async fn require_admin(db: &PgPool, user: &AuthUser) -> Result<(), AppError> {
+    let admin: Option<bool> = sqlx::query_scalar("SELECT is_admin FROM users WHERE id = $1")
+        .bind(&user.user_id)
+        .fetch_optional(db)
+        .await?;
+    if admin != Some(true) {
+        return Err(AppError::forbidden());
+    }
+    Ok(())
+}
+
+/// GET /api/admin/channels
+pub async fn list_channels(State(state): State<Arc<AppState>>, user: AuthUser) -> Result<Json<Vec<Channel>>, AppError> {
+    require_admin(&state.db, &user).await?;
+    Ok(Json(all_channels(&state.db).await?))
+}
+
+/// POST /api/admin/channels/{id}/archive
+pub async fn archive_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Channel>, AppError> {
+    require_admin(&state.db, &user).await?;
+    let channel_id = parse_channel_id(&id)?;
+    find_channel(&state.db, &channel_id).await?;
+    Ok(Json(change_archived(&state, &channel_id, true).await?))
+}
+
+/// DELETE /api/admin/channels/{id}
+///
+/// Deletes the channel and everything in it: members (whose sockets leave
+/// the room), messages, files in storage, invites, incoming hooks, content
+/// filter rules and read markers. The audit trails are kept.
+pub async fn purge_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<ChannelPurged>, AppError> {
+    require_admin(&state.db, &user).await?;
+    let channel_id = parse_channel_id(&id)?;
+    find_channel(&state.db, &channel_id).await?;
+
+    let members: Vec<UserId> = sqlx::query_scalar("DELETE FROM channel_members WHERE channel_id = $1 RETURNING user_id")
+        .bind(&channel_id)
+        .fetch_all(&state.db)
+        .await?;
+    if let Some(gateway) = &state.gateway {
+        for user_id in &members {
+            let change = MembershipChange { channel_id: channel_id.clone(), user_id: user_id.clone(), role: None };
+            gateway.membership_changed(&change).await;
+        }
+    }
+
+    // Later than anything posted while the purge runs.
+    let cutoff = Utc::now() + chrono::Duration::days(1);
+    let stats = retention::purge_channel(&state, &channel_id, cutoff, retention::batch_size_from_env()).await?;
+
+    let mut tx = state.db.begin().await?;
+    for table in [
+        "channel_invites",
+        "incoming_webhooks",
+        "content_filter_rules",
+        "scheduled_messages",
+        "channel_read_markers",
+        "channel_members",
+    ] {
+        sqlx::query(&format!("DELETE FROM {} WHERE channel_id = $1", table))
+            .bind(&channel_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("DELETE FROM channels WHERE id = $1").bind(&channel_id).execute(&mut *tx).await?;
+    tx.commit().await?;
+
+    tracing::info!(channel_id = %channel_id, purged_by = %user.user_id, messages = stats.messages, "channel purged");
+    Ok(Json(ChannelPurged {
+        channel_id,
+        members: members.len() as u64,
+        messages: stats.messages,
+        files: stats.files,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::messages::tests::insert;
+    use crate::retention::tests::insert_file;
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    async fn admin(db: &PgPool) -> UserId {
+        let id = UserId::new();
+        sqlx::query("INSERT INTO users (id, username, is_admin) VALUES ($1, $2, true)")
+            .bind(&id)
+            .bind(format!("admin-{}", id))
+            .execute(db)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn only_site_admins_manage_channels() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "admin-private", "private").await;
+
+        let (status, _) = call(&state, Method::GET, "/api/admin/channels", Some(&owner), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&state, Method::DELETE, &format!("/api/admin/channels/{}", channel), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let admin = admin(&state.db).await;
+        let (status, channels) = call(&state, Method::GET, "/api/admin/channels", Some(&admin), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(channels.as_array().unwrap().iter().any(|c| c["id"] == channel.as_str()));
+
+        let uri = format!("/api/admin/channels/{}/archive", channel);
+        let (status, body) = call(&state, Method::POST, &uri, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["archived_at"].is_string());
+        let (status, again) = call(&state, Method::POST, &uri, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again["archived_at"], body["archived_at"]);
+    }
+
+    #[tokio::test]
+    async fn purging_removes_the_channel_and_its_content() {
+        let Some(state) = test_state().await else { return };
+        let (owner, member) = (UserId::new(), UserId::new());
+        let channel = create(&state, &owner, "admin-purge", "public").await;
+        let uri = format!("/api/channels/{}/members", channel);
+        call(&state, Method::POST, &uri, Some(&owner), Some(json!({ "user_id": member, "role": "write" }))).await;
+        insert(&state.db, &channel, &owner, "hello", Utc::now()).await;
+        let path = insert_file(&state, &channel, &owner, Utc::now()).await;
+
+        let admin = admin(&state.db).await;
+        let (status, body) =
+            call(&state, Method::DELETE, &format!("/api/admin/channels/{}", channel), Some(&admin), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["members"], 2);
+        assert_eq!(body["messages"], 1);
+        assert_eq!(body["files"], 1);
+        assert!(state.storage.get(&path, None).await.is_err());
+
+        for table in ["messages", "file_uploads", "channel_members"] {
+            let left: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {} WHERE channel_id = $1", table))
+                .bind(&channel)
+                .fetch_one(&state.db)
+                .await
+                .unwrap();
+            assert_eq!(left, 0, "{}", table);
+        }
+        let (status, _) = call(&state, Method::GET, &format!("/api/channels/{}", channel), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
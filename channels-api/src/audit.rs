@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+
+use uchat_proto::audit::{MessageAction, MessageAuditEntry};
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::{ChannelId, MessageId, UserId};
+use uchat_proto::messages::Page;
+
+use crate::auth::AuthUser;
+use crate::channels::parse_channel_id;
+use crate::error::AppError;
+use crate::AppState;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 500;
+
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    id: i64,
+    message_id: MessageId,
+    channel_id: ChannelId,
+    actor_id: UserId,
+    action: String,
+    before_hash: Option<String>,
+    after_hash: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl TryFrom<AuditRow> for MessageAuditEntry {
+    type Error = AppError;
+
+    fn try_from(row: AuditRow) -> Result<Self, AppError> {
+        let action = row.action.parse().map_err(|e: String| {
+            tracing::warn!("bad audit row {}: {}", row.id, e);
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "corrupt audit row")
+        })?;
+        Ok(MessageAuditEntry {
+            id: row.id,
+            message_id: row.message_id,
+            channel_id: row.channel_id,
+            actor_id: row.actor_id,
+            action,
+            before_hash: row.before_hash,
+            after_hash: row.after_hash,
+            created_at: row.created_at,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    message_id: Option<String>,
+    channel_id: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+    /// `next_cursor` of the previous page.
+    before: Option<String>,
+}
+
+async fn is_compliance(db: &PgPool, user_id: &UserId) -> Result<bool, AppError> {
+    let flag: Option<bool> = sqlx::query_scalar("SELECT is_compliance FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?;
+    Ok(flag.unwrap_or(false))
+}
+
+/// GET /api/admin/audit/messages
+///
+/// Read-only view of the message audit trail, newest first, for users
+/// with `users.is_compliance`.
+pub async fn list_message_audit(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Page<MessageAuditEntry>>, AppError> {
+    if !is_compliance(&state.db, &user.user_id).await? {
+        return Err(AppError::forbidden());
+    }
+
+    let message_id = query
+        .message_id
+        .as_deref()
+        .map(|s| s.parse::<MessageId>().map_err(|_| AppError::invalid("invalid message_id")))
+        .transpose()?;
+    let channel_id = query.channel_id.as_deref().map(parse_channel_id).transpose()?;
+    let actor = query
+        .actor
+        .as_deref()
+        .map(|s| s.parse::<UserId>().map_err(|_| AppError::invalid("invalid actor")))
+        .transpose()?;
+    let action = query
+        .action
+        .as_deref()
+        .map(|s| s.parse::<MessageAction>().map_err(AppError::invalid))
+        .transpose()?;
+    let before = query
+        .before
+        .as_deref()
+        .map(|s| s.parse::<i64>().map_err(|_| AppError::invalid("invalid cursor")))
+        .transpose()?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT id, message_id, channel_id, actor_id, action, before_hash, after_hash, created_at
+         FROM message_audit WHERE true",
+    );
+    if let Some(message_id) = &message_id {
+        qb.push(" AND message_id = ").push_bind(message_id);
+    }
+    if let Some(channel_id) = &channel_id {
+        qb.push(" AND channel_id = ").push_bind(channel_id);
+    }
+    if let Some(actor) = &actor {
+        qb.push(" AND actor_id = ").push_bind(actor);
+    }
+    if let Some(action) = action {
+        qb.push(" AND action = ").push_bind(action.as_str());
+    }
+    if let Some(from) = query.from {
+        qb.push(" AND created_at >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+        qb.push(" AND created_at < ").push_bind(to);
+    }
+    if let Some(before) = before {
+        qb.push(" AND id < ").push_bind(before);
+    }
+    qb.push(" ORDER BY id DESC LIMIT ");
+    // One extra row tells us whether another page exists.
+    qb.push_bind(limit + 1);
+
+    let rows: Vec<AuditRow> = qb.build_query_as().fetch_all(&state.db).await?;
+
+    let has_more = rows.len() as i64 > limit;
+    let items = rows
+        .into_iter()
+        .take(limit as usize)
+        .map(MessageAuditEntry::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    let next_cursor = if has_more { items.last().map(|e| e.id.to_string()) } else { None };
+
+    Ok(Json(Page { items, next_cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::call;
+    use crate::test_state;
+    use axum::http::Method;
+    use uchat_db::audit::{content_hash, record_message, MessageAudit};
+
+    async fn compliance_user(db: &PgPool) -> UserId {
+        let id = UserId::new();
+        sqlx::query("INSERT INTO users (id, username, is_compliance) VALUES ($1, $2, true)")
+            .bind(&id)
+            .bind(format!("compliance-{}", id))
+            .execute(db)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn trail_survives_message_deletion() {
+        let Some(state) = test_state().await else { return };
+        let officer = compliance_user(&state.db).await;
+        let sender = UserId::new();
+        let channel_id = ChannelId::new();
+        let message_id = MessageId::new();
+
+        let mut tx = state.db.begin().await.unwrap();
+        sqlx::query("INSERT INTO messages (id, channel_id, sender_id, content) VALUES ($1, $2, $3, 'hello')")
+            .bind(&message_id)
+            .bind(&channel_id)
+            .bind(&sender)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let entry = |action, before, after| MessageAudit {
+            message_id: &message_id,
+            channel_id: &channel_id,
+            actor_id: &sender,
+            action,
+            before,
+            after,
+        };
+        record_message(&mut tx, entry(MessageAction::Created, None, Some("hello"))).await.unwrap();
+        record_message(&mut tx, entry(MessageAction::Edited, Some("hello"), Some("hi"))).await.unwrap();
+        tx.commit().await.unwrap();
+
+        sqlx::query("DELETE FROM messages WHERE id = $1").bind(&message_id).execute(&state.db).await.unwrap();
+
+        let uri = format!("/api/admin/audit/messages?message_id={}", message_id);
+        let (status, page) = call(&state, Method::GET, &uri, Some(&officer), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let items = page["items"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0]["action"], "edited");
+        assert_eq!(items[0]["before_hash"], content_hash("hello"));
+        assert_eq!(items[0]["after_hash"], content_hash("hi"));
+        assert_eq!(items[1]["action"], "created");
+        assert!(items[1]["before_hash"].is_null());
+
+        let (_, page) = call(&state, Method::GET, &format!("{}&action=created&limit=1", uri), Some(&officer), None).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert!(page["next_cursor"].is_null());
+
+        let (_, page) = call(&state, Method::GET, &format!("{}&limit=1", uri), Some(&officer), None).await;
+        let cursor = page["next_cursor"].as_str().unwrap();
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}&limit=1&before={}", uri, cursor), Some(&officer), None).await;
+        assert_eq!(page["items"][0]["action"], "created");
+    }
+
+    #[tokio::test]
+    async fn rolled_back_mutation_leaves_no_entry() {
+        let Some(state) = test_state().await else { return };
+        let officer = compliance_user(&state.db).await;
+        let message_id = MessageId::new();
+
+        let mut tx = state.db.begin().await.unwrap();
+        let entry = MessageAudit {
+            message_id: &message_id,
+            channel_id: &ChannelId::new(),
+            actor_id: &UserId::new(),
+            action: MessageAction::Deleted,
+            before: Some("gone"),
+            after: None,
+        };
+        record_message(&mut tx, entry).await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let uri = format!("/api/admin/audit/messages?message_id={}", message_id);
+        let (_, page) = call(&state, Method::GET, &uri, Some(&officer), None).await;
+        assert!(page["items"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn compliance_only() {
+        let Some(state) = test_state().await else { return };
+        let uri = "/api/admin/audit/messages";
+
+        let (status, _) = call(&state, Method::GET, uri, None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&state, Method::GET, uri, Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let officer = compliance_user(&state.db).await;
+        let (status, _) = call(&state, Method::GET, &format!("{}?action=read", uri), Some(&officer), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
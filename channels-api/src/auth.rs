@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::jwt::verify_claims;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// Caller identified by a valid `Authorization: Bearer` token whose
+/// account hasn't been deleted since it was issued.
+pub struct AuthUser {
+    pub user_id: UserId,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, AppError> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(AppError::unauthorized)?;
+
+        let claims = verify_claims(&state.jwt_secret, token).ok_or_else(AppError::unauthorized)?;
+        let user_id: UserId = claims.sub.parse().map_err(|_| AppError::unauthorized())?;
+
+        let deleted: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NOT NULL)")
+                .bind(&user_id)
+                .fetch_one(&state.db)
+                .await?;
+        if deleted {
+            return Err(AppError::new(StatusCode::UNAUTHORIZED, ErrorCode::AccountDeleted, "account deleted"));
+        }
+
+        Ok(AuthUser { user_id })
+    }
+}
@@ -0,0 +1,762 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use uchat_proto::channels::{
+    Channel, ChannelArchiveChanged, ChannelCreated, ChannelType, CreateChannel, MemberRole, MembershipChange,
+    UpdateChannel,
+};
+use uchat_proto::ids::{ChannelId, UserId};
+
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::AppState;
+
+const MAX_NAME_LEN: usize = 80;
+/// Members a channel can be created with; more are added afterwards.
+const MAX_INITIAL_MEMBERS: usize = 500;
+
+#[derive(sqlx::FromRow)]
+struct ChannelRow {
+    id: ChannelId,
+    name: String,
+    description: String,
+    channel_type: String,
+    created_by: UserId,
+    created_at: DateTime<Utc>,
+    restrict_file_types: bool,
+    retention_days: Option<i32>,
+    archived_at: Option<DateTime<Utc>>,
+    allow_markdown_formatting: bool,
+    link_previews: bool,
+    e2ee_required: bool,
+}
+
+impl From<ChannelRow> for Channel {
+    fn from(row: ChannelRow) -> Self {
+        Channel {
+            id: row.id,
+            name: row.name,
+            description: row.description,
+            channel_type: row.channel_type.parse().unwrap_or_default(),
+            created_by: row.created_by,
+            created_at: row.created_at,
+            restrict_file_types: row.restrict_file_types,
+            retention_days: row.retention_days,
+            archived_at: row.archived_at,
+            allow_markdown_formatting: row.allow_markdown_formatting,
+            link_previews: row.link_previews,
+            e2ee_required: row.e2ee_required,
+        }
+    }
+}
+
+const CHANNEL_COLUMNS: &str = "id, name, description, channel_type, created_by, created_at, restrict_file_types, \
+     retention_days, archived_at, allow_markdown_formatting, link_previews, e2ee_required";
+
+/// Upper bound on `retention_days`, about a century.
+const MAX_RETENTION_DAYS: i32 = 36_500;
+
+pub fn parse_channel_id(raw: &str) -> Result<ChannelId, AppError> {
+    raw.parse().map_err(|_| AppError::invalid("invalid channel id"))
+}
+
+fn validate_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::invalid(format!("name must be 1-{} characters", MAX_NAME_LEN)));
+    }
+    Ok(name.to_string())
+}
+
+/// The caller's role in `channel_id`, if they are a member.
+pub async fn member_role(
+    db: &PgPool,
+    channel_id: &ChannelId,
+    user_id: &UserId,
+) -> Result<Option<MemberRole>, sqlx::Error> {
+    let role: Option<String> =
+        sqlx::query_scalar("SELECT role FROM channel_members WHERE channel_id = $1 AND user_id = $2")
+            .bind(channel_id)
+            .bind(user_id)
+            .fetch_optional(db)
+            .await?;
+
+    Ok(role.and_then(|r| r.parse().ok()))
+}
+
+/// Loads a channel regardless of who is asking.
+pub async fn find_channel(db: &PgPool, channel_id: &ChannelId) -> Result<Channel, AppError> {
+    let row: Option<ChannelRow> =
+        sqlx::query_as(&format!("SELECT {} FROM channels WHERE id = $1", CHANNEL_COLUMNS))
+            .bind(channel_id)
+            .fetch_optional(db)
+            .await?;
+
+    row.map(Channel::from).ok_or_else(AppError::not_found)
+}
+
+/// Loads a channel the caller is allowed to see, with their role in it.
+/// Private channels are reported as missing to non-members so their
+/// existence does not leak.
+pub async fn visible_channel(
+    db: &PgPool,
+    channel_id: &ChannelId,
+    user_id: &UserId,
+) -> Result<(Channel, Option<MemberRole>), AppError> {
+    let channel = find_channel(db, channel_id).await?;
+    let role = member_role(db, channel_id, user_id).await?;
+
+    if channel.channel_type == ChannelType::Private && role.is_none() {
+        return Err(AppError::not_found());
+    }
+
+    Ok((channel, role))
+}
+
+/// Like `visible_channel`, but refuses archived channels, for handlers
+/// that post to or change content in the channel.
+pub async fn writable_channel(
+    db: &PgPool,
+    channel_id: &ChannelId,
+    user_id: &UserId,
+) -> Result<(Channel, Option<MemberRole>), AppError> {
+    let (channel, role) = visible_channel(db, channel_id, user_id).await?;
+    if channel.archived_at.is_some() {
+        return Err(AppError::archived());
+    }
+    Ok((channel, role))
+}
+
+/// GET /api/channels
+///
+/// Archived channels are included; clients tell them apart by
+/// `archived_at`.
+pub async fn list_channels(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<Channel>>, AppError> {
+    let rows: Vec<ChannelRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM channels c
+         WHERE c.channel_type = 'public'
+            OR EXISTS (SELECT 1 FROM channel_members m
+                       WHERE m.channel_id = c.id AND m.user_id = $1)
+         ORDER BY c.created_at, c.id",
+        CHANNEL_COLUMNS
+    ))
+    .bind(&user.user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(rows.into_iter().map(Channel::from).collect()))
+}
+
+/// Every channel, private ones included, oldest first, for operators.
+pub async fn all_channels(db: &PgPool) -> Result<Vec<Channel>, sqlx::Error> {
+    let rows: Vec<ChannelRow> =
+        sqlx::query_as(&format!("SELECT {} FROM channels ORDER BY created_at, id", CHANNEL_COLUMNS))
+            .fetch_all(db)
+            .await?;
+    Ok(rows.into_iter().map(Channel::from).collect())
+}
+
+/// POST /api/channels
+///
+/// The creator becomes the channel's first admin and `member_ids` join as
+/// writers. Names are unique per creator, ignoring case.
+pub async fn create_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(body): Json<CreateChannel>,
+) -> Result<(StatusCode, Json<Channel>), AppError> {
+    let name = validate_name(&body.name)?;
+    let mut member_ids = body.member_ids;
+    member_ids.sort();
+    member_ids.dedup();
+    member_ids.retain(|id| *id != user.user_id);
+    if member_ids.len() > MAX_INITIAL_MEMBERS {
+        return Err(AppError::invalid(format!("at most {} member_ids", MAX_INITIAL_MEMBERS)));
+    }
+
+    let mut tx = state.db.begin().await?;
+
+    let row: Option<ChannelRow> = sqlx::query_as(&format!(
+        "INSERT INTO channels
+             (id, name, description, channel_type, created_by, restrict_file_types, allow_markdown_formatting,
+              e2ee_required)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+         ON CONFLICT (created_by, lower(name)) DO NOTHING
+         RETURNING {}",
+        CHANNEL_COLUMNS
+    ))
+    .bind(ChannelId::new())
+    .bind(&name)
+    .bind(body.description.trim())
+    .bind(body.channel_type.as_str())
+    .bind(&user.user_id)
+    .bind(body.restrict_file_types)
+    .bind(body.allow_markdown_formatting)
+    .bind(body.e2ee_required)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let row = row.ok_or_else(|| AppError::conflict("you already have a channel with this name"))?;
+
+    sqlx::query("INSERT INTO channel_members (channel_id, user_id, role) VALUES ($1, $2, 'admin')")
+        .bind(&row.id)
+        .bind(&user.user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO channel_members (channel_id, user_id, role)
+         SELECT $1, member, 'write' FROM UNNEST($2::text[]) AS member",
+    )
+    .bind(&row.id)
+    .bind(&member_ids)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    let channel = Channel::from(row);
+    if let (Some(gateway), true) = (&state.gateway, channel.allow_markdown_formatting || channel.e2ee_required) {
+        gateway.channel_updated(&channel).await;
+    }
+    if let Some(gateway) = &state.gateway {
+        // The creator's open sockets may use the channel without logging
+        // in again, which is how bots start direct messages.
+        let creator = std::iter::once((&user.user_id, MemberRole::Admin));
+        for (member, role) in creator.chain(member_ids.iter().map(|m| (m, MemberRole::Write))) {
+            let change = MembershipChange { channel_id: channel.id.clone(), user_id: member.clone(), role: Some(role) };
+            gateway.membership_changed(&change).await;
+        }
+        if !member_ids.is_empty() {
+            gateway.channel_created(&ChannelCreated { channel: channel.clone(), member_ids }).await;
+        }
+    }
+
+    Ok((StatusCode::CREATED, Json(channel)))
+}
+
+/// GET /api/channels/{id}
+pub async fn get_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Channel>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (channel, _) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    Ok(Json(channel))
+}
+
+/// PATCH /api/channels/{id}
+pub async fn update_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<UpdateChannel>,
+) -> Result<Json<Channel>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+
+    let name = body.name.as_deref().map(validate_name).transpose()?;
+    let description = body.description.as_deref().map(str::trim);
+    if body.retention_days.is_some_and(|days| !(0..=MAX_RETENTION_DAYS).contains(&days)) {
+        return Err(AppError::invalid(format!("retention_days must be 0-{}", MAX_RETENTION_DAYS)));
+    }
+
+    // Whether messages must be encrypted is fixed when the channel is
+    // created: the gateway never stores what it sends, so "no messages
+    // yet" isn't something this table can tell. The update matches no row
+    // when it would change it.
+    let row: Option<ChannelRow> = sqlx::query_as(&format!(
+        "UPDATE channels
+         SET name = COALESCE($2, name),
+             description = COALESCE($3, description),
+             restrict_file_types = COALESCE($4, restrict_file_types),
+             retention_days = CASE WHEN $5::int IS NULL THEN retention_days ELSE NULLIF($5, 0) END,
+             allow_markdown_formatting = COALESCE($6, allow_markdown_formatting),
+             link_previews = COALESCE($7, link_previews),
+             e2ee_required = COALESCE($8, e2ee_required)
+         WHERE id = $1
+           AND ($8::bool IS NULL OR $8 = e2ee_required)
+         RETURNING {}",
+        CHANNEL_COLUMNS
+    ))
+    .bind(&channel_id)
+    .bind(name)
+    .bind(description)
+    .bind(body.restrict_file_types)
+    .bind(body.retention_days)
+    .bind(body.allow_markdown_formatting)
+    .bind(body.link_previews)
+    .bind(body.e2ee_required)
+    .fetch_optional(&state.db)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            AppError::conflict("the creator already has a channel with this name")
+        }
+        e => e.into(),
+    })?;
+    let row = row.ok_or_else(|| AppError::conflict("e2ee_required is fixed when the channel is created"))?;
+
+    let channel = Channel::from(row);
+    if let Some(gateway) = &state.gateway {
+        gateway.channel_updated(&channel).await;
+    }
+    Ok(Json(channel))
+}
+
+/// GET /internal/e2ee-channels
+///
+/// Every channel with `e2ee_required`, for a gateway that just started.
+pub async fn e2ee_channels(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ChannelId>>, AppError> {
+    channel_ids_where(&state, &headers, "e2ee_required").await
+}
+
+/// GET /internal/archived-channels
+///
+/// Every archived channel, for a gateway that just started.
+pub async fn archived_channels(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ChannelId>>, AppError> {
+    channel_ids_where(&state, &headers, "archived_at IS NOT NULL").await
+}
+
+/// GET /internal/markdown-channels
+///
+/// Every channel with `allow_markdown_formatting`, for a gateway that
+/// just started.
+pub async fn markdown_channels(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ChannelId>>, AppError> {
+    channel_ids_where(&state, &headers, "allow_markdown_formatting").await
+}
+
+/// Ids of the channels matching `condition`, for the gateway only.
+async fn channel_ids_where(
+    state: &AppState,
+    headers: &HeaderMap,
+    condition: &'static str,
+) -> Result<Json<Vec<ChannelId>>, AppError> {
+    if !state.push.gateway_authorized(headers) {
+        return Err(AppError::forbidden());
+    }
+
+    let ids: Vec<ChannelId> = sqlx::query_scalar(&format!("SELECT id FROM channels WHERE {} ORDER BY id", condition))
+        .fetch_all(&state.db)
+        .await?;
+    Ok(Json(ids))
+}
+
+/// POST /api/channels/{id}/archive
+///
+/// Makes the channel read-only. Archiving an archived channel keeps its
+/// original `archived_at`.
+pub async fn archive_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Channel>, AppError> {
+    set_archived(&state, &user, &id, true).await
+}
+
+/// POST /api/channels/{id}/unarchive
+pub async fn unarchive_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Channel>, AppError> {
+    set_archived(&state, &user, &id, false).await
+}
+
+async fn set_archived(state: &AppState, user: &AuthUser, id: &str, archived: bool) -> Result<Json<Channel>, AppError> {
+    let channel_id = parse_channel_id(id)?;
+    let (before, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+    if before.archived_at.is_some() == archived {
+        return Ok(Json(before));
+    }
+    change_archived(state, &channel_id, archived).await.map(Json)
+}
+
+/// Archives or reactivates the channel, whoever asks, and tells the
+/// gateway. Already being in that state is not an error.
+pub async fn change_archived(state: &AppState, channel_id: &ChannelId, archived: bool) -> Result<Channel, AppError> {
+    let row: Option<ChannelRow> = sqlx::query_as(&format!(
+        "UPDATE channels
+         SET archived_at = CASE WHEN $2 THEN now() END
+         WHERE id = $1 AND (archived_at IS NOT NULL) <> $2
+         RETURNING {}",
+        CHANNEL_COLUMNS
+    ))
+    .bind(channel_id)
+    .bind(archived)
+    .fetch_optional(&state.db)
+    .await?;
+
+    // Already in that state, or lost a race with another admin making the
+    // same change.
+    let Some(row) = row else {
+        return find_channel(&state.db, channel_id).await;
+    };
+
+    let channel = Channel::from(row);
+    if let Some(gateway) = &state.gateway {
+        let change = ChannelArchiveChanged { channel_id: channel_id.clone(), archived_at: channel.archived_at };
+        gateway.channel_archive_changed(&change).await;
+    }
+    Ok(channel)
+}
+
+/// DELETE /api/channels/{id}
+pub async fn delete_channel(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query("DELETE FROM channel_members WHERE channel_id = $1")
+        .bind(&channel_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM channel_invites WHERE channel_id = $1")
+        .bind(&channel_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM channels WHERE id = $1")
+        .bind(&channel_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use uchat_proto::jwt::create_token;
+
+    pub async fn call(
+        state: &Arc<AppState>,
+        method: Method,
+        uri: &str,
+        user: Option<&UserId>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(user) = user {
+            let token = create_token(&state.jwt_secret, user.as_str());
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        let req = match body {
+            Some(body) => req
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => req.body(Body::empty()).unwrap(),
+        };
+
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, value)
+    }
+
+    pub async fn create(state: &Arc<AppState>, owner: &UserId, name: &str, channel_type: &str) -> String {
+        let (status, body) = call(
+            state,
+            Method::POST,
+            "/api/channels",
+            Some(owner),
+            Some(json!({ "name": name, "channel_type": channel_type })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        body["id"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn requires_token() {
+        let Some(state) = test_state().await else { return };
+        let (status, body) = call(&state, Method::GET, "/api/channels", None, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn creates_with_members_and_unique_names() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let (a, b) = (UserId::new(), UserId::new());
+
+        let (status, body) = call(
+            &state,
+            Method::POST,
+            "/api/channels",
+            Some(&owner),
+            Some(json!({ "name": "Launch", "type": "private", "member_ids": [a, b, a, owner] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["channel_type"], "private");
+        let id = body["id"].as_str().unwrap();
+
+        let (status, members) =
+            call(&state, Method::GET, &format!("/api/channels/{}/members", id), Some(&a), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut roles: Vec<(String, String)> = members
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| (m["user_id"].as_str().unwrap().to_string(), m["role"].as_str().unwrap().to_string()))
+            .collect();
+        roles.sort();
+        let mut expected = vec![
+            (owner.to_string(), "admin".to_string()),
+            (a.to_string(), "write".to_string()),
+            (b.to_string(), "write".to_string()),
+        ];
+        expected.sort();
+        assert_eq!(roles, expected);
+
+        let (status, body) =
+            call(&state, Method::POST, "/api/channels", Some(&owner), Some(json!({ "name": "launch" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+
+        // Another creator may reuse the name, but not rename into a clash.
+        create(&state, &a, "launch", "public").await;
+        let other = create(&state, &owner, "later", "public").await;
+        let (status, _) = call(
+            &state,
+            Method::PATCH,
+            &format!("/api/channels/{}", other),
+            Some(&owner),
+            Some(json!({ "name": "LAUNCH" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn create_get_update_delete() {
+        let Some(mut state) = test_state().await else { return };
+        Arc::get_mut(&mut state).unwrap().push.internal_token = Some("internal-secret".into());
+        let owner = UserId::new();
+        let id = create(&state, &owner, "  project-x  ", "public").await;
+        let uri = format!("/api/channels/{}", id);
+
+        let (status, body) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "project-x");
+        assert_eq!(body["created_by"], owner.as_str());
+
+        let (status, body) = call(
+            &state,
+            Method::PATCH,
+            &uri,
+            Some(&owner),
+            Some(json!({ "description": "the x project", "allow_markdown_formatting": true })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "project-x");
+        assert_eq!(body["description"], "the x project");
+        assert_eq!(body["allow_markdown_formatting"], true);
+        let mut gateway = HeaderMap::new();
+        gateway.insert("x-internal-token", "internal-secret".parse().unwrap());
+        let Json(ids) = markdown_channels(State(state.clone()), gateway).await.unwrap();
+        assert!(ids.iter().any(|c| c.as_str() == id));
+
+        let (status, _) = call(&state, Method::DELETE, &uri, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn archived_channels_are_read_only() {
+        let Some(mut state) = test_state().await else { return };
+        Arc::get_mut(&mut state).unwrap().push.internal_token = Some("internal-secret".into());
+        let mut gateway = HeaderMap::new();
+        gateway.insert("x-internal-token", "internal-secret".parse().unwrap());
+        let owner = UserId::new();
+        let member = UserId::new();
+        let id = create(&state, &owner, "old-project", "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", id), Some(&member), Some(json!({}))).await;
+        let msg = crate::messages::tests::insert(&state.db, &id, &member, "done", Utc::now()).await;
+
+        let archive = format!("/api/channels/{}/archive", id);
+        let (status, _) = call(&state, Method::POST, &archive, Some(&member), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call(&state, Method::POST, &archive, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let archived_at = body["archived_at"].clone();
+        assert!(archived_at.is_string());
+        let (_, body) = call(&state, Method::POST, &archive, Some(&owner), None).await;
+        assert_eq!(body["archived_at"], archived_at);
+
+        let (_, list) = call(&state, Method::GET, "/api/channels", Some(&member), None).await;
+        let listed = list.as_array().unwrap().iter().find(|c| c["id"] == id.as_str()).unwrap();
+        assert_eq!(listed["archived_at"], archived_at);
+        let Json(ids) = archived_channels(State(state.clone()), gateway.clone()).await.unwrap();
+        assert!(ids.iter().any(|c| c.as_str() == id));
+
+        for read in [format!("/api/channels/{}/messages", id), format!("/api/channels/{}/members", id)] {
+            let (status, _) = call(&state, Method::GET, &read, Some(&member), None).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let message = format!("/api/channels/{}/messages/{}", id, msg);
+        let (status, body) =
+            call(&state, Method::PATCH, &message, Some(&member), Some(json!({ "content": "not done" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "channel_archived");
+        let reaction = format!("{}/reactions/:tada:", message);
+        let (_, body) = call(&state, Method::PUT, &reaction, Some(&member), None).await;
+        assert_eq!(body["code"], "channel_archived");
+
+        let (status, body) =
+            call(&state, Method::POST, &format!("/api/channels/{}/unarchive", id), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["archived_at"].is_null());
+        let Json(ids) = archived_channels(State(state.clone()), gateway).await.unwrap();
+        assert!(!ids.iter().any(|c| c.as_str() == id));
+        let (status, _) = call(&state, Method::PUT, &reaction, Some(&member), None).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn e2ee_required_is_fixed_at_creation() {
+        let Some(mut state) = test_state().await else { return };
+        Arc::get_mut(&mut state).unwrap().push.internal_token = Some("internal-secret".into());
+        let (owner, member) = (UserId::new(), UserId::new());
+        let body = json!({ "name": format!("legal-{}", owner), "channel_type": "public", "e2ee_required": true });
+        let (status, channel) = call(&state, Method::POST, "/api/channels", Some(&owner), Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(channel["e2ee_required"], true);
+        let id = channel["id"].as_str().unwrap();
+        call(&state, Method::POST, &format!("/api/channels/{}/members", id), Some(&member), Some(json!({}))).await;
+
+        let (_, list) = call(&state, Method::GET, "/api/channels", Some(&member), None).await;
+        let listed = list.as_array().unwrap().iter().find(|c| c["id"] == id).unwrap();
+        assert_eq!(listed["e2ee_required"], true);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-internal-token", "internal-secret".parse().unwrap());
+        let Json(ids) = e2ee_channels(State(state.clone()), headers).await.unwrap();
+        assert!(ids.iter().any(|c| c.as_str() == id));
+
+        // Scheduling would leave plaintext on the server until send time.
+        let scheduled = json!({ "content": "hi", "send_at": Utc::now() + chrono::Duration::hours(1) });
+        let uri = format!("/api/channels/{}/scheduled_messages", id);
+        let (status, body) = call(&state, Method::POST, &uri, Some(&member), Some(scheduled)).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::CONFLICT, Some("e2ee_required")));
+
+        let message = crate::messages::tests::insert(&state.db, id, &member, "ciphertext", Utc::now()).await;
+        let uri = format!("/api/channels/{}/messages/{}", id, message);
+        let (status, body) = call(&state, Method::PATCH, &uri, Some(&member), Some(json!({ "content": "plain" }))).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::CONFLICT, Some("e2ee_required")));
+
+        // Messages sent through the gateway leave no trace here, so the
+        // flag can't change even while the channel looks empty.
+        let uri = format!("/api/channels/{}", create(&state, &owner, "open", "public").await);
+        let (status, _) = call(&state, Method::PATCH, &uri, Some(&owner), Some(json!({ "e2ee_required": true }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let uri = format!("/api/channels/{}", id);
+        let (status, _) = call(&state, Method::PATCH, &uri, Some(&member), Some(json!({ "e2ee_required": false }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&state, Method::PATCH, &uri, Some(&owner), Some(json!({ "e2ee_required": false }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        // Setting it to what it already is, or changing something else, is fine.
+        let (status, _) = call(&state, Method::PATCH, &uri, Some(&owner), Some(json!({ "e2ee_required": true }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = call(&state, Method::PATCH, &uri, Some(&owner), Some(json!({ "description": "counsel" }))).await;
+        assert_eq!((status, body["e2ee_required"].as_bool()), (StatusCode::OK, Some(true)));
+    }
+
+    #[tokio::test]
+    async fn private_channels_are_hidden_from_non_members() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let outsider = UserId::new();
+        let id = create(&state, &owner, "secret", "private").await;
+
+        let (_, list) = call(&state, Method::GET, "/api/channels", Some(&outsider), None).await;
+        assert!(!list.as_array().unwrap().iter().any(|c| c["id"] == id.as_str()));
+
+        let (_, list) = call(&state, Method::GET, "/api/channels", Some(&owner), None).await;
+        assert!(list.as_array().unwrap().iter().any(|c| c["id"] == id.as_str()));
+
+        let uri = format!("/api/channels/{}", id);
+        let (status, _) = call(&state, Method::GET, &uri, Some(&outsider), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_admins_modify() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let other = UserId::new();
+        let id = create(&state, &owner, "public-room", "public").await;
+        let uri = format!("/api/channels/{}", id);
+
+        let (status, body) = call(&state, Method::DELETE, &uri, Some(&other), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "forbidden");
+
+        let (status, _) =
+            call(&state, Method::PATCH, &uri, Some(&other), Some(json!({ "name": "mine" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_input() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+
+        let (status, body) = call(
+            &state,
+            Method::POST,
+            "/api/channels",
+            Some(&owner),
+            Some(json!({ "name": "   " })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_request");
+
+        let (status, _) = call(&state, Method::GET, "/api/channels/general", Some(&owner), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
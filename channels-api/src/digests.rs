@@ -0,0 +1,470 @@
+//! Email digests for users who are away. A worker looks for unread
+//! @mentions, and for `all`, direct messages (private channels of two),
+//! that are older than the delay and whose recipient isn't online, and
+//! mails each recipient one digest of them at most once per interval.
+//! Every message mailed is recorded, so a restart never sends it twice.
+//! Each digest carries a signed link that turns the emails off without
+//! signing in.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    response::Html,
+};
+use chrono::Utc;
+use serde::Deserialize;
+
+use uchat_proto::ids::{ChannelId, MessageId, UserId};
+use uchat_proto::signing::LinkSigner;
+use uchat_proto::users::EmailNotifications;
+
+use crate::error::AppError;
+use crate::mailer::{Email, Mailer};
+use crate::AppState;
+
+const RUN_INTERVAL: Duration = Duration::from_secs(60);
+/// Messages older than this are never mailed, so turning digests on
+/// doesn't mail out a backlog.
+const LOOKBACK: chrono::Duration = chrono::Duration::hours(24);
+/// Messages listed in one digest; the rest are counted.
+const MAX_LISTED: usize = 20;
+const MAX_EXCERPT_CHARS: usize = 300;
+
+#[derive(Debug, Clone)]
+pub struct DigestConfig {
+    /// How long a message waits unread before it is mailed.
+    pub delay: chrono::Duration,
+    /// The least time between two digests to the same user.
+    pub min_interval: chrono::Duration,
+    /// The web app's address, for links back into it. The API is expected
+    /// under the same origin.
+    pub app_url: String,
+}
+
+impl DigestConfig {
+    /// From `EMAIL_DIGEST_DELAY_MINS` (default 15),
+    /// `EMAIL_DIGEST_INTERVAL_MINS` (default 60) and `APP_URL`, which is
+    /// required.
+    pub fn from_env() -> Self {
+        let minutes = |name: &str, default: i64| {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(default)
+        };
+        let app_url = std::env::var("APP_URL").ok().filter(|v| !v.is_empty()).expect("APP_URL must be set for email digests");
+        Self {
+            delay: chrono::Duration::minutes(minutes("EMAIL_DIGEST_DELAY_MINS", 15)),
+            min_interval: chrono::Duration::minutes(minutes("EMAIL_DIGEST_INTERVAL_MINS", 60)),
+            app_url: app_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+/// Runs digests every minute.
+pub fn spawn(state: Arc<AppState>, mailer: Arc<dyn Mailer>, config: DigestConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RUN_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = run(&state, mailer.as_ref(), &config).await {
+                tracing::warn!("email digest run failed: {}", e);
+            }
+        }
+    });
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PendingRow {
+    user_id: UserId,
+    email: String,
+    username: String,
+    email_notifications: String,
+    message_id: MessageId,
+    channel_id: ChannelId,
+    channel_name: String,
+    sender_name: Option<String>,
+    /// `None` when encrypted.
+    content: Option<String>,
+    direct: bool,
+}
+
+impl PendingRow {
+    fn wanted(&self) -> bool {
+        let mentioned = self.content.as_deref().is_some_and(|c| mentions(c, &self.username));
+        match self.email_notifications.parse() {
+            Ok(EmailNotifications::All) => mentioned || self.direct,
+            Ok(EmailNotifications::Mentions) => mentioned,
+            _ => false,
+        }
+    }
+}
+
+/// Mails every user with messages waiting; returns how many digests went
+/// out.
+pub async fn run(state: &AppState, mailer: &dyn Mailer, config: &DigestConfig) -> Result<usize, sqlx::Error> {
+    let now = Utc::now();
+    // Narrowed by substring here; `wanted` checks mentions properly.
+    let rows: Vec<PendingRow> = sqlx::query_as(
+        "SELECT * FROM (
+             SELECT u.id AS user_id, u.email, u.username, u.email_notifications,
+                    m.id AS message_id, m.channel_id, c.name AS channel_name,
+                    COALESCE(m.sender_name, s.display_name, s.username) AS sender_name,
+                    CASE WHEN m.encrypted THEN NULL ELSE m.content END AS content,
+                    m.created_at,
+                    c.channel_type = 'private'
+                        AND (SELECT count(*) FROM channel_members x WHERE x.channel_id = c.id) = 2 AS direct
+             FROM messages m
+             JOIN channels c ON c.id = m.channel_id
+             JOIN channel_members cm ON cm.channel_id = m.channel_id
+             JOIN users u ON u.id = cm.user_id
+             LEFT JOIN users s ON s.id = m.sender_id
+             LEFT JOIN channel_read_markers r ON r.user_id = u.id AND r.channel_id = m.channel_id
+             WHERE m.created_at > $1 AND m.created_at <= $2 AND m.deleted_at IS NULL
+               AND m.sender_id <> u.id
+               AND u.email IS NOT NULL AND u.email_notifications <> 'none' AND u.deleted_at IS NULL
+               AND (r.last_read_at IS NULL OR r.last_read_at < m.created_at)
+               AND NOT EXISTS (SELECT 1 FROM email_notification_sends e WHERE e.user_id = u.id AND e.message_id = m.id)
+               AND NOT EXISTS (SELECT 1 FROM email_notification_sends e WHERE e.user_id = u.id AND e.sent_at > $3)
+         ) pending
+         WHERE direct OR strpos(lower(content), '@' || lower(username)) > 0
+         ORDER BY user_id, created_at, message_id",
+    )
+    .bind(now - LOOKBACK)
+    .bind(now - config.delay)
+    .bind(now - config.min_interval)
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut by_user: BTreeMap<UserId, Vec<PendingRow>> = BTreeMap::new();
+    for row in rows.into_iter().filter(PendingRow::wanted) {
+        by_user.entry(row.user_id.clone()).or_default().push(row);
+    }
+    if by_user.is_empty() {
+        return Ok(0);
+    }
+
+    if let Some(gateway) = &state.gateway {
+        let ids: Vec<UserId> = by_user.keys().cloned().collect();
+        // Better a late email than one to someone reading along.
+        let Some(online) = gateway.online(&ids).await else { return Ok(0) };
+        by_user.retain(|id, _| !online.contains(id));
+    }
+
+    let mut sent = 0;
+    for (user_id, rows) in by_user {
+        match send_digest(state, mailer, config, &user_id, rows).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(e) => tracing::warn!(%user_id, "email digest failed: {}", e),
+        }
+    }
+    Ok(sent)
+}
+
+/// Records and mails one user's digest. The records are only kept if the
+/// relay took the mail; `false` when another instance got there first.
+async fn send_digest(
+    state: &AppState,
+    mailer: &dyn Mailer,
+    config: &DigestConfig,
+    user_id: &UserId,
+    rows: Vec<PendingRow>,
+) -> Result<bool, String> {
+    let mut tx = state.db.begin().await.map_err(|e| e.to_string())?;
+    let mut claimed = Vec::new();
+    for row in rows {
+        let inserted = sqlx::query(
+            "INSERT INTO email_notification_sends (user_id, message_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(&row.message_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .rows_affected();
+        if inserted == 1 {
+            claimed.push(row);
+        }
+    }
+    if claimed.is_empty() {
+        return Ok(false);
+    }
+
+    mailer.send(&render(config, &state.links, &claimed)).await?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+/// The digest for one user's `rows`, oldest first.
+fn render(config: &DigestConfig, links: &LinkSigner, rows: &[PendingRow]) -> Email {
+    let first = &rows[0];
+    let subject = match rows {
+        [row] if row.direct => format!("{} sent you a message", sender(row)),
+        [row] => format!("{} mentioned you in #{}", sender(row), row.channel_name),
+        _ => format!("{} messages are waiting for you", rows.len()),
+    };
+    let unsubscribe_url = format!(
+        "{}/api/email/unsubscribe?user={}&token={}",
+        config.app_url,
+        first.user_id,
+        unsubscribe_token(links, &first.user_id)
+    );
+
+    let mut text = String::new();
+    let mut html = String::from("<!DOCTYPE html><html><body>");
+    for row in rows.iter().take(MAX_LISTED) {
+        let link = format!("{}/channels/{}?message={}", config.app_url, row.channel_id, row.message_id);
+        let place = if row.direct { "direct message".to_string() } else { format!("#{}", row.channel_name) };
+        let excerpt = row.content.as_deref().map(excerpt).unwrap_or_else(|| "Encrypted message".into());
+        text.push_str(&format!("{} in {}:\n{}\n{}\n\n", sender(row), place, excerpt, link));
+        html.push_str(&format!(
+            "<p><strong>{}</strong> in {}:<br>{}<br><a href=\"{}\">Open in uchat</a></p>",
+            escape(sender(row)),
+            escape(&place),
+            escape(&excerpt),
+            escape(&link)
+        ));
+    }
+    if rows.len() > MAX_LISTED {
+        let more = format!("…and {} more.", rows.len() - MAX_LISTED);
+        text.push_str(&format!("{}\n\n", more));
+        html.push_str(&format!("<p>{}</p>", more));
+    }
+    text.push_str(&format!("Stop these emails: {}\n", unsubscribe_url));
+    html.push_str(&format!(
+        "<p style=\"color:#888;font-size:small\"><a href=\"{}\">Stop these emails</a></p></body></html>",
+        escape(&unsubscribe_url)
+    ));
+
+    Email { to: first.email.clone(), subject, text, html, unsubscribe_url: Some(unsubscribe_url) }
+}
+
+fn sender(row: &PendingRow) -> &str {
+    row.sender_name.as_deref().unwrap_or("Someone")
+}
+
+fn excerpt(content: &str) -> String {
+    let line = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= MAX_EXCERPT_CHARS {
+        return line;
+    }
+    let mut cut: String = line.chars().take(MAX_EXCERPT_CHARS - 1).collect();
+    cut.push('…');
+    cut
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Whether `content` has `@username` as a whole word, ignoring case.
+fn mentions(content: &str, username: &str) -> bool {
+    let word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let (content, needle) = (content.to_lowercase(), format!("@{}", username.to_lowercase()));
+    content.match_indices(&needle).any(|(at, _)| {
+        let before = content[..at].chars().next_back();
+        let after = content[at + needle.len()..].chars().next();
+        !before.is_some_and(word) && !after.is_some_and(|c| word(c) && c != '.')
+    })
+}
+
+fn unsubscribe_token(links: &LinkSigner, user_id: &UserId) -> String {
+    links.sign("email-unsubscribe", &[user_id.as_str()])
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnsubscribeQuery {
+    user: String,
+    token: String,
+}
+
+/// GET or POST /api/email/unsubscribe?user=..&token=..
+///
+/// Turns off the user's notification emails. The signed link is the
+/// credential, so no session is needed; POST is the one-click form mail
+/// clients use.
+pub async fn unsubscribe(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<Html<&'static str>, AppError> {
+    let user_id: UserId = query.user.parse().map_err(|_| AppError::forbidden())?;
+    if !state.links.verify("email-unsubscribe", &[user_id.as_str()], &query.token) {
+        return Err(AppError::forbidden());
+    }
+
+    sqlx::query("UPDATE users SET email_notifications = 'none' WHERE id = $1")
+        .bind(&user_id)
+        .execute(&state.db)
+        .await?;
+    Ok(Html(
+        "<!DOCTYPE html><html><body><p>You won't get notification emails any more. \
+         You can turn them back on in your profile settings.</p></body></html>",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use async_trait::async_trait;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<Email>>);
+
+    #[async_trait]
+    impl Mailer for Outbox {
+        async fn send(&self, email: &Email) -> Result<(), String> {
+            self.0.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mentions_are_whole_words() {
+        assert!(mentions("hey @Ada, look", "ada"));
+        assert!(mentions("@ada.", "ada"));
+        assert!(!mentions("hey @adam", "ada"));
+        assert!(!mentions("mail ops@ada", "ada"));
+        assert!(!mentions("no mention", "ada"));
+    }
+
+    fn config() -> DigestConfig {
+        DigestConfig {
+            delay: chrono::Duration::minutes(15),
+            min_interval: chrono::Duration::minutes(60),
+            app_url: "https://chat.example".into(),
+        }
+    }
+
+    async fn user(state: &AppState, name: &str, email: Option<&str>, notifications: &str) -> UserId {
+        let id = UserId::new();
+        sqlx::query("INSERT INTO users (id, username, email, email_notifications) VALUES ($1, $2, $3, $4)")
+            .bind(&id)
+            .bind(format!("{}-{}", name, id.as_uuid().simple()))
+            .bind(email)
+            .bind(notifications)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn username(state: &AppState, id: &UserId) -> String {
+        sqlx::query_scalar("SELECT username FROM users WHERE id = $1").bind(id).fetch_one(&state.db).await.unwrap()
+    }
+
+    async fn message(state: &AppState, channel: &str, sender: &UserId, content: &str, minutes_ago: i64) -> MessageId {
+        let id = MessageId::new();
+        sqlx::query("INSERT INTO messages (id, channel_id, sender_id, content, created_at) VALUES ($1, $2, $3, $4, $5)")
+            .bind(&id)
+            .bind(channel)
+            .bind(sender)
+            .bind(content)
+            .bind(Utc::now() - chrono::Duration::minutes(minutes_ago))
+            .execute(&state.db)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn join(state: &Arc<AppState>, channel: &str, owner: &UserId, user: &UserId) {
+        let body = json!({ "user_id": user });
+        let (status, _) =
+            call(state, Method::POST, &format!("/api/channels/{}/members", channel), Some(owner), Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    /// Emails to `address`, oldest first.
+    fn mail_to(outbox: &Outbox, address: &str) -> Vec<Email> {
+        outbox.0.lock().unwrap().iter().filter(|e| e.to == address).cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn mails_unread_mentions_and_direct_messages_once() {
+        let Some(state) = test_state().await else { return };
+        let tag = UserId::new().as_uuid().simple().to_string();
+        let address = |name: &str| format!("{}-{}@example.com", name, tag);
+        let (ada_mail, bob_mail, cy_mail, dee_mail) = (address("ada"), address("bob"), address("cy"), address("dee"));
+        let grace = user(&state, "grace", None, "mentions").await;
+        let ada = user(&state, "ada", Some(&ada_mail), "all").await;
+        let bob = user(&state, "bob", Some(&bob_mail), "mentions").await;
+        let cy = user(&state, "cy", Some(&cy_mail), "none").await;
+        let dee = user(&state, "dee", Some(&dee_mail), "mentions").await;
+        let grace_name = username(&state, &grace).await;
+        let (ada_name, bob_name) = (username(&state, &ada).await, username(&state, &bob).await);
+        let (cy_name, dee_name) = (username(&state, &cy).await, username(&state, &dee).await);
+
+        let ops = create(&state, &grace, "ops", "public").await;
+        for member in [&ada, &bob, &cy, &dee] {
+            join(&state, &ops, &grace, member).await;
+        }
+        let dm_ada = create(&state, &grace, "dm-ada", "private").await;
+        join(&state, &dm_ada, &grace, &ada).await;
+        let dm_bob = create(&state, &grace, "dm-bob", "private").await;
+        join(&state, &dm_bob, &grace, &bob).await;
+
+        let mention = message(&state, &ops, &grace, &format!("@{} <b>deploy</b> is done", ada_name), 30).await;
+        message(&state, &ops, &grace, &format!("@{} and @{}", cy_name, bob_name), 30).await;
+        message(&state, &ops, &grace, &format!("too soon @{}", dee_name), 5).await;
+        message(&state, &ops, &grace, &format!("not @{}x", dee_name), 30).await;
+        message(&state, &dm_ada, &grace, "lunch?", 20).await;
+        message(&state, &dm_bob, &grace, "lunch?", 20).await;
+        // Dee has read up to here.
+        let read = message(&state, &ops, &grace, &format!("@{} read this", dee_name), 40).await;
+        let (status, _) = call(
+            &state,
+            Method::PUT,
+            &format!("/api/channels/{}/read", ops),
+            Some(&dee),
+            Some(json!({ "message_id": read })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let outbox = Outbox::default();
+        run(&state, &outbox, &config()).await.unwrap();
+
+        let ada_digest = mail_to(&outbox, &ada_mail);
+        assert_eq!(ada_digest.len(), 1);
+        assert_eq!(ada_digest[0].subject, "2 messages are waiting for you");
+        assert!(ada_digest[0].text.contains(&format!("https://chat.example/channels/{}?message={}", ops, mention)));
+        assert!(ada_digest[0].text.contains("in direct message:\nlunch?"));
+        assert!(ada_digest[0].html.contains("&lt;b&gt;deploy&lt;/b&gt;"));
+        let bob_digest = mail_to(&outbox, &bob_mail);
+        assert_eq!(bob_digest.len(), 1);
+        assert_eq!(bob_digest[0].subject, format!("{} mentioned you in #ops", grace_name));
+        assert!(mail_to(&outbox, &cy_mail).is_empty());
+        assert!(mail_to(&outbox, &dee_mail).is_empty());
+
+        // Nothing new, and Ada's next digest has to wait out the interval.
+        message(&state, &dm_ada, &grace, "still there?", 16).await;
+        let outbox = Outbox::default();
+        run(&state, &outbox, &config()).await.unwrap();
+        assert!(mail_to(&outbox, &ada_mail).is_empty() && mail_to(&outbox, &bob_mail).is_empty());
+        let hurried = DigestConfig { min_interval: chrono::Duration::zero(), ..config() };
+        run(&state, &outbox, &hurried).await.unwrap();
+        let next = mail_to(&outbox, &ada_mail);
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].subject, format!("{} sent you a message", grace_name));
+
+        // The link in the mail turns them off without signing in.
+        let url = next[0].unsubscribe_url.clone().unwrap();
+        let path = url.strip_prefix("https://chat.example").unwrap();
+        let (status, _) = call(&state, Method::POST, path, None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let setting: String = sqlx::query_scalar("SELECT email_notifications FROM users WHERE id = $1")
+            .bind(&ada)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(setting, "none");
+        let forged = format!("/api/email/unsubscribe?user={}&token={}", bob, unsubscribe_token(&LinkSigner::new("other-key"), &bob));
+        let (status, _) = call(&state, Method::GET, &forged, None, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
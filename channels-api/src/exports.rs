@@ -0,0 +1,785 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::{ChannelId, ExportId, FileId, MessageId, UserId};
+use uchat_proto::signing::LinkSigner;
+use uchat_proto::users::{DataExport, ExportStatus};
+
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::zip::ZipWriter;
+use crate::AppState;
+
+/// How long a finished archive is kept.
+const ARCHIVE_TTL: chrono::Duration = chrono::Duration::days(7);
+/// How long each signed download link works.
+const LINK_TTL: chrono::Duration = chrono::Duration::minutes(15);
+/// A job still running after this long is assumed lost with its process.
+const STALE_AFTER: chrono::Duration = chrono::Duration::hours(6);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Rows fetched per query while writing an archive.
+const BATCH_SIZE: i64 = 500;
+
+#[derive(sqlx::FromRow)]
+struct ExportRow {
+    id: ExportId,
+    status: String,
+    created_at: DateTime<Utc>,
+    completed_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+const EXPORT_COLUMNS: &str = "id, status, created_at, completed_at, expires_at";
+
+impl ExportRow {
+    fn into_export(self, links: &LinkSigner) -> Result<DataExport, AppError> {
+        let status: ExportStatus = self.status.parse().map_err(|e: String| {
+            tracing::warn!("bad export row {}: {}", self.id, e);
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "corrupt export row")
+        })?;
+        let download_url = (status == ExportStatus::Ready).then(|| download_url(links, &self.id, Utc::now() + LINK_TTL));
+        Ok(DataExport {
+            id: self.id,
+            status,
+            created_at: self.created_at,
+            completed_at: self.completed_at,
+            expires_at: self.expires_at,
+            download_url,
+        })
+    }
+}
+
+fn download_url(links: &LinkSigner, id: &ExportId, expires: DateTime<Utc>) -> String {
+    let expires = expires.timestamp();
+    let signature = links.sign("data-export", &[id.as_str(), &expires.to_string()]);
+    format!("/api/exports/{}/download?expires={}&signature={}", id, expires, signature)
+}
+
+/// POST /api/users/me/export
+///
+/// Queues an export of everything stored about the caller and returns it
+/// as `pending` (202). Only one export per user may be pending or running
+/// at a time; asking again meanwhile is a 409.
+pub async fn request_export(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<(StatusCode, Json<DataExport>), AppError> {
+    let row: ExportRow = sqlx::query_as(&format!(
+        "INSERT INTO data_exports (id, user_id) VALUES ($1, $2) RETURNING {}",
+        EXPORT_COLUMNS
+    ))
+    .bind(ExportId::new())
+    .bind(&user.user_id)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => {
+            AppError::conflict("an export is already in progress")
+        }
+        e => e.into(),
+    })?;
+
+    let id = row.id.clone();
+    let job_state = state.clone();
+    tokio::spawn(async move { run(&job_state, &id).await });
+
+    Ok((StatusCode::ACCEPTED, Json(row.into_export(&state.links)?)))
+}
+
+/// GET /api/users/me/export/{job_id}
+///
+/// The caller's export, with a fresh signed `download_url` once ready.
+pub async fn get_export(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<DataExport>, AppError> {
+    let id: ExportId = id.parse().map_err(|_| AppError::not_found())?;
+    let row: Option<ExportRow> =
+        sqlx::query_as(&format!("SELECT {} FROM data_exports WHERE id = $1 AND user_id = $2", EXPORT_COLUMNS))
+            .bind(&id)
+            .bind(&user.user_id)
+            .fetch_optional(&state.db)
+            .await?;
+    Ok(Json(row.ok_or_else(AppError::not_found)?.into_export(&state.links)?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    expires: i64,
+    signature: String,
+}
+
+/// GET /api/exports/{id}/download?expires=..&signature=..
+///
+/// Streams a ready archive. The signed link is the credential, so no
+/// token is needed; links stop working after `expires` (410).
+pub async fn download_export(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, AppError> {
+    let id: ExportId = id.parse().map_err(|_| AppError::not_found())?;
+    if !state.links.verify("data-export", &[id.as_str(), &query.expires.to_string()], &query.signature) {
+        return Err(AppError::forbidden());
+    }
+    if query.expires < Utc::now().timestamp() {
+        return Err(AppError::new(StatusCode::GONE, ErrorCode::LinkExpired, "download link has expired"));
+    }
+
+    let path: Option<Option<String>> =
+        sqlx::query_scalar("SELECT storage_path FROM data_exports WHERE id = $1 AND status = 'ready'")
+            .bind(&id)
+            .fetch_optional(&state.db)
+            .await?;
+    let Some(Some(path)) = path else {
+        return Err(AppError::not_found());
+    };
+    let stream = state.storage.get(&path, None).await.map_err(|e| {
+        tracing::warn!("reading {} failed: {}", path, e);
+        AppError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "storage error")
+    })?;
+
+    let mut response = Response::new(Body::from_stream(stream));
+    let out = response.headers_mut();
+    out.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/zip"));
+    out.insert(header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=\"uchat-export.zip\""));
+    out.insert(header::CACHE_CONTROL, HeaderValue::from_static("private, no-store"));
+    Ok(response)
+}
+
+/// Sweeps at startup and then hourly.
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = sweep(&state).await {
+                tracing::warn!("export sweep failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Fails jobs that went stale, runs any left pending by a restart, and
+/// deletes archives past their expiry.
+pub async fn sweep(state: &AppState) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE data_exports SET status = 'failed', error = 'interrupted', completed_at = now()
+         WHERE status = 'running' AND created_at < $1",
+    )
+    .bind(Utc::now() - STALE_AFTER)
+    .execute(&state.db)
+    .await?;
+
+    let pending: Vec<ExportId> = sqlx::query_scalar("SELECT id FROM data_exports WHERE status = 'pending'")
+        .fetch_all(&state.db)
+        .await?;
+    for id in pending {
+        run(state, &id).await;
+    }
+
+    let expired: Vec<(ExportId, String)> = sqlx::query_as(
+        "UPDATE data_exports SET status = 'expired', storage_path = NULL
+         FROM (SELECT id, storage_path FROM data_exports
+               WHERE status = 'ready' AND expires_at < now() FOR UPDATE) old
+         WHERE data_exports.id = old.id
+         RETURNING old.id, old.storage_path",
+    )
+    .fetch_all(&state.db)
+    .await?;
+    for (id, path) in expired {
+        if let Err(e) = state.storage.delete(&path).await {
+            tracing::warn!("deleting export {} from storage failed: {}", id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Builds the archive for a pending export and records the outcome. A
+/// job already claimed elsewhere is left alone. The export stays pending
+/// until one of the rate limiter's expensive-work permits is free, and
+/// holds it throughout.
+pub async fn run(state: &AppState, id: &ExportId) {
+    let _permit = state.rate_limiter.expensive_job().await;
+    let claimed: Result<Option<UserId>, _> = sqlx::query_scalar(
+        "UPDATE data_exports SET status = 'running' WHERE id = $1 AND status = 'pending' RETURNING user_id",
+    )
+    .bind(id)
+    .fetch_optional(&state.db)
+    .await;
+    let user_id = match claimed {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::warn!("claiming export {} failed: {}", id, e);
+            return;
+        }
+    };
+
+    let path = format!("exports/{}/{}.zip", user_id, id);
+    let finished = match write_archive(state, &user_id, &path).await {
+        Ok(()) => sqlx::query(
+            "UPDATE data_exports SET status = 'ready', storage_path = $2, completed_at = now(), expires_at = $3
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(&path)
+        .bind(Utc::now() + ARCHIVE_TTL)
+        .execute(&state.db)
+        .await,
+        Err(e) => {
+            tracing::warn!("export {} failed: {}", id, e);
+            sqlx::query("UPDATE data_exports SET status = 'failed', error = $2, completed_at = now() WHERE id = $1")
+                .bind(id)
+                .bind(e.to_string())
+                .execute(&state.db)
+                .await
+        }
+    };
+    if let Err(e) = finished {
+        tracing::warn!("recording export {} failed: {}", id, e);
+    }
+}
+
+/// Writes the archive straight into storage as it is generated. If
+/// generating fails the stream errors, so storage keeps nothing.
+async fn write_archive(state: &AppState, user_id: &UserId, path: &str) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel(8);
+    let data = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) }).boxed();
+
+    let produce = async {
+        let result = write_contents(state, user_id, ZipWriter::new(tx.clone())).await;
+        if let Err(e) = &result {
+            let _ = tx.send(Err(io::Error::new(e.kind(), e.to_string()))).await;
+        }
+        drop(tx);
+        result
+    };
+    let (produced, stored) = tokio::join!(produce, state.storage.put(path, data));
+    produced.and(stored)
+}
+
+fn db_error(e: sqlx::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// A JSON array written to its own archive entry one element at a time.
+struct JsonArray {
+    empty: bool,
+}
+
+impl JsonArray {
+    async fn start(zip: &mut ZipWriter, name: &str) -> io::Result<Self> {
+        zip.start_file(name).await?;
+        zip.write(b"[").await?;
+        Ok(Self { empty: true })
+    }
+
+    async fn push(&mut self, zip: &mut ZipWriter, value: &impl Serialize) -> io::Result<()> {
+        let sep: &[u8] = if self.empty { b"\n  " } else { b",\n  " };
+        self.empty = false;
+        zip.write(sep).await?;
+        zip.write(&serde_json::to_vec(value)?).await
+    }
+
+    async fn end(self, zip: &mut ZipWriter) -> io::Result<()> {
+        zip.write(if self.empty { b"]\n" } else { b"\n]\n" }).await
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ProfileRow {
+    id: UserId,
+    username: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    created_at: DateTime<Utc>,
+    is_admin: bool,
+    suspended: bool,
+    suspended_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct MembershipRow {
+    channel_id: ChannelId,
+    name: String,
+    channel_type: String,
+    role: String,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct MessageRow {
+    id: MessageId,
+    channel_id: ChannelId,
+    content: Option<String>,
+    encrypted: bool,
+    created_at: DateTime<Utc>,
+    edited_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ExportedMessage {
+    #[serde(flatten)]
+    message: MessageRow,
+    /// Earlier versions of the message, oldest first.
+    previous_versions: Vec<String>,
+    /// Reactions by emoji. Who reacted belongs to the other users, so
+    /// only counts are included.
+    reactions: std::collections::BTreeMap<String, i64>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct ReactionRow {
+    message_id: MessageId,
+    channel_id: ChannelId,
+    emoji: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct FileRow {
+    id: FileId,
+    channel_id: ChannelId,
+    filename: String,
+    mime_type: String,
+    size_bytes: i64,
+    checksum: String,
+    created_at: DateTime<Utc>,
+    #[serde(skip)]
+    storage_path: String,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct AuditRow {
+    id: i64,
+    message_id: MessageId,
+    channel_id: ChannelId,
+    /// Other users who acted on the caller's messages (moderators, say)
+    /// are not named.
+    by_you: bool,
+    action: String,
+    before_hash: Option<String>,
+    after_hash: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+/// The archive: `profile.json`, `memberships.json`, `messages.json` (the
+/// user's own messages), `reactions.json` (reactions they gave),
+/// `files.json` plus each uploaded file under `files/`, and `audit.json`.
+/// Other users appear only as counts or not at all, and other users'
+/// messages are never included.
+async fn write_contents(state: &AppState, user_id: &UserId, mut zip: ZipWriter) -> io::Result<()> {
+    let db = &state.db;
+
+    let profile: Option<ProfileRow> = sqlx::query_as(
+        "SELECT id, username, display_name, bio, created_at, is_admin, suspended, suspended_until
+         FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(db)
+    .await
+    .map_err(db_error)?;
+    zip.start_file("profile.json").await?;
+    zip.write(&serde_json::to_vec_pretty(&serde_json::json!({ "user_id": user_id, "profile": profile }))?).await?;
+
+    // One row per membership; small enough to fetch whole.
+    let memberships: Vec<MembershipRow> = sqlx::query_as(
+        "SELECT c.id AS channel_id, c.name, c.channel_type, m.role
+         FROM channel_members m JOIN channels c ON c.id = m.channel_id
+         WHERE m.user_id = $1 ORDER BY c.name",
+    )
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+    .map_err(db_error)?;
+    zip.start_file("memberships.json").await?;
+    zip.write(&serde_json::to_vec_pretty(&memberships)?).await?;
+
+    let mut array = JsonArray::start(&mut zip, "messages.json").await?;
+    let mut after: Option<(DateTime<Utc>, MessageId)> = None;
+    loop {
+        let rows: Vec<MessageRow> = sqlx::query_as(
+            "SELECT id, channel_id, content, encrypted, created_at, edited_at, deleted_at
+             FROM messages
+             WHERE sender_id = $1 AND ($2::timestamptz IS NULL OR (created_at, id) > ($2, $3))
+             ORDER BY created_at, id LIMIT $4",
+        )
+        .bind(user_id)
+        .bind(after.as_ref().map(|(at, _)| *at))
+        .bind(after.as_ref().map(|(_, id)| id))
+        .bind(BATCH_SIZE)
+        .fetch_all(db)
+        .await
+        .map_err(db_error)?;
+        let Some(last) = rows.last() else { break };
+        after = Some((last.created_at, last.id.clone()));
+        let done = (rows.len() as i64) < BATCH_SIZE;
+
+        let ids: Vec<MessageId> = rows.iter().map(|m| m.id.clone()).collect();
+        let edits: Vec<(MessageId, String)> =
+            sqlx::query_as("SELECT message_id, content FROM message_edits WHERE message_id = ANY($1) ORDER BY id")
+                .bind(&ids)
+                .fetch_all(db)
+                .await
+                .map_err(db_error)?;
+        let reactions: Vec<(MessageId, String, i64)> = sqlx::query_as(
+            "SELECT message_id, emoji, COUNT(*) FROM message_reactions
+             WHERE message_id = ANY($1) GROUP BY message_id, emoji",
+        )
+        .bind(&ids)
+        .fetch_all(db)
+        .await
+        .map_err(db_error)?;
+
+        for message in rows {
+            let exported = ExportedMessage {
+                previous_versions: edits.iter().filter(|(id, _)| *id == message.id).map(|(_, c)| c.clone()).collect(),
+                reactions: reactions
+                    .iter()
+                    .filter(|(id, _, _)| *id == message.id)
+                    .map(|(_, emoji, count)| (emoji.clone(), *count))
+                    .collect(),
+                message,
+            };
+            array.push(&mut zip, &exported).await?;
+        }
+        if done {
+            break;
+        }
+    }
+    array.end(&mut zip).await?;
+
+    let mut array = JsonArray::start(&mut zip, "reactions.json").await?;
+    let mut after: Option<(DateTime<Utc>, MessageId, String)> = None;
+    loop {
+        let rows: Vec<ReactionRow> = sqlx::query_as(
+            "SELECT r.message_id, m.channel_id, r.emoji, r.created_at
+             FROM message_reactions r JOIN messages m ON m.id = r.message_id
+             WHERE r.user_id = $1
+               AND ($2::timestamptz IS NULL OR (r.created_at, r.message_id, r.emoji) > ($2, $3, $4))
+             ORDER BY r.created_at, r.message_id, r.emoji LIMIT $5",
+        )
+        .bind(user_id)
+        .bind(after.as_ref().map(|a| a.0))
+        .bind(after.as_ref().map(|a| &a.1))
+        .bind(after.as_ref().map(|a| &a.2))
+        .bind(BATCH_SIZE)
+        .fetch_all(db)
+        .await
+        .map_err(db_error)?;
+        let Some(last) = rows.last() else { break };
+        after = Some((last.created_at, last.message_id.clone(), last.emoji.clone()));
+        let done = (rows.len() as i64) < BATCH_SIZE;
+        for row in &rows {
+            array.push(&mut zip, row).await?;
+        }
+        if done {
+            break;
+        }
+    }
+    array.end(&mut zip).await?;
+
+    // Metadata first, then the contents, each in batches; the archive
+    // can't interleave two entries.
+    let mut array = JsonArray::start(&mut zip, "files.json").await?;
+    let mut after: Option<(DateTime<Utc>, FileId)> = None;
+    while let Some(rows) = next_files(state, user_id, &mut after).await? {
+        for row in &rows {
+            array.push(&mut zip, row).await?;
+        }
+    }
+    array.end(&mut zip).await?;
+
+    let mut after = None;
+    while let Some(rows) = next_files(state, user_id, &mut after).await? {
+        for row in rows {
+            let mut data = match state.storage.get(&row.storage_path, None).await {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::warn!("export skipping file {}: {}", row.id, e);
+                    continue;
+                }
+            };
+            zip.start_file(&format!("files/{}/{}", row.id, archive_name(&row.filename))).await?;
+            while let Some(chunk) = data.next().await {
+                zip.write(&chunk?).await?;
+            }
+        }
+    }
+
+    let mut array = JsonArray::start(&mut zip, "audit.json").await?;
+    let mut after = 0i64;
+    loop {
+        let rows: Vec<AuditRow> = sqlx::query_as(
+            "SELECT id, message_id, channel_id, actor_id = $1 AS by_you, action, before_hash, after_hash, created_at
+             FROM message_audit
+             WHERE id > $2 AND (actor_id = $1 OR message_id IN (SELECT id FROM messages WHERE sender_id = $1))
+             ORDER BY id LIMIT $3",
+        )
+        .bind(user_id)
+        .bind(after)
+        .bind(BATCH_SIZE)
+        .fetch_all(db)
+        .await
+        .map_err(db_error)?;
+        let Some(last) = rows.last() else { break };
+        after = last.id;
+        let done = (rows.len() as i64) < BATCH_SIZE;
+        for row in &rows {
+            array.push(&mut zip, row).await?;
+        }
+        if done {
+            break;
+        }
+    }
+    array.end(&mut zip).await?;
+
+    zip.finish().await
+}
+
+/// The next batch of the user's uploads after `after`, or `None` once
+/// they run out.
+async fn next_files(
+    state: &AppState,
+    user_id: &UserId,
+    after: &mut Option<(DateTime<Utc>, FileId)>,
+) -> io::Result<Option<Vec<FileRow>>> {
+    let rows: Vec<FileRow> = sqlx::query_as(
+        "SELECT id, channel_id, filename, mime_type, size_bytes, checksum, created_at, storage_path
+         FROM file_uploads
+         WHERE uploader_id = $1 AND scan_status = 'available'
+           AND ($2::timestamptz IS NULL OR (created_at, id) > ($2, $3))
+         ORDER BY created_at, id LIMIT $4",
+    )
+    .bind(user_id)
+    .bind(after.as_ref().map(|(at, _)| *at))
+    .bind(after.as_ref().map(|(_, id)| id))
+    .bind(BATCH_SIZE)
+    .fetch_all(&state.db)
+    .await
+    .map_err(db_error)?;
+
+    let Some(last) = rows.last() else { return Ok(None) };
+    *after = Some((last.created_at, last.id.clone()));
+    Ok(Some(rows))
+}
+
+/// `filename` as a single archive path component.
+fn archive_name(filename: &str) -> String {
+    let name: String = filename
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':') { '_' } else { c })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "file".into(),
+        name => name.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::messages::tests::insert;
+    use crate::zip::tests::read_entries;
+    use crate::{app, test_state};
+    use axum::http::{Method, Request};
+    use bytes::Bytes;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use uchat_db::audit::{record_message, MessageAudit};
+    use uchat_proto::audit::MessageAction;
+
+    async fn fetch(state: &Arc<AppState>, uri: &str) -> (StatusCode, Vec<u8>) {
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        (status, axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap().to_vec())
+    }
+
+    async fn wait_until_done(state: &Arc<AppState>, user: &UserId, id: &str) -> Value {
+        for _ in 0..200 {
+            let (_, export) = call(state, Method::GET, &format!("/api/users/me/export/{}", id), Some(user), None).await;
+            if export["status"] != "pending" && export["status"] != "running" {
+                return export;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!("export {} never finished", id);
+    }
+
+    async fn audit(state: &AppState, message: &MessageId, channel: &str, actor: &UserId, action: MessageAction) {
+        let mut conn = state.db.acquire().await.unwrap();
+        let channel_id: ChannelId = channel.parse().unwrap();
+        let entry = MessageAudit { message_id: message, channel_id: &channel_id, actor_id: actor, action, before: None, after: Some("x") };
+        record_message(&mut conn, entry).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exports_own_data_without_other_users() {
+        let Some(state) = test_state().await else { return };
+        let me = UserId::new();
+        let other = UserId::new();
+        sqlx::query("INSERT INTO users (id, username, display_name) VALUES ($1, $2, 'Me')")
+            .bind(&me)
+            .bind(format!("export-{}", me))
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let channel = create(&state, &other, "export-ctx", "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&me), Some(json!({}))).await;
+        let mine = insert(&state.db, &channel, &me, "my words", Utc::now()).await;
+        let theirs = insert(&state.db, &channel, &other, "their secret words", Utc::now()).await;
+        sqlx::query("INSERT INTO message_edits (message_id, content) VALUES ($1, 'my first draft')")
+            .bind(&mine)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        for (message, user, emoji) in [(&mine, &other, ":tada:"), (&theirs, &me, ":eyes:")] {
+            sqlx::query("INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3)")
+                .bind(message)
+                .bind(user)
+                .bind(emoji)
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+        audit(&state, &mine, &channel, &me, MessageAction::Created).await;
+        audit(&state, &mine, &channel, &other, MessageAction::Deleted).await;
+        audit(&state, &theirs, &channel, &other, MessageAction::Created).await;
+
+        let file_id = FileId::new();
+        let path = format!("{}/{}", channel, file_id);
+        let data = stream::once(async { Ok(Bytes::from_static(b"attachment body")) }).boxed();
+        state.storage.put(&path, data).await.unwrap();
+        sqlx::query(
+            "INSERT INTO file_uploads
+                 (id, channel_id, uploader_id, filename, mime_type, size_bytes, checksum, storage_backend, storage_path)
+             VALUES ($1, $2, $3, '../notes.txt', 'text/plain', 15, '', 'local', $4)",
+        )
+        .bind(&file_id)
+        .bind(&channel)
+        .bind(&me)
+        .bind(&path)
+        .execute(&state.db)
+        .await
+        .unwrap();
+
+        let (status, export) = call(&state, Method::POST, "/api/users/me/export", Some(&me), None).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(export["status"], "pending");
+        let export = wait_until_done(&state, &me, export["id"].as_str().unwrap()).await;
+        assert_eq!(export["status"], "ready");
+
+        let (status, archive) = fetch(&state, export["download_url"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let entries = read_entries(&archive);
+        let names: Vec<&str> = entries.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "profile.json",
+                "memberships.json",
+                "messages.json",
+                "reactions.json",
+                "files.json",
+                &format!("files/{}/_notes.txt", file_id),
+                "audit.json",
+            ]
+        );
+        let json = |name: &str| -> Value {
+            serde_json::from_slice(&entries.iter().find(|(n, _)| n == name).unwrap().1).unwrap()
+        };
+
+        assert_eq!(json("profile.json")["profile"]["display_name"], "Me");
+        assert_eq!(json("memberships.json")[0]["channel_id"], channel.as_str());
+        let messages = json("messages.json");
+        assert_eq!(messages.as_array().unwrap().len(), 1);
+        assert_eq!(messages[0]["content"], "my words");
+        assert_eq!(messages[0]["previous_versions"], json!(["my first draft"]));
+        assert_eq!(messages[0]["reactions"], json!({ ":tada:": 1 }));
+        assert_eq!(json("reactions.json")[0]["message_id"], theirs.as_str());
+        assert_eq!(json("files.json")[0]["filename"], "../notes.txt");
+        assert_eq!(entries[5].1, b"attachment body");
+        let audit = json("audit.json");
+        assert_eq!(audit.as_array().unwrap().len(), 2);
+        assert_eq!(audit[1]["by_you"], false);
+
+        // Nothing identifies the other user or quotes their messages.
+        let text = String::from_utf8_lossy(&archive);
+        assert!(!text.contains(other.as_str()));
+        assert!(!text.contains("their secret words"));
+
+        // A finished export frees the user to ask again; a second request
+        // while one is in flight is refused.
+        let (status, _) = call(&state, Method::POST, "/api/users/me/export", Some(&me), None).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        sqlx::query("INSERT INTO data_exports (id, user_id, status) VALUES ($1, $2, 'running')")
+            .bind(ExportId::new())
+            .bind(&other)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let (status, body) = call(&state, Method::POST, "/api/users/me/export", Some(&other), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+
+        let uri = format!("/api/users/me/export/{}", export["id"].as_str().unwrap());
+        let (status, _) = call(&state, Method::GET, &uri, Some(&other), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn download_links_are_signed_and_expire() {
+        let Some(state) = test_state().await else { return };
+        let me = UserId::new();
+        let (_, export) = call(&state, Method::POST, "/api/users/me/export", Some(&me), None).await;
+        let id: ExportId = export["id"].as_str().unwrap().parse().unwrap();
+        let export = wait_until_done(&state, &me, id.as_str()).await;
+        let url = export["download_url"].as_str().unwrap();
+
+        let (status, archive) = fetch(&state, url).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(read_entries(&archive).len(), 6);
+
+        let tampered = url.replace("signature=", "signature=00");
+        assert_eq!(fetch(&state, &tampered).await.0, StatusCode::FORBIDDEN);
+        let other_id = download_url(&state.links, &ExportId::new(), Utc::now() + LINK_TTL);
+        assert_eq!(fetch(&state, &other_id).await.0, StatusCode::NOT_FOUND);
+        let stale = download_url(&state.links, &id, Utc::now() - chrono::Duration::seconds(1));
+        let (status, body) = fetch(&state, &stale).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["code"], "link_expired");
+
+        // Past its expiry the archive is deleted and the link stops working.
+        sqlx::query("UPDATE data_exports SET expires_at = now() - interval '1 minute' WHERE id = $1")
+            .bind(&id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        sweep(&state).await.unwrap();
+        let (_, export) = call(&state, Method::GET, &format!("/api/users/me/export/{}", id), Some(&me), None).await;
+        assert_eq!(export["status"], "expired");
+        assert!(export.get("download_url").is_none());
+        assert_eq!(fetch(&state, url).await.0, StatusCode::NOT_FOUND);
+        let path = format!("exports/{}/{}.zip", me, id);
+        assert!(state.storage.get(&path, None).await.is_err());
+    }
+}
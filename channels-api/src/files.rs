@@ -0,0 +1,1024 @@
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use uchat_proto::channels::MemberRole;
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::files::{FileDownloadUrl, FileUpload, ScanStatus};
+use uchat_proto::ids::{ChannelId, FileId, UserId};
+use uchat_proto::signing::LinkSigner;
+
+use crate::auth::AuthUser;
+use crate::channels::{member_role, parse_channel_id};
+use crate::error::AppError;
+use crate::scanning;
+use crate::thumbnails::{self, ThumbnailSize};
+use crate::AppState;
+
+pub const CHANNEL_HEADER: &str = "x-channel-id";
+pub const FILENAME_HEADER: &str = "x-filename";
+/// Optional lowercase hex SHA-256 the client computed; uploads that do
+/// not match it are rejected.
+pub const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+
+const MAX_FILENAME_LEN: usize = 255;
+/// Bytes kept from the start of an upload for magic-byte sniffing.
+const SNIFF_LEN: usize = 8192;
+/// How long a link from `download-url` works.
+const LINK_TTL: chrono::Duration = chrono::Duration::seconds(60);
+
+const DEFAULT_DENIED_TYPES: &[&str] = &[
+    "application/x-executable",
+    "application/vnd.microsoft.portable-executable",
+    "application/x-mach-binary",
+    "application/vnd.android.dex",
+];
+
+/// Upload rules: `FILES_MAX_BYTES` (default 25 MiB) per file,
+/// `FILES_USER_QUOTA_BYTES` (default 1 GiB) per user, and the
+/// comma-separated `FILES_DENIED_TYPES` (default: executables) refused in
+/// channels with `restrict_file_types`.
+#[derive(Debug, Clone)]
+pub struct FilePolicy {
+    pub max_file_bytes: u64,
+    pub user_quota_bytes: u64,
+    pub denied_types: Vec<String>,
+}
+
+impl FilePolicy {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        let denied_types = match std::env::var("FILES_DENIED_TYPES") {
+            Ok(list) => list.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect(),
+            Err(_) => DEFAULT_DENIED_TYPES.iter().map(|t| t.to_string()).collect(),
+        };
+        Self {
+            max_file_bytes: var("FILES_MAX_BYTES", 25 * 1024 * 1024),
+            user_quota_bytes: var("FILES_USER_QUOTA_BYTES", 1024 * 1024 * 1024),
+            denied_types,
+        }
+    }
+
+    fn denies(&self, mime_type: &str) -> bool {
+        let essence = mime_type.split(';').next().unwrap_or("").trim();
+        self.denied_types.iter().any(|t| t.eq_ignore_ascii_case(essence))
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct FileRow {
+    id: FileId,
+    channel_id: ChannelId,
+    uploader_id: UserId,
+    filename: String,
+    mime_type: String,
+    detected_mime_type: Option<String>,
+    size_bytes: i64,
+    checksum: String,
+    storage_backend: String,
+    storage_path: String,
+    created_at: DateTime<Utc>,
+    thumbnail_mime_type: Option<String>,
+    scan_status: String,
+}
+
+impl FileRow {
+    /// Unknown statuses read as quarantined, so nothing unvetted is served.
+    fn scan_status(&self) -> ScanStatus {
+        self.scan_status.parse().unwrap_or(ScanStatus::Quarantined)
+    }
+}
+
+impl From<FileRow> for FileUpload {
+    fn from(row: FileRow) -> Self {
+        let scan_status = row.scan_status();
+        FileUpload {
+            id: row.id,
+            channel_id: row.channel_id,
+            uploader_id: row.uploader_id,
+            filename: row.filename,
+            mime_type: row.mime_type,
+            detected_mime_type: row.detected_mime_type,
+            size_bytes: row.size_bytes,
+            checksum: row.checksum,
+            created_at: row.created_at,
+            has_thumbnail: row.thumbnail_mime_type.is_some(),
+            scan_status,
+        }
+    }
+}
+
+const FILE_COLUMNS: &str = "id, channel_id, uploader_id, filename, mime_type, detected_mime_type, \
+                            size_bytes, checksum, storage_backend, storage_path, created_at, thumbnail_mime_type, \
+                            scan_status";
+
+pub fn too_large(message: &str) -> AppError {
+    AppError::new(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, message)
+}
+
+fn type_denied() -> AppError {
+    AppError::new(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ErrorCode::FileTypeDenied,
+        "this file type is not allowed in this channel",
+    )
+}
+
+/// `attachment` disposition for `filename`, reduced to its last path
+/// component with control characters, quotes and separators replaced. The
+/// plain `filename` parameter is ASCII-only; `filename*` carries the
+/// UTF-8 name percent-encoded (RFC 6266).
+fn content_disposition(filename: &str) -> String {
+    let base = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '"' | ';' | '%') { '_' } else { c })
+        .collect();
+    let cleaned = cleaned.trim().trim_start_matches('.');
+    let name = if cleaned.is_empty() { "download" } else { cleaned };
+
+    let ascii: String = name.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+    let mut encoded = String::new();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii, encoded)
+}
+
+/// Refusal unless `user_id` may read a file in `row`'s scan state: anyone
+/// in the channel once it is available, only its uploader while it is
+/// being scanned, and nobody once it is quarantined.
+fn check_scanned(row: &FileRow, user_id: &UserId) -> Result<(), AppError> {
+    match row.scan_status() {
+        ScanStatus::Available => Ok(()),
+        ScanStatus::PendingScan if row.uploader_id == *user_id => Ok(()),
+        ScanStatus::PendingScan => {
+            Err(AppError::new(StatusCode::CONFLICT, ErrorCode::Conflict, "this file is still being scanned"))
+        }
+        ScanStatus::Quarantined => {
+            Err(AppError::new(StatusCode::FORBIDDEN, ErrorCode::Forbidden, "this file was quarantined"))
+        }
+    }
+}
+
+fn storage_error() -> AppError {
+    AppError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "storage error")
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Running SHA-256, byte count and leading bytes of an upload, shared
+/// with the stream handed to storage so the handler can read them once it
+/// finishes.
+struct Meter {
+    hasher: Sha256,
+    size: u64,
+    limit: u64,
+    exceeded: bool,
+    prefix: Vec<u8>,
+}
+
+async fn used_bytes(db: &PgPool, user_id: &UserId) -> Result<u64, sqlx::Error> {
+    let used: Option<i64> = sqlx::query_scalar("SELECT SUM(size_bytes)::BIGINT FROM file_uploads WHERE uploader_id = $1")
+        .bind(user_id)
+        .fetch_one(db)
+        .await?;
+    Ok(used.unwrap_or(0) as u64)
+}
+
+async fn load_file(db: &PgPool, id: &str) -> Result<FileRow, AppError> {
+    let id: FileId = id.parse().map_err(|_| AppError::invalid("invalid file id"))?;
+    let row: Option<FileRow> = sqlx::query_as(&format!("SELECT {} FROM file_uploads WHERE id = $1", FILE_COLUMNS))
+        .bind(&id)
+        .fetch_optional(db)
+        .await?;
+    row.ok_or_else(AppError::not_found)
+}
+
+/// POST /api/files
+///
+/// The raw request body is the file. `X-Channel-Id` and `X-Filename`
+/// carry the metadata; `Content-Type` is recorded as the declared type
+/// next to the type sniffed from the content. The caller must be able to
+/// post in the channel. The quota check is made against what the user had
+/// stored when the upload started.
+///
+/// Checksum and type checks run once the content is stored, and a
+/// rejected upload's object is deleted again. With a malware scanner
+/// configured the file starts out `pending_scan`; see `scanning`. Images
+/// get thumbnails once available; `has_thumbnail` is set once they are
+/// stored.
+pub async fn upload_file(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<FileUpload>), AppError> {
+    let channel_id = parse_channel_id(
+        header_str(&headers, CHANNEL_HEADER).ok_or_else(|| AppError::invalid("missing X-Channel-Id"))?,
+    )?;
+    let filename = header_str(&headers, FILENAME_HEADER).map(str::trim).unwrap_or_default();
+    if filename.is_empty() || filename.chars().count() > MAX_FILENAME_LEN {
+        return Err(AppError::invalid(format!("X-Filename must be 1-{} characters", MAX_FILENAME_LEN)));
+    }
+    let mime_type = header_str(&headers, header::CONTENT_TYPE.as_str())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let declared_checksum = match header_str(&headers, CHECKSUM_HEADER) {
+        Some(sum) if sum.len() == 64 && sum.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Some(sum.to_ascii_lowercase())
+        }
+        Some(_) => return Err(AppError::invalid("X-Checksum-Sha256 must be 64 hex characters")),
+        None => None,
+    };
+
+    match member_role(&state.db, &channel_id, &user.user_id).await? {
+        Some(MemberRole::Write | MemberRole::Admin) => {}
+        Some(MemberRole::Read) => return Err(AppError::forbidden()),
+        None => return Err(AppError::not_found()),
+    }
+
+    let (restrict_types, archived): (bool, bool) =
+        sqlx::query_as("SELECT restrict_file_types, archived_at IS NOT NULL FROM channels WHERE id = $1")
+            .bind(&channel_id)
+            .fetch_optional(&state.db)
+            .await?
+            .unwrap_or_default();
+    if archived {
+        return Err(AppError::archived());
+    }
+    let policy = &state.file_policy;
+    if restrict_types && policy.denies(&mime_type) {
+        return Err(type_denied());
+    }
+
+    let remaining = policy.user_quota_bytes.saturating_sub(used_bytes(&state.db, &user.user_id).await?);
+    let limit = policy.max_file_bytes.min(remaining);
+
+    let declared_len = header_str(&headers, header::CONTENT_LENGTH.as_str()).and_then(|v| v.parse::<u64>().ok());
+    if declared_len.is_some_and(|len| len > limit) {
+        return Err(too_large("file exceeds the size limit or your storage quota"));
+    }
+
+    let meter = Arc::new(Mutex::new(Meter {
+        hasher: Sha256::new(),
+        size: 0,
+        limit,
+        exceeded: false,
+        prefix: Vec::new(),
+    }));
+    let stream_meter = meter.clone();
+    let data = body
+        .into_data_stream()
+        .map(move |chunk| {
+            let chunk = chunk.map_err(io::Error::other)?;
+            let mut m = stream_meter.lock().unwrap();
+            m.size += chunk.len() as u64;
+            if m.size > m.limit {
+                m.exceeded = true;
+                return Err(io::Error::other("upload exceeds limit"));
+            }
+            m.hasher.update(&chunk);
+            let keep = SNIFF_LEN.saturating_sub(m.prefix.len()).min(chunk.len());
+            m.prefix.extend_from_slice(&chunk[..keep]);
+            Ok(chunk)
+        })
+        .boxed();
+
+    let file_id = FileId::new();
+    let storage_path = format!("{}/{}", channel_id, file_id);
+
+    if let Err(e) = state.storage.put(&storage_path, data).await {
+        if meter.lock().unwrap().exceeded {
+            return Err(too_large("file exceeds the size limit or your storage quota"));
+        }
+        tracing::warn!("storing upload failed: {}", e);
+        return Err(storage_error());
+    }
+
+    let (size, checksum, detected_mime_type) = {
+        let m = meter.lock().unwrap();
+        let detected = infer::get(&m.prefix).map(|t| t.mime_type().to_string());
+        (m.size, hex::encode(m.hasher.clone().finalize()), detected)
+    };
+
+    let rejection = if declared_checksum.is_some_and(|sum| sum != checksum) {
+        Some(AppError::new(StatusCode::BAD_REQUEST, ErrorCode::ChecksumMismatch, "checksum mismatch"))
+    } else if restrict_types && detected_mime_type.as_deref().is_some_and(|t| policy.denies(t)) {
+        Some(type_denied())
+    } else {
+        None
+    };
+    if let Some(rejection) = rejection {
+        if let Err(e) = state.storage.delete(&storage_path).await {
+            tracing::warn!("deleting rejected upload {} failed: {}", storage_path, e);
+        }
+        return Err(rejection);
+    }
+
+    let inserted: Result<FileRow, sqlx::Error> = sqlx::query_as(&format!(
+        "INSERT INTO file_uploads
+             (id, channel_id, uploader_id, filename, mime_type, detected_mime_type,
+              size_bytes, checksum, storage_backend, storage_path, scan_status)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+         RETURNING {}",
+        FILE_COLUMNS
+    ))
+    .bind(&file_id)
+    .bind(&channel_id)
+    .bind(&user.user_id)
+    .bind(filename)
+    .bind(&mime_type)
+    .bind(&detected_mime_type)
+    .bind(size as i64)
+    .bind(&checksum)
+    .bind(state.storage.name())
+    .bind(&storage_path)
+    .bind(state.scanning.initial_status().as_str())
+    .fetch_one(&state.db)
+    .await;
+
+    match inserted {
+        Ok(row) => {
+            if row.scan_status() == ScanStatus::PendingScan {
+                let pending = scanning::Pending {
+                    file_id: row.id.clone(),
+                    channel_id: row.channel_id.clone(),
+                    uploader_id: row.uploader_id.clone(),
+                    filename: row.filename.clone(),
+                    storage_path: row.storage_path.clone(),
+                    detected_mime_type: row.detected_mime_type.clone(),
+                };
+                scanning::spawn(state.clone(), pending);
+            } else if thumbnails::supported(row.detected_mime_type.as_deref()) {
+                thumbnails::spawn(state.clone(), row.id.clone(), row.storage_path.clone());
+            }
+            Ok((StatusCode::CREATED, Json(row.into())))
+        }
+        Err(e) => {
+            let _ = state.storage.delete(&storage_path).await;
+            Err(e.into())
+        }
+    }
+}
+
+/// Resolves a `Range` header against a file of `size` bytes. Only single
+/// `bytes=` ranges are honored; anything else is ignored and the whole
+/// file is served. `Err` means the range cannot be satisfied.
+fn parse_range(value: &str, size: u64) -> Result<Option<Range<u64>>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else { return Ok(None) };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else { return Ok(None) };
+
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let Ok(n) = suffix.parse::<u64>() else { return Ok(None) };
+            if n == 0 || size == 0 {
+                return Err(());
+            }
+            size.saturating_sub(n)..size
+        }
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else { return Ok(None) };
+            let end = match end {
+                "" => size,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => (end + 1).min(size),
+                    _ => return Ok(None),
+                },
+            };
+            if start >= size {
+                return Err(());
+            }
+            start..end
+        }
+    };
+
+    Ok(Some(range))
+}
+
+/// `<expires>.<user_id>.<hex signature>`: who the link was made for and
+/// until when.
+fn download_token(links: &LinkSigner, file_id: &FileId, expires: i64, user_id: &UserId) -> String {
+    let signature = links.sign("file-download", &[file_id.as_str(), &expires.to_string(), user_id.as_str()]);
+    format!("{}.{}.{}", expires, user_id, signature)
+}
+
+/// The user a download token was made for, if it is genuine and unexpired.
+fn verify_download_token(links: &LinkSigner, file_id: &FileId, token: &str) -> Result<UserId, AppError> {
+    let mut parts = token.splitn(3, '.');
+    let (Some(expires), Some(user_id), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(AppError::forbidden());
+    };
+    let expires: i64 = expires.parse().map_err(|_| AppError::forbidden())?;
+    let user_id: UserId = user_id.parse().map_err(|_| AppError::forbidden())?;
+    if !links.verify("file-download", &[file_id.as_str(), &expires.to_string(), user_id.as_str()], signature) {
+        return Err(AppError::forbidden());
+    }
+    if expires < Utc::now().timestamp() {
+        return Err(AppError::new(StatusCode::GONE, ErrorCode::LinkExpired, "download link has expired"));
+    }
+    Ok(user_id)
+}
+
+/// GET /api/files/{id}/download-url
+///
+/// A link to the file that works for 60 seconds without a token, for
+/// members who may download it.
+pub async fn download_url(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<FileDownloadUrl>, AppError> {
+    let row = load_file(&state.db, &id).await?;
+    if member_role(&state.db, &row.channel_id, &user.user_id).await?.is_none() {
+        return Err(AppError::not_found());
+    }
+    check_scanned(&row, &user.user_id)?;
+
+    let expires_at = Utc::now() + LINK_TTL;
+    let token = download_token(&state.links, &row.id, expires_at.timestamp(), &user.user_id);
+    Ok(Json(FileDownloadUrl { url: format!("/api/files/{}?token={}", row.id, token), expires_at }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DownloadQuery {
+    token: Option<String>,
+}
+
+/// GET /api/files/{id}
+///
+/// Streams the file to members of its channel, honoring single byte
+/// ranges. A `token` from `download-url` stands in for the bearer token.
+/// Files not yet cleared by the malware scanner are only served to their
+/// uploader, and quarantined ones to nobody.
+pub async fn download_file(
+    State(state): State<Arc<AppState>>,
+    user: Option<AuthUser>,
+    Path(id): Path<String>,
+    Query(query): Query<DownloadQuery>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let user_id = match (&query.token, user) {
+        (Some(token), _) => {
+            let file_id: FileId = id.parse().map_err(|_| AppError::invalid("invalid file id"))?;
+            verify_download_token(&state.links, &file_id, token)?
+        }
+        (None, Some(user)) => user.user_id,
+        (None, None) => return Err(AppError::unauthorized()),
+    };
+    let row = load_file(&state.db, &id).await?;
+    // Membership is checked again, so a link stops working for someone
+    // removed from the channel since.
+    if member_role(&state.db, &row.channel_id, &user_id).await?.is_none() {
+        return Err(AppError::not_found());
+    }
+    check_scanned(&row, &user_id)?;
+    if row.storage_backend != state.storage.name() {
+        tracing::warn!("file {} is on backend {:?}, not configured", row.id, row.storage_backend);
+        return Err(storage_error());
+    }
+
+    let size = row.size_bytes as u64;
+    let range = match header_str(&headers, header::RANGE.as_str()).map(|v| parse_range(v, size)) {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            )
+                .into_response());
+        }
+        None => None,
+    };
+
+    let stream = state.storage.get(&row.storage_path, range.clone()).await.map_err(|e| {
+        tracing::warn!("reading {} failed: {}", row.storage_path, e);
+        storage_error()
+    })?;
+
+    let mut response = Response::new(Body::from_stream(stream));
+    let out = response.headers_mut();
+    out.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    // Prefer what the bytes actually are over what the uploader claimed.
+    let content_type = row.detected_mime_type.as_deref().unwrap_or(&row.mime_type);
+    out.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type).unwrap_or(HeaderValue::from_static("application/octet-stream")),
+    );
+    out.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if let Ok(disposition) = HeaderValue::from_str(&content_disposition(&row.filename)) {
+        out.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    if let Ok(etag) = HeaderValue::from_str(&format!("\"{}\"", row.checksum)) {
+        out.insert(header::ETAG, etag);
+    }
+
+    match range {
+        Some(range) => {
+            out.insert(header::CONTENT_LENGTH, HeaderValue::from(range.end - range.start));
+            out.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end - 1, size)).unwrap(),
+            );
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        }
+        None => {
+            out.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+        }
+    }
+
+    Ok(response)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ThumbnailQuery {
+    #[serde(default)]
+    size: ThumbnailSize,
+}
+
+/// GET /api/files/{id}/thumbnail?size=small|medium
+///
+/// For members of the file's channel, once `has_thumbnail` is set.
+pub async fn download_thumbnail(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<Response, AppError> {
+    let row = load_file(&state.db, &id).await?;
+    if member_role(&state.db, &row.channel_id, &user.user_id).await?.is_none() {
+        return Err(AppError::not_found());
+    }
+    check_scanned(&row, &user.user_id)?;
+    let mime_type = row.thumbnail_mime_type.ok_or_else(AppError::not_found)?;
+
+    let path = thumbnails::path(&row.storage_path, query.size);
+    let stream = state.storage.get(&path, None).await.map_err(|e| {
+        tracing::warn!("reading {} failed: {}", path, e);
+        storage_error()
+    })?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".into()),
+            (header::CACHE_CONTROL, "private, max-age=86400".into()),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+/// DELETE /api/files/{id}
+///
+/// Allowed for the uploader and for channel admins.
+pub async fn delete_file(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let row = load_file(&state.db, &id).await?;
+    let role = member_role(&state.db, &row.channel_id, &user.user_id).await?;
+    if row.uploader_id != user.user_id {
+        match role {
+            Some(MemberRole::Admin) => {}
+            Some(_) => return Err(AppError::forbidden()),
+            None => return Err(AppError::not_found()),
+        }
+    }
+
+    sqlx::query("DELETE FROM file_uploads WHERE id = $1")
+        .bind(&row.id)
+        .execute(&state.db)
+        .await?;
+
+    if let Err(e) = state.storage.delete(&row.storage_path).await {
+        tracing::warn!("deleting {} from storage failed: {}", row.storage_path, e);
+    }
+    if row.thumbnail_mime_type.is_some() {
+        thumbnails::delete(&state, &row.storage_path).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::{app, test_state};
+    use axum::http::{Method, Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use uchat_proto::jwt::create_token;
+
+    fn bearer(state: &AppState, user: &UserId) -> String {
+        format!("Bearer {}", create_token(&state.jwt_secret, user.as_str()))
+    }
+
+    async fn send(state: &Arc<AppState>, req: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, headers, body.to_vec())
+    }
+
+    async fn upload(state: &Arc<AppState>, user: &UserId, channel: &str, name: &str, data: &[u8]) -> (StatusCode, Value) {
+        upload_with(state, user, channel, name, data, &[]).await
+    }
+
+    async fn upload_with(
+        state: &Arc<AppState>,
+        user: &UserId,
+        channel: &str,
+        name: &str,
+        data: &[u8],
+        extra: &[(&str, &str)],
+    ) -> (StatusCode, Value) {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri("/api/files")
+            .header("Authorization", bearer(state, user))
+            .header(CHANNEL_HEADER, channel)
+            .header(FILENAME_HEADER, name);
+        if !extra.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-type")) {
+            req = req.header("Content-Type", "text/plain");
+        }
+        for (name, value) in extra {
+            req = req.header(*name, *value);
+        }
+        let (status, _, body) = send(state, req.body(Body::from(data.to_vec())).unwrap()).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn stored_files(state: &AppState, channel: &str) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM file_uploads WHERE channel_id = $1")
+            .bind(channel)
+            .fetch_one(&state.db)
+            .await
+            .unwrap()
+    }
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// Start of a 64-bit ELF header, padded to the full 64-byte header.
+    fn elf() -> Vec<u8> {
+        let mut header = b"\x7fELF\x02\x01\x01".to_vec();
+        header.resize(64, 0);
+        header
+    }
+
+    async fn download(state: &Arc<AppState>, user: &UserId, id: &str, range: Option<&str>) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let mut req = Request::builder()
+            .uri(format!("/api/files/{}", id))
+            .header("Authorization", bearer(state, user));
+        if let Some(range) = range {
+            req = req.header("Range", range);
+        }
+        send(state, req.body(Body::empty()).unwrap()).await
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), Ok(Some(0..5)));
+        assert_eq!(parse_range("bytes=5-", 10), Ok(Some(5..10)));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some(7..10)));
+        assert_eq!(parse_range("bytes=8-100", 10), Ok(Some(8..10)));
+        assert_eq!(parse_range("bytes=10-", 10), Err(()));
+        assert_eq!(parse_range("bytes=-1", 0), Err(()));
+        assert_eq!(parse_range("bytes=0-1,3-4", 10), Ok(None));
+        assert_eq!(parse_range("items=0-1", 10), Ok(None));
+        assert_eq!(parse_range("bytes=4-2", 10), Ok(None));
+    }
+
+    #[test]
+    fn dispositions_are_sanitized() {
+        assert_eq!(content_disposition("report.pdf"), "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf");
+        assert_eq!(
+            content_disposition("../../etc/pa\"ss\r\nwd"),
+            "attachment; filename=\"pa_ss__wd\"; filename*=UTF-8''pa_ss__wd"
+        );
+        assert_eq!(content_disposition("C:\\x\\.."), "attachment; filename=\"download\"; filename*=UTF-8''download");
+        assert_eq!(
+            content_disposition("résumé 1.txt"),
+            "attachment; filename=\"r_sum_ 1.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.txt"
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_download_delete() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "files", "public").await;
+
+        let (status, meta) = upload(&state, &owner, &channel, "notes.txt", b"hello world").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(meta["size_bytes"], 11);
+        assert_eq!(meta["checksum"], "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        let id = meta["id"].as_str().unwrap().to_string();
+
+        let (status, headers, body) = download(&state, &owner, &id, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(
+            headers["content-disposition"],
+            "attachment; filename=\"notes.txt\"; filename*=UTF-8''notes.txt"
+        );
+        assert_eq!(body, b"hello world");
+
+        let (status, headers, body) = download(&state, &owner, &id, Some("bytes=6-")).await;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(headers["content-range"], "bytes 6-10/11");
+        assert_eq!(body, b"world");
+
+        let (status, _, _) = download(&state, &owner, &id, Some("bytes=50-")).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let req = Request::delete(format!("/api/files/{}", id))
+            .header("Authorization", bearer(&state, &owner))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&state, req).await.0, StatusCode::NO_CONTENT);
+
+        let (status, _, _) = download(&state, &owner, &id, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn signed_links_download_without_a_token() {
+        let Some(state) = test_state().await else { return };
+        let (owner, outsider) = (UserId::new(), UserId::new());
+        let channel = create(&state, &owner, &format!("links-{}", owner), "public").await;
+        let (_, meta) = upload(&state, &owner, &channel, "a.txt", b"abc").await;
+        let id = meta["id"].as_str().unwrap();
+
+        let (status, _) = call(&state, Method::GET, &format!("/api/files/{}/download-url", id), Some(&outsider), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, link) = call(&state, Method::GET, &format!("/api/files/{}/download-url", id), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let url = link["url"].as_str().unwrap();
+        let get = |uri: String| send(&state, Request::get(uri).body(Body::empty()).unwrap());
+
+        let (status, headers, body) = get(url.to_string()).await;
+        assert_eq!((status, body.as_slice()), (StatusCode::OK, &b"abc"[..]));
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(get(format!("/api/files/{}", id)).await.0, StatusCode::UNAUTHORIZED);
+
+        // Tampering with the user, the expiry or the file breaks the signature.
+        let token = url.split_once("?token=").unwrap().1;
+        let (expires, rest) = token.split_once('.').unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = format!("/api/files/{}?token={}.{}.{}", id, expires, outsider, signature);
+        assert_eq!(get(forged).await.0, StatusCode::FORBIDDEN);
+        let later = format!("/api/files/{}?token={}.{}", id, expires.parse::<i64>().unwrap() + 3600, rest);
+        assert_eq!(get(later).await.0, StatusCode::FORBIDDEN);
+        let (_, other) = upload(&state, &owner, &channel, "b.txt", b"def").await;
+        let elsewhere = format!("/api/files/{}?token={}", other["id"].as_str().unwrap(), token);
+        assert_eq!(get(elsewhere).await.0, StatusCode::FORBIDDEN);
+
+        let file_id: FileId = id.parse().unwrap();
+        let expired = download_token(&state.links, &file_id, Utc::now().timestamp() - 1, &owner);
+        let (status, _, body) = get(format!("/api/files/{}?token={}", id, expired)).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["code"], "link_expired");
+    }
+
+    #[tokio::test]
+    async fn downloads_need_membership() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let outsider = UserId::new();
+        let channel = create(&state, &owner, "members-only", "public").await;
+
+        let (_, meta) = upload(&state, &owner, &channel, "a.txt", b"abc").await;
+        let id = meta["id"].as_str().unwrap();
+
+        let (status, _, _) = download(&state, &outsider, id, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = upload(&state, &outsider, &channel, "b.txt", b"abc").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Members other than the uploader or an admin cannot delete.
+        let member = UserId::new();
+        let join = Request::post(format!("/api/channels/{}/members", channel))
+            .header("Authorization", bearer(&state, &member))
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({}).to_string()))
+            .unwrap();
+        assert_eq!(send(&state, join).await.0, StatusCode::CREATED);
+
+        let req = Request::delete(format!("/api/files/{}", id))
+            .header("Authorization", bearer(&state, &member))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&state, req).await.0, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn enforces_size_and_quota() {
+        let Some(base) = test_state().await else { return };
+        let state = Arc::new(AppState {
+            db: base.db.clone(),
+            jwt_secret: base.jwt_secret.clone(),
+            links: base.links.clone(),
+            gateway: None,
+            storage: base.storage.clone(),
+            file_policy: FilePolicy { max_file_bytes: 8, user_quota_bytes: 12, denied_types: Vec::new() },
+            edit_window: base.edit_window,
+            rate_limiter: crate::rate_limit::RateLimiter::new(crate::rate_limit::RateLimits::default()),
+            push: crate::push::PushDispatcher::default(),
+            scanning: crate::scanning::Scanning::default(),
+        });
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "limits", "public").await;
+
+        let (status, body) = upload(&state, &owner, &channel, "big.bin", b"0123456789").await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["code"], "payload_too_large");
+
+        let (status, _) = upload(&state, &owner, &channel, "a.bin", b"01234567").await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _) = upload(&state, &owner, &channel, "b.bin", b"01234567").await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, _) = upload(&state, &owner, &channel, "c.bin", b"0123").await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_is_rejected() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "checksums", "public").await;
+
+        let wrong = "0".repeat(64);
+        let (status, body) =
+            upload_with(&state, &owner, &channel, "a.txt", b"hello world", &[(CHECKSUM_HEADER, &wrong)]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "checksum_mismatch");
+        assert_eq!(stored_files(&state, &channel).await, 0);
+
+        let right = "B94D27B9934D3E08A52E52D7DA7DABFAC484EFE37A5380EE9088F7ACE2EFCDE9";
+        let (status, _) =
+            upload_with(&state, &owner, &channel, "a.txt", b"hello world", &[(CHECKSUM_HEADER, right)]).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _) = upload_with(&state, &owner, &channel, "a.txt", b"x", &[(CHECKSUM_HEADER, "abc")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn spoofed_types_are_detected() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "sniffing", "public").await;
+
+        let (status, meta) = upload(&state, &owner, &channel, "cat.txt", PNG).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(meta["mime_type"], "text/plain");
+        assert_eq!(meta["detected_mime_type"], "image/png");
+
+        let (_, headers, _) = download(&state, &owner, meta["id"].as_str().unwrap(), None).await;
+        assert_eq!(headers["content-type"], "image/png");
+
+        // Unrestricted channels accept executables.
+        let (status, meta) = upload(&state, &owner, &channel, "tool.txt", &elf()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(meta["detected_mime_type"], "application/x-executable");
+    }
+
+    #[tokio::test]
+    async fn images_get_thumbnails_after_upload() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let outsider = UserId::new();
+        let channel = create(&state, &owner, "pictures", "public").await;
+
+        let (status, meta) =
+            upload_with(&state, &owner, &channel, "wide.png", &thumbnails::tests::png(1000, 500), &[("Content-Type", "image/png")])
+                .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(meta["has_thumbnail"], false);
+        let id = meta["id"].as_str().unwrap().to_string();
+
+        let thumbnail = |user: UserId, size: &'static str| {
+            let state = state.clone();
+            let uri = format!("/api/files/{}/thumbnail?size={}", id, size);
+            async move {
+                let req = Request::get(uri).header("Authorization", bearer(&state, &user)).body(Body::empty()).unwrap();
+                send(&state, req).await
+            }
+        };
+        let mut ready = false;
+        for _ in 0..100 {
+            let (status, headers, body) = thumbnail(owner.clone(), "medium").await;
+            if status == StatusCode::OK {
+                assert_eq!(headers["content-type"], "image/png");
+                assert_eq!(image::load_from_memory(&body).unwrap().width(), 640);
+                ready = true;
+                break;
+            }
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(ready, "no thumbnail was made");
+        let (status, _, body) = thumbnail(owner.clone(), "small").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(image::load_from_memory(&body).unwrap().width(), 160);
+        assert_eq!(thumbnail(outsider, "small").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(thumbnail(owner.clone(), "huge").await.0, StatusCode::BAD_REQUEST);
+
+        // Files that aren't images never get one.
+        let (_, meta) = upload(&state, &owner, &channel, "notes.txt", b"hello").await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let uri = format!("/api/files/{}/thumbnail", meta["id"].as_str().unwrap());
+        let req = Request::get(uri).header("Authorization", bearer(&state, &owner)).body(Body::empty()).unwrap();
+        assert_eq!(send(&state, req).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn restricted_channels_refuse_denied_types() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let (status, channel) = call(
+            &state,
+            Method::POST,
+            "/api/channels",
+            Some(&owner),
+            Some(json!({ "name": "locked", "restrict_file_types": true })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let channel = channel["id"].as_str().unwrap().to_string();
+
+        let (status, body) = upload(&state, &owner, &channel, "notes.txt", &elf()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body["code"], "file_type_denied");
+
+        let (status, _) = upload_with(
+            &state,
+            &owner,
+            &channel,
+            "a.bin",
+            b"plain",
+            &[("Content-Type", "application/x-executable")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(stored_files(&state, &channel).await, 0);
+
+        let (status, _) = upload(&state, &owner, &channel, "cat.png", PNG).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn empty_file() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "empty", "public").await;
+
+        let empty_sha = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let (status, meta) = upload_with(&state, &owner, &channel, "empty", b"", &[(CHECKSUM_HEADER, empty_sha)]).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(meta["size_bytes"], 0);
+        assert_eq!(meta["checksum"], empty_sha);
+        assert!(meta["detected_mime_type"].is_null());
+
+        let id = meta["id"].as_str().unwrap();
+        let (status, headers, body) = download(&state, &owner, id, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-length"], "0");
+        assert!(body.is_empty());
+
+        let (status, _, _) = download(&state, &owner, id, Some("bytes=0-")).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+}
@@ -0,0 +1,240 @@
+//! Content filter rules. Channel admins list words, phrases and regexes
+//! that block or redact messages in their channel; the gateway enforces
+//! them, so each change pushes the channel's whole list to it, and a
+//! gateway that starts up fetches every channel's.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use uchat_proto::filters::{ChannelFilterRules, CreateFilterRule, FilterRule};
+use uchat_proto::ids::{ChannelId, UserId};
+
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::invites::require_admin;
+use crate::AppState;
+
+/// Longest pattern or replacement.
+const MAX_PATTERN_CHARS: usize = 200;
+/// Most rules a channel may have; every message is checked against each.
+const MAX_RULES: i64 = 100;
+/// Compiled size limit for regex rules, well above anything sensible.
+const MAX_REGEX_BYTES: usize = 256 * 1024;
+
+#[derive(sqlx::FromRow)]
+struct RuleRow {
+    id: i64,
+    channel_id: ChannelId,
+    pattern: String,
+    is_regex: bool,
+    replacement: Option<String>,
+    created_by: UserId,
+    created_at: DateTime<Utc>,
+}
+
+impl From<RuleRow> for FilterRule {
+    fn from(row: RuleRow) -> Self {
+        FilterRule {
+            id: row.id,
+            channel_id: row.channel_id,
+            pattern: row.pattern,
+            regex: row.is_regex,
+            replacement: row.replacement,
+            created_by: row.created_by,
+            created_at: row.created_at,
+        }
+    }
+}
+
+const RULE_COLUMNS: &str = "id, channel_id, pattern, is_regex, replacement, created_by, created_at";
+
+async fn channel_rules(db: &PgPool, channel_id: &ChannelId) -> Result<Vec<FilterRule>, sqlx::Error> {
+    let rows: Vec<RuleRow> =
+        sqlx::query_as(&format!("SELECT {} FROM content_filter_rules WHERE channel_id = $1 ORDER BY id", RULE_COLUMNS))
+            .bind(channel_id)
+            .fetch_all(db)
+            .await?;
+    Ok(rows.into_iter().map(FilterRule::from).collect())
+}
+
+/// Sends the channel's current rules to the gateway.
+async fn push_rules(state: &AppState, channel_id: &ChannelId) -> Result<(), AppError> {
+    if let Some(gateway) = &state.gateway {
+        let rules = channel_rules(&state.db, channel_id).await?;
+        gateway.content_filters_changed(&ChannelFilterRules { channel_id: channel_id.clone(), rules }).await;
+    }
+    Ok(())
+}
+
+fn check_rule(rule: &CreateFilterRule) -> Result<(), AppError> {
+    let too_long = |s: &str| s.chars().count() > MAX_PATTERN_CHARS;
+    if rule.pattern.trim().is_empty() || too_long(&rule.pattern) {
+        return Err(AppError::invalid(format!("pattern must be 1-{} characters", MAX_PATTERN_CHARS)));
+    }
+    if rule.replacement.as_deref().is_some_and(too_long) {
+        return Err(AppError::invalid(format!("replacement must be at most {} characters", MAX_PATTERN_CHARS)));
+    }
+    if rule.regex {
+        regex::RegexBuilder::new(&rule.pattern)
+            .size_limit(MAX_REGEX_BYTES)
+            .build()
+            .map_err(|e| AppError::invalid(format!("invalid regex: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// GET /api/channels/{id}/filters
+pub async fn list_rules(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<FilterRule>>, AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+    Ok(Json(channel_rules(&state.db, &channel_id).await?))
+}
+
+/// POST /api/channels/{id}/filters
+///
+/// Admins only. Without a `replacement` the rule blocks matching
+/// messages; with one it replaces each match.
+pub async fn create_rule(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<CreateFilterRule>,
+) -> Result<(StatusCode, Json<FilterRule>), AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+    check_rule(&body)?;
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM content_filter_rules WHERE channel_id = $1")
+        .bind(&channel_id)
+        .fetch_one(&state.db)
+        .await?;
+    if count >= MAX_RULES {
+        return Err(AppError::conflict(format!("channels may have at most {} filter rules", MAX_RULES)));
+    }
+
+    let row: RuleRow = sqlx::query_as(&format!(
+        "INSERT INTO content_filter_rules (channel_id, pattern, is_regex, replacement, created_by)
+         VALUES ($1, $2, $3, $4, $5)
+         RETURNING {}",
+        RULE_COLUMNS
+    ))
+    .bind(&channel_id)
+    .bind(&body.pattern)
+    .bind(body.regex)
+    .bind(&body.replacement)
+    .bind(&user.user_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    push_rules(&state, &channel_id).await?;
+    tracing::info!(channel_id = %channel_id, rule_id = row.id, created_by = %user.user_id, "content filter rule added");
+    Ok((StatusCode::CREATED, Json(row.into())))
+}
+
+/// DELETE /api/channels/{id}/filters/{rule_id}
+pub async fn delete_rule(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, rule_id)): Path<(String, i64)>,
+) -> Result<StatusCode, AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+
+    let deleted = sqlx::query("DELETE FROM content_filter_rules WHERE id = $1 AND channel_id = $2")
+        .bind(rule_id)
+        .bind(&channel_id)
+        .execute(&state.db)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(AppError::not_found());
+    }
+
+    push_rules(&state, &channel_id).await?;
+    tracing::info!(channel_id = %channel_id, rule_id, deleted_by = %user.user_id, "content filter rule removed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /internal/content-filters
+///
+/// Every channel's rules, for a gateway that just started.
+pub async fn all_rules(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ChannelFilterRules>>, AppError> {
+    if !state.push.gateway_authorized(&headers) {
+        return Err(AppError::forbidden());
+    }
+
+    let rows: Vec<RuleRow> = sqlx::query_as(&format!("SELECT {} FROM content_filter_rules ORDER BY id", RULE_COLUMNS))
+        .fetch_all(&state.db)
+        .await?;
+    let mut channels: BTreeMap<ChannelId, Vec<FilterRule>> = BTreeMap::new();
+    for row in rows {
+        channels.entry(row.channel_id.clone()).or_default().push(row.into());
+    }
+    Ok(Json(channels.into_iter().map(|(channel_id, rules)| ChannelFilterRules { channel_id, rules }).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use axum::http::Method;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn channel_admins_manage_rules_the_gateway_can_fetch() {
+        let Some(mut state) = test_state().await else { return };
+        Arc::get_mut(&mut state).unwrap().push.internal_token = Some("internal-secret".into());
+        let (admin, stranger) = (UserId::new(), UserId::new());
+        let channel = create(&state, &admin, &format!("filtered-{}", admin), "public").await;
+        let uri = format!("/api/channels/{}/filters", channel);
+
+        let rule = json!({ "pattern": "darn", "replacement": "****" });
+        let (status, _) = call(&state, Method::POST, &uri, Some(&stranger), Some(rule.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call(&state, Method::POST, &uri, Some(&admin), Some(json!({ "pattern": "(x", "regex": true }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().starts_with("invalid regex"));
+        let (status, _) = call(&state, Method::POST, &uri, Some(&admin), Some(json!({ "pattern": " " }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, redact) = call(&state, Method::POST, &uri, Some(&admin), Some(rule)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((redact["pattern"].as_str(), redact["replacement"].as_str()), (Some("darn"), Some("****")));
+        let block = json!({ "pattern": r"\bfalcon-\d+\b", "regex": true });
+        let (status, block) = call(&state, Method::POST, &uri, Some(&admin), Some(block)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((block["regex"].as_bool(), block.get("replacement")), (Some(true), None));
+
+        let (_, listed) = call(&state, Method::GET, &uri, Some(&admin), None).await;
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-internal-token", "internal-secret".parse().unwrap());
+        let Json(all) = all_rules(State(state.clone()), headers).await.unwrap();
+        let ours = all.iter().find(|c| c.channel_id.as_str() == channel).unwrap();
+        let ids: Vec<i64> = ours.rules.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![redact["id"].as_i64().unwrap(), block["id"].as_i64().unwrap()]);
+        assert!(all_rules(State(state.clone()), HeaderMap::new()).await.is_err());
+
+        let rule_uri = format!("{}/{}", uri, redact["id"]);
+        let (status, _) = call(&state, Method::DELETE, &rule_uri, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&state, Method::DELETE, &rule_uri, Some(&admin), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, listed) = call(&state, Method::GET, &uri, Some(&admin), None).await;
+        assert_eq!(listed[0]["id"], block["id"]);
+    }
+}
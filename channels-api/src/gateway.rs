@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use uchat_proto::channels::{Channel, ChannelArchiveChanged, ChannelCreated, MembershipChange};
+use uchat_proto::files::FileQuarantined;
+use uchat_proto::filters::ChannelFilterRules;
+use uchat_proto::ids::UserId;
+use uchat_proto::moderation::{HeldUser, SpamDecided, SpamDecision, SpamDecisionRequest};
+use uchat_proto::messages::{
+    LinkPreviewReady, MessageDeleted, MessageEdited, MessagePosted, MessagesExpired, ReactionChanged,
+    ScheduledMessageCancelled,
+};
+use uchat_proto::users::UserPresence;
+use uchat_telemetry::{current_correlation_id, CORRELATION_ID_HEADER};
+
+/// Pushes changes to gateway-service's internal endpoints so open sockets
+/// see them right away: membership changes re-authorize (or kick) the
+/// user, channel settings and archiving change how the room's messages
+/// are handled, messages posted over HTTP go out to the room, edits and
+/// reactions update cached messages, unfurled links get their previews,
+/// expired or deleted messages are dropped from clients' caches, and
+/// uploaders hear when their file is quarantined and authors when their
+/// scheduled message is dropped. Moderators' spam decisions and channels'
+/// content filter rules go the same way.
+#[derive(Clone)]
+pub struct GatewayNotifier {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl GatewayNotifier {
+    pub fn new(base_url: &str, token: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(2))
+            .build()
+            .expect("build gateway client");
+
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    /// Reads `GATEWAY_INTERNAL_URL` and `GATEWAY_INTERNAL_TOKEN`; `None`
+    /// when either is unset.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("GATEWAY_INTERNAL_URL").ok().filter(|v| !v.is_empty())?;
+        let token = std::env::var("GATEWAY_INTERNAL_TOKEN").ok().filter(|v| !v.is_empty())?;
+        Some(Self::new(&url, token))
+    }
+
+    pub async fn membership_changed(&self, change: &MembershipChange) {
+        self.post("/internal/membership", change).await
+    }
+
+    pub async fn channel_created(&self, created: &ChannelCreated) {
+        self.post("/internal/channel-created", created).await
+    }
+
+    pub async fn channel_updated(&self, channel: &Channel) {
+        self.post("/internal/channel-updated", channel).await
+    }
+
+    pub async fn content_filters_changed(&self, rules: &ChannelFilterRules) {
+        self.post("/internal/content-filters", rules).await
+    }
+
+    pub async fn channel_archive_changed(&self, change: &ChannelArchiveChanged) {
+        self.post("/internal/channel-archived", change).await
+    }
+
+    pub async fn messages_expired(&self, expired: &MessagesExpired) {
+        self.post("/internal/messages-expired", expired).await
+    }
+
+    pub async fn message_edited(&self, edited: &MessageEdited) {
+        self.post("/internal/message-edited", edited).await
+    }
+
+    pub async fn reaction_changed(&self, change: &ReactionChanged) {
+        self.post("/internal/reaction", change).await
+    }
+
+    pub async fn message_deleted(&self, deleted: &MessageDeleted) {
+        self.post("/internal/message-deleted", deleted).await
+    }
+
+    pub async fn message_posted(&self, posted: &MessagePosted) {
+        self.post("/internal/message-posted", posted).await
+    }
+
+    pub async fn link_preview(&self, ready: &LinkPreviewReady) {
+        self.post("/internal/link-preview", ready).await
+    }
+
+    pub async fn file_quarantined(&self, quarantined: &FileQuarantined) {
+        self.post("/internal/file-quarantined", quarantined).await
+    }
+
+    pub async fn scheduled_cancelled(&self, cancelled: &ScheduledMessageCancelled) {
+        self.post("/internal/scheduled-cancelled", cancelled).await
+    }
+
+    /// Which of `user_ids` have a live gateway connection, or `None` when
+    /// the gateway can't be reached.
+    pub async fn online(&self, user_ids: &[UserId]) -> Option<Vec<UserId>> {
+        let ids = user_ids.iter().map(UserId::as_str).collect::<Vec<_>>().join(",");
+        let result = with_correlation_id(self.client.get(format!("{}/internal/presence", self.base_url)))
+            .header("x-internal-token", &self.token)
+            .query(&[("ids", ids)])
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+
+        match result {
+            Ok(resp) => resp.json::<UserPresence>().await.ok().map(|p| p.online),
+            Err(e) => {
+                tracing::warn!("failed to ask gateway for presence: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Users the gateway holds messages from as spam.
+    pub async fn held_users(&self) -> Result<Vec<HeldUser>, reqwest::Error> {
+        with_correlation_id(self.client.get(format!("{}/internal/spam", self.base_url)))
+            .header("x-internal-token", &self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Applies a moderator's decision on a held user; `None` when the
+    /// gateway isn't holding them.
+    pub async fn decide_spam(&self, user_id: &UserId, decision: SpamDecision) -> Result<Option<SpamDecided>, reqwest::Error> {
+        let resp = with_correlation_id(self.client.post(format!("{}/internal/spam/{}", self.base_url, user_id)))
+            .header("x-internal-token", &self.token)
+            .json(&SpamDecisionRequest { decision })
+            .send()
+            .await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        resp.error_for_status()?.json().await.map(Some)
+    }
+
+    /// Best effort: the database change has already been committed, so a
+    /// gateway that is down only delays enforcement until reconnect.
+    async fn post(&self, path: &str, body: &impl Serialize) {
+        let result = with_correlation_id(self.client.post(format!("{}{}", self.base_url, path)))
+            .header("x-internal-token", &self.token)
+            .json(body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+
+        if let Err(e) = result {
+            tracing::warn!("failed to notify gateway ({}): {}", path, e);
+        }
+    }
+}
+
+/// Tags a call made while handling a request with that request's
+/// correlation id.
+fn with_correlation_id(req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match current_correlation_id() {
+        Some(id) => req.header(CORRELATION_ID_HEADER, id),
+        None => req,
+    }
+}
@@ -0,0 +1,387 @@
+//! Incoming webhooks, for CI systems and monitoring tools that post into a
+//! channel over plain HTTP. Channel admins create and revoke a channel's
+//! hooks. A post authenticates with its hook's secret, either as a bearer
+//! token or as an `X-Hub-Signature-256` HMAC of the body, and is stored
+//! and sent to the room like any other message, shown under the hook's
+//! display name.
+
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+
+use uchat_db::audit::{record_message, MessageAudit};
+use uchat_proto::audit::MessageAction;
+use uchat_proto::channels::{CreateIncomingWebhook, IncomingWebhook};
+use uchat_proto::ids::{ChannelId, MessageId, UserId, WebhookId};
+use uchat_proto::messages::{Message, MessagePosted, WebhookPost};
+use uchat_proto::signing;
+
+use crate::auth::AuthUser;
+use crate::channels::find_channel;
+use crate::error::AppError;
+use crate::files::too_large;
+use crate::invites::{hash_token, new_token, require_admin};
+use crate::messages::{MessageRow, MESSAGE_COLUMNS};
+use crate::AppState;
+
+/// Longest display name, and longest `username` a post may show instead.
+const MAX_NAME_CHARS: usize = 80;
+/// Largest body a hook accepts.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+
+#[derive(sqlx::FromRow)]
+struct HookRow {
+    id: WebhookId,
+    channel_id: ChannelId,
+    display_name: String,
+    secret: String,
+    created_by: UserId,
+    created_at: DateTime<Utc>,
+}
+
+impl From<HookRow> for IncomingWebhook {
+    fn from(row: HookRow) -> Self {
+        IncomingWebhook {
+            id: row.id,
+            channel_id: row.channel_id,
+            display_name: row.display_name,
+            created_by: row.created_by,
+            created_at: row.created_at,
+            secret: None,
+        }
+    }
+}
+
+const HOOK_COLUMNS: &str = "id, channel_id, display_name, secret, created_by, created_at";
+
+fn check_name(name: &str, field: &str) -> Result<(), AppError> {
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Err(AppError::invalid(format!("{} must be 1-{} characters", field, MAX_NAME_CHARS)));
+    }
+    Ok(())
+}
+
+/// POST /api/channels/{id}/hooks
+///
+/// Admins only. The response is the one place the secret appears.
+pub async fn create_hook(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<CreateIncomingWebhook>,
+) -> Result<(StatusCode, Json<IncomingWebhook>), AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+    let display_name = body.display_name.trim();
+    check_name(display_name, "display_name")?;
+
+    let row: HookRow = sqlx::query_as(&format!(
+        "INSERT INTO incoming_webhooks (id, channel_id, display_name, secret, created_by)
+         VALUES ($1, $2, $3, $4, $5)
+         RETURNING {}",
+        HOOK_COLUMNS
+    ))
+    .bind(WebhookId::new())
+    .bind(&channel_id)
+    .bind(display_name)
+    .bind(new_token())
+    .bind(&user.user_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    let secret = row.secret.clone();
+    Ok((StatusCode::CREATED, Json(IncomingWebhook { secret: Some(secret), ..row.into() })))
+}
+
+/// GET /api/channels/{id}/hooks
+///
+/// The channel's unrevoked hooks, without their secrets.
+pub async fn list_hooks(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<IncomingWebhook>>, AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+
+    let rows: Vec<HookRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM incoming_webhooks WHERE channel_id = $1 AND revoked_at IS NULL ORDER BY created_at, id",
+        HOOK_COLUMNS
+    ))
+    .bind(&channel_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(rows.into_iter().map(IncomingWebhook::from).collect()))
+}
+
+/// DELETE /api/channels/{id}/hooks/{hook_id}
+///
+/// Revokes the hook; posts to it are refused at once.
+pub async fn revoke_hook(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, hook_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+    let hook_id: WebhookId = hook_id.parse().map_err(|_| AppError::not_found())?;
+
+    let revoked = sqlx::query(
+        "UPDATE incoming_webhooks SET revoked_at = COALESCE(revoked_at, now())
+         WHERE id = $1 AND channel_id = $2",
+    )
+    .bind(&hook_id)
+    .bind(&channel_id)
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    if revoked == 0 {
+        return Err(AppError::not_found());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Checks `Authorization: Bearer <secret>` or, failing that, an
+/// `X-Hub-Signature-256: sha256=<hex>` HMAC-SHA256 of the body keyed with
+/// the secret.
+fn authenticate(secret: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), AppError> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(token) = header(header::AUTHORIZATION.as_str()).and_then(|v| v.strip_prefix("Bearer ")) {
+        // Digests are compared so the time taken says nothing about the secret.
+        if hash_token(token) == hash_token(secret) {
+            return Ok(());
+        }
+        return Err(AppError::unauthorized());
+    }
+    let signature = header(SIGNATURE_HEADER).and_then(|v| v.strip_prefix("sha256=")).ok_or_else(AppError::unauthorized)?;
+    if !signing::verify(secret.as_bytes(), body, signature) {
+        return Err(AppError::unauthorized());
+    }
+    Ok(())
+}
+
+/// The message text: `text`, then a paragraph per attachment with its
+/// title, link and text.
+fn render(post: &WebhookPost) -> String {
+    let mut paragraphs = vec![post.text.trim().to_string()];
+    for attachment in &post.attachments {
+        let title = match (&attachment.title, &attachment.title_link) {
+            (Some(title), Some(link)) => Some(format!("{} ({})", title.trim(), link.trim())),
+            (Some(title), None) => Some(title.trim().to_string()),
+            (None, link) => link.as_ref().map(|link| link.trim().to_string()),
+        };
+        let lines: Vec<String> = [title, attachment.text.as_ref().map(|t| t.trim().to_string())]
+            .into_iter()
+            .flatten()
+            .filter(|line| !line.is_empty())
+            .collect();
+        paragraphs.push(lines.join("\n"));
+    }
+    paragraphs.retain(|p| !p.is_empty());
+    paragraphs.join("\n\n")
+}
+
+/// POST /api/hooks/{hook_id}
+///
+/// Takes a `WebhookPost` authenticated with the hook's secret and posts
+/// it to the hook's channel, from the hook. Revoked hooks are reported as
+/// missing; bodies over 64 KiB are refused.
+pub async fn post_to_hook(
+    State(state): State<Arc<AppState>>,
+    Path(hook_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<(StatusCode, Json<Message>), AppError> {
+    let hook_id: WebhookId = hook_id.parse().map_err(|_| AppError::not_found())?;
+    let hook: HookRow = sqlx::query_as(&format!(
+        "SELECT {} FROM incoming_webhooks WHERE id = $1 AND revoked_at IS NULL",
+        HOOK_COLUMNS
+    ))
+    .bind(&hook_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(AppError::not_found)?;
+
+    let body: Bytes = axum::body::to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|_| too_large("webhook body exceeds 64 KiB"))?;
+    authenticate(&hook.secret, &headers, &body)?;
+
+    let post: WebhookPost =
+        serde_json::from_slice(&body).map_err(|e| AppError::invalid(format!("invalid webhook body: {}", e)))?;
+    let content = render(&post);
+    if content.is_empty() {
+        return Err(AppError::invalid("text or attachments must not be empty"));
+    }
+    let sender_name = match post.username.as_deref().map(str::trim) {
+        Some(username) if !username.is_empty() => {
+            check_name(username, "username")?;
+            username.to_string()
+        }
+        _ => hook.display_name.clone(),
+    };
+
+    let channel = find_channel(&state.db, &hook.channel_id).await?;
+    if channel.archived_at.is_some() {
+        return Err(AppError::archived());
+    }
+    // Hooks post plaintext.
+    if channel.e2ee_required {
+        return Err(AppError::e2ee_required());
+    }
+
+    // The hook posts as itself, so its messages can be told apart from any
+    // user's.
+    let sender_id = UserId::from(hook.id.as_uuid());
+    let mut tx = state.db.begin().await?;
+    let row: MessageRow = sqlx::query_as(&format!(
+        "INSERT INTO messages (id, channel_id, sender_id, sender_name, content)
+         VALUES ($1, $2, $3, $4, $5)
+         RETURNING {}",
+        MESSAGE_COLUMNS
+    ))
+    .bind(MessageId::new())
+    .bind(&channel.id)
+    .bind(&sender_id)
+    .bind(&sender_name)
+    .bind(&content)
+    .fetch_one(&mut *tx)
+    .await?;
+    let message = Message::from(row);
+    record_message(
+        &mut tx,
+        MessageAudit {
+            message_id: &message.id,
+            channel_id: &message.channel_id,
+            actor_id: &sender_id,
+            action: MessageAction::Created,
+            before: None,
+            after: Some(&content),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+
+    if let Some(gateway) = &state.gateway {
+        let posted = MessagePosted {
+            id: message.id.clone(),
+            channel_id: message.channel_id.clone(),
+            sender_id,
+            sender_name: Some(sender_name),
+            content,
+            thread_id: None,
+        };
+        gateway.message_posted(&posted).await;
+    }
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::{app, test_state};
+    use axum::http::{Method, Request};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    async fn post(state: &Arc<AppState>, hook_id: &str, header: Option<(&str, String)>, body: &str) -> (StatusCode, Value) {
+        let mut req = Request::post(format!("/api/hooks/{}", hook_id)).header("Content-Type", "application/json");
+        if let Some((name, value)) = header {
+            req = req.header(name, value);
+        }
+        let resp = app(state.clone()).oneshot(req.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+        let status = resp.status();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    fn bearer(secret: &str) -> Option<(&'static str, String)> {
+        Some(("Authorization", format!("Bearer {}", secret)))
+    }
+
+    fn signed(secret: &str, body: &str) -> Option<(&'static str, String)> {
+        Some(("X-Hub-Signature-256", format!("sha256={}", signing::sign(secret.as_bytes(), body.as_bytes()))))
+    }
+
+    #[test]
+    fn attachments_become_paragraphs() {
+        let post: WebhookPost = serde_json::from_value(json!({
+            "text": "deploy finished",
+            "attachments": [
+                {"title": "build #12", "title_link": "https://ci.example/12", "text": "all green"},
+                {"text": "took 4m"},
+                {}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(render(&post), "deploy finished\n\nbuild #12 (https://ci.example/12)\nall green\n\ntook 4m");
+        assert_eq!(render(&WebhookPost::default()), "");
+    }
+
+    #[tokio::test]
+    async fn hooks_post_as_themselves_until_revoked() {
+        let Some(state) = test_state().await else { return };
+        let (admin, member) = (UserId::new(), UserId::new());
+        let channel = create(&state, &admin, "hooks", "public").await;
+        let hooks = format!("/api/channels/{}/hooks", channel);
+
+        let (status, _) = call(&state, Method::POST, &hooks, Some(&member), Some(json!({"display_name": "CI"}))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call(&state, Method::POST, &hooks, Some(&admin), Some(json!({"display_name": " "}))).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_request")));
+        let (status, hook) = call(&state, Method::POST, &hooks, Some(&admin), Some(json!({"display_name": "CI"}))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (id, secret) = (hook["id"].as_str().unwrap(), hook["secret"].as_str().unwrap());
+        let (_, listed) = call(&state, Method::GET, &hooks, Some(&admin), None).await;
+        assert_eq!(listed[0]["id"], id);
+        assert!(listed[0].get("secret").is_none());
+
+        let (status, message) = post(&state, id, bearer(secret), r#"{"text": "build passed"}"#).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((message["sender_id"].as_str(), message["sender_name"].as_str()), (Some(id), Some("CI")));
+        let body = r#"{"text": "deployed", "username": "Deploy Bot"}"#;
+        let (status, message) = post(&state, id, signed(secret, body), body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(message["sender_name"], "Deploy Bot");
+        let (_, page) = call(&state, Method::GET, &format!("/api/channels/{}/messages", channel), Some(&admin), None).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 2);
+
+        assert_eq!(post(&state, id, bearer("wrong"), body).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(post(&state, id, signed("wrong", body), body).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(post(&state, id, None, body).await.0, StatusCode::UNAUTHORIZED);
+
+        let (status, error) = post(&state, id, bearer(secret), r#"{"text": 5}"#).await;
+        assert_eq!((status, error["code"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_request")));
+        assert_eq!(post(&state, id, bearer(secret), "{}").await.0, StatusCode::BAD_REQUEST);
+        let huge = json!({"text": "x".repeat(MAX_BODY_BYTES)}).to_string();
+        let (status, error) = post(&state, id, bearer(secret), &huge).await;
+        assert_eq!((status, error["code"].as_str()), (StatusCode::PAYLOAD_TOO_LARGE, Some("payload_too_large")));
+
+        let (status, _) = call(&state, Method::DELETE, &format!("{}/{}", hooks, id), Some(&admin), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(post(&state, id, bearer(secret), body).await.0, StatusCode::NOT_FOUND);
+        let (_, listed) = call(&state, Method::GET, &hooks, Some(&admin), None).await;
+        assert_eq!(listed, json!([]));
+    }
+
+    #[tokio::test]
+    async fn hooks_cannot_post_to_e2ee_only_channels() {
+        let Some(state) = test_state().await else { return };
+        let admin = UserId::new();
+        let body = json!({ "name": format!("sealed-{}", admin), "channel_type": "public", "e2ee_required": true });
+        let (_, channel) = call(&state, Method::POST, "/api/channels", Some(&admin), Some(body)).await;
+        let hooks = format!("/api/channels/{}/hooks", channel["id"].as_str().unwrap());
+        let (_, hook) = call(&state, Method::POST, &hooks, Some(&admin), Some(json!({"display_name": "CI"}))).await;
+
+        let (id, secret) = (hook["id"].as_str().unwrap(), hook["secret"].as_str().unwrap());
+        let (status, body) = post(&state, id, bearer(secret), r#"{"text": "build passed"}"#).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::CONFLICT, Some("e2ee_required")));
+    }
+}
@@ -0,0 +1,375 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use uchat_proto::channels::{Channel, CreateInvite, Invite, InvitePreview, MemberRole, MembershipChange};
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::{ChannelId, InviteId, UserId};
+
+use crate::auth::AuthUser;
+use crate::channels::{find_channel, parse_channel_id, visible_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+/// Upper bound on `max_uses`.
+const MAX_INVITE_USES: i32 = 10_000;
+
+#[derive(sqlx::FromRow)]
+struct InviteRow {
+    id: InviteId,
+    channel_id: ChannelId,
+    created_by: UserId,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    max_uses: Option<i32>,
+    remaining_uses: Option<i32>,
+}
+
+impl From<InviteRow> for Invite {
+    fn from(row: InviteRow) -> Self {
+        Invite {
+            id: row.id,
+            channel_id: row.channel_id,
+            created_by: row.created_by,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            max_uses: row.max_uses,
+            remaining_uses: row.remaining_uses,
+            token: None,
+        }
+    }
+}
+
+const INVITE_COLUMNS: &str = "id, channel_id, created_by, created_at, expires_at, max_uses, remaining_uses";
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// 244 random bits, hex encoded.
+pub fn new_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Refuses an expired or used-up invite.
+fn check_usable(row: &InviteRow) -> Result<(), AppError> {
+    if row.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(AppError::new(StatusCode::GONE, ErrorCode::InviteExpired, "invite has expired"));
+    }
+    if row.remaining_uses.is_some_and(|uses| uses <= 0) {
+        return Err(AppError::new(StatusCode::GONE, ErrorCode::InviteExhausted, "invite has no uses left"));
+    }
+    Ok(())
+}
+
+/// Revoked invites are reported as missing.
+async fn find_invite(conn: &mut sqlx::PgConnection, token: &str, lock: bool) -> Result<InviteRow, AppError> {
+    let row: Option<InviteRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM channel_invites WHERE token_hash = $1 AND revoked_at IS NULL{}",
+        INVITE_COLUMNS,
+        if lock { " FOR UPDATE" } else { "" }
+    ))
+    .bind(hash_token(token))
+    .fetch_optional(conn)
+    .await?;
+    row.ok_or_else(AppError::not_found)
+}
+
+/// The channel `id` names, if `user` is one of its admins.
+pub async fn require_admin(state: &AppState, user: &AuthUser, id: &str) -> Result<ChannelId, AppError> {
+    let channel_id = parse_channel_id(id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+    Ok(channel_id)
+}
+
+/// POST /api/channels/{id}/invites
+///
+/// Admins only. The response is the one place the token appears.
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<CreateInvite>,
+) -> Result<(StatusCode, Json<Invite>), AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+    if body.expires_at.is_some_and(|at| at <= Utc::now()) {
+        return Err(AppError::invalid("expires_at must be in the future"));
+    }
+    if body.max_uses.is_some_and(|uses| !(1..=MAX_INVITE_USES).contains(&uses)) {
+        return Err(AppError::invalid(format!("max_uses must be 1-{}", MAX_INVITE_USES)));
+    }
+
+    let token = new_token();
+    let row: InviteRow = sqlx::query_as(&format!(
+        "INSERT INTO channel_invites (id, channel_id, token_hash, created_by, expires_at, max_uses, remaining_uses)
+         VALUES ($1, $2, $3, $4, $5, $6, $6)
+         RETURNING {}",
+        INVITE_COLUMNS
+    ))
+    .bind(InviteId::new())
+    .bind(&channel_id)
+    .bind(hash_token(&token))
+    .bind(&user.user_id)
+    .bind(body.expires_at)
+    .bind(body.max_uses)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(Invite { token: Some(token), ..row.into() })))
+}
+
+/// GET /api/channels/{id}/invites
+///
+/// The channel's unrevoked invites, expired and used-up ones included.
+pub async fn list_invites(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Invite>>, AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+
+    let rows: Vec<InviteRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM channel_invites WHERE channel_id = $1 AND revoked_at IS NULL ORDER BY created_at, id",
+        INVITE_COLUMNS
+    ))
+    .bind(&channel_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(rows.into_iter().map(Invite::from).collect()))
+}
+
+/// DELETE /api/channels/{id}/invites/{invite_id}
+///
+/// Revokes the invite; its token stops working at once.
+pub async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, invite_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let channel_id = require_admin(&state, &user, &id).await?;
+    let invite_id: InviteId = invite_id.parse().map_err(|_| AppError::not_found())?;
+
+    let revoked = sqlx::query(
+        "UPDATE channel_invites SET revoked_at = COALESCE(revoked_at, now())
+         WHERE id = $1 AND channel_id = $2",
+    )
+    .bind(&invite_id)
+    .bind(&channel_id)
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    if revoked == 0 {
+        return Err(AppError::not_found());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// GET /api/invites/{token}
+///
+/// Enough about the channel to decide whether to join, shown even for
+/// private channels since the token was handed out by an admin.
+pub async fn preview_invite(
+    State(state): State<Arc<AppState>>,
+    _user: AuthUser,
+    Path(token): Path<String>,
+) -> Result<Json<InvitePreview>, AppError> {
+    let mut conn = state.db.acquire().await?;
+    let invite = find_invite(&mut conn, &token, false).await?;
+    check_usable(&invite)?;
+
+    let channel = find_channel(&state.db, &invite.channel_id).await?;
+    let member_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM channel_members WHERE channel_id = $1")
+        .bind(&channel.id)
+        .fetch_one(&state.db)
+        .await?;
+
+    Ok(Json(InvitePreview {
+        channel_id: channel.id,
+        name: channel.name,
+        description: channel.description,
+        channel_type: channel.channel_type,
+        member_count,
+        expires_at: invite.expires_at,
+    }))
+}
+
+/// POST /api/invites/{token}/accept
+///
+/// Joins the caller as a writer: 201 when they joined, 200 when they were
+/// already a member, in which case no use is spent.
+pub async fn accept_invite(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(token): Path<String>,
+) -> Result<(StatusCode, Json<Channel>), AppError> {
+    // The invite row stays locked until commit, so concurrent accepts
+    // spend its uses one at a time.
+    let mut tx = state.db.begin().await?;
+    let invite = find_invite(&mut tx, &token, true).await?;
+
+    let existing: Option<String> =
+        sqlx::query_scalar("SELECT role FROM channel_members WHERE channel_id = $1 AND user_id = $2")
+            .bind(&invite.channel_id)
+            .bind(&user.user_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    let joined = if existing.is_some() {
+        false
+    } else {
+        check_usable(&invite)?;
+        let inserted = sqlx::query(
+            "INSERT INTO channel_members (channel_id, user_id, role) VALUES ($1, $2, 'write')
+             ON CONFLICT (channel_id, user_id) DO NOTHING",
+        )
+        .bind(&invite.channel_id)
+        .bind(&user.user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+        if inserted {
+            sqlx::query(
+                "UPDATE channel_invites SET remaining_uses = remaining_uses - 1
+                 WHERE id = $1 AND remaining_uses IS NOT NULL",
+            )
+            .bind(&invite.id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        inserted
+    };
+    tx.commit().await?;
+
+    let channel = find_channel(&state.db, &invite.channel_id).await?;
+    if !joined {
+        return Ok((StatusCode::OK, Json(channel)));
+    }
+    if let Some(gateway) = &state.gateway {
+        let change = MembershipChange {
+            channel_id: channel.id.clone(),
+            user_id: user.user_id,
+            role: Some(MemberRole::Write),
+        };
+        gateway.membership_changed(&change).await;
+    }
+    Ok((StatusCode::CREATED, Json(channel)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use axum::http::Method;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn invite_lifecycle() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let (guest, late) = (UserId::new(), UserId::new());
+        let channel = create(&state, &owner, "invite-only", "private").await;
+        let invites = format!("/api/channels/{}/invites", channel);
+
+        let (status, _) = call(&state, Method::POST, &invites, Some(&guest), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, invite) = call(&state, Method::POST, &invites, Some(&owner), Some(json!({ "max_uses": 1 }))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let token = invite["token"].as_str().unwrap().to_string();
+
+        let (status, preview) = call(&state, Method::GET, &format!("/api/invites/{}", token), Some(&guest), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(preview["name"], "invite-only");
+        assert_eq!(preview["member_count"], 1);
+
+        let accept = format!("/api/invites/{}/accept", token);
+        let (status, body) = call(&state, Method::POST, &accept, Some(&guest), None).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["id"], channel.as_str());
+        let (status, _) = call(&state, Method::POST, &accept, Some(&guest), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::GET, &format!("/api/channels/{}", channel), Some(&guest), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(&state, Method::POST, &accept, Some(&late), None).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body["code"], "invite_exhausted");
+
+        let (_, list) = call(&state, Method::GET, &invites, Some(&owner), None).await;
+        assert_eq!(list[0]["remaining_uses"], 0);
+        assert!(list[0].get("token").is_none());
+
+        let (status, _) =
+            call(&state, Method::DELETE, &format!("{}/{}", invites, invite["id"].as_str().unwrap()), Some(&owner), None)
+                .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&state, Method::GET, &format!("/api/invites/{}", token), Some(&late), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn expired_invites_are_refused() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "expiring", "private").await;
+        let invites = format!("/api/channels/{}/invites", channel);
+
+        let (status, _) =
+            call(&state, Method::POST, &invites, Some(&owner), Some(json!({ "expires_at": "2020-01-01T00:00:00Z" })))
+                .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, invite) = call(&state, Method::POST, &invites, Some(&owner), Some(json!({}))).await;
+        sqlx::query("UPDATE channel_invites SET expires_at = now() - interval '1 minute' WHERE id = $1")
+            .bind(invite["id"].as_str().unwrap())
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let accept = format!("/api/invites/{}/accept", invite["token"].as_str().unwrap());
+        let (status, body) = call(&state, Method::POST, &accept, Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body["code"], "invite_expired");
+    }
+
+    #[tokio::test]
+    async fn concurrent_accepts_respect_max_uses() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "limited", "public").await;
+        let (_, invite) = call(
+            &state,
+            Method::POST,
+            &format!("/api/channels/{}/invites", channel),
+            Some(&owner),
+            Some(json!({ "max_uses": 3 })),
+        )
+        .await;
+        let accept = format!("/api/invites/{}/accept", invite["token"].as_str().unwrap());
+
+        let requests = (0..10).map(|_| {
+            let (state, accept) = (state.clone(), accept.clone());
+            tokio::spawn(async move { call(&state, Method::POST, &accept, Some(&UserId::new()), None).await.0 })
+        });
+        let mut joined = 0;
+        for request in requests {
+            match request.await.unwrap() {
+                StatusCode::CREATED => joined += 1,
+                status => assert_eq!(status, StatusCode::GONE),
+            }
+        }
+        assert_eq!(joined, 3);
+    }
+}
@@ -0,0 +1,226 @@
+//! Outgoing email. Everything that sends mail goes through the `Mailer`
+//! trait; the service itself hands messages to an SMTP relay, which is
+//! expected to sit on a trusted network and take care of delivery.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One message to one recipient, with plain-text and HTML bodies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+    /// Sent as `List-Unsubscribe`, with one-click unsubscribing allowed.
+    pub unsubscribe_url: Option<String>,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync + 'static {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+/// Builds the SMTP mailer from `SMTP_RELAY` (`host:port`) and
+/// `MAIL_FROM`; `None` when the relay is unset.
+pub fn from_env() -> Option<SmtpMailer> {
+    let relay = std::env::var("SMTP_RELAY").ok().filter(|v| !v.is_empty())?;
+    let from = std::env::var("MAIL_FROM").ok().filter(|v| !v.is_empty()).expect("MAIL_FROM must be set with SMTP_RELAY");
+    Some(SmtpMailer::new(&relay, &from))
+}
+
+/// Hands mail to an SMTP relay, one connection per message.
+pub struct SmtpMailer {
+    relay: String,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(relay: &str, from: &str) -> Self {
+        Self { relay: relay.to_string(), from: from.to_string() }
+    }
+
+    async fn deliver(&self, email: &Email) -> Result<(), String> {
+        let stream = TcpStream::connect(&self.relay).await.map_err(|e| e.to_string())?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+        let domain = self.from.rsplit('@').next().unwrap_or("localhost");
+
+        expect(&mut read, 220).await?;
+        for (command, code) in [
+            (format!("EHLO {}", domain), 250),
+            (format!("MAIL FROM:<{}>", self.from), 250),
+            (format!("RCPT TO:<{}>", email.to), 250),
+            ("DATA".to_string(), 354),
+        ] {
+            write.write_all(format!("{}\r\n", command).as_bytes()).await.map_err(|e| e.to_string())?;
+            expect(&mut read, code).await?;
+        }
+        write.write_all(&self.message(email)).await.map_err(|e| e.to_string())?;
+        write.write_all(b".\r\n").await.map_err(|e| e.to_string())?;
+        expect(&mut read, 250).await?;
+        let _ = write.write_all(b"QUIT\r\n").await;
+        Ok(())
+    }
+
+    /// The message as sent after `DATA`, up to the closing dot.
+    fn message(&self, email: &Email) -> Vec<u8> {
+        let boundary = format!("uchat-{}", uuid::Uuid::new_v4().simple());
+        let domain = self.from.rsplit('@').next().unwrap_or("localhost");
+        let mut headers = vec![
+            format!("From: {}", self.from),
+            format!("To: {}", email.to),
+            format!("Subject: {}", encode_header(&email.subject)),
+            format!("Date: {}", Utc::now().to_rfc2822()),
+            format!("Message-ID: <{}@{}>", uuid::Uuid::new_v4(), domain),
+            "MIME-Version: 1.0".to_string(),
+            format!("Content-Type: multipart/alternative; boundary=\"{}\"", boundary),
+        ];
+        if let Some(url) = &email.unsubscribe_url {
+            headers.push(format!("List-Unsubscribe: <{}>", url));
+            headers.push("List-Unsubscribe-Post: List-Unsubscribe=One-Click".to_string());
+        }
+
+        let mut out = headers.join("\r\n");
+        out.push_str("\r\n\r\n");
+        for (content_type, body) in [("text/plain", &email.text), ("text/html", &email.html)] {
+            out.push_str(&format!(
+                "--{}\r\nContent-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+                boundary, content_type
+            ));
+            // Base64 lines never start with a dot, so nothing needs stuffing.
+            let encoded = STANDARD.encode(body);
+            for line in encoded.as_bytes().chunks(76) {
+                out.push_str(std::str::from_utf8(line).unwrap_or_default());
+                out.push_str("\r\n");
+            }
+        }
+        out.push_str(&format!("--{}--\r\n", boundary));
+        out.into_bytes()
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        tokio::time::timeout(TIMEOUT, self.deliver(email)).await.map_err(|_| "SMTP relay timed out".to_string())?
+    }
+}
+
+/// Reads a reply, multi-line or not, and checks its code.
+async fn expect<R: AsyncBufReadExt + Unpin>(read: &mut R, code: u16) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Err("SMTP relay closed the connection".into());
+        }
+        let line = line.trim_end();
+        if !line.starts_with(&code.to_string()) {
+            return Err(format!("SMTP relay replied {:?}", line));
+        }
+        // `250-...` continues, `250 ...` ends.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// `text` as an RFC 2047 encoded word when it isn't plain ASCII.
+fn encode_header(text: &str) -> String {
+    if text.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        text.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(text.replace(['\r', '\n'], " ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Plays a relay for one message, returning what came after `DATA`.
+    async fn fake_relay(listener: TcpListener) -> (Vec<String>, String) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 relay ready\r\n").await.unwrap();
+        let mut commands = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            commands.push(line.clone());
+            match line.split(' ').next().unwrap() {
+                "EHLO" => write.write_all(b"250-relay\r\n250 8BITMIME\r\n").await.unwrap(),
+                "DATA" => {
+                    write.write_all(b"354 go ahead\r\n").await.unwrap();
+                    let mut data = String::new();
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        if line == "." {
+                            break;
+                        }
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    write.write_all(b"250 queued\r\n").await.unwrap();
+                    lines.next_line().await.unwrap();
+                    return (commands, data);
+                }
+                _ => write.write_all(b"250 ok\r\n").await.unwrap(),
+            }
+        }
+        panic!("relay hung up");
+    }
+
+    #[tokio::test]
+    async fn hands_multipart_mail_to_the_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mailer = SmtpMailer::new(&listener.local_addr().unwrap().to_string(), "notify@uchat.example");
+        let relay = tokio::spawn(fake_relay(listener));
+
+        let email = Email {
+            to: "ada@example.com".into(),
+            subject: "Grace mentioned you in #ops ✓".into(),
+            text: ".starts with a dot".into(),
+            html: "<p>hi</p>".into(),
+            unsubscribe_url: Some("https://chat.example/api/email/unsubscribe?user=u&token=t".into()),
+        };
+        mailer.send(&email).await.unwrap();
+        let (commands, data) = relay.await.unwrap();
+
+        assert_eq!(
+            commands,
+            ["EHLO uchat.example", "MAIL FROM:<notify@uchat.example>", "RCPT TO:<ada@example.com>", "DATA"]
+        );
+        assert!(data.contains("To: ada@example.com\n"));
+        assert!(data.contains(&format!("Subject: =?UTF-8?B?{}?=\n", STANDARD.encode(&email.subject))));
+        assert!(data.contains("List-Unsubscribe: <https://chat.example/api/email/unsubscribe?user=u&token=t>\n"));
+        assert!(data.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click\n"));
+        assert!(data.contains(&format!("Content-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: base64\n\n{}\n", STANDARD.encode(&email.text))));
+        assert!(data.contains(&STANDARD.encode(&email.html)));
+    }
+
+    #[tokio::test]
+    async fn reports_refusals() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mailer = SmtpMailer::new(&listener.local_addr().unwrap().to_string(), "notify@uchat.example");
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 no service\r\n").await.unwrap();
+        });
+        let email = Email {
+            to: "ada@example.com".into(),
+            subject: "s".into(),
+            text: String::new(),
+            html: String::new(),
+            unsubscribe_url: None,
+        };
+        let err = mailer.send(&email).await.unwrap_err();
+        assert!(err.contains("554"), "{}", err);
+    }
+}
@@ -0,0 +1,204 @@
+mod admin;
+mod audit;
+mod auth;
+mod channels;
+mod digests;
+mod error;
+mod exports;
+mod files;
+mod filters;
+mod gateway;
+mod hooks;
+mod invites;
+mod mailer;
+mod members;
+mod messages;
+mod moderation;
+mod previews;
+mod push;
+mod rate_limit;
+mod reactions;
+mod read_markers;
+mod retention;
+mod scanning;
+mod scheduled;
+mod search;
+mod storage;
+mod thumbnails;
+mod users;
+mod zip;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    middleware,
+    routing::{delete, get, patch, post, put},
+    Router,
+};
+use sqlx::PgPool;
+
+use uchat_proto::jwt::secret_from_env;
+use uchat_proto::signing::LinkSigner;
+
+use files::FilePolicy;
+use gateway::GatewayNotifier;
+use storage::StorageBackend;
+
+pub struct AppState {
+    pub db: PgPool,
+    pub jwt_secret: String,
+    /// Signs the links that stand in for a token: file downloads, data
+    /// exports and unsubscribing from emails.
+    pub links: LinkSigner,
+    /// Where membership changes are pushed; unset in tests and when
+    /// `GATEWAY_INTERNAL_URL` is not configured.
+    pub gateway: Option<GatewayNotifier>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub file_policy: FilePolicy,
+    /// How long after posting a message its sender may edit it.
+    pub edit_window: chrono::Duration,
+    pub rate_limiter: rate_limit::RateLimiter,
+    pub push: push::PushDispatcher,
+    pub scanning: scanning::Scanning,
+}
+
+fn app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/api/channels", get(channels::list_channels).post(channels::create_channel))
+        .route(
+            "/api/channels/:id",
+            get(channels::get_channel)
+                .patch(channels::update_channel)
+                .delete(channels::delete_channel),
+        )
+        .route("/api/channels/:id/archive", post(channels::archive_channel))
+        .route("/api/channels/:id/unarchive", post(channels::unarchive_channel))
+        .route("/api/channels/:id/invites", get(invites::list_invites).post(invites::create_invite))
+        .route("/api/channels/:id/invites/:invite_id", delete(invites::revoke_invite))
+        .route("/api/channels/:id/filters", get(filters::list_rules).post(filters::create_rule))
+        .route("/api/channels/:id/filters/:rule_id", delete(filters::delete_rule))
+        .route("/api/channels/:id/hooks", get(hooks::list_hooks).post(hooks::create_hook))
+        .route("/api/channels/:id/hooks/:hook_id", delete(hooks::revoke_hook))
+        .route("/api/channels/:id/members", get(members::list_members).post(members::add_member))
+        .route(
+            "/api/channels/:id/members/:user_id",
+            patch(members::update_member).delete(members::remove_member),
+        )
+        .route("/api/channels/:id/messages", get(messages::list_messages))
+        .route(
+            "/api/channels/:id/messages/:message_id",
+            patch(messages::edit_message).delete(messages::delete_message),
+        )
+        .route("/api/channels/:id/messages/:message_id/history", get(messages::message_history))
+        .route(
+            "/api/channels/:id/messages/:message_id/reactions/:emoji",
+            put(reactions::add_reaction).delete(reactions::remove_reaction),
+        )
+        .route("/api/channels/:id/notifications", get(push::get_notifications).put(push::set_notifications))
+        .route("/api/channels/:id/read", put(read_markers::mark_read))
+        .route("/api/channels/:id/retention/preview", get(retention::preview))
+        .route(
+            "/api/channels/:id/scheduled_messages",
+            get(scheduled::list_scheduled).post(scheduled::schedule_message),
+        )
+        .route("/api/channels/:id/scheduled_messages/:scheduled_id", delete(scheduled::cancel_scheduled))
+        .route("/api/hooks/:hook_id", post(hooks::post_to_hook))
+        .route("/api/push/register", post(push::register))
+        .route("/api/invites/:token", get(invites::preview_invite))
+        .route("/api/invites/:token/accept", post(invites::accept_invite))
+        .route("/api/unread", get(read_markers::list_unread))
+        .route("/api/users", get(users::list_users))
+        .route("/api/users/me", get(users::get_me).patch(users::update_me))
+        .route("/api/users/me/avatar", put(users::put_avatar))
+        .route("/api/users/me/export", post(exports::request_export))
+        .route("/api/users/me/export/:job_id", get(exports::get_export))
+        .route("/api/exports/:id/download", get(exports::download_export))
+        .route("/api/email/unsubscribe", get(digests::unsubscribe).post(digests::unsubscribe))
+        .route("/api/users/:id", get(users::get_user))
+        .route("/api/users/:id/avatar", get(users::get_avatar))
+        .route("/api/admin/channels", get(admin::list_channels))
+        .route("/api/admin/channels/:id", delete(admin::purge_channel))
+        .route("/api/admin/channels/:id/archive", post(admin::archive_channel))
+        .route("/api/admin/audit/messages", get(audit::list_message_audit))
+        .route("/api/admin/spam", get(moderation::list_held))
+        .route("/api/admin/spam/:user_id", post(moderation::decide))
+        .route("/api/search/messages", get(search::search_messages))
+        .route("/api/files", post(files::upload_file))
+        .route("/api/files/:id", get(files::download_file).delete(files::delete_file))
+        .route("/api/files/:id/download-url", get(files::download_url))
+        .route("/api/files/:id/thumbnail", get(files::download_thumbnail))
+        .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit))
+        .route("/internal/push", post(push::dispatch))
+        .route("/internal/moderation/flagged", post(moderation::flagged))
+        .route("/internal/moderation/filtered", post(moderation::filtered))
+        .route("/internal/content-filters", get(filters::all_rules))
+        .route("/internal/e2ee-channels", get(channels::e2ee_channels))
+        .route("/internal/archived-channels", get(channels::archived_channels))
+        .route("/internal/markdown-channels", get(channels::markdown_channels))
+        .route("/internal/channels/:id/members/:user_id", delete(members::kick_member))
+        .layer(middleware::from_fn(uchat_telemetry::propagate))
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() {
+    uchat_telemetry::init("channels-api", env!("CARGO_PKG_VERSION"));
+
+    let database_url = std::env::var("DATABASE_URL")
+        .unwrap_or_else(|_| "postgres://postgres@localhost/uchat".into());
+
+    let state = Arc::new(AppState {
+        db: uchat_db::connect(&database_url).await.expect("connect to DATABASE_URL"),
+        jwt_secret: secret_from_env(),
+        links: LinkSigner::from_env(),
+        gateway: GatewayNotifier::from_env(),
+        storage: storage::from_env().expect("configure FILE_STORAGE"),
+        file_policy: FilePolicy::from_env(),
+        edit_window: messages::edit_window_from_env(),
+        rate_limiter: rate_limit::RateLimiter::new(rate_limit::RateLimits::from_env()),
+        push: push::PushDispatcher::from_env(),
+        scanning: scanning::Scanning::from_env().expect("configure FILES_SCANNER"),
+    });
+
+    let metrics_addr = uchat_metrics::addr_from_env("0.0.0.0:9401");
+    rate_limit::export_metrics(state.clone(), &metrics_addr).await.expect("bind METRICS_ADDR");
+
+    retention::spawn(state.clone(), retention::batch_size_from_env());
+    exports::spawn(state.clone());
+    scheduled::spawn(state.clone());
+    previews::spawn(state.clone(), previews::Fetcher::default());
+    match mailer::from_env() {
+        Some(mailer) => digests::spawn(state.clone(), Arc::new(mailer), digests::DigestConfig::from_env()),
+        None => tracing::info!("SMTP_RELAY is unset; email digests are off"),
+    }
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:9400").await.unwrap();
+
+    tracing::info!("channels-api running on http://0.0.0.0:9400");
+
+    axum::serve(listener, app(state).into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+/// State backed by `TEST_DATABASE_URL`; DB tests skip when it is unset.
+#[cfg(test)]
+async fn test_state() -> Option<Arc<AppState>> {
+    Some(Arc::new(AppState {
+        db: uchat_db::connect_test().await?,
+        jwt_secret: "test-secret".into(),
+        links: LinkSigner::new("test-link-key"),
+        gateway: None,
+        storage: Arc::new(storage::LocalDisk::new(std::env::temp_dir().join("uchat-files-test"))),
+        file_policy: FilePolicy {
+            max_file_bytes: 1024 * 1024,
+            user_quota_bytes: 10 * 1024 * 1024,
+            denied_types: vec!["application/x-executable".into()],
+        },
+        edit_window: chrono::Duration::seconds(messages::DEFAULT_EDIT_WINDOW_SECS),
+        rate_limiter: rate_limit::RateLimiter::new(rate_limit::RateLimits::default()),
+        push: push::PushDispatcher::default(),
+        scanning: scanning::Scanning::default(),
+    }))
+}
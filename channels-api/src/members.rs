@@ -0,0 +1,485 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use sqlx::{Postgres, Transaction};
+
+use uchat_proto::channels::{AddMember, ChannelType, Member, MemberRole, MembershipChange, UpdateMember};
+use uchat_proto::ids::{ChannelId, UserId};
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, visible_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+fn parse_user_id(raw: &str) -> Result<UserId, AppError> {
+    raw.parse().map_err(|_| AppError::invalid("invalid user id"))
+}
+
+async fn notify(state: &AppState, channel_id: &ChannelId, user_id: &UserId, role: Option<MemberRole>) {
+    if let Some(gateway) = &state.gateway {
+        let change = MembershipChange { channel_id: channel_id.clone(), user_id: user_id.clone(), role };
+        gateway.membership_changed(&change).await;
+    }
+}
+
+/// Locks the channel's admin rows and returns how many there are, so a
+/// concurrent demotion cannot leave the channel without an admin.
+async fn lock_admins(tx: &mut Transaction<'_, Postgres>, channel_id: &ChannelId) -> Result<usize, sqlx::Error> {
+    let admins: Vec<String> = sqlx::query_scalar(
+        "SELECT user_id FROM channel_members WHERE channel_id = $1 AND role = 'admin' FOR UPDATE",
+    )
+    .bind(channel_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(admins.len())
+}
+
+/// GET /api/channels/{id}/members
+pub async fn list_members(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Member>>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    visible_channel(&state.db, &channel_id, &user.user_id).await?;
+
+    let rows: Vec<(UserId, String)> =
+        sqlx::query_as("SELECT user_id, role FROM channel_members WHERE channel_id = $1 ORDER BY user_id")
+            .bind(&channel_id)
+            .fetch_all(&state.db)
+            .await?;
+
+    let members = rows
+        .into_iter()
+        .map(|(user_id, role)| Member { user_id, role: role.parse().unwrap_or_default() })
+        .collect();
+
+    Ok(Json(members))
+}
+
+/// POST /api/channels/{id}/members
+///
+/// Admins add anyone with any role. Anyone else may only add themselves to
+/// a public channel as a writer, which is idempotent: joining a channel you
+/// are already in returns your current membership with 200.
+pub async fn add_member(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<AddMember>,
+) -> Result<(StatusCode, Json<Member>), AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (channel, caller_role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    let target = body.user_id.unwrap_or_else(|| user.user_id.clone());
+
+    let role = if target == user.user_id {
+        if let Some(role) = caller_role {
+            return Ok((StatusCode::OK, Json(Member { user_id: target, role })));
+        }
+        if channel.channel_type != ChannelType::Public
+            || body.role.is_some_and(|r| r != MemberRole::Write)
+        {
+            return Err(AppError::forbidden());
+        }
+        MemberRole::Write
+    } else {
+        if caller_role != Some(MemberRole::Admin) {
+            return Err(AppError::forbidden());
+        }
+        body.role.unwrap_or_default()
+    };
+
+    let inserted = sqlx::query(
+        "INSERT INTO channel_members (channel_id, user_id, role) VALUES ($1, $2, $3)
+         ON CONFLICT (channel_id, user_id) DO NOTHING",
+    )
+    .bind(&channel_id)
+    .bind(&target)
+    .bind(role.as_str())
+    .execute(&state.db)
+    .await?
+    .rows_affected();
+
+    if inserted == 0 {
+        return Err(AppError::conflict("already a member"));
+    }
+
+    notify(&state, &channel_id, &target, Some(role)).await;
+    Ok((StatusCode::CREATED, Json(Member { user_id: target, role })))
+}
+
+/// PATCH /api/channels/{id}/members/{user_id}
+pub async fn update_member(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, member_id)): Path<(String, String)>,
+    Json(body): Json<UpdateMember>,
+) -> Result<Json<Member>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let target = parse_user_id(&member_id)?;
+    let (_, caller_role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if caller_role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+
+    let mut tx = state.db.begin().await?;
+    let admins = lock_admins(&mut tx, &channel_id).await?;
+
+    let current: Option<String> = sqlx::query_scalar(
+        "SELECT role FROM channel_members WHERE channel_id = $1 AND user_id = $2 FOR UPDATE",
+    )
+    .bind(&channel_id)
+    .bind(&target)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let current: MemberRole = current.ok_or_else(AppError::not_found)?.parse().unwrap_or_default();
+    if current == MemberRole::Admin && body.role != MemberRole::Admin && admins <= 1 {
+        return Err(AppError::conflict("a channel must keep at least one admin"));
+    }
+
+    sqlx::query("UPDATE channel_members SET role = $3 WHERE channel_id = $1 AND user_id = $2")
+        .bind(&channel_id)
+        .bind(&target)
+        .bind(body.role.as_str())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    notify(&state, &channel_id, &target, Some(body.role)).await;
+    Ok(Json(Member { user_id: target, role: body.role }))
+}
+
+/// DELETE /api/channels/{id}/members/{user_id}
+///
+/// Admins remove anyone; members may remove themselves (leave).
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, member_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let target = parse_user_id(&member_id)?;
+    let (_, caller_role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if target != user.user_id && caller_role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+
+    delete_member(&state, &channel_id, &target).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// DELETE /internal/channels/{id}/members/{user_id}
+///
+/// A kick from a trusted service, such as the gateway's gRPC `KickUser`;
+/// the same rules as `remove_member` apply except the caller's role.
+pub async fn kick_member(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((id, member_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    if !state.push.gateway_authorized(&headers) {
+        return Err(AppError::forbidden());
+    }
+    let channel_id = parse_channel_id(&id)?;
+    let target = parse_user_id(&member_id)?;
+
+    delete_member(&state, &channel_id, &target).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Removes `target` from the channel unless that would leave it without
+/// an admin, then tells the gateway.
+async fn delete_member(state: &AppState, channel_id: &ChannelId, target: &UserId) -> Result<(), AppError> {
+    let mut tx = state.db.begin().await?;
+    let admins = lock_admins(&mut tx, channel_id).await?;
+
+    let removed: Option<String> = sqlx::query_scalar(
+        "DELETE FROM channel_members WHERE channel_id = $1 AND user_id = $2 RETURNING role",
+    )
+    .bind(channel_id)
+    .bind(target)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    match removed.as_deref() {
+        None => return Err(AppError::not_found()),
+        Some("admin") if admins <= 1 => {
+            return Err(AppError::conflict("a channel must keep at least one admin"));
+        }
+        Some(_) => {}
+    }
+    tx.commit().await?;
+
+    notify(state, channel_id, target, None).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::gateway::GatewayNotifier;
+    use crate::test_state;
+    use axum::http::Method;
+    use axum::routing::post;
+    use axum::Router;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+
+    fn members_uri(channel: &str) -> String {
+        format!("/api/channels/{}/members", channel)
+    }
+
+    fn member_uri(channel: &str, user: &UserId) -> String {
+        format!("/api/channels/{}/members/{}", channel, user)
+    }
+
+    fn role_of(members: &Value, user: &UserId) -> Option<String> {
+        members
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|m| m["user_id"] == user.as_str())
+            .map(|m| m["role"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn self_join_public_is_idempotent() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let joiner = UserId::new();
+        let channel = create(&state, &owner, "open", "public").await;
+
+        let (status, body) = call(&state, Method::POST, &members_uri(&channel), Some(&joiner), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["role"], "write");
+
+        let (status, _) = call(&state, Method::POST, &members_uri(&channel), Some(&joiner), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, members) = call(&state, Method::GET, &members_uri(&channel), Some(&joiner), None).await;
+        assert_eq!(role_of(&members, &owner).as_deref(), Some("admin"));
+        assert_eq!(role_of(&members, &joiner).as_deref(), Some("write"));
+
+        // Self-joins cannot pick an elevated role.
+        let (status, _) = call(
+            &state,
+            Method::POST,
+            &members_uri(&channel),
+            Some(&UserId::new()),
+            Some(json!({ "role": "admin" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn private_channels_need_an_admin_to_add() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let outsider = UserId::new();
+        let channel = create(&state, &owner, "closed", "private").await;
+
+        let (status, _) = call(&state, Method::POST, &members_uri(&channel), Some(&outsider), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(
+            &state,
+            Method::POST,
+            &members_uri(&channel),
+            Some(&owner),
+            Some(json!({ "user_id": outsider, "role": "read" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, _) = call(
+            &state,
+            Method::POST,
+            &members_uri(&channel),
+            Some(&owner),
+            Some(json!({ "user_id": outsider })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // A plain member cannot add others.
+        let (status, _) = call(
+            &state,
+            Method::POST,
+            &members_uri(&channel),
+            Some(&outsider),
+            Some(json!({ "user_id": UserId::new() })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn role_changes_keep_an_admin() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "roles", "public").await;
+        call(&state, Method::POST, &members_uri(&channel), Some(&member), Some(json!({}))).await;
+
+        let (status, _) = call(
+            &state,
+            Method::PATCH,
+            &member_uri(&channel, &owner),
+            Some(&member),
+            Some(json!({ "role": "read" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = call(
+            &state,
+            Method::PATCH,
+            &member_uri(&channel, &owner),
+            Some(&owner),
+            Some(json!({ "role": "write" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+
+        let (status, _) = call(
+            &state,
+            Method::PATCH,
+            &member_uri(&channel, &member),
+            Some(&owner),
+            Some(json!({ "role": "admin" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(
+            &state,
+            Method::PATCH,
+            &member_uri(&channel, &owner),
+            Some(&owner),
+            Some(json!({ "role": "write" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["role"], "write");
+
+        let (status, _) = call(
+            &state,
+            Method::PATCH,
+            &member_uri(&channel, &UserId::new()),
+            Some(&member),
+            Some(json!({ "role": "read" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn removal_rules() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let a = UserId::new();
+        let b = UserId::new();
+        let channel = create(&state, &owner, "removals", "public").await;
+        for user in [&a, &b] {
+            call(&state, Method::POST, &members_uri(&channel), Some(user), Some(json!({}))).await;
+        }
+
+        let (status, _) = call(&state, Method::DELETE, &member_uri(&channel, &b), Some(&a), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = call(&state, Method::DELETE, &member_uri(&channel, &a), Some(&a), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = call(&state, Method::DELETE, &member_uri(&channel, &b), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = call(&state, Method::DELETE, &member_uri(&channel, &owner), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = call(&state, Method::DELETE, &member_uri(&channel, &b), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn trusted_services_can_kick() {
+        let Some(mut state) = test_state().await else { return };
+        Arc::get_mut(&mut state).unwrap().push.internal_token = Some("internal-secret".into());
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "kicks", "public").await;
+        call(&state, Method::POST, &members_uri(&channel), Some(&member), Some(json!({}))).await;
+        let kick = |headers, user: &UserId| {
+            kick_member(State(state.clone()), headers, Path((channel.clone(), user.to_string())))
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-internal-token", "internal-secret".parse().unwrap());
+
+        let refused = kick(HeaderMap::new(), &member).await.unwrap_err();
+        assert_eq!(refused.status, StatusCode::FORBIDDEN);
+        assert_eq!(kick(headers.clone(), &member).await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(kick(headers.clone(), &member).await.unwrap_err().status, StatusCode::NOT_FOUND);
+        assert_eq!(kick(headers, &owner).await.unwrap_err().status, StatusCode::CONFLICT);
+
+        let (_, members) = call(&state, Method::GET, &members_uri(&channel), Some(&owner), None).await;
+        assert_eq!(role_of(&members, &member), None);
+    }
+
+    #[tokio::test]
+    async fn removal_notifies_gateway() {
+        let Some(base) = test_state().await else { return };
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<(HeaderMap, MembershipChange)>();
+        let fake_gateway = Router::new().route(
+            "/internal/membership",
+            post(move |headers: HeaderMap, Json(change): Json<MembershipChange>| async move {
+                let _ = tx.send((headers, change));
+                StatusCode::NO_CONTENT
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_gateway).await.unwrap() });
+
+        let state = Arc::new(AppState {
+            db: base.db.clone(),
+            jwt_secret: base.jwt_secret.clone(),
+            links: base.links.clone(),
+            gateway: Some(GatewayNotifier::new(&format!("http://{}", addr), "internal".into())),
+            storage: base.storage.clone(),
+            file_policy: base.file_policy.clone(),
+            edit_window: base.edit_window,
+            rate_limiter: crate::rate_limit::RateLimiter::new(crate::rate_limit::RateLimits::default()),
+            push: crate::push::PushDispatcher::default(),
+            scanning: crate::scanning::Scanning::default(),
+        });
+
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "kick", "public").await;
+        // The creator's own sockets hear about the channel first.
+        let (_, created) = rx.recv().await.unwrap();
+        assert_eq!((created.user_id, created.role), (owner.clone(), Some(MemberRole::Admin)));
+        call(&state, Method::POST, &members_uri(&channel), Some(&member), Some(json!({}))).await;
+
+        let (headers, joined) = rx.recv().await.unwrap();
+        assert_eq!(headers["x-internal-token"], "internal");
+        // Tagged with the id of the request that caused it.
+        assert!(headers.contains_key(uchat_telemetry::CORRELATION_ID_HEADER));
+        assert_eq!(joined.role, Some(MemberRole::Write));
+
+        call(&state, Method::DELETE, &member_uri(&channel, &member), Some(&owner), None).await;
+        let (_, removed) = rx.recv().await.unwrap();
+        assert_eq!(removed.channel_id.as_str(), channel);
+        assert_eq!(removed.user_id, member);
+        assert_eq!(removed.role, None);
+    }
+}
@@ -0,0 +1,699 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{Postgres, QueryBuilder};
+
+use uchat_db::audit::{record_message, MessageAudit};
+use uchat_proto::audit::MessageAction;
+use uchat_proto::channels::MemberRole;
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::{ChannelId, MessageId, UserId};
+use uchat_proto::messages::{EditMessage, Message, MessageDeleted, MessageEdited, MessageVersion, Page};
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, visible_channel, writable_channel};
+use crate::error::AppError;
+use crate::reactions;
+use crate::AppState;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 100;
+/// Prior versions kept per message; older ones are dropped on edit.
+const MAX_EDIT_VERSIONS: i64 = 10;
+pub const DEFAULT_EDIT_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+pub const MESSAGE_COLUMNS: &str =
+    "id, channel_id, sender_id, sender_name, content, created_at, deleted_at, edited_at, redacted";
+
+/// How long after posting a sender may edit, from
+/// `MESSAGE_EDIT_WINDOW_SECS`.
+pub fn edit_window_from_env() -> chrono::Duration {
+    let secs = std::env::var("MESSAGE_EDIT_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n >= 0)
+        .unwrap_or(DEFAULT_EDIT_WINDOW_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+#[derive(sqlx::FromRow)]
+pub struct MessageRow {
+    id: MessageId,
+    channel_id: ChannelId,
+    sender_id: UserId,
+    sender_name: Option<String>,
+    /// `NULL` once the message is deleted.
+    content: Option<String>,
+    created_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+    edited_at: Option<DateTime<Utc>>,
+    redacted: bool,
+}
+
+impl From<MessageRow> for Message {
+    fn from(row: MessageRow) -> Self {
+        Message {
+            id: row.id,
+            channel_id: row.channel_id,
+            sender_id: row.sender_id,
+            sender_name: row.sender_name,
+            content: row.content.unwrap_or_default(),
+            created_at: row.created_at,
+            deleted_at: row.deleted_at,
+            edited_at: row.edited_at,
+            redacted: row.redacted,
+            reactions: Default::default(),
+        }
+    }
+}
+
+/// Position in a channel's history. Messages are totally ordered by
+/// `(created_at, id)`, so paging stays stable when many share a timestamp.
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    created_at: DateTime<Utc>,
+    id: MessageId,
+}
+
+impl Cursor {
+    fn of(message: &Message) -> Self {
+        Cursor { created_at: message.created_at, id: message.id.clone() }
+    }
+
+    fn encode(&self) -> String {
+        format!("{}_{}", self.created_at.timestamp_micros(), self.id)
+    }
+
+    fn decode(raw: &str) -> Result<Self, AppError> {
+        let invalid = || AppError::invalid("invalid cursor");
+        let (micros, id) = raw.split_once('_').ok_or_else(invalid)?;
+        let created_at = micros
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or_else(invalid)?;
+        let id = id.parse().map_err(|_| invalid())?;
+        Ok(Cursor { created_at, id })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<i64>,
+    before: Option<String>,
+    after: Option<String>,
+    sender: Option<String>,
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+/// GET /api/channels/{id}/messages
+///
+/// Returns messages newest-first. `before`/`after` take cursors from a
+/// previous page's `next_cursor`; paging with `after` alone walks forward
+/// in time, so its `next_cursor` is meant to be passed back as `after`.
+pub async fn list_messages(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Page<Message>>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+
+    if query.include_deleted && role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let before = query.before.as_deref().map(Cursor::decode).transpose()?;
+    let after = query.after.as_deref().map(Cursor::decode).transpose()?;
+    let sender = query
+        .sender
+        .as_deref()
+        .map(|s| s.parse::<UserId>().map_err(|_| AppError::invalid("invalid sender")))
+        .transpose()?;
+    let forward = after.is_some() && before.is_none();
+
+    let mut qb: QueryBuilder<Postgres> =
+        QueryBuilder::new(format!("SELECT {} FROM messages WHERE channel_id = ", MESSAGE_COLUMNS));
+    qb.push_bind(&channel_id);
+    if !query.include_deleted {
+        qb.push(" AND deleted_at IS NULL");
+    }
+    if let Some(sender) = &sender {
+        qb.push(" AND sender_id = ").push_bind(sender);
+    }
+    if let Some(c) = &before {
+        qb.push(" AND (created_at, id) < (").push_bind(c.created_at);
+        qb.push(", ").push_bind(&c.id).push(")");
+    }
+    if let Some(c) = &after {
+        qb.push(" AND (created_at, id) > (").push_bind(c.created_at);
+        qb.push(", ").push_bind(&c.id).push(")");
+    }
+    qb.push(if forward {
+        " ORDER BY created_at ASC, id ASC LIMIT "
+    } else {
+        " ORDER BY created_at DESC, id DESC LIMIT "
+    });
+    // One extra row tells us whether another page exists.
+    qb.push_bind(limit + 1);
+
+    let rows: Vec<MessageRow> =
+        uchat_metrics::time_db_query("list_messages", qb.build_query_as().fetch_all(&state.db)).await?;
+
+    let mut items: Vec<Message> = rows.into_iter().map(Message::from).collect();
+    let has_more = items.len() as i64 > limit;
+    items.truncate(limit as usize);
+
+    let next_cursor = if has_more { items.last().map(|m| Cursor::of(m).encode()) } else { None };
+    reactions::attach(&state.db, &user.user_id, &mut items).await?;
+
+    if forward {
+        items.reverse();
+    }
+
+    Ok(Json(Page { items, next_cursor }))
+}
+
+fn parse_message_id(raw: &str) -> Result<MessageId, AppError> {
+    raw.parse().map_err(|_| AppError::not_found())
+}
+
+/// Locks the message row for the rest of the transaction.
+async fn lock_message(
+    tx: &mut sqlx::PgConnection,
+    channel_id: &ChannelId,
+    message_id: &MessageId,
+) -> Result<MessageRow, AppError> {
+    let row: Option<MessageRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM messages WHERE id = $1 AND channel_id = $2 FOR UPDATE",
+        MESSAGE_COLUMNS
+    ))
+    .bind(message_id)
+    .bind(channel_id)
+    .fetch_optional(tx)
+    .await?;
+    row.ok_or_else(AppError::not_found)
+}
+
+/// PATCH /api/channels/{id}/messages/{message_id}
+///
+/// Replaces the content of the caller's own message while it is younger
+/// than the edit window. The replaced content is kept in the message's
+/// edit history.
+pub async fn edit_message(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, message_id)): Path<(String, String)>,
+    Json(body): Json<EditMessage>,
+) -> Result<Json<Message>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let message_id = parse_message_id(&message_id)?;
+    if body.content.trim().is_empty() {
+        return Err(AppError::invalid("content must not be empty"));
+    }
+    let (channel, _) = writable_channel(&state.db, &channel_id, &user.user_id).await?;
+    // Edits arrive as plaintext.
+    if channel.e2ee_required {
+        return Err(AppError::e2ee_required());
+    }
+
+    let mut tx = state.db.begin().await?;
+    let row = lock_message(&mut tx, &channel_id, &message_id).await?;
+
+    if row.sender_id != user.user_id {
+        return Err(AppError::forbidden());
+    }
+    let Some(previous) = row.content.filter(|_| row.deleted_at.is_none()) else {
+        return Err(AppError::conflict("message is deleted"));
+    };
+    if Utc::now() - row.created_at > state.edit_window {
+        return Err(AppError::new(StatusCode::FORBIDDEN, ErrorCode::Forbidden, "edit window has passed"));
+    }
+    if previous == body.content {
+        return Ok(Json(MessageRow { content: Some(previous), ..row }.into()));
+    }
+
+    sqlx::query("INSERT INTO message_edits (message_id, content) VALUES ($1, $2)")
+        .bind(&message_id)
+        .bind(&previous)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "DELETE FROM message_edits WHERE message_id = $1 AND id NOT IN (
+             SELECT id FROM message_edits WHERE message_id = $1 ORDER BY id DESC LIMIT $2
+         )",
+    )
+    .bind(&message_id)
+    .bind(MAX_EDIT_VERSIONS)
+    .execute(&mut *tx)
+    .await?;
+    let edited: MessageRow = sqlx::query_as(&format!(
+        "UPDATE messages SET content = $2, edited_at = now() WHERE id = $1 RETURNING {}",
+        MESSAGE_COLUMNS
+    ))
+    .bind(&message_id)
+    .bind(&body.content)
+    .fetch_one(&mut *tx)
+    .await?;
+    record_message(
+        &mut tx,
+        MessageAudit {
+            message_id: &message_id,
+            channel_id: &channel_id,
+            actor_id: &user.user_id,
+            action: MessageAction::Edited,
+            before: Some(&previous),
+            after: Some(&body.content),
+        },
+    )
+    .await?;
+    tx.commit().await?;
+
+    let message = Message::from(edited);
+    if let Some(gateway) = &state.gateway {
+        let edited = MessageEdited {
+            id: message.id.clone(),
+            channel_id: message.channel_id.clone(),
+            content: message.content.clone(),
+            edited_at: message.edited_at.unwrap_or_else(Utc::now),
+        };
+        gateway.message_edited(&edited).await;
+    }
+    Ok(Json(message))
+}
+
+/// GET /api/channels/{id}/messages/{message_id}/history
+///
+/// Prior versions of a message, newest first. Deleted messages have no
+/// visible history.
+pub async fn message_history(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, message_id)): Path<(String, String)>,
+) -> Result<Json<Vec<MessageVersion>>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let message_id = parse_message_id(&message_id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role.is_none() {
+        return Err(AppError::forbidden());
+    }
+
+    let live: Option<bool> =
+        sqlx::query_scalar("SELECT deleted_at IS NULL FROM messages WHERE id = $1 AND channel_id = $2")
+            .bind(&message_id)
+            .bind(&channel_id)
+            .fetch_optional(&state.db)
+            .await?;
+    if live != Some(true) {
+        return Err(AppError::not_found());
+    }
+
+    let versions: Vec<(String, DateTime<Utc>)> = sqlx::query_as(
+        "SELECT content, replaced_at FROM message_edits WHERE message_id = $1 ORDER BY id DESC",
+    )
+    .bind(&message_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(
+        versions
+            .into_iter()
+            .map(|(content, replaced_at)| MessageVersion { content, replaced_at })
+            .collect(),
+    ))
+}
+
+/// DELETE /api/channels/{id}/messages/{message_id}
+///
+/// Soft delete: the row stays as a tombstone with its content, edit
+/// history and reactions cleared, and the channel's live subscribers are
+/// told to drop it. Only the sender or a channel admin may delete.
+/// Deleting a tombstone again returns it unchanged.
+pub async fn delete_message(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, message_id)): Path<(String, String)>,
+) -> Result<Json<Message>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let message_id = parse_message_id(&message_id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+
+    let mut tx = state.db.begin().await?;
+    let row = lock_message(&mut tx, &channel_id, &message_id).await?;
+
+    if row.sender_id != user.user_id && role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+    if row.deleted_at.is_some() {
+        return Ok(Json(row.into()));
+    }
+
+    let deleted: MessageRow = sqlx::query_as(&format!(
+        "UPDATE messages SET deleted_at = now(), content = NULL WHERE id = $1 RETURNING {}",
+        MESSAGE_COLUMNS
+    ))
+    .bind(&message_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    // Earlier versions go too, so no copy of the content survives.
+    sqlx::query("DELETE FROM message_edits WHERE message_id = $1")
+        .bind(&message_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM message_reactions WHERE message_id = $1")
+        .bind(&message_id)
+        .execute(&mut *tx)
+        .await?;
+    record_message(
+        &mut tx,
+        MessageAudit {
+            message_id: &message_id,
+            channel_id: &channel_id,
+            actor_id: &user.user_id,
+            action: MessageAction::Deleted,
+            before: row.content.as_deref(),
+            after: None,
+        },
+    )
+    .await?;
+    tx.commit().await?;
+
+    if let Some(gateway) = &state.gateway {
+        gateway.message_deleted(&MessageDeleted { id: message_id, channel_id }).await;
+    }
+    Ok(Json(deleted.into()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    pub async fn insert(
+        db: &PgPool,
+        channel_id: &str,
+        sender: &UserId,
+        content: &str,
+        created_at: DateTime<Utc>,
+    ) -> MessageId {
+        let id = MessageId::new();
+        sqlx::query(
+            "INSERT INTO messages (id, channel_id, sender_id, content, created_at)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&id)
+        .bind(channel_id)
+        .bind(sender)
+        .bind(content)
+        .bind(created_at)
+        .execute(db)
+        .await
+        .unwrap();
+        id
+    }
+
+    fn contents(page: &Value) -> Vec<String> {
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor {
+            created_at: DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap(),
+            id: MessageId::new(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::decode("nonsense").is_err());
+        assert!(Cursor::decode("12_not-a-uuid").is_err());
+    }
+
+    #[tokio::test]
+    async fn pages_through_shared_timestamps() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "history", "public").await;
+
+        let ts = Utc::now();
+        for i in 0..7 {
+            insert(&state.db, &channel, &owner, &format!("m{}", i), ts).await;
+        }
+
+        let mut seen = Vec::new();
+        let mut uri = format!("/api/channels/{}/messages?limit=3", channel);
+        loop {
+            let (status, page) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+            assert_eq!(status, StatusCode::OK);
+            seen.extend(contents(&page));
+            match page["next_cursor"].as_str() {
+                Some(cursor) => {
+                    uri = format!("/api/channels/{}/messages?limit=3&before={}", channel, cursor)
+                }
+                None => break,
+            }
+        }
+
+        seen.sort();
+        assert_eq!(seen, (0..7).map(|i| format!("m{}", i)).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn newest_first_with_filters() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let other = UserId::new();
+        let channel = create(&state, &owner, "filters", "public").await;
+
+        let base = Utc::now();
+        let secs = chrono::Duration::seconds;
+        insert(&state.db, &channel, &owner, "first", base).await;
+        let second = insert(&state.db, &channel, &other, "second", base + secs(1)).await;
+        insert(&state.db, &channel, &owner, "third", base + secs(2)).await;
+        sqlx::query("UPDATE messages SET deleted_at = now() WHERE id = $1")
+            .bind(&second)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let uri = format!("/api/channels/{}/messages", channel);
+        let (_, page) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+        assert_eq!(contents(&page), ["third", "first"]);
+        assert!(page["next_cursor"].is_null());
+
+        let (status, _) =
+            call(&state, Method::GET, &format!("{}?include_deleted=true", uri), Some(&other), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}?include_deleted=true", uri), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["third", "second", "first"]);
+        assert!(!page["items"][1]["deleted_at"].is_null());
+
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}?sender={}", uri, owner), Some(&other), None).await;
+        assert_eq!(contents(&page), ["third", "first"]);
+
+        let (_, page) = call(&state, Method::GET, &format!("{}?limit=0", uri), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["third"]);
+
+        let cursor = page["next_cursor"].as_str().unwrap();
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}?limit=1&before={}", uri, cursor), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["first"]);
+    }
+
+    #[tokio::test]
+    async fn after_walks_forward() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "forward", "public").await;
+
+        let base = Utc::now();
+        for i in 0..5 {
+            insert(&state.db, &channel, &owner, &format!("m{}", i), base + chrono::Duration::seconds(i)).await;
+        }
+
+        let uri = format!("/api/channels/{}/messages", channel);
+        let (_, page) = call(&state, Method::GET, &format!("{}?limit=4", uri), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["m4", "m3", "m2", "m1"]);
+        let oldest = page["next_cursor"].as_str().unwrap().to_string();
+
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}?limit=2&after={}", uri, oldest), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["m3", "m2"]);
+
+        let cursor = page["next_cursor"].as_str().unwrap();
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}?limit=2&after={}", uri, cursor), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["m4"]);
+        assert!(page["next_cursor"].is_null());
+    }
+
+    #[tokio::test]
+    async fn private_history_needs_membership() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "private-history", "private").await;
+
+        let uri = format!("/api/channels/{}/messages", channel);
+        let (status, _) = call(&state, Method::GET, &uri, Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&state, Method::GET, &format!("{}?before=bogus", uri), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn soft_delete_leaves_a_tombstone() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let sender = UserId::new();
+        let channel = create(&state, &owner, "soft-delete", "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&sender), Some(json!({}))).await;
+
+        let base = Utc::now();
+        insert(&state.db, &channel, &owner, "kept", base).await;
+        let doomed = insert(&state.db, &channel, &sender, "regrettable", base + chrono::Duration::seconds(1)).await;
+
+        let uri = format!("/api/channels/{}/messages/{}", channel, doomed);
+        call(&state, Method::PATCH, &uri, Some(&sender), Some(json!({ "content": "still regrettable" }))).await;
+        let (status, body) = call(&state, Method::DELETE, &uri, Some(&sender), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["content"], "");
+        let deleted_at = body["deleted_at"].clone();
+        assert!(!deleted_at.is_null());
+
+        let stored: Option<String> = sqlx::query_scalar("SELECT content FROM messages WHERE id = $1")
+            .bind(&doomed)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert!(stored.is_none());
+        let versions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_edits WHERE message_id = $1")
+            .bind(&doomed)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(versions, 0);
+
+        // Idempotent, and audited only once.
+        let (status, body) = call(&state, Method::DELETE, &uri, Some(&sender), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["deleted_at"], deleted_at);
+        let audits: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM message_audit WHERE message_id = $1 AND action = 'deleted'")
+                .bind(&doomed)
+                .fetch_one(&state.db)
+                .await
+                .unwrap();
+        assert_eq!(audits, 1);
+
+        let history = format!("/api/channels/{}/messages", channel);
+        let (_, page) = call(&state, Method::GET, &history, Some(&sender), None).await;
+        assert_eq!(contents(&page), ["kept"]);
+        let (_, page) =
+            call(&state, Method::GET, &format!("{}?include_deleted=true", history), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["", "kept"]);
+        assert_eq!(page["items"][0]["id"], doomed.as_str());
+    }
+
+    #[tokio::test]
+    async fn only_sender_or_admin_may_delete() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "delete-perms", "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&member), Some(json!({}))).await;
+        let msg = insert(&state.db, &channel, &owner, "mine", Utc::now()).await;
+        let theirs = insert(&state.db, &channel, &member, "theirs", Utc::now()).await;
+
+        let uri = |id: &MessageId| format!("/api/channels/{}/messages/{}", channel, id);
+        let (status, _) = call(&state, Method::DELETE, &uri(&msg), Some(&member), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&state, Method::DELETE, &uri(&msg), Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call(&state, Method::DELETE, &uri(&MessageId::new()), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&state, Method::DELETE, &uri(&theirs), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn edits_keep_bounded_history() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "edits", "public").await;
+        let msg = insert(&state.db, &channel, &owner, "v0", Utc::now()).await;
+
+        let uri = format!("/api/channels/{}/messages/{}", channel, msg);
+        for i in 1..=12 {
+            let (status, body) =
+                call(&state, Method::PATCH, &uri, Some(&owner), Some(json!({ "content": format!("v{}", i) }))).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["content"], format!("v{}", i));
+            assert!(!body["edited_at"].is_null());
+        }
+
+        let (status, history) = call(&state, Method::GET, &format!("{}/history", uri), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let versions: Vec<&str> =
+            history.as_array().unwrap().iter().map(|v| v["content"].as_str().unwrap()).collect();
+        assert_eq!(versions, (2..=11).rev().map(|i| format!("v{}", i)).collect::<Vec<_>>());
+
+        let (status, _) =
+            call(&state, Method::GET, &format!("{}/history", uri), Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, page) = call(&state, Method::GET, &format!("/api/channels/{}/messages", channel), Some(&owner), None).await;
+        assert_eq!(contents(&page), ["v12"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_foreign_stale_and_deleted_edits() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "edit-rules", "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&member), Some(json!({}))).await;
+
+        let fresh = insert(&state.db, &channel, &owner, "fresh", Utc::now()).await;
+        let stale = insert(&state.db, &channel, &owner, "stale", Utc::now() - chrono::Duration::days(2)).await;
+        let uri = |id: &MessageId| format!("/api/channels/{}/messages/{}", channel, id);
+        let edit = Some(json!({ "content": "changed" }));
+
+        let (status, body) = call(&state, Method::PATCH, &uri(&fresh), Some(&member), edit.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["code"], "forbidden");
+
+        let (status, _) = call(&state, Method::PATCH, &uri(&stale), Some(&owner), edit.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = call(&state, Method::PATCH, &uri(&fresh), Some(&owner), Some(json!({ "content": " " }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        call(&state, Method::DELETE, &uri(&fresh), Some(&owner), None).await;
+        let (status, body) = call(&state, Method::PATCH, &uri(&fresh), Some(&owner), edit).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+        let (status, _) = call(&state, Method::GET, &format!("{}/history", uri(&fresh)), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
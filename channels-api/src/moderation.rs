@@ -0,0 +1,283 @@
+//! The moderators' side of the gateway's spam holds. Admins list held
+//! users and clear or confirm them; the gateway reports each new hold.
+//! Holds and decisions are all recorded in `moderation_audit`, as are the
+//! messages the gateway's content filters redact or block.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+
+use uchat_db::audit::{record_moderation, ModerationAudit};
+use uchat_proto::audit::ModerationAction;
+use uchat_proto::filters::{ContentFiltered, FilterVerdict};
+use uchat_proto::ids::UserId;
+use uchat_proto::moderation::{HeldUser, SpamDecided, SpamDecision, SpamDecisionRequest, SpamFlagged, SpamReason};
+
+use crate::admin::require_admin;
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::gateway::GatewayNotifier;
+use crate::AppState;
+
+fn gateway(state: &AppState) -> Result<&GatewayNotifier, AppError> {
+    state.gateway.as_ref().ok_or_else(|| AppError::unavailable("gateway not configured"))
+}
+
+fn unreachable(e: reqwest::Error) -> AppError {
+    tracing::warn!("gateway call failed: {}", e);
+    AppError::unavailable("gateway unavailable")
+}
+
+/// GET /api/admin/spam
+pub async fn list_held(State(state): State<Arc<AppState>>, user: AuthUser) -> Result<Json<Vec<HeldUser>>, AppError> {
+    require_admin(&state.db, &user).await?;
+    Ok(Json(gateway(&state)?.held_users().await.map_err(unreachable)?))
+}
+
+/// POST /api/admin/spam/{user_id}
+///
+/// `{"decision": "clear"|"confirm"}`, audited once the gateway has
+/// applied it; 404 when the gateway isn't holding the user.
+pub async fn decide(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(req): Json<SpamDecisionRequest>,
+) -> Result<Json<SpamDecided>, AppError> {
+    require_admin(&state.db, &user).await?;
+    let user_id: UserId = id.parse().map_err(|_| AppError::invalid("invalid user id"))?;
+    let decided = gateway(&state)?
+        .decide_spam(&user_id, req.decision)
+        .await
+        .map_err(unreachable)?
+        .ok_or_else(AppError::not_found)?;
+
+    let (action, detail) = match decided.decision {
+        SpamDecision::Clear => (ModerationAction::Cleared, format!("{} held messages sent", decided.messages)),
+        SpamDecision::Confirm => (ModerationAction::Confirmed, format!("{} held messages discarded", decided.messages)),
+    };
+    let mut conn = state.db.acquire().await?;
+    let entry = ModerationAudit { user_id: &user_id, actor_id: Some(&user.user_id), action, detail: Some(&detail) };
+    record_moderation(&mut conn, entry).await?;
+
+    tracing::info!(user_id = %user_id, decided_by = %user.user_id, decision = %decided.decision, "spam hold decided");
+    Ok(Json(decided))
+}
+
+/// POST /internal/moderation/flagged
+///
+/// Called by the gateway when it starts holding a user's messages.
+pub async fn flagged(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(flagged): Json<SpamFlagged>,
+) -> Result<StatusCode, AppError> {
+    if !state.push.gateway_authorized(&headers) {
+        return Err(AppError::forbidden());
+    }
+
+    let reasons: Vec<&str> = flagged.reasons.iter().map(SpamReason::as_str).collect();
+    let detail = format!("{} in {}", reasons.join(","), flagged.channel_id);
+    let mut conn = state.db.acquire().await?;
+    let entry = ModerationAudit {
+        user_id: &flagged.user_id,
+        actor_id: None,
+        action: ModerationAction::Flagged,
+        detail: Some(&detail),
+    };
+    record_moderation(&mut conn, entry).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /internal/moderation/filtered
+///
+/// Called by the gateway for each message a content filter redacted or
+/// blocked. A stored message is updated to match: redacted text replaces
+/// its content, and a blocked one is deleted.
+pub async fn filtered(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(filtered): Json<ContentFiltered>,
+) -> Result<StatusCode, AppError> {
+    if !state.push.gateway_authorized(&headers) {
+        return Err(AppError::forbidden());
+    }
+
+    let (action, detail) = match &filtered.verdict {
+        FilterVerdict::Allow => return Ok(StatusCode::NO_CONTENT),
+        FilterVerdict::Redact { .. } => (ModerationAction::Redacted, format!("{} in {}", filtered.filter, filtered.channel_id)),
+        FilterVerdict::Block { reason } => {
+            (ModerationAction::Blocked, format!("{}: {} in {}", filtered.filter, reason, filtered.channel_id))
+        }
+    };
+    let mut tx = state.db.begin().await?;
+    let entry = ModerationAudit { user_id: &filtered.user_id, actor_id: None, action, detail: Some(&detail) };
+    record_moderation(&mut tx, entry).await?;
+    if let Some(message_id) = &filtered.message_id {
+        let update = match &filtered.verdict {
+            FilterVerdict::Redact { content } => {
+                sqlx::query("UPDATE messages SET content = $2, redacted = true WHERE id = $1 AND deleted_at IS NULL")
+                    .bind(message_id)
+                    .bind(content)
+            }
+            _ => sqlx::query("UPDATE messages SET content = NULL, deleted_at = now() WHERE id = $1 AND deleted_at IS NULL")
+                .bind(message_id),
+        };
+        update.execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::call;
+    use crate::messages::tests::insert;
+    use crate::test_state;
+    use axum::http::Method;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use chrono::Utc;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uchat_proto::ids::MessageId;
+
+    async fn admin(db: &PgPool) -> UserId {
+        let id = UserId::new();
+        sqlx::query("INSERT INTO users (id, username, is_admin) VALUES ($1, $2, true)")
+            .bind(&id)
+            .bind(format!("admin-{}", id))
+            .execute(db)
+            .await
+            .unwrap();
+        id
+    }
+
+    async fn audit(db: &PgPool, user_id: &UserId) -> Vec<(Option<UserId>, String, Option<String>)> {
+        sqlx::query_as("SELECT actor_id, action, detail FROM moderation_audit WHERE user_id = $1 ORDER BY id")
+            .bind(user_id)
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    /// A gateway holding `held`'s messages, two of them.
+    async fn fake_gateway(held: UserId) -> GatewayNotifier {
+        let listed = held.clone();
+        let app = Router::new()
+            .route(
+                "/internal/spam",
+                get(move || async move {
+                    Json(vec![HeldUser {
+                        user_id: listed,
+                        reasons: vec![SpamReason::Duplicates],
+                        since: Utc::now(),
+                        held: 2,
+                        confirmed: false,
+                    }])
+                }),
+            )
+            .route(
+                "/internal/spam/:user_id",
+                post(move |Path(id): Path<String>, Json(req): Json<SpamDecisionRequest>| async move {
+                    if id != held.as_str() {
+                        return Err(StatusCode::NOT_FOUND);
+                    }
+                    Ok(Json(SpamDecided { user_id: held, decision: req.decision, messages: 2 }))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        GatewayNotifier::new(&format!("http://{}", addr), "internal-secret".into())
+    }
+
+    #[tokio::test]
+    async fn admins_decide_on_held_users_and_everything_is_audited() {
+        let Some(mut state) = test_state().await else { return };
+        let (spammer, member) = (UserId::new(), UserId::new());
+        let admin_id = admin(&state.db).await;
+        let settings = Arc::get_mut(&mut state).unwrap();
+        settings.gateway = Some(fake_gateway(spammer.clone()).await);
+        settings.push.internal_token = Some("internal-secret".into());
+
+        let flag = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-internal-token", token.parse().unwrap());
+            let flagged = SpamFlagged {
+                user_id: spammer.clone(),
+                channel_id: uchat_proto::ids::ChannelId::new(),
+                reasons: vec![SpamReason::Duplicates, SpamReason::LinkDensity],
+            };
+            super::flagged(State(state.clone()), headers, Json(flagged))
+        };
+        assert!(matches!(flag("nope").await, Err(AppError { status: StatusCode::FORBIDDEN, .. })));
+        assert_eq!(flag("internal-secret").await.unwrap(), StatusCode::NO_CONTENT);
+
+        let (status, _) = call(&state, Method::GET, "/api/admin/spam", Some(&member), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = call(&state, Method::GET, "/api/admin/spam", Some(&admin_id), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["user_id"], spammer.as_str());
+
+        let uri = format!("/api/admin/spam/{}", spammer);
+        let (status, body) = call(&state, Method::POST, &uri, Some(&admin_id), Some(json!({ "decision": "confirm" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((body["decision"].as_str(), body["messages"].as_u64()), (Some("confirm"), Some(2)));
+        let uri = format!("/api/admin/spam/{}", member);
+        let (status, _) = call(&state, Method::POST, &uri, Some(&admin_id), Some(json!({ "decision": "clear" }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let trail = audit(&state.db, &spammer).await;
+        assert_eq!(trail.len(), 2);
+        assert_eq!((&trail[0].0, trail[0].1.as_str()), (&None, "flagged"));
+        assert!(trail[0].2.as_deref().unwrap().starts_with("duplicates,link_density in "));
+        assert_eq!(trail[1], (Some(admin_id), "confirmed".into(), Some("2 held messages discarded".into())));
+        assert!(audit(&state.db, &member).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filter_decisions_are_audited_and_update_stored_messages() {
+        let Some(mut state) = test_state().await else { return };
+        Arc::get_mut(&mut state).unwrap().push.internal_token = Some("internal-secret".into());
+        let (sender, channel_id) = (UserId::new(), uchat_proto::ids::ChannelId::new());
+        let redacted = insert(&state.db, channel_id.as_str(), &sender, "darn it", Utc::now()).await;
+        let blocked = insert(&state.db, channel_id.as_str(), &sender, "project falcon", Utc::now()).await;
+
+        let report = |message_id: MessageId, filter: &str, verdict: FilterVerdict| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-internal-token", "internal-secret".parse().unwrap());
+            let filtered = ContentFiltered {
+                user_id: sender.clone(),
+                channel_id: channel_id.clone(),
+                message_id: Some(message_id),
+                filter: filter.into(),
+                verdict,
+            };
+            super::filtered(State(state.clone()), headers, Json(filtered))
+        };
+        let redact = FilterVerdict::Redact { content: "**** it".into() };
+        assert_eq!(report(redacted.clone(), "rules", redact).await.unwrap(), StatusCode::NO_CONTENT);
+        let block = FilterVerdict::Block { reason: "export control".into() };
+        assert_eq!(report(blocked.clone(), "webhook", block).await.unwrap(), StatusCode::NO_CONTENT);
+
+        let stored = |id: MessageId| {
+            sqlx::query_as::<_, (Option<String>, bool, bool)>(
+                "SELECT content, redacted, deleted_at IS NOT NULL FROM messages WHERE id = $1",
+            )
+            .bind(id)
+            .fetch_one(&state.db)
+        };
+        assert_eq!(stored(redacted).await.unwrap(), (Some("**** it".into()), true, false));
+        assert_eq!(stored(blocked).await.unwrap(), (None, false, true));
+
+        let trail = audit(&state.db, &sender).await;
+        assert_eq!(trail[0], (None, "redacted".into(), Some(format!("rules in {}", channel_id))));
+        assert_eq!(trail[1], (None, "blocked".into(), Some(format!("webhook: export control in {}", channel_id))));
+    }
+}
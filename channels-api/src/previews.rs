@@ -0,0 +1,599 @@
+//! Link previews. A worker takes each newly stored message with links in
+//! it, unless it is encrypted or its channel has `link_previews` off,
+//! unfurls up to three of the links from their OpenGraph tags (or title
+//! and description) and sends each preview to the gateway for the room.
+//! Previews are cached by URL for a day.
+//!
+//! Fetching what users link to is a server-side request forgery risk, so
+//! only http(s) pages on public addresses are fetched, the connection is
+//! pinned to the address that was checked, redirects are followed by hand
+//! and checked the same way, and only the first 512 KiB of HTML is read.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+
+use uchat_proto::ids::{ChannelId, MessageId};
+use uchat_proto::messages::{LinkPreview, LinkPreviewReady};
+
+use crate::AppState;
+
+const RUN_INTERVAL: Duration = Duration::from_secs(5);
+/// Messages older than this are left alone, so turning previews on for a
+/// channel doesn't unfurl its history.
+const LOOKBACK: chrono::Duration = chrono::Duration::minutes(10);
+const BATCH_SIZE: i64 = 100;
+const MAX_LINKS_PER_MESSAGE: usize = 3;
+const CACHE_TTL: chrono::Duration = chrono::Duration::hours(24);
+/// Covers resolving, connecting and reading, across every redirect.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REDIRECTS: usize = 3;
+const MAX_HTML_BYTES: usize = 512 * 1024;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 500;
+
+/// Fetches pages for previews, refusing addresses `allowed` rejects.
+pub struct Fetcher {
+    allowed: fn(IpAddr) -> bool,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self { allowed: is_public }
+    }
+}
+
+/// Unfurls new messages every five seconds.
+pub fn spawn(state: Arc<AppState>, fetcher: Fetcher) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RUN_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = run(&state, &fetcher).await {
+                tracing::warn!("link preview run failed: {}", e);
+            }
+        }
+    });
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingRow {
+    id: MessageId,
+    channel_id: ChannelId,
+    content: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct PreviewRow {
+    url: String,
+    title: Option<String>,
+    description: Option<String>,
+    image_url: Option<String>,
+    site_name: Option<String>,
+}
+
+impl PreviewRow {
+    /// `None` for a page that had nothing to show.
+    fn into_preview(self) -> Option<LinkPreview> {
+        if self.title.is_none() && self.description.is_none() && self.image_url.is_none() {
+            return None;
+        }
+        let PreviewRow { url, title, description, image_url, site_name } = self;
+        Some(LinkPreview { url, title, description, image_url, site_name })
+    }
+}
+
+/// Unfurls the links in every message waiting; returns the previews sent.
+pub async fn run(state: &AppState, fetcher: &Fetcher) -> Result<Vec<LinkPreviewReady>, sqlx::Error> {
+    let pending: Vec<PendingRow> = sqlx::query_as(
+        "SELECT m.id, m.channel_id, m.content
+         FROM messages m JOIN channels c ON c.id = m.channel_id
+         WHERE m.unfurled_at IS NULL AND m.created_at > $1
+           AND NOT m.encrypted AND m.deleted_at IS NULL AND c.link_previews
+           AND m.content ~ 'https?://'
+         ORDER BY m.created_at
+         LIMIT $2",
+    )
+    .bind(Utc::now() - LOOKBACK)
+    .bind(BATCH_SIZE)
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut sent = Vec::new();
+    for message in pending {
+        // Claimed first, so another instance or a failed fetch can't have
+        // the message unfurled twice.
+        let claimed = sqlx::query("UPDATE messages SET unfurled_at = now() WHERE id = $1 AND unfurled_at IS NULL")
+            .bind(&message.id)
+            .execute(&state.db)
+            .await?;
+        if claimed.rows_affected() == 0 {
+            continue;
+        }
+
+        for url in links(&message.content) {
+            let Some(preview) = preview(state, fetcher, &url).await? else { continue };
+            let ready = LinkPreviewReady { message_id: message.id.clone(), channel_id: message.channel_id.clone(), preview };
+            if let Some(gateway) = &state.gateway {
+                gateway.link_preview(&ready).await;
+            }
+            sent.push(ready);
+        }
+    }
+    Ok(sent)
+}
+
+/// The cached preview for `url`, or a fresh one, cached.
+async fn preview(state: &AppState, fetcher: &Fetcher, url: &Url) -> Result<Option<LinkPreview>, sqlx::Error> {
+    let url_hash = hex::encode(Sha256::digest(url.as_str()));
+    let cached: Option<PreviewRow> = sqlx::query_as(
+        "SELECT url, title, description, image_url, site_name FROM link_previews
+         WHERE url_hash = $1 AND fetched_at > $2",
+    )
+    .bind(&url_hash)
+    .bind(Utc::now() - CACHE_TTL)
+    .fetch_optional(&state.db)
+    .await?;
+    if let Some(row) = cached {
+        return Ok(row.into_preview());
+    }
+
+    let fetched = match fetcher.fetch(url).await {
+        Ok(preview) => preview,
+        Err(e) => {
+            tracing::info!(url = %url, "not unfurling link: {}", e);
+            None
+        }
+    };
+    let stored = fetched.clone().unwrap_or(LinkPreview {
+        url: url.to_string(),
+        title: None,
+        description: None,
+        image_url: None,
+        site_name: None,
+    });
+    sqlx::query(
+        "INSERT INTO link_previews (url_hash, url, title, description, image_url, site_name)
+         VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (url_hash) DO UPDATE
+         SET title = EXCLUDED.title, description = EXCLUDED.description, image_url = EXCLUDED.image_url,
+             site_name = EXCLUDED.site_name, fetched_at = now()",
+    )
+    .bind(&url_hash)
+    .bind(&stored.url)
+    .bind(&stored.title)
+    .bind(&stored.description)
+    .bind(&stored.image_url)
+    .bind(&stored.site_name)
+    .execute(&state.db)
+    .await?;
+
+    Ok(fetched)
+}
+
+/// The first few distinct http(s) links in `content`, without trailing
+/// punctuation.
+fn links(content: &str) -> Vec<Url> {
+    let mut links: Vec<Url> = Vec::new();
+    for word in content.split_whitespace() {
+        let Some(start) = word.find("http://").or_else(|| word.find("https://")) else { continue };
+        let candidate = word[start..].trim_end_matches(|c: char| ".,;:!?)]}>'\"".contains(c));
+        let Ok(url) = Url::parse(candidate) else { continue };
+        if url.host_str().is_some() && !links.contains(&url) {
+            links.push(url);
+        }
+        if links.len() == MAX_LINKS_PER_MESSAGE {
+            break;
+        }
+    }
+    links
+}
+
+impl Fetcher {
+    /// The preview for the HTML page at `url`, or `None` when it isn't an
+    /// HTML page; `Err` for links that mustn't or couldn't be fetched.
+    pub async fn fetch(&self, url: &Url) -> Result<Option<LinkPreview>, String> {
+        tokio::time::timeout(FETCH_TIMEOUT, self.fetch_following(url.clone()))
+            .await
+            .map_err(|_| "timed out".to_string())?
+    }
+
+    async fn fetch_following(&self, mut url: Url) -> Result<Option<LinkPreview>, String> {
+        for _ in 0..=MAX_REDIRECTS {
+            let resp = self.get(&url).await?;
+            if resp.status().is_redirection() {
+                let location = resp.headers().get(LOCATION).and_then(|v| v.to_str().ok()).ok_or("redirect without a location")?;
+                url = url.join(location).map_err(|e| e.to_string())?;
+                continue;
+            }
+            if !resp.status().is_success() {
+                return Err(format!("status {}", resp.status()));
+            }
+            let html = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).is_some_and(|t| t.starts_with("text/html"));
+            if !html {
+                return Ok(None);
+            }
+            let page = read_capped(resp).await?;
+            return Ok(parse_html(&url, &String::from_utf8_lossy(&page)));
+        }
+        Err("too many redirects".into())
+    }
+
+    /// One request, connected to an address that was checked.
+    async fn get(&self, url: &Url) -> Result<reqwest::Response, String> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("{} links aren't fetched", url.scheme()));
+        }
+        let host = url.host_str().ok_or("no host")?;
+        let port = url.port_or_known_default().ok_or("no port")?;
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await
+            .map_err(|e| e.to_string())?
+            .collect();
+        // Every address must pass, or a name could list a public address
+        // next to an internal one and the connection pick the latter.
+        if addrs.is_empty() || !addrs.iter().all(|addr| (self.allowed)(addr.ip())) {
+            return Err(format!("{} resolves to an address that isn't public", host));
+        }
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .no_proxy()
+            .resolve(host, addrs[0])
+            .build()
+            .map_err(|e| e.to_string())?;
+        client
+            .get(url.clone())
+            .header(ACCEPT, "text/html")
+            .send()
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The start of the body, up to `MAX_HTML_BYTES`; the tags previews use
+/// are in the head.
+async fn read_capped(mut resp: reqwest::Response) -> Result<Vec<u8>, String> {
+    let mut page = Vec::new();
+    while let Some(chunk) = resp.chunk().await.map_err(|e| e.to_string())? {
+        let room = MAX_HTML_BYTES - page.len();
+        page.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        if page.len() == MAX_HTML_BYTES {
+            break;
+        }
+    }
+    Ok(page)
+}
+
+/// Whether `ip` is on the public internet: not loopback, private,
+/// link-local, shared, reserved or otherwise special.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // NAT64 addresses reach the IPv4 address they embed.
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+}
+
+/// The preview `html` describes, if it has a title, description or image.
+fn parse_html(url: &Url, html: &str) -> Option<LinkPreview> {
+    let og = |name: &str| meta_content(html, name).map(|value| decode_entities(&value));
+    let og_title = og("og:title");
+    let description = og("og:description").or_else(|| og("description"));
+    let image = og("og:image");
+    let site_name = og("og:site_name");
+    let title = og_title.or_else(|| title_tag(html).map(|t| decode_entities(&t)));
+
+    let clean = |text: Option<String>, max: usize| {
+        text.map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|t| !t.is_empty())
+            .map(|t| truncate(&t, max))
+    };
+    let title = clean(title, MAX_TITLE_CHARS);
+    let description = clean(description, MAX_DESCRIPTION_CHARS);
+    let image_url = image
+        .and_then(|src| url.join(src.trim()).ok())
+        .filter(|src| matches!(src.scheme(), "http" | "https"))
+        .map(String::from);
+    if title.is_none() && description.is_none() && image_url.is_none() {
+        return None;
+    }
+    Some(LinkPreview { url: url.to_string(), title, description, image_url, site_name: clean(site_name, MAX_TITLE_CHARS) })
+}
+
+/// `content` of the first `<meta>` whose `property` or `name` is `name`.
+fn meta_content(html: &str, name: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(start) = lower[from..].find("<meta").map(|i| from + i) {
+        let end = lower[start..].find('>').map_or(html.len(), |i| start + i);
+        let attrs = attributes(&html[start + 5..end]);
+        let named = attrs.iter().any(|(key, value)| (key == "property" || key == "name") && value.eq_ignore_ascii_case(name));
+        if named {
+            if let Some((_, content)) = attrs.into_iter().find(|(key, _)| key == "content") {
+                return Some(content);
+            }
+        }
+        from = end;
+    }
+    None
+}
+
+/// An element's attributes, names lowercased.
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag.trim_start_matches('/').trim();
+    while !rest.is_empty() {
+        let name_end = rest.find(|c: char| c == '=' || c.is_whitespace() || c == '/').unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after) = rest.strip_prefix('=') {
+            let after = after.trim_start();
+            let (found, tail) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let body = &after[1..];
+                    let close = body.find(quote).unwrap_or(body.len());
+                    (&body[..close], body.get(close + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let close = after.find(char::is_whitespace).unwrap_or(after.len());
+                    (&after[..close], &after[close..])
+                }
+            };
+            value = found.to_string();
+            rest = tail;
+        }
+        if !name.is_empty() {
+            attrs.push((name, value));
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    }
+    attrs
+}
+
+fn title_tag(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    Some(html[start..end].to_string())
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use axum::response::{IntoResponse, Redirect};
+    use axum::routing::get;
+    use axum::Router;
+    use serde_json::json;
+    use uchat_proto::ids::UserId;
+
+    const PAGE: &str = r#"<html><head>
+        <title>Fallback title</title>
+        <meta property="og:title" content="Release &amp; notes">
+        <meta name='description' content='What&#39;s new'>
+        <META PROPERTY="og:image" CONTENT="/img/card.png" />
+        <meta property="og:site_name" content="Example">
+        </head><body>...</body></html>"#;
+
+    #[test]
+    fn reads_opengraph_tags_and_falls_back_to_the_title() {
+        let url = Url::parse("https://example.com/blog/post").unwrap();
+        let preview = parse_html(&url, PAGE).unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Release & notes"));
+        assert_eq!(preview.description.as_deref(), Some("What's new"));
+        assert_eq!(preview.image_url.as_deref(), Some("https://example.com/img/card.png"));
+        assert_eq!(preview.site_name.as_deref(), Some("Example"));
+
+        let preview = parse_html(&url, "<title>\n  Just a   title </title>").unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Just a title"));
+        assert!(parse_html(&url, "<p>nothing here</p>").is_none());
+        let script_image = r#"<meta property="og:image" content="javascript:alert(1)">"#;
+        assert!(parse_html(&url, script_image).is_none());
+    }
+
+    #[test]
+    fn finds_links_in_messages() {
+        let found = links("see (https://example.com/a), http://example.org/b. and https://example.com/a again");
+        let found: Vec<&str> = found.iter().map(Url::as_str).collect();
+        assert_eq!(found, ["https://example.com/a", "http://example.org/b"]);
+        assert!(links("ftp://example.com and https:// alone").is_empty());
+        assert_eq!(links("https://a.test https://b.test https://c.test https://d.test").len(), MAX_LINKS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn only_public_addresses_are_fetched() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "8.8.8.8"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        let internal = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "255.255.255.255", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1", "64:ff9b::a00:1",
+        ];
+        for ip in internal {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    /// Serves `app` on 127.0.0.1, with a fetcher that allows only that
+    /// address.
+    async fn serve(app: Router) -> (Url, Fetcher) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let fetcher = Fetcher { allowed: |ip| ip == IpAddr::V4(Ipv4Addr::LOCALHOST) };
+        (url, fetcher)
+    }
+
+    fn html(body: &'static str) -> impl IntoResponse {
+        ([(CONTENT_TYPE, "text/html; charset=utf-8")], body)
+    }
+
+    #[tokio::test]
+    async fn refuses_internal_addresses_and_redirects_into_them() {
+        let app = Router::new()
+            .route("/page", get(|| async { html(PAGE) }))
+            .route("/hop", get(|| async { Redirect::temporary("/page") }))
+            .route("/escape", get(|| async { Redirect::temporary("http://127.0.0.2/admin") }))
+            .route("/json", get(|| async { axum::Json(json!({ "title": "no" })) }));
+        let (base, fetcher) = serve(app).await;
+
+        let preview = fetcher.fetch(&base.join("/hop").unwrap()).await.unwrap().unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Release & notes"));
+        assert!(fetcher.fetch(&base.join("/escape").unwrap()).await.unwrap_err().contains("isn't public"));
+        assert_eq!(fetcher.fetch(&base.join("/json").unwrap()).await, Ok(None));
+
+        let strict = Fetcher::default();
+        assert!(strict.fetch(&base.join("/page").unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_start_of_large_pages() {
+        static BIG: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        let page = BIG.get_or_init(|| format!("{}{}", PAGE, "x".repeat(4 * MAX_HTML_BYTES)));
+        let app = Router::new().route("/big", get(move || async move { html(page) }));
+        let (base, fetcher) = serve(app).await;
+
+        let preview = fetcher.fetch(&base.join("/big").unwrap()).await.unwrap().unwrap();
+        assert_eq!(preview.site_name.as_deref(), Some("Example"));
+    }
+
+    async fn message(state: &AppState, channel: &str, sender: &UserId, content: &str, encrypted: bool) -> MessageId {
+        let id = MessageId::new();
+        sqlx::query("INSERT INTO messages (id, channel_id, sender_id, content, encrypted) VALUES ($1, $2, $3, $4, $5)")
+            .bind(&id)
+            .bind(channel)
+            .bind(sender)
+            .bind(content)
+            .bind(encrypted)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn unfurls_new_messages_once_except_where_turned_off() {
+        let Some(state) = test_state().await else { return };
+        let (base, fetcher) = serve(Router::new().route("/page", get(|| async { html(PAGE) }))).await;
+        let link = base.join("/page").unwrap();
+        let owner = UserId::new();
+        let open = create(&state, &owner, "previews-on", "public").await;
+        let quiet = create(&state, &owner, "previews-off", "public").await;
+        let (status, _) = call(
+            &state,
+            Method::PATCH,
+            &format!("/api/channels/{}", quiet),
+            Some(&owner),
+            Some(json!({ "link_previews": false })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let shown = message(&state, &open, &owner, &format!("look: {}", link), false).await;
+        let sealed = message(&state, &open, &owner, &format!("ciphertext {}", link), true).await;
+        let muted = message(&state, &quiet, &owner, &format!("look: {}", link), false).await;
+
+        let sent = run(&state, &fetcher).await.unwrap();
+        let ours: Vec<&LinkPreviewReady> = sent.iter().filter(|r| [&shown, &sealed, &muted].contains(&&r.message_id)).collect();
+        assert_eq!(ours.len(), 1);
+        assert_eq!(ours[0].message_id, shown);
+        assert_eq!(ours[0].preview.url, link.as_str());
+        assert_eq!(ours[0].preview.title.as_deref(), Some("Release & notes"));
+
+        // Unfurled once, and the next message with the link is answered
+        // from the cache even though the page can't be fetched any more.
+        assert!(!run(&state, &fetcher).await.unwrap().iter().any(|r| r.message_id == shown));
+        let again = message(&state, &open, &owner, &format!("again {}", link), false).await;
+        let sent = run(&state, &Fetcher { allowed: |_| false }).await.unwrap();
+        assert!(sent.iter().any(|r| r.message_id == again && r.preview.title.as_deref() == Some("Release & notes")));
+    }
+}
@@ -0,0 +1,787 @@
+//! Mobile push notifications. Devices register their FCM or APNs token
+//! with `POST /api/push/register`, and members can mute a channel's pushes.
+//! The gateway hands over every message it fans out, naming who was
+//! online in the channel; everyone else with a registered device hears
+//! about it through their platform's push service. Encrypted messages are
+//! announced without content, and tokens the push service reports as dead
+//! are dropped.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use uchat_proto::push::{
+    ChannelNotifications, PushMessage, PushPlatform, PushPrefs, PushRegistration, RegisterPushToken,
+};
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, visible_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+const MAX_TOKEN_LEN: usize = 4096;
+/// Longest message text shown in a notification, in characters.
+const MAX_PREVIEW_CHARS: usize = 180;
+/// Pushes in flight at once for one message.
+const CONCURRENT_SENDS: usize = 8;
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+/// APNs refuses provider tokens older than an hour.
+const APNS_TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+
+/// How a push went, as counted in `uchat_push_deliveries_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Delivered,
+    /// The service says the token is dead; its registration is dropped.
+    InvalidToken,
+    Failed,
+    /// No credentials are configured for the platform.
+    Unconfigured,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Delivered => "delivered",
+            Outcome::InvalidToken => "invalid_token",
+            Outcome::Failed => "failed",
+            Outcome::Unconfigured => "unconfigured",
+        }
+    }
+}
+
+/// What a device is shown. Notifications for the same channel share a
+/// collapse key, so a backlog shows as the latest rather than a stack.
+#[derive(Debug, Clone, PartialEq)]
+struct Notification {
+    title: String,
+    body: String,
+    collapse_key: String,
+}
+
+/// Sends pushes through whichever platforms are configured.
+#[derive(Default)]
+pub struct PushDispatcher {
+    /// Shared with the gateway, which presents it on `/internal/push`
+    /// and `/internal/moderation/flagged`.
+    pub internal_token: Option<String>,
+    fcm: Option<Fcm>,
+    apns: Option<Apns>,
+    /// By platform and outcome.
+    attempts: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+}
+
+impl PushDispatcher {
+    /// Takes the gateway's token from `GATEWAY_INTERNAL_TOKEN`, FCM from the
+    /// service account key at `FCM_SERVICE_ACCOUNT_PATH`, and APNs from the
+    /// `.p8` key at `APNS_KEY_PATH` with `APNS_KEY_ID`, `APNS_TEAM_ID` and
+    /// `APNS_TOPIC` (the app's bundle id). `FCM_ENDPOINT` and
+    /// `APNS_ENDPOINT` override the services' addresses, e.g. for APNs'
+    /// sandbox. A platform left unset is skipped; one set up wrong is fatal.
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let read = |path: &str| std::fs::read(path).unwrap_or_else(|e| panic!("reading {}: {}", path, e));
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().expect("build push client");
+
+        let fcm = var("FCM_SERVICE_ACCOUNT_PATH").map(|path| {
+            let account: ServiceAccount =
+                serde_json::from_slice(&read(&path)).unwrap_or_else(|e| panic!("parsing {}: {}", path, e));
+            let key = EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+                .unwrap_or_else(|e| panic!("invalid private_key in {}: {}", path, e));
+            Fcm {
+                client: client.clone(),
+                endpoint: var("FCM_ENDPOINT").unwrap_or_else(|| "https://fcm.googleapis.com".into()),
+                account,
+                key,
+                access_token: tokio::sync::Mutex::new(None),
+            }
+        });
+        let apns = var("APNS_KEY_PATH").map(|path| {
+            let required = |name: &str| var(name).unwrap_or_else(|| panic!("{} must be set with APNS_KEY_PATH", name));
+            Apns {
+                client: client.clone(),
+                endpoint: var("APNS_ENDPOINT").unwrap_or_else(|| "https://api.push.apple.com".into()),
+                topic: required("APNS_TOPIC"),
+                team_id: required("APNS_TEAM_ID"),
+                key_id: required("APNS_KEY_ID"),
+                key: EncodingKey::from_ec_pem(&read(&path)).unwrap_or_else(|e| panic!("invalid key in {}: {}", path, e)),
+                provider_token: Mutex::new(None),
+            }
+        });
+
+        Self { internal_token: var("GATEWAY_INTERNAL_TOKEN"), fcm, apns, attempts: Mutex::default() }
+    }
+
+    /// Whether a request carries the gateway's internal token.
+    pub fn gateway_authorized(&self, headers: &HeaderMap) -> bool {
+        let presented = headers.get("x-internal-token").and_then(|v| v.to_str().ok());
+        self.internal_token.is_some() && presented == self.internal_token.as_deref()
+    }
+
+    async fn send(&self, platform: PushPlatform, token: &str, notification: &Notification) -> Outcome {
+        let outcome = match (platform, &self.fcm, &self.apns) {
+            (PushPlatform::Fcm, Some(fcm), _) => fcm.send(token, notification).await,
+            (PushPlatform::Apns, _, Some(apns)) => apns.send(token, notification).await,
+            _ => Outcome::Unconfigured,
+        };
+        *self.attempts.lock().unwrap().entry((platform.as_str(), outcome.as_str())).or_default() += 1;
+        outcome
+    }
+
+    /// The `uchat_push_deliveries_total` family.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP uchat_push_deliveries_total Push notification attempts, by platform and outcome.\n");
+        out.push_str("# TYPE uchat_push_deliveries_total counter\n");
+        for ((platform, outcome), count) in self.attempts.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "uchat_push_deliveries_total{{platform=\"{}\",outcome=\"{}\"}} {}",
+                platform, outcome, count
+            );
+        }
+        out
+    }
+}
+
+/// The fields of a Google service account key that FCM needs.
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// FCM's HTTP v1 API, authenticated with OAuth tokens minted from a
+/// service account.
+struct Fcm {
+    client: reqwest::Client,
+    endpoint: String,
+    account: ServiceAccount,
+    key: EncodingKey,
+    /// The current access token and when it stops working.
+    access_token: tokio::sync::Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Serialize)]
+struct OAuthClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct OAuthToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl Fcm {
+    async fn access_token(&self) -> Result<String, String> {
+        let mut cached = self.access_token.lock().await;
+        if let Some((token, expires)) = &*cached {
+            if *expires > Instant::now() + Duration::from_secs(60) {
+                return Ok(token.clone());
+            }
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = OAuthClaims {
+            iss: &self.account.client_email,
+            scope: FCM_SCOPE,
+            aud: &self.account.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let assertion =
+            jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key).map_err(|e| e.to_string())?;
+        let token: OAuthToken = self
+            .client
+            .post(&self.account.token_uri)
+            .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &assertion)])
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        *cached = Some((token.access_token.clone(), Instant::now() + Duration::from_secs(token.expires_in)));
+        Ok(token.access_token)
+    }
+
+    async fn send(&self, token: &str, notification: &Notification) -> Outcome {
+        let access_token = match self.access_token().await {
+            Ok(access_token) => access_token,
+            Err(e) => {
+                tracing::warn!("FCM authentication failed: {}", e);
+                return Outcome::Failed;
+            }
+        };
+        let body = json!({
+            "message": {
+                "token": token,
+                "notification": {"title": notification.title, "body": notification.body},
+                "android": {"collapse_key": notification.collapse_key},
+            }
+        });
+        let url = format!("{}/v1/projects/{}/messages:send", self.endpoint, self.account.project_id);
+        let resp = match self.client.post(url).bearer_auth(access_token).json(&body).send().await {
+            Ok(resp) => resp,
+            Err(e) => {
+                tracing::warn!("FCM request failed: {}", e);
+                return Outcome::Failed;
+            }
+        };
+        if resp.status().is_success() {
+            return Outcome::Delivered;
+        }
+        let status = resp.status();
+        let error: Value = resp.json().await.unwrap_or_default();
+        let codes: Vec<&str> = error["error"]["details"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|detail| detail["errorCode"].as_str())
+            .collect();
+        // An unknown or malformed token; the message itself is always well formed.
+        if status == StatusCode::NOT_FOUND || codes.iter().any(|c| *c == "UNREGISTERED" || *c == "INVALID_ARGUMENT") {
+            return Outcome::InvalidToken;
+        }
+        tracing::warn!(%status, ?codes, "FCM refused a push");
+        Outcome::Failed
+    }
+}
+
+/// APNs, authenticated with provider tokens signed by a `.p8` key.
+struct Apns {
+    client: reqwest::Client,
+    endpoint: String,
+    topic: String,
+    team_id: String,
+    key_id: String,
+    key: EncodingKey,
+    /// The current provider token and when it was made.
+    provider_token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Serialize)]
+struct ProviderClaims<'a> {
+    iss: &'a str,
+    iat: i64,
+}
+
+impl Apns {
+    fn provider_token(&self) -> Result<String, String> {
+        let mut cached = self.provider_token.lock().unwrap();
+        if let Some((token, made)) = &*cached {
+            if made.elapsed() < APNS_TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(self.key_id.clone());
+        let claims = ProviderClaims { iss: &self.team_id, iat: Utc::now().timestamp() };
+        let token = jsonwebtoken::encode(&header, &claims, &self.key).map_err(|e| e.to_string())?;
+        *cached = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+
+    async fn send(&self, token: &str, notification: &Notification) -> Outcome {
+        let provider_token = match self.provider_token() {
+            Ok(provider_token) => provider_token,
+            Err(e) => {
+                tracing::warn!("APNs signing failed: {}", e);
+                return Outcome::Failed;
+            }
+        };
+        let body = json!({
+            "aps": {
+                "alert": {"title": notification.title, "body": notification.body},
+                "thread-id": notification.collapse_key,
+            }
+        });
+        let resp = self
+            .client
+            .post(format!("{}/3/device/{}", self.endpoint, token))
+            .bearer_auth(provider_token)
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "alert")
+            .header("apns-collapse-id", &notification.collapse_key)
+            .json(&body)
+            .send()
+            .await;
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                tracing::warn!("APNs request failed: {}", e);
+                return Outcome::Failed;
+            }
+        };
+        if resp.status().is_success() {
+            return Outcome::Delivered;
+        }
+        let status = resp.status();
+        let error: Value = resp.json().await.unwrap_or_default();
+        let reason = error["reason"].as_str().unwrap_or_default();
+        if status == StatusCode::GONE || matches!(reason, "BadDeviceToken" | "DeviceTokenNotForTopic" | "Unregistered") {
+            return Outcome::InvalidToken;
+        }
+        tracing::warn!(%status, reason, "APNs refused a push");
+        Outcome::Failed
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TokenRow {
+    platform: String,
+    token: String,
+    enabled: bool,
+    show_previews: bool,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<TokenRow> for PushRegistration {
+    type Error = AppError;
+
+    fn try_from(row: TokenRow) -> Result<Self, AppError> {
+        Ok(PushRegistration {
+            platform: row.platform.parse().map_err(AppError::invalid)?,
+            token: row.token,
+            prefs: PushPrefs { enabled: row.enabled, show_previews: row.show_previews },
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+/// POST /api/push/register
+///
+/// Registers the caller's device, or updates its settings.
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(body): Json<RegisterPushToken>,
+) -> Result<Json<PushRegistration>, AppError> {
+    // Tokens end up in APNs request paths.
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '-');
+    if body.token.is_empty() || body.token.len() > MAX_TOKEN_LEN || !body.token.chars().all(valid) {
+        return Err(AppError::invalid("token is not a valid device token"));
+    }
+
+    let row: TokenRow = sqlx::query_as(
+        "INSERT INTO push_tokens (token, user_id, platform, enabled, show_previews)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (token) DO UPDATE SET
+             user_id = EXCLUDED.user_id,
+             platform = EXCLUDED.platform,
+             enabled = EXCLUDED.enabled,
+             show_previews = EXCLUDED.show_previews,
+             updated_at = now()
+         RETURNING platform, token, enabled, show_previews, updated_at",
+    )
+    .bind(&body.token)
+    .bind(&user.user_id)
+    .bind(body.platform.as_str())
+    .bind(body.prefs.enabled)
+    .bind(body.prefs.show_previews)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(row.try_into()?))
+}
+
+/// The caller's membership row must exist to hold its settings.
+async fn require_member(state: &AppState, user: &AuthUser, id: &str) -> Result<uchat_proto::ids::ChannelId, AppError> {
+    let channel_id = parse_channel_id(id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role.is_none() {
+        return Err(AppError::forbidden());
+    }
+    Ok(channel_id)
+}
+
+/// GET /api/channels/{id}/notifications
+pub async fn get_notifications(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<ChannelNotifications>, AppError> {
+    let channel_id = require_member(&state, &user, &id).await?;
+    let muted: bool =
+        sqlx::query_scalar("SELECT push_muted FROM channel_members WHERE channel_id = $1 AND user_id = $2")
+            .bind(&channel_id)
+            .bind(&user.user_id)
+            .fetch_one(&state.db)
+            .await?;
+    Ok(Json(ChannelNotifications { muted }))
+}
+
+/// PUT /api/channels/{id}/notifications
+pub async fn set_notifications(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<ChannelNotifications>,
+) -> Result<Json<ChannelNotifications>, AppError> {
+    let channel_id = require_member(&state, &user, &id).await?;
+    sqlx::query("UPDATE channel_members SET push_muted = $3 WHERE channel_id = $1 AND user_id = $2")
+        .bind(&channel_id)
+        .bind(&user.user_id)
+        .bind(body.muted)
+        .execute(&state.db)
+        .await?;
+    Ok(Json(body))
+}
+
+/// POST /internal/push
+///
+/// Called by the gateway for each message it fans out. Pushes go out in
+/// the background; 202 once they are queued.
+pub async fn dispatch(State(state): State<Arc<AppState>>, headers: HeaderMap, Json(message): Json<PushMessage>) -> StatusCode {
+    if !state.push.gateway_authorized(&headers) {
+        return StatusCode::FORBIDDEN;
+    }
+    uchat_metrics::spawn_task("push_dispatch", async move {
+        if let Err(e) = deliver(&state, message).await {
+            tracing::warn!("push dispatch failed: {}", e);
+        }
+    });
+    StatusCode::ACCEPTED
+}
+
+#[derive(sqlx::FromRow)]
+struct RecipientRow {
+    token: String,
+    platform: String,
+    show_previews: bool,
+}
+
+/// Pushes `message` to the enabled devices of channel members who didn't
+/// get it live and haven't muted the channel, dropping tokens the push
+/// services reject.
+async fn deliver(state: &AppState, message: PushMessage) -> Result<(), sqlx::Error> {
+    let delivered_to: Vec<String> = message.delivered_to.iter().map(|id| id.to_string()).collect();
+    let recipients: Vec<RecipientRow> = sqlx::query_as(
+        "SELECT t.token, t.platform, t.show_previews
+         FROM push_tokens t
+         JOIN channel_members m ON m.user_id = t.user_id
+         WHERE m.channel_id = $1 AND NOT m.push_muted AND t.enabled
+           AND t.user_id <> $2 AND NOT (t.user_id = ANY($3))",
+    )
+    .bind(&message.channel_id)
+    .bind(&message.sender_id)
+    .bind(&delivered_to)
+    .fetch_all(&state.db)
+    .await?;
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    let (channel_name, username): (String, Option<String>) =
+        sqlx::query_as("SELECT name, (SELECT COALESCE(display_name, username) FROM users WHERE id = $2) FROM channels WHERE id = $1")
+            .bind(&message.channel_id)
+            .bind(&message.sender_id)
+            .fetch_one(&state.db)
+            .await?;
+    let sender = message.sender_name.clone().or(username).unwrap_or_else(|| "Someone".into());
+    let notification = |show_preview: bool| Notification {
+        title: format!("#{}", channel_name),
+        body: match &message.content {
+            None => "New encrypted message".into(),
+            Some(_) if !show_preview => "New message".into(),
+            Some(content) => format!("{}: {}", sender, preview(content)),
+        },
+        collapse_key: message.channel_id.to_string(),
+    };
+
+    let dead: Vec<String> = stream::iter(recipients)
+        .map(|recipient| {
+            let notification = notification(recipient.show_previews);
+            async move {
+                let Ok(platform) = recipient.platform.parse() else { return None };
+                let outcome = state.push.send(platform, &recipient.token, &notification).await;
+                (outcome == Outcome::InvalidToken).then_some(recipient.token)
+            }
+        })
+        .buffer_unordered(CONCURRENT_SENDS)
+        .filter_map(|dead| async move { dead })
+        .collect()
+        .await;
+    if !dead.is_empty() {
+        sqlx::query("DELETE FROM push_tokens WHERE token = ANY($1)").bind(&dead).execute(&state.db).await?;
+    }
+    Ok(())
+}
+
+/// `content` cut to `MAX_PREVIEW_CHARS`, on one line.
+fn preview(content: &str) -> String {
+    let line = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= MAX_PREVIEW_CHARS {
+        return line;
+    }
+    let mut cut: String = line.chars().take(MAX_PREVIEW_CHARS - 1).collect();
+    cut.push('…');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use axum::{body::Bytes, http::Method, http::Uri, Router};
+    use tokio::sync::mpsc;
+    use uchat_proto::ids::{ChannelId, UserId};
+
+    #[test]
+    fn previews_fit_on_one_short_line() {
+        assert_eq!(preview("hello\n  there"), "hello there");
+        let long = preview(&"x".repeat(500));
+        assert_eq!(long.chars().count(), MAX_PREVIEW_CHARS);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn apns_provider_tokens_are_signed_and_reused() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let apns = Apns {
+            client: reqwest::Client::new(),
+            endpoint: String::new(),
+            topic: "chat.uchat.app".into(),
+            team_id: "TEAM123".into(),
+            key_id: "KEY456".into(),
+            key: EncodingKey::from_ec_pem(key.serialize_pem().as_bytes()).unwrap(),
+            provider_token: Mutex::new(None),
+        };
+        let token = apns.provider_token().unwrap();
+        assert_eq!(apns.provider_token().unwrap(), token);
+
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!((header.alg, header.kid.as_deref()), (Algorithm::ES256, Some("KEY456")));
+        let decoding = jsonwebtoken::DecodingKey::from_ec_pem(key.public_key_pem().as_bytes()).unwrap();
+        let mut validation = jsonwebtoken::Validation::new(Algorithm::ES256);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        let claims = jsonwebtoken::decode::<Value>(&token, &decoding, &validation).unwrap().claims;
+        assert_eq!(claims["iss"], "TEAM123");
+    }
+
+    #[tokio::test]
+    async fn devices_register_and_members_mute_channels() {
+        let Some(state) = test_state().await else { return };
+        let (owner, outsider) = (UserId::new(), UserId::new());
+        let token = format!("fcm:{}", UserId::new().as_uuid().simple());
+
+        let body = json!({"platform": "fcm", "token": token});
+        let (status, registered) = call(&state, Method::POST, "/api/push/register", Some(&owner), Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(registered["prefs"], json!({"enabled": true, "show_previews": true}));
+        let body = json!({"platform": "fcm", "token": token, "prefs": {"show_previews": false}});
+        let (_, registered) = call(&state, Method::POST, "/api/push/register", Some(&outsider), Some(body)).await;
+        assert_eq!(registered["prefs"], json!({"enabled": true, "show_previews": false}));
+        let owners: Vec<UserId> = sqlx::query_scalar("SELECT user_id FROM push_tokens WHERE token = $1")
+            .bind(&token)
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(owners, vec![outsider.clone()]);
+
+        for body in [json!({"platform": "sms", "token": "abc"}), json!({"platform": "apns", "token": "../x"})] {
+            let (status, _) = call(&state, Method::POST, "/api/push/register", Some(&owner), Some(body)).await;
+            assert!(status.is_client_error(), "{}", status);
+        }
+
+        let channel = create(&state, &owner, "push-prefs", "public").await;
+        let uri = format!("/api/channels/{}/notifications", channel);
+        let (_, prefs) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+        assert_eq!(prefs, json!({"muted": false}));
+        let (status, _) = call(&state, Method::PUT, &uri, Some(&owner), Some(json!({"muted": true}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, prefs) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+        assert_eq!(prefs, json!({"muted": true}));
+        let (status, _) = call(&state, Method::PUT, &uri, Some(&outsider), Some(json!({"muted": true}))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    /// Stands in for both FCM and APNs, reporting each request's path,
+    /// headers and body. Tokens starting with `dead` are refused.
+    async fn fake_push_services() -> (String, mpsc::UnboundedReceiver<(String, HeaderMap, Value)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let service = Router::new().fallback(move |uri: Uri, headers: HeaderMap, body: Bytes| async move {
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let dead = uri.path().contains("/dead") || body["message"]["token"].as_str().is_some_and(|t| t.starts_with("dead"));
+            let _ = tx.send((uri.path().to_string(), headers, body));
+            match (dead, uri.path().starts_with("/3/")) {
+                (false, _) => (StatusCode::OK, Json(json!({}))),
+                (true, true) => (StatusCode::GONE, Json(json!({"reason": "Unregistered"}))),
+                (true, false) => (
+                    StatusCode::NOT_FOUND,
+                    Json(json!({"error": {"details": [{"errorCode": "UNREGISTERED"}]}})),
+                ),
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+        (format!("http://{}", addr), rx)
+    }
+
+    fn dispatcher(endpoint: &str) -> PushDispatcher {
+        let far = Instant::now() + Duration::from_secs(3600);
+        PushDispatcher {
+            internal_token: Some("internal-secret".into()),
+            fcm: Some(Fcm {
+                client: reqwest::Client::new(),
+                endpoint: endpoint.into(),
+                account: ServiceAccount {
+                    project_id: "uchat-test".into(),
+                    client_email: String::new(),
+                    private_key: String::new(),
+                    token_uri: String::new(),
+                },
+                key: EncodingKey::from_secret(b"unused"),
+                access_token: tokio::sync::Mutex::new(Some(("fcm-access".into(), far))),
+            }),
+            apns: Some(Apns {
+                client: reqwest::Client::new(),
+                endpoint: endpoint.into(),
+                topic: "chat.uchat.app".into(),
+                team_id: String::new(),
+                key_id: String::new(),
+                key: EncodingKey::from_secret(b"unused"),
+                provider_token: Mutex::new(Some(("apns-provider".into(), Instant::now()))),
+            }),
+            attempts: Mutex::default(),
+        }
+    }
+
+    async fn device(state: &AppState, user: &UserId, platform: &str, token: &str, show_previews: bool) {
+        sqlx::query("INSERT INTO push_tokens (token, user_id, platform, show_previews) VALUES ($1, $2, $3, $4)")
+            .bind(token)
+            .bind(user)
+            .bind(platform)
+            .bind(show_previews)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn pushes_reach_offline_members_and_prune_dead_tokens() {
+        let Some(base) = test_state().await else { return };
+        let (endpoint, mut requests) = fake_push_services().await;
+        let state = Arc::new(AppState {
+            db: base.db.clone(),
+            jwt_secret: base.jwt_secret.clone(),
+            links: base.links.clone(),
+            gateway: None,
+            storage: base.storage.clone(),
+            file_policy: base.file_policy.clone(),
+            edit_window: base.edit_window,
+            rate_limiter: crate::rate_limit::RateLimiter::new(crate::rate_limit::RateLimits::default()),
+            push: dispatcher(&endpoint),
+            scanning: crate::scanning::Scanning::default(),
+        });
+        let users: Vec<UserId> = (0..6).map(|_| UserId::new()).collect();
+        let [sender, offline, online, muted, stale, private] = users.as_slice() else { unreachable!() };
+        let channel = create(&state, sender, "push-fanout", "public").await;
+        for user in &users[1..] {
+            let (status, _) =
+                call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(user), Some(json!({}))).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let (status, _) = call(
+            &state,
+            Method::PUT,
+            &format!("/api/channels/{}/notifications", channel),
+            Some(muted),
+            Some(json!({"muted": true})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let tag = UserId::new().as_uuid().simple().to_string();
+        device(&state, sender, "fcm", &format!("sender-{}", tag), true).await;
+        device(&state, offline, "fcm", &format!("offline-{}", tag), true).await;
+        device(&state, online, "apns", &format!("online-{}", tag), true).await;
+        device(&state, muted, "apns", &format!("muted-{}", tag), true).await;
+        device(&state, stale, "apns", &format!("dead-{}", tag), true).await;
+        device(&state, private, "apns", &format!("private-{}", tag), false).await;
+
+        let channel_id: ChannelId = channel.parse().unwrap();
+        let message = PushMessage {
+            channel_id: channel_id.clone(),
+            sender_id: sender.clone(),
+            sender_name: None,
+            content: Some("deploy\nfinished".into()),
+            delivered_to: vec![online.clone()],
+        };
+        deliver(&state, message.clone()).await.unwrap();
+
+        let mut seen = BTreeMap::new();
+        for _ in 0..3 {
+            let (path, headers, body) = requests.recv().await.unwrap();
+            seen.insert(path, (headers, body));
+        }
+        assert!(requests.try_recv().is_err());
+        let (headers, fcm) = &seen["/v1/projects/uchat-test/messages:send"];
+        assert_eq!(headers["authorization"], "Bearer fcm-access");
+        assert_eq!(fcm["message"]["token"], format!("offline-{}", tag));
+        assert_eq!(fcm["message"]["notification"]["title"], "#push-fanout");
+        assert!(fcm["message"]["notification"]["body"].as_str().unwrap().ends_with(": deploy finished"));
+        assert_eq!(fcm["message"]["android"]["collapse_key"], channel);
+        let (headers, apns) = &seen[&format!("/3/device/private-{}", tag)];
+        assert_eq!(headers["apns-collapse-id"], channel.as_str());
+        assert_eq!(headers["apns-topic"], "chat.uchat.app");
+        assert_eq!(apns["aps"]["alert"]["body"], "New message");
+        assert!(seen.contains_key(&format!("/3/device/dead-{}", tag)));
+
+        let remaining: Vec<String> = sqlx::query_scalar("SELECT token FROM push_tokens WHERE token LIKE '%' || $1")
+            .bind(&tag)
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 5);
+        assert!(!remaining.contains(&format!("dead-{}", tag)));
+        let metrics = state.push.render_metrics();
+        assert!(metrics.contains("uchat_push_deliveries_total{platform=\"apns\",outcome=\"invalid_token\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("uchat_push_deliveries_total{platform=\"fcm\",outcome=\"delivered\"} 1\n"));
+
+        // Encrypted messages never carry their content.
+        deliver(&state, PushMessage { content: None, ..message }).await.unwrap();
+        for _ in 0..2 {
+            let (_, _, body) = requests.recv().await.unwrap();
+            let alert = if body["aps"].is_null() { &body["message"]["notification"] } else { &body["aps"]["alert"] };
+            assert_eq!(alert["body"], "New encrypted message");
+        }
+
+        let push = |token: Option<&str>| {
+            let mut req = axum::http::Request::post("/internal/push").header("Content-Type", "application/json");
+            if let Some(token) = token {
+                req = req.header("x-internal-token", token);
+            }
+            req.body(axum::body::Body::from(serde_json::to_vec(&json!({"channel_id": channel, "sender_id": sender})).unwrap()))
+                .unwrap()
+        };
+        use tower::ServiceExt;
+        let resp = crate::app(state.clone()).oneshot(push(Some("wrong"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = crate::app(state.clone()).oneshot(push(Some("internal-secret"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::jwt::verify_claims;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// Routes that share a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Read,
+    Write,
+    Upload,
+    /// Posts to an incoming webhook, limited per hook.
+    Hook,
+}
+
+/// Routes that also count against the global cap on concurrent
+/// expensive requests. Data exports count too, but from their background
+/// job rather than the request that queues them; see `expensive_job`.
+const EXPENSIVE_ROUTES: &[&str] = &["/api/search/messages", "/api/exports/:id/download"];
+const UPLOAD_ROUTES: &[&str] = &["/api/files", "/api/users/me/avatar"];
+const HOOK_ROUTE: &str = "/api/hooks/:hook_id";
+/// Buckets kept before idle ones are swept out.
+const MAX_BUCKETS: usize = 10_000;
+/// Independently locked parts of the bucket map, so a sweep only stalls
+/// the callers hashed to the same shard.
+const SHARDS: usize = 16;
+/// Least time between two sweeps of one shard, so a shard full of busy
+/// buckets isn't rescanned on every request.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+fn route_group(method: &Method, route: &str) -> RouteGroup {
+    if route == HOOK_ROUTE {
+        RouteGroup::Hook
+    } else if method == Method::GET || method == Method::HEAD {
+        RouteGroup::Read
+    } else if UPLOAD_ROUTES.contains(&route) {
+        RouteGroup::Upload
+    } else {
+        RouteGroup::Write
+    }
+}
+
+/// Requests per minute for each route group, each allowed as a burst,
+/// and the cap on concurrent expensive requests. Zero turns a limit off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    pub reads_per_minute: u32,
+    pub writes_per_minute: u32,
+    pub uploads_per_minute: u32,
+    pub hook_posts_per_minute: u32,
+    pub max_expensive: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            reads_per_minute: 600,
+            writes_per_minute: 120,
+            uploads_per_minute: 20,
+            hook_posts_per_minute: 60,
+            max_expensive: 8,
+        }
+    }
+}
+
+impl RateLimits {
+    /// `RATE_LIMIT_READS_PER_MIN`, `RATE_LIMIT_WRITES_PER_MIN`,
+    /// `RATE_LIMIT_UPLOADS_PER_MIN`, `RATE_LIMIT_HOOK_POSTS_PER_MIN` and
+    /// `RATE_LIMIT_MAX_EXPENSIVE`, each falling back to the default.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        }
+        let defaults = Self::default();
+        Self {
+            reads_per_minute: var("RATE_LIMIT_READS_PER_MIN", defaults.reads_per_minute),
+            writes_per_minute: var("RATE_LIMIT_WRITES_PER_MIN", defaults.writes_per_minute),
+            uploads_per_minute: var("RATE_LIMIT_UPLOADS_PER_MIN", defaults.uploads_per_minute),
+            hook_posts_per_minute: var("RATE_LIMIT_HOOK_POSTS_PER_MIN", defaults.hook_posts_per_minute),
+            max_expensive: var("RATE_LIMIT_MAX_EXPENSIVE", defaults.max_expensive),
+        }
+    }
+
+    fn per_minute(&self, group: RouteGroup) -> u32 {
+        match group {
+            RouteGroup::Read => self.reads_per_minute,
+            RouteGroup::Write => self.writes_per_minute,
+            RouteGroup::Upload => self.uploads_per_minute,
+            RouteGroup::Hook => self.hook_posts_per_minute,
+        }
+    }
+}
+
+/// Who a request is charged to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Caller {
+    User(UserId),
+    /// Posts to an incoming webhook, whoever sends them.
+    Hook(String),
+    /// Requests without a valid token; `None` when the peer address is
+    /// unknown, as in tests.
+    Ip(Option<IpAddr>),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refills for the time since the last call, then takes a token if
+    /// there is one. Otherwise returns how long until there will be.
+    fn take(&mut self, capacity: f64, now: Instant) -> Result<(), Duration> {
+        let per_sec = capacity / 60.0;
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * per_sec).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        }
+    }
+}
+
+/// One part of the bucket map and when it was last swept.
+struct Shard {
+    buckets: HashMap<(Caller, RouteGroup), Bucket>,
+    swept: Option<Instant>,
+}
+
+/// Token buckets per caller and route group, plus rejection counts per
+/// route for the metrics exporter.
+pub struct RateLimiter {
+    limits: RateLimits,
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+    expensive: Arc<Semaphore>,
+    rejections: Mutex<HashMap<(String, &'static str), u64>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let expensive = Arc::new(Semaphore::new(limits.max_expensive.max(1)));
+        let shards = (0..SHARDS).map(|_| Mutex::new(Shard { buckets: HashMap::new(), swept: None })).collect();
+        Self { limits, shards, hasher: RandomState::new(), expensive, rejections: Mutex::default() }
+    }
+
+    /// One of the permits expensive routes share, for background work
+    /// such as a data export; waits for one to free up rather than
+    /// failing. `None` when the cap is off.
+    pub async fn expensive_job(&self) -> Option<OwnedSemaphorePermit> {
+        if self.limits.max_expensive == 0 {
+            return None;
+        }
+        self.expensive.clone().acquire_owned().await.ok()
+    }
+
+    fn check(&self, caller: Caller, group: RouteGroup) -> Result<(), Duration> {
+        let per_minute = self.limits.per_minute(group);
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = per_minute as f64;
+        let now = Instant::now();
+
+        let key = (caller, group);
+        let mut shard = self.shards[self.hasher.hash_one(&key) as usize % SHARDS].lock().unwrap();
+        if shard.buckets.len() >= MAX_BUCKETS / SHARDS && shard.swept.is_none_or(|at| now - at >= SWEEP_INTERVAL) {
+            // Anything idle long enough to have refilled is the same as
+            // no bucket at all.
+            shard.buckets.retain(|_, b| now.duration_since(b.updated) < Duration::from_secs(60));
+            shard.swept = Some(now);
+        }
+        shard
+            .buckets
+            .entry(key)
+            .or_insert(Bucket { tokens: capacity, updated: now })
+            .take(capacity, now)
+    }
+
+    fn rejected(&self, route: &str, reason: &'static str) {
+        *self.rejections.lock().unwrap().entry((route.to_string(), reason)).or_default() += 1;
+    }
+
+    /// Rejection counts in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP uchat_rate_limited_total Requests rejected by the rate limiter.\n");
+        out.push_str("# TYPE uchat_rate_limited_total counter\n");
+        let rejections = self.rejections.lock().unwrap();
+        let mut rows: Vec<_> = rejections.iter().collect();
+        rows.sort();
+        for ((route, reason), count) in rows {
+            let _ = writeln!(out, "uchat_rate_limited_total{{route=\"{}\",reason=\"{}\"}} {}", route, reason, count);
+        }
+        out
+    }
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response =
+        AppError::new(StatusCode::TOO_MANY_REQUESTS, ErrorCode::RateLimited, "too many requests").into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+    response
+}
+
+fn caller(state: &AppState, req: &Request, route: &str) -> Caller {
+    if route == HOOK_ROUTE {
+        let hook_id = req.uri().path().rsplit('/').next().unwrap_or_default();
+        return Caller::Hook(hook_id.to_string());
+    }
+    let user = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .and_then(|token| verify_claims(&state.jwt_secret, token))
+        .and_then(|claims| claims.sub.parse().ok());
+    match user {
+        Some(user_id) => Caller::User(user_id),
+        None => Caller::Ip(req.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip())),
+    }
+}
+
+/// Charges each request to its caller's bucket for the route's group,
+/// answering 429 with `Retry-After` once it is empty. Expensive routes
+/// also need one of the shared permits, held until the handler returns;
+/// when none is free they get a 429 too.
+pub async fn limit(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let route = req.extensions().get::<MatchedPath>().map_or("unmatched", |p| p.as_str()).to_string();
+    let limiter = &state.rate_limiter;
+
+    if let Err(retry_after) = limiter.check(caller(&state, &req, &route), route_group(req.method(), &route)) {
+        limiter.rejected(&route, "rate");
+        return too_many_requests(retry_after);
+    }
+
+    if limiter.limits.max_expensive == 0 || !EXPENSIVE_ROUTES.contains(&route.as_str()) {
+        return next.run(req).await;
+    }
+    let Ok(_permit) = limiter.expensive.clone().try_acquire_owned() else {
+        limiter.rejected(&route, "concurrency");
+        return too_many_requests(Duration::from_secs(1));
+    };
+    next.run(req).await
+}
+
+/// Starts the shared metrics exporter on `bind_addr`, with the rate
+/// limiter's rejection counts and push delivery counts alongside the
+/// standard families.
+pub async fn export_metrics(state: Arc<AppState>, bind_addr: &str) -> std::io::Result<SocketAddr> {
+    let addr = uchat_metrics::init("channels-api", bind_addr).await?;
+    uchat_metrics::add_collector(move |out| {
+        out.push_str(&state.rate_limiter.render_metrics());
+        out.push_str(&state.push.render_metrics());
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::call;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    async fn limited(limits: RateLimits) -> Option<Arc<AppState>> {
+        let base = test_state().await?;
+        Some(Arc::new(AppState {
+            db: base.db.clone(),
+            jwt_secret: base.jwt_secret.clone(),
+            links: base.links.clone(),
+            gateway: None,
+            storage: base.storage.clone(),
+            file_policy: base.file_policy.clone(),
+            edit_window: base.edit_window,
+            rate_limiter: RateLimiter::new(limits),
+            push: crate::push::PushDispatcher::default(),
+            scanning: crate::scanning::Scanning::default(),
+        }))
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let start = Instant::now();
+        let mut bucket = Bucket { tokens: 2.0, updated: start };
+        assert!(bucket.take(2.0, start).is_ok());
+        assert!(bucket.take(2.0, start).is_ok());
+        assert_eq!(bucket.take(2.0, start), Err(Duration::from_secs(30)));
+        assert!(bucket.take(2.0, start + Duration::from_secs(30)).is_ok());
+    }
+
+    #[test]
+    fn sweeps_idle_buckets_a_shard_at_a_time() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let long_ago = Instant::now() - Duration::from_secs(120);
+        for shard in &limiter.shards {
+            let mut shard = shard.lock().unwrap();
+            for _ in 0..MAX_BUCKETS / SHARDS {
+                let bucket = Bucket { tokens: 0.0, updated: long_ago };
+                shard.buckets.insert((Caller::User(UserId::new()), RouteGroup::Read), bucket);
+            }
+        }
+
+        assert!(limiter.check(Caller::User(UserId::new()), RouteGroup::Read).is_ok());
+        let sizes: Vec<usize> = limiter.shards.iter().map(|s| s.lock().unwrap().buckets.len()).collect();
+        assert_eq!(sizes.iter().filter(|n| **n == 1).count(), 1, "{:?}", sizes);
+        assert_eq!(sizes.iter().filter(|n| **n == MAX_BUCKETS / SHARDS).count(), SHARDS - 1);
+    }
+
+    #[test]
+    fn routes_fall_into_groups() {
+        assert_eq!(route_group(&Method::GET, "/api/files/:id"), RouteGroup::Read);
+        assert_eq!(route_group(&Method::POST, "/api/files"), RouteGroup::Upload);
+        assert_eq!(route_group(&Method::PUT, "/api/users/me/avatar"), RouteGroup::Upload);
+        assert_eq!(route_group(&Method::DELETE, "/api/files/:id"), RouteGroup::Write);
+        assert_eq!(route_group(&Method::POST, "/api/hooks/:hook_id"), RouteGroup::Hook);
+    }
+
+    #[tokio::test]
+    async fn limits_each_user_per_route_group() {
+        let limits = RateLimits { reads_per_minute: 3, writes_per_minute: 1, ..RateLimits::default() };
+        let Some(state) = limited(limits).await else { return };
+        let (alice, bob) = (UserId::new(), UserId::new());
+
+        for _ in 0..3 {
+            let (status, _) = call(&state, Method::GET, "/api/unread", Some(&alice), None).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, body) = call(&state, Method::GET, "/api/unread", Some(&alice), None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "rate_limited");
+
+        // Other users and other route groups have their own buckets.
+        let (status, _) = call(&state, Method::GET, "/api/unread", Some(&bob), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::POST, "/api/channels", Some(&alice), Some(serde_json::json!({}))).await;
+        assert_ne!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = call(&state, Method::POST, "/api/channels", Some(&alice), Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let req = Request::get("/api/unread")
+            .header("Authorization", format!("Bearer {}", uchat_proto::jwt::create_token(&state.jwt_secret, alice.as_str())))
+            .body(Body::empty())
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = resp.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+        assert!((1..=20).contains(&retry_after), "{}", retry_after);
+
+        let metrics = state.rate_limiter.render_metrics();
+        assert!(metrics.contains("uchat_rate_limited_total{route=\"/api/unread\",reason=\"rate\"} 2\n"), "{}", metrics);
+        assert!(metrics.contains("uchat_rate_limited_total{route=\"/api/channels\",reason=\"rate\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn hook_posts_are_limited_per_hook() {
+        let limits = RateLimits { hook_posts_per_minute: 1, ..RateLimits::default() };
+        let Some(state) = limited(limits).await else { return };
+        let (first, second) = (format!("/api/hooks/{}", UserId::new()), format!("/api/hooks/{}", UserId::new()));
+
+        let (status, _) = call(&state, Method::POST, &first, None, Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&state, Method::POST, &first, None, Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = call(&state, Method::POST, &second, None, Some(serde_json::json!({}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_requests_share_the_address_bucket() {
+        let limits = RateLimits { reads_per_minute: 2, ..RateLimits::default() };
+        let Some(state) = limited(limits).await else { return };
+
+        for _ in 0..2 {
+            let (status, _) = call(&state, Method::GET, "/api/unread", None, None).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) = call(&state, Method::GET, "/api/users", None, None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // A token moves the caller to their own bucket.
+        let (status, _) = call(&state, Method::GET, "/api/unread", Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn caps_concurrent_expensive_requests() {
+        let limits = RateLimits { max_expensive: 1, ..RateLimits::default() };
+        let Some(state) = limited(limits).await else { return };
+
+        let held = state.rate_limiter.expensive.clone().try_acquire_owned().unwrap();
+        let (status, body) = call(&state, Method::GET, "/api/search/messages?q=x", Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "rate_limited");
+        // Cheap routes are unaffected.
+        let (status, _) = call(&state, Method::GET, "/api/unread", Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        drop(held);
+        let (status, _) = call(&state, Method::GET, "/api/search/messages?q=x", Some(&UserId::new()), None).await;
+        assert_ne!(status, StatusCode::TOO_MANY_REQUESTS);
+        let metrics = state.rate_limiter.render_metrics();
+        assert!(metrics.contains("route=\"/api/search/messages\",reason=\"concurrency\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn export_jobs_wait_for_an_expensive_permit() {
+        let limits = RateLimits { max_expensive: 1, ..RateLimits::default() };
+        let Some(state) = limited(limits).await else { return };
+        let user = UserId::new();
+
+        let held = state.rate_limiter.expensive.clone().try_acquire_owned().unwrap();
+        let (status, export) = call(&state, Method::POST, "/api/users/me/export", Some(&user), None).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let uri = format!("/api/users/me/export/{}", export["id"].as_str().unwrap());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let (_, export) = call(&state, Method::GET, &uri, Some(&user), None).await;
+        assert_eq!(export["status"], "pending");
+
+        drop(held);
+        for _ in 0..200 {
+            let (_, export) = call(&state, Method::GET, &uri, Some(&user), None).await;
+            if export["status"] == "ready" {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!("export never ran");
+    }
+
+    #[tokio::test]
+    async fn exports_shared_and_rate_limit_families() {
+        let limits = RateLimits { reads_per_minute: 1, ..RateLimits::default() };
+        let Some(state) = limited(limits).await else { return };
+        let addr = export_metrics(state.clone(), "127.0.0.1:0").await.unwrap();
+
+        let user = UserId::new();
+        let (status, _) = call(&state, Method::GET, "/api/search/messages?q=x", Some(&user), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&state, Method::GET, "/api/unread", Some(&user), None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let body = uchat_metrics::scrape(addr).await.unwrap();
+        for family in ["uchat_db_query_duration_seconds", "uchat_rate_limited_total", "process_resident_memory_bytes"] {
+            assert!(body.contains(&format!("# TYPE {} ", family)), "{} missing", family);
+        }
+        assert!(body.contains("query=\"search_messages\""), "{}", body);
+    }
+}
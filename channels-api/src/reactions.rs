@@ -0,0 +1,300 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+use uchat_proto::ids::{ChannelId, MessageId, UserId};
+use uchat_proto::messages::{Message, ReactionChanged, ReactionSummary};
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, writable_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+/// Distinct emoji one user may put on a single message.
+pub const MAX_REACTIONS_PER_USER: i64 = 20;
+
+/// Shortcodes accepted alongside literal emoji.
+const SHORTCODES: &[&str] = &[
+    ":+1:", ":-1:", ":thumbsup:", ":thumbsdown:", ":heart:", ":joy:", ":smile:", ":tada:",
+    ":eyes:", ":fire:", ":rocket:", ":clap:", ":pray:", ":100:", ":thinking:", ":cry:",
+    ":white_check_mark:", ":x:",
+];
+
+/// A reaction is a known shortcode or exactly one non-ASCII, non-letter
+/// grapheme, so arbitrary text can't be stored as a "reaction".
+fn valid_emoji(emoji: &str) -> bool {
+    if SHORTCODES.contains(&emoji) {
+        return true;
+    }
+    !emoji.is_ascii()
+        && emoji.graphemes(true).count() == 1
+        && !emoji.chars().next().is_some_and(char::is_alphanumeric)
+        && !emoji.chars().any(|c| c.is_control() || c.is_whitespace())
+}
+
+struct Target {
+    channel_id: ChannelId,
+    message_id: MessageId,
+    emoji: String,
+}
+
+/// Parses the path and checks the caller belongs to the channel, which
+/// must not be archived.
+async fn target(state: &AppState, user: &AuthUser, id: &str, message_id: &str, emoji: String) -> Result<Target, AppError> {
+    let channel_id = parse_channel_id(id)?;
+    let message_id: MessageId = message_id.parse().map_err(|_| AppError::not_found())?;
+    if !valid_emoji(&emoji) {
+        return Err(AppError::invalid("emoji must be a single emoji or a known shortcode"));
+    }
+
+    let (_, role) = writable_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role.is_none() {
+        return Err(AppError::forbidden());
+    }
+    Ok(Target { channel_id, message_id, emoji })
+}
+
+async fn notify(state: &AppState, target: Target, user_id: UserId, added: bool) {
+    if let Some(gateway) = &state.gateway {
+        let change = ReactionChanged {
+            channel_id: target.channel_id,
+            message_id: target.message_id,
+            user_id,
+            emoji: target.emoji,
+            added,
+        };
+        gateway.reaction_changed(&change).await;
+    }
+}
+
+/// PUT /api/channels/{id}/messages/{message_id}/reactions/{emoji}
+///
+/// Adds the caller's reaction: 201 when new, 200 when it was already
+/// there.
+pub async fn add_reaction(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, message_id, emoji)): Path<(String, String, String)>,
+) -> Result<StatusCode, AppError> {
+    let target = target(&state, &user, &id, &message_id, emoji).await?;
+
+    // Locking the message serializes reactions on it, which keeps the
+    // per-user cap exact and stops reactions landing on a message as it
+    // is deleted.
+    let mut tx = state.db.begin().await?;
+    let deleted: Option<bool> = sqlx::query_scalar(
+        "SELECT deleted_at IS NOT NULL FROM messages WHERE id = $1 AND channel_id = $2 FOR UPDATE",
+    )
+    .bind(&target.message_id)
+    .bind(&target.channel_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    match deleted {
+        None => return Err(AppError::not_found()),
+        Some(true) => return Err(AppError::conflict("message is deleted")),
+        Some(false) => {}
+    }
+
+    let others: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji <> $3",
+    )
+    .bind(&target.message_id)
+    .bind(&user.user_id)
+    .bind(&target.emoji)
+    .fetch_one(&mut *tx)
+    .await?;
+    if others >= MAX_REACTIONS_PER_USER {
+        return Err(AppError::conflict("too many reactions on this message"));
+    }
+
+    let added = sqlx::query(
+        "INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3)
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(&target.message_id)
+    .bind(&user.user_id)
+    .bind(&target.emoji)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        == 1;
+    tx.commit().await?;
+
+    if !added {
+        return Ok(StatusCode::OK);
+    }
+    notify(&state, target, user.user_id, true).await;
+    Ok(StatusCode::CREATED)
+}
+
+/// DELETE /api/channels/{id}/messages/{message_id}/reactions/{emoji}
+///
+/// Removes the caller's reaction; removing one that isn't there is a
+/// no-op.
+pub async fn remove_reaction(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, message_id, emoji)): Path<(String, String, String)>,
+) -> Result<StatusCode, AppError> {
+    let target = target(&state, &user, &id, &message_id, emoji).await?;
+
+    let removed = sqlx::query(
+        "DELETE FROM message_reactions r USING messages m
+         WHERE r.message_id = $1 AND r.user_id = $2 AND r.emoji = $3
+           AND m.id = r.message_id AND m.channel_id = $4",
+    )
+    .bind(&target.message_id)
+    .bind(&user.user_id)
+    .bind(&target.emoji)
+    .bind(&target.channel_id)
+    .execute(&state.db)
+    .await?
+    .rows_affected()
+        == 1;
+
+    if removed {
+        notify(&state, target, user.user_id, false).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Fills in `reactions` on a page of messages for `viewer`, in one query.
+pub async fn attach(db: &sqlx::PgPool, viewer: &UserId, messages: &mut [Message]) -> Result<(), sqlx::Error> {
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<MessageId> = messages.iter().map(|m| m.id.clone()).collect();
+
+    let rows: Vec<(MessageId, String, i64, bool)> = sqlx::query_as(
+        "SELECT message_id, emoji, COUNT(*), bool_or(user_id = $2)
+         FROM message_reactions WHERE message_id = ANY($1)
+         GROUP BY message_id, emoji",
+    )
+    .bind(&ids)
+    .bind(viewer)
+    .fetch_all(db)
+    .await?;
+
+    for (message_id, emoji, count, me) in rows {
+        if let Some(message) = messages.iter_mut().find(|m| m.id == message_id) {
+            message.reactions.insert(emoji, ReactionSummary { count, me });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::messages::tests::insert;
+    use crate::test_state;
+    use axum::http::Method;
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn validates_emoji() {
+        for ok in ["👍", "❤️", "👩‍👩‍👧", "🇳🇿", ":tada:", ":+1:"] {
+            assert!(valid_emoji(ok), "{}", ok);
+        }
+        for bad in ["", "a", "ok", "👍👍", ":not_a_code:", "ü", "中", "\u{3000}"] {
+            assert!(!valid_emoji(bad), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn reactions_aggregate_in_history() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "reactions", "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&member), Some(json!({}))).await;
+        let msg = insert(&state.db, &channel, &owner, "ship it", Utc::now()).await;
+
+        let uri = |emoji: &str| format!("/api/channels/{}/messages/{}/reactions/{}", channel, msg, emoji);
+        let (status, _) = call(&state, Method::PUT, &uri("%F0%9F%9A%80"), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = call(&state, Method::PUT, &uri("%F0%9F%9A%80"), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        call(&state, Method::PUT, &uri("%F0%9F%9A%80"), Some(&member), None).await;
+        call(&state, Method::PUT, &uri(":tada:"), Some(&member), None).await;
+
+        let history = format!("/api/channels/{}/messages", channel);
+        let (_, page) = call(&state, Method::GET, &history, Some(&owner), None).await;
+        let reactions = &page["items"][0]["reactions"];
+        assert_eq!(reactions["🚀"], json!({ "count": 2, "me": true }));
+        assert_eq!(reactions[":tada:"], json!({ "count": 1, "me": false }));
+
+        let (status, _) = call(&state, Method::DELETE, &uri(":tada:"), Some(&member), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&state, Method::DELETE, &uri(":tada:"), Some(&member), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, page) = call(&state, Method::GET, &history, Some(&member), None).await;
+        assert_eq!(page["items"][0]["reactions"], json!({ "🚀": { "count": 2, "me": true } }));
+
+        let (status, _) = call(&state, Method::PUT, &uri("lol"), Some(&owner), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&state, Method::PUT, &uri(":tada:"), Some(&UserId::new()), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn caps_reactions_per_user() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "reaction-cap", "public").await;
+        let msg = insert(&state.db, &channel, &owner, "react to me", Utc::now()).await;
+
+        // Seed the cap directly; only the API enforces it.
+        for i in 0..MAX_REACTIONS_PER_USER {
+            sqlx::query("INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3)")
+                .bind(&msg)
+                .bind(&owner)
+                .bind(format!("seed-{}", i))
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+
+        let uri = format!("/api/channels/{}/messages/{}/reactions/:fire:", channel, msg);
+        let (status, body) = call(&state, Method::PUT, &uri, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+    }
+
+    #[tokio::test]
+    async fn concurrent_add_and_remove_stay_consistent() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "reaction-race", "public").await;
+        let msg = insert(&state.db, &channel, &owner, "race", Utc::now()).await;
+        let uri = format!("/api/channels/{}/messages/{}/reactions/:eyes:", channel, msg);
+
+        let requests = (0..20).map(|i| {
+            let method = if i % 2 == 0 { Method::PUT } else { Method::DELETE };
+            let (state, uri, owner) = (state.clone(), uri.clone(), owner.clone());
+            tokio::spawn(async move { call(&state, method, &uri, Some(&owner), None).await.0 })
+        });
+        for request in requests {
+            let status = request.await.unwrap();
+            assert!(status.is_success(), "{}", status);
+        }
+
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_reactions WHERE message_id = $1")
+            .bind(&msg)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert!(rows <= 1);
+
+        let (status, _) = call(&state, Method::PUT, &uri, Some(&owner), None).await;
+        assert!(status.is_success());
+        let (_, page) = call(&state, Method::GET, &format!("/api/channels/{}/messages", channel), Some(&owner), None).await;
+        assert_eq!(page["items"][0]["reactions"][":eyes:"], json!({ "count": 1, "me": true }));
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+
+use uchat_proto::ids::{ChannelId, MessageId};
+use uchat_proto::messages::{MarkRead, ReadMarker, UnreadCount};
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, visible_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+#[derive(sqlx::FromRow)]
+struct MarkerRow {
+    channel_id: ChannelId,
+    last_read_message_id: MessageId,
+    updated_at: DateTime<Utc>,
+}
+
+/// PUT /api/channels/{id}/read
+///
+/// Moves the caller's read marker to `message_id`. Markers only move
+/// forward in `(created_at, id)` order; re-marking the current message is
+/// a no-op and moving backwards is a conflict.
+pub async fn mark_read(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<MarkRead>,
+) -> Result<Json<ReadMarker>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (_, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role.is_none() {
+        return Err(AppError::forbidden());
+    }
+
+    let created_at: Option<DateTime<Utc>> =
+        sqlx::query_scalar("SELECT created_at FROM messages WHERE id = $1 AND channel_id = $2")
+            .bind(&body.message_id)
+            .bind(&channel_id)
+            .fetch_optional(&state.db)
+            .await?;
+    let created_at = created_at.ok_or_else(AppError::not_found)?;
+
+    let row: Option<MarkerRow> = sqlx::query_as(
+        "INSERT INTO channel_read_markers (user_id, channel_id, last_read_message_id, last_read_at)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (user_id, channel_id) DO UPDATE
+         SET last_read_message_id = EXCLUDED.last_read_message_id,
+             last_read_at = EXCLUDED.last_read_at,
+             updated_at = now()
+         WHERE (channel_read_markers.last_read_at, channel_read_markers.last_read_message_id)
+            <= (EXCLUDED.last_read_at, EXCLUDED.last_read_message_id)
+         RETURNING channel_id, last_read_message_id, updated_at",
+    )
+    .bind(&user.user_id)
+    .bind(&channel_id)
+    .bind(&body.message_id)
+    .bind(created_at)
+    .fetch_optional(&state.db)
+    .await?;
+
+    let row = row.ok_or_else(|| AppError::conflict("read marker cannot move backwards"))?;
+    Ok(Json(ReadMarker {
+        channel_id: row.channel_id,
+        last_read_message_id: row.last_read_message_id,
+        updated_at: row.updated_at,
+    }))
+}
+
+#[derive(sqlx::FromRow)]
+struct UnreadRow {
+    channel_id: ChannelId,
+    unread: i64,
+    last_read_message_id: Option<MessageId>,
+}
+
+/// GET /api/unread
+///
+/// Unread counts for every channel the caller belongs to, in one query.
+/// Without a marker the whole channel counts as unread. The caller's own
+/// messages never count.
+pub async fn list_unread(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> Result<Json<Vec<UnreadCount>>, AppError> {
+    let rows: Vec<UnreadRow> = sqlx::query_as(
+        "SELECT cm.channel_id, COUNT(m.id) AS unread, r.last_read_message_id
+         FROM channel_members cm
+         LEFT JOIN channel_read_markers r
+                ON r.user_id = cm.user_id AND r.channel_id = cm.channel_id
+         LEFT JOIN messages m
+                ON m.channel_id = cm.channel_id
+               AND m.deleted_at IS NULL
+               AND m.sender_id <> cm.user_id
+               AND (r.last_read_at IS NULL
+                    OR (m.created_at, m.id) > (r.last_read_at, r.last_read_message_id))
+         WHERE cm.user_id = $1
+         GROUP BY cm.channel_id, r.last_read_message_id
+         ORDER BY cm.channel_id",
+    )
+    .bind(&user.user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| UnreadCount {
+                channel_id: row.channel_id,
+                unread: row.unread,
+                last_read_message_id: row.last_read_message_id,
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::messages::tests::insert;
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use uchat_proto::ids::UserId;
+
+    fn unread_for(list: &Value, channel: &str) -> i64 {
+        list.as_array()
+            .unwrap()
+            .iter()
+            .find(|c| c["channel_id"] == channel)
+            .map(|c| c["unread"].as_i64().unwrap())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn markers_drive_unread_counts() {
+        let Some(state) = test_state().await else { return };
+        let (owner, author) = (UserId::new(), UserId::new());
+        let busy = create(&state, &owner, "unread-busy", "public").await;
+        let quiet = create(&state, &owner, "unread-quiet", "public").await;
+
+        let base = Utc::now();
+        let mut ids = Vec::new();
+        for i in 0..4 {
+            ids.push(insert(&state.db, &busy, &author, &format!("m{}", i), base + chrono::Duration::seconds(i)).await);
+        }
+        insert(&state.db, &quiet, &owner, "talking to myself", base).await;
+
+        let (status, list) = call(&state, Method::GET, "/api/unread", Some(&owner), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list.as_array().unwrap().len(), 2);
+        assert_eq!(unread_for(&list, &busy), 4);
+        assert_eq!(unread_for(&list, &quiet), 0);
+
+        let uri = format!("/api/channels/{}/read", busy);
+        let (status, marker) =
+            call(&state, Method::PUT, &uri, Some(&owner), Some(json!({ "message_id": ids[2] }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(marker["last_read_message_id"], ids[2].as_str());
+
+        let (_, list) = call(&state, Method::GET, "/api/unread", Some(&owner), None).await;
+        assert_eq!(unread_for(&list, &busy), 1);
+
+        let (status, _) = call(&state, Method::PUT, &uri, Some(&owner), Some(json!({ "message_id": ids[2] }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) =
+            call(&state, Method::PUT, &uri, Some(&owner), Some(json!({ "message_id": ids[0] }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "conflict");
+    }
+
+    #[tokio::test]
+    async fn rejects_foreign_messages_and_non_members() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "unread-a", "public").await;
+        let other = create(&state, &owner, "unread-b", "public").await;
+        let elsewhere = insert(&state.db, &other, &owner, "elsewhere", Utc::now()).await;
+        let here = insert(&state.db, &channel, &owner, "here", Utc::now()).await;
+
+        let uri = format!("/api/channels/{}/read", channel);
+        let (status, _) =
+            call(&state, Method::PUT, &uri, Some(&owner), Some(json!({ "message_id": elsewhere }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) =
+            call(&state, Method::PUT, &uri, Some(&UserId::new()), Some(json!({ "message_id": here }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,394 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+
+use uchat_proto::channels::{MemberRole, RetentionPreview};
+use uchat_proto::ids::{ChannelId, MessageId};
+use uchat_proto::messages::MessagesExpired;
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, visible_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_BATCH_SIZE: i64 = 1000;
+
+/// Rows deleted per statement, from `RETENTION_BATCH_SIZE`. Small batches
+/// keep each delete's locks short on busy tables.
+pub fn batch_size_from_env() -> i64 {
+    std::env::var("RETENTION_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_BATCH_SIZE)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PurgeStats {
+    pub messages: u64,
+    pub files: u64,
+    /// Delete statements issued, messages and files together.
+    pub batches: u64,
+}
+
+impl PurgeStats {
+    fn add(&mut self, other: PurgeStats) {
+        self.messages += other.messages;
+        self.files += other.files;
+        self.batches += other.batches;
+    }
+}
+
+fn cutoff(retention_days: i32) -> DateTime<Utc> {
+    Utc::now() - chrono::Duration::days(retention_days.into())
+}
+
+/// Runs the purge hourly for as long as the service is up.
+pub fn spawn(state: Arc<AppState>, batch_size: i64) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match run_once(&state, batch_size).await {
+                Ok(stats) if stats.messages + stats.files > 0 => tracing::info!(
+                    messages = stats.messages,
+                    files = stats.files,
+                    "retention purge finished"
+                ),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("retention purge failed: {}", e),
+            }
+            match delete_queued_objects(&state, batch_size).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!(objects = n, "deleted queued storage objects"),
+                Err(e) => tracing::warn!("deleting queued storage objects failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Deletes the objects queued in `storage_deletions` by services that
+/// can't reach file storage, with their thumbnails, `batch_size` at a
+/// time, returning how many went. One whose delete fails stays queued
+/// for the next run.
+pub async fn delete_queued_objects(state: &AppState, batch_size: i64) -> Result<u64, sqlx::Error> {
+    let mut deleted = 0;
+    let mut after = String::new();
+    loop {
+        let paths: Vec<String> =
+            sqlx::query_scalar("SELECT path FROM storage_deletions WHERE path > $1 ORDER BY path LIMIT $2")
+                .bind(&after)
+                .bind(batch_size)
+                .fetch_all(&state.db)
+                .await?;
+
+        for path in &paths {
+            if let Err(e) = state.storage.delete(path).await {
+                tracing::warn!("deleting {} from storage failed: {}", path, e);
+                continue;
+            }
+            crate::thumbnails::delete(state, path).await;
+            sqlx::query("DELETE FROM storage_deletions WHERE path = $1").bind(path).execute(&state.db).await?;
+            deleted += 1;
+        }
+        match paths.last() {
+            Some(last) if paths.len() as i64 == batch_size => after = last.clone(),
+            _ => return Ok(deleted),
+        }
+    }
+}
+
+/// Purges every channel that has a retention period. Channels without
+/// one (the default) are never touched.
+pub async fn run_once(state: &AppState, batch_size: i64) -> Result<PurgeStats, sqlx::Error> {
+    let channels: Vec<(ChannelId, i32)> =
+        sqlx::query_as("SELECT id, retention_days FROM channels WHERE retention_days IS NOT NULL")
+            .fetch_all(&state.db)
+            .await?;
+
+    let mut total = PurgeStats::default();
+    for (channel_id, days) in channels {
+        total.add(purge_channel(state, &channel_id, cutoff(days), batch_size).await?);
+    }
+    Ok(total)
+}
+
+/// Hard-deletes the channel's messages (with their edit history and
+/// reactions) and files created before `cutoff`, at most `batch_size`
+/// rows per statement. Each message batch is announced to the channel's
+/// live subscribers.
+pub async fn purge_channel(
+    state: &AppState,
+    channel_id: &ChannelId,
+    cutoff: DateTime<Utc>,
+    batch_size: i64,
+) -> Result<PurgeStats, sqlx::Error> {
+    let mut stats = PurgeStats::default();
+
+    loop {
+        // A batch's messages go together with their edits and reactions.
+        let mut tx = state.db.begin().await?;
+        let ids: Vec<MessageId> = sqlx::query_scalar(
+            "DELETE FROM messages WHERE id IN (
+                 SELECT id FROM messages WHERE channel_id = $1 AND created_at < $2 LIMIT $3
+             )
+             RETURNING id",
+        )
+        .bind(channel_id)
+        .bind(cutoff)
+        .bind(batch_size)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM message_edits WHERE message_id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM message_reactions WHERE message_id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        stats.batches += 1;
+        stats.messages += ids.len() as u64;
+        let done = (ids.len() as i64) < batch_size;
+
+        if let (Some(gateway), false) = (&state.gateway, ids.is_empty()) {
+            let expired = MessagesExpired { channel_id: channel_id.clone(), message_ids: ids };
+            gateway.messages_expired(&expired).await;
+        }
+        if done {
+            break;
+        }
+    }
+
+    loop {
+        let paths: Vec<String> = sqlx::query_scalar(
+            "DELETE FROM file_uploads WHERE id IN (
+                 SELECT id FROM file_uploads WHERE channel_id = $1 AND created_at < $2 LIMIT $3
+             )
+             RETURNING storage_path",
+        )
+        .bind(channel_id)
+        .bind(cutoff)
+        .bind(batch_size)
+        .fetch_all(&state.db)
+        .await?;
+
+        stats.batches += 1;
+        stats.files += paths.len() as u64;
+        for path in &paths {
+            if let Err(e) = state.storage.delete(path).await {
+                tracing::warn!("deleting {} from storage failed: {}", path, e);
+            }
+            crate::thumbnails::delete(state, path).await;
+        }
+        if (paths.len() as i64) < batch_size {
+            break;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// GET /api/channels/{id}/retention/preview
+///
+/// Dry run for channel admins: what the next purge would delete.
+pub async fn preview(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<RetentionPreview>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (channel, role) = visible_channel(&state.db, &channel_id, &user.user_id).await?;
+    if role != Some(MemberRole::Admin) {
+        return Err(AppError::forbidden());
+    }
+
+    let mut preview = RetentionPreview {
+        channel_id,
+        retention_days: channel.retention_days,
+        cutoff: channel.retention_days.map(cutoff),
+        messages: 0,
+        files: 0,
+        file_bytes: 0,
+    };
+    let Some(cutoff) = preview.cutoff else {
+        return Ok(Json(preview));
+    };
+
+    preview.messages =
+        sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE channel_id = $1 AND created_at < $2")
+            .bind(&preview.channel_id)
+            .bind(cutoff)
+            .fetch_one(&state.db)
+            .await?;
+    (preview.files, preview.file_bytes) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(size_bytes), 0)::BIGINT
+         FROM file_uploads WHERE channel_id = $1 AND created_at < $2",
+    )
+    .bind(&preview.channel_id)
+    .bind(cutoff)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(Json(preview))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::messages::tests::insert;
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use bytes::Bytes;
+    use futures_util::StreamExt;
+    use serde_json::json;
+    use uchat_proto::ids::{FileId, UserId};
+
+    async fn message_count(state: &AppState, channel: &str) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE channel_id = $1")
+            .bind(channel)
+            .fetch_one(&state.db)
+            .await
+            .unwrap()
+    }
+
+    pub async fn insert_file(state: &AppState, channel: &str, owner: &UserId, created_at: DateTime<Utc>) -> String {
+        let id = FileId::new();
+        let path = format!("retention/{}", id);
+        let data = futures_util::stream::once(async { Ok(Bytes::from_static(b"old")) }).boxed();
+        state.storage.put(&path, data).await.unwrap();
+        sqlx::query(
+            "INSERT INTO file_uploads
+                 (id, channel_id, uploader_id, filename, mime_type, size_bytes, checksum,
+                  storage_backend, storage_path, created_at)
+             VALUES ($1, $2, $3, 'old.txt', 'text/plain', 3, '', 'local', $4, $5)",
+        )
+        .bind(&id)
+        .bind(channel)
+        .bind(owner)
+        .bind(&path)
+        .bind(created_at)
+        .execute(&state.db)
+        .await
+        .unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn purges_in_bounded_batches() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "retention-batches", "public").await;
+        let channel_id = parse_channel_id(&channel).unwrap();
+
+        let now = Utc::now();
+        let old = now - chrono::Duration::days(40);
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            ids.push(insert(&state.db, &channel, &owner, &format!("old {}", i), old).await);
+        }
+        sqlx::query("INSERT INTO message_edits (message_id, content) VALUES ($1, 'older')")
+            .bind(&ids[0])
+            .execute(&state.db)
+            .await
+            .unwrap();
+        insert(&state.db, &channel, &owner, "fresh", now).await;
+        let path = insert_file(&state, &channel, &owner, old).await;
+
+        let stats = purge_channel(&state, &channel_id, cutoff(30), 2).await.unwrap();
+        // Messages: 2 + 2 + 1; files: 1.
+        assert_eq!(stats, PurgeStats { messages: 5, files: 1, batches: 4 });
+        assert_eq!(message_count(&state, &channel).await, 1);
+        assert!(state.storage.get(&path, None).await.is_err());
+        let versions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_edits WHERE message_id = $1")
+            .bind(&ids[0])
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(versions, 0);
+    }
+
+    #[tokio::test]
+    async fn disabled_by_default_and_dry_run() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let old = Utc::now() - chrono::Duration::days(400);
+
+        let kept = create(&state, &owner, "retention-off", "public").await;
+        insert(&state.db, &kept, &owner, "ancient", old).await;
+
+        let purged = create(&state, &owner, "retention-on", "public").await;
+        insert(&state.db, &purged, &owner, "ancient", old).await;
+        insert(&state.db, &purged, &owner, "fresh", Utc::now()).await;
+        insert_file(&state, &purged, &owner, old).await;
+
+        let uri = |channel: &str| format!("/api/channels/{}", channel);
+        let (status, _) =
+            call(&state, Method::PATCH, &uri(&purged), Some(&UserId::new()), Some(json!({ "retention_days": 30 })))
+                .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) =
+            call(&state, Method::PATCH, &uri(&purged), Some(&owner), Some(json!({ "retention_days": -1 }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, body) =
+            call(&state, Method::PATCH, &uri(&purged), Some(&owner), Some(json!({ "retention_days": 30 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["retention_days"], 30);
+
+        let (_, preview) =
+            call(&state, Method::GET, &format!("{}/retention/preview", uri(&kept)), Some(&owner), None).await;
+        assert!(preview["cutoff"].is_null());
+        assert_eq!(preview["messages"], 0);
+
+        let (_, preview) =
+            call(&state, Method::GET, &format!("{}/retention/preview", uri(&purged)), Some(&owner), None).await;
+        assert_eq!(preview["messages"], 1);
+        assert_eq!(preview["files"], 1);
+        assert_eq!(preview["file_bytes"], 3);
+        assert_eq!(message_count(&state, &purged).await, 2);
+
+        run_once(&state, DEFAULT_BATCH_SIZE).await.unwrap();
+        assert_eq!(message_count(&state, &kept).await, 1);
+        assert_eq!(message_count(&state, &purged).await, 1);
+
+        let (_, body) =
+            call(&state, Method::PATCH, &uri(&purged), Some(&owner), Some(json!({ "retention_days": 0 }))).await;
+        assert!(body.get("retention_days").is_none());
+    }
+
+    #[tokio::test]
+    async fn deletes_queued_objects() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let paths = [
+            insert_file(&state, "queued", &owner, Utc::now()).await,
+            insert_file(&state, "queued", &owner, Utc::now()).await,
+        ];
+        sqlx::query("INSERT INTO storage_deletions (path) SELECT unnest($1::text[])")
+            .bind(&paths[..])
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        assert!(delete_queued_objects(&state, 1).await.unwrap() >= 2);
+        for path in &paths {
+            assert!(state.storage.get(path, None).await.is_err());
+        }
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM storage_deletions WHERE path = ANY($1)")
+            .bind(&paths[..])
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+}
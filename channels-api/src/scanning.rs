@@ -0,0 +1,574 @@
+//! Malware scanning of uploads. With a scanner configured, each upload is
+//! stored as `pending_scan`, which only its uploader may download, and
+//! scanned in the background: a clean verdict makes it `available` to the
+//! channel, an infected one quarantines it. A scan that times out or
+//! fails is settled by `FailurePolicy`.
+//!
+//! `FILES_SCANNER` picks the scanner: `none` (the default, files are
+//! available at once), `clamd` (a clamd daemon at `CLAMD_ADDR`, default
+//! `127.0.0.1:3310`) or `webhook` (`FILES_SCANNER_URL`, which is POSTed
+//! the file and answers `{"verdict":"clean"|"infected","signature":…}`).
+//! `FILES_SCAN_TIMEOUT_SECS` (default 60) bounds each scan and
+//! `FILES_SCAN_ON_TIMEOUT` (`allow` or `block`, the default) settles the
+//! ones that don't finish.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use uchat_db::audit::{record_file, FileAudit};
+use uchat_proto::audit::FileAction;
+use uchat_proto::files::{FileQuarantined, ScanStatus};
+use uchat_proto::ids::{ChannelId, FileId, UserId};
+
+use crate::{storage, thumbnails, AppState};
+
+/// Bytes per clamd `INSTREAM` chunk.
+const CLAMD_CHUNK: usize = 64 * 1024;
+/// Longest clamd reply read.
+const MAX_CLAMD_REPLY: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// With the name of what was found.
+    Infected(String),
+}
+
+/// Something that can tell whether a file is malware.
+#[async_trait]
+pub trait FileScanner: Send + Sync {
+    /// As `FILES_SCANNER` names it.
+    fn name(&self) -> &'static str;
+
+    /// `Err` when the scanner couldn't give a verdict.
+    async fn scan(&self, file_id: &FileId, data: &[u8]) -> Result<Verdict, String>;
+}
+
+/// Finds every file clean; what runs when no scanner is configured.
+/// Uploads skip `pending_scan` with it.
+pub struct NoopScanner;
+
+#[async_trait]
+impl FileScanner for NoopScanner {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    async fn scan(&self, _file_id: &FileId, _data: &[u8]) -> Result<Verdict, String> {
+        Ok(Verdict::Clean)
+    }
+}
+
+/// A clamd daemon, sent the file over TCP with `INSTREAM`.
+pub struct ClamdScanner {
+    addr: String,
+}
+
+impl ClamdScanner {
+    pub fn new(addr: &str) -> Self {
+        Self { addr: addr.to_string() }
+    }
+}
+
+#[async_trait]
+impl FileScanner for ClamdScanner {
+    fn name(&self) -> &'static str {
+        "clamd"
+    }
+
+    async fn scan(&self, _file_id: &FileId, data: &[u8]) -> Result<Verdict, String> {
+        let mut stream = TcpStream::connect(&self.addr).await.map_err(|e| format!("clamd: {}", e))?;
+        let io = async {
+            stream.write_all(b"zINSTREAM\0").await?;
+            for chunk in data.chunks(CLAMD_CHUNK) {
+                stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+                stream.write_all(chunk).await?;
+            }
+            stream.write_all(&0u32.to_be_bytes()).await?;
+            let mut reply = Vec::new();
+            (&mut stream).take(MAX_CLAMD_REPLY).read_to_end(&mut reply).await?;
+            Ok::<_, std::io::Error>(reply)
+        };
+        let reply = io.await.map_err(|e| format!("clamd: {}", e))?;
+        parse_clamd_reply(&String::from_utf8_lossy(&reply))
+    }
+}
+
+/// `stream: OK`, `stream: <signature> FOUND`, or an error.
+fn parse_clamd_reply(reply: &str) -> Result<Verdict, String> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    match reply.strip_prefix("stream: ") {
+        Some("OK") => Ok(Verdict::Clean),
+        Some(found) if found.ends_with(" FOUND") => Ok(Verdict::Infected(found.trim_end_matches(" FOUND").to_string())),
+        _ => Err(format!("clamd: {}", reply)),
+    }
+}
+
+/// Any HTTP service that takes the file as the request body and answers
+/// with a verdict.
+pub struct WebhookScanner {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookScanner {
+    pub fn new(url: &str) -> Self {
+        Self { client: reqwest::Client::new(), url: url.to_string() }
+    }
+}
+
+#[derive(Deserialize)]
+struct WebhookVerdict {
+    verdict: String,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+#[async_trait]
+impl FileScanner for WebhookScanner {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn scan(&self, file_id: &FileId, data: &[u8]) -> Result<Verdict, String> {
+        let resp = self
+            .client
+            .post(&self.url)
+            .header("content-type", "application/octet-stream")
+            .header("x-file-id", file_id.as_str())
+            .body(data.to_vec())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("scanner returned {}", resp.status()));
+        }
+        let answer: WebhookVerdict = resp.json().await.map_err(|e| e.to_string())?;
+        match answer.verdict.as_str() {
+            "clean" => Ok(Verdict::Clean),
+            "infected" => Ok(Verdict::Infected(answer.signature.unwrap_or_else(|| "malware".into()))),
+            other => Err(format!("scanner answered {:?}", other)),
+        }
+    }
+}
+
+/// What becomes of files whose scan times out or fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    Allow,
+    Block,
+}
+
+/// How a scan ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Clean,
+    Infected(String),
+    /// Timed out or errored, with why.
+    Failed(String),
+}
+
+/// The status a file in `status` moves to once its scan ends in
+/// `outcome`. Only pending files move; a verdict never reopens a decided
+/// file.
+pub fn next_status(status: ScanStatus, outcome: &Outcome, on_failure: FailurePolicy) -> ScanStatus {
+    match (status, outcome, on_failure) {
+        (ScanStatus::PendingScan, Outcome::Clean, _) => ScanStatus::Available,
+        (ScanStatus::PendingScan, Outcome::Infected(_), _) => ScanStatus::Quarantined,
+        (ScanStatus::PendingScan, Outcome::Failed(_), FailurePolicy::Allow) => ScanStatus::Available,
+        (ScanStatus::PendingScan, Outcome::Failed(_), FailurePolicy::Block) => ScanStatus::Quarantined,
+        (decided, _, _) => decided,
+    }
+}
+
+/// The configured scanner and how its results are settled.
+#[derive(Clone)]
+pub struct Scanning {
+    scanner: Arc<dyn FileScanner>,
+    timeout: Duration,
+    on_failure: FailurePolicy,
+}
+
+impl Default for Scanning {
+    /// Uploads aren't scanned.
+    fn default() -> Self {
+        Self { scanner: Arc::new(NoopScanner), timeout: Duration::from_secs(60), on_failure: FailurePolicy::Block }
+    }
+}
+
+impl Scanning {
+    pub fn new(scanner: Arc<dyn FileScanner>, timeout: Duration, on_failure: FailurePolicy) -> Self {
+        Self { scanner, timeout, on_failure }
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        let scanner: Arc<dyn FileScanner> = match std::env::var("FILES_SCANNER").unwrap_or_default().as_str() {
+            "" | "none" => Arc::new(NoopScanner),
+            "clamd" => {
+                let addr = std::env::var("CLAMD_ADDR").unwrap_or_else(|_| "127.0.0.1:3310".into());
+                Arc::new(ClamdScanner::new(&addr))
+            }
+            "webhook" => {
+                let url = std::env::var("FILES_SCANNER_URL").map_err(|_| "FILES_SCANNER_URL is unset")?;
+                Arc::new(WebhookScanner::new(&url))
+            }
+            other => return Err(format!("unknown FILES_SCANNER {:?}", other)),
+        };
+        let timeout = std::env::var("FILES_SCAN_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60);
+        let on_failure = match std::env::var("FILES_SCAN_ON_TIMEOUT").unwrap_or_default().as_str() {
+            "allow" => FailurePolicy::Allow,
+            "" | "block" => FailurePolicy::Block,
+            other => return Err(format!("unknown FILES_SCAN_ON_TIMEOUT {:?}", other)),
+        };
+        Ok(Self::new(scanner, Duration::from_secs(timeout), on_failure))
+    }
+
+    /// Status new uploads start in.
+    pub fn initial_status(&self) -> ScanStatus {
+        match self.scanner.name() {
+            "none" => ScanStatus::Available,
+            _ => ScanStatus::PendingScan,
+        }
+    }
+
+    async fn scan(&self, file_id: &FileId, data: &[u8]) -> Outcome {
+        match tokio::time::timeout(self.timeout, self.scanner.scan(file_id, data)).await {
+            Ok(Ok(Verdict::Clean)) => Outcome::Clean,
+            Ok(Ok(Verdict::Infected(signature))) => Outcome::Infected(signature),
+            Ok(Err(e)) => Outcome::Failed(e),
+            Err(_) => Outcome::Failed("scan timed out".into()),
+        }
+    }
+}
+
+/// An upload waiting for its verdict.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub file_id: FileId,
+    pub channel_id: ChannelId,
+    pub uploader_id: UserId,
+    pub filename: String,
+    pub storage_path: String,
+    pub detected_mime_type: Option<String>,
+}
+
+/// Scans the file and settles its status.
+pub fn spawn(state: Arc<AppState>, file: Pending) {
+    uchat_metrics::spawn_task("file_scan", async move {
+        let limit = state.file_policy.max_file_bytes as usize;
+        let outcome = match storage::read_all(&*state.storage, &file.storage_path, limit).await {
+            Ok(data) => state.scanning.scan(&file.file_id, &data).await,
+            Err(e) => Outcome::Failed(format!("reading the file failed: {}", e)),
+        };
+        if let Err(e) = settle(&state, &file, outcome).await {
+            tracing::warn!(file_id = %file.file_id, "recording a scan verdict failed: {}", e);
+        }
+    });
+}
+
+/// Records the file's new status; quarantines are audited in the same
+/// transaction and told to the uploader, and files made available get
+/// their thumbnails.
+async fn settle(state: &Arc<AppState>, file: &Pending, outcome: Outcome) -> Result<(), sqlx::Error> {
+    let status = next_status(ScanStatus::PendingScan, &outcome, state.scanning.on_failure);
+    let detail = match &outcome {
+        Outcome::Clean => None,
+        Outcome::Infected(signature) => Some(signature.as_str()),
+        Outcome::Failed(why) => Some(why.as_str()),
+    };
+    if let Outcome::Failed(why) = &outcome {
+        tracing::warn!(file_id = %file.file_id, "scan failed, file is {}: {}", status, why);
+    }
+
+    let mut tx = state.db.begin().await?;
+    // A file deleted meanwhile, or already decided, is left alone.
+    let updated = sqlx::query(
+        "UPDATE file_uploads SET scan_status = $2, scan_detail = $3 WHERE id = $1 AND scan_status = 'pending_scan'",
+    )
+    .bind(&file.file_id)
+    .bind(status.as_str())
+    .bind(detail)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(());
+    }
+    if status == ScanStatus::Quarantined {
+        let entry = FileAudit {
+            file_id: &file.file_id,
+            channel_id: &file.channel_id,
+            uploader_id: &file.uploader_id,
+            action: FileAction::Quarantined,
+            detail,
+        };
+        record_file(&mut tx, entry).await?;
+    }
+    tx.commit().await?;
+
+    match status {
+        ScanStatus::Available if thumbnails::supported(file.detected_mime_type.as_deref()) => {
+            thumbnails::spawn(state.clone(), file.file_id.clone(), file.storage_path.clone());
+        }
+        ScanStatus::Quarantined => {
+            tracing::warn!(file_id = %file.file_id, uploader_id = %file.uploader_id, "file quarantined: {}", detail.unwrap_or(""));
+            if let Some(gateway) = &state.gateway {
+                let reason = match &outcome {
+                    Outcome::Infected(signature) => format!("{} was found in it", signature),
+                    _ => "it could not be scanned".into(),
+                };
+                let quarantined = FileQuarantined {
+                    file_id: file.file_id.clone(),
+                    channel_id: file.channel_id.clone(),
+                    uploader_id: file.uploader_id.clone(),
+                    filename: file.filename.clone(),
+                    reason,
+                };
+                gateway.file_quarantined(&quarantined).await;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    /// Flags files containing `EICAR`, hangs on ones containing `HANG`.
+    struct FakeScanner;
+
+    #[async_trait]
+    impl FileScanner for FakeScanner {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        async fn scan(&self, _file_id: &FileId, data: &[u8]) -> Result<Verdict, String> {
+            if data.windows(4).any(|w| w == b"HANG") {
+                std::future::pending::<()>().await;
+            }
+            match data.windows(5).any(|w| w == b"EICAR") {
+                true => Ok(Verdict::Infected("Eicar-Test-Signature".into())),
+                false => Ok(Verdict::Clean),
+            }
+        }
+    }
+
+    #[test]
+    fn every_transition() {
+        use FailurePolicy::{Allow, Block};
+        use ScanStatus::{Available, PendingScan, Quarantined};
+
+        let clean = Outcome::Clean;
+        let infected = Outcome::Infected("Eicar-Test-Signature".into());
+        let failed = Outcome::Failed("scan timed out".into());
+        assert_eq!(next_status(PendingScan, &clean, Block), Available);
+        assert_eq!(next_status(PendingScan, &infected, Allow), Quarantined);
+        assert_eq!(next_status(PendingScan, &failed, Allow), Available);
+        assert_eq!(next_status(PendingScan, &failed, Block), Quarantined);
+        for decided in [Available, Quarantined] {
+            for outcome in [&clean, &infected, &failed] {
+                for policy in [Allow, Block] {
+                    assert_eq!(next_status(decided, outcome, policy), decided);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_clamd_replies() {
+        assert_eq!(parse_clamd_reply("stream: OK\0"), Ok(Verdict::Clean));
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Test-Signature FOUND\0"),
+            Ok(Verdict::Infected("Eicar-Test-Signature".into()))
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+        assert!(parse_clamd_reply("").is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_files_to_clamd() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut command = [0; 10];
+                conn.read_exact(&mut command).await.unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = Vec::new();
+                loop {
+                    let len = conn.read_u32().await.unwrap() as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; len];
+                    conn.read_exact(&mut chunk).await.unwrap();
+                    data.extend(chunk);
+                }
+                let reply: &[u8] = if data.windows(5).any(|w| w == b"EICAR") { b"stream: Eicar-Test-Signature FOUND\0" } else { b"stream: OK\0" };
+                conn.write_all(reply).await.unwrap();
+            }
+        });
+
+        let clamd = ClamdScanner::new(&addr);
+        let big = vec![b'a'; CLAMD_CHUNK * 2 + 5];
+        assert_eq!(clamd.scan(&FileId::new(), &big).await, Ok(Verdict::Clean));
+        assert_eq!(
+            clamd.scan(&FileId::new(), b"X5O!P%@AP EICAR").await,
+            Ok(Verdict::Infected("Eicar-Test-Signature".into()))
+        );
+        assert!(ClamdScanner::new("127.0.0.1:1").scan(&FileId::new(), b"x").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn asks_webhooks_for_a_verdict() {
+        use axum::{body::Bytes, routing::post, Json, Router};
+
+        let app = Router::new().route(
+            "/scan",
+            post(|body: Bytes| async move {
+                match &body[..] {
+                    b"bad" => Json(json!({ "verdict": "infected", "signature": "Trojan.Test" })),
+                    b"odd" => Json(json!({ "verdict": "maybe" })),
+                    _ => Json(json!({ "verdict": "clean" })),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/scan", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let scanner = WebhookScanner::new(&url);
+        assert_eq!(scanner.scan(&FileId::new(), b"fine").await, Ok(Verdict::Clean));
+        assert_eq!(scanner.scan(&FileId::new(), b"bad").await, Ok(Verdict::Infected("Trojan.Test".into())));
+        assert!(scanner.scan(&FileId::new(), b"odd").await.is_err());
+        assert!(WebhookScanner::new(&format!("{}/missing", url)).scan(&FileId::new(), b"x").await.is_err());
+    }
+
+    /// `test_state` with `FakeScanner`, giving up on scans after 500ms.
+    async fn scanning_state(on_failure: FailurePolicy) -> Option<Arc<AppState>> {
+        let base = test_state().await?;
+        let mut state = Arc::try_unwrap(base).ok().unwrap();
+        state.scanning = Scanning::new(Arc::new(FakeScanner), Duration::from_millis(500), on_failure);
+        Some(Arc::new(state))
+    }
+
+    async fn upload(state: &Arc<AppState>, user: &UserId, channel: &str, data: &'static [u8]) -> String {
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let token = uchat_proto::jwt::create_token(&state.jwt_secret, user.as_str());
+        let req = Request::post("/api/files")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(crate::files::CHANNEL_HEADER, channel)
+            .header(crate::files::FILENAME_HEADER, "report.txt")
+            .header("Content-Type", "text/plain")
+            .body(Body::from(data))
+            .unwrap();
+        let resp = crate::app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let meta: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(meta["scan_status"], "pending_scan");
+        meta["id"].as_str().unwrap().to_string()
+    }
+
+    async fn settled(state: &AppState, id: &str) -> (String, Option<String>) {
+        for _ in 0..100 {
+            let row: (String, Option<String>) =
+                sqlx::query_as("SELECT scan_status, scan_detail FROM file_uploads WHERE id = $1")
+                    .bind(id)
+                    .fetch_one(&state.db)
+                    .await
+                    .unwrap();
+            if row.0 != "pending_scan" {
+                return row;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("file {} was never scanned", id);
+    }
+
+    async fn audited(state: &AppState, id: &str) -> Vec<(String, Option<String>)> {
+        sqlx::query_as("SELECT action, detail FROM file_audit WHERE file_id = $1")
+            .bind(id)
+            .fetch_all(&state.db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_are_held_until_scanned() {
+        let Some(state) = scanning_state(FailurePolicy::Block).await else { return };
+        let owner = UserId::new();
+        let member = UserId::new();
+        let channel = create(&state, &owner, "scanned", "public").await;
+        let (status, _) = call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&member), Some(json!({}))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let download = |user: &UserId, id: &str| {
+            let (state, user, uri) = (state.clone(), user.clone(), format!("/api/files/{}", id));
+            async move { call(&state, Method::GET, &uri, Some(&user), None).await.0 }
+        };
+
+        // Pending: the uploader may download, nobody else yet.
+        let hung = upload(&state, &owner, &channel, b"HANG").await;
+        assert_eq!(download(&owner, &hung).await, StatusCode::OK);
+        assert_eq!(download(&member, &hung).await, StatusCode::CONFLICT);
+
+        // Clean: everyone may.
+        let clean = upload(&state, &owner, &channel, b"quarterly numbers").await;
+        assert_eq!(settled(&state, &clean).await, ("available".into(), None));
+        assert_eq!(download(&member, &clean).await, StatusCode::OK);
+        assert!(audited(&state, &clean).await.is_empty());
+
+        // Infected: nobody may, and it is audited.
+        let infected = upload(&state, &owner, &channel, b"X5O!P%@AP EICAR").await;
+        assert_eq!(settled(&state, &infected).await, ("quarantined".into(), Some("Eicar-Test-Signature".into())));
+        assert_eq!(download(&owner, &infected).await, StatusCode::FORBIDDEN);
+        assert_eq!(download(&member, &infected).await, StatusCode::FORBIDDEN);
+        assert_eq!(audited(&state, &infected).await, [("quarantined".into(), Some("Eicar-Test-Signature".into()))]);
+
+        // Timed out, with the block policy: quarantined too.
+        assert_eq!(settled(&state, &hung).await, ("quarantined".into(), Some("scan timed out".into())));
+        assert_eq!(download(&owner, &hung).await, StatusCode::FORBIDDEN);
+        assert_eq!(audited(&state, &hung).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn timeouts_can_be_allowed_through() {
+        let Some(state) = scanning_state(FailurePolicy::Allow).await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "lenient", "public").await;
+
+        let hung = upload(&state, &owner, &channel, b"HANG").await;
+        assert_eq!(settled(&state, &hung).await, ("available".into(), Some("scan timed out".into())));
+        assert!(audited(&state, &hung).await.is_empty());
+        let infected = upload(&state, &owner, &channel, b"EICAR").await;
+        assert_eq!(settled(&state, &infected).await.0, "quarantined");
+
+        // A verdict arriving for a decided file changes nothing.
+        let pending = Pending {
+            file_id: infected.parse().unwrap(),
+            channel_id: channel.parse().unwrap(),
+            uploader_id: owner.clone(),
+            filename: "report.txt".into(),
+            storage_path: String::new(),
+            detected_mime_type: None,
+        };
+        settle(&state, &pending, Outcome::Clean).await.unwrap();
+        assert_eq!(settled(&state, &infected).await.0, "quarantined");
+        assert_eq!(audited(&state, &infected).await.len(), 1);
+    }
+}
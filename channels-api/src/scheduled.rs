@@ -0,0 +1,476 @@
+//! Scheduled messages. Authors queue a message for later; every replica
+//! polls for due ones and posts them the way an incoming webhook's
+//! messages are posted, claiming each row with `FOR UPDATE SKIP LOCKED`
+//! so it is sent once however many replicas run.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+
+use uchat_db::audit::{record_message, MessageAudit};
+use uchat_proto::audit::MessageAction;
+use uchat_proto::channels::MemberRole;
+use uchat_proto::ids::{ChannelId, MessageId, ScheduledMessageId, UserId};
+use uchat_proto::messages::{MessagePosted, ScheduleMessage, ScheduledMessage, ScheduledMessageCancelled};
+
+use crate::auth::AuthUser;
+use crate::channels::{parse_channel_id, writable_channel};
+use crate::error::AppError;
+use crate::AppState;
+
+/// Largest message that can be scheduled, the same as an incoming
+/// webhook's body.
+const MAX_CONTENT_BYTES: usize = 64 * 1024;
+/// How far ahead a message can be scheduled.
+const MAX_AHEAD: chrono::Duration = chrono::Duration::days(365);
+/// Pending messages an author may have in one channel.
+const MAX_PENDING: i64 = 100;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Rows claimed per transaction.
+const BATCH_SIZE: i64 = 100;
+
+#[derive(sqlx::FromRow)]
+struct ScheduledRow {
+    id: ScheduledMessageId,
+    channel_id: ChannelId,
+    author_id: UserId,
+    content: String,
+    reply_to: Option<MessageId>,
+    send_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<ScheduledRow> for ScheduledMessage {
+    fn from(row: ScheduledRow) -> Self {
+        ScheduledMessage {
+            id: row.id,
+            channel_id: row.channel_id,
+            author_id: row.author_id,
+            content: row.content,
+            reply_to: row.reply_to,
+            send_at: row.send_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+const SCHEDULED_COLUMNS: &str = "id, channel_id, author_id, content, reply_to, send_at, created_at";
+
+/// POST /api/channels/{id}/scheduled_messages
+///
+/// Queues a message from the caller, who must be able to post in the
+/// channel, for `send_at`. With `reply_to` it goes into that message's
+/// thread.
+pub async fn schedule_message(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    Json(body): Json<ScheduleMessage>,
+) -> Result<(StatusCode, Json<ScheduledMessage>), AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let (channel, role) = writable_channel(&state.db, &channel_id, &user.user_id).await?;
+    if !matches!(role, Some(MemberRole::Write | MemberRole::Admin)) {
+        return Err(AppError::forbidden());
+    }
+    // The server would have to hold the plaintext until it is sent.
+    if channel.e2ee_required {
+        return Err(AppError::e2ee_required());
+    }
+
+    if body.content.trim().is_empty() {
+        return Err(AppError::invalid("content must not be empty"));
+    }
+    if body.content.len() > MAX_CONTENT_BYTES {
+        return Err(AppError::invalid("content exceeds 64 KiB"));
+    }
+    let now = Utc::now();
+    if body.send_at <= now {
+        return Err(AppError::invalid("send_at must be in the future"));
+    }
+    if body.send_at > now + MAX_AHEAD {
+        return Err(AppError::invalid(format!("send_at must be within {} days", MAX_AHEAD.num_days())));
+    }
+    if let Some(reply_to) = &body.reply_to {
+        let found: Option<bool> =
+            sqlx::query_scalar("SELECT true FROM messages WHERE id = $1 AND channel_id = $2 AND deleted_at IS NULL")
+                .bind(reply_to)
+                .bind(&channel_id)
+                .fetch_optional(&state.db)
+                .await?;
+        if found.is_none() {
+            return Err(AppError::invalid("reply_to is not a message in this channel"));
+        }
+    }
+
+    let pending: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM scheduled_messages
+         WHERE author_id = $1 AND channel_id = $2 AND sent_at IS NULL AND cancelled_at IS NULL",
+    )
+    .bind(&user.user_id)
+    .bind(&channel_id)
+    .fetch_one(&state.db)
+    .await?;
+    if pending >= MAX_PENDING {
+        return Err(AppError::conflict(format!("at most {} scheduled messages per channel", MAX_PENDING)));
+    }
+
+    let row: ScheduledRow = sqlx::query_as(&format!(
+        "INSERT INTO scheduled_messages (id, channel_id, author_id, content, reply_to, send_at)
+         VALUES ($1, $2, $3, $4, $5, $6)
+         RETURNING {}",
+        SCHEDULED_COLUMNS
+    ))
+    .bind(ScheduledMessageId::new())
+    .bind(&channel_id)
+    .bind(&user.user_id)
+    .bind(&body.content)
+    .bind(&body.reply_to)
+    .bind(body.send_at)
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(row.into())))
+}
+
+/// GET /api/channels/{id}/scheduled_messages
+///
+/// The caller's pending messages in the channel, soonest first. Nobody
+/// else's are shown, admins' included.
+pub async fn list_scheduled(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<ScheduledMessage>>, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+
+    let rows: Vec<ScheduledRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM scheduled_messages
+         WHERE channel_id = $1 AND author_id = $2 AND sent_at IS NULL AND cancelled_at IS NULL
+         ORDER BY send_at, id",
+        SCHEDULED_COLUMNS
+    ))
+    .bind(&channel_id)
+    .bind(&user.user_id)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(rows.into_iter().map(ScheduledMessage::from).collect()))
+}
+
+/// DELETE /api/channels/{id}/scheduled_messages/{scheduled_id}
+///
+/// Cancels one of the caller's pending messages; 409 once it was sent.
+pub async fn cancel_scheduled(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Path((id, scheduled_id)): Path<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let channel_id = parse_channel_id(&id)?;
+    let scheduled_id: ScheduledMessageId = scheduled_id.parse().map_err(|_| AppError::not_found())?;
+
+    // A scheduler sending it right now holds its row lock, so this waits and
+    // then leaves it sent.
+    let found: Option<(bool, bool)> = sqlx::query_as(
+        "WITH cancelled AS (
+             UPDATE scheduled_messages SET cancelled_at = now(), cancel_reason = 'cancelled by author'
+             WHERE id = $1 AND channel_id = $2 AND author_id = $3 AND sent_at IS NULL AND cancelled_at IS NULL
+             RETURNING id
+         )
+         SELECT EXISTS (SELECT 1 FROM cancelled), sent_at IS NOT NULL
+         FROM scheduled_messages
+         WHERE id = $1 AND channel_id = $2 AND author_id = $3",
+    )
+    .bind(&scheduled_id)
+    .bind(&channel_id)
+    .bind(&user.user_id)
+    .fetch_optional(&state.db)
+    .await?;
+
+    match found {
+        Some((true, _)) => Ok(StatusCode::NO_CONTENT),
+        Some((false, true)) => Err(AppError::conflict("message was already sent")),
+        // Already cancelled, or not the caller's.
+        _ => Err(AppError::not_found()),
+    }
+}
+
+pub fn spawn(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = send_due(&state).await {
+                tracing::warn!("sending scheduled messages failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Sends every message that is due, a batch per transaction. A row is
+/// marked sent in the transaction that stores its message, and rows
+/// another replica has claimed are skipped, so each is sent once.
+pub async fn send_due(state: &AppState) -> Result<(), sqlx::Error> {
+    loop {
+        let (posted, cancelled, claimed) = send_batch(state).await?;
+
+        if let Some(gateway) = &state.gateway {
+            for posted in &posted {
+                gateway.message_posted(posted).await;
+            }
+            for cancelled in &cancelled {
+                gateway.scheduled_cancelled(cancelled).await;
+            }
+        }
+        if claimed < BATCH_SIZE as usize {
+            return Ok(());
+        }
+    }
+}
+
+type Batch = (Vec<MessagePosted>, Vec<ScheduledMessageCancelled>, usize);
+
+async fn send_batch(state: &AppState) -> Result<Batch, sqlx::Error> {
+    let mut tx = state.db.begin().await?;
+    let due: Vec<ScheduledRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM scheduled_messages
+         WHERE sent_at IS NULL AND cancelled_at IS NULL AND send_at <= now()
+         ORDER BY send_at LIMIT $1 FOR UPDATE SKIP LOCKED",
+        SCHEDULED_COLUMNS
+    ))
+    .bind(BATCH_SIZE)
+    .fetch_all(&mut *tx)
+    .await?;
+    let claimed = due.len();
+
+    let (mut posted, mut cancelled) = (Vec::new(), Vec::new());
+    for row in due {
+        if let Some(reason) = cannot_post(&mut tx, &row.channel_id, &row.author_id).await? {
+            sqlx::query("UPDATE scheduled_messages SET cancelled_at = now(), cancel_reason = $2 WHERE id = $1")
+                .bind(&row.id)
+                .bind(reason)
+                .execute(&mut *tx)
+                .await?;
+            tracing::info!(scheduled_id = %row.id, channel_id = %row.channel_id, reason, "scheduled message cancelled");
+            cancelled.push(ScheduledMessageCancelled {
+                id: row.id,
+                channel_id: row.channel_id,
+                author_id: row.author_id,
+                reason: reason.to_string(),
+            });
+            continue;
+        }
+
+        let message_id = MessageId::new();
+        sqlx::query("INSERT INTO messages (id, channel_id, sender_id, content) VALUES ($1, $2, $3, $4)")
+            .bind(&message_id)
+            .bind(&row.channel_id)
+            .bind(&row.author_id)
+            .bind(&row.content)
+            .execute(&mut *tx)
+            .await?;
+        record_message(
+            &mut tx,
+            MessageAudit {
+                message_id: &message_id,
+                channel_id: &row.channel_id,
+                actor_id: &row.author_id,
+                action: MessageAction::Created,
+                before: None,
+                after: Some(&row.content),
+            },
+        )
+        .await?;
+        sqlx::query("UPDATE scheduled_messages SET sent_at = now(), message_id = $2 WHERE id = $1")
+            .bind(&row.id)
+            .bind(&message_id)
+            .execute(&mut *tx)
+            .await?;
+        posted.push(MessagePosted {
+            id: message_id,
+            channel_id: row.channel_id,
+            sender_id: row.author_id,
+            sender_name: None,
+            content: row.content,
+            thread_id: row.reply_to,
+        });
+    }
+    tx.commit().await?;
+
+    Ok((posted, cancelled, claimed))
+}
+
+/// Why the author can't post in the channel any more, if they can't.
+async fn cannot_post(
+    tx: &mut sqlx::PgConnection,
+    channel_id: &ChannelId,
+    author_id: &UserId,
+) -> Result<Option<&'static str>, sqlx::Error> {
+    let found: Option<(bool, bool, Option<String>)> = sqlx::query_as(
+        "SELECT c.archived_at IS NOT NULL, c.e2ee_required, m.role
+         FROM channels c
+         LEFT JOIN channel_members m ON m.channel_id = c.id AND m.user_id = $2
+         WHERE c.id = $1",
+    )
+    .bind(channel_id)
+    .bind(author_id)
+    .fetch_optional(tx)
+    .await?;
+
+    Ok(match found {
+        None => Some("the channel was deleted"),
+        Some((true, _, _)) => Some("the channel is archived"),
+        Some((_, true, _)) => Some("the channel now requires end-to-end encryption"),
+        Some((_, _, None)) => Some("you are no longer a member of this channel"),
+        Some((_, _, Some(role))) => match role.parse() {
+            Ok(MemberRole::Write | MemberRole::Admin) => None,
+            _ => Some("you can no longer post in this channel"),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::gateway::GatewayNotifier;
+    use crate::messages::tests::insert;
+    use crate::test_state;
+    use axum::http::Method;
+    use axum::routing::post;
+    use axum::Router;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+
+    /// A gateway that hands over what it is sent, with the path.
+    async fn fake_gateway() -> (GatewayNotifier, mpsc::UnboundedReceiver<(&'static str, Value)>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut app = Router::new();
+        for path in ["/internal/message-posted", "/internal/scheduled-cancelled"] {
+            let tx = tx.clone();
+            app = app.route(
+                path,
+                post(move |Json(body): Json<Value>| async move {
+                    let _ = tx.send((path, body));
+                    StatusCode::NO_CONTENT
+                }),
+            );
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (GatewayNotifier::new(&format!("http://{}", addr), "internal".into()), rx)
+    }
+
+    async fn make_due(state: &AppState, id: &Value) {
+        sqlx::query("UPDATE scheduled_messages SET send_at = now() - interval '1 second' WHERE id = $1")
+            .bind(id.as_str().unwrap())
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn authors_manage_their_own_schedule() {
+        let Some(state) = test_state().await else { return };
+        let (owner, author) = (UserId::new(), UserId::new());
+        let channel = create(&state, &owner, &format!("later-{}", owner), "public").await;
+        let uri = format!("/api/channels/{}/scheduled_messages", channel);
+        let soon = Utc::now() + chrono::Duration::hours(1);
+
+        // Only members who can post may schedule.
+        let body = json!({ "content": "standup notes", "send_at": soon });
+        let (status, _) = call(&state, Method::POST, &uri, Some(&author), Some(body.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&author), Some(json!({}))).await;
+
+        let past = json!({ "content": "too late", "send_at": Utc::now() - chrono::Duration::minutes(1) });
+        let (status, _) = call(&state, Method::POST, &uri, Some(&author), Some(past)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let stray = json!({ "content": "re", "send_at": soon, "reply_to": MessageId::new() });
+        let (status, _) = call(&state, Method::POST, &uri, Some(&author), Some(stray)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, later) = call(&state, Method::POST, &uri, Some(&author), Some(body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let sooner = json!({ "content": "first", "send_at": soon - chrono::Duration::minutes(30) });
+        let (_, sooner) = call(&state, Method::POST, &uri, Some(&author), Some(sooner)).await;
+
+        let (_, listed) = call(&state, Method::GET, &uri, Some(&author), None).await;
+        let ids: Vec<&Value> = listed.as_array().unwrap().iter().map(|m| &m["id"]).collect();
+        assert_eq!(ids, vec![&sooner["id"], &later["id"]]);
+        let (_, theirs) = call(&state, Method::GET, &uri, Some(&owner), None).await;
+        assert_eq!(theirs, json!([]));
+
+        let one = format!("{}/{}", uri, later["id"].as_str().unwrap());
+        let (status, _) = call(&state, Method::DELETE, &one, Some(&owner), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&state, Method::DELETE, &one, Some(&author), None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = call(&state, Method::DELETE, &one, Some(&author), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (_, listed) = call(&state, Method::GET, &uri, Some(&author), None).await;
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn due_messages_are_sent_once_or_cancelled() {
+        let Some(mut state) = test_state().await else { return };
+        let (gateway, mut rx) = fake_gateway().await;
+        Arc::get_mut(&mut state).unwrap().gateway = Some(gateway);
+        let (owner, author) = (UserId::new(), UserId::new());
+        let channel = create(&state, &owner, &format!("due-{}", owner), "public").await;
+        call(&state, Method::POST, &format!("/api/channels/{}/members", channel), Some(&author), Some(json!({}))).await;
+        let root = insert(&state.db, &channel, &owner, "who's around tomorrow?", Utc::now()).await;
+
+        let uri = format!("/api/channels/{}/scheduled_messages", channel);
+        let send_at = Utc::now() + chrono::Duration::hours(8);
+        let reply = json!({ "content": "me, from 9", "send_at": send_at, "reply_to": root });
+        let (_, reply) = call(&state, Method::POST, &uri, Some(&author), Some(reply)).await;
+        let (_, orphan) = call(&state, Method::POST, &uri, Some(&author), Some(json!({ "content": "bye", "send_at": send_at }))).await;
+        make_due(&state, &reply["id"]).await;
+
+        // Two replicas polling at once still send it once.
+        let (a, b) = tokio::join!(send_due(&state), send_due(&state));
+        a.unwrap();
+        b.unwrap();
+        let (path, posted) = rx.recv().await.unwrap();
+        assert_eq!(path, "/internal/message-posted");
+        assert_eq!((posted["sender_id"].as_str(), posted["thread_id"].as_str()), (Some(author.as_str()), Some(root.as_str())));
+        let message_id: MessageId = posted["id"].as_str().unwrap().parse().unwrap();
+        let stored: Vec<String> = sqlx::query_scalar("SELECT content FROM messages WHERE channel_id = $1 AND sender_id = $2")
+            .bind(channel.parse::<ChannelId>().unwrap())
+            .bind(&author)
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(stored, vec!["me, from 9".to_string()]);
+        let audited: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_audit WHERE message_id = $1 AND action = 'created'")
+            .bind(&message_id)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(audited, 1);
+
+        let sent = format!("{}/{}", uri, reply["id"].as_str().unwrap());
+        let (status, _) = call(&state, Method::DELETE, &sent, Some(&author), None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // Leaving the channel drops the rest, and the author hears why.
+        call(&state, Method::DELETE, &format!("/api/channels/{}/members/{}", channel, author), Some(&author), None).await;
+        make_due(&state, &orphan["id"]).await;
+        send_due(&state).await.unwrap();
+        let (path, cancelled) = rx.recv().await.unwrap();
+        assert_eq!(path, "/internal/scheduled-cancelled");
+        assert_eq!(cancelled["id"], orphan["id"]);
+        assert_eq!(cancelled["author_id"], author.as_str());
+        assert_eq!(cancelled["reason"], "you are no longer a member of this channel");
+        let (_, listed) = call(&state, Method::GET, &uri, Some(&author), None).await;
+        assert_eq!(listed, json!([]));
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::{Postgres, QueryBuilder};
+
+use uchat_proto::ids::UserId;
+use uchat_proto::messages::{Message, Page, SearchHit};
+
+use crate::auth::AuthUser;
+use crate::channels::parse_channel_id;
+use crate::error::AppError;
+use crate::messages::MessageRow;
+use crate::AppState;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 50;
+const MAX_QUERY_CHARS: usize = 256;
+
+/// Age at which a hit's rank is halved.
+const RANK_HALF_LIFE_SECS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(sqlx::FromRow)]
+struct HitRow {
+    #[sqlx(flatten)]
+    message: MessageRow,
+    headline: String,
+    rank: f64,
+}
+
+/// Position in a result list. Ranks decay with age, so every page is
+/// scored as of the first page's time and later messages stay out of it.
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    as_of: DateTime<Utc>,
+    offset: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        format!("{}_{}", self.as_of.timestamp_micros(), self.offset)
+    }
+
+    fn decode(raw: &str) -> Result<Self, AppError> {
+        let invalid = || AppError::invalid("invalid cursor");
+        let (micros, offset) = raw.split_once('_').ok_or_else(invalid)?;
+        let as_of = micros
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or_else(invalid)?;
+        let offset = offset.parse().ok().filter(|o| *o >= 0).ok_or_else(invalid)?;
+        Ok(Cursor { as_of, offset })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    channel_id: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    sender: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+/// GET /api/search/messages
+///
+/// Full-text search over the caller's channels. `q` takes web-search
+/// syntax: words, `"quoted phrases"`, `or` and `-excluded` terms. Hits
+/// are ordered by relevance, discounted by age so recent messages win
+/// ties. Encrypted and deleted messages are never returned.
+pub async fn search_messages(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Page<SearchHit>>, AppError> {
+    let q = query.q.trim();
+    if q.is_empty() {
+        return Err(AppError::invalid("q is required"));
+    }
+    if q.chars().count() > MAX_QUERY_CHARS {
+        return Err(AppError::invalid("query too long"));
+    }
+
+    let channel_id = query.channel_id.as_deref().map(parse_channel_id).transpose()?;
+    let sender = query
+        .sender
+        .as_deref()
+        .map(|s| s.parse::<UserId>().map_err(|_| AppError::invalid("invalid sender")))
+        .transpose()?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let cursor = match query.cursor.as_deref() {
+        Some(raw) => Cursor::decode(raw)?,
+        None => Cursor { as_of: Utc::now(), offset: 0 },
+    };
+
+    // websearch_to_tsquery never raises on malformed input, so user text
+    // can't produce tsquery syntax errors.
+    let mut qb: QueryBuilder<Postgres> = QueryBuilder::new(
+        "WITH query AS (SELECT websearch_to_tsquery('english', ",
+    );
+    qb.push_bind(q);
+    qb.push(
+        ") AS tsq)
+         SELECT m.id, m.channel_id, m.sender_id, m.sender_name, m.content, m.created_at, m.deleted_at, m.edited_at,
+                m.redacted,
+                ts_headline('english', m.content, query.tsq,
+                            'StartSel=**, StopSel=**, MaxWords=30, MinWords=10, MaxFragments=2') AS headline,
+                (ts_rank(m.search_vector, query.tsq)
+                    / (1 + EXTRACT(EPOCH FROM (",
+    );
+    qb.push_bind(cursor.as_of);
+    qb.push(" - m.created_at)) / ");
+    qb.push_bind(RANK_HALF_LIFE_SECS as f64);
+    qb.push(
+        "))::float8 AS rank
+         FROM messages m, query
+         WHERE m.search_vector @@ query.tsq
+           AND m.deleted_at IS NULL
+           AND m.channel_id IN (SELECT channel_id FROM channel_members WHERE user_id = ",
+    );
+    qb.push_bind(&user.user_id);
+    qb.push(") AND m.created_at <= ").push_bind(cursor.as_of);
+    if let Some(channel_id) = &channel_id {
+        qb.push(" AND m.channel_id = ").push_bind(channel_id);
+    }
+    if let Some(sender) = &sender {
+        qb.push(" AND m.sender_id = ").push_bind(sender);
+    }
+    if let Some(from) = query.from {
+        qb.push(" AND m.created_at >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+        qb.push(" AND m.created_at < ").push_bind(to);
+    }
+    qb.push(" ORDER BY rank DESC, m.created_at DESC, m.id DESC LIMIT ");
+    // One extra row tells us whether another page exists.
+    qb.push_bind(limit + 1);
+    qb.push(" OFFSET ").push_bind(cursor.offset);
+
+    let rows: Vec<HitRow> =
+        uchat_metrics::time_db_query("search_messages", qb.build_query_as().fetch_all(&state.db)).await?;
+
+    let has_more = rows.len() as i64 > limit;
+    let items: Vec<SearchHit> = rows
+        .into_iter()
+        .take(limit as usize)
+        .map(|row| SearchHit {
+            message: Message::from(row.message),
+            headline: row.headline,
+            rank: row.rank,
+        })
+        .collect();
+
+    let next_cursor = has_more.then(|| {
+        Cursor { as_of: cursor.as_of, offset: cursor.offset + limit }.encode()
+    });
+
+    Ok(Json(Page { items, next_cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::{call, create};
+    use crate::messages::tests::insert;
+    use crate::test_state;
+    use axum::http::{Method, StatusCode};
+    use serde_json::Value;
+
+    fn contents(page: &Value) -> Vec<String> {
+        page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hit| hit["message"]["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    async fn search(state: &Arc<AppState>, user: &UserId, params: &str) -> (StatusCode, Value) {
+        call(state, Method::GET, &format!("/api/search/messages?{}", params), Some(user), None).await
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let cursor = Cursor { as_of: DateTime::from_timestamp_micros(1_700_000_000_000_000).unwrap(), offset: 40 };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::decode("12_-1").is_err());
+        assert!(Cursor::decode("nonsense").is_err());
+    }
+
+    #[tokio::test]
+    async fn phrases_exclusions_and_recency() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let channel = create(&state, &owner, "search", "public").await;
+
+        let now = Utc::now();
+        let days = chrono::Duration::days;
+        insert(&state.db, &channel, &owner, "old zebra crossing notes", now - days(60)).await;
+        insert(&state.db, &channel, &owner, "new zebra crossing notes", now - days(1)).await;
+        insert(&state.db, &channel, &owner, "a zebra herd", now).await;
+
+        let (status, page) = search(&state, &owner, "q=zebra%20crossing").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(contents(&page), ["new zebra crossing notes", "old zebra crossing notes"]);
+        assert!(page["items"][0]["headline"].as_str().unwrap().contains("**zebra**"));
+
+        let (_, page) = search(&state, &owner, "q=%22zebra%20crossing%22%20-old").await;
+        assert_eq!(contents(&page), ["new zebra crossing notes"]);
+
+        let (_, page) = search(&state, &owner, "q=zebra&limit=2").await;
+        assert_eq!(contents(&page).len(), 2);
+        let cursor = page["next_cursor"].as_str().unwrap();
+        let (_, page) = search(&state, &owner, &format!("q=zebra&limit=2&cursor={}", cursor)).await;
+        assert_eq!(contents(&page).len(), 1);
+        assert!(page["next_cursor"].is_null());
+    }
+
+    #[tokio::test]
+    async fn only_member_channels_and_plaintext() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+        let outsider = UserId::new();
+        let channel = create(&state, &owner, "search-private", "private").await;
+
+        let now = Utc::now();
+        insert(&state.db, &channel, &owner, "quokka sighting", now).await;
+        let secret = insert(&state.db, &channel, &owner, "quokka ciphertext", now).await;
+        sqlx::query("UPDATE messages SET encrypted = true WHERE id = $1")
+            .bind(&secret)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        let (_, page) = search(&state, &owner, "q=quokka").await;
+        assert_eq!(contents(&page), ["quokka sighting"]);
+
+        let (_, page) = search(&state, &outsider, "q=quokka").await;
+        assert!(contents(&page).is_empty());
+
+        let (_, page) = search(&state, &owner, &format!("q=quokka&sender={}", outsider)).await;
+        assert!(contents(&page).is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_queries_are_not_errors() {
+        let Some(state) = test_state().await else { return };
+        let owner = UserId::new();
+
+        for q in ["%22unterminated", "a%20%26%20%7C%20!(", "-", "the"] {
+            let (status, page) = search(&state, &owner, &format!("q={}", q)).await;
+            assert_eq!(status, StatusCode::OK, "q={}", q);
+            assert!(contents(&page).is_empty());
+        }
+
+        let (status, _) = search(&state, &owner, "q=%20").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = search(&state, &owner, "q=x&cursor=bogus").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
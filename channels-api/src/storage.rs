@@ -0,0 +1,243 @@
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::{BoxStream, StreamExt, TryStreamExt};
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path as ObjectPath;
+use object_store::{GetOptions, GetRange, ObjectStore, WriteMultipart};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Where uploaded file contents live. Paths are relative keys chosen by
+/// the service (`{channel_id}/{file_id}`), never client input.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Recorded in `file_uploads.storage_backend`.
+    fn name(&self) -> &'static str;
+
+    /// Stores the whole stream at `path`. If the stream or the write
+    /// fails, nothing is left at `path`.
+    async fn put(&self, path: &str, data: ByteStream) -> io::Result<()>;
+
+    /// Streams the object, or just `range` of it.
+    async fn get(&self, path: &str, range: Option<Range<u64>>) -> io::Result<ByteStream>;
+
+    /// Removes the object; deleting a missing object is not an error.
+    async fn delete(&self, path: &str) -> io::Result<()>;
+}
+
+/// The whole object at `path`, for work that needs it in memory; objects
+/// over `limit` bytes are refused rather than read.
+pub async fn read_all(storage: &dyn StorageBackend, path: &str, limit: usize) -> io::Result<Vec<u8>> {
+    let mut stream = storage.get(path, None).await?;
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        if data.len() + chunk.len() > limit {
+            return Err(io::Error::other("object is larger than uploads may be"));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Picks the backend from `FILE_STORAGE` (`local`, the default, or `s3`).
+///
+/// `local` writes under `FILE_STORAGE_DIR` (default `./data/files`).
+/// `s3` is configured through the usual `AWS_*` variables, including
+/// `AWS_BUCKET` and `AWS_ENDPOINT` for S3-compatible stores.
+pub fn from_env() -> Result<Arc<dyn StorageBackend>, String> {
+    match std::env::var("FILE_STORAGE").unwrap_or_else(|_| "local".into()).as_str() {
+        "local" => {
+            let root = std::env::var("FILE_STORAGE_DIR").unwrap_or_else(|_| "./data/files".into());
+            Ok(Arc::new(LocalDisk::new(root)))
+        }
+        "s3" => {
+            let store = AmazonS3Builder::from_env().build().map_err(|e| e.to_string())?;
+            Ok(Arc::new(S3Storage::new(Arc::new(store))))
+        }
+        other => Err(format!("unknown FILE_STORAGE {:?}", other)),
+    }
+}
+
+pub struct LocalDisk {
+    root: PathBuf,
+}
+
+impl LocalDisk {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn full_path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalDisk {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, path: &str, mut data: ByteStream) -> io::Result<()> {
+        let target = self.full_path(path);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write beside the target and rename, so readers never see a
+        // half-written file.
+        let partial = target.with_extension("part");
+        let result = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            while let Some(chunk) = data.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            tokio::fs::rename(&partial, &target).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        result
+    }
+
+    async fn get(&self, path: &str, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.full_path(path)).await?;
+
+        match range {
+            Some(range) => {
+                file.seek(io::SeekFrom::Start(range.start)).await?;
+                let limited = tokio::io::AsyncReadExt::take(file, range.end - range.start);
+                Ok(ReaderStream::new(limited).boxed())
+            }
+            None => Ok(ReaderStream::new(file).boxed()),
+        }
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.full_path(path)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+}
+
+/// Any S3-compatible object store.
+pub struct S3Storage {
+    store: Arc<dyn ObjectStore>,
+}
+
+impl S3Storage {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self { store }
+    }
+}
+
+fn store_error(e: object_store::Error) -> io::Error {
+    match e {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, e),
+        e => io::Error::other(e),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, path: &str, mut data: ByteStream) -> io::Result<()> {
+        let upload = self.store.put_multipart(&ObjectPath::from(path)).await.map_err(store_error)?;
+        let mut writer = WriteMultipart::new(upload);
+
+        while let Some(chunk) = data.next().await {
+            match chunk {
+                Ok(chunk) => writer.write(&chunk),
+                Err(e) => {
+                    let _ = writer.abort().await;
+                    return Err(e);
+                }
+            }
+        }
+
+        writer.finish().await.map(|_| ()).map_err(store_error)
+    }
+
+    async fn get(&self, path: &str, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        let options = GetOptions {
+            range: range.map(|r| GetRange::Bounded(r.start as usize..r.end as usize)),
+            ..Default::default()
+        };
+        let result = self.store.get_opts(&ObjectPath::from(path), options).await.map_err(store_error)?;
+        Ok(result.into_stream().map_err(store_error).boxed())
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        match self.store.delete(&ObjectPath::from(path)).await {
+            Err(object_store::Error::NotFound { .. }) => Ok(()),
+            other => other.map_err(store_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use object_store::memory::InMemory;
+
+    fn chunks(parts: &[&'static [u8]]) -> ByteStream {
+        stream::iter(parts.iter().map(|p| Ok(Bytes::from_static(p))).collect::<Vec<_>>()).boxed()
+    }
+
+    async fn collect(stream: ByteStream) -> Vec<u8> {
+        let chunks: Vec<Bytes> = stream.try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    async fn round_trip(storage: &dyn StorageBackend) {
+        storage.put("c/f", chunks(&[b"hello ", b"world"])).await.unwrap();
+        assert_eq!(collect(storage.get("c/f", None).await.unwrap()).await, b"hello world");
+        assert_eq!(collect(storage.get("c/f", Some(6..11)).await.unwrap()).await, b"world");
+
+        storage.delete("c/f").await.unwrap();
+        assert!(storage.get("c/f", None).await.is_err());
+        storage.delete("c/f").await.unwrap();
+    }
+
+    async fn failed_put_leaves_nothing(storage: &dyn StorageBackend) {
+        let failing = stream::iter(vec![
+            Ok(Bytes::from_static(b"partial")),
+            Err(io::Error::other("client went away")),
+        ])
+        .boxed();
+
+        assert!(storage.put("c/broken", failing).await.is_err());
+        assert!(storage.get("c/broken", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_disk() {
+        let dir = std::env::temp_dir().join(format!("uchat-storage-{}", uchat_proto::ids::FileId::new()));
+        let storage = LocalDisk::new(&dir);
+        round_trip(&storage).await;
+        failed_put_leaves_nothing(&storage).await;
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn object_store() {
+        let storage = S3Storage::new(Arc::new(InMemory::new()));
+        round_trip(&storage).await;
+        failed_put_leaves_nothing(&storage).await;
+    }
+}
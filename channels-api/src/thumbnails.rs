@@ -0,0 +1,222 @@
+//! Thumbnails for uploaded images, made in the background once the upload
+//! has been answered. Images are decoded only after their header shows a
+//! pixel count within `MAX_PIXELS`, turned upright by their EXIF
+//! orientation and re-encoded, which leaves their metadata behind.
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures_util::stream::StreamExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+
+use uchat_proto::ids::FileId;
+
+use crate::{storage, AppState};
+
+/// Larger images are left without thumbnails rather than decoded.
+const MAX_PIXELS: u64 = 40_000_000;
+const MAX_DIMENSION: u32 = 16_384;
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailSize {
+    #[default]
+    Small,
+    Medium,
+}
+
+impl ThumbnailSize {
+    pub const ALL: [ThumbnailSize; 2] = [ThumbnailSize::Small, ThumbnailSize::Medium];
+
+    /// The longest edge; smaller images keep their size.
+    fn max_edge(self) -> u32 {
+        match self {
+            ThumbnailSize::Small => 160,
+            ThumbnailSize::Medium => 640,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ThumbnailSize::Small => "small",
+            ThumbnailSize::Medium => "medium",
+        }
+    }
+}
+
+/// Where the `size` thumbnail of the file at `storage_path` is kept.
+pub fn path(storage_path: &str, size: ThumbnailSize) -> String {
+    format!("{}.thumb-{}", storage_path, size.as_str())
+}
+
+/// Whether uploads sniffed as `detected_mime_type` get thumbnails.
+pub fn supported(detected_mime_type: Option<&str>) -> bool {
+    matches!(detected_mime_type, Some("image/png" | "image/jpeg" | "image/gif" | "image/webp"))
+}
+
+/// Each thumbnail, encoded as the content type returned with them: JPEG,
+/// or PNG for images with transparency.
+#[derive(Debug)]
+pub struct Rendered {
+    pub mime_type: &'static str,
+    pub images: Vec<(ThumbnailSize, Vec<u8>)>,
+}
+
+/// Decodes `data` within the limits and renders every size.
+pub fn render(data: &[u8]) -> Result<Rendered, String> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format().map_err(|e| e.to_string())?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP)) {
+        return Err("not a supported image".into());
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+    let (width, height) = decoder.dimensions();
+    if u64::from(width) * u64::from(height) > MAX_PIXELS {
+        return Err(format!("{}x{} is more pixels than allowed", width, height));
+    }
+    let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    image.apply_orientation(orientation);
+
+    let opaque = !image.color().has_alpha();
+    let mut images = Vec::new();
+    for size in ThumbnailSize::ALL {
+        let edge = size.max_edge();
+        let scaled = if image.width() > edge || image.height() > edge {
+            image.resize(edge, edge, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        let mut out = Vec::new();
+        let encoded = if opaque {
+            scaled.to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))
+        } else {
+            scaled.write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        };
+        encoded.map_err(|e| e.to_string())?;
+        images.push((size, out));
+    }
+    Ok(Rendered { mime_type: if opaque { "image/jpeg" } else { "image/png" }, images })
+}
+
+/// Makes the file's thumbnails and records their type, logging rather
+/// than failing: a file without thumbnails is still a file.
+pub fn spawn(state: Arc<AppState>, file_id: FileId, storage_path: String) {
+    uchat_metrics::spawn_task("thumbnail", async move {
+        if let Err(e) = generate(&state, &file_id, &storage_path).await {
+            tracing::info!(file_id = %file_id, "no thumbnail: {}", e);
+        }
+    });
+}
+
+async fn generate(state: &AppState, file_id: &FileId, storage_path: &str) -> Result<(), String> {
+    let limit = state.file_policy.max_file_bytes as usize;
+    let data = storage::read_all(&*state.storage, storage_path, limit).await.map_err(|e| e.to_string())?;
+    let rendered = tokio::task::spawn_blocking(move || render(&data)).await.map_err(|e| e.to_string())??;
+    for (size, image) in rendered.images {
+        let stream = futures_util::stream::once(async move { Ok(Bytes::from(image)) }).boxed();
+        state.storage.put(&path(storage_path, size), stream).await.map_err(|e| e.to_string())?;
+    }
+
+    // The file may have been deleted meanwhile; then nothing is updated
+    // and the thumbnails are removed again.
+    let updated = sqlx::query("UPDATE file_uploads SET thumbnail_mime_type = $2 WHERE id = $1")
+        .bind(file_id)
+        .bind(rendered.mime_type)
+        .execute(&state.db)
+        .await
+        .map_err(|e| e.to_string())?;
+    if updated.rows_affected() == 0 {
+        delete(state, storage_path).await;
+    }
+    Ok(())
+}
+
+/// Removes the thumbnails of the file at `storage_path`, if it has any.
+pub async fn delete(state: &AppState, storage_path: &str) {
+    for size in ThumbnailSize::ALL {
+        let path = path(storage_path, size);
+        if let Err(e) = state.storage.delete(&path).await {
+            tracing::warn!("deleting {} from storage failed: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage, Rgba, RgbaImage};
+
+    pub fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        RgbaImage::from_pixel(width, height, Rgba([10, 120, 200, 128]))
+            .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+            .unwrap();
+        out
+    }
+
+    /// A JPEG whose EXIF says to rotate it 90° clockwise for display.
+    fn rotated_jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        RgbImage::from_pixel(width, height, Rgb([200, 30, 30]))
+            .write_with_encoder(JpegEncoder::new(&mut jpeg))
+            .unwrap();
+        // Big-endian TIFF with one IFD entry: Orientation (0x0112) = 6.
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0".to_vec();
+        let mut app1 = vec![0xff, 0xe1];
+        app1.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        app1.append(&mut exif);
+        jpeg.splice(2..2, app1);
+        jpeg
+    }
+
+    #[test]
+    fn scales_down_to_each_size_and_keeps_small_images() {
+        let rendered = render(&rotated_jpeg(1200, 600)).unwrap();
+        assert_eq!(rendered.mime_type, "image/jpeg");
+        let dims: Vec<(u32, u32)> =
+            rendered.images.iter().map(|(_, data)| image::load_from_memory(data).unwrap().dimensions()).collect();
+        // Upright, so portrait.
+        assert_eq!(dims, [(80, 160), (320, 640)]);
+
+        let rendered = render(&png(100, 50)).unwrap();
+        assert_eq!(rendered.mime_type, "image/png");
+        let small = image::load_from_memory(&rendered.images[0].1).unwrap();
+        assert_eq!(small.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn thumbnails_carry_no_metadata() {
+        let rendered = render(&rotated_jpeg(300, 200)).unwrap();
+        for (_, data) in &rendered.images {
+            assert!(!data.windows(4).any(|w| w == b"Exif"));
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_and_corrupt_images_before_decoding() {
+        // A real header claiming 10000x10000, with the IHDR checksum fixed
+        // up, so only the pixel count can refuse it.
+        let mut bomb = png(1, 1);
+        bomb[16..20].copy_from_slice(&10_000u32.to_be_bytes());
+        bomb[20..24].copy_from_slice(&10_000u32.to_be_bytes());
+        let crc = crc32fast::hash(&bomb[12..29]);
+        bomb[29..33].copy_from_slice(&crc.to_be_bytes());
+        assert!(render(&bomb).unwrap_err().contains("more pixels"));
+
+        let mut truncated = png(40, 40);
+        truncated.truncate(truncated.len() / 2);
+        assert!(render(&truncated).is_err());
+        assert!(render(b"GIF89a not really").is_err());
+        assert!(render(b"plain text").is_err());
+    }
+}
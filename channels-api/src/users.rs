@@ -0,0 +1,555 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::stream::StreamExt;
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::UserId;
+use uchat_proto::users::{avatar_path, avatar_url, UpdateProfile, UserProfile, AVATAR_SIZES};
+
+use crate::auth::AuthUser;
+use crate::error::AppError;
+use crate::files::too_large;
+use crate::AppState;
+
+const MAX_AVATAR_BYTES: usize = 5 * 1024 * 1024;
+/// Largest accepted source image, in pixels per side.
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+const MAX_BATCH_IDS: usize = 100;
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+const MAX_BIO_LEN: usize = 500;
+const MAX_EMAIL_LEN: usize = 254;
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: UserId,
+    username: String,
+    display_name: Option<String>,
+    avatar_version: Option<String>,
+    bio: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<UserRow> for UserProfile {
+    fn from(row: UserRow) -> Self {
+        UserProfile {
+            avatar_url: row.avatar_version.map(|v| avatar_url(&row.id, &v)),
+            id: row.id,
+            username: row.username,
+            display_name: row.display_name,
+            bio: row.bio,
+            created_at: row.created_at,
+            online: None,
+            email: None,
+            email_notifications: None,
+        }
+    }
+}
+
+/// A user's row with the settings only they may see.
+#[derive(sqlx::FromRow)]
+struct OwnRow {
+    #[sqlx(flatten)]
+    user: UserRow,
+    email: Option<String>,
+    email_notifications: String,
+}
+
+impl From<OwnRow> for UserProfile {
+    fn from(row: OwnRow) -> Self {
+        UserProfile {
+            email: row.email,
+            email_notifications: row.email_notifications.parse().ok(),
+            ..row.user.into()
+        }
+    }
+}
+
+const USER_COLUMNS: &str = "id, username, display_name, avatar_version, bio, created_at";
+const OWN_COLUMNS: &str = "id, username, display_name, avatar_version, bio, created_at, email, email_notifications";
+
+fn parse_user_id(raw: &str) -> Result<UserId, AppError> {
+    raw.parse().map_err(|_| AppError::invalid("invalid user id"))
+}
+
+async fn load_profile(state: &AppState, user_id: &UserId) -> Result<Json<UserProfile>, AppError> {
+    let row: Option<UserRow> = sqlx::query_as(&format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS))
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await?;
+
+    Ok(Json(row.ok_or_else(AppError::not_found)?.into()))
+}
+
+/// Fills in `online` from the gateway; left unset when it can't be asked.
+async fn attach_presence(state: &AppState, profiles: &mut [UserProfile]) {
+    let Some(gateway) = &state.gateway else { return };
+    if profiles.is_empty() {
+        return;
+    }
+    let ids: Vec<UserId> = profiles.iter().map(|p| p.id.clone()).collect();
+    if let Some(online) = gateway.online(&ids).await {
+        for profile in profiles {
+            profile.online = Some(online.contains(&profile.id));
+        }
+    }
+}
+
+/// GET /api/users/{id}
+pub async fn get_user(
+    State(state): State<Arc<AppState>>,
+    _user: AuthUser,
+    Path(id): Path<String>,
+) -> Result<Json<UserProfile>, AppError> {
+    let Json(mut profile) = load_profile(&state, &parse_user_id(&id)?).await?;
+    attach_presence(&state, std::slice::from_mut(&mut profile)).await;
+    Ok(Json(profile))
+}
+
+/// GET /api/users/me
+///
+/// The caller's profile, with their email settings.
+pub async fn get_me(State(state): State<Arc<AppState>>, user: AuthUser) -> Result<Json<UserProfile>, AppError> {
+    let row: Option<OwnRow> = sqlx::query_as(&format!("SELECT {} FROM users WHERE id = $1", OWN_COLUMNS))
+        .bind(&user.user_id)
+        .fetch_optional(&state.db)
+        .await?;
+
+    Ok(Json(row.ok_or_else(AppError::not_found)?.into()))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BatchQuery {
+    ids: String,
+}
+
+/// GET /api/users?ids=a,b,c
+///
+/// Profiles for up to 100 users in one call; unknown ids are left out.
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _user: AuthUser,
+    Query(query): Query<BatchQuery>,
+) -> Result<Json<Vec<UserProfile>>, AppError> {
+    let ids = query
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| parse_user_id(s).map(UserId::into_inner))
+        .collect::<Result<Vec<_>, _>>()?;
+    if ids.len() > MAX_BATCH_IDS {
+        return Err(AppError::invalid(format!("at most {} ids per request", MAX_BATCH_IDS)));
+    }
+
+    let rows: Vec<UserRow> =
+        sqlx::query_as(&format!("SELECT {} FROM users WHERE id = ANY($1) ORDER BY username", USER_COLUMNS))
+            .bind(&ids)
+            .fetch_all(&state.db)
+            .await?;
+
+    let mut profiles: Vec<UserProfile> = rows.into_iter().map(UserProfile::from).collect();
+    attach_presence(&state, &mut profiles).await;
+    Ok(Json(profiles))
+}
+
+/// PATCH /api/users/me
+pub async fn update_me(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    Json(body): Json<UpdateProfile>,
+) -> Result<Json<UserProfile>, AppError> {
+    let display_name = body.display_name.as_deref().map(str::trim);
+    if display_name.is_some_and(|n| n.chars().count() > MAX_DISPLAY_NAME_LEN) {
+        return Err(AppError::invalid(format!("display_name must be at most {} characters", MAX_DISPLAY_NAME_LEN)));
+    }
+    let bio = body.bio.as_deref().map(str::trim);
+    if bio.is_some_and(|b| b.chars().count() > MAX_BIO_LEN) {
+        return Err(AppError::invalid(format!("bio must be at most {} characters", MAX_BIO_LEN)));
+    }
+
+    let email = body.email.as_deref().map(str::trim);
+    if email.is_some_and(|e| !e.is_empty() && !valid_email(e)) {
+        return Err(AppError::invalid("email is not a valid address"));
+    }
+
+    let row: Option<OwnRow> = sqlx::query_as(&format!(
+        "UPDATE users
+         SET display_name = CASE WHEN $2::text IS NULL THEN display_name ELSE NULLIF($2, '') END,
+             bio = CASE WHEN $3::text IS NULL THEN bio ELSE NULLIF($3, '') END,
+             email = CASE WHEN $4::text IS NULL THEN email ELSE NULLIF($4, '') END,
+             email_notifications = COALESCE($5, email_notifications)
+         WHERE id = $1
+         RETURNING {}",
+        OWN_COLUMNS
+    ))
+    .bind(&user.user_id)
+    .bind(display_name)
+    .bind(bio)
+    .bind(email)
+    .bind(body.email_notifications.map(|n| n.as_str()))
+    .fetch_optional(&state.db)
+    .await?;
+
+    Ok(Json(row.ok_or_else(AppError::not_found)?.into()))
+}
+
+/// A single `local@domain` address with nothing that could break out of
+/// an email header.
+fn valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else { return false };
+    email.len() <= MAX_EMAIL_LEN
+        && !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | ',' | ';' | '"'))
+        && !domain.contains('@')
+}
+
+/// Decodes `data` as PNG, JPEG, GIF or WebP within the size limits and
+/// renders it as a square PNG for each of `AVATAR_SIZES`.
+fn render_avatar(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, AppError> {
+    let not_image = || AppError::invalid("avatar must be a PNG, JPEG, GIF or WebP image");
+
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format().map_err(|_| not_image())?;
+    if !matches!(
+        reader.format(),
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP)
+    ) {
+        return Err(not_image());
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    reader.limits(limits);
+
+    let image = reader.decode().map_err(|e| match e {
+        image::ImageError::Limits(_) => AppError::invalid(format!(
+            "avatar must be at most {}x{} pixels",
+            MAX_AVATAR_DIMENSION, MAX_AVATAR_DIMENSION
+        )),
+        _ => not_image(),
+    })?;
+
+    AVATAR_SIZES
+        .iter()
+        .map(|&size| {
+            let mut png = Vec::new();
+            image
+                .resize_to_fill(size, size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+                .map_err(|_| not_image())?;
+            Ok((size, png))
+        })
+        .collect()
+}
+
+/// PUT /api/users/me/avatar
+///
+/// Takes the raw image as the request body. The old avatar's objects are
+/// removed once the new one is in place.
+pub async fn put_avatar(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    body: Body,
+) -> Result<Json<UserProfile>, AppError> {
+    let data = axum::body::to_bytes(body, MAX_AVATAR_BYTES)
+        .await
+        .map_err(|_| too_large("avatar exceeds the 5 MiB limit"))?;
+
+    let rendered = tokio::task::spawn_blocking(move || render_avatar(&data))
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "avatar rendering task failed");
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "could not process avatar")
+        })??;
+
+    let previous: Option<Option<String>> = sqlx::query_scalar("SELECT avatar_version FROM users WHERE id = $1")
+        .bind(&user.user_id)
+        .fetch_optional(&state.db)
+        .await?;
+    let previous = previous.ok_or_else(AppError::not_found)?;
+
+    let version = format!("{:x}", Utc::now().timestamp_micros());
+    for (size, png) in rendered {
+        let path = avatar_path(&user.user_id, &version, size);
+        let stream = futures_util::stream::once(async move { Ok(Bytes::from(png)) }).boxed();
+        state.storage.put(&path, stream).await.map_err(|e| {
+            tracing::warn!("storing avatar {} failed: {}", path, e);
+            AppError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "storage error")
+        })?;
+    }
+
+    let row: UserRow = sqlx::query_as(&format!(
+        "UPDATE users SET avatar_version = $2 WHERE id = $1 RETURNING {}",
+        USER_COLUMNS
+    ))
+    .bind(&user.user_id)
+    .bind(&version)
+    .fetch_one(&state.db)
+    .await?;
+
+    if let Some(old) = previous {
+        for &size in AVATAR_SIZES {
+            let path = avatar_path(&user.user_id, &old, size);
+            if let Err(e) = state.storage.delete(&path).await {
+                tracing::warn!("deleting old avatar {} failed: {}", path, e);
+            }
+        }
+    }
+
+    Ok(Json(row.into()))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AvatarQuery {
+    size: Option<u32>,
+}
+
+/// GET /api/users/{id}/avatar?size=
+///
+/// Public so it can back an `<img src>`. Serves the smallest stored size
+/// that is at least `size` (the largest by default). URLs carry the
+/// avatar version, so responses are cached as immutable.
+pub async fn get_avatar(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AvatarQuery>,
+) -> Result<Response, AppError> {
+    let user_id = parse_user_id(&id)?;
+    let version: Option<Option<String>> = sqlx::query_scalar("SELECT avatar_version FROM users WHERE id = $1")
+        .bind(&user_id)
+        .fetch_optional(&state.db)
+        .await?;
+    let version = version.flatten().ok_or_else(AppError::not_found)?;
+
+    let largest = AVATAR_SIZES[AVATAR_SIZES.len() - 1];
+    let wanted = query.size.unwrap_or(largest);
+    let size = AVATAR_SIZES.iter().copied().find(|&s| s >= wanted).unwrap_or(largest);
+
+    let stream = state
+        .storage
+        .get(&avatar_path(&user_id, &version, size), None)
+        .await
+        .map_err(|_| AppError::not_found())?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels::tests::call;
+    use crate::{app, test_state};
+    use axum::http::{Method, Request};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+    use crate::gateway::GatewayNotifier;
+    use uchat_proto::jwt::create_token;
+    use uchat_proto::users::UserPresence;
+
+    async fn user(db: &PgPool) -> UserId {
+        let id = UserId::new();
+        sqlx::query("INSERT INTO users (id, username) VALUES ($1, $2)")
+            .bind(&id)
+            .bind(format!("user-{}", id))
+            .execute(db)
+            .await
+            .unwrap();
+        id
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        image::RgbImage::from_pixel(width, height, image::Rgb([200, 30, 30]))
+            .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+            .unwrap();
+        out
+    }
+
+    async fn upload(state: &Arc<AppState>, user: &UserId, data: Vec<u8>) -> (StatusCode, Value) {
+        let req = Request::put("/api/users/me/avatar")
+            .header("Authorization", format!("Bearer {}", create_token(&state.jwt_secret, user.as_str())))
+            .header("Content-Type", "image/png")
+            .body(Body::from(data))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    async fn fetch(state: &Arc<AppState>, uri: &str) -> (StatusCode, Bytes) {
+        let resp = app(state.clone()).oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let status = resp.status();
+        (status, axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap())
+    }
+
+    #[test]
+    fn renders_square_sizes() {
+        let rendered = render_avatar(&png(300, 120)).unwrap();
+        let sizes: Vec<_> = rendered
+            .iter()
+            .map(|(size, data)| {
+                let image = image::load_from_memory(data).unwrap();
+                (*size, image.width(), image.height())
+            })
+            .collect();
+        assert_eq!(sizes, [(64, 64, 64), (256, 256, 256)]);
+
+        assert!(render_avatar(b"GIF89a not really").is_err());
+        assert!(render_avatar(b"plain text").is_err());
+        assert!(render_avatar(&png(MAX_AVATAR_DIMENSION + 1, 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn profiles_single_and_batched() {
+        let Some(state) = test_state().await else { return };
+        let a = user(&state.db).await;
+        let b = user(&state.db).await;
+
+        let (status, profile) = call(&state, Method::GET, &format!("/api/users/{}", a), Some(&b), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["username"], format!("user-{}", a));
+        assert!(profile["avatar_url"].is_null());
+
+        let (status, profile) =
+            call(&state, Method::PATCH, "/api/users/me", Some(&a), Some(json!({ "display_name": " Ada " }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["display_name"], "Ada");
+        assert!(profile["bio"].is_null());
+
+        let (status, profile) =
+            call(&state, Method::PATCH, "/api/users/me", Some(&a), Some(json!({ "bio": "Counts things." }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["bio"], "Counts things.");
+        assert_eq!(profile["display_name"], "Ada");
+        let (status, _) =
+            call(&state, Method::PATCH, "/api/users/me", Some(&a), Some(json!({ "bio": "x".repeat(501) }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let body = json!({ "email": " ada@example.com ", "email_notifications": "all" });
+        let (status, profile) = call(&state, Method::PATCH, "/api/users/me", Some(&a), Some(body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&profile["email"], &profile["email_notifications"]), (&json!("ada@example.com"), &json!("all")));
+        let (_, profile) = call(&state, Method::GET, "/api/users/me", Some(&a), None).await;
+        assert_eq!(profile["email"], "ada@example.com");
+        let (_, profile) = call(&state, Method::GET, &format!("/api/users/{}", a), Some(&b), None).await;
+        assert!(profile.get("email").is_none() && profile.get("email_notifications").is_none());
+        for email in ["ada", "ada@localhost", "ada@example.com\r\nBcc: x@example.com", "<ada@example.com>"] {
+            let (status, _) = call(&state, Method::PATCH, "/api/users/me", Some(&a), Some(json!({ "email": email }))).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", email);
+        }
+
+        let uri = format!("/api/users?ids={},{},{}", a, b, UserId::new());
+        let (status, list) = call(&state, Method::GET, &uri, Some(&a), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let mut ids: Vec<_> = list.as_array().unwrap().iter().map(|p| p["id"].as_str().unwrap()).collect();
+        ids.sort();
+        let mut expected = vec![a.as_str(), b.as_str()];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        let (status, _) = call(&state, Method::GET, "/api/users?ids=nope", Some(&a), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&state, Method::GET, &format!("/api/users/{}", UserId::new()), Some(&a), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn tokens_of_deleted_accounts_are_refused() {
+        let Some(state) = test_state().await else { return };
+        let a = user(&state.db).await;
+        assert_eq!(call(&state, Method::GET, "/api/users/me", Some(&a), None).await.0, StatusCode::OK);
+
+        sqlx::query("UPDATE users SET deleted_at = now() WHERE id = $1").bind(&a).execute(&state.db).await.unwrap();
+        let (status, body) = call(&state, Method::GET, "/api/users/me", Some(&a), None).await;
+        assert_eq!((status, &body["code"]), (StatusCode::UNAUTHORIZED, &json!("account_deleted")));
+    }
+
+    #[tokio::test]
+    async fn avatar_upload_and_replace() {
+        let Some(state) = test_state().await else { return };
+        let me = user(&state.db).await;
+
+        let (status, _) = upload(&state, &me, b"not an image".to_vec()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = upload(&state, &me, vec![0; MAX_AVATAR_BYTES + 1]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, profile) = upload(&state, &me, png(500, 400)).await;
+        assert_eq!(status, StatusCode::OK);
+        let first_url = profile["avatar_url"].as_str().unwrap().to_string();
+
+        let (status, data) = fetch(&state, &first_url).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(image::load_from_memory(&data).unwrap().width(), 256);
+        let (_, data) = fetch(&state, &format!("/api/users/{}/avatar?size=32", me)).await;
+        assert_eq!(image::load_from_memory(&data).unwrap().width(), 64);
+
+        let (_, profile) = upload(&state, &me, png(64, 64)).await;
+        assert_ne!(profile["avatar_url"], first_url.as_str());
+        let old_version = first_url.rsplit("v=").next().unwrap();
+        assert!(state.storage.get(&avatar_path(&me, old_version, 256), None).await.is_err());
+
+        let (status, _) = fetch(&state, &format!("/api/users/{}/avatar", UserId::new())).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn presence_comes_from_gateway() {
+        let Some(base) = test_state().await else { return };
+        let a = user(&base.db).await;
+        let b = user(&base.db).await;
+
+        let online = a.clone();
+        let fake_gateway = axum::Router::new().route(
+            "/internal/presence",
+            axum::routing::get(move || async move { Json(UserPresence { online: vec![online] }) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_gateway).await.unwrap() });
+
+        let (_, profile) = call(&base, Method::GET, &format!("/api/users/{}", a), Some(&b), None).await;
+        assert!(profile.get("online").is_none());
+
+        let state = Arc::new(AppState {
+            db: base.db.clone(),
+            jwt_secret: base.jwt_secret.clone(),
+            links: base.links.clone(),
+            gateway: Some(GatewayNotifier::new(&format!("http://{}", addr), "internal".into())),
+            storage: base.storage.clone(),
+            file_policy: base.file_policy.clone(),
+            edit_window: base.edit_window,
+            rate_limiter: crate::rate_limit::RateLimiter::new(crate::rate_limit::RateLimits::default()),
+            push: crate::push::PushDispatcher::default(),
+            scanning: crate::scanning::Scanning::default(),
+        });
+        let (_, profile) = call(&state, Method::GET, &format!("/api/users/{}", a), Some(&b), None).await;
+        assert_eq!(profile["online"], true);
+        let (_, list) = call(&state, Method::GET, &format!("/api/users?ids={}", b), Some(&a), None).await;
+        assert_eq!(list[0]["online"], false);
+    }
+}
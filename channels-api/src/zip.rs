@@ -0,0 +1,242 @@
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc;
+
+/// Buffered output handed downstream in chunks of about this size.
+const CHUNK: usize = 64 * 1024;
+/// 1980-01-01, the earliest date a zip entry can carry.
+const DOS_DATE: u16 = (1 << 5) | 1;
+/// Sizes in the entry headers come after the data, and names are UTF-8.
+const FLAGS: u16 = 0x0008 | 0x0800;
+
+struct Entry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    size: u32,
+}
+
+/// Streams a zip archive of uncompressed entries into `out` as it is
+/// written, so an archive never has to fit in memory. Each entry's CRC
+/// and size follow its data in a descriptor; the central directory at
+/// the end repeats them for readers.
+///
+/// Only the classic format is written, so the archive and every entry
+/// must stay under 4 GiB and 65535 entries; writes past that fail.
+pub struct ZipWriter {
+    out: mpsc::Sender<io::Result<Bytes>>,
+    buf: BytesMut,
+    offset: u64,
+    entries: Vec<Entry>,
+    current: Option<(crc32fast::Hasher, u64)>,
+}
+
+fn too_large() -> io::Error {
+    io::Error::other("archive too large for zip")
+}
+
+impl ZipWriter {
+    pub fn new(out: mpsc::Sender<io::Result<Bytes>>) -> Self {
+        Self { out, buf: BytesMut::with_capacity(CHUNK), offset: 0, entries: Vec::new(), current: None }
+    }
+
+    /// Ends the current entry, if any, and starts one called `name`.
+    pub async fn start_file(&mut self, name: &str) -> io::Result<()> {
+        self.end_file().await?;
+        if self.entries.len() == u16::MAX as usize {
+            return Err(too_large());
+        }
+        let offset = u32::try_from(self.offset).map_err(|_| too_large())?;
+
+        self.put_u32(0x04034b50);
+        self.put_u16(20);
+        self.put_u16(FLAGS);
+        self.put_u16(0); // stored
+        self.put_u16(0);
+        self.put_u16(DOS_DATE);
+        self.put_u32(0);
+        self.put_u32(0);
+        self.put_u32(0);
+        self.put_u16(name.len() as u16);
+        self.put_u16(0);
+        self.put_slice(name.as_bytes());
+
+        self.entries.push(Entry { name: name.to_string(), offset, crc: 0, size: 0 });
+        self.current = Some((crc32fast::Hasher::new(), 0));
+        self.flush_full().await
+    }
+
+    /// Appends to the current entry.
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some((hasher, size)) = &mut self.current else {
+            return Err(io::Error::other("write before start_file"));
+        };
+        hasher.update(data);
+        *size += data.len() as u64;
+        if *size > u32::MAX as u64 {
+            return Err(too_large());
+        }
+        self.put_slice(data);
+        self.flush_full().await
+    }
+
+    /// Writes the central directory and flushes everything downstream.
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.end_file().await?;
+        let start = u32::try_from(self.offset).map_err(|_| too_large())?;
+
+        let entries = std::mem::take(&mut self.entries);
+        for Entry { name, offset, crc, size } in &entries {
+            let (crc, size, offset) = (*crc, *size, *offset);
+            self.put_u32(0x02014b50);
+            self.put_u16(20);
+            self.put_u16(20);
+            self.put_u16(FLAGS);
+            self.put_u16(0);
+            self.put_u16(0);
+            self.put_u16(DOS_DATE);
+            self.put_u32(crc);
+            self.put_u32(size);
+            self.put_u32(size);
+            self.put_u16(name.len() as u16);
+            self.put_u16(0);
+            self.put_u16(0);
+            self.put_u16(0);
+            self.put_u16(0);
+            self.put_u32(0);
+            self.put_u32(offset);
+            self.put_slice(name.as_bytes());
+            self.flush_full().await?;
+        }
+
+        let directory_size = u32::try_from(self.offset - start as u64).map_err(|_| too_large())?;
+        let count = entries.len() as u16;
+        self.put_u32(0x06054b50);
+        self.put_u16(0);
+        self.put_u16(0);
+        self.put_u16(count);
+        self.put_u16(count);
+        self.put_u32(directory_size);
+        self.put_u32(start);
+        self.put_u16(0);
+        self.flush().await
+    }
+
+    async fn end_file(&mut self) -> io::Result<()> {
+        let Some((hasher, size)) = self.current.take() else { return Ok(()) };
+        let crc = hasher.finalize();
+        let entry = self.entries.last_mut().expect("open entry");
+        entry.crc = crc;
+        entry.size = size as u32;
+
+        self.put_u32(0x08074b50);
+        self.put_u32(crc);
+        self.put_u32(size as u32);
+        self.put_u32(size as u32);
+        self.flush_full().await
+    }
+
+    fn put_u16(&mut self, v: u16) {
+        self.buf.put_u16_le(v);
+        self.offset += 2;
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        self.buf.put_u32_le(v);
+        self.offset += 4;
+    }
+
+    fn put_slice(&mut self, data: &[u8]) {
+        self.buf.put_slice(data);
+        self.offset += data.len() as u64;
+    }
+
+    async fn flush_full(&mut self) -> io::Result<()> {
+        if self.buf.len() >= CHUNK {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = self.buf.split().freeze();
+        self.out.send(Ok(chunk)).await.map_err(|_| io::Error::other("archive reader went away"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn u16_at(b: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([b[at], b[at + 1]])
+    }
+
+    fn u32_at(b: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+    }
+
+    /// Reads a stored-only archive back through its central directory,
+    /// checking each entry's CRC.
+    pub fn read_entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), 0x06054b50);
+        let count = u16_at(archive, end + 10) as usize;
+        let mut at = u32_at(archive, end + 16) as usize;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(archive, at), 0x02014b50);
+            let crc = u32_at(archive, at + 16);
+            let size = u32_at(archive, at + 24) as usize;
+            let name_len = u16_at(archive, at + 28) as usize;
+            let local = u32_at(archive, at + 42) as usize;
+            let name = String::from_utf8(archive[at + 46..at + 46 + name_len].to_vec()).unwrap();
+
+            assert_eq!(u32_at(archive, local), 0x04034b50);
+            let data_at = local + 30 + u16_at(archive, local + 26) as usize;
+            let data = archive[data_at..data_at + size].to_vec();
+            assert_eq!(crc32fast::hash(&data), crc, "{}", name);
+            assert_eq!(u32_at(archive, data_at + size), 0x08074b50);
+
+            entries.push((name, data));
+            at += 46 + name_len;
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn writes_a_readable_archive() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let writer = tokio::spawn(async move {
+            let mut zip = ZipWriter::new(tx);
+            zip.start_file("a.json").await?;
+            zip.write(b"{\"a\":").await?;
+            zip.write(b"1}").await?;
+            zip.start_file("files/empty.txt").await?;
+            zip.start_file("files/big.bin").await?;
+            zip.write(&vec![7u8; 3 * CHUNK]).await?;
+            zip.finish().await
+        });
+
+        let mut archive = Vec::new();
+        let mut chunks = 0;
+        while let Some(chunk) = rx.recv().await {
+            archive.extend_from_slice(&chunk.unwrap());
+            chunks += 1;
+        }
+        writer.await.unwrap().unwrap();
+        assert!(chunks > 1);
+
+        let entries = read_entries(&archive);
+        let names: Vec<&str> = entries.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["a.json", "files/empty.txt", "files/big.bin"]);
+        assert_eq!(entries[0].1, b"{\"a\":1}");
+        assert!(entries[1].1.is_empty());
+        assert_eq!(entries[2].1.len(), 3 * CHUNK);
+    }
+}
@@ -7,9 +7,10 @@ use futures_util::SinkExt;
 
 use tungstenite::protocol::Message;
 
+use uchat_proto::errors::ErrorCode;
 use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::ids::{RoomId, UserId};
 
-use serde_json;
 use anyhow::Result;
 
 #[tokio::main]
@@ -52,29 +53,40 @@ async fn handle_chat(
         }
     });
 
+    // No auth here, so each connection gets an ephemeral sender id.
+    let conn_id = UserId::new();
+
     let msg_tx_clone = msg_tx.clone();
     let mut rx2 = rx.resubscribe();
     let broadcast_task = tokio::spawn(async move {
-        while let Ok(content) = rx2.recv().await {
-            let evt = ServerEvent::MessageBroadcast {
-                from: "chat-service".into(),
-                content,
-            };
-            let _ = msg_tx_clone.send(Message::Text(serde_json::to_string(&evt).unwrap()));
+        while let Ok(json) = rx2.recv().await {
+            let _ = msg_tx_clone.send(Message::Text(json));
         }
     });
 
     while let Some(msg) = ws_read.next().await {
         if let Ok(Message::Text(text)) = msg {
             match serde_json::from_str::<ClientEvent>(&text) {
-                Ok(ClientEvent::SendMessage { content }) => {
-                    let _ = tx.send(content);
+                Ok(ClientEvent::SendMessage { room_id, content, encrypted, content_type, thread_id }) => {
+                    let evt = ServerEvent::MessageBroadcast {
+                        room_id: RoomId { channel: room_id, thread: thread_id },
+                        from: conn_id.clone(),
+                        content,
+                        encrypted,
+                        content_type,
+                        seq: None,
+                        bridged_from: None,
+                        sender_name: None,
+                        message_id: None,
+                        sender_role: None,
+                        redacted: false,
+                        traceparent: None,
+                    };
+                    let _ = tx.send(serde_json::to_string(&evt).unwrap());
                 }
                 Ok(_) => {}
                 Err(_) => {
-                    let err = ServerEvent::Error {
-                        details: "Invalid event".into(),
-                    };
+                    let err = ServerEvent::error(ErrorCode::InvalidEvent, "Invalid event");
                     let _ = msg_tx.send(Message::Text(serde_json::to_string(&err).unwrap()));
                 }
             }
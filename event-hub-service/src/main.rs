@@ -1,75 +1,265 @@
+mod stun;
+
 use tokio::net::TcpListener;
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::broadcast;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 use anyhow::Result;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::Instrument;
+
+/// How many frames late joiners are sent on connect, from
+/// `EVENT_HUB_REPLAY_FRAMES`.
+const DEFAULT_REPLAY_FRAMES: usize = 100;
+/// How many frames a connection may fall behind by before it is
+/// dropped; one slow reader must not hold up the rest.
+const SUBSCRIBER_QUEUE: usize = 1024;
+/// Longest line accepted; a connection that sends more without a newline
+/// is dropped rather than buffered without end.
+const MAX_FRAME: usize = 1 << 20;
+
+/// One newline-terminated line, as the gateway's hub client writes them,
+/// shared by every queue it is relayed to.
+type Frame = Arc<[u8]>;
+
+/// The hub relays one stream, so it has one channel: everyone connected,
+/// and the last frames relayed for whoever connects next.
+struct AppState {
+    hub: Mutex<Hub>,
+    replay_capacity: usize,
+    queue_capacity: usize,
+}
+
+/// Kept under one lock, so a connection joins either before a frame is
+/// relayed (and is sent it) or after (and replays it), never both.
+#[derive(Default)]
+struct Hub {
+    subscribers: HashMap<Uuid, mpsc::Sender<Frame>>,
+    replay: VecDeque<Frame>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    uchat_telemetry::init("event-hub-service", env!("CARGO_PKG_VERSION"));
+    uchat_metrics::init("event-hub-service", &uchat_metrics::addr_from_env("0.0.0.0:9702")).await?;
+
+    let replay_capacity = std::env::var("EVENT_HUB_REPLAY_FRAMES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_REPLAY_FRAMES);
     let listener = TcpListener::bind("127.0.0.1:9700").await?;
-    let (tx, _) = broadcast::channel::<(Uuid, Vec<u8>)>(100);
+    uchat_metrics::spawn_task("stun_listener", stun::serve(TcpListener::bind(stun::addr_from_env()).await?));
+    tracing::info!("event-hub-service relaying on 127.0.0.1:9700");
+    relay(listener, replay_capacity, SUBSCRIBER_QUEUE).await
+}
 
-    let connections = Arc::new(
-        tokio::sync::Mutex::new(
-            std::collections::HashMap::<Uuid, Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>>::new()
-        )
-    );
+/// Accepts connections and writes every complete line each one sends to
+/// all the others. New connections are first sent the last
+/// `replay_capacity` lines; each has its own queue of `queue_capacity`
+/// frames and is dropped when that fills or a write fails.
+async fn relay(listener: TcpListener, replay_capacity: usize, queue_capacity: usize) -> Result<()> {
+    let state = Arc::new(AppState { hub: Mutex::new(Hub::default()), replay_capacity, queue_capacity });
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
         let id = Uuid::new_v4();
+        let span = tracing::info_span!("connection", connection_id = %id, peer = %peer);
+        span.in_scope(|| tracing::info!("connected"));
 
         let (read_half, write_half) = stream.into_split();
+        let (tx, rx) = mpsc::channel(state.queue_capacity);
+        let replay: Vec<Frame> = {
+            let mut hub = state.hub.lock().unwrap();
+            hub.subscribers.insert(id, tx);
+            hub.replay.iter().cloned().collect()
+        };
+        uchat_metrics::spawn_task(
+            "hub_writer",
+            write_frames(state.clone(), id, write_half, replay, rx).instrument(span.clone()),
+        );
 
-        let tx_reader = tx.clone();
-        let tx_writer = tx.clone();
-
-        let write_half = Arc::new(tokio::sync::Mutex::new(write_half));
-        let connections_reader = connections.clone();
-        let connections_writer = connections.clone();
-
-        connections
-            .lock()
-            .await
-            .insert(id, write_half.clone());
-
-        tokio::spawn(async move {
+        let state_reader = state.clone();
+        uchat_metrics::spawn_task("hub_reader", async move {
             let mut reader = read_half;
-            let mut buf = [0u8; 1024];
+            let mut buf = [0u8; 16 * 1024];
+            let mut partial = Vec::new();
 
             loop {
                 let n = match reader.read(&mut buf).await {
-                    Ok(n) if n == 0 => {
-                        connections_reader.lock().await.remove(&id);
+                    Ok(0) => {
+                        tracing::info!("disconnected");
                         break;
                     }
                     Ok(n) => n,
-                    Err(_) => {
-                        connections_reader.lock().await.remove(&id);
+                    Err(e) => {
+                        tracing::warn!(error = %e, "read failed, disconnecting");
                         break;
                     }
                 };
 
-                let _ = tx_reader.send((id, buf[..n].to_vec()));
+                partial.extend_from_slice(&buf[..n]);
+                let mut frames = Vec::new();
+                while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+                    frames.push(Frame::from(partial.drain(..=end).as_slice()));
+                }
+                if partial.len() > MAX_FRAME {
+                    tracing::warn!("line too long, disconnecting");
+                    break;
+                }
+                fan_out(&state_reader, id, frames);
             }
-        });
+            state_reader.hub.lock().unwrap().subscribers.remove(&id);
+        }.instrument(span));
+    }
+}
 
-        tokio::spawn(async move {
-            let mut rx = tx_writer.subscribe();
+/// Queues `frames` for every connection but the sender, dropping any
+/// connection whose queue is full, and keeps them for replay.
+fn fan_out(state: &AppState, sender: Uuid, frames: Vec<Frame>) {
+    if frames.is_empty() {
+        return;
+    }
+    let mut hub = state.hub.lock().unwrap();
 
-            while let Ok((sender, msg)) = rx.recv().await {
-                let conns = connections_writer.lock().await;
+    hub.subscribers.retain(|other_id, queue| {
+        *other_id == sender
+            || frames.iter().all(|frame| match queue.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    tracing::warn!(connection_id = %other_id, "subscriber fell behind, dropping it");
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            })
+    });
 
-                for (other_id, writer_lock) in conns.iter() {
-                    if *other_id == sender {
-                        continue;
-                    }
+    for frame in frames {
+        if hub.replay.len() == state.replay_capacity {
+            hub.replay.pop_front();
+        }
+        if state.replay_capacity > 0 {
+            hub.replay.push_back(frame);
+        }
+    }
+}
 
-                    let mut writer = writer_lock.lock().await;
-                    let _ = writer.write_all(&msg).await;
-                }
-            }
+/// Writes a connection's replay and then its queue, until the queue is
+/// dropped or a write fails. Closing the write half tells the peer to
+/// reconnect.
+async fn write_frames(
+    state: Arc<AppState>,
+    id: Uuid,
+    mut writer: OwnedWriteHalf,
+    replay: Vec<Frame>,
+    mut rx: mpsc::Receiver<Frame>,
+) {
+    for frame in replay {
+        if writer.write_all(&frame).await.is_err() {
+            state.hub.lock().unwrap().subscribers.remove(&id);
+            return;
+        }
+    }
+    while let Some(frame) = rx.recv().await {
+        if let Err(e) = writer.write_all(&frame).await {
+            tracing::warn!(error = %e, "write failed, dropping subscriber");
+            break;
+        }
+        uchat_metrics::messages_relayed(1);
+    }
+    state.hub.lock().unwrap().subscribers.remove(&id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn relays_to_other_connections_and_counts_them() {
+        let metrics = uchat_metrics::init("event-hub-service", "127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(relay(listener, DEFAULT_REPLAY_FRAMES, SUBSCRIBER_QUEUE));
+
+        let mut alice = TcpStream::connect(addr).await.unwrap();
+        let mut bob = TcpStream::connect(addr).await.unwrap();
+        // Let the hub register both before anything is sent.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        alice.write_all(b"hel").await.unwrap();
+        alice.write_all(b"lo\n").await.unwrap();
+        let mut buf = [0u8; 6];
+        tokio::time::timeout(Duration::from_secs(2), bob.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf, b"hello\n");
+
+        let body = uchat_metrics::scrape(metrics).await.unwrap();
+        let relayed: u64 = body
+            .lines()
+            .find_map(|l| l.strip_prefix("uchat_messages_relayed_total{service=\"event-hub-service\"} "))
+            .and_then(|v| v.parse().ok())
+            .unwrap();
+        assert!(relayed >= 1, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn late_joiners_get_the_last_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(relay(listener, 2, SUBSCRIBER_QUEUE));
+
+        let mut gateway = TcpStream::connect(addr).await.unwrap();
+        gateway.write_all(b"{\"seq\":1}\n{\"seq\":2}\n").await.unwrap();
+        gateway.write_all(b"{\"seq\":3}\n{\"seq\"").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Only complete lines are kept, and only the last two of them.
+        let mut late = tokio::io::BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        for expected in ["{\"seq\":2}\n", "{\"seq\":3}\n"] {
+            line.clear();
+            tokio::time::timeout(Duration::from_secs(2), late.read_line(&mut line)).await.unwrap().unwrap();
+            assert_eq!(line, expected);
+        }
+
+        // Then it follows along live, a whole line at a time, even one the
+        // sender had started before it joined.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        gateway.write_all(b":4}\n").await.unwrap();
+        line.clear();
+        tokio::time::timeout(Duration::from_secs(2), late.read_line(&mut line)).await.unwrap().unwrap();
+        assert_eq!(line, "{\"seq\":4}\n");
+    }
+
+    #[tokio::test]
+    async fn slow_readers_are_dropped_without_holding_up_the_rest() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(relay(listener, 0, 16));
+
+        let mut gateway = TcpStream::connect(addr).await.unwrap();
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        let mut fast = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Far more than the socket buffers and the slow reader's queue hold.
+        let frame = format!("{}\n", "x".repeat(64 * 1024 - 1));
+        let sent = frame.repeat(256);
+        let mut fast = tokio::spawn(async move {
+            let mut received = vec![0u8; 256 * 64 * 1024];
+            fast.read_exact(&mut received).await.map(|_| received)
         });
+        gateway.write_all(sent.as_bytes()).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(10), &mut fast).await.unwrap().unwrap().unwrap();
+        assert_eq!(received, sent.as_bytes());
+
+        // The slow reader gets whole frames up to where it was cut off.
+        let mut backlog = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), slow.read_to_end(&mut backlog)).await.unwrap().unwrap();
+        assert!(backlog.len() < sent.len());
+        assert_eq!(backlog.len() % frame.len(), 0);
     }
 }
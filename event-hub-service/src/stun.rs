@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head we read before giving up on a client.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+/// How long a client gets to send its whole request head.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address for the STUN-style HTTP endpoint, from `EVENT_HUB_STUN_ADDR`.
+/// It listens publicly by default, unlike the relay port, since the
+/// point is to see the client's address from outside its NAT.
+pub fn addr_from_env() -> String {
+    std::env::var("EVENT_HUB_STUN_ADDR").unwrap_or_else(|_| "0.0.0.0:9701".into())
+}
+
+/// Answers `GET /stun` with the address the request came from, so devices
+/// behind NAT can learn their public mapping without a full STUN server.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        uchat_metrics::spawn_task("stun_request", handle(stream, peer));
+    }
+}
+
+async fn handle(mut stream: TcpStream, peer: SocketAddr) {
+    let Ok(Some(head)) = tokio::time::timeout(READ_TIMEOUT, read_head(&mut stream)).await else {
+        return;
+    };
+
+    let request_line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(request_line).unwrap_or_default().split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/stun")) => {
+            let body = format!(r#"{{"public_ip":"{}","public_port":{}}}"#, peer.ip(), peer.port());
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Reads up to the blank line ending the request head, or `None` when the
+/// client hangs up or sends too much.
+async fn read_head(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+        if head.len() > MAX_REQUEST_BYTES {
+            return None;
+        }
+    }
+    Some(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(addr: SocketAddr, path: &str) -> (SocketAddr, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let local = stream.local_addr().unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: hub\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        (local, response)
+    }
+
+    #[tokio::test]
+    async fn reports_the_client_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let (local, response) = request(addr, "/stun").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, format!(r#"{{"public_ip":"{}","public_port":{}}}"#, local.ip(), local.port()));
+
+        let (_, response) = request(addr, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_clients_that_never_finish_the_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /stun HTTP/1.1\r\n").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,11 @@
+//! Generates the gRPC stubs for `proto/gateway.proto` when the `grpc`
+//! feature is on, with a vendored protoc so none needs installing.
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/gateway.proto").expect("generate gRPC stubs");
+    }
+}
@@ -0,0 +1,157 @@
+//! Bridges mirror a room to another chat system. A bridge is attached to
+//! one room: it is handed every message sent there, and sends what it
+//! hears from the other side back in as its bot user. The gateway only
+//! knows the `Bridge` trait, so bridges beyond the IRC one here can live
+//! in their own crates and be attached with `attach`.
+
+pub mod irc;
+
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::broadcast;
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::{ChannelId, RoomId, UserId};
+
+use crate::{AppState, OutgoingMessage, RoomMessage};
+
+/// A message sent to a bridged room by someone other than the bridge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeMessage {
+    pub from: UserId,
+    /// Set when the sender isn't a user, such as an incoming webhook.
+    pub sender_name: Option<String>,
+    pub content: String,
+}
+
+#[async_trait]
+pub trait Bridge: Send + Sync + 'static {
+    /// For logs, e.g. `irc`.
+    fn name(&self) -> &str;
+
+    /// Called once when the bridge is attached, before any messages.
+    /// Returns once the bridge is running; it keeps `room` to send into it.
+    async fn start(&self, room: BridgeRoom);
+
+    /// A message sent to the room. Encrypted messages and the bridge's own
+    /// are not passed on.
+    async fn on_room_message(&self, message: BridgeMessage);
+
+    /// Called when the gateway shuts down.
+    async fn stop(&self) {}
+}
+
+/// A bridge's way into its room.
+#[derive(Clone)]
+pub struct BridgeRoom {
+    state: Weak<AppState>,
+    room_id: ChannelId,
+    bot_user_id: UserId,
+}
+
+impl BridgeRoom {
+    pub fn room_id(&self) -> &ChannelId {
+        &self.room_id
+    }
+
+    /// Sends `content` to the room from the bridge's bot user, shown as
+    /// from `sender_name`. `Err` is why it was refused, as a socket's
+    /// `Nack` would have it.
+    pub async fn send_to_room(&self, sender_name: &str, content: &str) -> Result<(), ErrorCode> {
+        let state = self.state.upgrade().ok_or(ErrorCode::Internal)?;
+        let message = OutgoingMessage {
+            room_id: self.room_id.clone(),
+            thread_id: None,
+            content: content.into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            bridged_from: None,
+            sender_name: Some(sender_name.into()),
+            message_id: None,
+            sender_role: None,
+            sender_joined: None,
+        };
+        state.send_message(&self.bot_user_id, None, message, Instant::now()).await.map(drop)
+    }
+}
+
+/// One entry of the `GATEWAY_BRIDGES_PATH` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BridgeConfig {
+    pub room_id: ChannelId,
+    /// The user that messages from the other side are sent as.
+    pub bot_user_id: UserId,
+    #[serde(flatten)]
+    pub kind: BridgeKind,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BridgeKind {
+    Irc(irc::IrcConfig),
+}
+
+/// Attaches the bridges listed in the JSON file at `GATEWAY_BRIDGES_PATH`,
+/// an array of `BridgeConfig`s; none when it is unset. A missing or
+/// invalid file is fatal.
+pub async fn attach_from_env(state: &Arc<AppState>) {
+    let Some(path) = std::env::var("GATEWAY_BRIDGES_PATH").ok().filter(|v| !v.is_empty()) else {
+        return;
+    };
+    let file = std::fs::read(&path).unwrap_or_else(|e| panic!("reading {}: {}", path, e));
+    let configs: Vec<BridgeConfig> =
+        serde_json::from_slice(&file).unwrap_or_else(|e| panic!("parsing {}: {}", path, e));
+    for config in configs {
+        let bridge: Arc<dyn Bridge> = match config.kind {
+            BridgeKind::Irc(irc) => Arc::new(irc::IrcBridge::new(irc)),
+        };
+        attach(state, config.room_id, config.bot_user_id, bridge).await;
+    }
+}
+
+/// Starts `bridge` on `room_id`, sending as `bot_user_id`, and hands it
+/// the room's messages until the gateway shuts down.
+pub async fn attach(state: &Arc<AppState>, room_id: ChannelId, bot_user_id: UserId, bridge: Arc<dyn Bridge>) {
+    // Subscribing keeps the room open while nobody else is in it.
+    let mut rx = state.room(&RoomId::from(room_id.clone())).await.subscribe();
+    tracing::info!(bridge = bridge.name(), room_id = %room_id, "attaching bridge");
+    let room = BridgeRoom { state: Arc::downgrade(state), room_id, bot_user_id: bot_user_id.clone() };
+    bridge.start(room).await;
+
+    uchat_metrics::spawn_task("bridge", async move {
+        loop {
+            match rx.recv().await {
+                Ok(message) => {
+                    if let Some(message) = bridge_message(&message, &bot_user_id) {
+                        bridge.on_room_message(message).await;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(bridge = bridge.name(), missed, "bridge fell behind its room");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        bridge.stop().await;
+    });
+}
+
+/// The client message in a room frame, unless it is encrypted or the
+/// bridge's own.
+fn bridge_message(message: &RoomMessage, bot_user_id: &UserId) -> Option<BridgeMessage> {
+    let json = message.as_json()?;
+    if !json.starts_with("{\"MessageBroadcast\"") {
+        return None;
+    }
+    match serde_json::from_str(json).ok()? {
+        ServerEvent::MessageBroadcast { from, content, encrypted: false, sender_name, .. } if from != *bot_user_id => {
+            Some(BridgeMessage { from, sender_name, content })
+        }
+        _ => None,
+    }
+}
+
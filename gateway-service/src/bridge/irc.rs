@@ -0,0 +1,453 @@
+//! Mirrors a room to an IRC channel over plain TCP. Room messages go out
+//! as `<name> text`; channel messages come in from the bridge's bot user,
+//! shown under the IRC nick. The connection is retried with backoff, a
+//! taken nick gets a number appended, and long messages are split to fit
+//! IRC's 512-byte lines.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex, Notify};
+
+use super::{Bridge, BridgeMessage, BridgeRoom};
+
+/// IRC's limit on a line, `\r\n` included.
+const MAX_LINE_BYTES: usize = 512;
+/// Left for the `:nick!user@host ` prefix the server adds when passing our
+/// lines on, which counts against the same limit.
+const SOURCE_RESERVE: usize = 100;
+/// Longest nick tried after a collision; most servers allow at least this.
+const MAX_NICK_CHARS: usize = 16;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Lines waiting to go out while the bridge is disconnected.
+const QUEUE_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IrcConfig {
+    /// `host:port`.
+    pub server: String,
+    /// Including the `#`.
+    pub channel: String,
+    pub nick: String,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+pub struct IrcBridge {
+    config: IrcConfig,
+    outgoing: mpsc::Sender<String>,
+    /// Taken by the connection task on `start`.
+    queued: Mutex<Option<mpsc::Receiver<String>>>,
+    shutdown: Arc<Notify>,
+}
+
+impl IrcBridge {
+    pub fn new(config: IrcConfig) -> Self {
+        let (outgoing, queued) = mpsc::channel(QUEUE_CAPACITY);
+        Self { config, outgoing, queued: Mutex::new(Some(queued)), shutdown: Arc::default() }
+    }
+}
+
+#[async_trait]
+impl Bridge for IrcBridge {
+    fn name(&self) -> &str {
+        "irc"
+    }
+
+    async fn start(&self, room: BridgeRoom) {
+        let Some(queued) = self.queued.lock().await.take() else { return };
+        let connection = Connection {
+            config: self.config.clone(),
+            room,
+            queued,
+            shutdown: self.shutdown.clone(),
+        };
+        uchat_metrics::spawn_task("irc_bridge", connection.run());
+    }
+
+    async fn on_room_message(&self, message: BridgeMessage) {
+        let name = message.sender_name.unwrap_or_else(|| message.from.as_str().chars().take(8).collect());
+        let prefix = format!("<{}> ", strip_line_breaks(&name));
+        let budget = MAX_LINE_BYTES - "\r\n".len() - SOURCE_RESERVE - format!("PRIVMSG {} :", self.config.channel).len();
+        for chunk in split_message(&message.content, budget.saturating_sub(prefix.len()).max(1)) {
+            if self.outgoing.try_send(format!("{}{}", prefix, chunk)).is_err() {
+                tracing::warn!(channel = %self.config.channel, "IRC bridge queue full; dropping a message");
+                return;
+            }
+        }
+    }
+
+    async fn stop(&self) {
+        self.shutdown.notify_one();
+    }
+}
+
+struct Connection {
+    config: IrcConfig,
+    room: BridgeRoom,
+    queued: mpsc::Receiver<String>,
+    shutdown: Arc<Notify>,
+}
+
+/// Why a session ended.
+enum Ended {
+    Shutdown,
+    /// The server closed the connection. After a successful registration
+    /// reconnecting starts over from the shortest wait.
+    Dropped { registered: bool },
+}
+
+impl Connection {
+    async fn run(mut self) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.session().await {
+                Ok(Ended::Shutdown) => return,
+                Ok(Ended::Dropped { registered }) => {
+                    tracing::warn!(server = %self.config.server, "IRC connection closed");
+                    if registered {
+                        backoff = MIN_BACKOFF;
+                    }
+                }
+                Err(e) => tracing::warn!(server = %self.config.server, "IRC connection failed: {}", e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = self.shutdown.notified() => return,
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    async fn session(&mut self) -> std::io::Result<Ended> {
+        let stream = TcpStream::connect(&self.config.server).await?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+
+        let mut nick = self.config.nick.clone();
+        let mut attempt = 0;
+        if let Some(password) = &self.config.password {
+            write.write_all(format!("PASS {}\r\n", password).as_bytes()).await?;
+        }
+        write.write_all(format!("NICK {}\r\nUSER {} 0 * :U-Chat bridge\r\n", nick, nick).as_bytes()).await?;
+
+        let (mut registered, mut joined) = (false, false);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            tokio::select! {
+                _ = self.shutdown.notified() => {
+                    let _ = write.write_all(b"QUIT :bridge shutting down\r\n").await;
+                    return Ok(Ended::Shutdown);
+                }
+                text = self.queued.recv(), if joined => {
+                    let Some(text) = text else { return Ok(Ended::Shutdown) };
+                    write.write_all(format!("PRIVMSG {} :{}\r\n", self.config.channel, text).as_bytes()).await?;
+                }
+                read = read.read_until(b'\n', &mut line) => {
+                    if read? == 0 {
+                        return Ok(Ended::Dropped { registered });
+                    }
+                    let text = String::from_utf8_lossy(&line);
+                    let Some(message) = parse_line(&text) else { continue };
+                    match (message.command, message.params.as_slice()) {
+                        ("PING", params) => {
+                            write.write_all(format!("PONG :{}\r\n", params.first().unwrap_or(&"")).as_bytes()).await?;
+                        }
+                        // Welcome: registration went through.
+                        ("001", _) => {
+                            registered = true;
+                            write.write_all(format!("JOIN {}\r\n", self.config.channel).as_bytes()).await?;
+                        }
+                        // Nick in use, or recently used.
+                        ("433" | "436", _) if !registered => {
+                            attempt += 1;
+                            nick = next_nick(&self.config.nick, attempt);
+                            write.write_all(format!("NICK {}\r\n", nick).as_bytes()).await?;
+                        }
+                        ("JOIN", [channel, ..]) if message.nick == Some(nick.as_str()) && channel.eq_ignore_ascii_case(&self.config.channel) => {
+                            joined = true;
+                        }
+                        ("KICK", [channel, kicked, ..]) if *kicked == nick && channel.eq_ignore_ascii_case(&self.config.channel) => {
+                            joined = false;
+                            write.write_all(format!("JOIN {}\r\n", self.config.channel).as_bytes()).await?;
+                        }
+                        ("NICK", [new_nick]) if message.nick == Some(nick.as_str()) => nick = new_nick.to_string(),
+                        ("PRIVMSG", [target, text]) if target.eq_ignore_ascii_case(&self.config.channel) => {
+                            let Some(sender) = message.nick.filter(|sender| *sender != nick) else { continue };
+                            let Some(text) = incoming_text(text) else { continue };
+                            if let Err(code) = self.room.send_to_room(sender, &text).await {
+                                tracing::warn!(room_id = %self.room.room_id(), ?code, "IRC message refused by the room");
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A line from the server, with the sender's nick taken out of the prefix.
+#[derive(Debug, PartialEq)]
+struct IrcLine<'a> {
+    nick: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+fn parse_line(line: &str) -> Option<IrcLine<'_>> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    // IRCv3 tags aren't asked for, but skip them if a server sends them.
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1;
+    }
+    let mut nick = None;
+    if let Some(prefixed) = rest.strip_prefix(':') {
+        let (prefix, after) = prefixed.split_once(' ')?;
+        nick = Some(prefix.split(['!', '@']).next().unwrap_or(prefix));
+        rest = after;
+    }
+    let (middle, trailing) = match rest.split_once(" :") {
+        Some((middle, trailing)) => (middle, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = middle.split(' ').filter(|w| !w.is_empty());
+    let command = words.next()?;
+    let mut params: Vec<&str> = words.collect();
+    params.extend(trailing);
+    Some(IrcLine { nick, command, params })
+}
+
+/// The nick to try after `attempt` collisions: a number on the end,
+/// shortening the configured nick to keep within `MAX_NICK_CHARS`.
+fn next_nick(base: &str, attempt: u32) -> String {
+    let suffix = attempt.to_string();
+    let keep = MAX_NICK_CHARS.saturating_sub(suffix.len());
+    format!("{}{}", base.chars().take(keep).collect::<String>(), suffix)
+}
+
+/// The text of a channel message, with mIRC formatting codes removed and
+/// a `/me` action written out. Other CTCP requests are dropped.
+fn incoming_text(text: &str) -> Option<String> {
+    let text = match text.strip_prefix('\u{1}') {
+        Some(ctcp) => format!("* {}", ctcp.trim_end_matches('\u{1}').strip_prefix("ACTION ")?),
+        None => text.to_string(),
+    };
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Colour, followed by up to two digits each of foreground and
+            // background.
+            '\u{3}' => {
+                for _ in 0..2 {
+                    chars.next_if(char::is_ascii_digit);
+                }
+                if chars.peek() == Some(&',') {
+                    chars.next();
+                    for _ in 0..2 {
+                        chars.next_if(char::is_ascii_digit);
+                    }
+                }
+            }
+            '\u{2}' | '\u{f}' | '\u{11}' | '\u{16}' | '\u{1d}' | '\u{1e}' | '\u{1f}' => {}
+            c => plain.push(c),
+        }
+    }
+    let plain = plain.trim().to_string();
+    (!plain.is_empty()).then_some(plain)
+}
+
+fn strip_line_breaks(text: &str) -> String {
+    text.chars().filter(|c| !matches!(c, '\r' | '\n' | '\0')).collect()
+}
+
+/// `text` as IRC messages of at most `max_bytes` each: one or more per
+/// line, split at the last space that fits, or mid-word when there is
+/// none, but never inside a character.
+fn split_message(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    for line in text.split(['\r', '\n']).map(|line| line.replace('\0', "")) {
+        let mut rest = line.trim();
+        while !rest.is_empty() {
+            if rest.len() <= max_bytes {
+                chunks.push(rest.to_string());
+                break;
+            }
+            let mut end = max_bytes;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            if end == 0 {
+                // A character wider than the budget; send it whole.
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            let cut = rest[..end].rfind(' ').filter(|&at| at > 0).unwrap_or(end);
+            chunks.push(rest[..cut].trim_end().to_string());
+            rest = rest[cut..].trim_start();
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::attach;
+    use crate::{test_state, AppState, OutgoingMessage};
+    use std::time::Instant;
+    use tokio::io::{Lines, ReadHalf, WriteHalf};
+    use tokio::net::TcpListener;
+    use uchat_proto::events::ServerEvent;
+    use uchat_proto::ids::{ChannelId, RoomId, UserId};
+
+    #[test]
+    fn parses_server_lines() {
+        assert_eq!(
+            parse_line(":alice!a@example.net PRIVMSG #uchat :hello there\r\n"),
+            Some(IrcLine { nick: Some("alice"), command: "PRIVMSG", params: vec!["#uchat", "hello there"] })
+        );
+        assert_eq!(
+            parse_line("PING :irc.example.net\r\n"),
+            Some(IrcLine { nick: None, command: "PING", params: vec!["irc.example.net"] })
+        );
+        assert_eq!(
+            parse_line("@time=x :irc.example.net 433 * uchat :Nickname is already in use"),
+            Some(IrcLine {
+                nick: Some("irc.example.net"),
+                command: "433",
+                params: vec!["*", "uchat", "Nickname is already in use"]
+            })
+        );
+        assert_eq!(parse_line(""), None);
+    }
+
+    #[test]
+    fn nicks_get_numbers_within_the_length_limit() {
+        assert_eq!(next_nick("uchat", 1), "uchat1");
+        assert_eq!(next_nick("a-very-long-bridge-nick", 12), "a-very-long-br12");
+    }
+
+    #[test]
+    fn incoming_text_loses_formatting() {
+        assert_eq!(incoming_text("\u{2}build\u{2} \u{3}04,01red\u{3} ok").as_deref(), Some("build red ok"));
+        assert_eq!(incoming_text("\u{1}ACTION waves\u{1}").as_deref(), Some("* waves"));
+        assert_eq!(incoming_text("\u{1}VERSION\u{1}"), None);
+        assert_eq!(incoming_text(" \u{f} "), None);
+    }
+
+    #[test]
+    fn long_messages_split_at_spaces_and_characters() {
+        assert_eq!(split_message("one two three", 8), ["one two", "three"]);
+        assert_eq!(split_message("first\r\nsecond\n\nthird", 100), ["first", "second", "third"]);
+        assert_eq!(split_message("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        // Three bytes each, so a budget of 4 fits one at a time.
+        assert_eq!(split_message("日本語", 4), ["日", "本", "語"]);
+        let long = "word ".repeat(500);
+        assert!(split_message(&long, 300).iter().all(|chunk| chunk.len() <= 300));
+    }
+
+    struct FakeServer {
+        lines: Lines<BufReader<ReadHalf<TcpStream>>>,
+        write: WriteHalf<TcpStream>,
+    }
+
+    impl FakeServer {
+        async fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await.unwrap().unwrap();
+            let (read, write) = tokio::io::split(stream);
+            Self { lines: BufReader::new(read).lines(), write }
+        }
+
+        async fn expect(&mut self, line: &str) {
+            let got = tokio::time::timeout(Duration::from_secs(5), self.lines.next_line()).await.unwrap().unwrap();
+            assert_eq!(got.as_deref(), Some(line));
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.write.write_all(format!("{}\r\n", line).as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn next_message(rx: &mut tokio::sync::broadcast::Receiver<crate::RoomMessage>) -> ServerEvent {
+        let message = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        serde_json::from_str(message.as_json().unwrap()).unwrap()
+    }
+
+    async fn say(state: &Arc<AppState>, channel: &ChannelId, from: &UserId, content: &str) {
+        let message = OutgoingMessage {
+            room_id: channel.clone(),
+            thread_id: None,
+            content: content.into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: None,
+            sender_joined: None,
+        };
+        state.send_message(from, None, message, Instant::now()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_both_ways_and_reconnects() {
+        let state = test_state();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (channel, bot) = (ChannelId::new(), UserId::new());
+        let config = IrcConfig {
+            server: listener.local_addr().unwrap().to_string(),
+            channel: "#uchat".into(),
+            nick: "uchat".into(),
+            password: None,
+        };
+        attach(&state, channel.clone(), bot.clone(), Arc::new(IrcBridge::new(config))).await;
+        let mut rx = state.room(&RoomId::from(channel.clone())).await.subscribe();
+
+        let mut server = FakeServer::accept(&listener).await;
+        server.expect("NICK uchat").await;
+        server.expect("USER uchat 0 * :U-Chat bridge").await;
+        server.send(":irc.test 433 * uchat :Nickname is already in use").await;
+        server.expect("NICK uchat1").await;
+        server.send(":irc.test 001 uchat1 :Welcome").await;
+        server.expect("JOIN #uchat").await;
+
+        // Sent before the bridge has joined, so it waits in the queue.
+        let alice = UserId::new();
+        say(&state, &channel, &alice, "hello irc").await;
+        next_message(&mut rx).await;
+        server.send(":uchat1!b@host JOIN #uchat").await;
+        server.expect(&format!("PRIVMSG #uchat :<{}> hello irc", &alice.as_str()[..8])).await;
+
+        server.send("PING :irc.test").await;
+        server.expect("PONG :irc.test").await;
+        server.send(":bob!b@host PRIVMSG #uchat :\u{2}hi\u{2} from irc").await;
+        match next_message(&mut rx).await {
+            ServerEvent::MessageBroadcast { from, content, sender_name, .. } => {
+                assert_eq!((from, content.as_str(), sender_name.as_deref()), (bot.clone(), "hi from irc", Some("bob")));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        say(&state, &channel, &alice, &"word ".repeat(200)).await;
+        let mut relayed = String::new();
+        while relayed.len() < 999 {
+            let line = tokio::time::timeout(Duration::from_secs(5), server.lines.next_line()).await.unwrap().unwrap().unwrap();
+            assert!(line.len() + 2 + SOURCE_RESERVE <= MAX_LINE_BYTES, "{} bytes", line.len());
+            let text = line.split_once("> ").unwrap().1;
+            relayed = format!("{} {}", relayed, text).trim().to_string();
+        }
+        assert_eq!(relayed, "word ".repeat(200).trim());
+
+        // The server going away is followed by a fresh registration.
+        drop(server);
+        let mut server = FakeServer::accept(&listener).await;
+        server.expect("NICK uchat").await;
+    }
+}
@@ -0,0 +1,269 @@
+use std::time::Duration;
+
+use uchat_proto::filters::{ChannelFilterRules, ContentFiltered};
+use uchat_proto::ids::{ChannelId, MessageId, UserId};
+use uchat_proto::messages::MarkRead;
+use uchat_proto::moderation::SpamFlagged;
+use uchat_proto::push::PushMessage;
+use uchat_proto::users::UserProfile;
+use uchat_telemetry::{current_traceparent, CORRELATION_ID_HEADER, TRACEPARENT_HEADER};
+
+/// Calls channels-api on behalf of a connected user, with the token they
+/// connected with, so the API applies its own membership checks.
+#[derive(Clone)]
+pub struct ChannelsClient {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl ChannelsClient {
+    pub fn new(base_url: &str) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("build channels-api client");
+
+        Self { client, base_url: base_url.trim_end_matches('/').to_string() }
+    }
+
+    /// Builds a client from `CHANNELS_API_URL`, or `None` when it is unset.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("CHANNELS_API_URL").ok().filter(|v| !v.is_empty())?;
+        Some(Self::new(&url))
+    }
+
+    /// Advances the user's read marker via `PUT /api/channels/{id}/read`,
+    /// as part of the action `correlation_id` and the current trace.
+    pub async fn mark_read(
+        &self,
+        token: &str,
+        correlation_id: &str,
+        channel_id: &ChannelId,
+        message_id: &MessageId,
+    ) -> Result<(), String> {
+        let mut req = self
+            .client
+            .put(format!("{}/api/channels/{}/read", self.base_url, channel_id))
+            .bearer_auth(token)
+            .header(CORRELATION_ID_HEADER, correlation_id);
+        if let Some(traceparent) = current_traceparent() {
+            req = req.header(TRACEPARENT_HEADER, traceparent);
+        }
+        let resp = req
+            .json(&MarkRead { message_id: message_id.clone() })
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("channels-api returned {}", resp.status()))
+        }
+    }
+
+    /// Profiles of `user_ids` via `GET /api/users?ids=`, as the bearer of
+    /// `token` sees them; unknown users are left out.
+    pub async fn profiles(&self, token: &str, user_ids: &[UserId]) -> Result<Vec<UserProfile>, String> {
+        let ids = user_ids.iter().map(UserId::as_str).collect::<Vec<_>>().join(",");
+        let resp = self
+            .client
+            .get(format!("{}/api/users", self.base_url))
+            .query(&[("ids", ids)])
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !resp.status().is_success() {
+            return Err(format!("channels-api returned {}", resp.status()));
+        }
+        resp.json().await.map_err(|e| e.to_string())
+    }
+
+    /// Hands a message to channels-api's push dispatcher via
+    /// `POST /internal/push`, authenticated with the gateway's internal
+    /// token.
+    pub async fn push(&self, internal_token: &str, message: &PushMessage) -> Result<(), String> {
+        let resp = self
+            .client
+            .post(format!("{}/internal/push", self.base_url))
+            .header("x-internal-token", internal_token)
+            .json(message)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("channels-api returned {}", resp.status()))
+        }
+    }
+
+    /// Removes a member on behalf of a trusted service via
+    /// `DELETE /internal/channels/{id}/members/{user_id}`, returning the
+    /// status channels-api answered with. For the gRPC `KickUser`.
+    #[cfg(feature = "grpc")]
+    pub async fn kick(
+        &self,
+        internal_token: &str,
+        channel_id: &ChannelId,
+        user_id: &UserId,
+    ) -> Result<reqwest::StatusCode, String> {
+        let resp = self
+            .client
+            .delete(format!("{}/internal/channels/{}/members/{}", self.base_url, channel_id, user_id))
+            .header("x-internal-token", internal_token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(resp.status())
+    }
+
+    /// Reports a new spam hold for the moderation audit trail via
+    /// `POST /internal/moderation/flagged`.
+    pub async fn spam_flagged(&self, internal_token: &str, flagged: &SpamFlagged) -> Result<(), String> {
+        let resp = self
+            .client
+            .post(format!("{}/internal/moderation/flagged", self.base_url))
+            .header("x-internal-token", internal_token)
+            .json(flagged)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("channels-api returned {}", resp.status()))
+        }
+    }
+
+    /// Reports a content filter's redaction or block for the moderation
+    /// audit trail via `POST /internal/moderation/filtered`.
+    pub async fn content_filtered(&self, internal_token: &str, filtered: &ContentFiltered) -> Result<(), String> {
+        let resp = self
+            .client
+            .post(format!("{}/internal/moderation/filtered", self.base_url))
+            .header("x-internal-token", internal_token)
+            .json(filtered)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("channels-api returned {}", resp.status()))
+        }
+    }
+
+    /// Every channel's content filter rules, from
+    /// `GET /internal/content-filters`, for a gateway that just started.
+    pub async fn content_filters(&self, internal_token: &str) -> Result<Vec<ChannelFilterRules>, String> {
+        let resp = self
+            .client
+            .get(format!("{}/internal/content-filters", self.base_url))
+            .header("x-internal-token", internal_token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !resp.status().is_success() {
+            return Err(format!("channels-api returned {}", resp.status()));
+        }
+        resp.json().await.map_err(|e| e.to_string())
+    }
+
+    /// The channels in one of the sets the gateway keeps, such as
+    /// `GET /internal/e2ee-channels`, for a gateway that just started.
+    pub async fn channel_ids(&self, internal_token: &str, path: &str) -> Result<Vec<ChannelId>, String> {
+        let resp = self
+            .client
+            .get(format!("{}/internal/{}", self.base_url, path))
+            .header("x-internal-token", internal_token)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !resp.status().is_success() {
+            return Err(format!("channels-api returned {}", resp.status()));
+        }
+        resp.json().await.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::Path,
+        http::{HeaderMap, StatusCode},
+        routing::{post, put},
+        Json, Router,
+    };
+    use tokio::sync::mpsc;
+    use uchat_proto::ids::UserId;
+
+    #[tokio::test]
+    async fn forwards_token_and_reports_rejections() {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(String, HeaderMap, MarkRead)>();
+        let fake_api = Router::new().route(
+            "/api/channels/:id/read",
+            put(move |Path(id): Path<String>, headers: HeaderMap, Json(body): Json<MarkRead>| async move {
+                let backwards = body.message_id.as_str().starts_with('0');
+                let _ = tx.send((id, headers, body));
+                if backwards { StatusCode::CONFLICT } else { StatusCode::OK }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_api).await.unwrap() });
+
+        let client = ChannelsClient::new(&format!("http://{}/", addr));
+        let channel = ChannelId::new();
+        let message: MessageId = "10000000-0000-4000-8000-000000000000".parse().unwrap();
+
+        client.mark_read("tok", "c-1", &channel, &message).await.unwrap();
+        let (id, headers, body) = rx.recv().await.unwrap();
+        assert_eq!(id, channel.as_str());
+        assert_eq!(headers["authorization"], "Bearer tok");
+        assert_eq!(headers[CORRELATION_ID_HEADER], "c-1");
+        assert_eq!(body.message_id, message);
+
+        let older: MessageId = "00000000-0000-4000-8000-000000000000".parse().unwrap();
+        assert!(client.mark_read("tok", "c-2", &channel, &older).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hands_messages_to_the_push_dispatcher() {
+        let (tx, mut rx) = mpsc::unbounded_channel::<PushMessage>();
+        let fake_api = Router::new().route(
+            "/internal/push",
+            post(move |headers: HeaderMap, Json(body): Json<PushMessage>| async move {
+                if headers.get("x-internal-token").is_none_or(|t| t != "internal-secret") {
+                    return StatusCode::FORBIDDEN;
+                }
+                let _ = tx.send(body);
+                StatusCode::ACCEPTED
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_api).await.unwrap() });
+
+        let client = ChannelsClient::new(&format!("http://{}", addr));
+        let message = PushMessage {
+            channel_id: ChannelId::new(),
+            sender_id: UserId::new(),
+            sender_name: None,
+            content: None,
+            delivered_to: vec![UserId::new()],
+        };
+        client.push("internal-secret", &message).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), message);
+        assert!(client.push("wrong", &message).await.is_err());
+    }
+}
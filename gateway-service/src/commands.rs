@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use uchat_proto::commands::{CommandInvocation, CommandResponse, CommandTarget, SlashCommand};
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::{InvocationId, RoomId, UserId};
+
+use crate::internal::authorized;
+use crate::{AppState, OutgoingMessage};
+
+/// An error for the invoker: its code and message.
+pub type Refusal = (ErrorCode, String);
+
+/// How long a bot has to answer an invocation.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Slash commands registered on this instance, and the bot invocations
+/// still waiting on an answer.
+pub struct CommandRegistry {
+    commands: Mutex<HashMap<String, SlashCommand>>,
+    pending: Mutex<HashMap<InvocationId, Pending>>,
+    http: reqwest::Client,
+}
+
+/// An invocation sent to a bot.
+pub struct Pending {
+    bot: UserId,
+    pub invoker: UserId,
+    pub room_id: RoomId,
+    pub command: String,
+    sent_at: Instant,
+}
+
+impl Pending {
+    /// `text` as the invoker alone sees it.
+    pub fn result(&self, text: String) -> ServerEvent {
+        ServerEvent::CommandResult { room_id: self.room_id.clone(), command: self.command.clone(), text }
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("build command webhook client");
+        Self { commands: Mutex::default(), pending: Mutex::default(), http }
+    }
+}
+
+impl CommandRegistry {
+    /// Adds `command`, unless its name is taken.
+    pub fn register(&self, command: SlashCommand) -> Result<(), StatusCode> {
+        let mut commands = self.commands.lock().unwrap();
+        if commands.contains_key(&command.name) {
+            return Err(StatusCode::CONFLICT);
+        }
+        commands.insert(command.name.clone(), command);
+        Ok(())
+    }
+
+    pub fn unregister(&self, name: &str) -> bool {
+        self.commands.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Every command, by name.
+    pub fn list(&self) -> Vec<SlashCommand> {
+        let mut commands: Vec<SlashCommand> = self.commands.lock().unwrap().values().cloned().collect();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        commands
+    }
+
+    /// The invocation of `/name text` by `user_id` in `room_id` and where
+    /// it goes, or the error to show them.
+    pub fn invocation(
+        &self,
+        name: &str,
+        text: &str,
+        user_id: &UserId,
+        user_role: Option<String>,
+        room_id: RoomId,
+    ) -> Result<(CommandTarget, CommandInvocation), Refusal> {
+        let Some(command) = self.commands.lock().unwrap().get(name).cloned() else {
+            return Err((ErrorCode::UnknownCommand, format!("unknown command /{}", name)));
+        };
+        let args = command.parse_args(text).map_err(|usage| (ErrorCode::InvalidRequest, usage))?;
+        let invocation = CommandInvocation {
+            id: InvocationId::new(),
+            command: command.name,
+            args,
+            text: text.trim().to_string(),
+            user_id: user_id.clone(),
+            room_id,
+            user_role,
+        };
+        Ok((command.target, invocation))
+    }
+
+    /// Remembers that `bot` owes an answer to `invocation`, forgetting
+    /// those it never answered.
+    fn expect_response(&self, bot: &UserId, invocation: &CommandInvocation) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.sent_at.elapsed() < RESPONSE_TIMEOUT);
+        pending.insert(
+            invocation.id.clone(),
+            Pending {
+                bot: bot.clone(),
+                invoker: invocation.user_id.clone(),
+                room_id: invocation.room_id.clone(),
+                command: invocation.command.clone(),
+                sent_at: Instant::now(),
+            },
+        );
+    }
+
+    /// The invocation `bot` is answering, if it was sent one by that id
+    /// and it hasn't timed out.
+    pub fn take_response(&self, id: &InvocationId, bot: &UserId) -> Option<Pending> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(id).is_none_or(|p| p.bot != *bot) {
+            return None;
+        }
+        pending.remove(id).filter(|p| p.sent_at.elapsed() < RESPONSE_TIMEOUT)
+    }
+}
+
+/// Hands `invocation` to its target. Webhooks are answered here; bots
+/// answer later with `CommandResponse`. `Err` is the error to show the
+/// invoker.
+pub async fn dispatch(state: &AppState, target: CommandTarget, invocation: CommandInvocation) -> Result<(), Refusal> {
+    let name = invocation.command.clone();
+    let unavailable = || (ErrorCode::Unavailable, format!("/{} isn't available", name));
+    match target {
+        CommandTarget::Bot { user_id } => {
+            if state.presence.online(std::slice::from_ref(&user_id)).await.is_empty() {
+                return Err(unavailable());
+            }
+            state.commands.expect_response(&user_id, &invocation);
+            let json = serde_json::to_string(&ServerEvent::CommandInvoked { invocation }).map_err(|_| unavailable())?;
+            let _ = state.user_events.send((user_id, json));
+            Ok(())
+        }
+        CommandTarget::Webhook { url } => {
+            let resp = state.commands.http.post(&url).json(&invocation).send().await;
+            let body = match resp {
+                Ok(resp) if resp.status().is_success() => resp.bytes().await.unwrap_or_default(),
+                Ok(resp) => {
+                    tracing::warn!(command = %invocation.command, status = %resp.status(), "command webhook failed");
+                    return Err(unavailable());
+                }
+                Err(e) => {
+                    tracing::warn!(command = %invocation.command, error = %e, "command webhook failed");
+                    return Err(unavailable());
+                }
+            };
+            // An empty answer means nothing to say.
+            if body.is_empty() {
+                return Ok(());
+            }
+            let Ok(response) = serde_json::from_slice::<CommandResponse>(&body) else {
+                tracing::warn!(command = %invocation.command, "command webhook answered with something else");
+                return Err(unavailable());
+            };
+            if response.ephemeral {
+                let result =
+                    ServerEvent::CommandResult { room_id: invocation.room_id, command: invocation.command, text: response.text };
+                if let Ok(json) = serde_json::to_string(&result) {
+                    let _ = state.user_events.send((invocation.user_id, json));
+                }
+                return Ok(());
+            }
+            // Posted for the invoker, under the command's name.
+            let message = OutgoingMessage {
+                room_id: invocation.room_id.channel,
+                thread_id: invocation.room_id.thread,
+                content: response.text,
+                encrypted: false,
+                content_type: "text/plain".into(),
+                bridged_from: None,
+                sender_name: Some(format!("/{}", invocation.command)),
+                message_id: None,
+                sender_role: None,
+                sender_joined: None,
+            };
+            match state.send_message(&invocation.user_id, None, message, Instant::now()).await {
+                Ok(_) => Ok(()),
+                Err(code) => Err((code, format!("/{} couldn't post its answer", invocation.command))),
+            }
+        }
+    }
+}
+
+/// GET /internal/commands
+pub async fn list(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<Vec<SlashCommand>>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(state.commands.list()))
+}
+
+/// POST /internal/commands
+///
+/// Registers a slash command for a bot or integration; 409 when the name
+/// is taken, so one has to be removed before it is registered again.
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(command): Json<SlashCommand>,
+) -> Response {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if let Err(reason) = command.validate() {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    match state.commands.register(command) {
+        Ok(()) => StatusCode::CREATED.into_response(),
+        Err(status) => status.into_response(),
+    }
+}
+
+/// DELETE /internal/commands/{name}
+pub async fn unregister(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(name): Path<String>) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+    if state.commands.unregister(&name) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::routing::post;
+    use axum::Router;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+    use uchat_proto::commands::CommandArg;
+    use uchat_proto::ids::ChannelId;
+
+    use crate::internal::INTERNAL_TOKEN_HEADER;
+    use crate::{app, test_state};
+
+    fn command(name: &str, target: CommandTarget) -> SlashCommand {
+        SlashCommand {
+            name: name.into(),
+            description: String::new(),
+            args: vec![CommandArg { name: "text".into(), required: true }],
+            target,
+        }
+    }
+
+    fn register_request(token: &str, command: &SlashCommand) -> Request<Body> {
+        Request::post("/internal/commands")
+            .header(INTERNAL_TOKEN_HEADER, token)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(command).unwrap()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn registration_rejects_conflicts() {
+        let state = test_state();
+        let echo = command("echo", CommandTarget::Bot { user_id: UserId::new() });
+
+        let status = |resp: Response| resp.status();
+        let resp = app(state.clone()).oneshot(register_request("wrong", &echo)).await.unwrap();
+        assert_eq!(status(resp), StatusCode::FORBIDDEN);
+        let resp = app(state.clone()).oneshot(register_request("internal-secret", &echo)).await.unwrap();
+        assert_eq!(status(resp), StatusCode::CREATED);
+        let other = command("echo", CommandTarget::Webhook { url: "https://example.com".into() });
+        let resp = app(state.clone()).oneshot(register_request("internal-secret", &other)).await.unwrap();
+        assert_eq!(status(resp), StatusCode::CONFLICT);
+        let bad = command("Echo", CommandTarget::Webhook { url: "https://example.com".into() });
+        let resp = app(state.clone()).oneshot(register_request("internal-secret", &bad)).await.unwrap();
+        assert_eq!(status(resp), StatusCode::BAD_REQUEST);
+        assert_eq!(state.commands.list(), [echo]);
+
+        let delete = || {
+            Request::delete("/internal/commands/echo").header(INTERNAL_TOKEN_HEADER, "internal-secret").body(Body::empty()).unwrap()
+        };
+        assert_eq!(app(state.clone()).oneshot(delete()).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(app(state.clone()).oneshot(delete()).await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert!(state.commands.list().is_empty());
+    }
+
+    #[test]
+    fn unknown_commands_and_missing_arguments_are_errors() {
+        let state = test_state();
+        state.commands.register(command("echo", CommandTarget::Bot { user_id: UserId::new() })).unwrap();
+        let room = RoomId::from(ChannelId::new());
+        let user = UserId::new();
+
+        let err = state.commands.invocation("nope", "", &user, None, room.clone()).unwrap_err();
+        assert_eq!(err.0, ErrorCode::UnknownCommand);
+        let err = state.commands.invocation("echo", " ", &user, None, room.clone()).unwrap_err();
+        assert_eq!(err, (ErrorCode::InvalidRequest, "usage: /echo <text>".to_string()));
+
+        let (_, invocation) = state.commands.invocation("echo", " hi there", &user, Some("ops".into()), room.clone()).unwrap();
+        assert_eq!(invocation.args["text"], "hi there");
+        assert_eq!((invocation.user_id, invocation.room_id, invocation.user_role), (user, room, Some("ops".into())));
+    }
+
+    #[test]
+    fn only_the_target_bot_can_answer() {
+        let state = test_state();
+        let bot = UserId::new();
+        state.commands.register(command("echo", CommandTarget::Bot { user_id: bot.clone() })).unwrap();
+        let (_, invocation) =
+            state.commands.invocation("echo", "hi", &UserId::new(), None, ChannelId::new().into()).unwrap();
+        state.commands.expect_response(&bot, &invocation);
+
+        assert!(state.commands.take_response(&invocation.id, &UserId::new()).is_none());
+        assert!(state.commands.take_response(&invocation.id, &bot).is_some());
+        assert!(state.commands.take_response(&invocation.id, &bot).is_none());
+    }
+
+    #[tokio::test]
+    async fn webhook_answers_reach_the_invoker_or_the_room() {
+        let hook = Router::new().route(
+            "/hook",
+            post(|Json(invocation): Json<CommandInvocation>| async move {
+                let ephemeral = invocation.args["text"] == "quietly";
+                Json(CommandResponse { text: format!("{} said {}", invocation.user_id, invocation.text), ephemeral })
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, hook).await.unwrap() });
+
+        let state = test_state();
+        state.commands.register(command("say", CommandTarget::Webhook { url })).unwrap();
+        let room = RoomId::from(ChannelId::new());
+        let mut room_rx = state.room(&room).await.subscribe();
+        let mut user_rx = state.user_events.subscribe();
+        let user = UserId::new();
+
+        let (target, invocation) = state.commands.invocation("say", "quietly", &user, None, room.clone()).unwrap();
+        dispatch(&state, target, invocation).await.unwrap();
+        let (to, json) = user_rx.recv().await.unwrap();
+        assert_eq!(to, user);
+        let event: ServerEvent = serde_json::from_str(&json).unwrap();
+        assert!(matches!(event, ServerEvent::CommandResult { command, text, .. } if command == "say" && text == format!("{} said quietly", user)));
+
+        let (target, invocation) = state.commands.invocation("say", "hello", &user, None, room.clone()).unwrap();
+        dispatch(&state, target, invocation).await.unwrap();
+        let message = room_rx.recv().await.unwrap();
+        let event: ServerEvent = serde_json::from_str(message.as_json().unwrap()).unwrap();
+        let ServerEvent::MessageBroadcast { from, content, sender_name, .. } = event else { panic!() };
+        assert_eq!((from, content, sender_name), (user.clone(), format!("{} said hello", user), Some("/say".into())));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn offline_bots_are_unavailable() {
+        let state = test_state();
+        state.commands.register(command("echo", CommandTarget::Bot { user_id: UserId::new() })).unwrap();
+        let (target, invocation) =
+            state.commands.invocation("echo", "hi", &UserId::new(), None, ChannelId::new().into()).unwrap();
+        let err = dispatch(&state, target, invocation).await.unwrap_err();
+        assert_eq!(err.0, ErrorCode::Unavailable);
+    }
+}
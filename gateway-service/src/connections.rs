@@ -0,0 +1,84 @@
+//! How many connections each user holds open: WebSockets, long-polling
+//! sessions and SSE streams all count against the same cap.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use uchat_proto::ids::UserId;
+
+const DEFAULT_MAX_PER_USER: usize = 20;
+
+pub struct ConnectionLimits {
+    open: DashMap<UserId, usize>,
+    max_per_user: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PER_USER)
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(max_per_user: usize) -> Self {
+        Self { open: DashMap::new(), max_per_user }
+    }
+
+    /// Cap from `GATEWAY_MAX_CONNECTIONS_PER_USER`, default 20.
+    pub fn from_env() -> Self {
+        let max_per_user = std::env::var("GATEWAY_MAX_CONNECTIONS_PER_USER")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_PER_USER);
+        Self::new(max_per_user)
+    }
+
+    /// Counts a new connection for `user_id` until the slot is dropped,
+    /// or `None` when they are at the cap.
+    pub fn acquire(self: &Arc<Self>, user_id: &UserId) -> Option<ConnectionSlot> {
+        let mut open = self.open.entry(user_id.clone()).or_insert(0);
+        if *open >= self.max_per_user {
+            return None;
+        }
+        *open += 1;
+        Some(ConnectionSlot { limits: self.clone(), user_id: user_id.clone() })
+    }
+}
+
+/// One of a user's open connections.
+pub struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    user_id: UserId,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Some(mut open) = self.limits.open.get_mut(&self.user_id) {
+            *open -= 1;
+        }
+        self.limits.open.remove_if(&self.user_id, |_, open| *open == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_open_connections_per_user() {
+        let limits = Arc::new(ConnectionLimits::new(2));
+        let (alice, bob) = (UserId::new(), UserId::new());
+        let first = limits.acquire(&alice).unwrap();
+        let second = limits.acquire(&alice).unwrap();
+        assert!(limits.acquire(&alice).is_none());
+        assert!(limits.acquire(&bob).is_some());
+
+        drop(first);
+        let third = limits.acquire(&alice).unwrap();
+        assert!(limits.acquire(&alice).is_none());
+        drop(third);
+        drop(second);
+        assert!(limits.open.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+
+use uchat_proto::ids::{RoomId, UserId};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Drops `SendMessage` retries that already went out through any gateway
+/// instance sharing the same Redis.
+///
+/// Each room keeps a sorted set `{room_id}:msg_ids` whose members are
+/// message keys scored by their expiry time. `ZADD NX` is both the check
+/// and the insert, so two instances racing on the same retry can't both
+/// win. Expired members are trimmed with `ZREMRANGEBYSCORE` in the same
+/// transaction, and the set itself expires once its room goes quiet.
+#[derive(Clone)]
+pub struct RedisDeduplicator<C = MultiplexedConnection> {
+    conn: C,
+    ttl: Duration,
+}
+
+/// The key a client retry is recognised by: the sender plus the frame's
+/// `cid`, which clients reuse when they resend.
+pub fn message_key(from: &UserId, cid: &str) -> String {
+    format!("{}:{}", from, cid)
+}
+
+impl RedisDeduplicator {
+    /// Connects to `REDIS_URL`, or returns `None` when it is unset or
+    /// unreachable. `GATEWAY_DEDUP_TTL_SECS` sets how long a message key
+    /// is remembered (default five minutes).
+    pub async fn from_env() -> Option<Self> {
+        let url = std::env::var("REDIS_URL").ok().filter(|v| !v.is_empty())?;
+        let ttl = std::env::var("GATEWAY_DEDUP_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TTL);
+
+        let conn = match redis::Client::open(url) {
+            Ok(client) => client.get_multiplexed_tokio_connection().await,
+            Err(e) => Err(e),
+        };
+        match conn {
+            Ok(conn) => Some(Self::new(conn, ttl)),
+            Err(e) => {
+                tracing::warn!(error = %e, "redis dedup disabled");
+                None
+            }
+        }
+    }
+}
+
+impl<C: ConnectionLike + Clone> RedisDeduplicator<C> {
+    pub fn new(conn: C, ttl: Duration) -> Self {
+        Self { conn, ttl }
+    }
+
+    /// True the first time `message_key` is seen in `room_id` within the
+    /// TTL. Redis errors count as first-seen: a duplicate is better than
+    /// a lost message.
+    pub async fn first_seen(&self, room_id: &RoomId, message_key: &str) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        match self.first_seen_at(room_id, message_key, now).await {
+            Ok(first) => first,
+            Err(e) => {
+                tracing::warn!(error = %e, "redis dedup check failed");
+                true
+            }
+        }
+    }
+
+    async fn first_seen_at(&self, room_id: &RoomId, message_key: &str, now_ms: u64) -> redis::RedisResult<bool> {
+        let key = format!("{}:msg_ids", room_id);
+        let expires_at = now_ms + self.ttl.as_millis() as u64;
+
+        let (added,): (i64,) = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE").arg(&key).arg("-inf").arg(now_ms).ignore()
+            .cmd("ZADD").arg(&key).arg("NX").arg(expires_at).arg(message_key)
+            .cmd("PEXPIRE").arg(&key).arg(self.ttl.as_millis() as u64).ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(added == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Value;
+    use redis_test::{MockCmd, MockRedisConnection};
+    use uchat_proto::ids::ChannelId;
+
+    fn check(room_id: &RoomId, member: &str, now_ms: u64, added: i64) -> MockCmd {
+        let key = format!("{}:msg_ids", room_id);
+        let pipe = redis::pipe()
+            .atomic()
+            .cmd("ZREMRANGEBYSCORE").arg(&key).arg("-inf").arg(now_ms).ignore()
+            .cmd("ZADD").arg(&key).arg("NX").arg(now_ms + 60_000).arg(member)
+            .cmd("PEXPIRE").arg(&key).arg(60_000).ignore()
+            .clone();
+        let results = Value::Array(vec![Value::Int(0), Value::Int(added), Value::Int(1)]);
+        MockCmd::new(pipe, Ok(results))
+    }
+
+    #[tokio::test]
+    async fn second_sighting_is_a_duplicate() {
+        let room = RoomId::from(ChannelId::new());
+        let other = RoomId::from(ChannelId::new());
+        let key = message_key(&UserId::new(), "c1");
+
+        let conn = MockRedisConnection::new(vec![
+            check(&room, &key, 1_000, 1),
+            check(&room, &key, 2_000, 0),
+            check(&other, &key, 2_000, 1),
+        ]);
+        let dedup = RedisDeduplicator::new(conn, Duration::from_secs(60));
+
+        assert!(dedup.first_seen_at(&room, &key, 1_000).await.unwrap());
+        assert!(!dedup.first_seen_at(&room, &key, 2_000).await.unwrap());
+        assert!(dedup.first_seen_at(&other, &key, 2_000).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn redis_errors_fail_open() {
+        let dedup = RedisDeduplicator::new(MockRedisConnection::new(vec![]), Duration::from_secs(60));
+        let room = RoomId::from(ChannelId::new());
+        assert!(dedup.first_seen(&room, "u:c1").await);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use uchat_proto::ids::RoomId;
+
+use crate::internal::authorized;
+use crate::{AppState, RoomMessage};
+
+/// Dead letters kept; the oldest go first.
+const CAPACITY: usize = 10_000;
+const DEFAULT_LIMIT: usize = 100;
+
+/// A client message no subscriber received.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub room_id: RoomId,
+    /// The `ServerEvent` JSON that would have gone out.
+    pub payload: Arc<str>,
+    /// Unix millis.
+    pub failed_at: i64,
+}
+
+/// Client messages published to rooms with no subscribers on this
+/// instance, kept so an operator can look at them and send them again.
+/// Messages the event hub carried may have reached other instances and
+/// aren't kept.
+pub struct DeadLetterQueue {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    next_id: u64,
+    letters: VecDeque<DeadLetter>,
+}
+
+impl Default for DeadLetterQueue {
+    fn default() -> Self {
+        Self { inner: Mutex::new(Inner { next_id: 1, letters: VecDeque::new() }) }
+    }
+}
+
+impl DeadLetterQueue {
+    pub fn push(&self, room_id: RoomId, payload: Arc<str>) {
+        let failed_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64);
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.letters.len() >= CAPACITY {
+            inner.letters.pop_front();
+        }
+        inner.letters.push_back(DeadLetter { id, room_id, payload, failed_at });
+    }
+
+    /// The newest `limit` dead letters, newest first.
+    pub fn list(&self, limit: usize) -> Vec<DeadLetter> {
+        self.inner.lock().unwrap().letters.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Removes and returns dead letter `id`.
+    pub fn take(&self, id: u64) -> Option<DeadLetter> {
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner.letters.iter().position(|letter| letter.id == id)?;
+        inner.letters.remove(index)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    limit: Option<usize>,
+}
+
+/// GET /admin/dlq?limit=100
+///
+/// The newest dead letters first.
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<Vec<DeadLetter>>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(state.dead_letters.list(query.limit.unwrap_or(DEFAULT_LIMIT))))
+}
+
+#[derive(Serialize)]
+pub struct Retried {
+    /// Whether a subscriber got it this time; if not, it is back in the
+    /// queue under a new id.
+    delivered: bool,
+}
+
+/// POST /admin/dlq/{id}/retry
+///
+/// Publishes a dead letter to its room again.
+pub async fn retry(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Retried>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let letter = state.dead_letters.take(id).ok_or(StatusCode::NOT_FOUND)?;
+    let message = RoomMessage::json(&letter.payload, None);
+    let delivered = state.deliver(&letter.room_id, message).await;
+    Ok(Json(Retried { delivered }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::INTERNAL_TOKEN_HEADER;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use axum::http::Request;
+    use std::time::Instant;
+    use tower::ServiceExt;
+    use uchat_proto::ids::ChannelId;
+
+    #[test]
+    fn drops_the_oldest_past_capacity() {
+        let queue = DeadLetterQueue::default();
+        let room = RoomId::from(ChannelId::new());
+        for i in 0..CAPACITY + 5 {
+            queue.push(room.clone(), i.to_string().into());
+        }
+
+        let newest = queue.list(CAPACITY + 5);
+        assert_eq!(newest.len(), CAPACITY);
+        assert_eq!(&*newest[0].payload, (CAPACITY + 4).to_string());
+        assert_eq!(&*newest.last().unwrap().payload, "5");
+        assert!(queue.take(1).is_none());
+        assert_eq!(queue.take(6).unwrap().id, 6);
+        assert!(queue.take(6).is_none());
+    }
+
+    async fn call(state: &Arc<AppState>, req: axum::http::request::Builder) -> (StatusCode, serde_json::Value) {
+        let req = req.header(INTERNAL_TOKEN_HEADER, "internal-secret").body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn undelivered_messages_can_be_listed_and_retried() {
+        let state = test_state();
+        let room = RoomId::from(ChannelId::new());
+        state.broadcast(&room, "presence".into()).await;
+        state.broadcast_timed(&room, "first".into(), Instant::now()).await;
+        state.broadcast_timed(&room, "second".into(), Instant::now()).await;
+
+        let (status, letters) = call(&state, Request::get("/admin/dlq?limit=1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(letters.as_array().unwrap().len(), 1);
+        assert_eq!(letters[0]["payload"], "second");
+        assert_eq!(letters[0]["room_id"], room.to_string());
+        let (_, letters) = call(&state, Request::get("/admin/dlq")).await;
+        assert_eq!(letters.as_array().unwrap().len(), 2);
+        let first = letters[1]["id"].as_u64().unwrap();
+
+        let mut rx = state.room(&room).await.subscribe();
+        let (status, retried) = call(&state, Request::post(format!("/admin/dlq/{}/retry", first))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(retried["delivered"], true);
+        assert_eq!(rx.recv().await.unwrap().as_json().unwrap(), "first");
+        assert_eq!(state.dead_letters.list(10).len(), 1);
+
+        let (status, _) = call(&state, Request::post(format!("/admin/dlq/{}/retry", first))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let req = Request::get("/admin/dlq").body(Body::empty()).unwrap();
+        assert_eq!(app(state).oneshot(req).await.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,352 @@
+//! Content filters on the message path. Every message that isn't
+//! end-to-end encrypted passes each `MessageFilter` in turn before it is
+//! sanitized and sent: a redaction hands the rewritten text to the next
+//! filter, and a block refuses the message with `ContentBlocked`.
+//!
+//! Two filters ship here: channel admins' word and regex rules, pushed by
+//! channels-api, and an optional external webhook. Deployments with other
+//! policies can implement the trait in their own crate and `add` it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use regex::{NoExpand, Regex};
+
+use uchat_proto::filters::{ChannelFilterRules, FilterRequest, FilterRule, FilterVerdict};
+use uchat_proto::ids::{ChannelId, UserId};
+
+use crate::AppState;
+
+const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[async_trait]
+pub trait MessageFilter: Send + Sync + 'static {
+    /// For logs and the audit trail, e.g. `rules`.
+    fn name(&self) -> &str;
+
+    /// The verdict on `content`, which `from` is sending to `channel_id`.
+    /// Never called for encrypted messages.
+    async fn check(&self, channel_id: &ChannelId, from: &UserId, content: &str) -> FilterVerdict;
+}
+
+/// The filters a message passes, in order: channel rules first, then the
+/// webhook, then any added since.
+pub struct ContentFilters {
+    rules: Arc<RuleFilter>,
+    filters: RwLock<Vec<Arc<dyn MessageFilter>>>,
+}
+
+impl Default for ContentFilters {
+    fn default() -> Self {
+        let rules = Arc::new(RuleFilter::default());
+        Self { filters: RwLock::new(vec![rules.clone()]), rules }
+    }
+}
+
+/// What the filters made of a message.
+#[derive(Debug, PartialEq)]
+pub struct Filtered {
+    /// The message as it goes out, or `None` when it was blocked.
+    pub content: Option<String>,
+    /// The filters that redacted or blocked it, and their verdicts.
+    pub decisions: Vec<(String, FilterVerdict)>,
+}
+
+impl ContentFilters {
+    /// Channel rules, plus the webhook when `GATEWAY_FILTER_WEBHOOK_URL`
+    /// is set.
+    pub fn from_env() -> Self {
+        let filters = Self::default();
+        if let Some(webhook) = WebhookFilter::from_env() {
+            filters.filters.write().unwrap().push(Arc::new(webhook));
+        }
+        filters
+    }
+
+    pub fn rules(&self) -> &RuleFilter {
+        &self.rules
+    }
+
+    /// Runs `content` through every filter, stopping at the first block.
+    pub async fn check(&self, channel_id: &ChannelId, from: &UserId, mut content: String) -> Filtered {
+        let filters = self.filters.read().unwrap().clone();
+        let mut decisions = Vec::new();
+        for filter in filters {
+            let verdict = filter.check(channel_id, from, &content).await;
+            match &verdict {
+                FilterVerdict::Allow => continue,
+                FilterVerdict::Redact { content: redacted } => content.clone_from(redacted),
+                FilterVerdict::Block { .. } => {
+                    decisions.push((filter.name().to_string(), verdict));
+                    return Filtered { content: None, decisions };
+                }
+            }
+            decisions.push((filter.name().to_string(), verdict));
+        }
+        Filtered { content: Some(content), decisions }
+    }
+}
+
+/// Adds `filter` after those already running on `state`.
+pub fn add(state: &AppState, filter: Arc<dyn MessageFilter>) {
+    tracing::info!(filter = filter.name(), "adding content filter");
+    state.filters.filters.write().unwrap().push(filter);
+}
+
+/// Fetches every channel's rules from channels-api in the background, as
+/// a gateway starts with none; changes after that are pushed to it.
+pub fn load_rules(state: &Arc<AppState>) {
+    let (Some(channels), Some(token)) = (state.channels.clone(), state.internal_token.clone()) else {
+        return;
+    };
+    let state = Arc::downgrade(state);
+    uchat_metrics::spawn_task("content_filter_load", async move {
+        match channels.content_filters(&token).await {
+            Ok(all) => {
+                let Some(state) = state.upgrade() else { return };
+                tracing::info!(channels = all.len(), "loaded content filter rules");
+                for rules in all {
+                    state.filters.rules().set(rules);
+                }
+            }
+            Err(e) => tracing::warn!("loading content filter rules failed: {}", e),
+        }
+    });
+}
+
+/// Channel admins' rules, by channel, as channels-api last pushed them.
+#[derive(Default)]
+pub struct RuleFilter {
+    channels: RwLock<HashMap<ChannelId, Vec<CompiledRule>>>,
+}
+
+struct CompiledRule {
+    id: i64,
+    matcher: Regex,
+    replacement: Option<String>,
+}
+
+impl RuleFilter {
+    /// Replaces a channel's rules. Rules that don't compile are skipped;
+    /// channels-api refuses them, so this only happens across versions.
+    pub fn set(&self, rules: ChannelFilterRules) {
+        let compiled: Vec<CompiledRule> = rules
+            .rules
+            .iter()
+            .filter_map(|rule| match matcher(rule) {
+                Ok(matcher) => Some(CompiledRule { id: rule.id, matcher, replacement: rule.replacement.clone() }),
+                Err(e) => {
+                    tracing::warn!(rule_id = rule.id, "skipping filter rule: {}", e);
+                    None
+                }
+            })
+            .collect();
+        let mut channels = self.channels.write().unwrap();
+        if compiled.is_empty() {
+            channels.remove(&rules.channel_id);
+        } else {
+            channels.insert(rules.channel_id, compiled);
+        }
+    }
+}
+
+/// Words and phrases match in any case, and only as whole words where
+/// they start or end with a word character.
+fn matcher(rule: &FilterRule) -> Result<Regex, regex::Error> {
+    if rule.regex {
+        return Regex::new(&rule.pattern);
+    }
+    let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let start = if word(rule.pattern.chars().next()) { r"\b" } else { "" };
+    let end = if word(rule.pattern.chars().last()) { r"\b" } else { "" };
+    Regex::new(&format!("(?i){}{}{}", start, regex::escape(&rule.pattern), end))
+}
+
+#[async_trait]
+impl MessageFilter for RuleFilter {
+    fn name(&self) -> &str {
+        "rules"
+    }
+
+    /// Blocking rules are checked first, against the text as written.
+    async fn check(&self, channel_id: &ChannelId, _from: &UserId, content: &str) -> FilterVerdict {
+        let channels = self.channels.read().unwrap();
+        let Some(rules) = channels.get(channel_id) else {
+            return FilterVerdict::Allow;
+        };
+        if let Some(rule) = rules.iter().find(|r| r.replacement.is_none() && r.matcher.is_match(content)) {
+            return FilterVerdict::Block { reason: format!("rule {}", rule.id) };
+        }
+        let mut redacted = content.to_string();
+        for rule in rules {
+            if let Some(replacement) = &rule.replacement {
+                redacted = rule.matcher.replace_all(&redacted, NoExpand(replacement)).into_owned();
+            }
+        }
+        if redacted == content {
+            FilterVerdict::Allow
+        } else {
+            FilterVerdict::Redact { content: redacted }
+        }
+    }
+}
+
+/// Asks an external service about each message: a `FilterRequest` is
+/// POSTed to it and it answers with a `FilterVerdict`.
+pub struct WebhookFilter {
+    client: reqwest::Client,
+    url: String,
+    /// Whether messages go out when the webhook fails or is too slow.
+    fail_open: bool,
+}
+
+impl WebhookFilter {
+    pub fn new(url: &str, timeout: Duration, fail_open: bool) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("build filter webhook client");
+        Self { client, url: url.to_string(), fail_open }
+    }
+
+    /// `GATEWAY_FILTER_WEBHOOK_URL`, with `GATEWAY_FILTER_WEBHOOK_TIMEOUT_MS`
+    /// (default 500) and `GATEWAY_FILTER_WEBHOOK_FAIL_OPEN` (default
+    /// false: messages are blocked while the webhook is down); `None` when
+    /// the URL is unset.
+    pub fn from_env() -> Option<Self> {
+        let url = std::env::var("GATEWAY_FILTER_WEBHOOK_URL").ok().filter(|v| !v.is_empty())?;
+        let timeout = std::env::var("GATEWAY_FILTER_WEBHOOK_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map_or(DEFAULT_WEBHOOK_TIMEOUT, Duration::from_millis);
+        let fail_open = std::env::var("GATEWAY_FILTER_WEBHOOK_FAIL_OPEN")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(false);
+        Some(Self::new(&url, timeout, fail_open))
+    }
+
+    async fn ask(&self, request: &FilterRequest) -> Result<FilterVerdict, reqwest::Error> {
+        self.client.post(&self.url).json(request).send().await?.error_for_status()?.json().await
+    }
+}
+
+#[async_trait]
+impl MessageFilter for WebhookFilter {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn check(&self, channel_id: &ChannelId, from: &UserId, content: &str) -> FilterVerdict {
+        let request = FilterRequest { channel_id: channel_id.clone(), user_id: from.clone(), content: content.to_string() };
+        match self.ask(&request).await {
+            Ok(verdict) => verdict,
+            Err(e) if self.fail_open => {
+                tracing::warn!("filter webhook failed, letting the message through: {}", e);
+                FilterVerdict::Allow
+            }
+            Err(e) => {
+                tracing::warn!("filter webhook failed, blocking the message: {}", e);
+                FilterVerdict::Block { reason: "filter webhook unavailable".into() }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use chrono::Utc;
+
+    fn rule(id: i64, pattern: &str, regex: bool, replacement: Option<&str>) -> FilterRule {
+        FilterRule {
+            id,
+            channel_id: ChannelId::new(),
+            pattern: pattern.into(),
+            regex,
+            replacement: replacement.map(str::to_string),
+            created_by: UserId::new(),
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn webhook(answer: FilterVerdict, delay: Duration) -> String {
+        let app = Router::new().route(
+            "/filter",
+            post(move |Json(_): Json<FilterRequest>| async move {
+                tokio::time::sleep(delay).await;
+                Json(answer)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/filter", addr)
+    }
+
+    #[tokio::test]
+    async fn rules_block_before_they_redact() {
+        let (channel_id, from) = (ChannelId::new(), UserId::new());
+        let filter = RuleFilter::default();
+        filter.set(ChannelFilterRules {
+            channel_id: channel_id.clone(),
+            rules: vec![
+                rule(1, "darn", false, Some("****")),
+                rule(2, r"\b\d{3}-\d{2}-\d{4}\b", true, Some("[ssn]")),
+                rule(3, "project falcon", false, None),
+                rule(4, "(unclosed", true, None),
+            ],
+        });
+        let check = |content: &'static str| filter.check(&channel_id, &from, content);
+
+        assert_eq!(check("Darn it, my SSN is 123-45-6789").await, FilterVerdict::Redact { content: "**** it, my SSN is [ssn]".into() });
+        assert_eq!(check("darning socks").await, FilterVerdict::Allow);
+        assert_eq!(check("darn, Project  Falcon? project falcon!").await, FilterVerdict::Block { reason: "rule 3".into() });
+        // The broken regex was skipped rather than matching literally.
+        assert_eq!(check("(unclosed").await, FilterVerdict::Allow);
+        assert_eq!(filter.check(&ChannelId::new(), &from, "darn").await, FilterVerdict::Allow);
+
+        filter.set(ChannelFilterRules { channel_id: channel_id.clone(), rules: vec![] });
+        assert_eq!(check("darn").await, FilterVerdict::Allow);
+    }
+
+    #[tokio::test]
+    async fn webhook_failures_follow_the_configured_policy() {
+        let (channel_id, from) = (ChannelId::new(), UserId::new());
+        let redact = FilterVerdict::Redact { content: "[removed]".into() };
+        let url = webhook(redact.clone(), Duration::ZERO).await;
+        let filter = WebhookFilter::new(&url, Duration::from_millis(500), false);
+        assert_eq!(filter.check(&channel_id, &from, "secret").await, redact);
+
+        let slow = webhook(FilterVerdict::Allow, Duration::from_secs(5)).await;
+        let closed = WebhookFilter::new(&slow, Duration::from_millis(50), false);
+        assert!(matches!(closed.check(&channel_id, &from, "hi").await, FilterVerdict::Block { .. }));
+        let open = WebhookFilter::new(&slow, Duration::from_millis(50), true);
+        assert_eq!(open.check(&channel_id, &from, "hi").await, FilterVerdict::Allow);
+    }
+
+    #[tokio::test]
+    async fn filters_run_in_order_on_the_redacted_text() {
+        let (channel_id, from) = (ChannelId::new(), UserId::new());
+        let filters = ContentFilters::default();
+        filters.rules().set(ChannelFilterRules { channel_id: channel_id.clone(), rules: vec![rule(1, "heck", false, Some("h***"))] });
+        let url = webhook(FilterVerdict::Block { reason: "export control".into() }, Duration::ZERO).await;
+        filters.filters.write().unwrap().push(Arc::new(WebhookFilter::new(&url, Duration::from_secs(1), true)));
+
+        let filtered = filters.check(&channel_id, &from, "what the heck".into()).await;
+        assert_eq!(filtered.content, None);
+        assert_eq!(
+            filtered.decisions,
+            vec![
+                ("rules".to_string(), FilterVerdict::Redact { content: "what the h***".into() }),
+                ("webhook".to_string(), FilterVerdict::Block { reason: "export control".into() }),
+            ]
+        );
+
+        let filtered = ContentFilters::default().check(&channel_id, &from, "what the heck".into()).await;
+        assert_eq!(filtered, Filtered { content: Some("what the heck".into()), decisions: vec![] });
+    }
+}
@@ -0,0 +1,323 @@
+//! The gRPC interface in `proto/gateway.proto`, for internal services
+//! that want something typed rather than `/internal/*`. It runs on its
+//! own port and takes the same shared secret, as `x-internal-token`
+//! metadata.
+
+// tonic hands `Status` around by value; boxing it here would only mean
+// unboxing it again at every call site.
+#![allow(clippy::result_large_err)]
+
+/// Messages, clients and server stubs, generated from
+/// `proto/gateway.proto` by `build.rs`.
+pub mod pb {
+    tonic::include_proto!("uchat.gateway.v1");
+}
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+use uchat_proto::channels::MembershipChange;
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::{ChannelId, RoomId, UserId};
+
+use crate::internal::INTERNAL_TOKEN_HEADER;
+use crate::{AppState, OutgoingMessage};
+use pb::presence_service_server::{PresenceService, PresenceServiceServer};
+use pb::room_service_server::{RoomService, RoomServiceServer};
+
+/// Serves `RoomService` and `PresenceService` on `listener`.
+pub async fn serve(listener: TcpListener, state: Arc<AppState>) {
+    tracing::info!("gateway-service gRPC listening on {}", listener.local_addr().unwrap());
+    let check = authorize(state.internal_token.clone());
+    let result = tonic::transport::Server::builder()
+        .add_service(RoomServiceServer::with_interceptor(Rooms(state.clone()), check.clone()))
+        .add_service(PresenceServiceServer::with_interceptor(Presence(state), check))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await;
+    if let Err(e) = result {
+        tracing::error!(error = %e, "gRPC server stopped");
+    }
+}
+
+/// Refuses calls without the internal token, and every call when none is
+/// configured.
+fn authorize(expected: Option<String>) -> impl tonic::service::Interceptor + Clone {
+    move |req: Request<()>| {
+        let supplied = req.metadata().get(INTERNAL_TOKEN_HEADER).and_then(|v| v.to_str().ok());
+        match (&expected, supplied) {
+            (Some(expected), Some(supplied)) if expected == supplied => Ok(req),
+            _ => Err(Status::unauthenticated("invalid internal token")),
+        }
+    }
+}
+
+fn parse<T: FromStr>(field: &str, value: &str) -> Result<T, Status> {
+    value.parse().map_err(|_| Status::invalid_argument(format!("invalid {}", field)))
+}
+
+/// A `Nack` code as a status, named as clients see it on the socket.
+fn rejected(code: ErrorCode) -> Status {
+    let name = serde_json::to_value(code).ok().and_then(|v| v.as_str().map(str::to_string));
+    Status::failed_precondition(name.unwrap_or_default())
+}
+
+struct Rooms(Arc<AppState>);
+
+#[tonic::async_trait]
+impl RoomService for Rooms {
+    async fn list_rooms(&self, _: Request<pb::ListRoomsRequest>) -> Result<Response<pb::ListRoomsResponse>, Status> {
+        let rooms = self
+            .0
+            .room_subscribers()
+            .await
+            .into_iter()
+            .map(|room| pb::Room { room_id: room.room_id.to_string(), subscribers: room.subscribers })
+            .collect();
+        Ok(Response::new(pb::ListRoomsResponse { rooms }))
+    }
+
+    /// Trusted callers may post anywhere, so unlike a socket there is no
+    /// role to check; everything after that is the socket's own path.
+    async fn broadcast_to_room(
+        &self,
+        request: Request<pb::BroadcastToRoomRequest>,
+    ) -> Result<Response<pb::BroadcastToRoomResponse>, Status> {
+        let req = request.into_inner();
+        let from: UserId = parse("from", &req.from)?;
+        let channel_id: ChannelId = parse("channel_id", &req.channel_id)?;
+        let thread_id = match req.thread_id.as_str() {
+            "" => None,
+            id => Some(parse("thread_id", id)?),
+        };
+        let room_id = RoomId { channel: channel_id.clone(), thread: thread_id.clone() };
+        let content_type = if req.content_type.is_empty() { "text/plain".into() } else { req.content_type };
+        let cid = Some(req.client_id).filter(|cid| !cid.is_empty());
+
+        let message = OutgoingMessage {
+            room_id: channel_id,
+            thread_id,
+            content: req.content,
+            encrypted: req.encrypted,
+            content_type,
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: None,
+            sender_joined: None,
+        };
+        match self.0.send_message(&from, cid.as_deref(), message, Instant::now()).await {
+            Ok(sent) => {
+                let seq = sent.map_or(0, |(_, seq)| seq);
+                Ok(Response::new(pb::BroadcastToRoomResponse { room_id: room_id.to_string(), seq }))
+            }
+            Err(code) => Err(rejected(code)),
+        }
+    }
+
+    async fn kick_user(&self, request: Request<pb::KickUserRequest>) -> Result<Response<pb::KickUserResponse>, Status> {
+        let req = request.into_inner();
+        let channel_id = parse("channel_id", &req.channel_id)?;
+        let user_id = parse("user_id", &req.user_id)?;
+        // The membership lives in channels-api; a kick that only closed
+        // sockets would be undone by the next reconnect.
+        let (Some(channels), Some(token)) = (&self.0.channels, &self.0.internal_token) else {
+            return Err(Status::unavailable("channels-api is not configured"));
+        };
+        match channels.kick(token, &channel_id, &user_id).await {
+            Ok(status) if status.is_success() => {}
+            Ok(reqwest::StatusCode::NOT_FOUND) => return Err(Status::not_found("not a member")),
+            Ok(reqwest::StatusCode::CONFLICT) => {
+                return Err(Status::failed_precondition("a channel must keep at least one admin"))
+            }
+            Ok(status) => return Err(Status::internal(format!("channels-api returned {}", status))),
+            Err(e) => return Err(Status::unavailable(e)),
+        }
+        // No receivers just means the user has no open sockets.
+        let _ = self.0.membership.send(MembershipChange { channel_id, user_id, role: None });
+        Ok(Response::new(pb::KickUserResponse {}))
+    }
+}
+
+struct Presence(Arc<AppState>);
+
+#[tonic::async_trait]
+impl PresenceService for Presence {
+    async fn get_presence(
+        &self,
+        request: Request<pb::GetPresenceRequest>,
+    ) -> Result<Response<pb::GetPresenceResponse>, Status> {
+        let channel_id: ChannelId = parse("channel_id", &request.get_ref().channel_id)?;
+        let online = self.0.presence.online_in(&channel_id).await;
+        let typing = self.0.presence.typing_in(&RoomId::from(channel_id)).await;
+        Ok(Response::new(pb::GetPresenceResponse {
+            online: online.iter().map(ToString::to_string).collect(),
+            typing: typing.iter().map(ToString::to_string).collect(),
+        }))
+    }
+
+    type StreamPresenceEventsStream = ReceiverStream<Result<pb::PresenceEvent, Status>>;
+
+    /// Relays the `Presence` events sockets already broadcast to the room,
+    /// so the stream sees exactly what subscribers do.
+    async fn stream_presence_events(
+        &self,
+        request: Request<pb::StreamPresenceEventsRequest>,
+    ) -> Result<Response<Self::StreamPresenceEventsStream>, Status> {
+        let channel_id: ChannelId = parse("channel_id", &request.get_ref().channel_id)?;
+        let room_id = RoomId::from(channel_id);
+        let mut rx = self.0.room(&room_id).await.subscribe();
+        let (tx, events) = mpsc::channel(64);
+        let state = self.0.clone();
+
+        uchat_metrics::spawn_task("grpc_presence_stream", async move {
+            loop {
+                let message = tokio::select! {
+                    _ = tx.closed() => break,
+                    message = rx.recv() => match message {
+                        Ok(message) => message,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                };
+                // Skip parsing the room's messages.
+                let Some(json) = message.as_json().filter(|json| json.starts_with("{\"Presence\"")) else { continue };
+                if let Ok(ServerEvent::Presence { room_id, user_id, online }) = serde_json::from_str(json) {
+                    let event = pb::PresenceEvent { channel_id: room_id.to_string(), user_id: user_id.to_string(), online };
+                    if tx.send(Ok(event)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            drop(rx);
+            state.cleanup_room(&room_id).await;
+        });
+        Ok(Response::new(ReceiverStream::new(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channels_client::ChannelsClient;
+    use crate::{sanitize, test_state};
+    use axum::extract::Path;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::delete;
+    use axum::Router;
+    use pb::presence_service_client::PresenceServiceClient;
+    use pb::room_service_client::RoomServiceClient;
+    use tonic::Code;
+
+    async fn start(state: Arc<AppState>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, state));
+        url
+    }
+
+    fn authed<T>(message: T) -> Request<T> {
+        let mut req = Request::new(message);
+        req.metadata_mut().insert(INTERNAL_TOKEN_HEADER, "internal-secret".parse().unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn broadcasts_like_a_socket_would() {
+        let state = test_state();
+        let mut rooms = RoomServiceClient::connect(start(state.clone()).await).await.unwrap();
+        let channel = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel.clone())).await.subscribe();
+
+        let err = rooms.list_rooms(pb::ListRoomsRequest {}).await.unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let listed = rooms.list_rooms(authed(pb::ListRoomsRequest {})).await.unwrap().into_inner();
+        assert_eq!(listed.rooms, vec![pb::Room { room_id: channel.to_string(), subscribers: 1 }]);
+
+        let bot = UserId::new();
+        let send = pb::BroadcastToRoomRequest {
+            channel_id: channel.to_string(),
+            from: bot.to_string(),
+            content: "<script>x</script>hi".into(),
+            ..Default::default()
+        };
+        let sent = rooms.broadcast_to_room(authed(send.clone())).await.unwrap().into_inner();
+        assert_eq!(sent.room_id, channel.to_string());
+        let message = rx.recv().await.unwrap();
+        match serde_json::from_str(message.as_json().unwrap()).unwrap() {
+            ServerEvent::MessageBroadcast { from, content, content_type, seq, .. } => {
+                assert_eq!(from, bot);
+                assert_eq!(content, sanitize::sanitize_content("<script>x</script>hi"));
+                assert_eq!(content_type, "text/plain");
+                assert_eq!(seq, Some(sent.seq));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        state.archived.write().await.insert(channel.clone());
+        let err = rooms.broadcast_to_room(authed(send)).await.unwrap_err();
+        assert_eq!((err.code(), err.message()), (Code::FailedPrecondition, "channel_archived"));
+        let bad = pb::BroadcastToRoomRequest { channel_id: "general".into(), ..Default::default() };
+        assert_eq!(rooms.broadcast_to_room(authed(bad)).await.unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn kicks_and_reports_presence() {
+        let (channel, user) = (ChannelId::new(), UserId::new());
+        let (tx, mut kicked) = mpsc::unbounded_channel::<(String, String)>();
+        let fake_api = Router::new().route(
+            "/internal/channels/:id/members/:user_id",
+            delete(move |Path(ids): Path<(String, String)>, headers: HeaderMap| async move {
+                assert_eq!(headers[INTERNAL_TOKEN_HEADER], "internal-secret");
+                let member = !ids.1.starts_with("00000000");
+                let _ = tx.send(ids);
+                if member { StatusCode::NO_CONTENT } else { StatusCode::NOT_FOUND }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, fake_api).await.unwrap() });
+
+        let mut unconfigured = RoomServiceClient::connect(start(test_state()).await).await.unwrap();
+        let kick = pb::KickUserRequest { channel_id: channel.to_string(), user_id: user.to_string() };
+        assert_eq!(unconfigured.kick_user(authed(kick)).await.unwrap_err().code(), Code::Unavailable);
+
+        let mut state = test_state();
+        Arc::get_mut(&mut state).unwrap().channels = Some(ChannelsClient::new(&api));
+        let url = start(state.clone()).await;
+        let (mut rooms, mut presence) =
+            (RoomServiceClient::connect(url.clone()).await.unwrap(), PresenceServiceClient::connect(url).await.unwrap());
+
+        let mut membership = state.membership.subscribe();
+        let kick = pb::KickUserRequest { channel_id: channel.to_string(), user_id: user.to_string() };
+        rooms.kick_user(authed(kick)).await.unwrap();
+        assert_eq!(kicked.recv().await.unwrap(), (channel.to_string(), user.to_string()));
+        let change = membership.recv().await.unwrap();
+        assert_eq!((change.channel_id, change.user_id, change.role), (channel.clone(), user.clone(), None));
+
+        let stranger = "00000000-0000-4000-8000-000000000000";
+        let kick = pb::KickUserRequest { channel_id: channel.to_string(), user_id: stranger.into() };
+        assert_eq!(rooms.kick_user(authed(kick)).await.unwrap_err().code(), Code::NotFound);
+        assert!(membership.try_recv().is_err());
+
+        let watch = pb::StreamPresenceEventsRequest { channel_id: channel.to_string() };
+        let mut events = presence.stream_presence_events(authed(watch)).await.unwrap().into_inner();
+        let room = RoomId::from(channel.clone());
+        state.broadcast(&room, r#"{"Typing":{}}"#.into()).await;
+        state.presence.heartbeat(&user, std::slice::from_ref(&channel)).await;
+        let joined = ServerEvent::Presence { room_id: channel.clone(), user_id: user.clone(), online: true };
+        state.broadcast(&room, serde_json::to_string(&joined).unwrap()).await;
+
+        let event = events.message().await.unwrap().unwrap();
+        let expected = pb::PresenceEvent { channel_id: channel.to_string(), user_id: user.to_string(), online: true };
+        assert_eq!(event, expected);
+        let now = presence.get_presence(authed(pb::GetPresenceRequest { channel_id: channel.to_string() })).await.unwrap();
+        assert_eq!(now.into_inner().online, vec![user.to_string()]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uchat_proto::events::SequencedMessage;
+use uchat_proto::ids::{ChannelId, MessageId, RoomId};
+
+/// Most messages one `Hello` replays.
+pub const MAX_REPLAY: usize = 500;
+/// Messages kept across all rooms.
+const CAPACITY: usize = 20_000;
+/// How long a message stays replayable; long enough to ride out a
+/// dropped connection, not to catch up after a day offline.
+const TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Recent client messages across all rooms, numbered in the order they
+/// went out, for sockets that reconnect with `Hello`. It lives in this
+/// instance's memory: numbers restart with the process, and don't carry
+/// over to another instance.
+pub struct RoomHistory {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// The `seq` the next message gets; numbering starts at 1.
+    next_seq: u64,
+    /// The newest `seq` dropped for space or age. Messages removed
+    /// because they were deleted don't count: there is nothing to miss.
+    evicted_through: u64,
+    entries: VecDeque<(Instant, SequencedMessage)>,
+}
+
+impl Default for RoomHistory {
+    fn default() -> Self {
+        Self { inner: Mutex::new(Inner { next_seq: 1, evicted_through: 0, entries: VecDeque::new() }) }
+    }
+}
+
+impl RoomHistory {
+    /// Numbers a message, whatever its `seq` was, and keeps it for
+    /// replay.
+    pub fn record(&self, message: SequencedMessage) -> SequencedMessage {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let message = SequencedMessage { seq: inner.next_seq, ..message };
+        inner.next_seq += 1;
+
+        while inner.entries.len() >= CAPACITY || inner.entries.front().is_some_and(|(at, _)| now - *at > TTL) {
+            if let Some((_, dropped)) = inner.entries.pop_front() {
+                inner.evicted_through = dropped.seq;
+            }
+        }
+        inner.entries.push_back((now, message.clone()));
+        message
+    }
+
+    /// The kept message in `room_id` numbered `seq` or stored as
+    /// `message_id`, whichever is given.
+    pub fn find(&self, room_id: &RoomId, seq: Option<u64>, message_id: Option<&MessageId>) -> Option<SequencedMessage> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter()
+            .rev()
+            .map(|(_, m)| m)
+            .filter(|m| m.room_id == *room_id)
+            .find(|m| match (seq, message_id) {
+                (Some(seq), _) => m.seq == seq,
+                (None, Some(id)) => m.message_id.as_ref() == Some(id),
+                (None, None) => false,
+            })
+            .cloned()
+    }
+
+    /// Forgets deleted or expired messages of `channel_id`, so replays
+    /// stop serving them.
+    pub fn remove(&self, channel_id: &ChannelId, message_ids: &[MessageId]) {
+        let message_ids: HashSet<&MessageId> = message_ids.iter().collect();
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.retain(|(_, m)| {
+            m.room_id.channel != *channel_id || !m.message_id.as_ref().is_some_and(|id| message_ids.contains(id))
+        });
+    }
+
+    /// Replaces an edited message's content, so replays show the edit.
+    pub fn edit(&self, channel_id: &ChannelId, message_id: &MessageId, content: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let kept = inner
+            .entries
+            .iter_mut()
+            .map(|(_, m)| m)
+            .filter(|m| m.room_id.channel == *channel_id && m.message_id.as_ref() == Some(message_id));
+        for message in kept {
+            message.content = content.to_string();
+        }
+    }
+
+    /// Messages after `last_seq` in channels `can_read` allows, oldest
+    /// first and at most `MAX_REPLAY` of the newest, plus whether any
+    /// were lost: cut by the cap, expired, or numbered by another process
+    /// so `last_seq` means nothing here.
+    pub fn since(&self, last_seq: u64, can_read: impl Fn(&ChannelId) -> bool) -> (Vec<SequencedMessage>, bool) {
+        let inner = self.inner.lock().unwrap();
+        let unknown = last_seq >= inner.next_seq;
+        let evicted = inner.evicted_through > last_seq;
+
+        let mut missed: Vec<SequencedMessage> = inner
+            .entries
+            .iter()
+            .rev()
+            .map(|(_, m)| m)
+            .take_while(|m| m.seq > last_seq)
+            .filter(|m| can_read(&m.room_id.channel))
+            .take(MAX_REPLAY + 1)
+            .cloned()
+            .collect();
+        let capped = missed.len() > MAX_REPLAY;
+        missed.truncate(MAX_REPLAY);
+        missed.reverse();
+        (missed, unknown || evicted || capped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uchat_proto::ids::UserId;
+
+    fn record(history: &RoomHistory, room: &ChannelId, content: &str) -> u64 {
+        let message = SequencedMessage {
+            seq: 0,
+            room_id: room.clone().into(),
+            from: UserId::new(),
+            content: content.into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: None,
+            redacted: false,
+        };
+        history.record(message).seq
+    }
+
+    #[test]
+    fn replays_readable_messages_after_last_seq() {
+        let history = RoomHistory::default();
+        let (mine, theirs) = (ChannelId::new(), ChannelId::new());
+        let first = record(&history, &mine, "one");
+        record(&history, &theirs, "secret");
+        let third = record(&history, &mine, "two");
+        assert_eq!((first, third), (1, 3));
+
+        let (missed, truncated) = history.since(first, |c| *c == mine);
+        assert_eq!(missed.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["two"]);
+        assert!(!truncated);
+
+        let (missed, truncated) = history.since(0, |c| *c == mine);
+        assert_eq!(missed.len(), 2);
+        assert!(!truncated);
+        assert!(history.since(third, |_| true).0.is_empty());
+    }
+
+    #[test]
+    fn finds_messages_by_seq_or_id_within_their_room() {
+        let history = RoomHistory::default();
+        let (room, other) = (ChannelId::new(), ChannelId::new());
+        let seq = record(&history, &room, "one");
+        let stored = MessageId::new();
+        let mut message = history.find(&room.clone().into(), Some(seq), None).unwrap();
+        message.message_id = Some(stored.clone());
+        let kept = history.record(message);
+
+        assert_eq!(history.find(&room.clone().into(), None, Some(&stored)).unwrap().seq, kept.seq);
+        assert!(history.find(&other.clone().into(), Some(seq), None).is_none());
+        assert!(history.find(&room.clone().into(), Some(99), None).is_none());
+        assert!(history.find(&room.into(), None, None).is_none());
+    }
+
+    #[test]
+    fn forgets_deleted_and_edited_content() {
+        let history = RoomHistory::default();
+        let (room, other) = (ChannelId::new(), ChannelId::new());
+        let stored = |room: &ChannelId, content: &str| {
+            let seq = record(&history, room, content);
+            let mut message = history.find(&room.clone().into(), Some(seq), None).unwrap();
+            message.message_id = Some(MessageId::new());
+            history.record(message).message_id.unwrap()
+        };
+        let contents = |room: &ChannelId| {
+            let (messages, truncated) = history.since(0, |c| c == room);
+            assert!(!truncated, "removals aren't losses");
+            messages.into_iter().filter(|m| m.message_id.is_some()).map(|m| m.content).collect::<Vec<_>>()
+        };
+        let (first, second) = (stored(&room, "first"), stored(&room, "second"));
+        let elsewhere = stored(&other, "elsewhere");
+
+        history.remove(&room, &[first, elsewhere.clone()]);
+        history.edit(&room, &second, "second, edited");
+        assert_eq!(contents(&room), ["second, edited"]);
+        assert_eq!(contents(&other), ["elsewhere"]);
+    }
+
+    #[test]
+    fn flags_what_it_cannot_replay() {
+        let history = RoomHistory::default();
+        let room = ChannelId::new();
+        for i in 0..MAX_REPLAY + 10 {
+            record(&history, &room, &i.to_string());
+        }
+
+        // Only the newest fit.
+        let (missed, truncated) = history.since(0, |_| true);
+        assert_eq!(missed.len(), MAX_REPLAY);
+        assert_eq!(missed[0].content, "10");
+        assert_eq!(missed.last().unwrap().seq, (MAX_REPLAY + 10) as u64);
+        assert!(truncated);
+
+        // A number this process never gave out, e.g. from before a restart.
+        let (missed, truncated) = history.since(1_000_000, |_| true);
+        assert!(missed.is_empty() && truncated);
+
+        // Expired out of the front.
+        let history = RoomHistory::default();
+        for content in ["a", "b", "c"] {
+            record(&history, &room, content);
+        }
+        {
+            let mut inner = history.inner.lock().unwrap();
+            let (_, expired) = inner.entries.pop_front().unwrap();
+            inner.evicted_through = expired.seq;
+        }
+        assert!(history.since(0, |_| true).1);
+        assert!(!history.since(1, |_| true).1);
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Forwards gateway broadcasts to event-hub-service.
+///
+/// Messages go through a bounded channel to a background task that owns
+/// the TCP connection, so the broadcast path never waits on the hub. Each
+/// message is written as one newline-terminated line. While the hub is
+/// unreachable the task keeps reconnecting and messages that do not fit
+/// in the channel are dropped.
+#[derive(Clone)]
+pub struct HubClient {
+    tx: mpsc::Sender<String>,
+}
+
+impl HubClient {
+    /// Builds a client from `EVENT_HUB_ADDR`, or `None` when it is unset.
+    pub fn from_env() -> Option<Self> {
+        let addr = std::env::var("EVENT_HUB_ADDR").ok()?;
+        Some(Self::spawn(addr))
+    }
+
+    pub fn spawn(addr: String) -> Self {
+        let (tx, rx) = mpsc::channel(1024);
+        uchat_metrics::spawn_task("hub_client", run(addr, rx));
+        Self { tx }
+    }
+
+    pub fn forward(&self, json: &str) {
+        let _ = self.tx.try_send(json.to_string());
+    }
+}
+
+async fn run(addr: String, mut rx: mpsc::Receiver<String>) {
+    loop {
+        let mut stream = match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                tracing::info!("connected to event hub at {}", addr);
+                stream
+            }
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        while let Some(mut line) = rx.recv().await {
+            line.push('\n');
+            if stream.write_all(line.as_bytes()).await.is_err() {
+                tracing::warn!("lost event hub connection, reconnecting");
+                break;
+            }
+        }
+
+        if rx.is_closed() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn forwards_lines_to_hub() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client = HubClient::spawn(addr);
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+
+        client.forward(r#"{"a":1}"#);
+        client.forward(r#"{"b":2}"#);
+
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), r#"{"a":1}"#);
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), r#"{"b":2}"#);
+    }
+}
@@ -0,0 +1,1040 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use serde::Deserialize;
+
+use uchat_proto::channels::{Channel, ChannelArchiveChanged, ChannelCreated, MembershipChange, RoomSubscribers};
+use uchat_proto::events::ServerEvent;
+use uchat_proto::files::FileQuarantined;
+use uchat_proto::filters::ChannelFilterRules;
+use uchat_proto::ids::{RoomId, UserId};
+use uchat_proto::messages::{
+    LinkPreviewReady, MessageDeleted, MessageEdited, MessagePosted, MessagesExpired, ReactionChanged,
+    ScheduledMessageCancelled,
+};
+use uchat_proto::moderation::{HeldUser, SpamDecided, SpamDecision, SpamDecisionRequest};
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::users::{UserDeleted, UserPresence};
+
+use crate::{AppState, OutgoingMessage};
+
+pub const INTERNAL_TOKEN_HEADER: &str = "x-internal-token";
+
+pub fn authorized(state: &AppState, headers: &HeaderMap) -> bool {
+    let supplied = headers.get(INTERNAL_TOKEN_HEADER).and_then(|v| v.to_str().ok());
+    matches!((&state.internal_token, supplied), (Some(expected), Some(supplied)) if expected == supplied)
+}
+
+/// POST /internal/membership
+///
+/// Called by channels-api after a member is added, re-roled or removed.
+/// Each of the user's sockets applies the new role, and a removal also
+/// drops their subscription to the room.
+pub async fn membership_changed(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(change): Json<MembershipChange>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    // No receivers just means the user has no open sockets.
+    let _ = state.membership.send(change);
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/channel-created
+///
+/// Called by channels-api after a channel is created with members. Each
+/// listed member's sockets get a `ChannelCreated` event.
+pub async fn channel_created(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(created): Json<ChannelCreated>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let Ok(json) = serde_json::to_string(&ServerEvent::ChannelCreated { channel: created.channel }) else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+    for member in created.member_ids {
+        let _ = state.user_events.send((member, json.clone()));
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/channel-updated
+///
+/// Called by channels-api when a channel's settings change. Only
+/// `allow_markdown_formatting` and `e2ee_required` matter here.
+pub async fn channel_updated(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(channel): Json<Channel>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let mut e2ee = state.e2ee_channels.write().await;
+    if channel.e2ee_required {
+        e2ee.insert(channel.id.clone());
+    } else {
+        e2ee.remove(&channel.id);
+    }
+    let mut markdown = state.markdown_channels.write().await;
+    if channel.allow_markdown_formatting {
+        markdown.insert(channel.id);
+    } else {
+        markdown.remove(&channel.id);
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/content-filters
+///
+/// Called by channels-api with a channel's whole rule list whenever a
+/// channel admin changes it.
+pub async fn content_filters_changed(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(rules): Json<ChannelFilterRules>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    state.filters.rules().set(rules);
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/user-deleted
+///
+/// Called by auth-api when an account is deleted. Its tokens are refused
+/// from now on, and its open sockets are told why and closed.
+pub async fn user_deleted(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(deleted): Json<UserDeleted>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    state.deleted_users.write().await.insert(deleted.user_id.clone());
+    if let Ok(json) = serde_json::to_string(&ServerEvent::error(ErrorCode::AccountDeleted, "account deleted")) {
+        let _ = state.user_events.send((deleted.user_id, json));
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/channel-archived
+///
+/// Called by channels-api when a channel is archived or reactivated.
+/// While archived, `SendMessage` frames to it are nacked; subscribers to
+/// its room hear about the change either way.
+pub async fn channel_archive_changed(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(change): Json<ChannelArchiveChanged>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let room_id = RoomId::from(change.channel_id.clone());
+    let event = match change.archived_at {
+        Some(archived_at) => {
+            state.archived.write().await.insert(change.channel_id.clone());
+            ServerEvent::ChannelArchived { room_id: change.channel_id, archived_at }
+        }
+        None => {
+            state.archived.write().await.remove(&change.channel_id);
+            ServerEvent::ChannelUnarchived { room_id: change.channel_id }
+        }
+    };
+    if let Ok(json) = serde_json::to_string(&event) {
+        state.broadcast(&room_id, json).await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/messages-expired
+///
+/// Called by channels-api's retention job after each purged batch. Only
+/// sockets subscribed to the channel's room or one of its thread rooms
+/// hear about it; the batch may hold replies from any thread. The
+/// history buffer forgets the batch.
+pub async fn messages_expired(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(expired): Json<MessagesExpired>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    state.history.remove(&expired.channel_id, &expired.message_ids);
+    let mut room_ids = state.thread_rooms(&expired.channel_id).await;
+    room_ids.push(RoomId::from(expired.channel_id.clone()));
+    let event = ServerEvent::MessagesExpired { room_id: expired.channel_id, message_ids: expired.message_ids };
+    if let Ok(json) = serde_json::to_string(&event) {
+        for room_id in &room_ids {
+            state.broadcast(room_id, json.clone()).await;
+        }
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/message-edited
+///
+/// Called by channels-api after a message's content changes. Replays
+/// from the history buffer show the new content too.
+pub async fn message_edited(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(edited): Json<MessageEdited>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    state.history.edit(&edited.channel_id, &edited.id, &edited.content);
+    let room_id = RoomId::from(edited.channel_id.clone());
+    let event = ServerEvent::MessageEdited {
+        id: edited.id,
+        channel_id: edited.channel_id,
+        content: edited.content,
+        edited_at: edited.edited_at,
+    };
+    if let Ok(json) = serde_json::to_string(&event) {
+        state.broadcast(&room_id, json).await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/reaction
+///
+/// Called by channels-api when a reaction is added or removed.
+pub async fn reaction_changed(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(change): Json<ReactionChanged>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let room_id = RoomId::from(change.channel_id.clone());
+    let (room, message_id, user_id, emoji) = (change.channel_id, change.message_id, change.user_id, change.emoji);
+    let event = if change.added {
+        ServerEvent::ReactionAdded { room_id: room, message_id, user_id, emoji }
+    } else {
+        ServerEvent::ReactionRemoved { room_id: room, message_id, user_id, emoji }
+    };
+    if let Ok(json) = serde_json::to_string(&event) {
+        state.broadcast(&room_id, json).await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/message-deleted
+///
+/// Called by channels-api after a message is soft deleted. Subscribers to
+/// the channel's room get the tombstone, and the history buffer forgets
+/// the message.
+pub async fn message_deleted(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(deleted): Json<MessageDeleted>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    state.history.remove(&deleted.channel_id, std::slice::from_ref(&deleted.id));
+    let room_id = RoomId::from(deleted.channel_id.clone());
+    let event = ServerEvent::MessageDeleted { id: deleted.id, channel_id: deleted.channel_id };
+    if let Ok(json) = serde_json::to_string(&event) {
+        state.broadcast(&room_id, json).await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/link-preview
+///
+/// Called by channels-api once it has unfurled a link in a stored message.
+pub async fn link_preview(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(ready): Json<LinkPreviewReady>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let room_id = RoomId::from(ready.channel_id);
+    let event = ServerEvent::LinkPreview { message_id: ready.message_id, preview: ready.preview };
+    if let Ok(json) = serde_json::to_string(&event) {
+        state.broadcast(&room_id, json).await;
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/file-quarantined
+///
+/// Called by channels-api when an upload fails its malware scan. Only the
+/// uploader's sockets are told.
+pub async fn file_quarantined(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(quarantined): Json<FileQuarantined>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let text = format!("Your file \"{}\" was quarantined: {}", quarantined.filename, quarantined.reason);
+    let event = ServerEvent::SystemMessage { room_id: quarantined.channel_id, text };
+    if let Ok(json) = serde_json::to_string(&event) {
+        let _ = state.user_events.send((quarantined.uploader_id, json));
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/scheduled-cancelled
+///
+/// Called by channels-api when a scheduled message can't be sent, such
+/// as because its author left the channel. Only the author's sockets are
+/// told.
+pub async fn scheduled_cancelled(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(cancelled): Json<ScheduledMessageCancelled>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let text = format!("Your scheduled message was not sent: {}", cancelled.reason);
+    let event = ServerEvent::SystemMessage { room_id: cancelled.channel_id, text };
+    if let Ok(json) = serde_json::to_string(&event) {
+        let _ = state.user_events.send((cancelled.author_id, json));
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// POST /internal/message-posted
+///
+/// Called by channels-api after storing a message posted over HTTP, such
+/// as through an incoming webhook or a scheduled message. It goes out the
+/// way a socket's `SendMessage` would; 409 when the channel is archived
+/// here.
+pub async fn message_posted(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(posted): Json<MessagePosted>,
+) -> StatusCode {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let message = OutgoingMessage {
+        room_id: posted.channel_id,
+        thread_id: posted.thread_id,
+        content: posted.content,
+        encrypted: false,
+        content_type: "text/plain".into(),
+        bridged_from: None,
+        sender_name: posted.sender_name,
+        message_id: Some(posted.id),
+        sender_role: None,
+        sender_joined: None,
+    };
+    match state.send_message(&posted.sender_id, None, message, std::time::Instant::now()).await {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::CONFLICT,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PresenceQuery {
+    ids: String,
+}
+
+/// GET /internal/presence?ids=a,b,c
+///
+/// Called by channels-api to fill in profiles' `online` field. Ids that
+/// don't parse are ignored.
+pub async fn presence(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<PresenceQuery>,
+) -> Result<Json<UserPresence>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let ids: Vec<UserId> = query.ids.split(',').filter_map(|id| id.trim().parse().ok()).collect();
+    Ok(Json(UserPresence { online: state.presence.online(&ids).await }))
+}
+
+/// GET /internal/rooms
+///
+/// The rooms this gateway has open, with how many receivers each has, for
+/// operators. Kicking someone is a `/internal/membership` removal.
+pub async fn rooms(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RoomSubscribers>>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(state.room_subscribers().await))
+}
+
+/// GET /internal/spam
+///
+/// Users whose messages are held as spam, for channels-api's moderation
+/// API.
+pub async fn held_users(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<Vec<HeldUser>>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(state.spam.held()))
+}
+
+/// POST /internal/spam/{user_id}
+///
+/// A moderator's decision on a held user, relayed by channels-api, which
+/// audits it. Clearing sends the held messages on as if just sent;
+/// confirming discards them. 404 when the user isn't held here.
+pub async fn decide_spam(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(request): Json<SpamDecisionRequest>,
+) -> Result<Json<SpamDecided>, StatusCode> {
+    if !authorized(&state, &headers) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let user_id: UserId = user_id.parse().map_err(|_| StatusCode::NOT_FOUND)?;
+    let messages = state.spam.decide(&user_id, request.decision).ok_or(StatusCode::NOT_FOUND)?;
+
+    let count = messages.len() as u32;
+    if request.decision == SpamDecision::Clear {
+        for message in messages {
+            if let Err(code) = state.send_unchecked(&user_id, None, message, std::time::Instant::now()).await {
+                tracing::warn!(user_id = %user_id, code = ?code, "held message refused on release");
+            }
+        }
+    }
+    tracing::info!(user_id = %user_id, decision = %request.decision, messages = count, "spam hold decided");
+    Ok(Json(SpamDecided { user_id, decision: request.decision, messages: count }))
+}
+
+/// GET /internal/metrics
+///
+/// Prometheus scrape endpoint.
+pub async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if !authorized(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let mut body = state.metrics.render();
+    uchat_metrics::render_tasks(&mut body);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use uchat_proto::channels::MemberRole;
+    use uchat_proto::errors::ErrorCode;
+    use uchat_proto::ids::{ChannelId, FileId, MessageId, ScheduledMessageId, UserId};
+    use uchat_proto::moderation::SpamReason;
+
+    fn request(token: Option<&str>, change: &MembershipChange) -> Request<Body> {
+        let mut req = Request::post("/internal/membership").header("Content-Type", "application/json");
+        if let Some(token) = token {
+            req = req.header(INTERNAL_TOKEN_HEADER, token);
+        }
+        req.body(Body::from(serde_json::to_string(change).unwrap())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn publishes_with_valid_token() {
+        let state = test_state();
+        let mut rx = state.membership.subscribe();
+        let change = MembershipChange {
+            channel_id: ChannelId::new(),
+            user_id: UserId::new(),
+            role: Some(MemberRole::Read),
+        };
+
+        let resp = app(state.clone()).oneshot(request(Some("internal-secret"), &change)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(rx.recv().await.unwrap(), change);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_or_wrong_token() {
+        let state = test_state();
+        let mut rx = state.membership.subscribe();
+        let change = MembershipChange { channel_id: ChannelId::new(), user_id: UserId::new(), role: None };
+
+        for token in [None, Some("nope")] {
+            let resp = app(state.clone()).oneshot(request(token, &change)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn deleted_accounts_lose_their_tokens() {
+        let state = test_state();
+        let user_id = UserId::new();
+        let token = uchat_proto::jwt::create_token("test-secret", user_id.as_str());
+        assert!(crate::verify_token(&state, &token).await.is_some());
+        let mut events = state.user_events.subscribe();
+
+        let req = Request::post("/internal/user-deleted")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&UserDeleted { user_id: user_id.clone() }).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        assert!(crate::verify_token(&state, &token).await.is_none());
+        let (to, json) = events.recv().await.unwrap();
+        assert_eq!(to, user_id);
+        assert!(json.contains("account_deleted"), "{}", json);
+    }
+
+    #[tokio::test]
+    async fn reports_presence_for_requested_users() {
+        let state = test_state();
+        let (online, offline) = (UserId::new(), UserId::new());
+        state.presence.connect(&online).await;
+
+        let uri = format!("/internal/presence?ids={},{},junk", online, offline);
+        let req = Request::get(&uri).header(INTERNAL_TOKEN_HEADER, "internal-secret").body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let presence: UserPresence = serde_json::from_slice(&body).unwrap();
+        assert_eq!(presence.online, vec![online]);
+
+        let req = Request::get(&uri).body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn lists_open_rooms_with_their_subscribers() {
+        let state = test_state();
+        let room_id = RoomId::from(ChannelId::new());
+        let _rx = state.room(&room_id).await.subscribe();
+
+        let req = Request::get("/internal/rooms").header(INTERNAL_TOKEN_HEADER, "internal-secret").body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let rooms: Vec<RoomSubscribers> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rooms, [RoomSubscribers { room_id, subscribers: 1 }]);
+
+        let req = Request::get("/internal/rooms").body(Body::empty()).unwrap();
+        let resp = app(state).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn channel_updates_toggle_markdown() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+
+        for allow in [true, false] {
+            let channel: Channel = serde_json::from_value(serde_json::json!({
+                "id": channel_id,
+                "name": "docs",
+                "description": "",
+                "channel_type": "public",
+                "created_by": UserId::new(),
+                "created_at": "2026-01-01T00:00:00Z",
+                "allow_markdown_formatting": allow,
+            }))
+            .unwrap();
+            let req = Request::post("/internal/channel-updated")
+                .header("Content-Type", "application/json")
+                .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+                .body(Body::from(serde_json::to_string(&channel).unwrap()))
+                .unwrap();
+            let resp = app(state.clone()).oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            assert_eq!(state.markdown_channels.read().await.contains(&channel_id), allow);
+        }
+    }
+
+    #[tokio::test]
+    async fn e2ee_only_channels_refuse_plaintext() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let channel: Channel = serde_json::from_value(serde_json::json!({
+            "id": channel_id,
+            "name": "legal",
+            "description": "",
+            "channel_type": "private",
+            "created_by": UserId::new(),
+            "created_at": "2026-01-01T00:00:00Z",
+            "e2ee_required": true,
+        }))
+        .unwrap();
+        let req = Request::post("/internal/channel-updated")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&channel).unwrap()))
+            .unwrap();
+        assert_eq!(app(state.clone()).oneshot(req).await.unwrap().status(), StatusCode::NO_CONTENT);
+
+        let send = |encrypted: bool| {
+            let message = OutgoingMessage {
+                room_id: channel_id.clone(),
+                thread_id: None,
+                content: "privileged".into(),
+                encrypted,
+                content_type: "text/plain".into(),
+                bridged_from: None,
+                sender_name: None,
+                message_id: None,
+                sender_role: None,
+                sender_joined: None,
+            };
+            let state = state.clone();
+            async move { state.send_message(&UserId::new(), None, message, std::time::Instant::now()).await }
+        };
+        assert_eq!(send(false).await, Err(ErrorCode::E2eeRequired));
+        assert!(send(true).await.unwrap().is_some());
+
+        // Messages stored over HTTP are plaintext too.
+        let posted = MessagePosted {
+            id: MessageId::new(),
+            channel_id: channel_id.clone(),
+            sender_id: UserId::new(),
+            sender_name: None,
+            content: "privileged".into(),
+            thread_id: None,
+        };
+        let req = Request::post("/internal/message-posted")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&posted).unwrap()))
+            .unwrap();
+        assert_eq!(app(state.clone()).oneshot(req).await.unwrap().status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn content_filters_apply_to_plain_messages_only() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let rules = serde_json::json!({
+            "channel_id": channel_id,
+            "rules": [
+                { "id": 1, "channel_id": channel_id, "pattern": "darn", "replacement": "****",
+                  "created_by": UserId::new(), "created_at": "2026-01-01T00:00:00Z" },
+                { "id": 2, "channel_id": channel_id, "pattern": "falcon",
+                  "created_by": UserId::new(), "created_at": "2026-01-01T00:00:00Z" },
+            ],
+        });
+        let req = Request::post("/internal/content-filters")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(rules.to_string()))
+            .unwrap();
+        assert_eq!(app(state.clone()).oneshot(req).await.unwrap().status(), StatusCode::NO_CONTENT);
+
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let send = |content: &str, encrypted: bool| {
+            let message = OutgoingMessage {
+                room_id: channel_id.clone(),
+                thread_id: None,
+                content: content.into(),
+                encrypted,
+                content_type: "text/plain".into(),
+                bridged_from: None,
+                sender_name: None,
+                message_id: None,
+                sender_role: None,
+                sender_joined: None,
+            };
+            let state = state.clone();
+            async move { state.send_message(&UserId::new(), None, message, std::time::Instant::now()).await }
+        };
+
+        assert!(send("darn it", false).await.unwrap().is_some());
+        let event: ServerEvent = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert!(matches!(event, ServerEvent::MessageBroadcast { content, redacted: true, .. } if content == "**** it"));
+
+        assert_eq!(send("project falcon", false).await, Err(ErrorCode::ContentBlocked));
+        assert!(rx.try_recv().is_err());
+
+        // Ciphertext is never inspected.
+        assert!(send("darn falcon", true).await.unwrap().is_some());
+        let event: ServerEvent = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert!(matches!(event, ServerEvent::MessageBroadcast { content, redacted: false, .. } if content == "darn falcon"));
+    }
+
+    #[tokio::test]
+    async fn archiving_locks_the_room_and_tells_subscribers() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+
+        for archived_at in [Some("2026-01-01T00:00:00Z"), None] {
+            let change: ChannelArchiveChanged = serde_json::from_value(serde_json::json!({
+                "channel_id": channel_id,
+                "archived_at": archived_at,
+            }))
+            .unwrap();
+            let req = Request::post("/internal/channel-archived")
+                .header("Content-Type", "application/json")
+                .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+                .body(Body::from(serde_json::to_string(&change).unwrap()))
+                .unwrap();
+            let resp = app(state.clone()).oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            assert_eq!(state.archived.read().await.contains(&channel_id), archived_at.is_some());
+        }
+
+        let event: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert_eq!(event["ChannelArchived"]["room_id"], channel_id.as_str());
+        let event: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert_eq!(event["ChannelUnarchived"]["room_id"], channel_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn expired_messages_reach_room_subscribers() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let thread = RoomId::thread(channel_id.clone(), MessageId::new());
+        let mut thread_rx = state.room(&thread).await.subscribe();
+        let mut other_rx = state.room(&RoomId::thread(ChannelId::new(), MessageId::new())).await.subscribe();
+        let expired = MessagesExpired { channel_id, message_ids: vec![MessageId::new()] };
+
+        let req = Request::post("/internal/messages-expired")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&expired).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        for rx in [&mut rx, &mut thread_rx] {
+            let event: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+            assert_eq!(event["MessagesExpired"]["message_ids"][0], expired.message_ids[0].as_str());
+        }
+        assert!(other_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn deleted_message_tombstone_reaches_room_subscribers() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let deleted = MessageDeleted { id: MessageId::new(), channel_id };
+
+        let req = Request::post("/internal/message-deleted")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&deleted).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let event: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert_eq!(event["MessageDeleted"]["id"], deleted.id.as_str());
+    }
+
+    #[tokio::test]
+    async fn link_previews_reach_room_subscribers() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let ready: LinkPreviewReady = serde_json::from_value(serde_json::json!({
+            "message_id": MessageId::new(),
+            "channel_id": channel_id,
+            "preview": { "url": "https://example.com/", "title": "Example" },
+        }))
+        .unwrap();
+
+        let req = Request::post("/internal/link-preview")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&ready).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let event: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert_eq!(event["LinkPreview"]["message_id"], ready.message_id.as_str());
+        assert_eq!(event["LinkPreview"]["preview"]["title"], "Example");
+    }
+
+    #[tokio::test]
+    async fn edits_reach_room_subscribers() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let edited: MessageEdited = serde_json::from_value(serde_json::json!({
+            "id": MessageId::new(),
+            "channel_id": channel_id,
+            "content": "fixed typo",
+            "edited_at": "2026-01-01T00:00:00Z",
+        }))
+        .unwrap();
+
+        let req = Request::post("/internal/message-edited")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&edited).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let event: serde_json::Value = serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap();
+        assert_eq!(event["MessageEdited"]["content"], "fixed typo");
+    }
+
+    #[tokio::test]
+    async fn channel_created_is_addressed_to_members() {
+        let state = test_state();
+        let mut rx = state.user_events.subscribe();
+        let member = UserId::new();
+        let created: ChannelCreated = serde_json::from_value(serde_json::json!({
+            "channel": {
+                "id": ChannelId::new(),
+                "name": "launch",
+                "description": "",
+                "channel_type": "private",
+                "created_by": UserId::new(),
+                "created_at": "2026-01-01T00:00:00Z",
+            },
+            "member_ids": [member],
+        }))
+        .unwrap();
+
+        let req = Request::post("/internal/channel-created")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&created).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let (to, json) = rx.recv().await.unwrap();
+        assert_eq!(to, member);
+        let event: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(event["ChannelCreated"]["channel"]["name"], "launch");
+    }
+
+    #[tokio::test]
+    async fn quarantines_are_told_to_the_uploader() {
+        let state = test_state();
+        let mut rx = state.user_events.subscribe();
+        let uploader = UserId::new();
+        let quarantined = FileQuarantined {
+            file_id: FileId::new(),
+            channel_id: ChannelId::new(),
+            uploader_id: uploader.clone(),
+            filename: "invoice.pdf".into(),
+            reason: "Eicar-Test-Signature".into(),
+        };
+
+        let req = Request::post("/internal/file-quarantined")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&quarantined).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let (to, json) = rx.recv().await.unwrap();
+        assert_eq!(to, uploader);
+        let event: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(event["SystemMessage"]["room_id"], quarantined.channel_id.as_str());
+        assert_eq!(event["SystemMessage"]["text"], "Your file \"invoice.pdf\" was quarantined: Eicar-Test-Signature");
+    }
+
+    #[tokio::test]
+    async fn cancelled_schedules_are_told_to_the_author() {
+        let state = test_state();
+        let mut rx = state.user_events.subscribe();
+        let cancelled = ScheduledMessageCancelled {
+            id: ScheduledMessageId::new(),
+            channel_id: ChannelId::new(),
+            author_id: UserId::new(),
+            reason: "you are no longer a member of this channel".into(),
+        };
+
+        let req = Request::post("/internal/scheduled-cancelled")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&cancelled).unwrap()))
+            .unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let (to, json) = rx.recv().await.unwrap();
+        assert_eq!(to, cancelled.author_id);
+        let event: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(event["SystemMessage"]["room_id"], cancelled.channel_id.as_str());
+        assert_eq!(
+            event["SystemMessage"]["text"],
+            "Your scheduled message was not sent: you are no longer a member of this channel"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_count_running_tasks() {
+        let state = test_state();
+        // Nothing listens there, so the client keeps retrying.
+        let _hub = crate::hub_client::HubClient::spawn("127.0.0.1:1".into());
+
+        let req = Request::get("/internal/metrics").header(INTERNAL_TOKEN_HEADER, "internal-secret").body(Body::empty()).unwrap();
+        let resp = app(state).oneshot(req).await.unwrap();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("# TYPE uchat_tasks gauge\n"), "{}", body);
+        assert!(body.contains("kind=\"hub_client\"} "), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn posted_messages_go_out_like_socket_messages() {
+        let state = test_state();
+        let channel_id = ChannelId::new();
+        let mut rx = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let posted = MessagePosted {
+            id: MessageId::new(),
+            channel_id: channel_id.clone(),
+            sender_id: UserId::new(),
+            sender_name: Some("CI".into()),
+            content: "<b>build</b> passed".into(),
+            thread_id: None,
+        };
+        let post = || {
+            Request::post("/internal/message-posted")
+                .header("Content-Type", "application/json")
+                .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+                .body(Body::from(serde_json::to_string(&posted).unwrap()))
+                .unwrap()
+        };
+
+        let resp = app(state.clone()).oneshot(post()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        match serde_json::from_str(rx.recv().await.unwrap().as_json().unwrap()).unwrap() {
+            ServerEvent::MessageBroadcast { from, content, sender_name, seq, message_id, .. } => {
+                assert_eq!((from, content.as_str(), sender_name.as_deref()), (posted.sender_id.clone(), "build passed", Some("CI")));
+                assert!(seq.is_some());
+                assert_eq!(message_id, Some(posted.id.clone()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        state.archived.write().await.insert(channel_id);
+        assert_eq!(app(state.clone()).oneshot(post()).await.unwrap().status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn held_spam_reaches_moderators_and_waits_for_a_decision() {
+        let mut state = Arc::into_inner(test_state()).unwrap();
+        let moderation_room = ChannelId::new();
+        state.spam = crate::spam::SpamGuard::new(crate::spam::SpamConfig {
+            duplicate_channels: 1,
+            moderation_room: Some(moderation_room.clone()),
+            ..Default::default()
+        });
+        let state = Arc::new(state);
+        let (user_id, channel_id) = (UserId::new(), ChannelId::new());
+        let mut moderators = state.room(&RoomId::from(moderation_room)).await.subscribe();
+        let mut room = state.room(&RoomId::from(channel_id.clone())).await.subscribe();
+        let mut user_events = state.user_events.subscribe();
+
+        // Messages channels-api posts are checked like a socket's.
+        let posted = MessagePosted {
+            id: MessageId::new(),
+            channel_id: channel_id.clone(),
+            sender_id: user_id.clone(),
+            sender_name: None,
+            content: "buy <i>followers</i> cheap, dm me now".into(),
+            thread_id: None,
+        };
+        let req = Request::post("/internal/message-posted")
+            .header("Content-Type", "application/json")
+            .header(INTERNAL_TOKEN_HEADER, "internal-secret")
+            .body(Body::from(serde_json::to_string(&posted).unwrap()))
+            .unwrap();
+        assert_eq!(app(state.clone()).oneshot(req).await.unwrap().status(), StatusCode::NO_CONTENT);
+
+        // The sender sees their message as usual; nobody else does.
+        let (to, echo) = user_events.recv().await.unwrap();
+        assert_eq!(to, user_id);
+        match serde_json::from_str(&echo).unwrap() {
+            ServerEvent::MessageBroadcast { seq, content, .. } => {
+                assert_eq!((seq, content.as_str()), (None, "buy followers cheap, dm me now"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(room.try_recv().is_err());
+        match serde_json::from_str(moderators.recv().await.unwrap().as_json().unwrap()).unwrap() {
+            ServerEvent::SpamFlagged { user_id: flagged, room_id, reasons } => {
+                assert_eq!((flagged, room_id), (user_id.clone(), channel_id.clone()));
+                assert_eq!(reasons, [SpamReason::Duplicates]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let req = Request::get("/internal/spam").header(INTERNAL_TOKEN_HEADER, "internal-secret").body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let held: Vec<HeldUser> = serde_json::from_slice(&body).unwrap();
+        assert_eq!((held.len(), held[0].held), (1, 1));
+
+        let decide = |token: &str| {
+            Request::post(format!("/internal/spam/{}", user_id))
+                .header("Content-Type", "application/json")
+                .header(INTERNAL_TOKEN_HEADER, token)
+                .body(Body::from(r#"{"decision":"clear"}"#))
+                .unwrap()
+        };
+        assert_eq!(app(state.clone()).oneshot(decide("nope")).await.unwrap().status(), StatusCode::FORBIDDEN);
+        let resp = app(state.clone()).oneshot(decide("internal-secret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let decided: SpamDecided = serde_json::from_slice(&body).unwrap();
+        assert_eq!((decided.decision, decided.messages), (SpamDecision::Clear, 1));
+        match serde_json::from_str(room.recv().await.unwrap().as_json().unwrap()).unwrap() {
+            ServerEvent::MessageBroadcast { from, content, seq, message_id, .. } => {
+                assert_eq!((from, content.as_str()), (user_id.clone(), "buy followers cheap, dm me now"));
+                assert!(seq.is_some());
+                assert_eq!(message_id, Some(posted.id.clone()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let resp = app(state.clone()).oneshot(decide("internal-secret")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
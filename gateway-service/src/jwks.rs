@@ -0,0 +1,203 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::{Mutex, RwLock};
+
+use uchat_proto::jwt::{key_id, verify_claims_jwks, Claims, JwkSet};
+
+/// Least time between fetches prompted by tokens that don't verify, so a
+/// stream of bad tokens can't hammer auth-api.
+const REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+/// Wait after the first failed fetch, doubling with each failure after it
+/// up to `REFETCH_INTERVAL`.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+/// The signing keys auth-api publishes as an OpenID provider, for tokens
+/// it issues through `/token` rather than signs with the shared secret.
+/// Nothing is fetched until a token needs the keys, so the gateway starts
+/// whether or not auth-api is up yet.
+pub struct Jwks {
+    client: reqwest::Client,
+    issuer: String,
+    keys: RwLock<JwkSet>,
+    /// Also serializes fetches.
+    fetches: Mutex<Fetches>,
+}
+
+#[derive(Default)]
+struct Fetches {
+    /// From discovery, once it has succeeded.
+    jwks_uri: Option<String>,
+    last: Option<Instant>,
+    /// Failed fetches since the last one that worked.
+    failures: u32,
+}
+
+impl Fetches {
+    /// Whether enough time has passed since the last attempt.
+    fn due(&self) -> bool {
+        let wait = match self.failures {
+            0 => REFETCH_INTERVAL,
+            n => RETRY_BACKOFF.saturating_mul(1 << (n - 1).min(16)).min(REFETCH_INTERVAL),
+        };
+        self.last.is_none_or(|at| at.elapsed() >= wait)
+    }
+}
+
+impl Jwks {
+    /// Keys for `issuer`, found through its
+    /// `/.well-known/openid-configuration` when first needed.
+    pub fn new(issuer: &str) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("build auth-api client");
+        Self {
+            client,
+            issuer: issuer.trim_end_matches('/').to_string(),
+            keys: RwLock::new(JwkSet { keys: Vec::new() }),
+            fetches: Mutex::new(Fetches::default()),
+        }
+    }
+
+    /// From `OIDC_ISSUER_URL`, or `None` when it is unset, in which case
+    /// only shared-secret tokens verify.
+    pub fn from_env() -> Option<Self> {
+        let issuer = std::env::var("OIDC_ISSUER_URL").ok().filter(|v| !v.is_empty())?;
+        Some(Self::new(&issuer))
+    }
+
+    /// Claims of a token signed by one of the provider's keys. A token
+    /// that doesn't verify against the cached keys, as on the first one or
+    /// after auth-api rotates its key, fetches them first: at most every
+    /// `REFETCH_INTERVAL`, or sooner with backoff while fetches fail.
+    pub async fn verify(&self, token: &str) -> Option<Claims> {
+        key_id(token)?;
+        if let Some(claims) = verify_claims_jwks(&*self.keys.read().await, token) {
+            return Some(claims);
+        }
+
+        let mut fetches = self.fetches.lock().await;
+        if !fetches.due() {
+            return None;
+        }
+        let result = self.fetch_keys(&mut fetches).await;
+        fetches.last = Some(Instant::now());
+        match result {
+            Ok(keys) => {
+                fetches.failures = 0;
+                *self.keys.write().await = keys;
+            }
+            Err(e) => {
+                fetches.failures += 1;
+                tracing::warn!(error = %e, issuer = self.issuer, "failed to fetch the OIDC key set");
+            }
+        }
+        drop(fetches);
+        verify_claims_jwks(&*self.keys.read().await, token)
+    }
+
+    async fn fetch_keys(&self, fetches: &mut Fetches) -> Result<JwkSet, String> {
+        let jwks_uri = match &fetches.jwks_uri {
+            Some(uri) => uri.clone(),
+            None => {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer);
+                let discovery: Discovery = fetch(&self.client, &url).await?;
+                fetches.jwks_uri.insert(discovery.jwks_uri).clone()
+            }
+        };
+        fetch(&self.client, &jwks_uri).await
+    }
+}
+
+async fn fetch<T: for<'de> Deserialize<'de>>(client: &reqwest::Client, url: &str) -> Result<T, String> {
+    let resp = client.get(url).send().await.map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("{} returned {}", url, resp.status()));
+    }
+    resp.json().await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use uchat_proto::jwt::SigningKey;
+    use uchat_proto::permissions::RoomPermissions;
+
+    /// What the provider serves; `None` while it is down.
+    type Published = Arc<StdMutex<Option<JwkSet>>>;
+
+    /// A provider serving whatever key set is in `Published`.
+    async fn provider(published: Published) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let jwks_uri = format!("{}/jwks.json", issuer);
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(|State(keys): State<Published>| async move {
+                    let up = keys.lock().unwrap().is_some();
+                    up.then(|| Json(serde_json::json!({ "jwks_uri": jwks_uri }))).ok_or(StatusCode::SERVICE_UNAVAILABLE)
+                }),
+            )
+            .route(
+                "/jwks.json",
+                get(|State(keys): State<Published>| async move {
+                    keys.lock().unwrap().clone().map(Json).ok_or(StatusCode::SERVICE_UNAVAILABLE)
+                }),
+            )
+            .with_state(published);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        issuer
+    }
+
+    fn token(key: &SigningKey) -> String {
+        let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as usize + 3600;
+        key.sign(&Claims { sub: "svc".into(), exp, rooms: RoomPermissions::default(), bot: true, role: None, joined: None })
+    }
+
+    #[tokio::test]
+    async fn refetches_keys_for_tokens_that_dont_verify() {
+        let old = SigningKey::generate();
+        let published = Arc::new(StdMutex::new(Some(JwkSet { keys: vec![old.jwk()] })));
+        let jwks = Jwks::new(&provider(published.clone()).await);
+        assert_eq!(jwks.verify(&token(&old)).await.unwrap().sub, "svc");
+
+        let new = SigningKey::generate();
+        *published.lock().unwrap() = Some(JwkSet { keys: vec![new.jwk()] });
+        // Fetched just now, so not again yet.
+        assert!(jwks.verify(&token(&new)).await.is_none());
+
+        jwks.fetches.lock().await.last = None;
+        assert_eq!(jwks.verify(&token(&new)).await.unwrap().sub, "svc");
+        assert!(jwks.verify(&token(&old)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_trying_when_the_provider_is_down_at_first() {
+        let key = SigningKey::generate();
+        let published: Published = Arc::new(StdMutex::new(None));
+        let jwks = Jwks::new(&provider(published.clone()).await);
+        assert!(jwks.verify(&token(&key)).await.is_none());
+
+        *published.lock().unwrap() = Some(JwkSet { keys: vec![key.jwk()] });
+        // Backing off after the failure.
+        assert!(jwks.verify(&token(&key)).await.is_none());
+        assert_eq!(jwks.fetches.lock().await.failures, 1);
+
+        tokio::time::sleep(RETRY_BACKOFF).await;
+        assert_eq!(jwks.verify(&token(&key)).await.unwrap().sub, "svc");
+        assert_eq!(jwks.fetches.lock().await.failures, 0);
+    }
+}
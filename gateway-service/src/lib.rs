@@ -0,0 +1,1827 @@
+pub mod bridge;
+mod channels_client;
+mod commands;
+mod connections;
+#[cfg(feature = "redis-dedup")]
+mod dedup;
+mod dlq;
+pub mod filter;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod history;
+mod hub_client;
+mod internal;
+mod jwks;
+mod load_shed;
+mod metrics;
+#[cfg(feature = "mtls")]
+mod mtls;
+mod poll;
+mod presence;
+mod room_poll;
+mod sanitize;
+mod schema;
+mod spam;
+mod sse;
+mod typing;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{
+        ws::{rejection::WebSocketUpgradeRejection, Message},
+        Extension, Query, State, WebSocketUpgrade,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Router,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::FutureExt;
+use futures_util::{Sink, SinkExt};
+
+use uchat_proto::capabilities;
+use uchat_proto::channels::{MembershipChange, RoomSubscribers};
+use uchat_proto::commands::split_command;
+use uchat_proto::compression;
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::{BridgedFrom, ClientEvent, ClientFrame, SequencedMessage, ServerEvent, MAX_RELAY_HOPS};
+use uchat_proto::filters::ContentFiltered;
+use uchat_proto::format::{self, SerializationFormat};
+use uchat_proto::ids::{ChannelId, MessageId, RoomId, UserId};
+use uchat_proto::jwt::{secret_from_env, verify_claims, Claims};
+use uchat_proto::moderation::SpamFlagged;
+use uchat_proto::permissions::RoomRole;
+use uchat_proto::push::PushMessage;
+use uchat_telemetry::CorrelationId;
+
+use channels_client::ChannelsClient;
+use hub_client::HubClient;
+use load_shed::LoadShedder;
+
+/// A serialized event on its way to a room's subscribers. The frame is
+/// shared, so the broadcast channel hands each subscriber a reference
+/// rather than its own copy.
+#[derive(Debug, Clone)]
+struct RoomMessage {
+    /// The encoding's `SerializationFormat::tag`, then the event. Rooms
+    /// with MessagePack sockets get each event twice, once per format,
+    /// and each socket forwards only its own.
+    frame: Bytes,
+    /// When a client's message entered the broadcast path; unset for
+    /// events the gateway or channels-api originate.
+    send_time: Option<Instant>,
+}
+
+impl RoomMessage {
+    fn json(json: &str, send_time: Option<Instant>) -> Self {
+        let mut frame = Vec::with_capacity(json.len() + 1);
+        frame.push(SerializationFormat::Json.tag());
+        frame.extend_from_slice(json.as_bytes());
+        Self { frame: frame.into(), send_time }
+    }
+
+    fn format(&self) -> Option<SerializationFormat> {
+        SerializationFormat::from_tag(*self.frame.first()?)
+    }
+
+    fn body(&self) -> &[u8] {
+        self.frame.get(1..).unwrap_or_default()
+    }
+
+    fn as_json(&self) -> Option<&str> {
+        match self.format()? {
+            SerializationFormat::Json => std::str::from_utf8(self.body()).ok(),
+            SerializationFormat::Msgpack => None,
+        }
+    }
+
+    /// The same event as MessagePack; `None` unless this is the JSON of a
+    /// `ServerEvent`.
+    fn to_msgpack(&self) -> Option<Self> {
+        let event: ServerEvent = serde_json::from_str(self.as_json()?).ok()?;
+        let mut frame = vec![SerializationFormat::Msgpack.tag()];
+        frame.extend(format::to_msgpack(&event).ok()?);
+        Some(Self { frame: frame.into(), send_time: self.send_time })
+    }
+}
+
+/// A client message on its way into a room.
+struct OutgoingMessage {
+    room_id: ChannelId,
+    thread_id: Option<MessageId>,
+    content: String,
+    encrypted: bool,
+    content_type: String,
+    /// Set for a bridge's `RelayMessage`.
+    bridged_from: Option<BridgedFrom>,
+    /// Set for senders that aren't users.
+    sender_name: Option<String>,
+    /// Set for messages channels-api stored.
+    message_id: Option<MessageId>,
+    /// The sender's `role` claim.
+    sender_role: Option<String>,
+    /// The sender's `joined` claim, for a socket's messages.
+    sender_joined: Option<i64>,
+}
+
+/// What the gateway can offer a client; `file-transfer` and `voice` go
+/// through other services.
+const SERVER_CAPABILITIES: &[&str] = &[capabilities::E2EE, capabilities::THREADING, capabilities::COMPRESSION_ZSTD];
+
+/// How long a socket whose handler panicked gets to flush its last frames.
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+pub struct AppState {
+    jwt_secret: String,
+    /// auth-api's OIDC signing keys; unset when `OIDC_ISSUER_URL` is not
+    /// configured.
+    jwks: Option<jwks::Jwks>,
+    /// Channel rooms and their thread rooms, all created and cleaned up
+    /// the same way.
+    rooms: RwLock<HashMap<RoomId, broadcast::Sender<RoomMessage>>>,
+    hub: Option<HubClient>,
+    /// Where read receipts are persisted; unset when `CHANNELS_API_URL`
+    /// is not configured.
+    channels: Option<ChannelsClient>,
+    /// Latest CPU usage sample in percent, written by `LoadShedder`.
+    current_load: Arc<AtomicU8>,
+    max_cpu_percent: u8,
+    /// Membership changes pushed by channels-api, fanned out to sockets.
+    membership: broadcast::Sender<MembershipChange>,
+    /// Serialized events addressed to one user rather than a room; each
+    /// of that user's sockets forwards them.
+    user_events: broadcast::Sender<(UserId, String)>,
+    /// Shared secret for `/internal/*`; those routes are refused when unset.
+    internal_token: Option<String>,
+    /// Archived channels, loaded from channels-api at startup and kept
+    /// current by its pushes.
+    archived: RwLock<HashSet<ChannelId>>,
+    presence: presence::PresenceStore,
+    /// Channels whose messages keep markdown formatting tags, loaded from
+    /// channels-api at startup and kept current by its pushes; everywhere
+    /// else messages are plain text.
+    markdown_channels: RwLock<HashSet<ChannelId>>,
+    /// Channels that refuse plaintext messages, loaded from channels-api
+    /// at startup and kept current by its pushes.
+    e2ee_channels: RwLock<HashSet<ChannelId>>,
+    /// Accounts auth-api deleted while this process was up; their tokens
+    /// are refused even before they expire.
+    deleted_users: RwLock<HashSet<UserId>>,
+    metrics: metrics::Metrics,
+    /// Recent client messages, replayed to sockets that reconnect.
+    history: history::RoomHistory,
+    /// Client messages nobody received, for `/admin/dlq`.
+    dead_letters: dlq::DeadLetterQueue,
+    /// Slash commands, intercepted from `SendMessage`.
+    commands: commands::CommandRegistry,
+    /// Sockets that negotiated MessagePack.
+    msgpack_sockets: AtomicUsize,
+    /// Per-token limit on `GET /rooms/{room_id}/typing`.
+    typing_polls: typing::PollLimiter,
+    /// Names `GET /rooms/{room_id}/typing` answers with.
+    typing_names: typing::TypingNames,
+    /// Long-polling sessions, for clients that can't hold a WebSocket.
+    polls: poll::PollSessions,
+    /// Open connections per user, whatever their transport.
+    connections: Arc<connections::ConnectionLimits>,
+    /// Flood detection, and the messages of users it is holding.
+    spam: spam::SpamGuard,
+    /// Content policy checks on messages that aren't encrypted.
+    filters: filter::ContentFilters,
+    /// Suppresses client retries across gateway instances; unset when
+    /// `REDIS_URL` is not configured.
+    #[cfg(feature = "redis-dedup")]
+    dedup: Option<dedup::RedisDeduplicator>,
+}
+
+impl AppState {
+    /// Returns the broadcast sender for `room_id`, creating the room on
+    /// first use.
+    async fn room(&self, room_id: &RoomId) -> broadcast::Sender<RoomMessage> {
+        let mut rooms = self.rooms.write().await;
+        rooms
+            .entry(room_id.clone())
+            .or_insert_with(|| broadcast::channel(1024).0)
+            .clone()
+    }
+
+    /// The open thread rooms under `channel_id`.
+    async fn thread_rooms(&self, channel_id: &ChannelId) -> Vec<RoomId> {
+        let rooms = self.rooms.read().await;
+        rooms.keys().filter(|room| room.thread.is_some() && room.channel == *channel_id).cloned().collect()
+    }
+
+    /// Every open room and how many receivers it has, by room id.
+    async fn room_subscribers(&self) -> Vec<RoomSubscribers> {
+        let mut rooms: Vec<RoomSubscribers> = self
+            .rooms
+            .read()
+            .await
+            .iter()
+            .map(|(room_id, tx)| RoomSubscribers { room_id: room_id.clone(), subscribers: tx.receiver_count() as u32 })
+            .collect();
+        rooms.sort_by_key(|room| room.room_id.to_string());
+        rooms
+    }
+
+    /// Sends `json` to everyone subscribed to `room_id`, and to the event
+    /// hub when one is configured. Rooms nobody has joined are not created
+    /// just to drop the message.
+    async fn broadcast(&self, room_id: &RoomId, json: String) {
+        self.publish(room_id, RoomMessage::json(&json, None)).await;
+    }
+
+    /// `broadcast` for a client's message, timed from `send_time` to each
+    /// subscriber for the latency metrics.
+    async fn broadcast_timed(&self, room_id: &RoomId, json: String, send_time: Instant) {
+        self.deliver(room_id, RoomMessage::json(&json, Some(send_time))).await;
+    }
+
+    /// `publish`, keeping the message as a dead letter when nobody got
+    /// it. Only client messages go through here; presence, typing and
+    /// the like are just dropped.
+    async fn deliver(&self, room_id: &RoomId, message: RoomMessage) -> bool {
+        let delivered = self.publish(room_id, message.clone()).await;
+        if let (false, None, Some(json)) = (delivered, &self.hub, message.as_json()) {
+            self.dead_letters.push(room_id.clone(), json.into());
+        }
+        delivered
+    }
+
+    /// Whether a subscriber on this instance got the message. Encoding
+    /// a MessagePack copy is skipped while no socket uses it.
+    async fn publish(&self, room_id: &RoomId, message: RoomMessage) -> bool {
+        if let (Some(hub), Some(json)) = (&self.hub, message.as_json()) {
+            hub.forward(json);
+        }
+        let rooms = self.rooms.read().await;
+        let Some(tx) = rooms.get(room_id) else { return false };
+        if self.msgpack_sockets.load(Ordering::Relaxed) > 0 {
+            if let Some(packed) = message.to_msgpack() {
+                let _ = tx.send(packed);
+            }
+        }
+        tx.send(message).is_ok()
+    }
+
+    /// Whether a `SendMessage` with this `cid` already went out to
+    /// `room_id`, possibly through another gateway instance. Frames
+    /// without a `cid` are never treated as retries.
+    #[cfg_attr(not(feature = "redis-dedup"), allow(unused_variables))]
+    async fn is_retry(&self, room_id: &RoomId, from: &UserId, cid: Option<&str>) -> bool {
+        #[cfg(feature = "redis-dedup")]
+        if let (Some(dedup), Some(cid)) = (&self.dedup, cid) {
+            return !dedup.first_seen(room_id, &dedup::message_key(from, cid)).await;
+        }
+        false
+    }
+
+    /// Sanitizes, records and fans out a message from `from`, once the
+    /// caller has checked they may write to the room: a socket's frames,
+    /// the gRPC `BroadcastToRoom`, channels-api's posted messages and the
+    /// bridges all come through here. Returns the room it went to and its
+    /// sequence number, or `None` for a retry that already went out or a
+    /// message held as spam; `Err` is the `Nack` code.
+    async fn send_message(
+        &self,
+        from: &UserId,
+        cid: Option<&str>,
+        message: OutgoingMessage,
+        send_time: Instant,
+    ) -> Result<Option<(RoomId, u64)>, ErrorCode> {
+        match self.spam.check(from, &message, send_time) {
+            spam::Verdict::Deliver => self.send_unchecked(from, cid, message, send_time).await,
+            verdict => {
+                self.shadow(from, message, verdict).await;
+                Ok(None)
+            }
+        }
+    }
+
+    /// `send_message` without the spam check, for messages a moderator
+    /// released.
+    async fn send_unchecked(
+        &self,
+        from: &UserId,
+        cid: Option<&str>,
+        message: OutgoingMessage,
+        send_time: Instant,
+    ) -> Result<Option<(RoomId, u64)>, ErrorCode> {
+        let OutgoingMessage {
+            room_id,
+            thread_id,
+            content,
+            encrypted,
+            content_type,
+            bridged_from,
+            sender_name,
+            message_id,
+            sender_role,
+            sender_joined: _,
+        } = message;
+        if self.archived.read().await.contains(&room_id) {
+            return Err(ErrorCode::ChannelArchived);
+        }
+        if !encrypted && self.e2ee_channels.read().await.contains(&room_id) {
+            return Err(ErrorCode::E2eeRequired);
+        }
+
+        let (content, redacted) = if encrypted {
+            (content, false)
+        } else {
+            self.filter(from, &room_id, message_id.as_ref(), content).await?
+        };
+        let content = self.sanitize(&room_id, content, encrypted).await;
+
+        // Thread replies go only to the thread room.
+        let room_id = RoomId { channel: room_id, thread: thread_id };
+        if self.is_retry(&room_id, from, cid).await {
+            return Ok(None);
+        }
+        let message = self.history.record(SequencedMessage {
+            seq: 0,
+            room_id: room_id.clone(),
+            from: from.clone(),
+            content,
+            encrypted,
+            content_type,
+            bridged_from,
+            sender_name,
+            message_id,
+            sender_role,
+            redacted,
+        });
+        let seq = message.seq;
+        // Encrypted messages are pushed without their content.
+        let push = self.channels.is_some().then(|| PushMessage {
+            channel_id: room_id.channel.clone(),
+            sender_id: from.clone(),
+            sender_name: message.sender_name.clone(),
+            content: (!encrypted).then(|| message.content.clone()),
+            delivered_to: Vec::new(),
+        });
+        let event = ServerEvent::MessageBroadcast {
+            room_id: room_id.clone(),
+            from: message.from,
+            content: message.content,
+            encrypted: message.encrypted,
+            content_type: message.content_type,
+            seq: Some(seq),
+            bridged_from: message.bridged_from,
+            sender_name: message.sender_name,
+            message_id: message.message_id,
+            sender_role: message.sender_role,
+            redacted: message.redacted,
+            traceparent: uchat_telemetry::current_traceparent(),
+        };
+        if let Ok(json) = serde_json::to_string(&event) {
+            self.broadcast_timed(&room_id, json, send_time).await;
+        }
+        if let (Some(mut push), Some(channels), Some(token)) = (push, &self.channels, &self.internal_token) {
+            push.delivered_to = self.presence.online_in(&room_id.channel).await;
+            let (channels, token) = (channels.clone(), token.clone());
+            uchat_metrics::spawn_task("push_handoff", async move {
+                if let Err(e) = channels.push(&token, &push).await {
+                    tracing::warn!("push hand-off failed: {}", e);
+                }
+            });
+        }
+        Ok(Some((room_id, seq)))
+    }
+
+    /// `content` once the content filters have seen it, and whether they
+    /// rewrote it; `Err` when one blocked it. Each redaction or block is
+    /// reported to channels-api for the audit trail, with the stored
+    /// message when there is one.
+    async fn filter(
+        &self,
+        from: &UserId,
+        channel_id: &ChannelId,
+        message_id: Option<&MessageId>,
+        content: String,
+    ) -> Result<(String, bool), ErrorCode> {
+        let filtered = self.filters.check(channel_id, from, content).await;
+        if filtered.decisions.is_empty() {
+            return Ok((filtered.content.unwrap_or_default(), false));
+        }
+
+        for (filter, verdict) in &filtered.decisions {
+            tracing::info!(user_id = %from, channel_id = %channel_id, filter, verdict = ?verdict, "content filtered");
+        }
+        if let (Some(channels), Some(token)) = (&self.channels, &self.internal_token) {
+            let reports: Vec<ContentFiltered> = filtered
+                .decisions
+                .into_iter()
+                .map(|(filter, verdict)| ContentFiltered {
+                    user_id: from.clone(),
+                    channel_id: channel_id.clone(),
+                    message_id: message_id.cloned(),
+                    filter,
+                    verdict,
+                })
+                .collect();
+            let (channels, token) = (channels.clone(), token.clone());
+            // In order, so a stored message ends up as the last filter left it.
+            uchat_metrics::spawn_task("content_filter_report", async move {
+                for report in reports {
+                    if let Err(e) = channels.content_filtered(&token, &report).await {
+                        tracing::warn!("content filter report failed: {}", e);
+                    }
+                }
+            });
+        }
+        filtered.content.map(|content| (content, true)).ok_or(ErrorCode::ContentBlocked)
+    }
+
+    /// `content` as the room's sockets get it.
+    async fn sanitize(&self, channel_id: &ChannelId, content: String, encrypted: bool) -> String {
+        // Ciphertext isn't HTML and must reach clients intact.
+        if encrypted {
+            content
+        } else if self.markdown_channels.read().await.contains(channel_id) {
+            sanitize::sanitize_markdown(&content)
+        } else {
+            sanitize::sanitize_content(&content)
+        }
+    }
+
+    /// Echoes a held user's message to their own sockets alone and keeps
+    /// it for a moderator. The message that got them held also alerts
+    /// the moderation room and channels-api's audit trail.
+    async fn shadow(&self, from: &UserId, message: OutgoingMessage, verdict: spam::Verdict) {
+        let event = ServerEvent::MessageBroadcast {
+            room_id: RoomId { channel: message.room_id.clone(), thread: message.thread_id.clone() },
+            from: from.clone(),
+            content: self.sanitize(&message.room_id, message.content.clone(), message.encrypted).await,
+            encrypted: message.encrypted,
+            content_type: message.content_type.clone(),
+            seq: None,
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: message.sender_role.clone(),
+            redacted: false,
+            traceparent: None,
+        };
+        if let Ok(json) = serde_json::to_string(&event) {
+            let _ = self.user_events.send((from.clone(), json));
+        }
+
+        if let spam::Verdict::Flag(reasons) = verdict {
+            tracing::warn!(user_id = %from, reasons = ?reasons, "holding messages as spam");
+            if let Some(room_id) = self.spam.moderation_room() {
+                let event = ServerEvent::SpamFlagged {
+                    user_id: from.clone(),
+                    room_id: message.room_id.clone(),
+                    reasons: reasons.clone(),
+                };
+                if let Ok(json) = serde_json::to_string(&event) {
+                    self.broadcast(&RoomId::from(room_id.clone()), json).await;
+                }
+            }
+            if let (Some(channels), Some(token)) = (&self.channels, &self.internal_token) {
+                let flagged = SpamFlagged { user_id: from.clone(), channel_id: message.room_id.clone(), reasons };
+                let (channels, token) = (channels.clone(), token.clone());
+                uchat_metrics::spawn_task("spam_flag_report", async move {
+                    if let Err(e) = channels.spam_flagged(&token, &flagged).await {
+                        tracing::warn!("spam flag report failed: {}", e);
+                    }
+                });
+            }
+        }
+        self.spam.hold(from, message);
+    }
+
+    /// Drops the room once its last subscriber is gone.
+    async fn cleanup_room(&self, room_id: &RoomId) {
+        let mut rooms = self.rooms.write().await;
+        if rooms.get(room_id).is_some_and(|tx| tx.receiver_count() == 0) {
+            rooms.remove(room_id);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WsQuery {
+    token: Option<String>,
+    /// Wrap client messages in `MessageTimestamp` on this socket.
+    #[serde(default)]
+    timestamps: bool,
+    /// For browsers, which can't set `x-correlation-id` on the upgrade
+    /// request.
+    correlation_id: Option<String>,
+    /// With `since`, what a `GET /ws` that isn't an upgrade polls instead;
+    /// see `room_poll`.
+    room_id: Option<String>,
+    #[serde(default)]
+    since: u64,
+}
+
+impl AppState {
+    /// State configured from the environment, as the service runs. Starts
+    /// sampling CPU load for shedding.
+    pub async fn from_env() -> Arc<Self> {
+        let current_load = Arc::new(AtomicU8::new(0));
+        LoadShedder::new(current_load.clone()).spawn();
+
+        let state = Arc::new(AppState {
+            jwt_secret: secret_from_env(),
+            jwks: jwks::Jwks::from_env(),
+            rooms: RwLock::new(HashMap::new()),
+            hub: HubClient::from_env(),
+            channels: ChannelsClient::from_env(),
+            current_load,
+            max_cpu_percent: load_shed::max_cpu_percent_from_env(),
+            membership: broadcast::channel(1024).0,
+            user_events: broadcast::channel(1024).0,
+            internal_token: std::env::var("GATEWAY_INTERNAL_TOKEN").ok().filter(|t| !t.is_empty()),
+            archived: RwLock::new(HashSet::new()),
+            markdown_channels: RwLock::new(HashSet::new()),
+            e2ee_channels: RwLock::new(HashSet::new()),
+            deleted_users: RwLock::new(HashSet::new()),
+            metrics: metrics::Metrics::default(),
+            history: history::RoomHistory::default(),
+            dead_letters: dlq::DeadLetterQueue::default(),
+            commands: commands::CommandRegistry::default(),
+            msgpack_sockets: AtomicUsize::new(0),
+            typing_polls: typing::PollLimiter::default(),
+            typing_names: typing::TypingNames::default(),
+            polls: poll::PollSessions::from_env(),
+            connections: Arc::new(connections::ConnectionLimits::from_env()),
+            spam: spam::SpamGuard::new(spam::SpamConfig::from_env()),
+            filters: filter::ContentFilters::from_env(),
+            presence: presence::PresenceStore::from_env().await,
+            #[cfg(feature = "redis-dedup")]
+            dedup: dedup::RedisDeduplicator::from_env().await,
+        });
+        bridge::attach_from_env(&state).await;
+        filter::load_rules(&state);
+        load_channel_sets(&state);
+        state
+    }
+
+    /// State that needs nothing outside the process: no event hub,
+    /// channels-api or Redis, presence kept in memory, and no load
+    /// shedding.
+    pub fn local(jwt_secret: &str, internal_token: Option<&str>) -> Arc<Self> {
+        Arc::new(AppState {
+            jwt_secret: jwt_secret.into(),
+            jwks: None,
+            rooms: RwLock::new(HashMap::new()),
+            hub: None,
+            channels: None,
+            current_load: Arc::new(AtomicU8::new(0)),
+            max_cpu_percent: load_shed::DEFAULT_MAX_CPU_PERCENT,
+            membership: broadcast::channel(1024).0,
+            user_events: broadcast::channel(1024).0,
+            internal_token: internal_token.map(str::to_string),
+            archived: RwLock::new(HashSet::new()),
+            markdown_channels: RwLock::new(HashSet::new()),
+            e2ee_channels: RwLock::new(HashSet::new()),
+            deleted_users: RwLock::new(HashSet::new()),
+            metrics: metrics::Metrics::default(),
+            history: history::RoomHistory::default(),
+            dead_letters: dlq::DeadLetterQueue::default(),
+            commands: commands::CommandRegistry::default(),
+            msgpack_sockets: AtomicUsize::new(0),
+            typing_polls: typing::PollLimiter::default(),
+            typing_names: typing::TypingNames::default(),
+            polls: poll::PollSessions::default(),
+            connections: Arc::default(),
+            spam: spam::SpamGuard::default(),
+            filters: filter::ContentFilters::default(),
+            presence: presence::PresenceStore::local(),
+            #[cfg(feature = "redis-dedup")]
+            dedup: None,
+        })
+    }
+}
+
+/// Serves the gateway on `listener`, over mutual TLS when `mtls` is
+/// configured.
+pub async fn serve(listener: TcpListener, state: Arc<AppState>) {
+    let addr = listener.local_addr().unwrap();
+
+    #[cfg(feature = "mtls")]
+    if let Some(tls) = mtls::MtlsConfig::from_env() {
+        tracing::info!("gateway-service listening on wss://{}/ws (mutual TLS)", addr);
+        mtls::serve(listener, app(state), Arc::new(tls)).await;
+        return;
+    }
+
+    tracing::info!("gateway-service listening on ws://{}/ws", addr);
+
+    axum::serve(listener, app(state)).await.unwrap();
+}
+
+/// The per-channel flags channels-api pushes to `/internal/*`, which a
+/// starting gateway also fetches so it doesn't miss earlier changes.
+#[derive(Clone, Copy, Debug)]
+enum ChannelSet {
+    Archived,
+    Markdown,
+    E2ee,
+}
+
+impl ChannelSet {
+    const ALL: [ChannelSet; 3] = [ChannelSet::Archived, ChannelSet::Markdown, ChannelSet::E2ee];
+
+    fn path(self) -> &'static str {
+        match self {
+            ChannelSet::Archived => "archived-channels",
+            ChannelSet::Markdown => "markdown-channels",
+            ChannelSet::E2ee => "e2ee-channels",
+        }
+    }
+
+    fn of(self, state: &AppState) -> &RwLock<HashSet<ChannelId>> {
+        match self {
+            ChannelSet::Archived => &state.archived,
+            ChannelSet::Markdown => &state.markdown_channels,
+            ChannelSet::E2ee => &state.e2ee_channels,
+        }
+    }
+}
+
+/// Fetches every `ChannelSet` from channels-api in the background,
+/// retrying with backoff until it answers; changes after that are pushed.
+fn load_channel_sets(state: &Arc<AppState>) {
+    let (Some(channels), Some(token)) = (state.channels.clone(), state.internal_token.clone()) else {
+        return;
+    };
+    for set in ChannelSet::ALL {
+        let (channels, token, state) = (channels.clone(), token.clone(), Arc::downgrade(state));
+        uchat_metrics::spawn_task("channel_set_load", async move {
+            let mut delay = Duration::from_secs(1);
+            loop {
+                match channels.channel_ids(&token, set.path()).await {
+                    Ok(ids) => {
+                        let Some(state) = state.upgrade() else { return };
+                        tracing::info!(channels = ids.len(), "loaded {}", set.path());
+                        set.of(&state).write().await.extend(ids);
+                        return;
+                    }
+                    Err(e) => tracing::warn!("loading {} failed, retrying in {:?}: {}", set.path(), delay, e),
+                }
+                tokio::time::sleep(delay).await;
+                if state.strong_count() == 0 {
+                    return;
+                }
+                delay = (delay * 2).min(Duration::from_secs(60));
+            }
+        });
+    }
+}
+
+pub fn app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/ws", get(ws_handler))
+        .route("/poll/connect", post(poll::connect))
+        .route("/poll", get(room_poll::poll_room))
+        .route("/sse/rooms/:room_id", get(sse::room_events))
+        .route_layer(middleware::from_fn_with_state(state.clone(), load_shed::shed_load))
+        .route("/rooms/:room_id/typing", get(typing::poll))
+        .route("/poll/:sid", get(poll::poll))
+        .route("/poll/:sid/send", post(poll::send))
+        .route("/internal/membership", post(internal::membership_changed))
+        .route("/internal/channel-created", post(internal::channel_created))
+        .route("/internal/channel-archived", post(internal::channel_archive_changed))
+        .route("/internal/channel-updated", post(internal::channel_updated))
+        .route("/internal/content-filters", post(internal::content_filters_changed))
+        .route("/internal/presence", get(internal::presence))
+        .route("/internal/rooms", get(internal::rooms))
+        .route("/internal/message-deleted", post(internal::message_deleted))
+        .route("/internal/message-posted", post(internal::message_posted))
+        .route("/internal/link-preview", post(internal::link_preview))
+        .route("/internal/file-quarantined", post(internal::file_quarantined))
+        .route("/internal/scheduled-cancelled", post(internal::scheduled_cancelled))
+        .route("/internal/message-edited", post(internal::message_edited))
+        .route("/internal/reaction", post(internal::reaction_changed))
+        .route("/internal/messages-expired", post(internal::messages_expired))
+        .route("/internal/user-deleted", post(internal::user_deleted))
+        .route("/internal/metrics", get(internal::metrics))
+        .route("/internal/commands", get(commands::list).post(commands::register))
+        .route("/internal/commands/:name", delete(commands::unregister))
+        .route("/internal/spam", get(internal::held_users))
+        .route("/internal/spam/:user_id", post(internal::decide_spam))
+        .route("/admin/dlq", get(dlq::list))
+        .route("/admin/dlq/:id/retry", post(dlq::retry))
+        .layer(middleware::from_fn(uchat_telemetry::propagate))
+        .with_state(state)
+}
+
+#[cfg(test)]
+fn test_state() -> Arc<AppState> {
+    AppState::local("test-secret", Some("internal-secret"))
+}
+
+async fn ws_handler(
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+    Extension(CorrelationId(correlation_id)): Extension<CorrelationId>,
+    State(state): State<Arc<AppState>>,
+    #[cfg(feature = "mtls")] device: Option<axum::Extension<mtls::DeviceCert>>,
+) -> Response {
+    // Proxies that drop `Upgrade: websocket` leave a plain GET, which is
+    // answered as a long-poll of the room it names.
+    let ws = match (ws, query.room_id) {
+        (Ok(ws), _) => ws,
+        (Err(_), Some(room_id)) => {
+            let poll = room_poll::RoomPollQuery { room_id, since: query.since, token: query.token };
+            return room_poll::poll_room(State(state), Query(poll), headers).await;
+        }
+        (Err(rejection), None) => return rejection.into_response(),
+    };
+    let correlation_id = match query.correlation_id {
+        Some(id) => uchat_telemetry::correlation_id(Some(&id)),
+        None => correlation_id,
+    };
+
+    // Registered devices authenticated during the TLS handshake; the
+    // token is not consulted for them.
+    #[cfg(feature = "mtls")]
+    if let Some(axum::Extension(device)) = device {
+        let claims = device.claims();
+        if let Ok(user_id) = claims.sub.parse::<UserId>() {
+            let Some(slot) = state.connections.acquire(&user_id) else {
+                return too_many_connections();
+            };
+            let span = connection_span(&correlation_id, &user_id);
+            let timestamps = query.timestamps;
+            return ws.on_upgrade(move |socket| {
+                let socket = handle_socket(socket.split(), state, None, claims, user_id, timestamps, correlation_id);
+                socket_task(slot, socket.instrument(span))
+            });
+        }
+    }
+
+    let upgrade = tracing::info_span!("ws.upgrade");
+    let Some((token, claims, user_id)) = verify_user(&state, query.token, &headers).instrument(upgrade).await else {
+        return (StatusCode::UNAUTHORIZED, "INVALID TOKEN").into_response();
+    };
+    let Some(slot) = state.connections.acquire(&user_id) else {
+        return too_many_connections();
+    };
+
+    let span = connection_span(&correlation_id, &user_id);
+    let timestamps = query.timestamps;
+    ws.on_upgrade(move |socket| {
+        let socket = handle_socket(socket.split(), state, Some(token), claims, user_id, timestamps, correlation_id);
+        socket_task(slot, socket.instrument(span))
+    })
+}
+
+/// Refusal for a user already holding as many connections as allowed.
+fn too_many_connections() -> Response {
+    (StatusCode::TOO_MANY_REQUESTS, "TOO MANY CONNECTIONS").into_response()
+}
+
+/// The token from `?token=` or `Authorization: Bearer`, with its claims
+/// and user, if it is valid.
+async fn verify_user(state: &AppState, query_token: Option<String>, headers: &HeaderMap) -> Option<(String, Claims, UserId)> {
+    let token = query_token.or_else(|| bearer_token(headers))?;
+    let claims = verify_token(state, &token).await?;
+    let user_id = claims.sub.parse().ok()?;
+    Some((token, claims, user_id))
+}
+
+/// Claims of a token signed with the shared secret or, failing that, by
+/// auth-api as an OIDC provider, unless its account has been deleted.
+async fn verify_token(state: &AppState, token: &str) -> Option<Claims> {
+    let claims = match verify_claims(&state.jwt_secret, token) {
+        Some(claims) => claims,
+        None => state.jwks.as_ref()?.verify(token).await?,
+    };
+    let deleted = match claims.sub.parse::<UserId>() {
+        Ok(user_id) => state.deleted_users.read().await.contains(&user_id),
+        Err(_) => false,
+    };
+    (!deleted).then_some(claims)
+}
+
+/// Runs a socket's receive loop as its own task, so it is counted and
+/// named like the tasks it spawns, holding the user's connection slot
+/// until it ends.
+async fn socket_task(slot: connections::ConnectionSlot, socket: impl std::future::Future<Output = ()> + Send + 'static) {
+    let _ = uchat_metrics::spawn_task("ws_receive", socket).await;
+    drop(slot);
+}
+
+/// Span for a socket's lifetime. The upgrade's request span ends when the
+/// handshake does, so the correlation id is carried over; created inside
+/// it, the socket stays in the upgrade request's trace.
+fn connection_span(correlation_id: &str, user_id: &UserId) -> Span {
+    tracing::info_span!("connection", correlation_id, user_id = %user_id)
+}
+
+/// Span for handling one frame, when it names its own action.
+fn frame_span(correlation_id: Option<&str>) -> Span {
+    match correlation_id {
+        Some(id) => tracing::info_span!("frame", correlation_id = id),
+        None => Span::current(),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::to_string)
+}
+
+/// Runs one connection: a WebSocket split into its halves, or a
+/// long-polling session's queues standing in for them. The connection
+/// ends when `ws_read` does. `token` is the bearer token the client
+/// authenticated with, `None` for a client certificate.
+async fn handle_socket<W, R>(
+    (mut ws_write, ws_read): (W, R),
+    state: Arc<AppState>,
+    token: Option<String>,
+    claims: Claims,
+    user_id: UserId,
+    timestamps: bool,
+    correlation_id: String,
+) where
+    W: Sink<Message> + Unpin + Send + 'static,
+    R: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    tracing::info!("connected");
+
+    // Writer channel
+    let (msg_tx, mut msg_rx) = mpsc::unbounded_channel::<Message>();
+
+    // Set by a `Hello` negotiating compression; the writer flags, and
+    // compresses where it pays, every frame it sends from then on.
+    let compressed = Arc::new(AtomicBool::new(false));
+
+    // Writer task (the ONLY task that touches ws_write)
+    let writer = uchat_metrics::spawn_task("ws_writer", {
+        let compressed = compressed.clone();
+        async move {
+            while let Some(msg) = msg_rx.recv().await {
+                let msg = if compressed.load(Ordering::Relaxed) { compress_frame(msg) } else { msg };
+                if ws_write.send(msg).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut conn = Connection {
+        state,
+        msg_tx,
+        compressed,
+        token,
+        claims,
+        user_id,
+        timestamps,
+        correlation_id,
+        subscriptions: HashMap::new(),
+        format: SerializationFormat::Json,
+        revoked: false,
+    };
+    let membership = conn.state.membership.subscribe();
+    let user_events = conn.state.user_events.subscribe();
+
+    conn.state.presence.connect(&conn.user_id).await;
+    let heartbeat = tokio::time::interval(presence::HEARTBEAT_INTERVAL);
+
+    // A panic while handling a frame must not skip the cleanup below, which
+    // would leave the user online and the socket's room tasks running.
+    let handled = AssertUnwindSafe(serve_frames(&mut conn, ws_read, heartbeat, membership, user_events))
+        .catch_unwind()
+        .await;
+    let Connection { state, msg_tx, user_id, subscriptions, format, revoked, .. } = conn;
+    let panicked = handled.is_err();
+    if let Err(panic) = handled {
+        tracing::error!(panic = uchat_telemetry::panic_message(&*panic), "connection handler panicked; closing");
+        send_event(&msg_tx, format, &ServerEvent::error(ErrorCode::Internal, "internal error"));
+        let _ = msg_tx.send(Message::Close(None));
+    }
+
+    let channels = channel_rooms(&subscriptions);
+    if state.presence.disconnect(&user_id, &channels).await {
+        for channel_id in channels {
+            let room_id = RoomId::from(channel_id.clone());
+            let event = ServerEvent::Presence { room_id: channel_id, user_id: user_id.clone(), online: false };
+            if let Ok(json) = serde_json::to_string(&event) {
+                state.broadcast(&room_id, json).await;
+            }
+        }
+    }
+
+    if format == SerializationFormat::Msgpack {
+        state.msgpack_sockets.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    tracing::info!("disconnected");
+    // After a panic or a deleted account the client is still there: let
+    // the writer deliver the last frames and the close frame.
+    let flush = panicked || revoked;
+    if !flush {
+        writer.abort();
+    }
+    for (room_id, forward) in subscriptions {
+        forward.abort();
+        // Wait for the task to drop its receiver before checking whether
+        // the room is now empty.
+        let _ = forward.await;
+        state.cleanup_room(&room_id).await;
+    }
+    if flush {
+        // The writer finishes once the last sender is gone.
+        drop(msg_tx);
+        let _ = tokio::time::timeout(CLOSE_GRACE, writer).await;
+    }
+}
+
+/// What a connection's frame loop shares with the cleanup after it.
+struct Connection {
+    state: Arc<AppState>,
+    msg_tx: mpsc::UnboundedSender<Message>,
+    compressed: Arc<AtomicBool>,
+    token: Option<String>,
+    claims: Claims,
+    user_id: UserId,
+    timestamps: bool,
+    correlation_id: String,
+    /// One forward task per joined room, thread rooms included.
+    subscriptions: HashMap<RoomId, JoinHandle<()>>,
+    /// Set by `Hello`, before anything else is sent.
+    format: SerializationFormat,
+    /// Set when the account is deleted under the socket, which then closes.
+    revoked: bool,
+}
+
+/// Handles a connection's frames, and the events pushed to its user,
+/// until `ws_read` ends or the socket has to close.
+async fn serve_frames<R>(
+    conn: &mut Connection,
+    mut ws_read: R,
+    mut heartbeat: tokio::time::Interval,
+    mut membership: broadcast::Receiver<MembershipChange>,
+    mut user_events: broadcast::Receiver<(UserId, String)>,
+) where
+    R: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let Connection {
+        ref state,
+        ref msg_tx,
+        ref compressed,
+        ref token,
+        ref claims,
+        ref user_id,
+        timestamps,
+        ref correlation_id,
+        ref mut subscriptions,
+        ref mut format,
+        ref mut revoked,
+    } = *conn;
+
+    // Roles pushed by channels-api since the token was issued; these win
+    // over the token's `rooms` claim. `None` means access was revoked.
+    let mut overrides: HashMap<ChannelId, Option<RoomRole>> = HashMap::new();
+
+    // Old clients send every frame as v0; say so once per socket.
+    let mut warned_v0 = false;
+    // Frames parsed so far; `Hello` only counts as the first.
+    let mut frames: u64 = 0;
+    // What this socket's `Hello` negotiated; `None` allows everything,
+    // for clients that never say.
+    let mut negotiated: Option<HashSet<String>> = None;
+
+    let role_for = |overrides: &HashMap<ChannelId, Option<RoomRole>>, room_id: &ChannelId| {
+        match overrides.get(room_id) {
+            Some(role) => *role,
+            None => claims.rooms.role_for(room_id.as_str()),
+        }
+    };
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_read.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+
+            _ = heartbeat.tick() => {
+                state.presence.heartbeat(user_id, &channel_rooms(subscriptions)).await;
+                continue;
+            }
+
+            event = user_events.recv() => {
+                match event {
+                    Ok((to, json)) if to == *user_id => {
+                        match *format {
+                            SerializationFormat::Json => {
+                                let _ = msg_tx.send(Message::Text(json));
+                            }
+                            SerializationFormat::Msgpack => {
+                                if let Some(packed) = RoomMessage::json(&json, None).to_msgpack() {
+                                    let _ = msg_tx.send(Message::Binary(packed.body().to_vec()));
+                                }
+                            }
+                        }
+                        // `/internal/user-deleted` says so this way.
+                        if state.deleted_users.read().await.contains(user_id) {
+                            let _ = msg_tx.send(Message::Close(None));
+                            *revoked = true;
+                            break;
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+                continue;
+            }
+
+            change = membership.recv() => {
+                let change = match change {
+                    Ok(change) if change.user_id == *user_id => change,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                let role = change.role.map(|r| r.room_role());
+                overrides.insert(change.channel_id.clone(), role);
+
+                if role.is_none() {
+                    // Losing the channel also drops its thread rooms.
+                    let revoked: Vec<RoomId> = subscriptions
+                        .keys()
+                        .filter(|room_id| room_id.channel == change.channel_id)
+                        .cloned()
+                        .collect();
+                    for room_id in &revoked {
+                        if let Some(forward) = subscriptions.remove(room_id) {
+                            forward.abort();
+                            let _ = forward.await;
+                            state.cleanup_room(room_id).await;
+                        }
+                    }
+                    if !revoked.is_empty() {
+                        send_event(msg_tx, *format, &ServerEvent::Removed { room_id: change.channel_id });
+                    }
+                }
+                continue;
+            }
+        };
+
+        let frame = match msg {
+            Ok(Message::Text(text)) => parse_frame(&text, user_id, &mut warned_v0).map_err(|_| ()),
+            Ok(Message::Binary(bytes)) if compressed.load(Ordering::Relaxed) => match compression::decode(&bytes) {
+                Ok(payload) => match *format {
+                    SerializationFormat::Json => std::str::from_utf8(&payload)
+                        .map_err(|_| ())
+                        .and_then(|text| parse_frame(text, user_id, &mut warned_v0).map_err(|_| ())),
+                    SerializationFormat::Msgpack => format::from_msgpack::<ClientFrame>(&payload).map_err(|_| ()),
+                },
+                Err(_) => Err(()),
+            },
+            Ok(Message::Binary(bytes)) => format::from_msgpack::<ClientFrame>(&bytes).map_err(|_| ()),
+            Ok(Message::Close(_)) => break,
+            _ => continue,
+        };
+
+        match frame.map(|f| {
+            observe_round_trip(&state.metrics, f.ts_gateway);
+            frames += 1;
+            let action = f.correlation_id.map(|id| uchat_telemetry::correlation_id(Some(&id)));
+            (f.cid, action, f.event)
+        }) {
+            Ok((_, _, ClientEvent::Login { .. })) => {
+                send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::InvalidEvent, "Login is handled by auth-api"));
+            }
+
+            Ok((_, _, ClientEvent::Hello { last_seq, capabilities: offered, format: requested })) => {
+                if frames > 1 {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::InvalidEvent, "Hello must be the first frame"));
+                    continue;
+                }
+                if let Some(requested) = requested {
+                    *format = requested;
+                    if *format == SerializationFormat::Msgpack {
+                        state.msgpack_sockets.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                if offered.is_some() || requested.is_some() {
+                    let capabilities = match offered {
+                        Some(offered) => {
+                            let capabilities = capabilities::negotiate(&offered, SERVER_CAPABILITIES);
+                            negotiated = Some(capabilities.clone());
+                            if capabilities.contains(capabilities::COMPRESSION_ZSTD) {
+                                compressed.store(true, Ordering::Relaxed);
+                            }
+                            capabilities
+                        }
+                        // Compression changes the framing, so only
+                        // clients that ask for it get it.
+                        None => SERVER_CAPABILITIES
+                            .iter()
+                            .filter(|cap| **cap != capabilities::COMPRESSION_ZSTD)
+                            .map(|cap| cap.to_string())
+                            .collect(),
+                    };
+                    send_event(msg_tx, *format, &ServerEvent::Capabilities { capabilities, format: *format });
+                }
+                // A fresh client has nothing to catch up on.
+                if let Some(last_seq) = last_seq {
+                    let (messages, truncated) = state.history.since(last_seq, |c| role_for(&overrides, c).is_some());
+                    send_event(msg_tx, *format, &ServerEvent::MessageBatch { messages, truncated });
+                }
+            }
+
+            Ok((_, _, ClientEvent::Subscribe { room_id })) => {
+                if role_for(&overrides, &room_id.channel).is_none() {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                if room_id.thread.is_some() && !allows(&negotiated, capabilities::THREADING) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::MissingCapability, "threads need the threading capability"));
+                    continue;
+                }
+                if subscriptions.contains_key(&room_id) {
+                    continue;
+                }
+
+                let rx = state.room(&room_id).await.subscribe();
+                let forward = uchat_metrics::spawn_task("room_forward", forward_room(rx, msg_tx.clone(), state.clone(), timestamps, *format));
+                subscriptions.insert(room_id.clone(), forward);
+
+                if room_id.thread.is_none() {
+                    let channel_id = room_id.channel.clone();
+                    state.presence.heartbeat(user_id, std::slice::from_ref(&channel_id)).await;
+                    let event = ServerEvent::Presence { room_id: channel_id, user_id: user_id.clone(), online: true };
+                    if let Ok(json) = serde_json::to_string(&event) {
+                        state.broadcast(&room_id, json).await;
+                    }
+                }
+            }
+
+            Ok((_, _, ClientEvent::Typing { room_id })) => {
+                if role_for(&overrides, &room_id.channel) != Some(RoomRole::Write) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                state.presence.typing(&room_id, user_id).await;
+                let event = ServerEvent::Typing { room_id: room_id.clone(), user_id: user_id.clone() };
+                if let Ok(json) = serde_json::to_string(&event) {
+                    state.broadcast(&room_id, json).await;
+                }
+            }
+
+            Ok((_, _, ClientEvent::Who { room_id })) => {
+                if role_for(&overrides, &room_id).is_none() {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                let online = state.presence.online_in(&room_id).await;
+                let typing = state.presence.typing_in(&RoomId::from(room_id.clone())).await;
+                send_event(msg_tx, *format, &ServerEvent::Who { room_id, online, typing });
+            }
+
+            Ok((cid, action, ClientEvent::SendMessage { room_id, content, encrypted, content_type, thread_id })) => {
+                let send_time = Instant::now();
+                if role_for(&overrides, &room_id) != Some(RoomRole::Write) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                // Slash commands go to whoever registered them, not the
+                // room.
+                if let Some((name, text)) = split_command(&content).filter(|_| !encrypted) {
+                    let room_id = RoomId { channel: room_id, thread: thread_id };
+                    let (target, invocation) =
+                        match state.commands.invocation(name, text, user_id, claims.role.clone(), room_id) {
+                            Ok(invoked) => invoked,
+                            Err((code, message)) => {
+                                send_event(msg_tx, *format, &ServerEvent::error(code, message));
+                                continue;
+                            }
+                        };
+                    let (state, msg_tx, format) = (state.clone(), msg_tx.clone(), *format);
+                    let command = async move {
+                        if let Err((code, message)) = commands::dispatch(&state, target, invocation).await {
+                            send_event(&msg_tx, format, &ServerEvent::error(code, message));
+                        }
+                    };
+                    uchat_metrics::spawn_task("slash_command", command.instrument(frame_span(action.as_deref())));
+                    continue;
+                }
+                let message = OutgoingMessage {
+                    room_id,
+                    thread_id,
+                    content,
+                    encrypted,
+                    content_type,
+                    bridged_from: None,
+                    sender_name: None,
+                    message_id: None,
+                    sender_role: claims.role.clone(),
+                    sender_joined: claims.joined,
+                };
+                if !may_send(&negotiated, &message) {
+                    send_event(msg_tx, *format, &ServerEvent::Nack { client_id: cid, code: ErrorCode::MissingCapability, retryable: false });
+                    continue;
+                }
+                match state.send_message(user_id, cid.as_deref(), message, send_time).await {
+                    Ok(Some((room_id, _))) => {
+                        frame_span(action.as_deref()).in_scope(|| tracing::debug!(room_id = %room_id, "message sent"));
+                    }
+                    Ok(None) => {}
+                    Err(code) => {
+                        send_event(msg_tx, *format, &ServerEvent::Nack { client_id: cid, code, retryable: false });
+                    }
+                }
+            }
+
+            // Bridges repost what they see in a room they follow into one
+            // they may write to; a room exists here while someone on this
+            // instance is subscribed to it.
+            Ok((cid, action, ClientEvent::RelayMessage { source_room, target_room, payload, seq, message_id })) => {
+                let send_time = Instant::now();
+                if !claims.is_bridge() || role_for(&overrides, &target_room.channel) != Some(RoomRole::Write) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                if !subscriptions.contains_key(&source_room) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::NotSubscribed, "not subscribed to the source room"));
+                    continue;
+                }
+                if !state.rooms.read().await.contains_key(&target_room) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::NotFound, "unknown target room"));
+                    continue;
+                }
+                // How far the message has travelled is ours to say, not
+                // the bridge's.
+                let Some(original) = state.history.find(&source_room, seq, message_id.as_ref()) else {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::NotFound, "unknown source message"));
+                    continue;
+                };
+                let hop_count = original.bridged_from.map_or(0, |from| from.hop_count);
+                if hop_count >= MAX_RELAY_HOPS {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::HopLimitReached, "relay hop limit reached"));
+                    continue;
+                }
+                let message = OutgoingMessage {
+                    room_id: target_room.channel,
+                    thread_id: target_room.thread,
+                    content: payload,
+                    encrypted: false,
+                    content_type: "text/plain".into(),
+                    bridged_from: Some(BridgedFrom { room_id: source_room, hop_count: hop_count + 1 }),
+                    sender_name: None,
+                    message_id: None,
+                    sender_role: claims.role.clone(),
+                    sender_joined: claims.joined,
+                };
+                if !may_send(&negotiated, &message) {
+                    send_event(msg_tx, *format, &ServerEvent::Nack { client_id: cid, code: ErrorCode::MissingCapability, retryable: false });
+                    continue;
+                }
+                match state.send_message(user_id, cid.as_deref(), message, send_time).await {
+                    Ok(Some((room_id, _))) => {
+                        frame_span(action.as_deref()).in_scope(|| tracing::debug!(room_id = %room_id, "message relayed"));
+                    }
+                    Ok(None) => {}
+                    Err(code) => {
+                        send_event(msg_tx, *format, &ServerEvent::Nack { client_id: cid, code, retryable: false });
+                    }
+                }
+            }
+
+            // A bot answering a slash command sent to it.
+            Ok((cid, action, ClientEvent::CommandResponse { invocation_id, text, ephemeral })) => {
+                let Some(pending) = state.commands.take_response(&invocation_id, user_id) else {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::NotFound, "no such invocation"));
+                    continue;
+                };
+                if ephemeral {
+                    if let Ok(json) = serde_json::to_string(&pending.result(text)) {
+                        let _ = state.user_events.send((pending.invoker, json));
+                    }
+                    continue;
+                }
+                if role_for(&overrides, &pending.room_id.channel) != Some(RoomRole::Write) {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                let message = OutgoingMessage {
+                    room_id: pending.room_id.channel,
+                    thread_id: pending.room_id.thread,
+                    content: text,
+                    encrypted: false,
+                    content_type: "text/plain".into(),
+                    bridged_from: None,
+                    sender_name: None,
+                    message_id: None,
+                    sender_role: claims.role.clone(),
+                    sender_joined: claims.joined,
+                };
+                if !may_send(&negotiated, &message) {
+                    send_event(msg_tx, *format, &ServerEvent::Nack { client_id: cid, code: ErrorCode::MissingCapability, retryable: false });
+                    continue;
+                }
+                match state.send_message(user_id, cid.as_deref(), message, Instant::now()).await {
+                    Ok(Some((room_id, _))) => {
+                        frame_span(action.as_deref()).in_scope(|| tracing::debug!(room_id = %room_id, "command answered"));
+                    }
+                    Ok(None) => {}
+                    Err(code) => {
+                        send_event(msg_tx, *format, &ServerEvent::Nack { client_id: cid, code, retryable: false });
+                    }
+                }
+            }
+
+            Ok((_, action, ClientEvent::MarkRead { room_id, message_id })) => {
+                if role_for(&overrides, &room_id).is_none() {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Forbidden, "not allowed in this room"));
+                    continue;
+                }
+                // channels-api stores markers for the bearer of a
+                // token, which a certificate doesn't give us.
+                let Some(token) = token.clone() else {
+                    let message = "read receipts need a token-authenticated connection";
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Unauthorized, message));
+                    continue;
+                };
+                let Some(channels) = state.channels.clone() else {
+                    send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::Unavailable, "read receipts unavailable"));
+                    continue;
+                };
+
+                // Persisting goes through channels-api, so don't hold
+                // up this socket's other frames while it runs.
+                let (state, msg_tx, user_id, format) = (state.clone(), msg_tx.clone(), user_id.clone(), *format);
+                let span = frame_span(action.as_deref());
+                let action = action.unwrap_or_else(|| correlation_id.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = channels.mark_read(&token, &action, &room_id, &message_id).await {
+                        tracing::warn!(error = %e, "read receipt rejected");
+                        send_event(&msg_tx, format, &ServerEvent::error(ErrorCode::InvalidRequest, "read receipt rejected"));
+                        return;
+                    }
+                    let event = ServerEvent::ReadReceipt { room_id: room_id.clone(), user_id, message_id };
+                    if let Ok(json) = serde_json::to_string(&event) {
+                        state.broadcast(&RoomId::from(room_id), json).await;
+                    }
+                }.instrument(span));
+            }
+
+            Ok(_) => {}
+
+            Err(_) => {
+                send_event(msg_tx, *format, &ServerEvent::error(ErrorCode::InvalidEvent, "Invalid event"));
+            }
+        }
+    }
+}
+
+/// Whether a socket that negotiated `negotiated` may use `capability`.
+fn allows(negotiated: &Option<HashSet<String>>, capability: &str) -> bool {
+    negotiated.as_ref().is_none_or(|caps| caps.contains(capability))
+}
+
+/// Whether a socket may send `message`: encrypted messages need `e2ee`,
+/// thread replies `threading`.
+fn may_send(negotiated: &Option<HashSet<String>>, message: &OutgoingMessage) -> bool {
+    let needs = [(message.encrypted, capabilities::E2EE), (message.thread_id.is_some(), capabilities::THREADING)];
+    needs.iter().all(|&(needed, cap)| !needed || allows(negotiated, cap))
+}
+
+/// The channels among a socket's subscriptions, leaving out thread rooms.
+fn channel_rooms(subscriptions: &HashMap<RoomId, JoinHandle<()>>) -> Vec<ChannelId> {
+    subscriptions
+        .keys()
+        .filter(|room_id| room_id.thread.is_none())
+        .map(|room_id| room_id.channel.clone())
+        .collect()
+}
+
+/// Relays a room's events in the socket's `format` to one socket. Client
+/// messages are timed into the fan-out latency metric and, when a JSON
+/// socket asked for `timestamps`, wrapped in a `MessageTimestamp`.
+/// axum's `Message` owns its buffer, so each socket still costs one copy
+/// of the event.
+async fn forward_room(
+    mut rx: broadcast::Receiver<RoomMessage>,
+    tx: mpsc::UnboundedSender<Message>,
+    state: Arc<AppState>,
+    timestamps: bool,
+    format: SerializationFormat,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(message) if message.format() == Some(format) => {
+                if let Some(sent) = message.send_time {
+                    state.metrics.fanout_latency_us.observe(sent.elapsed().as_micros() as f64);
+                }
+                let msg = match (message.as_json(), message.send_time) {
+                    (Some(json), Some(sent)) if timestamps => Message::Text(wrap_timestamp(metrics::gateway_nanos(sent), json)),
+                    (Some(json), _) => Message::Text(json.to_string()),
+                    (None, _) => Message::Binary(message.body().to_vec()),
+                };
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Serializes a `MessageTimestamp` around `payload` without copying it
+/// into one first, sized up front so it doesn't regrow. Quotes in the
+/// payload are escaped, hence the headroom.
+fn wrap_timestamp(ts_gateway: u128, payload: &str) -> String {
+    #[derive(serde::Serialize)]
+    struct Wrapped<'a> {
+        ts_gateway: u128,
+        payload: &'a str,
+    }
+    let mut out = Vec::with_capacity(payload.len() + payload.len() / 8 + 64);
+    match serde_json::to_writer(&mut out, &Wrapped { ts_gateway, payload }) {
+        Ok(()) => String::from_utf8(out).unwrap_or_else(|_| payload.to_string()),
+        Err(_) => payload.to_string(),
+    }
+}
+
+/// Records the round trip for a frame echoing a `MessageTimestamp`.
+fn observe_round_trip(metrics: &metrics::Metrics, ts_gateway: Option<u128>) {
+    if let Some(elapsed) = ts_gateway.and_then(metrics::since_gateway_nanos) {
+        metrics.round_trip_latency_us.observe(elapsed.as_micros() as f64);
+    }
+}
+
+/// Parses a current-schema frame, falling back to upgrading a v0 one.
+fn parse_frame(text: &str, user_id: &UserId, warned_v0: &mut bool) -> Result<ClientFrame, serde_json::Error> {
+    let err = match serde_json::from_str::<ClientFrame>(text) {
+        Ok(frame) => return Ok(frame),
+        Err(e) => e,
+    };
+    let frame = schema::migrate_message_v0_to_v1(text).map_err(|_| err)?;
+    if !*warned_v0 {
+        tracing::warn!(user_id = %user_id, "client is sending v0 frames; upgrading them");
+        *warned_v0 = true;
+    }
+    Ok(frame)
+}
+
+/// `msg` as a frame for a socket that negotiated compression.
+fn compress_frame(msg: Message) -> Message {
+    match msg {
+        Message::Text(text) => Message::Binary(compression::encode(text.as_bytes())),
+        Message::Binary(bytes) => Message::Binary(compression::encode(&bytes)),
+        other => other,
+    }
+}
+
+fn send_event(tx: &mpsc::UnboundedSender<Message>, format: SerializationFormat, event: &ServerEvent) {
+    let msg = match format {
+        SerializationFormat::Json => serde_json::to_string(event).ok().map(Message::Text),
+        SerializationFormat::Msgpack => format::to_msgpack(event).ok().map(Message::Binary),
+    };
+    if let Some(msg) = msg {
+        let _ = tx.send(msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use uchat_proto::events::MessageTimestamp;
+    use uchat_proto::ids::MessageId;
+    use uchat_proto::permissions::RoomPermissions;
+
+    /// Counts the bytes each thread allocates, for measuring fan-out.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    #[tokio::test]
+    async fn thread_rooms_are_isolated_and_cleaned_up() {
+        let state = test_state();
+        let channel = ChannelId::new();
+        let main_room = RoomId::from(channel.clone());
+        let thread_room = RoomId::thread(channel, MessageId::new());
+
+        let mut main_rx = state.room(&main_room).await.subscribe();
+        let mut thread_rx = state.room(&thread_room).await.subscribe();
+
+        state.broadcast(&thread_room, "reply".into()).await;
+        state.broadcast(&main_room, "post".into()).await;
+        assert_eq!(thread_rx.recv().await.unwrap().as_json().unwrap(), "reply");
+        assert_eq!(main_rx.recv().await.unwrap().as_json().unwrap(), "post");
+        assert!(thread_rx.try_recv().is_err());
+
+        drop(thread_rx);
+        state.cleanup_room(&thread_room).await;
+        state.cleanup_room(&main_room).await;
+        let rooms = state.rooms.read().await;
+        assert!(!rooms.contains_key(&thread_room));
+        assert!(rooms.contains_key(&main_room));
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_reports_an_internal_error_and_cleans_up() {
+        let state = test_state();
+        let user_id = UserId::new();
+        let claims = Claims { sub: user_id.to_string(), exp: usize::MAX, rooms: Default::default(), bot: false, role: None, joined: None };
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let write = Box::pin(futures_util::sink::unfold(tx, |tx, msg: Message| async move {
+            let _ = tx.send(msg);
+            Ok::<_, std::convert::Infallible>(tx)
+        }));
+        let read = futures_util::stream::poll_fn(|_| -> std::task::Poll<Option<Result<Message, axum::Error>>> {
+            panic!("malformed frame")
+        });
+
+        handle_socket((write, read), state.clone(), None, claims, user_id.clone(), false, String::new()).await;
+
+        let Some(Message::Text(error)) = rx.recv().await else { panic!("expected an error frame") };
+        assert!(error.contains("\"internal\""), "{}", error);
+        assert!(matches!(rx.recv().await, Some(Message::Close(None))));
+        assert!(rx.recv().await.is_none());
+        assert!(state.presence.online(&[user_id]).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn certificate_sockets_are_told_read_receipts_need_a_token() {
+        let state = test_state();
+        let user_id = UserId::new();
+        let mut rooms = RoomPermissions::new();
+        rooms.grant("*", RoomRole::Read);
+        let claims = Claims { sub: user_id.to_string(), exp: usize::MAX, rooms, bot: true, role: None, joined: None };
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let write = Box::pin(futures_util::sink::unfold(tx, |tx, msg: Message| async move {
+            let _ = tx.send(msg);
+            Ok::<_, std::convert::Infallible>(tx)
+        }));
+        let frame = serde_json::json!({
+            "schema_version": 1,
+            "MarkRead": { "room_id": ChannelId::new(), "message_id": MessageId::new() },
+        });
+        let read = futures_util::stream::iter([Ok(Message::Text(frame.to_string()))]).chain(futures_util::stream::pending());
+
+        let socket = tokio::spawn(handle_socket((write, read), state, None, claims, user_id, false, String::new()));
+        let Some(Message::Text(error)) = rx.recv().await else { panic!("expected an error frame") };
+        socket.abort();
+        assert!(error.contains("\"unauthorized\"") && error.contains("token-authenticated"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn capabilities_belong_to_the_socket_that_negotiated_them() {
+        let state = test_state();
+        let user_id = UserId::new();
+        let (channel_id, thread_id) = (ChannelId::new(), MessageId::new());
+        let thread = RoomId::thread(channel_id.clone(), thread_id.clone());
+        let socket = |frames: Vec<serde_json::Value>| {
+            let mut rooms = RoomPermissions::new();
+            rooms.grant("*", RoomRole::Write);
+            let claims = Claims { sub: user_id.to_string(), exp: usize::MAX, rooms, bot: false, role: None, joined: None };
+            let (tx, rx) = mpsc::unbounded_channel();
+            let write = Box::pin(futures_util::sink::unfold(tx, |tx, msg: Message| async move {
+                let _ = tx.send(msg);
+                Ok::<_, std::convert::Infallible>(tx)
+            }));
+            let read = futures_util::stream::iter(frames.into_iter().map(|f| Ok(Message::Text(f.to_string()))))
+                .chain(futures_util::stream::pending());
+            let task = tokio::spawn(handle_socket((write, read), state.clone(), None, claims, user_id.clone(), false, String::new()));
+            (task, rx)
+        };
+        let send = |content: &str| {
+            serde_json::json!({ "schema_version": 1, "SendMessage": {
+                "room_id": channel_id, "content": content, "encrypted": false,
+                "content_type": "text/plain", "thread_id": thread_id,
+            }})
+        };
+
+        // This socket leaves threading out of its Hello...
+        let hello = serde_json::json!({ "schema_version": 1, "Hello": { "capabilities": ["e2ee"] } });
+        let (narrow, mut narrow_rx) = socket(vec![hello, send("refused")]);
+        let Some(Message::Text(capabilities)) = narrow_rx.recv().await else { panic!("expected capabilities") };
+        assert!(capabilities.contains("Capabilities"), "{}", capabilities);
+        let Some(Message::Text(nack)) = narrow_rx.recv().await else { panic!("expected a nack") };
+        assert!(nack.contains("missing_capability"), "{}", nack);
+
+        // ...which says nothing about the same user's other socket.
+        let subscribe = serde_json::json!({ "schema_version": 1, "Subscribe": { "room_id": thread } });
+        let (legacy, mut legacy_rx) = socket(vec![subscribe, send("delivered")]);
+        let Some(Message::Text(message)) = legacy_rx.recv().await else { panic!("expected the thread reply") };
+        assert!(message.contains("delivered"), "{}", message);
+
+        narrow.abort();
+        legacy.abort();
+    }
+
+    #[tokio::test]
+    async fn channel_sets_are_loaded_once_channels_api_answers() {
+        let (archived, markdown, e2ee) = (ChannelId::new(), ChannelId::new(), ChannelId::new());
+        let (ready, archived_ids, markdown_ids, e2ee_ids) =
+            (Arc::new(AtomicBool::new(false)), vec![archived.clone()], vec![markdown.clone()], vec![e2ee.clone()]);
+        let fake_api = Router::new()
+            .route("/internal/archived-channels", get({
+                let ready = ready.clone();
+                move |headers: HeaderMap| async move {
+                    if !ready.load(Ordering::Relaxed) {
+                        return Err(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                    assert_eq!(headers["x-internal-token"], "internal-secret");
+                    Ok(axum::Json(archived_ids))
+                }
+            }))
+            .route("/internal/markdown-channels", get(move || async move { axum::Json(markdown_ids) }))
+            .route("/internal/e2ee-channels", get(move || async move { axum::Json(e2ee_ids) }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_api).await.unwrap() });
+
+        let mut state = test_state();
+        Arc::get_mut(&mut state).unwrap().channels = Some(ChannelsClient::new(&format!("http://{}", addr)));
+        load_channel_sets(&state);
+
+        // The first archived-channels attempt fails and is retried.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(state.archived.read().await.is_empty());
+        ready.store(true, Ordering::Relaxed);
+        for _ in 0..50 {
+            if state.archived.read().await.contains(&archived) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(state.archived.read().await.contains(&archived));
+        assert!(state.markdown_channels.read().await.contains(&markdown));
+        assert!(state.e2ee_channels.read().await.contains(&e2ee));
+    }
+
+    #[tokio::test]
+    async fn client_messages_are_timed_and_optionally_wrapped() {
+        let state = test_state();
+        let room = RoomId::from(ChannelId::new());
+
+        let (plain_tx, mut plain_rx) = mpsc::unbounded_channel();
+        let (wrapped_tx, mut wrapped_rx) = mpsc::unbounded_channel();
+        let plain = tokio::spawn(forward_room(state.room(&room).await.subscribe(), plain_tx, state.clone(), false, SerializationFormat::Json));
+        let wrapped = tokio::spawn(forward_room(state.room(&room).await.subscribe(), wrapped_tx, state.clone(), true, SerializationFormat::Json));
+
+        let sent = Instant::now();
+        state.broadcast(&room, "presence".into()).await;
+        state.broadcast_timed(&room, "message".into(), sent).await;
+
+        let text = |msg: Option<Message>| match msg {
+            Some(Message::Text(text)) => text,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(text(plain_rx.recv().await), "presence");
+        assert_eq!(text(plain_rx.recv().await), "message");
+        assert_eq!(text(wrapped_rx.recv().await), "presence");
+        let stamped: MessageTimestamp = serde_json::from_str(&text(wrapped_rx.recv().await)).unwrap();
+        assert_eq!(stamped.payload, "message");
+        assert_eq!(stamped.ts_gateway, metrics::gateway_nanos(sent));
+
+        plain.abort();
+        wrapped.abort();
+        let _ = (plain.await, wrapped.await);
+        assert!(state.metrics.render().contains("uchat_message_latency_us_count{stage=\"fanout\"} 2\n"));
+
+        observe_round_trip(&state.metrics, Some(stamped.ts_gateway));
+        assert!(state.metrics.render().contains("uchat_message_latency_us_count{stage=\"round_trip\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn msgpack_sockets_get_their_own_copy() {
+        let state = test_state();
+        let room = RoomId::from(ChannelId::new());
+        let (json_tx, mut json_rx) = mpsc::unbounded_channel();
+        let (packed_tx, mut packed_rx) = mpsc::unbounded_channel();
+        let json = tokio::spawn(forward_room(state.room(&room).await.subscribe(), json_tx, state.clone(), true, SerializationFormat::Json));
+        let packed = tokio::spawn(forward_room(state.room(&room).await.subscribe(), packed_tx, state.clone(), true, SerializationFormat::Msgpack));
+
+        // Nobody negotiated MessagePack yet, so nothing is encoded for it.
+        let event = ServerEvent::Presence { room_id: room.channel.clone(), user_id: UserId::new(), online: true };
+        state.broadcast(&room, serde_json::to_string(&event).unwrap()).await;
+        state.msgpack_sockets.fetch_add(1, Ordering::Relaxed);
+        state.broadcast(&room, serde_json::to_string(&event).unwrap()).await;
+        // Not a `ServerEvent`, so JSON only.
+        state.broadcast(&room, "presence".into()).await;
+        state.broadcast_timed(&room, serde_json::to_string(&event).unwrap(), Instant::now()).await;
+
+        for _ in 0..2 {
+            assert!(matches!(json_rx.recv().await, Some(Message::Text(text)) if text.contains("Presence")));
+        }
+        assert!(matches!(json_rx.recv().await, Some(Message::Text(text)) if text == "presence"));
+        assert!(matches!(json_rx.recv().await, Some(Message::Text(text)) if text.contains("ts_gateway")));
+        for _ in 0..2 {
+            let Some(Message::Binary(bytes)) = packed_rx.recv().await else { panic!("expected a binary frame") };
+            let decoded: ServerEvent = format::from_msgpack(&bytes).unwrap();
+            assert!(matches!(decoded, ServerEvent::Presence { online: true, .. }));
+        }
+
+        json.abort();
+        packed.abort();
+        let _ = (json.await, packed.await);
+        assert!(json_rx.try_recv().is_err() && packed_rx.try_recv().is_err());
+    }
+
+    /// Bytes allocated on this thread while one `size`-byte client
+    /// message fans out to `subscribers` sockets and is drained.
+    async fn fanout_allocations(subscribers: usize, size: usize, timestamps: bool) -> usize {
+        let state = test_state();
+        let room = RoomId::from(ChannelId::new());
+        let mut sockets = Vec::new();
+        for _ in 0..subscribers {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let forward = tokio::spawn(forward_room(state.room(&room).await.subscribe(), tx, state.clone(), timestamps, SerializationFormat::Json));
+            sockets.push((forward, rx));
+        }
+        // Let every forward task reach `recv` before measuring.
+        tokio::task::yield_now().await;
+        let json = "x".repeat(size);
+
+        let before = ALLOCATED.with(Cell::get);
+        state.broadcast_timed(&room, json, Instant::now()).await;
+        for (_, rx) in &mut sockets {
+            assert!(matches!(rx.recv().await, Some(Message::Text(text)) if text.len() >= size));
+        }
+        let allocated = ALLOCATED.with(Cell::get) - before;
+
+        for (forward, _) in sockets {
+            forward.abort();
+        }
+        allocated
+    }
+
+    #[tokio::test]
+    async fn fanout_copies_each_message_once_per_subscriber() {
+        const SUBSCRIBERS: usize = 500;
+        const SIZE: usize = 4096;
+
+        // One copy per socket for axum's owned `Message::Text`, plus
+        // slack for the channels' own bookkeeping. Cloning the event out
+        // of the broadcast channel as well would double it.
+        for timestamps in [false, true] {
+            let allocated = fanout_allocations(SUBSCRIBERS, SIZE, timestamps).await;
+            assert!(allocated < SUBSCRIBERS * SIZE * 5 / 4, "timestamps={} allocated {} bytes", timestamps, allocated);
+        }
+    }
+
+    #[test]
+    fn capabilities_default_to_everything() {
+        let offered = HashSet::from([capabilities::E2EE.to_string(), capabilities::VOICE.to_string()]);
+        let declared = Some(capabilities::negotiate(&offered, SERVER_CAPABILITIES));
+
+        assert!(allows(&None, capabilities::THREADING));
+        assert!(allows(&declared, capabilities::E2EE));
+        assert!(!allows(&declared, capabilities::THREADING));
+        assert!(!allows(&declared, capabilities::VOICE));
+    }
+
+    #[tokio::test]
+    async fn responses_echo_the_correlation_id() {
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+        use uchat_telemetry::CORRELATION_ID_HEADER;
+
+        let req = Request::get("/internal/metrics").header(CORRELATION_ID_HEADER, "req-42").body(Body::empty()).unwrap();
+        let resp = app(test_state()).oneshot(req).await.unwrap();
+        assert_eq!(resp.headers()[CORRELATION_ID_HEADER], "req-42");
+    }
+}
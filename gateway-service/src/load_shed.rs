@@ -0,0 +1,113 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use systemstat::{Platform, System};
+
+use crate::AppState;
+
+pub const DEFAULT_MAX_CPU_PERCENT: u8 = 90;
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Samples aggregate CPU usage once a second into a shared percentage that
+/// `shed_load` checks before accepting new connections.
+pub struct LoadShedder {
+    current_load: Arc<AtomicU8>,
+}
+
+impl LoadShedder {
+    pub fn new(current_load: Arc<AtomicU8>) -> Self {
+        Self { current_load }
+    }
+
+    pub fn spawn(self) {
+        uchat_metrics::spawn_task("load_sampler", async move {
+            let sys = System::new();
+            loop {
+                let measurement = match sys.cpu_load_aggregate() {
+                    Ok(m) => m,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "CPU sampling unavailable, load shedding disabled");
+                        return;
+                    }
+                };
+                tokio::time::sleep(Duration::from_secs(1)).await;
+
+                if let Ok(load) = measurement.done() {
+                    let percent = ((1.0 - load.idle) * 100.0).round().clamp(0.0, 100.0) as u8;
+                    self.current_load.store(percent, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+}
+
+/// `GATEWAY_MAX_CPU_PERCENT`, defaulting to 90.
+pub fn max_cpu_percent_from_env() -> u8 {
+    std::env::var("GATEWAY_MAX_CPU_PERCENT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CPU_PERCENT)
+}
+
+/// Rejects new connections with 503 while the last CPU sample is above
+/// `max_cpu_percent`. Established sockets are left alone.
+pub async fn shed_load(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    if state.current_load.load(Ordering::Relaxed) > state.max_cpu_percent {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+            Json(json!({ "error": "overloaded", "retry_after": RETRY_AFTER_SECS })),
+        )
+            .into_response();
+    }
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn rejects_upgrade_when_overloaded() {
+        let state = test_state();
+        state.current_load.store(100, Ordering::Relaxed);
+
+        let resp = app(state)
+            .oneshot(Request::get("/ws").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "5");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "error": "overloaded", "retry_after": 5 }));
+    }
+
+    #[tokio::test]
+    async fn passes_through_below_threshold() {
+        let state = test_state();
+        state.current_load.store(DEFAULT_MAX_CPU_PERCENT, Ordering::Relaxed);
+
+        let resp = app(state)
+            .oneshot(Request::get("/ws").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_ne!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use uchat_metrics::Histogram;
+
+/// Upper bounds of the latency buckets, in microseconds.
+const LATENCY_BUCKETS_US: &[f64] =
+    &[100.0, 250.0, 500.0, 1_000.0, 2_500.0, 5_000.0, 10_000.0, 25_000.0, 50_000.0, 100_000.0, 250_000.0, 1_000_000.0];
+
+/// What `ts_gateway` counts from.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// `at` as nanoseconds on the gateway's clock, for `MessageTimestamp`.
+pub fn gateway_nanos(at: Instant) -> u128 {
+    at.saturating_duration_since(*EPOCH).as_nanos()
+}
+
+/// How long ago a `gateway_nanos` timestamp was; `None` for one from the
+/// future, which can't have come from this process.
+pub fn since_gateway_nanos(nanos: u128) -> Option<Duration> {
+    let now = gateway_nanos(Instant::now());
+    let elapsed = now.checked_sub(nanos)?;
+    Some(Duration::from_nanos(u64::try_from(elapsed).ok()?))
+}
+
+/// Gateway metrics, served in the Prometheus text format at
+/// `/internal/metrics`.
+pub struct Metrics {
+    /// From a `SendMessage` entering the broadcast path to each
+    /// subscriber's socket being handed the message.
+    pub fanout_latency_us: Histogram,
+    /// From a broadcast to the gateway receiving a frame that echoes its
+    /// `ts_gateway`.
+    pub round_trip_latency_us: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            fanout_latency_us: Histogram::new(LATENCY_BUCKETS_US),
+            round_trip_latency_us: Histogram::new(LATENCY_BUCKETS_US),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP uchat_message_latency_us Message latency through the gateway, in microseconds.\n");
+        out.push_str("# TYPE uchat_message_latency_us histogram\n");
+        self.fanout_latency_us.render(&mut out, "uchat_message_latency_us", "stage=\"fanout\"");
+        self.round_trip_latency_us.render(&mut out, "uchat_message_latency_us", "stage=\"round_trip\"");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let metrics = Metrics::default();
+        for us in [50.0, 100.0, 700.0, 2_000_000.0] {
+            metrics.fanout_latency_us.observe(us);
+        }
+
+        let text = metrics.render();
+        assert!(text.contains("uchat_message_latency_us_bucket{stage=\"fanout\",le=\"100\"} 2\n"));
+        assert!(text.contains("uchat_message_latency_us_bucket{stage=\"fanout\",le=\"1000\"} 3\n"));
+        assert!(text.contains("uchat_message_latency_us_bucket{stage=\"fanout\",le=\"+Inf\"} 4\n"));
+        assert!(text.contains("uchat_message_latency_us_sum{stage=\"fanout\"} 2000850\n"));
+        assert!(text.contains("uchat_message_latency_us_count{stage=\"round_trip\"} 0\n"));
+    }
+
+    #[test]
+    fn gateway_timestamps_measure_elapsed_time() {
+        let sent = gateway_nanos(Instant::now());
+        assert!(since_gateway_nanos(sent).is_some());
+        assert!(since_gateway_nanos(sent + 60_000_000_000).is_none());
+    }
+}
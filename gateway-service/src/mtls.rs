@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use axum::{Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{self, server::WebPkiClientVerifier, RootCertStore, ServerConfig, ServerConnection};
+use tokio_rustls::TlsAcceptor;
+
+use uchat_proto::jwt::Claims;
+use uchat_proto::permissions::RoomPermissions;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// A registered device that presented a valid client certificate. Added
+/// to every request on that TLS connection.
+#[derive(Debug, Clone)]
+pub struct DeviceCert {
+    pub device_id: String,
+    /// The certificate's `notAfter`, in Unix seconds.
+    pub not_after: i64,
+    pub rooms: RoomPermissions,
+}
+
+impl DeviceCert {
+    /// Claims equivalent to a bot token for this device, so the socket
+    /// is handled the same way as a JWT-authenticated one.
+    pub fn claims(&self) -> Claims {
+        Claims {
+            sub: self.device_id.clone(),
+            exp: self.not_after.max(0) as usize,
+            rooms: self.rooms.clone(),
+            bot: true,
+            role: None,
+            joined: None,
+        }
+    }
+}
+
+/// TLS listener settings for mutual TLS.
+///
+/// Client certificates are verified against the CA bundle during the
+/// handshake but are optional, so browsers without one still connect and
+/// authenticate with a JWT. A certificate only stands in for a token when
+/// its `CN` is a registered device id.
+pub struct MtlsConfig {
+    acceptor: TlsAcceptor,
+    /// Registered device ids and the rooms each may use. Device ids are
+    /// user ids, so a device's messages are attributed like anyone else's.
+    devices: HashMap<String, RoomPermissions>,
+}
+
+impl MtlsConfig {
+    /// Reads `CA_CERT_PATH`, `TLS_CERT_PATH` and `TLS_KEY_PATH` (all PEM),
+    /// plus the device registry at `MTLS_DEVICES_PATH`: a JSON object
+    /// from device id to room permissions. `None` when `CA_CERT_PATH` is
+    /// unset; any other missing or invalid setting is fatal.
+    pub fn from_env() -> Option<Self> {
+        let ca_path = std::env::var("CA_CERT_PATH").ok().filter(|v| !v.is_empty())?;
+        let read = |var: &str| {
+            let path = std::env::var(var).unwrap_or_else(|_| panic!("{} must be set with CA_CERT_PATH", var));
+            std::fs::read(&path).unwrap_or_else(|e| panic!("reading {}: {}", path, e))
+        };
+        let ca = std::fs::read(&ca_path).unwrap_or_else(|e| panic!("reading {}: {}", ca_path, e));
+
+        let devices = match std::env::var("MTLS_DEVICES_PATH") {
+            Ok(path) => serde_json::from_slice(&read("MTLS_DEVICES_PATH"))
+                .unwrap_or_else(|e| panic!("parsing {}: {}", path, e)),
+            Err(_) => HashMap::new(),
+        };
+
+        let config = Self::new(&ca, &read("TLS_CERT_PATH"), &read("TLS_KEY_PATH"), devices)
+            .unwrap_or_else(|e| panic!("invalid mTLS configuration: {}", e));
+        Some(config)
+    }
+
+    pub fn new(
+        ca_pem: &[u8],
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        devices: HashMap<String, RoomPermissions>,
+    ) -> Result<Self, BoxError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &ca_pem[..]) {
+            roots.add(cert?)?;
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .allow_unauthenticated()
+            .build()?;
+
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..]).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])?.ok_or("no private key in TLS_KEY_PATH")?;
+
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)?;
+        // WebSocket upgrades need HTTP/1.1.
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self { acceptor: TlsAcceptor::from(Arc::new(config)), devices })
+    }
+
+    /// The registered device behind a verified client certificate, if any.
+    fn device(&self, conn: &ServerConnection) -> Option<DeviceCert> {
+        let der = conn.peer_certificates()?.first()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let cn = cert.subject().iter_common_name().next()?.as_str().ok()?;
+        let rooms = self.devices.get(cn)?;
+
+        Some(DeviceCert {
+            device_id: cn.to_string(),
+            not_after: cert.validity().not_after.timestamp(),
+            rooms: rooms.clone(),
+        })
+    }
+}
+
+/// Serves `app` over TLS. Connections from registered devices carry an
+/// `Extension<DeviceCert>` on each of their requests.
+pub async fn serve(listener: TcpListener, app: Router, tls: Arc<MtlsConfig>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::warn!(error = %e, "accept failed");
+                continue;
+            }
+        };
+
+        let (app, tls) = (app.clone(), tls.clone());
+        uchat_metrics::spawn_task("tls_connection", async move {
+            let stream = match tls.acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!(error = %e, peer = %peer, "TLS handshake failed");
+                    return;
+                }
+            };
+
+            let app = match tls.device(stream.get_ref().1) {
+                Some(device) => app.layer(Extension(device)),
+                None => app,
+            };
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app))
+                .await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use tokio_rustls::TlsConnector;
+    use uchat_proto::ids::UserId;
+    use uchat_proto::permissions::RoomRole;
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let ca_key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "uchat test ca");
+            Pki { ca: params.self_signed(&ca_key).unwrap(), ca_key }
+        }
+
+        fn issue(&self, cn: &str, sans: Vec<String>, usage: ExtendedKeyUsagePurpose) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(sans).unwrap();
+            params.distinguished_name.push(DnType::CommonName, cn);
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+    }
+
+    async fn whoami(addr: std::net::SocketAddr, pki: &Pki, client: Option<(String, String)>) -> String {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.der().clone()).unwrap();
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => {
+                let certs: Vec<CertificateDer> =
+                    rustls_pemfile::certs(&mut cert.as_bytes()).collect::<Result<_, _>>().unwrap();
+                let key: PrivateKeyDer = rustls_pemfile::private_key(&mut key.as_bytes()).unwrap().unwrap();
+                builder.with_client_auth_cert(certs, key).unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.rsplit("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn registered_device_certificates_become_bot_claims() {
+        let pki = Pki::new();
+        let (server_cert, server_key) =
+            pki.issue("gateway", vec!["localhost".into()], ExtendedKeyUsagePurpose::ServerAuth);
+
+        let mut rooms = RoomPermissions::new();
+        rooms.grant("sensors-*", RoomRole::Write);
+        let device_id = UserId::new().to_string();
+        let devices = HashMap::from([(device_id.clone(), rooms)]);
+        let tls = MtlsConfig::new(pki.ca.pem().as_bytes(), server_cert.as_bytes(), server_key.as_bytes(), devices)
+            .unwrap();
+
+        let app = Router::new().route(
+            "/whoami",
+            get(|device: Option<Extension<DeviceCert>>| async move {
+                match device {
+                    Some(Extension(device)) => {
+                        let claims = device.claims();
+                        format!("{} bot={} {:?}", claims.sub, claims.bot, claims.rooms.role_for("sensors-a"))
+                    }
+                    None => "anonymous".to_string(),
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, app, Arc::new(tls)));
+
+        let device = pki.issue(&device_id, vec![], ExtendedKeyUsagePurpose::ClientAuth);
+        assert_eq!(whoami(addr, &pki, Some(device)).await, format!("{} bot=true Some(Write)", device_id));
+
+        let unregistered = pki.issue(UserId::new().as_str(), vec![], ExtendedKeyUsagePurpose::ClientAuth);
+        assert_eq!(whoami(addr, &pki, Some(unregistered)).await, "anonymous");
+
+        assert_eq!(whoami(addr, &pki, None).await, "anonymous");
+    }
+}
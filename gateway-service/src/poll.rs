@@ -0,0 +1,350 @@
+//! HTTP long-polling for clients behind proxies that strip WebSocket
+//! upgrades. A session runs the same connection loop as a socket, so
+//! frames get the same validation and fan-out; only the transport
+//! differs. Frames the client sends arrive through `POST /poll/{sid}/send`,
+//! and frames the gateway would write to the socket queue up until
+//! `GET /poll/{sid}` collects them.
+//!
+//! The session id is the session's credential, as the upgraded socket is
+//! for a WebSocket. Sessions speak JSON only.
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use axum::{
+    extract::{ws::Message, Extension, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::{sink, stream};
+use serde::Deserialize;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use uchat_proto::events::{ClientEvent, ClientFrame};
+use uchat_proto::format::SerializationFormat;
+use uchat_proto::ids::PollSessionId;
+use uchat_proto::poll::{PollBatch, PollSession};
+use uchat_telemetry::CorrelationId;
+
+use crate::{connection_span, handle_socket, socket_task, too_many_connections, verify_user, AppState};
+
+/// Frames a session holds for its next poll; past this, new frames are
+/// dropped and counted.
+const QUEUE_CAPACITY: usize = 256;
+const DEFAULT_WAIT_SECS: u64 = 25;
+const MAX_WAIT_SECS: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Frames waiting for the next poll.
+#[derive(Default)]
+struct Outbox {
+    queue: Mutex<(VecDeque<String>, u64)>,
+    ready: Notify,
+}
+
+impl Outbox {
+    fn push(&self, frame: String) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            let (frames, dropped) = &mut *queue;
+            if frames.len() < QUEUE_CAPACITY {
+                frames.push_back(frame);
+            } else {
+                *dropped += 1;
+            }
+        }
+        self.ready.notify_one();
+    }
+
+    /// Everything queued, waiting up to `wait` for something to arrive if
+    /// the queue is empty.
+    async fn take(&self, wait: Duration) -> PollBatch {
+        let deadline = Instant::now() + wait;
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                let (frames, dropped) = &mut *queue;
+                if !frames.is_empty() || *dropped > 0 {
+                    return PollBatch { frames: frames.drain(..).collect(), dropped: std::mem::take(dropped) };
+                }
+            }
+            // A push since the check above left a permit, so this can't
+            // miss it.
+            if tokio::time::timeout_at(deadline, self.ready.notified()).await.is_err() {
+                return PollBatch::default();
+            }
+        }
+    }
+}
+
+struct Session {
+    /// Frames for the connection loop; dropping the session ends it.
+    inbound: mpsc::UnboundedSender<Message>,
+    outbox: Arc<Outbox>,
+    last_seen: Mutex<Instant>,
+}
+
+/// Open long-polling sessions, ended once idle for `idle_timeout`.
+pub struct PollSessions {
+    sessions: dashmap::DashMap<PollSessionId, Arc<Session>>,
+    idle_timeout: Duration,
+    sweeping: AtomicBool,
+}
+
+impl Default for PollSessions {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl PollSessions {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self { sessions: dashmap::DashMap::new(), idle_timeout, sweeping: AtomicBool::new(false) }
+    }
+
+    /// Idle timeout from `GATEWAY_POLL_IDLE_SECS`, default 60 seconds.
+    pub fn from_env() -> Self {
+        let idle_timeout = std::env::var("GATEWAY_POLL_IDLE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_secs);
+        Self::new(idle_timeout)
+    }
+
+    /// The session, marked as seen now.
+    fn get(&self, sid: &str) -> Option<Arc<Session>> {
+        let session = self.sessions.get(&sid.parse().ok()?)?.clone();
+        *session.last_seen.lock().unwrap() = Instant::now();
+        Some(session)
+    }
+
+    /// Ends sessions idle for the timeout as of `now`. A poll or send in
+    /// progress holds its session, which keeps it.
+    fn sweep(&self, now: Instant) {
+        self.sessions.retain(|_, session| {
+            Arc::strong_count(session) > 1 || now.duration_since(*session.last_seen.lock().unwrap()) < self.idle_timeout
+        });
+    }
+}
+
+/// Sweeps `state`'s idle sessions until it is dropped. Started by the
+/// first session.
+fn start_sweeping(state: &Arc<AppState>) {
+    if state.polls.sweeping.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let state: Weak<AppState> = Arc::downgrade(state);
+    uchat_metrics::spawn_task("poll_sweep", async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(state) = state.upgrade() else { break };
+            state.polls.sweep(Instant::now());
+        }
+    });
+}
+
+#[derive(Deserialize)]
+pub struct ConnectQuery {
+    token: Option<String>,
+    #[serde(default)]
+    timestamps: bool,
+}
+
+/// POST /poll/connect
+///
+/// Opens a session for the token's user, on the same terms as a
+/// WebSocket upgrade.
+pub async fn connect(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ConnectQuery>,
+    headers: HeaderMap,
+    Extension(CorrelationId(correlation_id)): Extension<CorrelationId>,
+) -> Response {
+    let Some((token, claims, user_id)) = verify_user(&state, query.token, &headers).await else {
+        return (StatusCode::UNAUTHORIZED, "INVALID TOKEN").into_response();
+    };
+    let Some(slot) = state.connections.acquire(&user_id) else {
+        return too_many_connections();
+    };
+    start_sweeping(&state);
+
+    let (inbound, rx) = mpsc::unbounded_channel();
+    let read = Box::pin(stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|msg| (Ok(msg), rx)) }));
+    let outbox = Arc::new(Outbox::default());
+    let write = Box::pin(sink::unfold(outbox.clone(), |outbox, msg: Message| async move {
+        if let Message::Text(text) = msg {
+            outbox.push(text);
+        }
+        Ok::<_, Infallible>(outbox)
+    }));
+
+    let sid = PollSessionId::new();
+    let session = Session { inbound, outbox, last_seen: Mutex::new(Instant::now()) };
+    state.polls.sessions.insert(sid.clone(), Arc::new(session));
+
+    let span = connection_span(&correlation_id, &user_id);
+    let connection = handle_socket((write, read), state.clone(), Some(token), claims, user_id, query.timestamps, correlation_id);
+    tokio::spawn(socket_task(slot, connection.instrument(span)));
+
+    Json(PollSession { sid, idle_timeout_secs: state.polls.idle_timeout.as_secs() }).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    wait: Option<u64>,
+}
+
+/// GET /poll/{sid}?wait=25
+///
+/// The frames queued for the session, waiting up to `wait` seconds (at
+/// most 30) for one when there are none.
+pub async fn poll(
+    State(state): State<Arc<AppState>>,
+    Path(sid): Path<String>,
+    Query(query): Query<PollQuery>,
+) -> Result<Json<PollBatch>, StatusCode> {
+    let session = state.polls.get(&sid).ok_or(StatusCode::NOT_FOUND)?;
+    let wait = Duration::from_secs(query.wait.unwrap_or(DEFAULT_WAIT_SECS).min(MAX_WAIT_SECS));
+    let batch = session.outbox.take(wait).await;
+    *session.last_seen.lock().unwrap() = Instant::now();
+    Ok(Json(batch))
+}
+
+/// POST /poll/{sid}/send
+///
+/// Hands one frame, as a socket would send it, to the session.
+pub async fn send(State(state): State<Arc<AppState>>, Path(sid): Path<String>, body: String) -> StatusCode {
+    let Some(session) = state.polls.get(&sid) else { return StatusCode::NOT_FOUND };
+    if asks_for_msgpack(&body) {
+        return StatusCode::BAD_REQUEST;
+    }
+    match session.inbound.send(Message::Text(body)) {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(_) => StatusCode::GONE,
+    }
+}
+
+/// Whether `frame` is a `Hello` switching to MessagePack, which has no
+/// text form to queue.
+fn asks_for_msgpack(frame: &str) -> bool {
+    matches!(
+        serde_json::from_str::<ClientFrame>(frame),
+        Ok(ClientFrame { event: ClientEvent::Hello { format: Some(SerializationFormat::Msgpack), .. }, .. })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use uchat_proto::events::{ServerEvent, CURRENT_SCHEMA_VERSION};
+    use uchat_proto::ids::{ChannelId, RoomId, UserId};
+    use uchat_proto::jwt::create_token_with_rooms;
+    use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+    async fn call(state: &Arc<AppState>, req: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn frame(event: ClientEvent) -> String {
+        let frame = ClientFrame { schema_version: CURRENT_SCHEMA_VERSION, cid: None, ts_gateway: None, correlation_id: None, event };
+        serde_json::to_string(&frame).unwrap()
+    }
+
+    async fn send(state: &Arc<AppState>, sid: &str, body: String) -> StatusCode {
+        call(state, Request::post(format!("/poll/{}/send", sid)).body(Body::from(body)).unwrap()).await.0
+    }
+
+    /// The next event polled for `sid`.
+    async fn next_event(state: &Arc<AppState>, sid: &str) -> ServerEvent {
+        let (status, batch) = call(state, Request::get(format!("/poll/{}?wait=5", sid)).body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let batch: PollBatch = serde_json::from_value(batch).unwrap();
+        assert_eq!(batch.frames.len(), 1, "{:?}", batch);
+        serde_json::from_str(&batch.frames[0]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_run_frames_like_a_socket() {
+        let state = test_state();
+        let (user, channel) = (UserId::new(), ChannelId::new());
+        let mut rooms = RoomPermissions::new();
+        rooms.grant(channel.as_str(), RoomRole::Write);
+        let token = create_token_with_rooms("test-secret", user.as_str(), rooms);
+
+        let (status, _) = call(&state, Request::post("/poll/connect").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let connect = Request::post("/poll/connect").header("Authorization", format!("Bearer {}", token));
+        let (status, session) = call(&state, connect.body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let session: PollSession = serde_json::from_value(session).unwrap();
+        let sid = session.sid.as_str();
+        assert_eq!(session.idle_timeout_secs, 60);
+
+        assert_eq!(send(&state, sid, frame(ClientEvent::Subscribe { room_id: channel.clone().into() })).await, StatusCode::ACCEPTED);
+        assert!(matches!(next_event(&state, sid).await, ServerEvent::Presence { online: true, .. }));
+
+        let message = ClientEvent::SendMessage {
+            room_id: channel.clone(),
+            content: "<b>hi</b>".into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            thread_id: None,
+        };
+        send(&state, sid, frame(message)).await;
+        match next_event(&state, sid).await {
+            ServerEvent::MessageBroadcast { from, content, .. } => assert_eq!((from, content.as_str()), (user, "hi")),
+            other => panic!("unexpected {:?}", other),
+        }
+        send(&state, sid, "not a frame".into()).await;
+        assert!(matches!(next_event(&state, sid).await, ServerEvent::Error { .. }));
+
+        let msgpack = ClientEvent::Hello { last_seq: None, capabilities: None, format: Some(SerializationFormat::Msgpack) };
+        assert_eq!(send(&state, sid, frame(msgpack)).await, StatusCode::BAD_REQUEST);
+
+        // Idle sessions end and give up their rooms.
+        state.polls.sweep(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(send(&state, sid, frame(ClientEvent::Who { room_id: channel.clone() })).await, StatusCode::NOT_FOUND);
+        let room = RoomId::from(channel);
+        for _ in 0..100 {
+            if !state.rooms.read().await.contains_key(&room) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the expired session kept its room");
+    }
+
+    #[tokio::test]
+    async fn outbox_drops_past_capacity_and_wakes_pollers() {
+        let outbox = Arc::new(Outbox::default());
+        for i in 0..QUEUE_CAPACITY + 3 {
+            outbox.push(i.to_string());
+        }
+        let batch = outbox.take(Duration::ZERO).await;
+        assert_eq!((batch.frames.len(), batch.frames[0].as_str(), batch.dropped), (QUEUE_CAPACITY, "0", 3));
+        assert_eq!(outbox.take(Duration::ZERO).await, PollBatch::default());
+
+        let waiting = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.take(Duration::from_secs(10)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        outbox.push("late".into());
+        assert_eq!(waiting.await.unwrap().frames, vec!["late".to_string()]);
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uchat_proto::ids::{ChannelId, RoomId, UserId};
+
+/// How long a user stays online without a heartbeat, so a crashed
+/// gateway's users drop off on their own.
+pub const PRESENCE_TTL: Duration = Duration::from_secs(30);
+/// How often each socket refreshes its user's presence.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a `Typing` frame keeps its sender listed as typing.
+pub const TYPING_TTL: Duration = Duration::from_secs(5);
+
+const ONLINE_KEY: &str = "presence:users";
+
+fn channel_key(channel_id: &ChannelId) -> String {
+    format!("presence:{}", channel_id)
+}
+
+fn typing_key(room_id: &RoomId) -> String {
+    format!("typing:{}", room_id)
+}
+
+/// Named sets of users whose members expire.
+#[derive(Default)]
+struct LocalSets {
+    sets: HashMap<String, HashMap<UserId, Instant>>,
+}
+
+impl LocalSets {
+    fn touch(&mut self, key: &str, user_id: &UserId, ttl: Duration) {
+        let set = self.sets.entry(key.to_string()).or_default();
+        set.insert(user_id.clone(), Instant::now() + ttl);
+    }
+
+    fn remove(&mut self, key: &str, user_id: &UserId) {
+        if let Some(set) = self.sets.get_mut(key) {
+            set.remove(user_id);
+            if set.is_empty() {
+                self.sets.remove(key);
+            }
+        }
+    }
+
+    fn members(&mut self, key: &str) -> Vec<UserId> {
+        let Some(set) = self.sets.get_mut(key) else { return Vec::new() };
+        let now = Instant::now();
+        set.retain(|_, expires| *expires > now);
+        let mut members: Vec<UserId> = set.keys().cloned().collect();
+        if members.is_empty() {
+            self.sets.remove(key);
+        }
+        members.sort();
+        members
+    }
+}
+
+/// Who is online, per channel and overall, and who is typing where.
+///
+/// This instance's own sockets are always tracked in memory. With Redis
+/// configured the same sets are kept there too, so every gateway instance
+/// sees every user; reads fall back to the local view while Redis is
+/// unreachable rather than failing the frame that asked. Each instance
+/// also records which users it holds sockets for, so one instance losing
+/// a user's last socket doesn't mark them offline while another still has
+/// one.
+pub struct PresenceStore {
+    local: Mutex<LocalSets>,
+    /// Open sockets per user on this instance.
+    sockets: Mutex<HashMap<UserId, usize>>,
+    #[cfg(feature = "redis-presence")]
+    redis: Option<redis_sets::RedisSets>,
+}
+
+impl PresenceStore {
+    /// Presence for this instance's sockets only.
+    pub fn local() -> Self {
+        Self {
+            local: Mutex::new(LocalSets::default()),
+            sockets: Mutex::new(HashMap::new()),
+            #[cfg(feature = "redis-presence")]
+            redis: None,
+        }
+    }
+
+    /// Shares presence through `REDIS_URL` when the `redis-presence`
+    /// feature is on and Redis is reachable; local-only otherwise.
+    pub async fn from_env() -> Self {
+        #[cfg(feature = "redis-presence")]
+        return Self { redis: redis_sets::RedisSets::from_env().await, ..Self::local() };
+        #[cfg(not(feature = "redis-presence"))]
+        Self::local()
+    }
+
+    /// Registers a new socket for `user_id`.
+    pub async fn connect(&self, user_id: &UserId) {
+        *self.sockets.lock().unwrap().entry(user_id.clone()).or_default() += 1;
+        self.heartbeat(user_id, &[]).await;
+    }
+
+    /// Unregisters a socket that was in `channels`. Returns whether it was
+    /// the user's last socket anywhere, in which case they are marked
+    /// offline straight away instead of waiting out the TTL.
+    pub async fn disconnect(&self, user_id: &UserId, channels: &[ChannelId]) -> bool {
+        {
+            let mut sockets = self.sockets.lock().unwrap();
+            match sockets.get_mut(user_id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    return false;
+                }
+                _ => sockets.remove(user_id),
+            };
+        }
+
+        let keys: Vec<String> =
+            std::iter::once(ONLINE_KEY.to_string()).chain(channels.iter().map(channel_key)).collect();
+        {
+            let mut local = self.local.lock().unwrap();
+            for key in &keys {
+                local.remove(key, user_id);
+            }
+        }
+        #[cfg(feature = "redis-presence")]
+        if let Some(redis) = &self.redis {
+            if let Some(offline) = log_err(redis.disconnect(&keys, user_id).await) {
+                return offline;
+            }
+        }
+        true
+    }
+
+    /// Keeps `user_id` online overall and in each of `channels`.
+    pub async fn heartbeat(&self, user_id: &UserId, channels: &[ChannelId]) {
+        let keys: Vec<String> =
+            std::iter::once(ONLINE_KEY.to_string()).chain(channels.iter().map(channel_key)).collect();
+        {
+            let mut local = self.local.lock().unwrap();
+            for key in &keys {
+                local.touch(key, user_id, PRESENCE_TTL);
+            }
+        }
+        #[cfg(feature = "redis-presence")]
+        if let Some(redis) = &self.redis {
+            log_err(redis.heartbeat(&keys, user_id, PRESENCE_TTL).await);
+        }
+    }
+
+    pub async fn typing(&self, room_id: &RoomId, user_id: &UserId) {
+        self.touch(&[typing_key(room_id)], user_id, TYPING_TTL).await;
+    }
+
+    /// `typing` that lapses after `ttl`.
+    #[cfg(test)]
+    pub async fn typing_for(&self, room_id: &RoomId, user_id: &UserId, ttl: Duration) {
+        self.touch(&[typing_key(room_id)], user_id, ttl).await;
+    }
+
+    /// Users online in `channel_id`.
+    pub async fn online_in(&self, channel_id: &ChannelId) -> Vec<UserId> {
+        self.members(&channel_key(channel_id)).await
+    }
+
+    pub async fn typing_in(&self, room_id: &RoomId) -> Vec<UserId> {
+        self.members(&typing_key(room_id)).await
+    }
+
+    /// Which of `user_ids` are online anywhere.
+    pub async fn online(&self, user_ids: &[UserId]) -> Vec<UserId> {
+        #[cfg(feature = "redis-presence")]
+        if let Some(redis) = &self.redis {
+            if let Some(online) = log_err(redis.online(ONLINE_KEY, user_ids).await) {
+                return online;
+            }
+        }
+        let online = self.local.lock().unwrap().members(ONLINE_KEY);
+        user_ids.iter().filter(|id| online.contains(id)).cloned().collect()
+    }
+
+    async fn touch(&self, keys: &[String], user_id: &UserId, ttl: Duration) {
+        {
+            let mut local = self.local.lock().unwrap();
+            for key in keys {
+                local.touch(key, user_id, ttl);
+            }
+        }
+        #[cfg(feature = "redis-presence")]
+        if let Some(redis) = &self.redis {
+            log_err(redis.touch(keys, user_id, ttl).await);
+        }
+    }
+
+    async fn members(&self, key: &str) -> Vec<UserId> {
+        #[cfg(feature = "redis-presence")]
+        if let Some(redis) = &self.redis {
+            if let Some(members) = log_err(redis.members(key).await) {
+                return members;
+            }
+        }
+        self.local.lock().unwrap().members(key)
+    }
+}
+
+#[cfg(feature = "redis-presence")]
+fn log_err<T>(result: redis::RedisResult<T>) -> Option<T> {
+    result.map_err(|e| tracing::warn!(error = %e, "redis presence unavailable, using local state")).ok()
+}
+
+#[cfg(feature = "redis-presence")]
+mod redis_sets {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use redis::aio::{ConnectionLike, MultiplexedConnection};
+
+    use uchat_proto::ids::UserId;
+
+    fn now_ms() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+
+    /// Drops this instance from a user's instances and, if no other live
+    /// instance is left, the user from every presence set. Returns 1 when
+    /// the user went offline.
+    const DISCONNECT_SCRIPT: &str = "\
+        redis.call('ZREM', KEYS[1], ARGV[1]) \
+        redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', ARGV[2]) \
+        if redis.call('ZCARD', KEYS[1]) > 0 then return 0 end \
+        for i = 2, #KEYS do redis.call('ZREM', KEYS[i], ARGV[3]) end \
+        return 1";
+
+    /// The gateway instances holding sockets for `user_id`, scored like
+    /// any other set.
+    fn instances_key(user_id: &UserId) -> String {
+        format!("presence:instances:{}", user_id)
+    }
+
+    /// The Redis side of `PresenceStore`: each set is a sorted set whose
+    /// members are scored by their expiry, trimmed on read. The key
+    /// itself expires once nobody refreshes it.
+    pub struct RedisSets<C = MultiplexedConnection> {
+        conn: C,
+        /// Names this process in the users' instance sets.
+        instance: String,
+    }
+
+    impl RedisSets {
+        /// Connects to `REDIS_URL`, or returns `None` when it is unset or
+        /// unreachable.
+        pub async fn from_env() -> Option<Self> {
+            let url = std::env::var("REDIS_URL").ok().filter(|v| !v.is_empty())?;
+            let conn = match redis::Client::open(url) {
+                Ok(client) => client.get_multiplexed_tokio_connection().await,
+                Err(e) => Err(e),
+            };
+            match conn {
+                Ok(conn) => Some(Self::new(conn)),
+                Err(e) => {
+                    tracing::warn!(error = %e, "redis presence disabled");
+                    None
+                }
+            }
+        }
+    }
+
+    impl<C: ConnectionLike + Clone> RedisSets<C> {
+        pub fn new(conn: C) -> Self {
+            Self::with_instance(conn, uuid::Uuid::new_v4().to_string())
+        }
+
+        pub fn with_instance(conn: C, instance: String) -> Self {
+            Self { conn, instance }
+        }
+
+        pub async fn touch(&self, keys: &[String], user_id: &UserId, ttl: Duration) -> redis::RedisResult<()> {
+            self.touch_at(keys, user_id, ttl, now_ms()).await
+        }
+
+        pub async fn touch_at(&self, keys: &[String], user_id: &UserId, ttl: Duration, now_ms: u64) -> redis::RedisResult<()> {
+            let ttl_ms = ttl.as_millis() as u64;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for key in keys {
+                pipe.cmd("ZADD").arg(key).arg(now_ms + ttl_ms).arg(user_id.as_str()).ignore();
+                pipe.cmd("PEXPIRE").arg(key).arg(ttl_ms).ignore();
+            }
+            pipe.query_async(&mut self.conn.clone()).await
+        }
+
+        /// `touch`, also keeping this instance in the user's instances.
+        pub async fn heartbeat(&self, keys: &[String], user_id: &UserId, ttl: Duration) -> redis::RedisResult<()> {
+            self.heartbeat_at(keys, user_id, ttl, now_ms()).await
+        }
+
+        pub async fn heartbeat_at(&self, keys: &[String], user_id: &UserId, ttl: Duration, now_ms: u64) -> redis::RedisResult<()> {
+            let instances = instances_key(user_id);
+            let ttl_ms = ttl.as_millis() as u64;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for key in keys {
+                pipe.cmd("ZADD").arg(key).arg(now_ms + ttl_ms).arg(user_id.as_str()).ignore();
+                pipe.cmd("PEXPIRE").arg(key).arg(ttl_ms).ignore();
+            }
+            pipe.cmd("ZADD").arg(&instances).arg(now_ms + ttl_ms).arg(&self.instance).ignore();
+            pipe.cmd("PEXPIRE").arg(&instances).arg(ttl_ms).ignore();
+            pipe.query_async(&mut self.conn.clone()).await
+        }
+
+        /// Called when this instance closes the user's last socket here.
+        /// Returns whether no other instance still has one, in which case
+        /// the user is gone from `keys`.
+        pub async fn disconnect(&self, keys: &[String], user_id: &UserId) -> redis::RedisResult<bool> {
+            self.disconnect_at(keys, user_id, now_ms()).await
+        }
+
+        pub async fn disconnect_at(&self, keys: &[String], user_id: &UserId, now_ms: u64) -> redis::RedisResult<bool> {
+            let offline: i64 = redis::cmd("EVAL")
+                .arg(DISCONNECT_SCRIPT)
+                .arg(keys.len() + 1)
+                .arg(instances_key(user_id))
+                .arg(keys)
+                .arg(&self.instance)
+                .arg(now_ms)
+                .arg(user_id.as_str())
+                .query_async(&mut self.conn.clone())
+                .await?;
+            Ok(offline == 1)
+        }
+
+        /// Which of `user_ids` are unexpired members of `key`, looked up
+        /// one by one rather than reading the whole set.
+        pub async fn online(&self, key: &str, user_ids: &[UserId]) -> redis::RedisResult<Vec<UserId>> {
+            self.online_at(key, user_ids, now_ms()).await
+        }
+
+        pub async fn online_at(&self, key: &str, user_ids: &[UserId], now_ms: u64) -> redis::RedisResult<Vec<UserId>> {
+            if user_ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let ids: Vec<&str> = user_ids.iter().map(|id| id.as_str()).collect();
+            let scores: Vec<Option<f64>> =
+                redis::cmd("ZMSCORE").arg(key).arg(&ids).query_async(&mut self.conn.clone()).await?;
+            Ok(user_ids
+                .iter()
+                .zip(scores)
+                .filter(|(_, score)| score.is_some_and(|expires| expires > now_ms as f64))
+                .map(|(id, _)| id.clone())
+                .collect())
+        }
+
+        pub async fn members(&self, key: &str) -> redis::RedisResult<Vec<UserId>> {
+            self.members_at(key, now_ms()).await
+        }
+
+        pub async fn members_at(&self, key: &str, now_ms: u64) -> redis::RedisResult<Vec<UserId>> {
+            let (members,): (Vec<String>,) = redis::pipe()
+                .atomic()
+                .cmd("ZREMRANGEBYSCORE").arg(key).arg("-inf").arg(now_ms).ignore()
+                .cmd("ZRANGE").arg(key).arg(0).arg(-1)
+                .query_async(&mut self.conn.clone())
+                .await?;
+            let mut members: Vec<UserId> = members.iter().filter_map(|m| m.parse().ok()).collect();
+            members.sort();
+            Ok(members)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use redis::Value;
+        use redis_test::{MockCmd, MockRedisConnection};
+
+        #[tokio::test]
+        async fn touch_scores_members_by_expiry() {
+            let user = UserId::new();
+            let keys = vec!["presence:users".to_string(), "presence:c".to_string()];
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for key in &keys {
+                pipe.cmd("ZADD").arg(key).arg(31_000).arg(user.as_str()).ignore();
+                pipe.cmd("PEXPIRE").arg(key).arg(30_000).ignore();
+            }
+            let results = Value::Array(vec![Value::Int(1), Value::Int(1), Value::Int(1), Value::Int(1)]);
+            let sets = RedisSets::new(MockRedisConnection::new(vec![MockCmd::new(pipe, Ok(results))]));
+
+            sets.touch_at(&keys, &user, Duration::from_secs(30), 1_000).await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn heartbeat_records_this_instance() {
+            let user = UserId::new();
+            let keys = vec!["presence:users".to_string()];
+            let instances = format!("presence:instances:{}", user);
+            let pipe = redis::pipe()
+                .atomic()
+                .cmd("ZADD").arg("presence:users").arg(31_000).arg(user.as_str()).ignore()
+                .cmd("PEXPIRE").arg("presence:users").arg(30_000).ignore()
+                .cmd("ZADD").arg(&instances).arg(31_000).arg("gw-a").ignore()
+                .cmd("PEXPIRE").arg(&instances).arg(30_000).ignore()
+                .clone();
+            let results = Value::Array(vec![Value::Int(1); 4]);
+            let conn = MockRedisConnection::new(vec![MockCmd::new(pipe, Ok(results))]);
+            let sets = RedisSets::with_instance(conn, "gw-a".into());
+
+            sets.heartbeat_at(&keys, &user, Duration::from_secs(30), 1_000).await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn disconnect_leaves_users_other_instances_hold() {
+            let user = UserId::new();
+            let keys = vec!["presence:users".to_string(), "presence:c".to_string()];
+            let eval = |result| {
+                let cmd = redis::cmd("EVAL")
+                    .arg(DISCONNECT_SCRIPT)
+                    .arg(3)
+                    .arg(format!("presence:instances:{}", user))
+                    .arg(&keys)
+                    .arg("gw-a")
+                    .arg(5_000)
+                    .arg(user.as_str())
+                    .clone();
+                MockCmd::new(cmd, Ok(Value::Int(result)))
+            };
+            let conn = MockRedisConnection::new(vec![eval(0), eval(1)]);
+            let sets = RedisSets::with_instance(conn, "gw-a".into());
+
+            assert!(!sets.disconnect_at(&keys, &user, 5_000).await.unwrap());
+            assert!(sets.disconnect_at(&keys, &user, 5_000).await.unwrap());
+        }
+
+        #[tokio::test]
+        async fn online_looks_up_only_the_ids_asked_for() {
+            let (fresh, stale, absent) = (UserId::new(), UserId::new(), UserId::new());
+            let cmd = redis::cmd("ZMSCORE")
+                .arg("presence:users")
+                .arg(&[fresh.as_str(), stale.as_str(), absent.as_str()])
+                .clone();
+            let scores = Value::Array(vec![
+                Value::BulkString(b"9000".to_vec()),
+                Value::BulkString(b"4000".to_vec()),
+                Value::Nil,
+            ]);
+            let sets = RedisSets::new(MockRedisConnection::new(vec![MockCmd::new(cmd, Ok(scores))]));
+
+            let online = sets.online_at("presence:users", &[fresh.clone(), stale, absent], 5_000).await.unwrap();
+            assert_eq!(online, vec![fresh]);
+            assert!(sets.online_at("presence:users", &[], 5_000).await.unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn members_trims_expired_first() {
+            let user = UserId::new();
+            let pipe = redis::pipe()
+                .atomic()
+                .cmd("ZREMRANGEBYSCORE").arg("presence:users").arg("-inf").arg(5_000).ignore()
+                .cmd("ZRANGE").arg("presence:users").arg(0).arg(-1)
+                .clone();
+            let members = Value::Array(vec![Value::BulkString(user.as_str().as_bytes().to_vec())]);
+            let results = Value::Array(vec![Value::Int(2), members]);
+            let sets = RedisSets::new(MockRedisConnection::new(vec![MockCmd::new(pipe, Ok(results))]));
+
+            assert_eq!(sets.members_at("presence:users", 5_000).await.unwrap(), vec![user]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn last_socket_takes_the_user_offline() {
+        let presence = PresenceStore::local();
+        let (user, other) = (UserId::new(), UserId::new());
+        let channel = ChannelId::new();
+
+        presence.connect(&user).await;
+        presence.connect(&user).await;
+        presence.heartbeat(&user, std::slice::from_ref(&channel)).await;
+        presence.connect(&other).await;
+        assert_eq!(presence.online_in(&channel).await, vec![user.clone()]);
+        assert_eq!(presence.online(&[user.clone(), other.clone(), UserId::new()]).await.len(), 2);
+
+        assert!(!presence.disconnect(&user, std::slice::from_ref(&channel)).await);
+        assert_eq!(presence.online(std::slice::from_ref(&user)).await, vec![user.clone()]);
+        assert!(presence.disconnect(&user, std::slice::from_ref(&channel)).await);
+        assert!(presence.online(std::slice::from_ref(&user)).await.is_empty());
+        assert!(presence.online_in(&channel).await.is_empty());
+    }
+
+    #[test]
+    fn local_members_expire() {
+        let mut sets = LocalSets::default();
+        let (user, idle) = (UserId::new(), UserId::new());
+        sets.touch("typing:x", &user, Duration::from_secs(60));
+        sets.touch("typing:x", &idle, Duration::ZERO);
+
+        assert_eq!(sets.members("typing:x"), vec![user.clone()]);
+        sets.remove("typing:x", &user);
+        assert!(sets.members("typing:x").is_empty());
+        assert!(sets.sets.is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+//! Sessionless long-polling of one room, for clients whose proxies cut
+//! WebSockets short. `GET /poll?room_id=&since=` (or a `GET /ws` that
+//! isn't an upgrade) answers from the same history buffer sockets replay
+//! from: the room's messages after `since`, waiting up to 20 seconds for
+//! one if there are none yet. With `Accept: application/x-ndjson` the
+//! response instead streams one message per line for the whole window.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::stream;
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+
+use uchat_proto::events::{SequencedMessage, ServerEvent};
+use uchat_proto::ids::RoomId;
+
+use crate::sse::message_seq;
+use crate::{too_many_connections, verify_user, AppState, RoomMessage};
+
+const MAX_WAIT: Duration = Duration::from_secs(20);
+const NDJSON: &str = "application/x-ndjson";
+/// Set on streamed responses when messages after `since` were lost; the
+/// client should reload the room's history from channels-api.
+const TRUNCATED_HEADER: &str = "x-history-truncated";
+
+#[derive(Deserialize)]
+pub struct RoomPollQuery {
+    pub room_id: String,
+    /// The last sequence number the client has; everything in the buffer
+    /// when left out.
+    #[serde(default)]
+    pub since: u64,
+    pub token: Option<String>,
+}
+
+/// GET /poll?room_id={id}&since={seq}
+///
+/// Answers with a `MessageBatch`, or a stream of `SequencedMessage` lines
+/// for `Accept: application/x-ndjson`. The token goes in
+/// `Authorization: Bearer` or `?token=`.
+pub async fn poll_room(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RoomPollQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((_, claims, user_id)) = verify_user(&state, query.token, &headers).await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(room_id) = query.room_id.parse::<RoomId>() else {
+        return (StatusCode::BAD_REQUEST, "INVALID ROOM").into_response();
+    };
+    if claims.rooms.role_for(room_id.channel.as_str()).is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(slot) = state.connections.acquire(&user_id) else {
+        return too_many_connections();
+    };
+
+    // Subscribed before reading the history, so no message falls between
+    // the two.
+    let mut rx = state.room(&room_id).await.subscribe();
+    let deadline = Instant::now() + MAX_WAIT;
+    let (mut messages, truncated) = since(&state, &room_id, query.since);
+
+    if !accepts_ndjson(&headers) {
+        while messages.is_empty() && !truncated {
+            if !matches!(tokio::time::timeout_at(deadline, next_message(&mut rx)).await, Ok(true)) {
+                break;
+            }
+            // A newer message can't have been lost, since the buffer only
+            // drops its oldest.
+            messages = since(&state, &room_id, query.since).0;
+        }
+        drop((rx, slot));
+        state.cleanup_room(&room_id).await;
+        return Json(ServerEvent::MessageBatch { messages, truncated }).into_response();
+    }
+
+    let (tx, lines) = mpsc::channel(64);
+    let state = state.clone();
+    uchat_metrics::spawn_task("room_poll", async move {
+        let mut last_seq = query.since;
+        let mut membership = state.membership.subscribe();
+        'stream: loop {
+            for message in messages.drain(..) {
+                last_seq = message.seq;
+                let Ok(mut line) = serde_json::to_string(&message) else { continue };
+                line.push('\n');
+                if tx.send(line).await.is_err() {
+                    break 'stream;
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => break,
+                _ = tx.closed() => break,
+                change = membership.recv() => match change {
+                    Ok(change) if change.user_id == user_id && change.channel_id == room_id.channel && change.role.is_none() => break,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    _ => continue,
+                },
+                more = next_message(&mut rx) => {
+                    if !more {
+                        break;
+                    }
+                    messages = since(&state, &room_id, last_seq).0;
+                }
+            }
+        }
+        drop((rx, slot));
+        state.cleanup_room(&room_id).await;
+    });
+
+    let lines = stream::unfold(lines, |mut lines| async move {
+        lines.recv().await.map(|line| (Ok::<_, Infallible>(line), lines))
+    });
+    let mut response = Body::from_stream(lines).into_response();
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(NDJSON));
+    if truncated {
+        response.headers_mut().insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// The room's messages after `last_seq`, and whether any were lost.
+fn since(state: &AppState, room_id: &RoomId, last_seq: u64) -> (Vec<SequencedMessage>, bool) {
+    let (mut messages, truncated) = state.history.since(last_seq, |c| *c == room_id.channel);
+    messages.retain(|m| m.room_id == *room_id);
+    (messages, truncated)
+}
+
+/// Waits for a client message in the room; `false` once the room is gone.
+async fn next_message(rx: &mut broadcast::Receiver<RoomMessage>) -> bool {
+    loop {
+        match rx.recv().await {
+            Ok(message) if message.as_json().and_then(message_seq).is_some() => return true,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return false,
+        }
+    }
+}
+
+fn accepts_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|t| t.split(';').next().unwrap_or_default().trim() == NDJSON))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_state, OutgoingMessage};
+    use axum::body::BodyDataStream;
+    use axum::http::Request;
+    use futures_util::StreamExt;
+    use tower::ServiceExt;
+    use uchat_proto::ids::{ChannelId, MessageId, UserId};
+    use uchat_proto::jwt::create_token_with_rooms;
+    use uchat_proto::messages::{MessageDeleted, MessageEdited, MessagesExpired};
+    use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+    fn token_for(channel: &ChannelId) -> String {
+        let mut rooms = RoomPermissions::new();
+        rooms.grant(channel.as_str(), RoomRole::Read);
+        create_token_with_rooms("test-secret", UserId::new().as_str(), rooms)
+    }
+
+    async fn send(state: &Arc<AppState>, channel: &ChannelId, content: &str) -> u64 {
+        stored(state, channel, content, None).await
+    }
+
+    async fn stored(state: &Arc<AppState>, channel: &ChannelId, content: &str, message_id: Option<MessageId>) -> u64 {
+        let message = OutgoingMessage {
+            room_id: channel.clone(),
+            thread_id: None,
+            content: content.into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            bridged_from: None,
+            sender_name: None,
+            message_id,
+            sender_role: None,
+            sender_joined: None,
+        };
+        state.send_message(&UserId::new(), None, message, std::time::Instant::now()).await.unwrap().unwrap().1
+    }
+
+    async fn get(state: &Arc<AppState>, uri: String, token: &str, accept: Option<&str>) -> Response {
+        let mut req = Request::get(uri).header("Authorization", format!("Bearer {}", token));
+        if let Some(accept) = accept {
+            req = req.header("Accept", accept);
+        }
+        app(state.clone()).oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    async fn batch(resp: Response) -> Vec<String> {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        match serde_json::from_slice(&body).unwrap() {
+            ServerEvent::MessageBatch { messages, truncated: false } => messages.into_iter().map(|m| m.content).collect(),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_from_history_or_waits_for_the_next_message() {
+        let state = test_state();
+        let channel = ChannelId::new();
+        let token = token_for(&channel);
+        let uri = |since: u64| format!("/poll?room_id={}&since={}", channel, since);
+
+        let first = send(&state, &channel, "first").await;
+        send(&state, &ChannelId::new(), "elsewhere").await;
+        assert_eq!(get(&state, uri(first - 1), "not-a-token", None).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&state, uri(first - 1), &token_for(&ChannelId::new()), None).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(batch(get(&state, uri(first - 1), &token, None).await).await, ["first"]);
+
+        // Nothing newer yet, so the poll waits for the next message.
+        let since = send(&state, &ChannelId::new(), "elsewhere").await;
+        let waiting = tokio::spawn({
+            let (state, uri, token) = (state.clone(), uri(since), token.clone());
+            async move { get(&state, uri, &token, None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        send(&state, &channel, "second").await;
+        assert_eq!(batch(waiting.await.unwrap()).await, ["second"]);
+
+        // A GET /ws without an upgrade is answered the same way.
+        let ws = format!("/ws?room_id={}&since={}", channel, first - 1);
+        assert_eq!(batch(get(&state, ws, &token, None).await).await, ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn forgets_deleted_messages() {
+        let state = test_state();
+        let channel = ChannelId::new();
+        let token = token_for(&channel);
+        let (gone, edited, expired) = (MessageId::new(), MessageId::new(), MessageId::new());
+        let first = stored(&state, &channel, "deleted", Some(gone.clone())).await;
+        stored(&state, &channel, "typo", Some(edited.clone())).await;
+        stored(&state, &channel, "old", Some(expired.clone())).await;
+        send(&state, &channel, "kept").await;
+
+        let internal = |path: &str, body: serde_json::Value| {
+            let req = Request::post(path)
+                .header("x-internal-token", "internal-secret")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            app(state.clone()).oneshot(req)
+        };
+        let deleted = MessageDeleted { id: gone, channel_id: channel.clone() };
+        let resp = internal("/internal/message-deleted", serde_json::to_value(deleted).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let edit = MessageEdited { id: edited, channel_id: channel.clone(), content: "fixed".into(), edited_at: chrono::Utc::now() };
+        internal("/internal/message-edited", serde_json::to_value(edit).unwrap()).await.unwrap();
+        let expiry = MessagesExpired { channel_id: channel.clone(), message_ids: vec![expired] };
+        internal("/internal/messages-expired", serde_json::to_value(expiry).unwrap()).await.unwrap();
+
+        let uri = format!("/poll?room_id={}&since={}", channel, first - 1);
+        assert_eq!(batch(get(&state, uri, &token, None).await).await, ["fixed", "kept"]);
+    }
+
+    /// The content of the next streamed message.
+    async fn next_line(body: &mut BodyDataStream) -> String {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+        let line = std::str::from_utf8(&chunk).unwrap().strip_suffix('\n').unwrap();
+        serde_json::from_str::<SequencedMessage>(line).unwrap().content
+    }
+
+    #[tokio::test]
+    async fn streams_newline_delimited_json() {
+        let state = test_state();
+        let channel = ChannelId::new();
+        let first = send(&state, &channel, "first").await;
+
+        let uri = format!("/poll?room_id={}&since={}", channel, first - 1);
+        let resp = get(&state, uri, &token_for(&channel), Some("application/json, application/x-ndjson")).await;
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], NDJSON);
+        assert!(resp.headers().get(TRUNCATED_HEADER).is_none());
+        let mut body = resp.into_body().into_data_stream();
+        assert_eq!(next_line(&mut body).await, "first");
+        send(&state, &channel, "second").await;
+        assert_eq!(next_line(&mut body).await, "second");
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use ammonia::Builder;
+
+/// Tags that markdown renders to and that carry no script or layout
+/// tricks. Links keep only `href` and get `rel="noopener noreferrer"`.
+const MARKDOWN_TAGS: &[&str] = &[
+    "a", "b", "blockquote", "br", "code", "del", "em", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "i", "li",
+    "ol", "p", "pre", "s", "strong", "ul",
+];
+
+static PLAIN: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::empty();
+    builder.clean_content_tags(HashSet::from(["script", "style"]));
+    builder
+});
+
+static MARKDOWN: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::default();
+    builder
+        .tags(MARKDOWN_TAGS.iter().copied().collect())
+        .tag_attributes(Default::default())
+        .generic_attributes(Default::default())
+        .add_tag_attributes("a", ["href"]);
+    builder
+});
+
+/// Strips every HTML tag from `text`, keeping the text between them.
+/// `script` and `style` lose their content too. The result is safe to
+/// drop into HTML as-is, so `&`, `<` and `>` come out as entities.
+pub fn sanitize_content(text: &str) -> String {
+    PLAIN.clean(text).to_string()
+}
+
+/// Like `sanitize_content`, but keeps the tags markdown formatting
+/// produces, for channels with `allow_markdown_formatting`.
+pub fn sanitize_markdown(text: &str) -> String {
+    MARKDOWN.clean(text).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_tags_but_keeps_text() {
+        assert_eq!(sanitize_content("hello <b>world</b>"), "hello world");
+        assert_eq!(sanitize_content("<img src=x onerror=alert(1)>hi"), "hi");
+        assert_eq!(sanitize_content("<script>alert(1)</script>ok"), "ok");
+        assert_eq!(sanitize_content("<a href=\"javascript:x\">link</a>"), "link");
+        assert_eq!(sanitize_content("1 < 2 & 3"), "1 &lt; 2 &amp; 3");
+    }
+
+    #[test]
+    fn markdown_keeps_formatting_tags_only() {
+        assert_eq!(sanitize_markdown("<strong>bold</strong> <em>it</em>"), "<strong>bold</strong> <em>it</em>");
+        assert_eq!(sanitize_markdown("<p onclick=\"x()\">para</p>"), "<p>para</p>");
+        assert_eq!(sanitize_markdown("<iframe src=x></iframe><code>a</code>"), "<code>a</code>");
+        assert_eq!(
+            sanitize_markdown("<a href=\"https://example.com\" style=\"x\">l</a>"),
+            "<a href=\"https://example.com\" rel=\"noopener noreferrer\">l</a>"
+        );
+        assert_eq!(sanitize_markdown("<a href=\"javascript:alert(1)\">l</a>"), "<a rel=\"noopener noreferrer\">l</a>");
+    }
+}
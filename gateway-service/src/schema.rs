@@ -0,0 +1,72 @@
+use serde::de::Error as _;
+use serde_json::{json, Value};
+
+use uchat_proto::events::{ClientFrame, CURRENT_SCHEMA_VERSION};
+
+/// Parses a frame from a client that predates `schema_version`.
+///
+/// v0 frames carry no version and their `SendMessage` has no `encrypted`
+/// or `content_type`; those are filled in as a plaintext `text/plain`
+/// message outside any thread. Frames that state a version are not v0
+/// and are rejected, as is anything that still fails to parse once
+/// upgraded.
+pub fn migrate_message_v0_to_v1(raw_json: &str) -> Result<ClientFrame, serde_json::Error> {
+    let mut value: Value = serde_json::from_str(raw_json)?;
+    let Some(frame) = value.as_object_mut() else {
+        return Err(serde_json::Error::custom("frame must be a JSON object"));
+    };
+    if frame.get("schema_version").is_some_and(|v| *v != json!(0)) {
+        return Err(serde_json::Error::custom("not a v0 frame"));
+    }
+    frame.insert("schema_version".into(), json!(CURRENT_SCHEMA_VERSION));
+
+    if let Some(Value::Object(message)) = frame.get_mut("SendMessage") {
+        message.entry("encrypted").or_insert(json!(false));
+        message.entry("content_type").or_insert(json!("text/plain"));
+        message.entry("thread_id").or_insert(Value::Null);
+    }
+    serde_json::from_value(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uchat_proto::events::ClientEvent;
+    use uchat_proto::ids::ChannelId;
+
+    #[test]
+    fn upgrades_v0_send_message() {
+        let room = ChannelId::new();
+        let raw = format!(r#"{{"cid":"c-1","SendMessage":{{"room_id":"{}","content":"hi"}}}}"#, room);
+
+        let frame = migrate_message_v0_to_v1(&raw).unwrap();
+        assert_eq!(frame.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(frame.cid.as_deref(), Some("c-1"));
+        match frame.event {
+            ClientEvent::SendMessage { room_id, content, encrypted, content_type, thread_id } => {
+                assert_eq!(room_id, room);
+                assert_eq!(content, "hi");
+                assert!(!encrypted);
+                assert_eq!(content_type, "text/plain");
+                assert!(thread_id.is_none());
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn upgrades_other_v0_events() {
+        let raw = format!(r#"{{"schema_version":0,"Subscribe":{{"room_id":"{}"}}}}"#, ChannelId::new());
+        let frame = migrate_message_v0_to_v1(&raw).unwrap();
+        assert!(matches!(frame.event, ClientEvent::Subscribe { .. }));
+    }
+
+    #[test]
+    fn rejects_versioned_and_malformed_frames() {
+        let room = ChannelId::new();
+        let v1 = format!(r#"{{"schema_version":1,"SendMessage":{{"room_id":"{}","content":"hi"}}}}"#, room);
+        assert!(migrate_message_v0_to_v1(&v1).is_err());
+        assert!(migrate_message_v0_to_v1(r#"["SendMessage"]"#).is_err());
+        assert!(migrate_message_v0_to_v1(r#"{"SendMessage":{"content":"hi"}}"#).is_err());
+    }
+}
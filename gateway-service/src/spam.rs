@@ -0,0 +1,428 @@
+//! Flood and spam detection. Each user's messages from the last few
+//! minutes are kept as fingerprints; posting the same text to several
+//! channels, several messages that are mostly links, or a burst of
+//! messages from a new account shadow-throttles the user. Their messages
+//! are then echoed back to them but held from everyone else until a
+//! moderator clears or confirms them. Every message sent through the
+//! gateway is checked: sockets' frames, gRPC, channels-api's posted
+//! messages and the bridges.
+//!
+//! Fingerprints and holds live in this instance's memory, like the rest of
+//! the gateway's per-user state. With several instances each judges only
+//! the messages that reach it, so a user spreading theirs across
+//! instances gets that many times the thresholds, a hold only stops what
+//! goes through the instance that made it, and restarts forget both.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::jwt::{ADMIN_ROLE, BRIDGE_ROLE};
+use uchat_proto::moderation::{HeldUser, SpamDecision, SpamReason};
+
+use crate::OutgoingMessage;
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(300);
+const DEFAULT_DUPLICATE_CHANNELS: usize = 3;
+const DEFAULT_LINK_MESSAGES: usize = 3;
+const DEFAULT_NEW_ACCOUNT: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_BURST: usize = 8;
+/// The burst check counts messages over this long.
+const BURST_WINDOW: Duration = Duration::from_secs(10);
+/// Shorter texts, such as "thanks!", are too common to count as
+/// duplicates.
+const MIN_FINGERPRINT_LEN: usize = 16;
+/// Most messages held per user; the oldest are dropped first.
+const MAX_HELD: usize = 100;
+/// Past this many users, those who went quiet are forgotten.
+const MAX_TRACKED: usize = 100_000;
+
+pub struct SpamConfig {
+    /// How long messages count towards the duplicate and link checks.
+    pub window: Duration,
+    /// Channels the same text may go to within the window; 0 turns the
+    /// check off.
+    pub duplicate_channels: usize,
+    /// Mostly-link messages allowed within the window; 0 turns the check
+    /// off.
+    pub link_messages: usize,
+    /// Accounts younger than this get the burst check.
+    pub new_account: Duration,
+    /// Messages a new account may send in `BURST_WINDOW`; 0 turns the
+    /// check off.
+    pub burst: usize,
+    /// Holders of these token roles are never checked.
+    pub exempt_roles: Vec<String>,
+    /// Channel that hears about each new hold.
+    pub moderation_room: Option<ChannelId>,
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_WINDOW,
+            duplicate_channels: DEFAULT_DUPLICATE_CHANNELS,
+            link_messages: DEFAULT_LINK_MESSAGES,
+            new_account: DEFAULT_NEW_ACCOUNT,
+            burst: DEFAULT_BURST,
+            exempt_roles: vec![ADMIN_ROLE.into(), "verified".into(), BRIDGE_ROLE.into()],
+            moderation_room: None,
+        }
+    }
+}
+
+impl SpamConfig {
+    /// Thresholds from `GATEWAY_SPAM_WINDOW_SECS` (default 300),
+    /// `GATEWAY_SPAM_DUPLICATE_CHANNELS` (3), `GATEWAY_SPAM_LINK_MESSAGES`
+    /// (3), `GATEWAY_SPAM_NEW_ACCOUNT_HOURS` (24) and `GATEWAY_SPAM_BURST`
+    /// (8), exempt roles from `GATEWAY_SPAM_EXEMPT_ROLES` (comma-separated,
+    /// default `admin,verified,bridge`) and `GATEWAY_MODERATION_ROOM`.
+    pub fn from_env() -> Self {
+        fn number(name: &str) -> Option<u64> {
+            std::env::var(name).ok().and_then(|v| v.parse().ok())
+        }
+
+        let defaults = Self::default();
+        Self {
+            window: number("GATEWAY_SPAM_WINDOW_SECS").map(Duration::from_secs).unwrap_or(defaults.window),
+            duplicate_channels: number("GATEWAY_SPAM_DUPLICATE_CHANNELS")
+                .map(|n| n as usize)
+                .unwrap_or(defaults.duplicate_channels),
+            link_messages: number("GATEWAY_SPAM_LINK_MESSAGES").map(|n| n as usize).unwrap_or(defaults.link_messages),
+            new_account: number("GATEWAY_SPAM_NEW_ACCOUNT_HOURS")
+                .map(|h| Duration::from_secs(h * 60 * 60))
+                .unwrap_or(defaults.new_account),
+            burst: number("GATEWAY_SPAM_BURST").map(|n| n as usize).unwrap_or(defaults.burst),
+            exempt_roles: std::env::var("GATEWAY_SPAM_EXEMPT_ROLES")
+                .map(|v| v.split(',').map(str::trim).filter(|r| !r.is_empty()).map(str::to_string).collect())
+                .unwrap_or(defaults.exempt_roles),
+            moderation_room: std::env::var("GATEWAY_MODERATION_ROOM").ok().and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+/// What to do with a message.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Deliver,
+    /// The sender is already held.
+    Hold,
+    /// This message tripped a check: the sender is held from now on.
+    Flag(Vec<SpamReason>),
+}
+
+struct Sent {
+    at: Instant,
+    channel_id: ChannelId,
+    fingerprint: Option<u64>,
+    link_heavy: bool,
+}
+
+struct Hold {
+    reasons: Vec<SpamReason>,
+    since: DateTime<Utc>,
+    messages: VecDeque<OutgoingMessage>,
+    confirmed: bool,
+}
+
+#[derive(Default)]
+pub struct SpamGuard {
+    config: SpamConfig,
+    recent: DashMap<UserId, VecDeque<Sent>>,
+    holds: DashMap<UserId, Hold>,
+}
+
+impl SpamGuard {
+    pub fn new(config: SpamConfig) -> Self {
+        Self { config, recent: DashMap::new(), holds: DashMap::new() }
+    }
+
+    pub fn moderation_room(&self) -> Option<&ChannelId> {
+        self.config.moderation_room.as_ref()
+    }
+
+    /// Records `message` from `user_id` and judges it. Exemptions and the
+    /// new-account check go by the sender's `role` and `joined` claims, so
+    /// senders without a token are never exempt nor new.
+    pub fn check(&self, user_id: &UserId, message: &OutgoingMessage, now: Instant) -> Verdict {
+        if message.sender_role.as_ref().is_some_and(|role| self.config.exempt_roles.contains(role)) {
+            return Verdict::Deliver;
+        }
+        if self.holds.contains_key(user_id) {
+            return Verdict::Hold;
+        }
+        if self.recent.len() > MAX_TRACKED {
+            self.recent.retain(|_, sent| sent.back().is_some_and(|last| now.duration_since(last.at) < self.config.window));
+        }
+
+        // Ciphertext says nothing about what was written.
+        let (fingerprint, link_heavy) = if message.encrypted {
+            (None, false)
+        } else {
+            (fingerprint(&message.content), is_link_heavy(&message.content))
+        };
+        let reasons = {
+            let mut recent = self.recent.entry(user_id.clone()).or_default();
+            while recent.front().is_some_and(|sent| now.duration_since(sent.at) >= self.config.window) {
+                recent.pop_front();
+            }
+            recent.push_back(Sent { at: now, channel_id: message.room_id.clone(), fingerprint, link_heavy });
+            self.tripped(&recent, message.sender_joined, now)
+        };
+        if reasons.is_empty() {
+            return Verdict::Deliver;
+        }
+
+        self.recent.remove(user_id);
+        let hold = Hold { reasons: reasons.clone(), since: Utc::now(), messages: VecDeque::new(), confirmed: false };
+        self.holds.insert(user_id.clone(), hold);
+        Verdict::Flag(reasons)
+    }
+
+    fn tripped(&self, recent: &VecDeque<Sent>, joined: Option<i64>, now: Instant) -> Vec<SpamReason> {
+        let mut reasons = Vec::new();
+        let last = recent.back().expect("the message being checked");
+
+        if let (Some(fingerprint), 1..) = (last.fingerprint, self.config.duplicate_channels) {
+            let channels: HashSet<&ChannelId> = recent
+                .iter()
+                .filter(|sent| sent.fingerprint == Some(fingerprint))
+                .map(|sent| &sent.channel_id)
+                .collect();
+            if channels.len() >= self.config.duplicate_channels {
+                reasons.push(SpamReason::Duplicates);
+            }
+        }
+
+        let link_heavy = recent.iter().filter(|sent| sent.link_heavy).count();
+        if self.config.link_messages > 0 && last.link_heavy && link_heavy >= self.config.link_messages {
+            reasons.push(SpamReason::LinkDensity);
+        }
+
+        // Tokens without `joined` aren't from auth-api's login, so they
+        // never count as new accounts.
+        let age = joined.map(|joined| (Utc::now().timestamp() - joined).max(0) as u64);
+        if self.config.burst > 0 && age.is_some_and(|age| age < self.config.new_account.as_secs()) {
+            let burst = recent.iter().filter(|sent| now.duration_since(sent.at) < BURST_WINDOW).count();
+            if burst > self.config.burst {
+                reasons.push(SpamReason::NewAccountBurst);
+            }
+        }
+        reasons
+    }
+
+    /// Keeps `message` from a held user for a decision.
+    pub fn hold(&self, user_id: &UserId, message: OutgoingMessage) {
+        if let Some(mut hold) = self.holds.get_mut(user_id) {
+            if hold.messages.len() >= MAX_HELD {
+                hold.messages.pop_front();
+            }
+            hold.messages.push_back(message);
+        }
+    }
+
+    /// Held users, longest held first.
+    pub fn held(&self) -> Vec<HeldUser> {
+        let mut held: Vec<HeldUser> = self
+            .holds
+            .iter()
+            .map(|entry| HeldUser {
+                user_id: entry.key().clone(),
+                reasons: entry.reasons.clone(),
+                since: entry.since,
+                held: entry.messages.len() as u32,
+                confirmed: entry.confirmed,
+            })
+            .collect();
+        held.sort_by_key(|user| user.since);
+        held
+    }
+
+    /// Applies a moderator's decision, returning the user's held
+    /// messages: to send on when cleared, to discard when confirmed.
+    /// `None` when the user isn't held.
+    pub fn decide(&self, user_id: &UserId, decision: SpamDecision) -> Option<Vec<OutgoingMessage>> {
+        match decision {
+            SpamDecision::Clear => self.holds.remove(user_id).map(|(_, hold)| hold.messages.into()),
+            SpamDecision::Confirm => {
+                let mut hold = self.holds.get_mut(user_id)?;
+                hold.confirmed = true;
+                Some(hold.messages.drain(..).collect())
+            }
+        }
+    }
+}
+
+/// A hash of `content` that ignores case, punctuation and spacing, or
+/// `None` when there is too little text to tell.
+fn fingerprint(content: &str) -> Option<u64> {
+    let normalized: String = content
+        .split_whitespace()
+        .map(|word| word.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect::<String>())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if normalized.len() < MIN_FINGERPRINT_LEN {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    normalized.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Whether at least half of the message's words, and at least two, are
+/// links.
+fn is_link_heavy(content: &str) -> bool {
+    let (mut words, mut links) = (0, 0);
+    for word in content.split_whitespace() {
+        words += 1;
+        let word = word.to_ascii_lowercase();
+        if ["http://", "https://", "www."].iter().any(|prefix| word.starts_with(prefix)) {
+            links += 1;
+        }
+    }
+    links >= 2 && links * 2 >= words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uchat_proto::jwt::Claims;
+
+    fn claims(role: Option<&str>, joined: Option<DateTime<Utc>>) -> Claims {
+        Claims {
+            sub: UserId::new().to_string(),
+            exp: usize::MAX,
+            rooms: Default::default(),
+            bot: false,
+            role: role.map(str::to_string),
+            joined: joined.map(|at| at.timestamp()),
+        }
+    }
+
+    fn message(content: &str) -> OutgoingMessage {
+        OutgoingMessage {
+            room_id: ChannelId::new(),
+            thread_id: None,
+            content: content.into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: None,
+            sender_joined: None,
+        }
+    }
+
+    /// A message to `channel_id` from the holder of `claims`.
+    fn sent(claims: &Claims, channel_id: &ChannelId, content: &str, encrypted: bool) -> OutgoingMessage {
+        OutgoingMessage {
+            room_id: channel_id.clone(),
+            encrypted,
+            sender_role: claims.role.clone(),
+            sender_joined: claims.joined,
+            ..message(content)
+        }
+    }
+
+    #[test]
+    fn flags_the_same_text_across_channels() {
+        let guard = SpamGuard::default();
+        let (user, user_id, now) = (claims(None, None), UserId::new(), Instant::now());
+        let spam = "Free crypto at example dot com, hurry!";
+
+        // Variations in case and punctuation don't make a new message.
+        let first = ChannelId::new();
+        assert_eq!(guard.check(&user_id, &sent(&user, &first, spam, false), now), Verdict::Deliver);
+        assert_eq!(guard.check(&user_id, &sent(&user, &first, "FREE crypto at example dot com... hurry", false), now), Verdict::Deliver);
+        assert_eq!(guard.check(&user_id, &sent(&user, &ChannelId::new(), spam, false), now), Verdict::Deliver);
+        // Short replies are everywhere.
+        for _ in 0..3 {
+            assert_eq!(guard.check(&user_id, &sent(&user, &ChannelId::new(), "thanks!", false), now), Verdict::Deliver);
+        }
+        // Once the first copies are old enough, they no longer count.
+        let later = now + DEFAULT_WINDOW;
+        for _ in 0..2 {
+            assert_eq!(guard.check(&user_id, &sent(&user, &ChannelId::new(), spam, false), later), Verdict::Deliver);
+        }
+
+        let third = ChannelId::new();
+        assert_eq!(guard.check(&user_id, &sent(&user, &third, spam, false), later), Verdict::Flag(vec![SpamReason::Duplicates]));
+        assert_eq!(guard.check(&user_id, &sent(&user, &third, "hello?", false), later), Verdict::Hold);
+    }
+
+    #[test]
+    fn flags_link_floods_and_bursts_from_new_accounts() {
+        let guard = SpamGuard::default();
+        let (channel_id, now) = (ChannelId::new(), Instant::now());
+
+        let user = claims(None, None);
+        let user_id = UserId::new();
+        let links = "https://a.example https://b.example look";
+        assert_eq!(guard.check(&user_id, &sent(&user, &channel_id, "see https://docs.example for details", false), now), Verdict::Deliver);
+        assert_eq!(guard.check(&user_id, &sent(&user, &channel_id, links, false), now), Verdict::Deliver);
+        assert_eq!(guard.check(&user_id, &sent(&user, &channel_id, &format!("{} again", links), false), now), Verdict::Deliver);
+        assert_eq!(
+            guard.check(&user_id, &sent(&user, &channel_id, &format!("and {}", links), false), now),
+            Verdict::Flag(vec![SpamReason::LinkDensity])
+        );
+
+        // The same burst is fine from an established account.
+        let (newcomer, old_timer) = (claims(None, Some(Utc::now())), claims(None, Some(Utc::now() - chrono::Duration::days(30))));
+        let (newcomer_id, old_timer_id) = (UserId::new(), UserId::new());
+        for i in 0..DEFAULT_BURST {
+            let content = format!("message number {}", i);
+            assert_eq!(guard.check(&newcomer_id, &sent(&newcomer, &channel_id, &content, true), now), Verdict::Deliver);
+            assert_eq!(guard.check(&old_timer_id, &sent(&old_timer, &channel_id, &content, false), now), Verdict::Deliver);
+        }
+        assert_eq!(guard.check(&old_timer_id, &sent(&old_timer, &channel_id, "one more", false), now), Verdict::Deliver);
+        assert_eq!(
+            guard.check(&newcomer_id, &sent(&newcomer, &channel_id, "one more", true), now),
+            Verdict::Flag(vec![SpamReason::NewAccountBurst])
+        );
+    }
+
+    #[test]
+    fn exempt_roles_are_never_held() {
+        let guard = SpamGuard::default();
+        let now = Instant::now();
+        for role in [ADMIN_ROLE, "verified", BRIDGE_ROLE] {
+            let (user, user_id) = (claims(Some(role), Some(Utc::now())), UserId::new());
+            for _ in 0..20 {
+                let verdict = guard.check(&user_id, &sent(&user, &ChannelId::new(), "https://a.example https://b.example", false), now);
+                assert_eq!(verdict, Verdict::Deliver);
+            }
+        }
+        assert!(guard.held().is_empty());
+    }
+
+    #[test]
+    fn decisions_release_or_discard_held_messages() {
+        let guard = SpamGuard::new(SpamConfig { duplicate_channels: 1, ..SpamConfig::default() });
+        let (user, user_id, now) = (claims(None, None), UserId::new(), Instant::now());
+        let spam = "buy followers cheap, dm me now";
+        assert!(matches!(guard.check(&user_id, &sent(&user, &ChannelId::new(), spam, false), now), Verdict::Flag(_)));
+        guard.hold(&user_id, message(spam));
+        guard.hold(&user_id, message("second"));
+        guard.hold(&UserId::new(), message("not held, so not kept"));
+
+        let held = guard.held();
+        assert_eq!((held.len(), held[0].held, held[0].confirmed), (1, 2, false));
+        assert_eq!(guard.decide(&user_id, SpamDecision::Confirm).unwrap().len(), 2);
+        // Confirmed users stay held.
+        assert_eq!(guard.check(&user_id, &sent(&user, &ChannelId::new(), "hi", false), now), Verdict::Hold);
+        guard.hold(&user_id, message("third"));
+        assert_eq!((guard.held()[0].held, guard.held()[0].confirmed), (1, true));
+
+        let released = guard.decide(&user_id, SpamDecision::Clear).unwrap();
+        assert_eq!(released.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["third"]);
+        assert!(guard.held().is_empty());
+        assert!(guard.decide(&user_id, SpamDecision::Clear).is_none());
+    }
+}
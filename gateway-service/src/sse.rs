@@ -0,0 +1,242 @@
+//! Read-only room streams over Server-Sent Events, for dashboards and
+//! integrations that only watch a room. Each event is a frame the room's
+//! sockets get, as JSON. Client messages carry their room sequence number
+//! as the event id, so a reconnect with `Last-Event-ID` replays what was
+//! missed from the history buffer, the way a socket's `Hello` does.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures_util::stream;
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::RoomId;
+
+use crate::{too_many_connections, verify_user, AppState};
+
+/// Comments sent this often keep idle streams from being cut by proxies.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct SseQuery {
+    token: Option<String>,
+}
+
+/// GET /sse/rooms/{room_id}
+///
+/// The token goes in `Authorization: Bearer` or, for `EventSource`,
+/// `?token=`, and must grant access to the room. The stream ends if that
+/// access is revoked.
+pub async fn room_events(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(query): Query<SseQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((_, claims, user_id)) = verify_user(&state, query.token, &headers).await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Ok(room_id) = room_id.parse::<RoomId>() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if claims.rooms.role_for(room_id.channel.as_str()).is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(slot) = state.connections.acquire(&user_id) else {
+        return too_many_connections();
+    };
+
+    // Subscribed before reading the history, so no message falls between
+    // the replay and the live stream.
+    let mut rx = state.room(&room_id).await.subscribe();
+    let mut membership = state.membership.subscribe();
+    let (tx, events) = mpsc::channel(64);
+
+    let last_event_id = headers.get("last-event-id").and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    let mut replayed = 0;
+    if let Some(last_seq) = last_event_id {
+        let (mut messages, truncated) = state.history.since(last_seq, |c| *c == room_id.channel);
+        messages.retain(|m| m.room_id == room_id);
+        replayed = messages.last().map_or(last_seq, |m| m.seq);
+        if !messages.is_empty() || truncated {
+            if let Ok(json) = serde_json::to_string(&ServerEvent::MessageBatch { messages, truncated }) {
+                let _ = tx.try_send(Event::default().id(replayed.to_string()).data(json));
+            }
+        }
+    }
+
+    let state = state.clone();
+    uchat_metrics::spawn_task("sse_room", async move {
+        loop {
+            let message = tokio::select! {
+                _ = tx.closed() => break,
+                change = membership.recv() => match change {
+                    Ok(change) if change.user_id == user_id && change.channel_id == room_id.channel && change.role.is_none() => break,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    _ => continue,
+                },
+                message = rx.recv() => match message {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+            // MessagePack copies of the same frames are skipped.
+            let Some(json) = message.as_json() else { continue };
+            let mut event = Event::default().data(json);
+            if let Some(seq) = message_seq(json) {
+                if seq <= replayed {
+                    continue;
+                }
+                event = event.id(seq.to_string());
+            }
+            if tx.send(event).await.is_err() {
+                break;
+            }
+        }
+        drop((rx, slot));
+        state.cleanup_room(&room_id).await;
+    });
+
+    let events = stream::unfold(events, |mut events| async move {
+        events.recv().await.map(|event| (Ok::<_, Infallible>(event), events))
+    });
+    Sse::new(events).keep_alive(KeepAlive::new().interval(KEEP_ALIVE)).into_response()
+}
+
+/// The sequence number of a client message; other frames have none.
+pub fn message_seq(json: &str) -> Option<u64> {
+    // Skip parsing presence, typing and the rest.
+    if !json.starts_with("{\"MessageBroadcast\"") {
+        return None;
+    }
+    match serde_json::from_str(json).ok()? {
+        ServerEvent::MessageBroadcast { seq, .. } => seq,
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_state, OutgoingMessage};
+    use axum::body::{Body, BodyDataStream};
+    use axum::http::Request;
+    use futures_util::StreamExt;
+    use std::time::Instant;
+    use tower::ServiceExt;
+    use uchat_proto::channels::MembershipChange;
+    use uchat_proto::ids::{ChannelId, UserId};
+    use uchat_proto::jwt::create_token_with_rooms;
+    use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+    fn token_for(user: &UserId, channel: &ChannelId) -> String {
+        let mut rooms = RoomPermissions::new();
+        rooms.grant(channel.as_str(), RoomRole::Read);
+        create_token_with_rooms("test-secret", user.as_str(), rooms)
+    }
+
+    async fn open(state: &Arc<AppState>, channel: &ChannelId, token: &str, last_event_id: Option<u64>) -> Response {
+        let mut req = Request::get(format!("/sse/rooms/{}", channel)).header("Authorization", format!("Bearer {}", token));
+        if let Some(id) = last_event_id {
+            req = req.header("Last-Event-ID", id.to_string());
+        }
+        app(state.clone()).oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    async fn send(state: &Arc<AppState>, channel: &ChannelId, content: &str) -> u64 {
+        let message = OutgoingMessage {
+            room_id: channel.clone(),
+            thread_id: None,
+            content: content.into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: None,
+            sender_joined: None,
+        };
+        state.send_message(&UserId::new(), None, message, Instant::now()).await.unwrap().unwrap().1
+    }
+
+    /// The next event's id and data.
+    async fn next_event(body: &mut BodyDataStream) -> (Option<u64>, ServerEvent) {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+        let text = std::str::from_utf8(&chunk).unwrap();
+        let field = |name: &str| text.lines().find_map(|line| line.strip_prefix(name));
+        (field("id: ").map(|id| id.parse().unwrap()), serde_json::from_str(field("data: ").unwrap()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn streams_the_room_and_replays_from_last_event_id() {
+        let state = test_state();
+        let (user, channel) = (UserId::new(), ChannelId::new());
+        let token = token_for(&user, &channel);
+        assert_eq!(open(&state, &channel, "not-a-token", None).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(open(&state, &ChannelId::new(), &token, None).await.status(), StatusCode::FORBIDDEN);
+
+        let missed = send(&state, &channel, "missed").await;
+        send(&state, &ChannelId::new(), "elsewhere").await;
+        let resp = open(&state, &channel, &token, Some(missed - 1)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut body = resp.into_body().into_data_stream();
+        match next_event(&mut body).await {
+            (Some(id), ServerEvent::MessageBatch { messages, truncated: false }) => {
+                assert_eq!(id, missed);
+                assert_eq!(messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["missed"]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let live = send(&state, &channel, "live").await;
+        match next_event(&mut body).await {
+            (Some(id), ServerEvent::MessageBroadcast { content, .. }) => assert_eq!((id, content.as_str()), (live, "live")),
+            other => panic!("unexpected {:?}", other),
+        }
+        let joined = ServerEvent::Presence { room_id: channel.clone(), user_id: user.clone(), online: true };
+        state.broadcast(&RoomId::from(channel.clone()), serde_json::to_string(&joined).unwrap()).await;
+        assert!(matches!(next_event(&mut body).await, (None, ServerEvent::Presence { online: true, .. })));
+
+        // Revoking access ends the stream and gives the slot back.
+        let _ = state.membership.send(MembershipChange { channel_id: channel.clone(), user_id: user.clone(), role: None });
+        assert!(tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_count_against_the_connection_cap() {
+        let state = test_state();
+        let (user, channel) = (UserId::new(), ChannelId::new());
+        let token = token_for(&user, &channel);
+        let mut streams = Vec::new();
+        loop {
+            let resp = open(&state, &channel, &token, None).await;
+            if resp.status() == StatusCode::TOO_MANY_REQUESTS {
+                break;
+            }
+            assert_eq!(resp.status(), StatusCode::OK);
+            streams.push(resp);
+        }
+        assert_eq!(streams.len(), 20);
+
+        streams.pop();
+        for _ in 0..100 {
+            if open(&state, &channel, &token, None).await.status() == StatusCode::OK {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("closing a stream did not free its slot");
+    }
+}
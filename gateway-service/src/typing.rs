@@ -0,0 +1,221 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use uchat_proto::ids::{RoomId, UserId};
+
+use crate::channels_client::ChannelsClient;
+use crate::presence::TYPING_TTL;
+use crate::{bearer_token, verify_token, AppState};
+
+/// Polls allowed per token each second.
+const POLLS_PER_SECOND: u32 = 10;
+/// Tokens tracked before idle ones are swept out.
+const MAX_TOKENS: usize = 10_000;
+/// How long a user's name is reused before it is looked up again.
+const NAME_TTL: Duration = Duration::from_secs(60);
+/// Names cached before expired ones are swept out.
+const MAX_NAMES: usize = 10_000;
+
+/// Counts each token's polls in one-second windows.
+#[derive(Default)]
+pub struct PollLimiter {
+    windows: DashMap<String, (Instant, u32)>,
+}
+
+impl PollLimiter {
+    /// Counts a poll by `token`, or returns `false` once it has used up
+    /// the current second.
+    fn allow(&self, token: &str, now: Instant) -> bool {
+        if self.windows.len() >= MAX_TOKENS {
+            self.windows.retain(|_, (start, _)| now.duration_since(*start) < Duration::from_secs(1));
+        }
+        let mut window = self.windows.entry(token.to_string()).or_insert((now, 0));
+        let (start, polls) = window.value_mut();
+        if now.duration_since(*start) >= Duration::from_secs(1) {
+            (*start, *polls) = (now, 0);
+        }
+        *polls += 1;
+        *polls <= POLLS_PER_SECOND
+    }
+}
+
+/// Names of typing users, looked up in channels-api and kept for a
+/// minute, so polls don't each ask again.
+#[derive(Default)]
+pub struct TypingNames {
+    names: DashMap<UserId, (String, Instant)>,
+}
+
+impl TypingNames {
+    /// What to show for each of `user_ids`, in order: their display name,
+    /// else their username. A user channels-api can't tell us about, or
+    /// every user when it isn't configured, shows as their id.
+    async fn resolve(&self, channels: Option<&ChannelsClient>, token: &str, user_ids: &[UserId]) -> Vec<String> {
+        let now = Instant::now();
+        let cached = |id: &UserId| {
+            self.names.get(id).filter(|entry| now.duration_since(entry.1) < NAME_TTL).map(|entry| entry.0.clone())
+        };
+
+        let missing: Vec<UserId> = user_ids.iter().filter(|id| cached(id).is_none()).cloned().collect();
+        if let (Some(channels), false) = (channels, missing.is_empty()) {
+            match channels.profiles(token, &missing).await {
+                Ok(profiles) => {
+                    if self.names.len() >= MAX_NAMES {
+                        self.names.retain(|_, (_, at)| now.duration_since(*at) < NAME_TTL);
+                    }
+                    for profile in profiles {
+                        let name = profile.display_name.unwrap_or(profile.username);
+                        self.names.insert(profile.id, (name, now));
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "looking up typing users' names failed"),
+            }
+        }
+
+        user_ids.iter().map(|id| cached(id).unwrap_or_else(|| id.to_string())).collect()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TypingQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TypingPoll {
+    /// Display names, or usernames for users without one.
+    typing_users: Vec<String>,
+    /// How long a listed user stays listed without typing again.
+    expires_in_ms: u64,
+}
+
+/// GET /rooms/{room_id}/typing
+///
+/// Who is typing in a room, by name, for clients that can't keep a
+/// WebSocket open. They poll every 3 seconds; a user stays listed for
+/// `expires_in_ms` after their last `Typing` frame, so nobody flickers
+/// out between polls. Limited to 10 polls a second per token. The token
+/// goes in `Authorization: Bearer` or `?token=`, must grant access to
+/// the room, and is what names are looked up in channels-api with.
+pub async fn poll(
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+    Query(query): Query<TypingQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(token) = query.token.or_else(|| bearer_token(&headers)) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let Some(claims) = verify_token(&state, &token).await else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if !state.typing_polls.allow(&token, Instant::now()) {
+        return (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "1")]).into_response();
+    }
+    let Ok(room_id) = room_id.parse::<RoomId>() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if claims.rooms.role_for(room_id.channel.as_str()).is_none() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let typing = state.presence.typing_in(&room_id).await;
+    let typing_users = state.typing_names.resolve(state.channels.as_ref(), &token, &typing).await;
+    Json(TypingPoll { typing_users, expires_in_ms: TYPING_TTL.as_millis() as u64 }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app, test_state};
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+    use uchat_proto::ids::ChannelId;
+    use uchat_proto::jwt::create_token_with_rooms;
+    use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+    async fn get(state: &Arc<AppState>, uri: &str, token: &str) -> (StatusCode, serde_json::Value) {
+        let req = Request::get(uri).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+        let resp = app(state.clone()).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn lists_only_unexpired_typists() {
+        let state = test_state();
+        let channel = ChannelId::new();
+        let room = RoomId::from(channel.clone());
+        let (typing, stopped) = (UserId::new(), UserId::new());
+        state.presence.typing(&room, &typing).await;
+        state.presence.typing_for(&room, &stopped, Duration::ZERO).await;
+
+        let mut rooms = RoomPermissions::new();
+        rooms.grant(channel.as_str(), RoomRole::Read);
+        let token = create_token_with_rooms("test-secret", UserId::new().as_str(), rooms);
+        let (status, body) = get(&state, &format!("/rooms/{}/typing", channel), &token).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["typing_users"], serde_json::json!([typing]));
+        assert_eq!(body["expires_in_ms"], 5000);
+
+        let (status, _) = get(&state, &format!("/rooms/{}/typing", ChannelId::new()), &token).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = get(&state, &format!("/rooms/{}/typing", channel), "not-a-token").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn names_come_from_channels_api() {
+        let (named, unnamed, unknown) = (UserId::new(), UserId::new(), UserId::new());
+        let lookups = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let profiles = serde_json::json!([
+            { "id": named, "username": "ada", "display_name": "Ada Lovelace", "created_at": "2026-01-01T00:00:00Z" },
+            { "id": unnamed, "username": "grace", "created_at": "2026-01-01T00:00:00Z" },
+        ]);
+        let fake_api = axum::Router::new().route(
+            "/api/users",
+            axum::routing::get({
+                let lookups = lookups.clone();
+                move |headers: HeaderMap| async move {
+                    assert!(headers[header::AUTHORIZATION].to_str().unwrap().starts_with("Bearer "));
+                    lookups.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    Json(profiles)
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, fake_api).await.unwrap() });
+        let channels = ChannelsClient::new(&format!("http://{}", addr));
+
+        let names = TypingNames::default();
+        let ids = [named.clone(), unnamed.clone(), unknown.clone()];
+        let expected = vec!["Ada Lovelace".to_string(), "grace".to_string(), unknown.to_string()];
+        assert_eq!(names.resolve(Some(&channels), "token", &ids).await, expected);
+        assert_eq!(names.resolve(Some(&channels), "token", &ids[..2]).await, expected[..2]);
+        assert_eq!(lookups.load(std::sync::atomic::Ordering::Relaxed), 1, "cached names are reused");
+    }
+
+    #[test]
+    fn limits_polls_per_token_each_second() {
+        let limiter = PollLimiter::default();
+        let start = Instant::now();
+        for _ in 0..POLLS_PER_SECOND {
+            assert!(limiter.allow("a", start));
+        }
+        assert!(!limiter.allow("a", start));
+        assert!(limiter.allow("b", start));
+        assert!(limiter.allow("a", start + Duration::from_secs(1)));
+    }
+}
@@ -6,9 +6,15 @@ pkill -f "auth-api|gateway-service|chat-service|presence-service|history-service
 # Export the shared JWT secret
 export JWT_SECRET="supersecret"
 
+# Key for channels-api's signed download and unsubscribe links
+export LINK_SIGNING_KEY="supersecretlinks"
+
 # Go to project dir
 cd ~/unhidra-rust
 
+# auth-api's OIDC signing key, generated here on first start
+export OIDC_SIGNING_KEY_PATH="$PWD/oidc-signing-key.der"
+
 # Create a logs directory if missing
 mkdir -p logs
 
@@ -0,0 +1,210 @@
+//! The services' admin APIs: auth-api's `/admin/users`, channels-api's
+//! `/api/admin/channels` and the gateway's `/internal/*`.
+
+use chrono::{DateTime, Utc};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use uchat_proto::channels::{Channel, MembershipChange, RoomSubscribers};
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::users::{AdminUser, ProvisionedUser, UserRole};
+
+use crate::config::Config;
+use crate::output::Purged;
+use crate::Failure;
+
+const INTERNAL_TOKEN_HEADER: &str = "x-internal-token";
+
+pub struct Api {
+    client: reqwest::Client,
+    config: Config,
+}
+
+impl Api {
+    pub fn new(config: Config) -> Api {
+        Api { client: reqwest::Client::new(), config }
+    }
+
+    /// A request to `url` as the configured admin.
+    fn admin(&self, method: Method, url: String) -> Result<RequestBuilder, Failure> {
+        let token = self.config.admin_token.as_deref().ok_or_else(|| {
+            Failure::Usage("no admin token: set UCHAT_ADMIN_TOKEN or admin_token in the config file".into())
+        })?;
+        Ok(self.client.request(method, url).bearer_auth(token))
+    }
+
+    fn internal(&self, method: Method, path: &str) -> Result<RequestBuilder, Failure> {
+        let token = self.config.gateway_internal_token.as_deref().ok_or_else(|| {
+            Failure::Usage("no gateway token: set GATEWAY_INTERNAL_TOKEN or gateway_internal_token in the config file".into())
+        })?;
+        let url = format!("{}{}", self.config.gateway_url(), path);
+        Ok(self.client.request(method, url).header(INTERNAL_TOKEN_HEADER, token))
+    }
+
+    fn auth(&self, method: Method, path: &str) -> Result<RequestBuilder, Failure> {
+        self.admin(method, format!("{}{}", self.config.auth_api_url(), path))
+    }
+
+    fn channels(&self, method: Method, path: &str) -> Result<RequestBuilder, Failure> {
+        self.admin(method, format!("{}{}", self.config.channels_api_url(), path))
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<AdminUser>, Failure> {
+        json_body(self.auth(Method::GET, "/admin/users")?).await
+    }
+
+    pub async fn create_user(&self, username: &str, role: UserRole) -> Result<ProvisionedUser, Failure> {
+        let req = self.auth(Method::POST, "/admin/users")?;
+        json_body(req.json(&json!({ "username": username, "role": role }))).await
+    }
+
+    /// A new activation token for an account without a password.
+    pub async fn reissue_activation(&self, user_id: &UserId) -> Result<ProvisionedUser, Failure> {
+        json_body(self.auth(Method::POST, &format!("/admin/users/{}/activation", user_id))?).await
+    }
+
+    /// Locks the user out, until `until` or for good, and reports them.
+    pub async fn lock(&self, user_id: &UserId, until: Option<DateTime<Utc>>) -> Result<AdminUser, Failure> {
+        let req = self.auth(Method::POST, &format!("/admin/users/{}/suspend", user_id))?;
+        json_body::<Value>(req.json(&json!({ "until": until }))).await?;
+        self.get_user(user_id).await
+    }
+
+    pub async fn unlock(&self, user_id: &UserId) -> Result<AdminUser, Failure> {
+        json_body::<Value>(self.auth(Method::POST, &format!("/admin/users/{}/unsuspend", user_id))?).await?;
+        self.get_user(user_id).await
+    }
+
+    async fn get_user(&self, user_id: &UserId) -> Result<AdminUser, Failure> {
+        json_body(self.auth(Method::GET, &format!("/admin/users/{}", user_id))?).await
+    }
+
+    pub async fn set_role(&self, user_id: &UserId, role: UserRole) -> Result<AdminUser, Failure> {
+        let req = self.auth(Method::PUT, &format!("/admin/users/{}/role", user_id))?;
+        json_body(req.json(&json!({ "role": role }))).await
+    }
+
+    /// Returns how many refresh tokens were deleted.
+    pub async fn revoke_tokens(&self, user_id: &UserId) -> Result<u64, Failure> {
+        let req = self.auth(Method::DELETE, &format!("/admin/users/{}/refresh-tokens", user_id))?;
+        let body: Value = json_body(req).await?;
+        Ok(body["revoked"].as_u64().unwrap_or_default())
+    }
+
+    pub async fn list_channels(&self) -> Result<Vec<Channel>, Failure> {
+        json_body(self.channels(Method::GET, "/api/admin/channels")?).await
+    }
+
+    pub async fn archive_channel(&self, channel_id: &ChannelId) -> Result<Channel, Failure> {
+        json_body(self.channels(Method::POST, &format!("/api/admin/channels/{}/archive", channel_id))?).await
+    }
+
+    pub async fn purge_channel(&self, channel_id: &ChannelId) -> Result<Purged, Failure> {
+        json_body(self.channels(Method::DELETE, &format!("/api/admin/channels/{}", channel_id))?).await
+    }
+
+    pub async fn rooms(&self) -> Result<Vec<RoomSubscribers>, Failure> {
+        json_body(self.internal(Method::GET, "/internal/rooms")?).await
+    }
+
+    /// Drops the user's sockets from the room, as removing them from the
+    /// channel would. Their membership is left alone, so they may rejoin.
+    pub async fn kick(&self, channel_id: &ChannelId, user_id: &UserId) -> Result<(), Failure> {
+        let change = MembershipChange { channel_id: channel_id.clone(), user_id: user_id.clone(), role: None };
+        send(self.internal(Method::POST, "/internal/membership")?.json(&change)).await?;
+        Ok(())
+    }
+}
+
+async fn json_body<T: DeserializeOwned>(req: RequestBuilder) -> Result<T, Failure> {
+    let resp = send(req).await?;
+    resp.json().await.map_err(|e| Failure::Failed(format!("unexpected response: {}", e)))
+}
+
+/// Sends `req`, turning error statuses into the matching failure.
+async fn send(req: RequestBuilder) -> Result<reqwest::Response, Failure> {
+    let resp = req.send().await.map_err(|e| Failure::Failed(e.to_string()))?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let body: Value = resp.json().await.unwrap_or(Value::Null);
+    let message = error_message(&body).unwrap_or_else(|| status.to_string());
+    Err(match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Failure::Refused(message),
+        StatusCode::NOT_FOUND => Failure::NotFound(message),
+        _ => Failure::Failed(message),
+    })
+}
+
+/// The message of an error body: channels-api's `{"code", "message"}` or
+/// auth-api's `{"Error": {...}}`.
+fn error_message(body: &Value) -> Option<String> {
+    let error = body.get("Error").unwrap_or(body);
+    error["message"].as_str().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::{get, post};
+    use axum::{http::HeaderMap, Json, Router};
+
+    async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn sends_credentials_and_maps_errors() {
+        let app = Router::new()
+            .route(
+                "/admin/users",
+                get(|headers: HeaderMap| async move {
+                    assert_eq!(headers["authorization"], "Bearer admin-token");
+                    Json(json!([]))
+                }),
+            )
+            .route(
+                "/admin/users/:id/role",
+                axum::routing::put(|| async {
+                    (StatusCode::NOT_FOUND, Json(json!({ "Error": { "code": "not_found", "message": "no such user" } })))
+                }),
+            )
+            .route(
+                "/internal/membership",
+                post(|headers: HeaderMap, Json(change): Json<MembershipChange>| async move {
+                    assert_eq!(headers[INTERNAL_TOKEN_HEADER], "internal");
+                    assert_eq!(change.role, None);
+                    StatusCode::NO_CONTENT
+                }),
+            )
+            .route("/api/admin/channels", get(|| async { StatusCode::FORBIDDEN }));
+        let url = serve(app).await;
+        let config = Config {
+            auth_api_url: Some(url.clone()),
+            channels_api_url: Some(url.clone()),
+            gateway_url: Some(url),
+            admin_token: Some("admin-token".into()),
+            gateway_internal_token: Some("internal".into()),
+            database_url: None,
+            backup_key: None,
+        };
+        let api = Api::new(config);
+
+        assert!(api.list_users().await.unwrap().is_empty());
+        match api.set_role(&UserId::new(), UserRole::Admin).await {
+            Err(Failure::NotFound(message)) => assert_eq!(message, "no such user"),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(api.list_channels().await, Err(Failure::Refused(_))));
+        api.kick(&ChannelId::new(), &UserId::new()).await.unwrap();
+
+        let unconfigured = Api::new(Config::default());
+        assert!(matches!(unconfigured.rooms().await, Err(Failure::Usage(_))));
+    }
+}
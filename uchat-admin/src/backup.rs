@@ -0,0 +1,586 @@
+//! `backup` and `restore`: every table's rows and every object in file
+//! storage, in a directory holding
+//!
+//!     manifest.json       what the backup holds, with checksums
+//!     manifest.sig        HMAC-SHA256 of manifest.json under the backup key
+//!     tables/<name>.copy  each table's rows, in COPY text format
+//!     objects/<path>      the objects copied into this backup
+//!
+//! Rows are dumped from one read-only snapshot and objects listed after
+//! it, so every object a restored row refers to is there, except those
+//! retention deleted mid-backup. An incremental backup copies only the
+//! objects written since its base and refers to the base for the rest, so
+//! restoring it needs the base too: where it was when the incremental one
+//! was taken, or beside it.
+//!
+//! Restore checks the signatures and every checksum, bases included,
+//! before touching anything.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, WriteMultipart};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::output::{BackedUp, Restored};
+use crate::Failure;
+
+const FORMAT: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const SIGNATURE: &str = "manifest.sig";
+/// Bookkeeping that restore recreates by migrating rather than copying.
+const MIGRATIONS_TABLE: &str = "_sqlx_migrations";
+/// Multipart uploads in flight per restored object.
+const UPLOAD_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub created_at: DateTime<Utc>,
+    /// The last migration applied to the backed-up database.
+    pub schema_version: i64,
+    pub base: Option<Base>,
+    pub tables: Vec<Table>,
+    pub objects: Vec<Object>,
+}
+
+/// The backup an incremental one takes unchanged objects from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Base {
+    pub path: PathBuf,
+    /// Of the base's manifest.json, so another backup in its place
+    /// doesn't pass for it.
+    pub manifest_sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Table {
+    pub name: String,
+    /// Dumped columns, leaving out generated ones.
+    pub columns: Vec<String>,
+    pub rows: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Object {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Held by the base rather than copied into this backup.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub in_base: bool,
+}
+
+/// One backup of a chain, whose signature has been checked.
+struct Link {
+    dir: PathBuf,
+    manifest: Manifest,
+    manifest_sha256: String,
+    /// Index into `manifest.objects` by path.
+    objects: HashMap<String, usize>,
+}
+
+impl Link {
+    fn object(&self, path: &str) -> Option<&Object> {
+        self.objects.get(path).map(|&i| &self.manifest.objects[i])
+    }
+}
+
+fn failed(e: impl Display) -> Failure {
+    Failure::Failed(e.to_string())
+}
+
+fn at(path: &Path) -> impl Fn(std::io::Error) -> Failure + '_ {
+    move |e| Failure::Failed(format!("{}: {}", path.display(), e))
+}
+
+fn mac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::new_from_slice(key).expect("HMAC takes keys of any length")
+}
+
+/// Quotes a table or column name for SQL.
+fn ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn column_list(columns: &[String]) -> String {
+    columns.iter().map(|c| ident(c)).collect::<Vec<_>>().join(", ")
+}
+
+/// `path` under `dir`, refusing anything that would climb out of it.
+fn inside(dir: &Path, path: &str) -> Result<PathBuf, Failure> {
+    let relative = Path::new(path);
+    if path.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Failure::Failed(format!("refusing unsafe path {:?}", path)));
+    }
+    Ok(dir.join(relative))
+}
+
+/// Backs up the database and `store` into `out`, which must not exist yet
+/// or be empty. With `incremental`, objects already in that backup are
+/// referred to rather than copied.
+pub async fn backup(
+    pool: &PgPool,
+    store: &dyn ObjectStore,
+    key: &[u8],
+    out: &Path,
+    incremental: Option<&Path>,
+) -> Result<BackedUp, Failure> {
+    let base = match incremental {
+        Some(dir) => Some(open_chain(dir, key).await?),
+        None => None,
+    };
+    if std::fs::read_dir(out).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(Failure::Usage(format!("{} is not empty", out.display())));
+    }
+
+    let created_at = Utc::now();
+    let (schema_version, tables) = dump_tables(pool, &out.join("tables")).await?;
+    let (objects, vanished) = copy_objects(store, base.as_ref().map(|chain| &chain[0]), &out.join("objects")).await?;
+
+    let base = match base {
+        Some(chain) => {
+            let path = chain[0].dir.canonicalize().map_err(at(&chain[0].dir))?;
+            Some(Base { path, manifest_sha256: chain[0].manifest_sha256.clone() })
+        }
+        None => None,
+    };
+    let manifest = Manifest { format: FORMAT, created_at, schema_version, base, tables, objects };
+    let bytes = serde_json::to_vec_pretty(&manifest).map_err(failed)?;
+    let signature = hex::encode(mac(key).chain_update(&bytes).finalize().into_bytes());
+    tokio::fs::write(out.join(MANIFEST), &bytes).await.map_err(at(out))?;
+    tokio::fs::write(out.join(SIGNATURE), signature).await.map_err(at(out))?;
+
+    let copied: Vec<&Object> = manifest.objects.iter().filter(|o| !o.in_base).collect();
+    Ok(BackedUp {
+        dir: out.display().to_string(),
+        tables: manifest.tables.len(),
+        rows: manifest.tables.iter().map(|t| t.rows).sum(),
+        objects: manifest.objects.len(),
+        copied: copied.len(),
+        bytes: copied.iter().map(|o| o.size).sum(),
+        vanished,
+    })
+}
+
+/// Dumps every table but the migrations' into `dir`, from one snapshot.
+async fn dump_tables(pool: &PgPool, dir: &Path) -> Result<(i64, Vec<Table>), Failure> {
+    tokio::fs::create_dir_all(dir).await.map_err(at(dir))?;
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY").execute(&mut *tx).await?;
+
+    let schema_version: Option<i64> =
+        sqlx::query_scalar(&format!("SELECT MAX(version) FROM {} WHERE success", MIGRATIONS_TABLE))
+            .fetch_one(&mut *tx)
+            .await?;
+    let schema_version = schema_version.ok_or_else(|| Failure::Failed("the database has no migrations applied".into()))?;
+    let names: Vec<String> = sqlx::query_scalar(
+        "SELECT tablename::text FROM pg_tables WHERE schemaname = current_schema() AND tablename <> $1 ORDER BY tablename",
+    )
+    .bind(MIGRATIONS_TABLE)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut tables = Vec::new();
+    for name in names {
+        let columns = dumped_columns(&mut tx, &name).await?;
+        let path = dir.join(format!("{}.copy", name));
+        let mut file = tokio::fs::File::create(&path).await.map_err(at(&path))?;
+        let (mut hasher, mut rows) = (Sha256::new(), 0);
+        let mut copy = tx.copy_out_raw(&format!("COPY {} ({}) TO STDOUT", ident(&name), column_list(&columns))).await?;
+        while let Some(chunk) = copy.try_next().await? {
+            hasher.update(&chunk);
+            // One line per row; newlines within values are escaped.
+            rows += chunk.iter().filter(|b| **b == b'\n').count() as u64;
+            file.write_all(&chunk).await.map_err(at(&path))?;
+        }
+        drop(copy);
+        file.flush().await.map_err(at(&path))?;
+        tables.push(Table { name, columns, rows, sha256: hex::encode(hasher.finalize()) });
+    }
+    tx.commit().await?;
+    Ok((schema_version, tables))
+}
+
+async fn dumped_columns(conn: &mut PgConnection, table: &str) -> Result<Vec<String>, Failure> {
+    Ok(sqlx::query_scalar(
+        "SELECT column_name::text FROM information_schema.columns
+         WHERE table_schema = current_schema() AND table_name = $1 AND is_generated = 'NEVER'
+         ORDER BY ordinal_position",
+    )
+    .bind(table)
+    .fetch_all(conn)
+    .await?)
+}
+
+/// Copies every object in `store` into `dir`, except those `base` already
+/// holds and that haven't been written since. Returns the manifest
+/// entries and how many listed objects were gone before they could be
+/// copied.
+async fn copy_objects(store: &dyn ObjectStore, base: Option<&Link>, dir: &Path) -> Result<(Vec<Object>, u64), Failure> {
+    let mut listed: Vec<_> = store.list(None).try_collect().await.map_err(failed)?;
+    listed.sort_by(|a, b| a.location.cmp(&b.location));
+
+    let (mut objects, mut vanished) = (Vec::new(), 0);
+    for meta in listed {
+        let path = meta.location.to_string();
+        let unchanged = base.and_then(|base| {
+            let held = base.object(&path)?;
+            (held.size == meta.size as u64 && meta.last_modified <= base.manifest.created_at).then_some(held)
+        });
+        if let Some(held) = unchanged {
+            objects.push(Object { in_base: true, ..held.clone() });
+            continue;
+        }
+        match copy_object(store, &meta.location, &inside(dir, &path)?).await? {
+            Some((size, sha256)) => objects.push(Object { path, size, sha256, in_base: false }),
+            None => vanished += 1,
+        }
+    }
+    Ok((objects, vanished))
+}
+
+/// Copies one object to `target`, returning its size and checksum, or
+/// `None` if it has been deleted since it was listed.
+async fn copy_object(store: &dyn ObjectStore, location: &ObjectPath, target: &Path) -> Result<Option<(u64, String)>, Failure> {
+    let mut stream = match store.get(location).await {
+        Ok(result) => result.into_stream(),
+        Err(object_store::Error::NotFound { .. }) => return Ok(None),
+        Err(e) => return Err(failed(e)),
+    };
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(at(parent))?;
+    }
+    let mut file = tokio::fs::File::create(target).await.map_err(at(target))?;
+    let (mut hasher, mut size) = (Sha256::new(), 0);
+    while let Some(chunk) = stream.try_next().await.map_err(failed)? {
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        file.write_all(&chunk).await.map_err(at(target))?;
+    }
+    file.flush().await.map_err(at(target))?;
+    Ok(Some((size, hex::encode(hasher.finalize()))))
+}
+
+/// Restores the backup in `dir` into the database and `store`, after
+/// checking it; with `verify_only`, only checks it. Refuses a database
+/// that already has rows unless `force`, which replaces them. Objects
+/// already in `store` are left alone.
+pub async fn restore(
+    pool: &PgPool,
+    store: &dyn ObjectStore,
+    key: &[u8],
+    dir: &Path,
+    verify_only: bool,
+    force: bool,
+) -> Result<Restored, Failure> {
+    let chain = open_chain(dir, key).await?;
+    verify_files(&chain).await?;
+    let manifest = &chain[0].manifest;
+    let mut restored = Restored {
+        dir: dir.display().to_string(),
+        schema_version: manifest.schema_version,
+        tables: manifest.tables.len(),
+        rows: manifest.tables.iter().map(|t| t.rows).sum(),
+        objects: manifest.objects.len(),
+        uploaded: 0,
+    };
+    if verify_only {
+        return Ok(restored);
+    }
+
+    uchat_db::migrate::run_to(pool, Some(manifest.schema_version))
+        .await
+        .map_err(|e| Failure::Failed(format!("migrate failed: {}", e)))?;
+    if !force {
+        for table in &manifest.tables {
+            let query = format!("SELECT EXISTS (SELECT 1 FROM {})", ident(&table.name));
+            if sqlx::query_scalar::<_, bool>(&query).fetch_one(pool).await? {
+                return Err(Failure::Refused(format!(
+                    "{} already has rows; pass --force to replace the database's contents",
+                    table.name
+                )));
+            }
+        }
+    }
+
+    for object in &manifest.objects {
+        let location = ObjectPath::parse(&object.path).map_err(failed)?;
+        match store.head(&location).await {
+            Ok(_) => continue,
+            Err(object_store::Error::NotFound { .. }) => {}
+            Err(e) => return Err(failed(e)),
+        }
+        upload(store, &location, &object_file(&chain, &object.path)?).await?;
+        restored.uploaded += 1;
+    }
+
+    let mut tx = pool.begin().await?;
+    let names: Vec<String> = manifest.tables.iter().map(|t| ident(&t.name)).collect();
+    if !names.is_empty() {
+        sqlx::query(&format!("TRUNCATE {} CASCADE", names.join(", "))).execute(&mut *tx).await?;
+    }
+    for table in &manifest.tables {
+        let path = dir.join("tables").join(format!("{}.copy", table.name));
+        let file = tokio::fs::File::open(&path).await.map_err(at(&path))?;
+        let mut copy = tx
+            .copy_in_raw(&format!("COPY {} ({}) FROM STDIN", ident(&table.name), column_list(&table.columns)))
+            .await?;
+        copy.read_from(file).await?;
+        let rows = copy.finish().await?;
+        if rows != table.rows {
+            return Err(Failure::Failed(format!("{}: loaded {} rows, expected {}", table.name, rows, table.rows)));
+        }
+    }
+    reset_sequences(&mut tx).await?;
+    tx.commit().await?;
+    Ok(restored)
+}
+
+/// Moves serial columns' sequences past the restored ids.
+async fn reset_sequences(conn: &mut PgConnection) -> Result<(), Failure> {
+    let serials: Vec<(String, String)> = sqlx::query_as(
+        "SELECT table_name::text, column_name::text FROM information_schema.columns
+         WHERE table_schema = current_schema() AND column_default LIKE 'nextval(%'",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for (table, column) in serials {
+        let query = format!(
+            "SELECT setval(pg_get_serial_sequence($1, $2), COALESCE(MAX({}), 0) + 1, false) FROM {}",
+            ident(&column),
+            ident(&table)
+        );
+        sqlx::query(&query).bind(ident(&table)).bind(&column).execute(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+async fn upload(store: &dyn ObjectStore, location: &ObjectPath, source: &Path) -> Result<(), Failure> {
+    let mut file = tokio::fs::File::open(source).await.map_err(at(source))?;
+    let mut writer = WriteMultipart::new(store.put_multipart(location).await.map_err(failed)?);
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buf).await.map_err(at(source))?;
+        if n == 0 {
+            break;
+        }
+        writer.wait_for_capacity(UPLOAD_CONCURRENCY).await.map_err(failed)?;
+        writer.write(&buf[..n]);
+    }
+    writer.finish().await.map_err(failed)?;
+    Ok(())
+}
+
+/// The backup in `dir` followed by its bases, newest first, with their
+/// signatures and the links between them checked.
+async fn open_chain(dir: &Path, key: &[u8]) -> Result<Vec<Link>, Failure> {
+    let mut chain: Vec<Link> = Vec::new();
+    let mut next = Some((dir.to_path_buf(), None));
+    while let Some((dir, expected_sha256)) = next.take() {
+        let link = open(dir, key).await?;
+        if expected_sha256.is_some_and(|sha256: String| sha256 != link.manifest_sha256) {
+            return Err(Failure::Failed(format!(
+                "{} is not the backup {} was taken against",
+                link.dir.display(),
+                chain[chain.len() - 1].dir.display()
+            )));
+        }
+        if let Some(base) = &link.manifest.base {
+            next = Some((locate_base(&link.dir, &base.path)?, Some(base.manifest_sha256.clone())));
+        }
+        chain.push(link);
+    }
+    Ok(chain)
+}
+
+async fn open(dir: PathBuf, key: &[u8]) -> Result<Link, Failure> {
+    let path = dir.join(MANIFEST);
+    let bytes = tokio::fs::read(&path).await.map_err(at(&path))?;
+    let path = dir.join(SIGNATURE);
+    let signature = tokio::fs::read_to_string(&path).await.map_err(at(&path))?;
+    let signature = hex::decode(signature.trim()).unwrap_or_default();
+    if mac(key).chain_update(&bytes).verify_slice(&signature).is_err() {
+        return Err(Failure::Failed(format!(
+            "{}: the manifest's signature doesn't match; wrong backup key, or the manifest was altered",
+            dir.display()
+        )));
+    }
+
+    let manifest: Manifest = serde_json::from_slice(&bytes).map_err(failed)?;
+    if manifest.format != FORMAT {
+        return Err(Failure::Failed(format!("{}: unknown backup format {}", dir.display(), manifest.format)));
+    }
+    let objects = manifest.objects.iter().enumerate().map(|(i, o)| (o.path.clone(), i)).collect();
+    let manifest_sha256 = hex::encode(Sha256::digest(&bytes));
+    Ok(Link { dir, manifest, manifest_sha256, objects })
+}
+
+/// Where the base recorded at `recorded` is now: still there, or moved
+/// beside the backup that refers to it.
+fn locate_base(dir: &Path, recorded: &Path) -> Result<PathBuf, Failure> {
+    let beside = recorded.file_name().and_then(|name| Some(dir.parent()?.join(name)));
+    [Some(recorded.to_path_buf()), beside]
+        .into_iter()
+        .flatten()
+        .find(|candidate| candidate.join(MANIFEST).is_file())
+        .ok_or_else(|| Failure::Failed(format!("base backup {} not found", recorded.display())))
+}
+
+/// The file holding the chain's copy of the object at `path`.
+fn object_file(chain: &[Link], path: &str) -> Result<PathBuf, Failure> {
+    for link in chain {
+        match link.object(path) {
+            Some(object) if !object.in_base => return inside(&link.dir.join("objects"), path),
+            Some(_) => continue,
+            None => break,
+        }
+    }
+    Err(Failure::Failed(format!("{} is missing from the backup and its bases", path)))
+}
+
+/// Checks every table dump and object the backup restores from against
+/// its checksum.
+async fn verify_files(chain: &[Link]) -> Result<(), Failure> {
+    let link = &chain[0];
+    for table in &link.manifest.tables {
+        let path = inside(&link.dir.join("tables"), &format!("{}.copy", table.name))?;
+        check(&path, None, &table.sha256).await?;
+    }
+    for object in &link.manifest.objects {
+        check(&object_file(chain, &object.path)?, Some(object.size), &object.sha256).await?;
+    }
+    Ok(())
+}
+
+async fn check(path: &Path, size: Option<u64>, sha256: &str) -> Result<(), Failure> {
+    let mut file = tokio::fs::File::open(path).await.map_err(at(path))?;
+    let (mut hasher, mut read) = (Sha256::new(), 0);
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buf).await.map_err(at(path))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        read += n as u64;
+    }
+    if size.is_some_and(|size| size != read) || hex::encode(hasher.finalize()) != sha256 {
+        return Err(Failure::Failed(format!("{} doesn't match its checksum; the backup is damaged", path.display())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use object_store::PutPayload;
+    use uchat_proto::ids::{FileId, UserId};
+
+    const KEY: &[u8] = b"backup-test-key";
+
+    /// A new, empty database on the test server, and its URL.
+    async fn scratch_database() -> Option<(PgPool, String)> {
+        let url = uchat_db::test_database_url().await?;
+        let server = uchat_db::connect_unmigrated(&url).await.expect("connect to the test database");
+        let name = format!("uchat_backup_{}", FileId::new().to_string().replace('-', ""));
+        sqlx::query(&format!("CREATE DATABASE {}", ident(&name))).execute(&server).await.unwrap();
+        let (server_url, _) = url.split_once('?').map_or((url.as_str(), ""), |(u, q)| (u, q));
+        let (server_url, _) = server_url.rsplit_once('/').unwrap();
+        let url = format!("{}/{}", server_url, name);
+        Some((uchat_db::connect_unmigrated(&url).await.unwrap(), name))
+    }
+
+    async fn drop_database(pool: PgPool, name: &str) {
+        pool.close().await;
+        let server = uchat_db::connect_unmigrated(&uchat_db::test_database_url().await.unwrap()).await.unwrap();
+        sqlx::query(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", ident(name))).execute(&server).await.unwrap();
+    }
+
+    async fn put(store: &InMemory, path: &str, data: &'static [u8]) {
+        store.put(&ObjectPath::from(path), PutPayload::from_static(data)).await.unwrap();
+    }
+
+    async fn add_user(pool: &PgPool, username: &str) -> UserId {
+        let id = UserId::new();
+        sqlx::query("INSERT INTO users (id, username) VALUES ($1, $2)").bind(&id).bind(username).execute(pool).await.unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn round_trips_rows_and_objects_through_incremental_backups() {
+        let Some((source, source_name)) = scratch_database().await else { return };
+        let Some((target, target_name)) = scratch_database().await else { return };
+        uchat_db::migrate::run_to(&source, None).await.unwrap();
+        let dir = std::env::temp_dir().join(format!("uchat-backup-{}", FileId::new()));
+        let (full, incremental) = (dir.join("full"), dir.join("incremental"));
+
+        let alice = add_user(&source, "alice").await;
+        sqlx::query("INSERT INTO messages (id, channel_id, sender_id, content) VALUES ('m1', 'c1', $1, 'hello\nworld')")
+            .bind(&alice)
+            .execute(&source)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO message_edits (message_id, content) VALUES ('m1', 'hi')").execute(&source).await.unwrap();
+        let files = InMemory::new();
+        put(&files, "c1/f1", b"first file").await;
+        put(&files, "avatars/a/1-64.png", b"avatar").await;
+
+        let summary = backup(&source, &files, KEY, &full, None).await.unwrap();
+        assert_eq!((summary.objects, summary.copied), (2, 2));
+        assert!(backup(&source, &files, KEY, &full, None).await.is_err(), "overwrote a backup");
+
+        add_user(&source, "bob").await;
+        put(&files, "c1/f2", b"second file").await;
+        let summary = backup(&source, &files, KEY, &incremental, Some(&full)).await.unwrap();
+        assert_eq!((summary.objects, summary.copied), (3, 1));
+        assert!(!incremental.join("objects/c1/f1").exists());
+
+        let restored_files = InMemory::new();
+        put(&restored_files, "c1/f1", b"first file").await;
+        let restored = restore(&target, &restored_files, KEY, &incremental, false, false).await.unwrap();
+        assert_eq!((restored.objects, restored.uploaded), (3, 2));
+        for (path, data) in [("c1/f1", &b"first file"[..]), ("c1/f2", b"second file"), ("avatars/a/1-64.png", b"avatar")] {
+            let got = restored_files.get(&ObjectPath::from(path)).await.unwrap().bytes().await.unwrap();
+            assert_eq!(&got[..], data, "{}", path);
+        }
+        let users: Vec<String> = sqlx::query_scalar("SELECT username FROM users ORDER BY username").fetch_all(&target).await.unwrap();
+        assert_eq!(users, ["alice", "bob"]);
+        let (content, search): (String, bool) =
+            sqlx::query_as("SELECT content, search_vector IS NOT NULL FROM messages WHERE id = 'm1'").fetch_one(&target).await.unwrap();
+        assert_eq!((content.as_str(), search), ("hello\nworld", true));
+        let edit: i64 = sqlx::query_scalar("INSERT INTO message_edits (message_id, content) VALUES ('m1', 'hey') RETURNING id")
+            .fetch_one(&target)
+            .await
+            .unwrap();
+        assert_eq!(edit, 2);
+
+        assert!(matches!(
+            restore(&target, &restored_files, KEY, &incremental, false, false).await,
+            Err(Failure::Refused(_))
+        ));
+        restore(&target, &restored_files, KEY, &full, false, true).await.unwrap();
+        let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(&target).await.unwrap();
+        assert_eq!(users, 1);
+
+        // Damage to the base shows up when verifying the incremental backup.
+        assert!(restore(&target, &restored_files, b"wrong-key", &incremental, true, false).await.is_err());
+        std::fs::write(full.join("objects/c1/f1"), b"tampered!!").unwrap();
+        assert!(restore(&target, &restored_files, KEY, &incremental, true, false).await.is_err());
+        assert!(restore(&target, &restored_files, KEY, &full, true, false).await.is_err());
+
+        let _ = std::fs::remove_dir_all(dir);
+        drop_database(source, &source_name).await;
+        drop_database(target, &target_name).await;
+    }
+}
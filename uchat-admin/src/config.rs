@@ -0,0 +1,124 @@
+//! Where the services are and the credentials for them. Credentials never
+//! come from the command line, where they would end up in shell history:
+//! they are read from a JSON file, then from the environment, which wins.
+//!
+//! The file is `UCHAT_ADMIN_CONFIG`, or `~/.config/uchat/admin.json` when
+//! that exists, with any of:
+//!
+//!     {"auth_api_url", "channels_api_url", "gateway_url", "admin_token",
+//!      "gateway_internal_token", "database_url", "backup_key"}
+//!
+//! and the variables are `AUTH_API_URL`, `CHANNELS_API_URL`,
+//! `GATEWAY_INTERNAL_URL`, `UCHAT_ADMIN_TOKEN`, `GATEWAY_INTERNAL_TOKEN`,
+//! `DATABASE_URL` and `UCHAT_BACKUP_KEY`.
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub auth_api_url: Option<String>,
+    pub channels_api_url: Option<String>,
+    pub gateway_url: Option<String>,
+    /// An access token for a user with `users.is_admin`.
+    pub admin_token: Option<String>,
+    pub gateway_internal_token: Option<String>,
+    /// For `migrate`, `backup` and `restore`.
+    pub database_url: Option<String>,
+    /// Signs backup manifests; restoring needs the key they were signed
+    /// with.
+    pub backup_key: Option<String>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        let explicit = std::env::var_os("UCHAT_ADMIN_CONFIG").map(PathBuf::from);
+        let default = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/uchat/admin.json"));
+        let mut config = match (explicit, default) {
+            (Some(path), _) => Config::read(&path)?,
+            (None, Some(path)) if path.exists() => Config::read(&path)?,
+            _ => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok());
+        Ok(config)
+    }
+
+    fn read(path: &PathBuf) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+        serde_json::from_str(&text).map_err(|e| format!("parsing {}: {}", path.display(), e))
+    }
+
+    /// Overrides settings with the variables `var` finds set and non-empty.
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        let settings = [
+            ("AUTH_API_URL", &mut self.auth_api_url),
+            ("CHANNELS_API_URL", &mut self.channels_api_url),
+            ("GATEWAY_INTERNAL_URL", &mut self.gateway_url),
+            ("UCHAT_ADMIN_TOKEN", &mut self.admin_token),
+            ("GATEWAY_INTERNAL_TOKEN", &mut self.gateway_internal_token),
+            ("DATABASE_URL", &mut self.database_url),
+            ("UCHAT_BACKUP_KEY", &mut self.backup_key),
+        ];
+        for (name, setting) in settings {
+            if let Some(value) = var(name).filter(|v| !v.is_empty()) {
+                *setting = Some(value);
+            }
+        }
+    }
+
+    pub fn auth_api_url(&self) -> &str {
+        self.auth_api_url.as_deref().unwrap_or("http://127.0.0.1:9200")
+    }
+
+    pub fn channels_api_url(&self) -> &str {
+        self.channels_api_url.as_deref().unwrap_or("http://127.0.0.1:9400")
+    }
+
+    pub fn gateway_url(&self) -> &str {
+        self.gateway_url.as_deref().unwrap_or("http://127.0.0.1:9000")
+    }
+
+    pub fn database_url(&self) -> &str {
+        self.database_url.as_deref().unwrap_or("postgres://postgres@localhost/uchat")
+    }
+}
+
+/// Whether a connection URL carries a password, which must not be typed
+/// on the command line.
+pub fn has_password(url: &str) -> bool {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split(['/', '?']).next().unwrap_or_default();
+    authority.rsplit_once('@').is_some_and(|(userinfo, _)| userinfo.contains(':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config: Config =
+            serde_json::from_str(r#"{"admin_token": "from-file", "gateway_url": "http://gw:9000"}"#).unwrap();
+        config.apply_env(|name| match name {
+            "UCHAT_ADMIN_TOKEN" => Some("from-env".into()),
+            "AUTH_API_URL" => Some(String::new()),
+            _ => None,
+        });
+        assert_eq!(config.admin_token.as_deref(), Some("from-env"));
+        assert_eq!(config.gateway_url(), "http://gw:9000");
+        assert_eq!(config.auth_api_url(), "http://127.0.0.1:9200");
+
+        assert!(serde_json::from_str::<Config>(r#"{"admin_tokn": "typo"}"#).is_err());
+    }
+
+    #[test]
+    fn spots_passwords_in_urls() {
+        assert!(has_password("postgres://admin:hunter2@db/uchat"));
+        assert!(has_password("postgres://admin:p@ss@db/uchat"));
+        assert!(!has_password("postgres://admin@db/uchat"));
+        assert!(!has_password("postgres://db/uchat?user=a:b@c"));
+        assert!(!has_password("postgres:///uchat"));
+    }
+}
@@ -0,0 +1,483 @@
+//! Operator commands for a U-chat deployment.
+//!
+//!     uchat-admin migrate [--dry-run | --to <version>]
+//!     uchat-admin user list
+//!     uchat-admin user create <username> [--role user|admin|compliance]
+//!     uchat-admin user activation <user-id>
+//!     uchat-admin user lock <user-id> [--until <rfc3339>]
+//!     uchat-admin user unlock <user-id>
+//!     uchat-admin user set-role <user-id> user|admin|compliance
+//!     uchat-admin channel list
+//!     uchat-admin channel archive <channel-id>
+//!     uchat-admin channel purge <channel-id>
+//!     uchat-admin token revoke --user <user-id>
+//!     uchat-admin gateway rooms
+//!     uchat-admin gateway kick <channel-id> <user-id>
+//!     uchat-admin backup --out <dir> [--incremental <previous-backup>]
+//!     uchat-admin restore --from <dir> [--verify-only] [--force]
+//!
+//! `migrate` applies pending schema migrations to `DATABASE_URL`, for
+//! deployments that run services with `UCHAT_AUTO_MIGRATE=false`.
+//! `backup` and `restore` work on that database and on file storage
+//! directly, configured as for channels-api, and sign and check backups
+//! with `UCHAT_BACKUP_KEY`; see `backup`. Stop the services before
+//! restoring.
+//!
+//! The rest go through the services' admin APIs, as the admin whose
+//! access token is configured, and the gateway's internal endpoints; see
+//! `config` for where those and their credentials come from. To create the
+//! first admin, `user` and `token` commands also work on the database
+//! directly with `--database-url <url>`, whose password, if any, goes in
+//! `PGPASSWORD` rather than the URL.
+//!
+//! `user create` prints the new account's one-time activation token, which
+//! its owner sets a password with at auth-api's `POST /activate`; `user
+//! activation` issues a fresh one for an account that has no password.
+//!
+//! `--json` prints results as JSON instead of a table. Locking, archiving,
+//! purging, revoking and kicking ask for confirmation first; `--yes` skips
+//! that, and without a terminal they refuse to run unless given.
+//!
+//! Exit codes: 0 done, 1 failed, 2 bad usage or configuration, 3 no such
+//! user or channel, 4 refused by the service or, for `restore`, by a
+//! database that isn't empty, 5 not confirmed.
+
+mod api;
+mod backup;
+mod config;
+mod output;
+mod storage;
+
+use std::fmt;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use chrono::{DateTime, Utc};
+
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::users::UserRole;
+
+use api::Api;
+use config::Config;
+use output::{Kicked, Revoked};
+
+const USAGE: &str = "usage: uchat-admin [--json] [--yes] [--database-url <url>] <command>
+  migrate [--dry-run | --to <version>]
+  user list | create <username> [--role <role>] | activation <user-id>
+       | lock <user-id> [--until <time>] | unlock <user-id> | set-role <user-id> <role>
+  channel list | archive <channel-id> | purge <channel-id>
+  token revoke --user <user-id>
+  gateway rooms | kick <channel-id> <user-id>
+  backup --out <dir> [--incremental <previous-backup>]
+  restore --from <dir> [--verify-only] [--force]";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// List pending migrations without applying them.
+    DryRun,
+    /// Apply pending migrations, up to `to` if given.
+    Migrate { to: Option<i64> },
+    UserList,
+    UserCreate { username: String, role: UserRole },
+    UserActivation { user_id: UserId },
+    UserLock { user_id: UserId, until: Option<DateTime<Utc>> },
+    UserUnlock { user_id: UserId },
+    UserSetRole { user_id: UserId, role: UserRole },
+    ChannelList,
+    ChannelArchive { channel_id: ChannelId },
+    ChannelPurge { channel_id: ChannelId },
+    TokenRevoke { user_id: UserId },
+    GatewayRooms,
+    GatewayKick { channel_id: ChannelId, user_id: UserId },
+    Backup { out: PathBuf, incremental: Option<PathBuf> },
+    Restore { from: PathBuf, verify_only: bool, force: bool },
+}
+
+#[derive(Debug, PartialEq)]
+struct Invocation {
+    command: Command,
+    json: bool,
+    yes: bool,
+    /// Work on this database instead of through the services.
+    database_url: Option<String>,
+}
+
+/// Why a command did not complete, each with its own exit code.
+#[derive(Debug)]
+pub enum Failure {
+    Usage(String),
+    NotFound(String),
+    Refused(String),
+    NotConfirmed,
+    Failed(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Failure::Failed(_) => 1,
+            Failure::Usage(_) => 2,
+            Failure::NotFound(_) => 3,
+            Failure::Refused(_) => 4,
+            Failure::NotConfirmed => 5,
+        })
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Usage(message) | Failure::NotFound(message) | Failure::Failed(message) => f.write_str(message),
+            Failure::Refused(message) => write!(f, "refused: {}", message),
+            Failure::NotConfirmed => f.write_str("not confirmed; pass --yes to skip the prompt"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for Failure {
+    fn from(e: sqlx::Error) -> Self {
+        Failure::Failed(format!("database: {}", e))
+    }
+}
+
+fn parse<T: std::str::FromStr>(what: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {} {:?}", what, value))
+}
+
+fn parse_args(args: &[String]) -> Result<Invocation, String> {
+    let (mut json, mut yes, mut database_url) = (false, false, None);
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--yes" | "-y" => yes = true,
+            "--database-url" => {
+                let url = args.next().ok_or("--database-url needs a URL")?;
+                if config::has_password(url) {
+                    return Err("keep the password out of --database-url; set PGPASSWORD instead".into());
+                }
+                database_url = Some(url.clone());
+            }
+            _ => rest.push(arg.as_str()),
+        }
+    }
+
+    let command = match rest.as_slice() {
+        ["migrate"] => Command::Migrate { to: None },
+        ["migrate", "--dry-run"] => Command::DryRun,
+        ["migrate", "--to", version] => version
+            .parse()
+            .map(|v| Command::Migrate { to: Some(v) })
+            .map_err(|_| format!("--to needs a migration version, got {:?}", version))?,
+        ["user", "list"] => Command::UserList,
+        ["user", "create", username] => Command::UserCreate { username: username.to_string(), role: UserRole::User },
+        ["user", "create", username, "--role", role] => {
+            Command::UserCreate { username: username.to_string(), role: parse("role", role)? }
+        }
+        ["user", "activation", user_id] => Command::UserActivation { user_id: parse("user id", user_id)? },
+        ["user", "lock", user_id] => Command::UserLock { user_id: parse("user id", user_id)?, until: None },
+        ["user", "lock", user_id, "--until", until] => {
+            Command::UserLock { user_id: parse("user id", user_id)?, until: Some(parse("time", until)?) }
+        }
+        ["user", "unlock", user_id] => Command::UserUnlock { user_id: parse("user id", user_id)? },
+        ["user", "set-role", user_id, role] => {
+            Command::UserSetRole { user_id: parse("user id", user_id)?, role: parse("role", role)? }
+        }
+        ["channel", "list"] => Command::ChannelList,
+        ["channel", "archive", channel_id] => Command::ChannelArchive { channel_id: parse("channel id", channel_id)? },
+        ["channel", "purge", channel_id] => Command::ChannelPurge { channel_id: parse("channel id", channel_id)? },
+        ["token", "revoke", "--user", user_id] => Command::TokenRevoke { user_id: parse("user id", user_id)? },
+        ["gateway", "rooms"] => Command::GatewayRooms,
+        ["gateway", "kick", channel_id, user_id] => Command::GatewayKick {
+            channel_id: parse("channel id", channel_id)?,
+            user_id: parse("user id", user_id)?,
+        },
+        ["backup", "--out", out] => Command::Backup { out: out.into(), incremental: None },
+        ["backup", "--out", out, "--incremental", base] => Command::Backup { out: out.into(), incremental: Some(base.into()) },
+        ["restore", "--from", from, flags @ ..] if flags.iter().all(|f| ["--verify-only", "--force"].contains(f)) => {
+            Command::Restore { from: from.into(), verify_only: flags.contains(&"--verify-only"), force: flags.contains(&"--force") }
+        }
+        [command, ..] if !["migrate", "user", "channel", "token", "gateway", "backup", "restore"].contains(command) => {
+            return Err(format!("unknown command {:?}\n{}", command, USAGE))
+        }
+        _ => return Err(USAGE.into()),
+    };
+    Ok(Invocation { command, json, yes, database_url })
+}
+
+/// Asks before a destructive command, unless `--yes` was given.
+fn confirm(yes: bool, question: &str) -> Result<(), Failure> {
+    if yes {
+        return Ok(());
+    }
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(Failure::NotConfirmed);
+    }
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush().ok();
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer).map_err(|e| Failure::Failed(e.to_string()))?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(Failure::NotConfirmed),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let invocation = match parse_args(&args) {
+        Ok(invocation) => invocation,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match Config::load() {
+        Ok(config) => run(invocation, config).await,
+        Err(e) => Err(Failure::Usage(e)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("uchat-admin: {}", e);
+            e.exit_code()
+        }
+    }
+}
+
+async fn run(invocation: Invocation, config: Config) -> Result<(), Failure> {
+    let Invocation { command, json, yes, database_url } = invocation;
+    let not_found = |what: &str| Failure::NotFound(format!("no such {}", what));
+
+    if let Command::DryRun | Command::Migrate { .. } = command {
+        let url = database_url.as_deref().unwrap_or(config.database_url());
+        return migrate(command, url).await;
+    }
+    if let Command::Backup { .. } | Command::Restore { .. } = command {
+        let url = database_url.as_deref().unwrap_or(config.database_url());
+        return backup_or_restore(command, url, &config, json, yes).await;
+    }
+    let db = match &database_url {
+        Some(url) => Some(uchat_db::connect_unmigrated(url).await?),
+        None => None,
+    };
+    if db.is_some()
+        && matches!(
+            command,
+            Command::ChannelList
+                | Command::ChannelArchive { .. }
+                | Command::ChannelPurge { .. }
+                | Command::GatewayRooms
+                | Command::GatewayKick { .. }
+        )
+    {
+        return Err(Failure::Usage("channel and gateway commands go through the services; drop --database-url".into()));
+    }
+    let api = Api::new(config);
+
+    match command {
+        Command::DryRun | Command::Migrate { .. } | Command::Backup { .. } | Command::Restore { .. } => {
+            unreachable!("handled above")
+        }
+        Command::UserList => {
+            let users = match &db {
+                Some(db) => uchat_db::admin::list_users(db).await?,
+                None => api.list_users().await?,
+            };
+            output::list(&users, json);
+        }
+        Command::UserCreate { username, role } => {
+            let user = match &db {
+                Some(db) => uchat_db::admin::create_user(db, &username, role)
+                    .await?
+                    .ok_or_else(|| Failure::Failed(format!("username {:?} is taken", username)))?,
+                None => api.create_user(&username, role).await?,
+            };
+            output::one(&user, json);
+        }
+        Command::UserActivation { user_id } => {
+            let user = match &db {
+                Some(db) => match uchat_db::admin::reissue_activation(db, &user_id).await? {
+                    Some(user) => user,
+                    None if uchat_db::admin::get_user(db, &user_id).await?.is_some() => {
+                        return Err(Failure::Refused("account has a password or is deleted".into()))
+                    }
+                    None => return Err(not_found("user")),
+                },
+                None => api.reissue_activation(&user_id).await?,
+            };
+            output::one(&user, json);
+        }
+        Command::UserLock { user_id, until } => {
+            confirm(yes, &format!("Lock user {} out?", user_id))?;
+            let user = match &db {
+                Some(db) => uchat_db::admin::set_suspended(db, &user_id, true, until).await?.ok_or_else(|| not_found("user"))?,
+                None => api.lock(&user_id, until).await?,
+            };
+            output::one(&user, json);
+        }
+        Command::UserUnlock { user_id } => {
+            let user = match &db {
+                Some(db) => uchat_db::admin::set_suspended(db, &user_id, false, None).await?.ok_or_else(|| not_found("user"))?,
+                None => api.unlock(&user_id).await?,
+            };
+            output::one(&user, json);
+        }
+        Command::UserSetRole { user_id, role } => {
+            let user = match &db {
+                Some(db) => uchat_db::admin::set_role(db, &user_id, role).await?.ok_or_else(|| not_found("user"))?,
+                None => api.set_role(&user_id, role).await?,
+            };
+            output::one(&user, json);
+        }
+        Command::TokenRevoke { user_id } => {
+            confirm(yes, &format!("Sign user {} out of every session?", user_id))?;
+            let revoked = match &db {
+                Some(db) => uchat_db::admin::revoke_refresh_tokens(db, &user_id).await?,
+                None => api.revoke_tokens(&user_id).await?,
+            };
+            output::one(&Revoked { user_id, revoked }, json);
+        }
+        Command::ChannelList => output::list(&api.list_channels().await?, json),
+        Command::ChannelArchive { channel_id } => {
+            confirm(yes, &format!("Archive channel {}? Nobody will be able to post in it.", channel_id))?;
+            output::one(&api.archive_channel(&channel_id).await?, json);
+        }
+        Command::ChannelPurge { channel_id } => {
+            confirm(yes, &format!("Delete channel {} with all its messages and files? This cannot be undone.", channel_id))?;
+            output::one(&api.purge_channel(&channel_id).await?, json);
+        }
+        Command::GatewayRooms => output::list(&api.rooms().await?, json),
+        Command::GatewayKick { channel_id, user_id } => {
+            confirm(yes, &format!("Disconnect user {} from channel {}?", user_id, channel_id))?;
+            api.kick(&channel_id, &user_id).await?;
+            output::one(&Kicked { channel_id, user_id }, json);
+        }
+    }
+    Ok(())
+}
+
+async fn backup_or_restore(command: Command, database_url: &str, config: &Config, json: bool, yes: bool) -> Result<(), Failure> {
+    let key = config.backup_key.as_deref().filter(|key| !key.is_empty()).ok_or_else(|| {
+        Failure::Usage("no backup key: set UCHAT_BACKUP_KEY or backup_key in the config file".into())
+    })?;
+    let pool = uchat_db::connect_unmigrated(database_url).await?;
+    let store = storage::from_env()?;
+    match command {
+        Command::Backup { out, incremental } => {
+            let done = backup::backup(&pool, store.as_ref(), key.as_bytes(), &out, incremental.as_deref()).await?;
+            if done.vanished > 0 {
+                eprintln!("{} objects were deleted during the backup and are not in it", done.vanished);
+            }
+            output::one(&done, json);
+        }
+        Command::Restore { from, verify_only, force } => {
+            if force && !verify_only {
+                confirm(yes, &format!("Replace the database's contents with the backup in {}?", from.display()))?;
+            }
+            output::one(&backup::restore(&pool, store.as_ref(), key.as_bytes(), &from, verify_only, force).await?, json);
+        }
+        _ => unreachable!("only called for backup and restore"),
+    }
+    Ok(())
+}
+
+async fn migrate(command: Command, database_url: &str) -> Result<(), Failure> {
+    let pool = uchat_db::connect_unmigrated(database_url).await?;
+    let (verb, migrations) = match command {
+        Command::Migrate { to } => ("applied", uchat_db::migrate::run_to(&pool, to).await),
+        _ => ("pending", uchat_db::migrate::pending(&pool).await),
+    };
+    let migrations = migrations.map_err(|e| Failure::Failed(format!("migrate failed: {}", e)))?;
+    if migrations.is_empty() {
+        println!("schema is up to date");
+    }
+    for migration in migrations {
+        println!("{} {:04} {}", verb, migration.version, migration.description);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn command(a: &[&str]) -> Result<Command, String> {
+        parse_args(&args(a)).map(|invocation| invocation.command)
+    }
+
+    #[test]
+    fn parses_migrate_flags() {
+        assert_eq!(command(&["migrate"]), Ok(Command::Migrate { to: None }));
+        assert_eq!(command(&["migrate", "--dry-run"]), Ok(Command::DryRun));
+        assert_eq!(command(&["migrate", "--to", "3"]), Ok(Command::Migrate { to: Some(3) }));
+
+        for bad in [&[][..], &["migrate", "--to"], &["migrate", "--to", "x"], &["migrate", "--dry-run", "--to", "3"], &["seed"]] {
+            assert!(parse_args(&args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn parses_management_commands_and_global_flags() {
+        let user_id = UserId::new();
+        let invocation = parse_args(&args(&["--json", "user", "lock", user_id.as_str(), "--until", "2026-01-01T00:00:00Z", "-y"]));
+        assert_eq!(
+            invocation,
+            Ok(Invocation {
+                command: Command::UserLock { user_id: user_id.clone(), until: Some("2026-01-01T00:00:00Z".parse().unwrap()) },
+                json: true,
+                yes: true,
+                database_url: None,
+            })
+        );
+        assert_eq!(
+            command(&["user", "set-role", user_id.as_str(), "compliance"]),
+            Ok(Command::UserSetRole { user_id: user_id.clone(), role: UserRole::Compliance })
+        );
+        assert_eq!(
+            command(&["user", "activation", user_id.as_str()]),
+            Ok(Command::UserActivation { user_id: user_id.clone() })
+        );
+        assert_eq!(command(&["token", "revoke", "--user", user_id.as_str()]), Ok(Command::TokenRevoke { user_id }));
+        assert_eq!(
+            command(&["backup", "--out", "b2", "--incremental", "b1"]),
+            Ok(Command::Backup { out: "b2".into(), incremental: Some("b1".into()) })
+        );
+        assert_eq!(
+            command(&["restore", "--from", "b2", "--force"]),
+            Ok(Command::Restore { from: "b2".into(), verify_only: false, force: true })
+        );
+
+        let invocation = parse_args(&args(&["user", "create", "ops", "--role", "admin", "--database-url", "postgres://db/uchat"]));
+        assert_eq!(invocation.unwrap().database_url.as_deref(), Some("postgres://db/uchat"));
+
+        for bad in [
+            &["user", "lock", "not-an-id"][..],
+            &["user", "set-role", "00000000-0000-0000-0000-000000000000", "root"],
+            &["channel", "purge"],
+            &["gateway", "kick", "x"],
+            &["restore", "--from", "b2", "--overwrite"],
+            &["user", "list", "--database-url", "postgres://ops:secret@db/uchat"],
+        ] {
+            assert!(parse_args(&args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn refuses_unconfirmed_commands_without_a_terminal() {
+        assert!(confirm(true, "sure?").is_ok());
+        // Tests run with stdin detached from any terminal.
+        if !std::io::stdin().is_terminal() {
+            assert!(matches!(confirm(false, "sure?"), Err(Failure::NotConfirmed)));
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Results as an aligned table for people, or as JSON for scripts.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use uchat_proto::channels::{Channel, RoomSubscribers};
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::users::{AdminUser, ProvisionedUser};
+
+/// Something printed one row per item.
+pub trait Row: Serialize {
+    const HEADERS: &'static [&'static str];
+    fn cells(&self) -> Vec<String>;
+}
+
+/// Prints `items` as a table, or as a JSON array with `json`.
+pub fn list<T: Row>(items: &[T], json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(items).unwrap());
+    } else {
+        print!("{}", table(items));
+    }
+}
+
+/// Prints `item` as a one-row table, or as a JSON object with `json`.
+pub fn one<T: Row>(item: &T, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(item).unwrap());
+    } else {
+        print!("{}", table(std::slice::from_ref(item)));
+    }
+}
+
+fn table<T: Row>(items: &[T]) -> String {
+    let rows: Vec<Vec<String>> = std::iter::once(T::HEADERS.iter().map(|h| h.to_string()).collect())
+        .chain(items.iter().map(Row::cells))
+        .collect();
+    let mut widths = vec![0; T::HEADERS.len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in rows {
+        let line: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<1$}", cell, width)).collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn time(at: Option<DateTime<Utc>>) -> String {
+    at.map_or_else(|| "-".into(), |at| at.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+impl Row for AdminUser {
+    const HEADERS: &'static [&'static str] = &["ID", "USERNAME", "ROLE", "LOCKED", "CREATED", "DELETED"];
+
+    fn cells(&self) -> Vec<String> {
+        let locked = match (self.suspended, self.suspended_until) {
+            (false, _) => "no".into(),
+            (true, None) => "yes".into(),
+            (true, until) => format!("until {}", time(until)),
+        };
+        vec![
+            self.id.to_string(),
+            self.username.clone(),
+            self.role.to_string(),
+            locked,
+            time(Some(self.created_at)),
+            time(self.deleted_at),
+        ]
+    }
+}
+
+impl Row for ProvisionedUser {
+    const HEADERS: &'static [&'static str] = &["ID", "USERNAME", "ROLE", "ACTIVATION TOKEN"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.user.id.to_string(), self.user.username.clone(), self.user.role.to_string(), self.activation_token.clone()]
+    }
+}
+
+impl Row for Channel {
+    const HEADERS: &'static [&'static str] = &["ID", "NAME", "TYPE", "CREATED BY", "ARCHIVED"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.name.clone(),
+            self.channel_type.to_string(),
+            self.created_by.to_string(),
+            time(self.archived_at),
+        ]
+    }
+}
+
+impl Row for RoomSubscribers {
+    const HEADERS: &'static [&'static str] = &["ROOM", "SUBSCRIBERS"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.room_id.to_string(), self.subscribers.to_string()]
+    }
+}
+
+/// What channels-api reports after purging a channel.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Purged {
+    pub channel_id: ChannelId,
+    pub members: u64,
+    pub messages: u64,
+    pub files: u64,
+}
+
+impl Row for Purged {
+    const HEADERS: &'static [&'static str] = &["CHANNEL", "MEMBERS", "MESSAGES", "FILES"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.channel_id.to_string(), self.members.to_string(), self.messages.to_string(), self.files.to_string()]
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revoked {
+    pub user_id: UserId,
+    /// Refresh tokens deleted.
+    pub revoked: u64,
+}
+
+impl Row for Revoked {
+    const HEADERS: &'static [&'static str] = &["USER", "REVOKED"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.user_id.to_string(), self.revoked.to_string()]
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Kicked {
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+}
+
+impl Row for Kicked {
+    const HEADERS: &'static [&'static str] = &["CHANNEL", "KICKED"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![self.channel_id.to_string(), self.user_id.to_string()]
+    }
+}
+
+/// What `backup` wrote.
+#[derive(Debug, Serialize)]
+pub struct BackedUp {
+    pub dir: String,
+    pub tables: usize,
+    pub rows: u64,
+    pub objects: usize,
+    /// Objects copied into this backup rather than left to its base.
+    pub copied: usize,
+    /// Size of the copied objects.
+    pub bytes: u64,
+    /// Objects deleted between being listed and copied.
+    pub vanished: u64,
+}
+
+impl Row for BackedUp {
+    const HEADERS: &'static [&'static str] = &["BACKUP", "TABLES", "ROWS", "OBJECTS", "COPIED", "BYTES", "VANISHED"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.dir.clone(),
+            self.tables.to_string(),
+            self.rows.to_string(),
+            self.objects.to_string(),
+            self.copied.to_string(),
+            self.bytes.to_string(),
+            self.vanished.to_string(),
+        ]
+    }
+}
+
+/// What `restore` checked and, unless only verifying, restored.
+#[derive(Debug, Serialize)]
+pub struct Restored {
+    pub dir: String,
+    pub schema_version: i64,
+    pub tables: usize,
+    pub rows: u64,
+    pub objects: usize,
+    /// Objects that were missing from storage and uploaded.
+    pub uploaded: usize,
+}
+
+impl Row for Restored {
+    const HEADERS: &'static [&'static str] = &["BACKUP", "SCHEMA", "TABLES", "ROWS", "OBJECTS", "UPLOADED"];
+
+    fn cells(&self) -> Vec<String> {
+        vec![
+            self.dir.clone(),
+            self.schema_version.to_string(),
+            self.tables.to_string(),
+            self.rows.to_string(),
+            self.objects.to_string(),
+            self.uploaded.to_string(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligns_columns() {
+        let rows = [
+            Revoked { user_id: UserId::deleted(), revoked: 12 },
+            Revoked { user_id: UserId::deleted(), revoked: 3 },
+        ];
+        let nil = UserId::deleted().to_string();
+        let pad = " ".repeat(nil.len() - "USER".len());
+        assert_eq!(table(&rows), format!("USER{}  REVOKED\n{}  12\n{}  3\n", pad, nil, nil));
+    }
+}
@@ -0,0 +1,28 @@
+//! The file store channels-api writes uploads, thumbnails, avatars and
+//! exports to, picked from the same variables: `FILE_STORAGE` (`local`,
+//! the default, or `s3`), `FILE_STORAGE_DIR` (default `./data/files`) and
+//! the usual `AWS_*` ones.
+
+use std::sync::Arc;
+
+use object_store::aws::AmazonS3Builder;
+use object_store::local::LocalFileSystem;
+use object_store::ObjectStore;
+
+use crate::Failure;
+
+pub fn from_env() -> Result<Arc<dyn ObjectStore>, Failure> {
+    match std::env::var("FILE_STORAGE").unwrap_or_else(|_| "local".into()).as_str() {
+        "local" => {
+            let root = std::env::var("FILE_STORAGE_DIR").unwrap_or_else(|_| "./data/files".into());
+            std::fs::create_dir_all(&root).map_err(|e| Failure::Usage(format!("FILE_STORAGE_DIR {}: {}", root, e)))?;
+            let store = LocalFileSystem::new_with_prefix(&root).map_err(|e| Failure::Usage(e.to_string()))?;
+            Ok(Arc::new(store))
+        }
+        "s3" => {
+            let store = AmazonS3Builder::from_env().build().map_err(|e| Failure::Usage(e.to_string()))?;
+            Ok(Arc::new(store))
+        }
+        other => Err(Failure::Usage(format!("unknown FILE_STORAGE {:?}", other))),
+    }
+}
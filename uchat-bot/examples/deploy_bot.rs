@@ -0,0 +1,54 @@
+//! A deploy bot: `/deploy <service> [env]` for admins and ops, `/ping` for
+//! everyone, and a look at anything mentioning an incident.
+//!
+//! GATEWAY_URL=ws://127.0.0.1:9000/ws BOT_API_KEY=<token> BOT_ROOMS=<id>,<id>
+//! CHANNELS_API_URL=http://127.0.0.1:3001 cargo run -p uchat-bot --example deploy_bot
+
+use uchat_bot::{require_role, Bot, BotConfig, BotError};
+use uchat_proto::ids::ChannelId;
+
+pub fn deploy_bot(config: BotConfig, rooms: Vec<ChannelId>) -> Result<Bot, BotError> {
+    let mut bot = Bot::new(config)?;
+    for room in rooms {
+        bot.join(room);
+    }
+
+    bot.command("deploy", |ctx, args| async move {
+        let Some(service) = args.first() else {
+            return ctx.reply("Usage: /deploy <service> [env]").await;
+        };
+        let env = args.get(1).map(String::as_str).unwrap_or("staging");
+        ctx.reply(format!("Deploying {} to {}.", service, env)).await
+    })
+    .middleware(require_role(&["admin", "ops"]));
+
+    bot.command("ping", |ctx, _| async move { ctx.reply("pong").await });
+
+    bot.on_message(
+        |ctx| ctx.content.to_lowercase().contains("incident"),
+        |ctx| async move {
+            ctx.react("eyes").await?;
+            ctx.send_dm(&ctx.sender, "Paging on-call about your incident report.").await
+        },
+    );
+
+    Ok(bot)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), BotError> {
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let mut config = BotConfig::new(
+        &env("GATEWAY_URL").unwrap_or_else(|| "ws://127.0.0.1:9000/ws".into()),
+        &env("BOT_API_KEY").expect("BOT_API_KEY must be set"),
+    );
+    config.channels_api_url = env("CHANNELS_API_URL");
+    let rooms = env("BOT_ROOMS")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(|id| id.trim().parse().expect("BOT_ROOMS holds channel ids"))
+        .collect();
+
+    deploy_bot(config, rooms)?.run().await
+}
@@ -0,0 +1,331 @@
+//! The bot's socket: connecting, reconnecting, and matching what the
+//! gateway sends back to the messages waiting on it.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use uchat_proto::events::{ClientEvent, ClientFrame, ServerEvent, CURRENT_SCHEMA_VERSION};
+use uchat_proto::ids::{ChannelId, InvocationId, RoomId, UserId};
+
+use crate::{BotConfig, BotError, Context, Handlers};
+
+/// How long `reply` waits for the gateway to send the message out.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A frame handed to whichever socket is up.
+pub(crate) enum Outgoing {
+    Subscribe(RoomId),
+    /// A message someone is waiting on under `cid`. `echo` is the room
+    /// the gateway sends it back out to, if it does.
+    Send { cid: String, echo: Option<RoomId>, event: ClientEvent },
+}
+
+/// What contexts share with the connection.
+pub(crate) struct Link {
+    pub(crate) user_id: UserId,
+    pub(crate) api_key: String,
+    pub(crate) channels_api_url: Option<String>,
+    pub(crate) http: reqwest::Client,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    next_cid: AtomicU64,
+    /// Messages sent or queued but not yet answered, by `cid`.
+    pending: Mutex<HashMap<String, oneshot::Sender<Result<(), BotError>>>>,
+    /// Rooms to subscribe to on every connect.
+    rooms: Mutex<HashSet<RoomId>>,
+    /// Direct message rooms found or created so far, by user.
+    pub(crate) dms: Mutex<HashMap<UserId, RoomId>>,
+}
+
+impl Link {
+    /// Sends `content` to `room_id` and waits for the gateway to send it
+    /// out, which is when the bot's own copy comes back, or refuse it.
+    pub(crate) async fn send(&self, room_id: RoomId, content: String) -> Result<(), BotError> {
+        let event = ClientEvent::SendMessage {
+            room_id: room_id.channel.clone(),
+            content,
+            encrypted: false,
+            content_type: "text/plain".into(),
+            thread_id: room_id.thread.clone(),
+        };
+        self.submit(event, Some(room_id)).await
+    }
+
+    /// Answers slash command `invocation_id` with `text` for its invoker
+    /// alone. Nothing comes back for these, so it returns once sent.
+    pub(crate) async fn respond_ephemeral(&self, invocation_id: InvocationId, text: String) -> Result<(), BotError> {
+        self.submit(ClientEvent::CommandResponse { invocation_id, text, ephemeral: true }, None).await
+    }
+
+    async fn submit(&self, event: ClientEvent, echo: Option<RoomId>) -> Result<(), BotError> {
+        let cid = format!("b-{}", self.next_cid.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(cid.clone(), tx);
+
+        if self.outgoing.send(Outgoing::Send { cid: cid.clone(), echo, event }).is_err() {
+            self.pending.lock().unwrap().remove(&cid);
+            return Err(BotError::Disconnected);
+        }
+
+        match tokio::time::timeout(SEND_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(BotError::Disconnected),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&cid);
+                Err(BotError::Timeout)
+            }
+        }
+    }
+
+    /// Listens in `room_id` from now on, across reconnects.
+    pub(crate) fn subscribe(&self, room_id: RoomId) {
+        if self.rooms.lock().unwrap().insert(room_id.clone()) {
+            let _ = self.outgoing.send(Outgoing::Subscribe(room_id));
+        }
+    }
+
+    fn answer(&self, cid: &str, result: Result<(), BotError>) {
+        if let Some(waiting) = self.pending.lock().unwrap().remove(cid) {
+            let _ = waiting.send(result);
+        }
+    }
+}
+
+/// Why a session ended.
+enum Ended {
+    /// Worth connecting again; `connected` says whether this attempt got
+    /// as far as an open socket.
+    Dropped { connected: bool },
+    Unauthorized,
+}
+
+pub(crate) async fn run(
+    config: BotConfig,
+    user_id: UserId,
+    rooms: Vec<ChannelId>,
+    handlers: Arc<Handlers>,
+) -> Result<(), BotError> {
+    let (outgoing, mut queue) = mpsc::unbounded_channel();
+    let link = Arc::new(Link {
+        user_id,
+        api_key: config.api_key,
+        channels_api_url: config.channels_api_url,
+        http: reqwest::Client::new(),
+        outgoing,
+        next_cid: AtomicU64::new(1),
+        pending: Mutex::new(HashMap::new()),
+        rooms: Mutex::new(rooms.into_iter().map(RoomId::from).collect()),
+        dms: Mutex::new(HashMap::new()),
+    });
+
+    let mut session = Session { link, handlers, last_seq: None };
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match session.run(&config.gateway_url, &mut queue).await {
+            Ended::Unauthorized => return Err(BotError::Unauthorized),
+            Ended::Dropped { connected } => {
+                if connected {
+                    backoff = MIN_BACKOFF;
+                }
+                tracing::warn!(retry_in = ?backoff, "lost the gateway connection");
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+struct Session {
+    link: Arc<Link>,
+    handlers: Arc<Handlers>,
+    /// The highest `seq` seen, to catch up from after a reconnect.
+    last_seq: Option<u64>,
+}
+
+impl Session {
+    async fn run(&mut self, url: &str, queue: &mut mpsc::UnboundedReceiver<Outgoing>) -> Ended {
+        let mut request = match url.into_client_request() {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!(error = %e, "bad gateway URL");
+                return Ended::Dropped { connected: false };
+            }
+        };
+        match format!("Bearer {}", self.link.api_key).parse() {
+            Ok(bearer) => request.headers_mut().insert("Authorization", bearer),
+            Err(_) => return Ended::Unauthorized,
+        };
+        let mut socket = match tokio_tungstenite::connect_async(request).await {
+            Ok((socket, _)) => socket,
+            Err(tungstenite::Error::Http(resp)) if resp.status() == StatusCode::UNAUTHORIZED => {
+                return Ended::Unauthorized;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to connect to the gateway");
+                return Ended::Dropped { connected: false };
+            }
+        };
+        tracing::info!(user_id = %self.link.user_id, "connected to the gateway");
+
+        let hello = ClientEvent::Hello { last_seq: self.last_seq, capabilities: None, format: None };
+        let rooms: Vec<RoomId> = self.link.rooms.lock().unwrap().iter().cloned().collect();
+        let mut setup = vec![frame(None, hello)];
+        setup.extend(rooms.into_iter().map(|room_id| frame(None, ClientEvent::Subscribe { room_id })));
+
+        // Messages of ours in flight to each room, oldest first; their
+        // copies come back in the order they were sent.
+        let mut in_flight: HashMap<RoomId, VecDeque<String>> = HashMap::new();
+        let mut ok = true;
+        // A message nothing will come back for, answered once written.
+        let mut sent = None;
+        for msg in setup {
+            ok = ok && socket.send(msg).await.is_ok();
+        }
+
+        while ok {
+            tokio::select! {
+                outgoing = queue.recv() => {
+                    let msg = match outgoing {
+                        Some(Outgoing::Subscribe(room_id)) => frame(None, ClientEvent::Subscribe { room_id }),
+                        Some(Outgoing::Send { cid, echo, event }) => {
+                            // Given up on while it sat in the queue.
+                            if !self.link.pending.lock().unwrap().contains_key(&cid) {
+                                continue;
+                            }
+                            match echo {
+                                Some(room_id) => in_flight.entry(room_id).or_default().push_back(cid.clone()),
+                                None => sent = Some(cid.clone()),
+                            }
+                            frame(Some(cid), event)
+                        }
+                        // The link holds a sender for as long as we run.
+                        None => break,
+                    };
+                    ok = socket.send(msg).await.is_ok();
+                    if let (true, Some(cid)) = (ok, sent.take()) {
+                        self.link.answer(&cid, Ok(()));
+                    }
+                }
+                msg = socket.next() => match msg {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                        Ok(event) => self.handle(event, &mut in_flight),
+                        Err(e) => tracing::debug!(error = %e, "skipping a frame we don't know"),
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => ok = false,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+
+        // Whatever was sent may or may not have gone out; let the senders
+        // decide whether to try again.
+        for (_, waiting) in self.link.pending.lock().unwrap().drain() {
+            let _ = waiting.send(Err(BotError::Disconnected));
+        }
+        Ended::Dropped { connected: true }
+    }
+
+    fn handle(&mut self, event: ServerEvent, in_flight: &mut HashMap<RoomId, VecDeque<String>>) {
+        match event {
+            ServerEvent::MessageBroadcast {
+                room_id,
+                from,
+                content,
+                encrypted,
+                seq,
+                message_id,
+                sender_role,
+                ..
+            } => {
+                if from == self.link.user_id {
+                    if let Some(cid) = in_flight.get_mut(&room_id).and_then(VecDeque::pop_front) {
+                        self.link.answer(&cid, Ok(()));
+                    }
+                }
+                let ctx = Context {
+                    room_id,
+                    sender: from,
+                    sender_role,
+                    content,
+                    message_id,
+                    seq,
+                    invocation: None,
+                    link: self.link.clone(),
+                };
+                self.receive(ctx, encrypted);
+            }
+            // What came in while we were away.
+            ServerEvent::MessageBatch { messages, truncated } => {
+                if truncated {
+                    tracing::warn!("missed messages the gateway could no longer replay");
+                }
+                for message in messages {
+                    let ctx = Context {
+                        room_id: message.room_id,
+                        sender: message.from,
+                        sender_role: message.sender_role,
+                        content: message.content,
+                        message_id: message.message_id,
+                        seq: Some(message.seq),
+                        invocation: None,
+                        link: self.link.clone(),
+                    };
+                    self.receive(ctx, message.encrypted);
+                }
+            }
+            // A slash command registered to this bot. It never reached the
+            // room, so it isn't sequenced or replayed.
+            ServerEvent::CommandInvoked { invocation } => {
+                let content = format!("/{} {}", invocation.command, invocation.text);
+                let ctx = Context {
+                    room_id: invocation.room_id,
+                    sender: invocation.user_id,
+                    sender_role: invocation.user_role,
+                    content: content.trim_end().to_string(),
+                    message_id: None,
+                    seq: None,
+                    invocation: Some(invocation.id),
+                    link: self.link.clone(),
+                };
+                self.handlers.dispatch(ctx);
+            }
+            ServerEvent::Nack { client_id: Some(cid), code, .. } => {
+                for queue in in_flight.values_mut() {
+                    queue.retain(|c| *c != cid);
+                }
+                self.link.answer(&cid, Err(BotError::Refused(code)));
+            }
+            ServerEvent::Error { code, message, .. } => tracing::warn!(?code, message, "gateway error"),
+            _ => {}
+        }
+    }
+
+    /// Hands a room message to the handlers, unless it is the bot's own,
+    /// one it can't read, or one already handled before a reconnect.
+    fn receive(&mut self, ctx: Context, encrypted: bool) {
+        if let Some(seq) = ctx.seq {
+            if self.last_seq.is_some_and(|last| seq <= last) {
+                return;
+            }
+            self.last_seq = Some(seq);
+        }
+        if ctx.sender == self.link.user_id || encrypted {
+            return;
+        }
+        self.handlers.dispatch(ctx);
+    }
+}
+
+fn frame(cid: Option<String>, event: ClientEvent) -> Message {
+    let frame = ClientFrame { schema_version: CURRENT_SCHEMA_VERSION, cid, ts_gateway: None, correlation_id: None, event };
+    Message::Text(serde_json::to_string(&frame).expect("client frames serialize"))
+}
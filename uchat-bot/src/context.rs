@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use reqwest::{Method, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use uchat_proto::channels::{Channel, ChannelType, CreateChannel};
+use uchat_proto::errors::ApiError;
+use uchat_proto::ids::{InvocationId, MessageId, RoomId, UserId};
+
+use crate::connection::Link;
+use crate::BotError;
+
+/// The message a handler runs for, and what it can do in response.
+#[derive(Clone)]
+pub struct Context {
+    /// Where the message was posted; replies go there too.
+    pub room_id: RoomId,
+    pub sender: UserId,
+    /// The `role` claim of the sender's token.
+    pub sender_role: Option<String>,
+    pub content: String,
+    /// Set for messages channels-api stored, which can be reacted to.
+    pub message_id: Option<MessageId>,
+    pub seq: Option<u64>,
+    /// Set when the gateway routed a registered slash command here
+    /// rather than the message reaching the room.
+    pub invocation: Option<InvocationId>,
+    pub(crate) link: Arc<Link>,
+}
+
+impl Context {
+    /// Posts `text` to the message's room, returning once the gateway has
+    /// sent it out.
+    pub async fn reply(&self, text: impl Into<String>) -> Result<(), BotError> {
+        self.link.send(self.room_id.clone(), text.into()).await
+    }
+
+    /// Shows `text` to the sender alone when answering a routed slash
+    /// command; otherwise it is an ordinary `reply`.
+    pub async fn reply_ephemeral(&self, text: impl Into<String>) -> Result<(), BotError> {
+        match &self.invocation {
+            Some(id) => self.link.respond_ephemeral(id.clone(), text.into()).await,
+            None => self.reply(text).await,
+        }
+    }
+
+    /// Reacts to the message with `emoji`, through channels-api.
+    pub async fn react(&self, emoji: &str) -> Result<(), BotError> {
+        let message_id = self.message_id.as_ref().ok_or(BotError::NoMessageId)?;
+        let path = ["api", "channels", self.room_id.channel.as_str(), "messages", message_id.as_str(), "reactions", emoji];
+        self.link.call::<(), serde_json::Value>(Method::PUT, &path, None).await?;
+        Ok(())
+    }
+
+    /// Posts `text` to a private channel between the bot and `user`,
+    /// creating it on first use.
+    pub async fn send_dm(&self, user: &UserId, text: impl Into<String>) -> Result<(), BotError> {
+        let room = self.link.dm_room(user).await?;
+        self.link.subscribe(room.clone());
+        self.link.send(room, text.into()).await
+    }
+}
+
+impl Link {
+    /// The private channel named `dm-{user}` the bot created for `user`,
+    /// creating it if there is none.
+    async fn dm_room(&self, user: &UserId) -> Result<RoomId, BotError> {
+        if let Some(room) = self.dms.lock().unwrap().get(user) {
+            return Ok(room.clone());
+        }
+
+        let name = format!("dm-{}", user);
+        let channels: Vec<Channel> = self.call::<(), _>(Method::GET, &["api", "channels"], None).await?;
+        let existing = channels
+            .into_iter()
+            .find(|c| c.name == name && c.created_by == self.user_id && c.channel_type == ChannelType::Private);
+        let channel = match existing {
+            Some(channel) => channel,
+            None => {
+                let create = CreateChannel {
+                    name,
+                    description: String::new(),
+                    channel_type: ChannelType::Private,
+                    restrict_file_types: false,
+                    allow_markdown_formatting: false,
+                    e2ee_required: false,
+                    member_ids: vec![user.clone()],
+                };
+                self.call(Method::POST, &["api", "channels"], Some(&create)).await?
+            }
+        };
+
+        let room = RoomId::from(channel.id);
+        self.dms.lock().unwrap().insert(user.clone(), room.clone());
+        Ok(room)
+    }
+
+    /// Calls channels-api as the bot, at the URL made of `path`'s
+    /// segments.
+    async fn call<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &[&str],
+        body: Option<&B>,
+    ) -> Result<T, BotError> {
+        let base = self.channels_api_url.as_deref().ok_or_else(|| BotError::Api("no channels_api_url set".into()))?;
+        let mut url = Url::parse(base).map_err(|e| BotError::Api(e.to_string()))?;
+        url.path_segments_mut()
+            .map_err(|_| BotError::Api(format!("{} can't be a base URL", base)))?
+            .pop_if_empty()
+            .extend(path);
+
+        let mut request = self.http.request(method, url).bearer_auth(&self.api_key);
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let resp = request.send().await.map_err(|e| BotError::Api(e.to_string()))?;
+        let status = resp.status();
+        let bytes = resp.bytes().await.map_err(|e| BotError::Api(e.to_string()))?;
+        if !status.is_success() {
+            let reason = match serde_json::from_slice::<ApiError>(&bytes) {
+                Ok(err) => err.message,
+                Err(_) => status.to_string(),
+            };
+            return Err(BotError::Api(format!("/{}: {}", path.join("/"), reason)));
+        }
+        // Some answers, like 204s, have no body worth reading.
+        let body = if bytes.is_empty() { &b"null"[..] } else { &bytes[..] };
+        serde_json::from_slice(body).map_err(|e| BotError::Api(e.to_string()))
+    }
+}
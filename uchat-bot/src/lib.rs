@@ -0,0 +1,268 @@
+//! Command bots. A `Bot` keeps a socket to the gateway open under its API
+//! key, reconnecting with backoff and catching up on what it missed, and
+//! hands room messages to the handlers registered on it:
+//!
+//! ```no_run
+//! use uchat_bot::{require_role, Bot, BotConfig, BotError};
+//!
+//! # async fn example(room: uchat_proto::ids::ChannelId) -> Result<(), BotError> {
+//! let mut bot = Bot::new(BotConfig::new("ws://127.0.0.1:9000/ws", "<api key>"))?;
+//! bot.join(room);
+//! bot.command("deploy", |ctx, args| async move { ctx.reply(format!("deploying {}", args.join(" "))).await })
+//!     .middleware(require_role(&["admin"]));
+//! bot.on_message(|ctx| ctx.content.contains("incident"), |ctx| async move { ctx.react("eyes").await });
+//! bot.run().await
+//! # }
+//! ```
+//!
+//! The API key is a token for the bot's user, as auth-api issues them:
+//! its `sub` is who the bot posts as and its `rooms` what it may join.
+
+mod connection;
+mod context;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::ids::{ChannelId, UserId};
+
+pub use context::Context;
+
+pub struct BotConfig {
+    /// The gateway's WebSocket endpoint, e.g. `ws://127.0.0.1:9000/ws`.
+    pub gateway_url: String,
+    pub api_key: String,
+    /// channels-api's base URL; `react` and `send_dm` need it.
+    pub channels_api_url: Option<String>,
+}
+
+impl BotConfig {
+    pub fn new(gateway_url: &str, api_key: &str) -> Self {
+        Self { gateway_url: gateway_url.to_string(), api_key: api_key.to_string(), channels_api_url: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum BotError {
+    /// The API key isn't a token, or the gateway refused it.
+    Unauthorized,
+    /// The socket was down, or dropped before the gateway answered.
+    Disconnected,
+    /// The gateway refused the message.
+    Refused(ErrorCode),
+    /// The gateway didn't answer in time; the message may still go out.
+    Timeout,
+    /// A channels-api call failed, or there is no `channels_api_url`.
+    Api(String),
+    /// The message wasn't stored, so there is nothing to react to.
+    NoMessageId,
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Unauthorized => write!(f, "the gateway refused the API key"),
+            BotError::Disconnected => write!(f, "not connected to the gateway"),
+            BotError::Refused(code) => write!(f, "the gateway refused the message: {:?}", code),
+            BotError::Timeout => write!(f, "the gateway didn't answer in time"),
+            BotError::Api(e) => write!(f, "channels-api: {}", e),
+            BotError::NoMessageId => write!(f, "the message has no stored id"),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+type BoxFuture = Pin<Box<dyn Future<Output = Result<(), BotError>> + Send>>;
+type CommandHandler = Arc<dyn Fn(Context, Vec<String>) -> BoxFuture + Send + Sync>;
+type MessageHandler = Arc<dyn Fn(Context) -> BoxFuture + Send + Sync>;
+type Filter = Arc<dyn Fn(&Context) -> bool + Send + Sync>;
+/// Runs before a command's handler; `Err` is shown to the sender instead
+/// of running it.
+type Middleware = Arc<dyn Fn(&Context) -> Result<(), String> + Send + Sync>;
+
+/// A slash command's handler and the checks in front of it.
+#[derive(Clone)]
+pub struct Command {
+    handler: CommandHandler,
+    middleware: Vec<Middleware>,
+}
+
+impl Command {
+    /// Adds a check, run in the order added.
+    pub fn middleware(&mut self, check: impl Fn(&Context) -> Result<(), String> + Send + Sync + 'static) -> &mut Self {
+        self.middleware.push(Arc::new(check));
+        self
+    }
+}
+
+/// Lets a command through only for senders whose token's `role` claim is
+/// one of `roles`.
+pub fn require_role(roles: &[&str]) -> impl Fn(&Context) -> Result<(), String> + Send + Sync + 'static {
+    let roles: Vec<String> = roles.iter().map(|role| role.to_string()).collect();
+    move |ctx| match &ctx.sender_role {
+        Some(role) if roles.contains(role) => Ok(()),
+        _ => Err(format!("Sorry, that needs the {} role.", roles.join(" or "))),
+    }
+}
+
+/// What a bot does with the messages it sees.
+#[derive(Default)]
+struct Handlers {
+    commands: HashMap<String, Command>,
+    triggers: Vec<(Filter, MessageHandler)>,
+}
+
+impl Handlers {
+    /// Runs the command `ctx` invokes, or else every trigger that matches
+    /// it, each in its own task.
+    fn dispatch(&self, ctx: Context) {
+        if let Some((name, args)) = parse_command(&ctx.content) {
+            if let Some(command) = self.commands.get(name) {
+                let command = command.clone();
+                tokio::spawn(async move {
+                    for check in &command.middleware {
+                        if let Err(refusal) = check(&ctx) {
+                            if let Err(e) = ctx.reply_ephemeral(refusal).await {
+                                tracing::warn!(error = %e, "failed to refuse a command");
+                            }
+                            return;
+                        }
+                    }
+                    if let Err(e) = (command.handler)(ctx, args).await {
+                        tracing::warn!(error = %e, "command failed");
+                    }
+                });
+                return;
+            }
+        }
+
+        for (filter, handler) in &self.triggers {
+            if filter(&ctx) {
+                let run = handler(ctx.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = run.await {
+                        tracing::warn!(error = %e, "message handler failed");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// `/name arg arg` as the name and its whitespace-separated arguments.
+fn parse_command(content: &str) -> Option<(&str, Vec<String>)> {
+    let rest = content.strip_prefix('/')?;
+    if rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut words = rest.split_whitespace();
+    let name = words.next()?;
+    Some((name, words.map(str::to_string).collect()))
+}
+
+pub struct Bot {
+    config: BotConfig,
+    user_id: UserId,
+    rooms: Vec<ChannelId>,
+    handlers: Handlers,
+}
+
+impl Bot {
+    /// Fails with `Unauthorized` when the API key isn't a token naming a
+    /// user; whether the gateway accepts it shows once `run` connects.
+    pub fn new(config: BotConfig) -> Result<Self, BotError> {
+        let user_id = token_subject(&config.api_key).ok_or(BotError::Unauthorized)?;
+        Ok(Self { config, user_id, rooms: Vec::new(), handlers: Handlers::default() })
+    }
+
+    /// The user the bot posts as.
+    pub fn user_id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    /// Listens in `room` from when the bot connects.
+    pub fn join(&mut self, room: ChannelId) -> &mut Self {
+        self.rooms.push(room);
+        self
+    }
+
+    /// Runs `handler` for `/name args...`, with the arguments split on
+    /// whitespace. The gateway only routes the command here once it is
+    /// registered there for this bot (`POST /internal/commands`); room
+    /// messages of that form count too. Registering a name again replaces
+    /// it.
+    pub fn command<F, Fut>(&mut self, name: &str, handler: F) -> &mut Command
+    where
+        F: Fn(Context, Vec<String>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), BotError>> + Send + 'static,
+    {
+        let handler: CommandHandler = Arc::new(move |ctx, args| Box::pin(handler(ctx, args)));
+        let command = Command { handler, middleware: Vec::new() };
+        match self.handlers.commands.entry(name.to_string()) {
+            Entry::Occupied(mut entry) => {
+                entry.insert(command);
+                entry.into_mut()
+            }
+            Entry::Vacant(entry) => entry.insert(command),
+        }
+    }
+
+    /// Runs `handler` for each message `filter` accepts that isn't a
+    /// registered command.
+    pub fn on_message<P, F, Fut>(&mut self, filter: P, handler: F) -> &mut Self
+    where
+        P: Fn(&Context) -> bool + Send + Sync + 'static,
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), BotError>> + Send + 'static,
+    {
+        let handler: MessageHandler = Arc::new(move |ctx| Box::pin(handler(ctx)));
+        self.handlers.triggers.push((Arc::new(filter), handler));
+        self
+    }
+
+    /// Connects and handles messages until the gateway refuses the API
+    /// key. Dropped connections are retried, waiting up to a minute
+    /// between attempts.
+    pub async fn run(self) -> Result<(), BotError> {
+        connection::run(self.config, self.user_id, self.rooms, Arc::new(self.handlers)).await
+    }
+}
+
+/// The `sub` claim of a JWT, read without checking the signature; the
+/// gateway does that.
+fn token_subject(token: &str) -> Option<UserId> {
+    let payload = token.split('.').nth(1)?;
+    let claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    claims.get("sub")?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uchat_proto::jwt::create_token;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("/deploy  web api"), Some(("deploy", vec!["web".to_string(), "api".to_string()])));
+        assert_eq!(parse_command("/status"), Some(("status", vec![])));
+        assert_eq!(parse_command("deploy web"), None);
+        assert_eq!(parse_command("/ web"), None);
+        assert_eq!(parse_command("/"), None);
+    }
+
+    #[test]
+    fn reads_the_bot_user_from_the_api_key() {
+        let user = UserId::new();
+        assert_eq!(token_subject(&create_token("secret", user.as_str())), Some(user));
+        assert_eq!(token_subject("not a token"), None);
+        assert!(matches!(Bot::new(BotConfig::new("ws://localhost/ws", "x.y.z")), Err(BotError::Unauthorized)));
+    }
+}
@@ -0,0 +1,287 @@
+//! The example deploy bot, run against an in-process gateway.
+
+#[allow(dead_code)]
+#[path = "../examples/deploy_bot.rs"]
+mod deploy_bot;
+
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use uchat_bot::BotConfig;
+use uchat_proto::channels::{MemberRole, MembershipChange};
+use uchat_proto::commands::{CommandTarget, SlashCommand};
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::ids::{ChannelId, MessageId, UserId};
+use uchat_proto::jwt::create_token_with_role;
+use uchat_proto::messages::MessagePosted;
+use uchat_proto::permissions::{RoomPermissions, RoomRole};
+use uchat_testkit::{spawn_gateway, GatewayConfig, TestGateway, TestWsClient};
+
+fn rooms(grants: &[(&ChannelId, RoomRole)]) -> RoomPermissions {
+    let mut rooms = RoomPermissions::new();
+    for (room, role) in grants {
+        rooms.grant(room.as_str(), *role);
+    }
+    rooms
+}
+
+fn text(room_id: &ChannelId, content: &str) -> ClientEvent {
+    ClientEvent::SendMessage {
+        room_id: room_id.clone(),
+        content: content.into(),
+        encrypted: false,
+        content_type: "text/plain".into(),
+        thread_id: None,
+    }
+}
+
+fn bot_config(gateway: &TestGateway, gateway_url: &str, bot: &UserId, room: &ChannelId) -> BotConfig {
+    BotConfig::new(gateway_url, &gateway.token(bot, rooms(&[(room, RoomRole::Write)])))
+}
+
+/// Registers the example's commands with the gateway, as an admin would,
+/// so it routes them to `bot`.
+async fn register_commands(gateway: &TestGateway, bot: &UserId) {
+    for name in ["deploy", "ping"] {
+        let command = SlashCommand {
+            name: name.into(),
+            description: String::new(),
+            args: Vec::new(),
+            target: CommandTarget::Bot { user_id: bot.clone() },
+        };
+        let resp = reqwest::Client::new()
+            .post(format!("http://{}/internal/commands", gateway.addr()))
+            .header("x-internal-token", "internal-secret")
+            .json(&command)
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+    }
+}
+
+/// Starts the example bot in `room`, returning once `watcher` (already in
+/// the room) sees it join.
+async fn start_bot(config: BotConfig, room: &ChannelId, watcher: &mut TestWsClient) -> JoinHandle<()> {
+    let running = deploy_bot::deploy_bot(config, vec![room.clone()]).unwrap();
+    let bot = running.user_id().clone();
+    let task = tokio::spawn(async move {
+        running.run().await.unwrap();
+    });
+    bot_joined(watcher, &bot, room).await;
+    task
+}
+
+async fn bot_joined(watcher: &mut TestWsClient, bot: &UserId, room: &ChannelId) {
+    watcher
+        .recv_until(|e| matches!(e, ServerEvent::Presence { room_id, user_id, online: true } if room_id == room && user_id == bot))
+        .await;
+}
+
+/// The next message `bot` posts where `client` can see it.
+async fn reply(client: &mut TestWsClient, bot: &UserId) -> (String, String) {
+    match client.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { from, .. } if from == bot)).await {
+        ServerEvent::MessageBroadcast { room_id, content, .. } => (room_id.to_string(), content),
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::test]
+async fn commands_check_the_senders_role() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let (room, bot, alice, bob) = (ChannelId::new(), UserId::new(), UserId::new(), UserId::new());
+
+    let alice_token = create_token_with_role("test-secret", alice.as_str(), rooms(&[(&room, RoomRole::Write)]), "ops");
+    let mut alice_ws = TestWsClient::connect(&gateway.ws_url(), &alice_token).await.unwrap();
+    alice_ws.join(&room).await;
+    let mut bob_ws = gateway.connect(&bob, rooms(&[(&room, RoomRole::Write)])).await;
+    bob_ws.join(&room).await;
+    register_commands(&gateway, &bot).await;
+    let task = start_bot(bot_config(&gateway, &gateway.ws_url(), &bot, &room), &room, &mut alice_ws).await;
+
+    alice_ws.send(text(&room, "/deploy web prod")).await;
+    assert_eq!(reply(&mut alice_ws, &bot).await, (room.to_string(), "Deploying web to prod.".into()));
+
+    // Refusals are shown to the sender alone.
+    assert_eq!(reply(&mut bob_ws, &bot).await.1, "Deploying web to prod.");
+    bob_ws.send(text(&room, "/deploy web")).await;
+    let refusal = bob_ws.recv_until(|e| matches!(e, ServerEvent::CommandResult { .. })).await;
+    let ServerEvent::CommandResult { command, text: refused, .. } = refusal else { unreachable!() };
+    assert_eq!((command.as_str(), refused.as_str()), ("deploy", "Sorry, that needs the admin or ops role."));
+    bob_ws.send(text(&room, "/ping")).await;
+    assert_eq!(reply(&mut bob_ws, &bot).await.1, "pong");
+    let next = alice_ws
+        .recv_until(|e| {
+            assert!(!matches!(e, ServerEvent::CommandResult { .. }), "alice saw bob's refusal");
+            matches!(e, ServerEvent::MessageBroadcast { from, .. } if *from == bot)
+        })
+        .await;
+    assert!(matches!(next, ServerEvent::MessageBroadcast { content, .. } if content == "pong"));
+
+    task.abort();
+}
+
+/// Forwards connections to the gateway until `cut`.
+struct Proxy {
+    addr: std::net::SocketAddr,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    server: JoinHandle<()>,
+}
+
+impl Proxy {
+    async fn start(upstream: std::net::SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let tracked = connections.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let forward = tokio::spawn(async move {
+                    let mut upstream = TcpStream::connect(upstream).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                });
+                tracked.lock().unwrap().push(forward);
+            }
+        });
+        Self { addr, connections, server }
+    }
+
+    /// Drops every connection so far; new ones still go through.
+    fn cut(&self) {
+        for forward in self.connections.lock().unwrap().drain(..) {
+            forward.abort();
+        }
+    }
+}
+
+#[tokio::test]
+async fn reconnects_after_losing_the_gateway() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let proxy = Proxy::start(gateway.addr()).await;
+    let (room, bot, alice) = (ChannelId::new(), UserId::new(), UserId::new());
+
+    let mut alice_ws = gateway.connect(&alice, rooms(&[(&room, RoomRole::Write)])).await;
+    alice_ws.join(&room).await;
+    register_commands(&gateway, &bot).await;
+    let url = format!("ws://{}/ws", proxy.addr);
+    let task = start_bot(bot_config(&gateway, &url, &bot, &room), &room, &mut alice_ws).await;
+
+    alice_ws.send(text(&room, "/ping")).await;
+    assert_eq!(reply(&mut alice_ws, &bot).await.1, "pong");
+
+    proxy.cut();
+    bot_joined(&mut alice_ws, &bot, &room).await;
+    alice_ws.send(text(&room, "/ping again")).await;
+    assert_eq!(reply(&mut alice_ws, &bot).await.1, "pong");
+
+    task.abort();
+    proxy.server.abort();
+}
+
+/// Plays channels-api for what the bot calls: listing and creating
+/// channels, and reacting.
+struct FakeApi {
+    gateway_url: String,
+    bot: UserId,
+    dm: ChannelId,
+    reactions: Mutex<Vec<(String, String, String, String)>>,
+}
+
+async fn create_channel(State(api): State<Arc<FakeApi>>, Json(body): Json<Value>) -> (StatusCode, Json<Value>) {
+    // Like channels-api, tell the gateway the creator may use the channel.
+    let change = MembershipChange { channel_id: api.dm.clone(), user_id: api.bot.clone(), role: Some(MemberRole::Admin) };
+    let resp = reqwest::Client::new()
+        .post(format!("{}/internal/membership", api.gateway_url))
+        .header("x-internal-token", "internal-secret")
+        .json(&change)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    let channel = json!({
+        "id": api.dm,
+        "name": body["name"],
+        "description": "",
+        "channel_type": "private",
+        "created_by": api.bot,
+        "created_at": "2026-01-01T00:00:00Z",
+    });
+    (StatusCode::CREATED, Json(channel))
+}
+
+async fn add_reaction(
+    State(api): State<Arc<FakeApi>>,
+    Path((id, message_id, emoji)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> StatusCode {
+    let auth = headers.get("authorization").unwrap().to_str().unwrap().to_string();
+    api.reactions.lock().unwrap().push((id, message_id, emoji, auth));
+    StatusCode::CREATED
+}
+
+#[tokio::test]
+async fn reacts_to_incidents_and_messages_the_reporter() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let (room, dm, bot, alice) = (ChannelId::new(), ChannelId::new(), UserId::new(), UserId::new());
+
+    let api = Arc::new(FakeApi {
+        gateway_url: format!("http://{}", gateway.addr()),
+        bot: bot.clone(),
+        dm: dm.clone(),
+        reactions: Mutex::new(Vec::new()),
+    });
+    let app = Router::new()
+        .route("/api/channels", get(|| async { Json(json!([])) }).post(create_channel))
+        .route("/api/channels/:id/messages/:message_id/reactions/:emoji", put(add_reaction))
+        .with_state(api.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api_url = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let mut alice_ws = gateway.connect(&alice, rooms(&[(&room, RoomRole::Write), (&dm, RoomRole::Write)])).await;
+    alice_ws.join(&room).await;
+    alice_ws.join(&dm).await;
+    let mut config = bot_config(&gateway, &gateway.ws_url(), &bot, &room);
+    config.channels_api_url = Some(api_url);
+    let api_key = config.api_key.clone();
+    let task = start_bot(config, &room, &mut alice_ws).await;
+
+    // Only stored messages can be reacted to, so post like channels-api.
+    let message_id = MessageId::new();
+    let posted = MessagePosted {
+        id: message_id.clone(),
+        channel_id: room.clone(),
+        sender_id: alice.clone(),
+        sender_name: None,
+        content: "Incident: the database is down".into(),
+        thread_id: None,
+    };
+    let resp = reqwest::Client::new()
+        .post(format!("http://{}/internal/message-posted", gateway.addr()))
+        .header("x-internal-token", "internal-secret")
+        .json(&posted)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    assert_eq!(
+        reply(&mut alice_ws, &bot).await,
+        (dm.to_string(), "Paging on-call about your incident report.".into())
+    );
+    assert_eq!(
+        *api.reactions.lock().unwrap(),
+        [(room.to_string(), message_id.to_string(), "eyes".to_string(), format!("Bearer {}", api_key))]
+    );
+
+    task.abort();
+    server.abort();
+}
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS users (
+    id         TEXT PRIMARY KEY,
+    username   TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_until TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS admin_notes TEXT;
+-- May read the message audit trail.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS is_compliance BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name TEXT;
+-- Avatars live in file storage under avatars/{id}/{version}-{size}.png.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS avatar_version TEXT;
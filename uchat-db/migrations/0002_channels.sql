@@ -0,0 +1,32 @@
+CREATE TABLE IF NOT EXISTS channels (
+    id           TEXT PRIMARY KEY,
+    name         TEXT NOT NULL,
+    description  TEXT NOT NULL DEFAULT '',
+    channel_type TEXT NOT NULL DEFAULT 'public',
+    created_by   TEXT NOT NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS restrict_file_types BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS retention_days INTEGER;
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS archived_at TIMESTAMPTZ;
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS allow_markdown_formatting BOOLEAN NOT NULL DEFAULT false;
+
+-- One channel per name per creator, ignoring case. Channels created
+-- before this was enforced may clash; all but the oldest of each clash
+-- get their id appended to the name first.
+UPDATE channels c
+SET name = c.name || '-' || c.id
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY created_by, lower(name) ORDER BY created_at, id) AS n
+    FROM channels
+) ranked
+WHERE ranked.id = c.id AND ranked.n > 1;
+CREATE UNIQUE INDEX IF NOT EXISTS channels_creator_name_idx ON channels (created_by, lower(name));
+
+CREATE TABLE IF NOT EXISTS channel_members (
+    channel_id TEXT NOT NULL,
+    user_id    TEXT NOT NULL,
+    role       TEXT NOT NULL DEFAULT 'write',
+    PRIMARY KEY (channel_id, user_id)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS user_keys (
+    user_id               TEXT PRIMARY KEY,
+    identity_key          TEXT NOT NULL,
+    signed_prekey         TEXT NOT NULL,
+    signed_prekey_sig     TEXT NOT NULL,
+    one_time_prekeys_json TEXT NOT NULL DEFAULT '[]'
+);
@@ -0,0 +1,40 @@
+CREATE TABLE IF NOT EXISTS messages (
+    id         TEXT PRIMARY KEY,
+    channel_id TEXT NOT NULL,
+    sender_id  TEXT NOT NULL,
+    content    TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    deleted_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS messages_channel_created_idx
+    ON messages (channel_id, created_at DESC, id DESC);
+
+-- E2EE ciphertext is opaque to the server and never indexed.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS encrypted BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS search_vector tsvector
+    GENERATED ALWAYS AS (
+        CASE WHEN encrypted THEN NULL ELSE to_tsvector('english', content) END
+    ) STORED;
+CREATE INDEX IF NOT EXISTS messages_search_idx ON messages USING GIN (search_vector);
+
+-- Soft-deleted messages keep their row as a tombstone with no content.
+ALTER TABLE messages ALTER COLUMN content DROP NOT NULL;
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS edited_at TIMESTAMPTZ;
+
+-- Prior versions of edited messages, newest last; trimmed on each edit.
+CREATE TABLE IF NOT EXISTS message_edits (
+    id          BIGSERIAL PRIMARY KEY,
+    message_id  TEXT NOT NULL,
+    content     TEXT NOT NULL,
+    replaced_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS message_edits_message_idx ON message_edits (message_id, id DESC);
+
+CREATE TABLE IF NOT EXISTS message_reactions (
+    message_id TEXT NOT NULL,
+    user_id    TEXT NOT NULL,
+    emoji      TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (message_id, user_id, emoji)
+);
@@ -0,0 +1,10 @@
+-- last_read_at is the marker message's created_at, kept here so unread
+-- counts survive that message being purged.
+CREATE TABLE IF NOT EXISTS channel_read_markers (
+    user_id              TEXT NOT NULL,
+    channel_id           TEXT NOT NULL,
+    last_read_message_id TEXT NOT NULL,
+    last_read_at         TIMESTAMPTZ NOT NULL,
+    updated_at           TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, channel_id)
+);
@@ -0,0 +1,13 @@
+-- Append-only; deliberately no foreign key to messages.
+CREATE TABLE IF NOT EXISTS message_audit (
+    id          BIGSERIAL PRIMARY KEY,
+    message_id  TEXT NOT NULL,
+    channel_id  TEXT NOT NULL,
+    actor_id    TEXT NOT NULL,
+    action      TEXT NOT NULL,
+    before_hash TEXT,
+    after_hash  TEXT,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS message_audit_message_idx ON message_audit (message_id);
+CREATE INDEX IF NOT EXISTS message_audit_channel_idx ON message_audit (channel_id, id DESC);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS file_uploads (
+    id              TEXT PRIMARY KEY,
+    channel_id      TEXT NOT NULL,
+    uploader_id     TEXT NOT NULL,
+    filename        TEXT NOT NULL,
+    mime_type       TEXT NOT NULL,
+    size_bytes      BIGINT NOT NULL,
+    checksum        TEXT NOT NULL,
+    storage_backend TEXT NOT NULL,
+    storage_path    TEXT NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS file_uploads_uploader_idx ON file_uploads (uploader_id);
+CREATE INDEX IF NOT EXISTS file_uploads_channel_created_idx ON file_uploads (channel_id, created_at);
+ALTER TABLE file_uploads ADD COLUMN IF NOT EXISTS detected_mime_type TEXT;
@@ -0,0 +1,13 @@
+-- remaining_uses is NULL for invites without a use limit.
+CREATE TABLE IF NOT EXISTS channel_invites (
+    id             TEXT PRIMARY KEY,
+    channel_id     TEXT NOT NULL,
+    token_hash     TEXT NOT NULL UNIQUE,
+    created_by     TEXT NOT NULL,
+    created_at     TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at     TIMESTAMPTZ,
+    max_uses       INTEGER,
+    remaining_uses INTEGER,
+    revoked_at     TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS channel_invites_channel_idx ON channel_invites (channel_id);
@@ -0,0 +1,16 @@
+-- Personal data exports. storage_path is set once the archive is written
+-- and cleared when it expires.
+CREATE TABLE IF NOT EXISTS data_exports (
+    id           TEXT PRIMARY KEY,
+    user_id      TEXT NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'pending',
+    storage_path TEXT,
+    error        TEXT,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    completed_at TIMESTAMPTZ,
+    expires_at   TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS data_exports_user_idx ON data_exports (user_id, created_at DESC);
+-- At most one export per user in flight.
+CREATE UNIQUE INDEX IF NOT EXISTS data_exports_active_idx
+    ON data_exports (user_id) WHERE status IN ('pending', 'running');
@@ -0,0 +1,4 @@
+-- Set when the user deletes their account; the row itself is removed
+-- 30 days later.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS users_deleted_idx ON users (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,16 @@
+-- Incoming webhooks post into a channel from outside systems. The
+-- secret is kept as issued, since signatures are checked against it.
+CREATE TABLE IF NOT EXISTS incoming_webhooks (
+    id           TEXT PRIMARY KEY,
+    channel_id   TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    secret       TEXT NOT NULL,
+    created_by   TEXT NOT NULL,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at   TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS incoming_webhooks_channel_idx ON incoming_webhooks (channel_id);
+
+-- Shown for senders that aren't users, such as incoming webhooks, whose
+-- sender_id is the hook's id.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS sender_name TEXT;
@@ -0,0 +1,15 @@
+-- Mobile devices registered for push notifications, with each device's
+-- own settings. A token belongs to one device, so to one user at a time.
+CREATE TABLE IF NOT EXISTS push_tokens (
+    token         TEXT PRIMARY KEY,
+    user_id       TEXT NOT NULL,
+    platform      TEXT NOT NULL,
+    enabled       BOOLEAN NOT NULL DEFAULT true,
+    show_previews BOOLEAN NOT NULL DEFAULT true,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS push_tokens_user_idx ON push_tokens (user_id);
+
+-- Members who want no pushes for the channel.
+ALTER TABLE channel_members ADD COLUMN IF NOT EXISTS push_muted BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,14 @@
+-- Where a user's notification emails go, and which messages they cover:
+-- 'all' (mentions and direct messages), 'mentions' or 'none'.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_notifications TEXT NOT NULL DEFAULT 'mentions';
+
+-- Each message a user has been emailed about, so a restart never sends it
+-- twice. The latest sent_at per user also spaces their emails out.
+CREATE TABLE IF NOT EXISTS email_notification_sends (
+    user_id    TEXT NOT NULL,
+    message_id TEXT NOT NULL,
+    sent_at    TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, message_id)
+);
+CREATE INDEX IF NOT EXISTS email_notification_sends_sent_idx ON email_notification_sends (user_id, sent_at DESC);
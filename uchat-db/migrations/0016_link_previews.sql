@@ -0,0 +1,18 @@
+-- Links in messages unfurled into previews, unless the channel opts out.
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS link_previews BOOLEAN NOT NULL DEFAULT true;
+
+-- Set once the preview worker has taken a message, so it is unfurled once.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS unfurled_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS messages_not_unfurled_idx ON messages (created_at) WHERE unfurled_at IS NULL;
+
+-- Previews by SHA-256 of the URL. Pages that had nothing to show are kept
+-- too, with every field null, so they aren't fetched again until stale.
+CREATE TABLE IF NOT EXISTS link_previews (
+    url_hash    TEXT PRIMARY KEY,
+    url         TEXT NOT NULL,
+    title       TEXT,
+    description TEXT,
+    image_url   TEXT,
+    site_name   TEXT,
+    fetched_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
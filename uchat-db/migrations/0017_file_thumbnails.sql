@@ -0,0 +1,3 @@
+-- Content type of an image upload's thumbnails, set once they are stored;
+-- null while there are none.
+ALTER TABLE file_uploads ADD COLUMN IF NOT EXISTS thumbnail_mime_type TEXT;
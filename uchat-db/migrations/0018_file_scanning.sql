@@ -0,0 +1,18 @@
+-- Uploads wait in pending_scan until the malware scanner clears them;
+-- files from before scanning existed are available.
+ALTER TABLE file_uploads ADD COLUMN IF NOT EXISTS scan_status TEXT NOT NULL DEFAULT 'available'
+    CHECK (scan_status IN ('pending_scan', 'available', 'quarantined'));
+-- The signature found, or why the scan failed, for quarantined files.
+ALTER TABLE file_uploads ADD COLUMN IF NOT EXISTS scan_detail TEXT;
+
+-- Append-only; deliberately no foreign key to file_uploads.
+CREATE TABLE IF NOT EXISTS file_audit (
+    id          BIGSERIAL PRIMARY KEY,
+    file_id     TEXT NOT NULL,
+    channel_id  TEXT NOT NULL,
+    uploader_id TEXT NOT NULL,
+    action      TEXT NOT NULL,
+    detail      TEXT,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS file_audit_file_idx ON file_audit (file_id);
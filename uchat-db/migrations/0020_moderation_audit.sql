@@ -0,0 +1,11 @@
+-- Spam holds placed by the gateway and moderators' decisions on them.
+-- Append-only; actor_id is NULL for the gateway's own flags.
+CREATE TABLE IF NOT EXISTS moderation_audit (
+    id         BIGSERIAL PRIMARY KEY,
+    user_id    TEXT NOT NULL,
+    actor_id   TEXT,
+    action     TEXT NOT NULL,
+    detail     TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS moderation_audit_user_idx ON moderation_audit (user_id);
@@ -0,0 +1,8 @@
+-- Each user's E2EE session store, encrypted on their device with a
+-- passphrase the server never sees. version counts uploads, for If-Match.
+CREATE TABLE IF NOT EXISTS e2ee_backups (
+    user_id    TEXT PRIMARY KEY,
+    blob       BYTEA NOT NULL,
+    version    BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
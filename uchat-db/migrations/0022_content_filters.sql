@@ -0,0 +1,16 @@
+-- Channel admins' content filter rules, enforced by the gateway. A rule
+-- without a replacement blocks matching messages; one with a replacement
+-- redacts the match.
+CREATE TABLE IF NOT EXISTS content_filter_rules (
+    id          BIGSERIAL PRIMARY KEY,
+    channel_id  TEXT NOT NULL,
+    pattern     TEXT NOT NULL,
+    is_regex    BOOLEAN NOT NULL DEFAULT false,
+    replacement TEXT,
+    created_by  TEXT NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS content_filter_rules_channel_idx ON content_filter_rules (channel_id);
+
+-- Set when a content filter rewrote the stored message.
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS redacted BOOLEAN NOT NULL DEFAULT false;
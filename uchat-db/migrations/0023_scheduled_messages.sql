@@ -0,0 +1,19 @@
+-- Messages their authors asked to send later. A row is pending until the
+-- scheduler posts it (sent_at, message_id) or it is cancelled, by the
+-- author or because they can no longer post in the channel.
+CREATE TABLE IF NOT EXISTS scheduled_messages (
+    id            TEXT PRIMARY KEY,
+    channel_id    TEXT NOT NULL,
+    author_id     TEXT NOT NULL,
+    content       TEXT NOT NULL,
+    reply_to      TEXT,
+    send_at       TIMESTAMPTZ NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at       TIMESTAMPTZ,
+    message_id    TEXT,
+    cancelled_at  TIMESTAMPTZ,
+    cancel_reason TEXT
+);
+CREATE INDEX IF NOT EXISTS scheduled_messages_due_idx ON scheduled_messages (send_at)
+    WHERE sent_at IS NULL AND cancelled_at IS NULL;
+CREATE INDEX IF NOT EXISTS scheduled_messages_author_idx ON scheduled_messages (author_id, channel_id);
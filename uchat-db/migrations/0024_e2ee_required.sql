@@ -0,0 +1,3 @@
+-- Channels that refuse plaintext messages. Only settable while the
+-- channel has no messages.
+ALTER TABLE channels ADD COLUMN IF NOT EXISTS e2ee_required BOOLEAN NOT NULL DEFAULT false;
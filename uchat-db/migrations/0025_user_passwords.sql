@@ -0,0 +1,3 @@
+-- PBKDF2 hash of the account's password. Null until the account's first
+-- login, whose password it then becomes; see auth-api's password module.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT;
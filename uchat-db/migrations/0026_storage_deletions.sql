@@ -0,0 +1,7 @@
+-- Objects in file storage that nothing refers to any more, queued by
+-- services without access to the storage (auth-api, erasing an account)
+-- and deleted by channels-api.
+CREATE TABLE IF NOT EXISTS storage_deletions (
+    path      TEXT PRIMARY KEY,
+    queued_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+-- Accounts an admin or an import made for someone else. A login never
+-- claims one by its first password, since whoever tries first need not be
+-- the person it was made for.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS provisioned BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,6 @@
+-- SHA-256 of the one-time token a provisioned account's owner sets its
+-- password with, until it is used or expires.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS activation_token_hash TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS activation_expires_at TIMESTAMPTZ;
+CREATE UNIQUE INDEX IF NOT EXISTS users_activation_token_idx ON users (activation_token_hash)
+    WHERE activation_token_hash IS NOT NULL;
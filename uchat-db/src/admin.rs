@@ -0,0 +1,195 @@
+//! Account management for operators: auth-api's `/admin/users` endpoints,
+//! and `uchat-admin` when it works on the database directly to bootstrap
+//! a deployment.
+
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use uchat_proto::ids::UserId;
+use uchat_proto::users::{AdminUser, ProvisionedUser, UserRole};
+
+/// How long an activation token can set the account's password.
+pub const ACTIVATION_TTL_DAYS: i32 = 7;
+
+const ADMIN_USER_COLUMNS: &str =
+    "id, username, is_admin, is_compliance, suspended, suspended_until, created_at, deleted_at";
+
+#[derive(sqlx::FromRow)]
+struct AdminUserRow {
+    id: UserId,
+    username: String,
+    is_admin: bool,
+    is_compliance: bool,
+    suspended: bool,
+    suspended_until: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+impl From<AdminUserRow> for AdminUser {
+    fn from(row: AdminUserRow) -> Self {
+        AdminUser {
+            id: row.id,
+            username: row.username,
+            role: UserRole::from_flags(row.is_admin, row.is_compliance),
+            suspended: row.suspended,
+            suspended_until: row.suspended_until,
+            created_at: row.created_at,
+            deleted_at: row.deleted_at,
+        }
+    }
+}
+
+/// Every account, deleted ones included, by username.
+pub async fn list_users(pool: &PgPool) -> Result<Vec<AdminUser>, sqlx::Error> {
+    let rows: Vec<AdminUserRow> =
+        sqlx::query_as(&format!("SELECT {} FROM users ORDER BY username", ADMIN_USER_COLUMNS))
+            .fetch_all(pool)
+            .await?;
+    Ok(rows.into_iter().map(AdminUser::from).collect())
+}
+
+pub async fn get_user(pool: &PgPool, user_id: &UserId) -> Result<Option<AdminUser>, sqlx::Error> {
+    let row: Option<AdminUserRow> = sqlx::query_as(&format!("SELECT {} FROM users WHERE id = $1", ADMIN_USER_COLUMNS))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(AdminUser::from))
+}
+
+/// A new one-time activation token, 32 random bytes in hex, and the hash
+/// it is stored as.
+pub fn activation_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).expect("system randomness");
+    let token = hex::encode(bytes);
+    let hash = activation_token_hash(&token);
+    (token, hash)
+}
+
+pub fn activation_token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Registers `username` as a provisioned account, which no login claims by
+/// its first password: its owner sets one with the activation token. `None`
+/// when the name is taken.
+pub async fn create_user(pool: &PgPool, username: &str, role: UserRole) -> Result<Option<ProvisionedUser>, sqlx::Error> {
+    let (is_admin, is_compliance) = role.flags();
+    let (token, token_hash) = activation_token();
+    let row: Option<AdminUserRow> = sqlx::query_as(&format!(
+        "INSERT INTO users (id, username, is_admin, is_compliance, provisioned, activation_token_hash, activation_expires_at)
+         VALUES ($1, $2, $3, $4, true, $5, now() + make_interval(days => $6))
+         ON CONFLICT (username) DO NOTHING
+         RETURNING {}",
+        ADMIN_USER_COLUMNS
+    ))
+    .bind(UserId::new())
+    .bind(username)
+    .bind(is_admin)
+    .bind(is_compliance)
+    .bind(token_hash)
+    .bind(ACTIVATION_TTL_DAYS)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| ProvisionedUser { user: row.into(), activation_token: token }))
+}
+
+/// A new activation token for an account that has no password yet, in
+/// place of any earlier one: for a token that was lost or expired, or an
+/// account from before passwords were stored. `None` when there is no such
+/// user, or they are deleted or have a password.
+pub async fn reissue_activation(pool: &PgPool, user_id: &UserId) -> Result<Option<ProvisionedUser>, sqlx::Error> {
+    let (token, token_hash) = activation_token();
+    let row: Option<AdminUserRow> = sqlx::query_as(&format!(
+        "UPDATE users SET activation_token_hash = $2, activation_expires_at = now() + make_interval(days => $3)
+         WHERE id = $1 AND password_hash IS NULL AND deleted_at IS NULL
+         RETURNING {}",
+        ADMIN_USER_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(token_hash)
+    .bind(ACTIVATION_TTL_DAYS)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| ProvisionedUser { user: row.into(), activation_token: token }))
+}
+
+/// `None` when there is no such user.
+pub async fn set_role(pool: &PgPool, user_id: &UserId, role: UserRole) -> Result<Option<AdminUser>, sqlx::Error> {
+    let (is_admin, is_compliance) = role.flags();
+    let row: Option<AdminUserRow> = sqlx::query_as(&format!(
+        "UPDATE users SET is_admin = $2, is_compliance = $3 WHERE id = $1 RETURNING {}",
+        ADMIN_USER_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(is_admin)
+    .bind(is_compliance)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(AdminUser::from))
+}
+
+/// Suspends the user, until `until` or indefinitely, or lifts their
+/// suspension. `None` when there is no such user.
+pub async fn set_suspended(
+    pool: &PgPool,
+    user_id: &UserId,
+    suspended: bool,
+    until: Option<DateTime<Utc>>,
+) -> Result<Option<AdminUser>, sqlx::Error> {
+    let row: Option<AdminUserRow> = sqlx::query_as(&format!(
+        "UPDATE users SET suspended = $2, suspended_until = $3 WHERE id = $1 RETURNING {}",
+        ADMIN_USER_COLUMNS
+    ))
+    .bind(user_id)
+    .bind(suspended)
+    .bind(until.filter(|_| suspended))
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(AdminUser::from))
+}
+
+/// Deletes the user's refresh tokens, signing out every session once its
+/// access token expires. Returns how many there were.
+pub async fn revoke_refresh_tokens(pool: &PgPool, user_id: &UserId) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1").bind(user_id).execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn creates_and_manages_users() {
+        let Some(pool) = crate::connect_test().await else { return };
+        let name = format!("admin-{}", UserId::new());
+
+        let created = create_user(&pool, &name, UserRole::Compliance).await.unwrap().unwrap();
+        let user = created.user;
+        assert_eq!(user.role, UserRole::Compliance);
+        let stored: Option<String> = sqlx::query_scalar("SELECT activation_token_hash FROM users WHERE id = $1")
+            .bind(&user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, Some(activation_token_hash(&created.activation_token)));
+        let reissued = reissue_activation(&pool, &user.id).await.unwrap().unwrap();
+        assert_ne!(reissued.activation_token, created.activation_token);
+        assert!(create_user(&pool, &name, UserRole::User).await.unwrap().is_none());
+        assert!(list_users(&pool).await.unwrap().iter().any(|u| u.id == user.id));
+
+        let user = set_role(&pool, &user.id, UserRole::Admin).await.unwrap().unwrap();
+        assert_eq!(user.role, UserRole::Admin);
+        let user = set_suspended(&pool, &user.id, true, None).await.unwrap().unwrap();
+        assert!(user.suspended);
+        let user = set_suspended(&pool, &user.id, false, Some(Utc::now())).await.unwrap().unwrap();
+        assert!(!user.suspended && user.suspended_until.is_none());
+        assert_eq!(get_user(&pool, &user.id).await.unwrap(), Some(user));
+
+        assert!(set_role(&pool, &UserId::new(), UserRole::User).await.unwrap().is_none());
+    }
+}
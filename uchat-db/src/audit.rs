@@ -0,0 +1,96 @@
+//! Message, file and moderation audit trails. Entries are written with
+//! the mutation they describe, on the same connection, so a committed
+//! change always has its row; no table has a foreign key to what it
+//! audits, so purging a message or file leaves its history intact.
+
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+
+use uchat_proto::audit::{FileAction, MessageAction, ModerationAction};
+use uchat_proto::ids::{ChannelId, FileId, MessageId, UserId};
+
+pub struct MessageAudit<'a> {
+    pub message_id: &'a MessageId,
+    pub channel_id: &'a ChannelId,
+    pub actor_id: &'a UserId,
+    pub action: MessageAction,
+    /// Content before the change; `None` for creations.
+    pub before: Option<&'a str>,
+    /// Content after the change; `None` for deletions.
+    pub after: Option<&'a str>,
+}
+
+/// Hex SHA-256 of message content, as stored in the audit trail.
+pub fn content_hash(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+/// Appends an audit row. Pass the transaction performing the mutation.
+pub async fn record_message(conn: &mut PgConnection, entry: MessageAudit<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO message_audit (message_id, channel_id, actor_id, action, before_hash, after_hash)
+         VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(entry.message_id)
+    .bind(entry.channel_id)
+    .bind(entry.actor_id)
+    .bind(entry.action.as_str())
+    .bind(entry.before.map(content_hash))
+    .bind(entry.after.map(content_hash))
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub struct FileAudit<'a> {
+    pub file_id: &'a FileId,
+    pub channel_id: &'a ChannelId,
+    /// Whose upload it is; file actions are taken by the service itself.
+    pub uploader_id: &'a UserId,
+    pub action: FileAction,
+    /// Such as the signature a scanner found.
+    pub detail: Option<&'a str>,
+}
+
+/// Appends a file audit row. Pass the transaction performing the change.
+pub async fn record_file(conn: &mut PgConnection, entry: FileAudit<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO file_audit (file_id, channel_id, uploader_id, action, detail)
+         VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(entry.file_id)
+    .bind(entry.channel_id)
+    .bind(entry.uploader_id)
+    .bind(entry.action.as_str())
+    .bind(entry.detail)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+pub struct ModerationAudit<'a> {
+    pub user_id: &'a UserId,
+    /// The moderator; `None` when the gateway flagged the user itself.
+    pub actor_id: Option<&'a UserId>,
+    pub action: ModerationAction,
+    /// Such as the checks the user tripped.
+    pub detail: Option<&'a str>,
+}
+
+/// Appends a moderation audit row.
+pub async fn record_moderation(conn: &mut PgConnection, entry: ModerationAudit<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO moderation_audit (user_id, actor_id, action, detail)
+         VALUES ($1, $2, $3, $4)",
+    )
+    .bind(entry.user_id)
+    .bind(entry.actor_id)
+    .bind(entry.action.as_str())
+    .bind(entry.detail)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+//! A throwaway Postgres in Docker for DB-backed tests, behind the
+//! `it-docker` feature.
+//!
+//! One container serves the whole test binary. Every `#[tokio::test]` has
+//! its own runtime, so the container can't be dropped by whichever test
+//! started it; it is removed with `docker rm` when the process exits.
+
+use std::sync::OnceLock;
+
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::AsyncRunner;
+use testcontainers_modules::testcontainers::ImageExt;
+use tokio::sync::OnceCell;
+
+static URL: OnceCell<Option<String>> = OnceCell::const_new();
+static CONTAINER_ID: OnceLock<String> = OnceLock::new();
+
+/// URL of the container's database, starting it on first use. `None` when
+/// Docker isn't reachable.
+pub async fn database_url() -> Option<String> {
+    URL.get_or_init(start).await.clone()
+}
+
+async fn start() -> Option<String> {
+    let container = match Postgres::default().with_tag("16-alpine").start().await {
+        Ok(container) => container,
+        Err(e) => {
+            eprintln!("it-docker: cannot start Postgres in Docker ({}), skipping", e);
+            return None;
+        }
+    };
+    CONTAINER_ID.set(container.id().to_string()).unwrap();
+    // SAFETY: `remove_container` is a plain `extern "C"` fn that doesn't
+    // unwind.
+    unsafe { libc::atexit(remove_container) };
+
+    let host = container.get_host().await.expect("container host");
+    let port = container.get_host_port_ipv4(5432).await.expect("container port");
+    std::mem::forget(container);
+    Some(format!("postgres://postgres:postgres@{}:{}/postgres", host, port))
+}
+
+extern "C" fn remove_container() {
+    if let Some(id) = CONTAINER_ID.get() {
+        let _ = std::process::Command::new("docker")
+            .args(["rm", "--force", "--volumes", id])
+            .stdout(std::process::Stdio::null())
+            .status();
+    }
+}
@@ -0,0 +1,52 @@
+//! Postgres schema and pool setup shared by the services that persist
+//! users, channels, messages, files and keys, plus the audit trails and
+//! account management queries. The schema itself lives in `migrations/`;
+//! see `migrate`.
+
+use sqlx::postgres::{PgPool, PgPoolOptions};
+
+pub mod admin;
+pub mod audit;
+#[cfg(feature = "it-docker")]
+mod docker;
+pub mod migrate;
+
+/// Pool for a service, with pending migrations applied first unless
+/// `UCHAT_AUTO_MIGRATE=false`.
+pub async fn connect(url: &str) -> Result<PgPool, sqlx::Error> {
+    let pool = connect_unmigrated(url).await?;
+    if migrate::auto_migrate_from_env() {
+        migrate::MIGRATOR.run(&pool).await?;
+    }
+    Ok(pool)
+}
+
+/// Pool that leaves the schema alone, for `uchat-admin`.
+pub async fn connect_unmigrated(url: &str) -> Result<PgPool, sqlx::Error> {
+    PgPoolOptions::new().max_connections(10).connect(url).await
+}
+
+/// Pool for DB-backed tests, or `None` (and the test skips) when there is
+/// no test database; see `test_database_url`.
+pub async fn connect_test() -> Option<PgPool> {
+    let url = test_database_url().await?;
+    let pool = connect_unmigrated(&url).await.expect("connect to the test database");
+    migrate::MIGRATOR.run(&pool).await.expect("migrate the test database");
+    Some(pool)
+}
+
+/// `TEST_DATABASE_URL`, or with the `it-docker` feature a throwaway
+/// Postgres container started for the test binary. `None` when neither is
+/// available.
+pub async fn test_database_url() -> Option<String> {
+    if let Some(url) = std::env::var("TEST_DATABASE_URL").ok().filter(|u| !u.is_empty()) {
+        return Some(url);
+    }
+    #[cfg(feature = "it-docker")]
+    return docker::database_url().await;
+    #[cfg(not(feature = "it-docker"))]
+    {
+        eprintln!("TEST_DATABASE_URL not set, skipping");
+        None
+    }
+}
@@ -0,0 +1,252 @@
+//! Schema migrations, embedded from `uchat-db/migrations/` at build time.
+//!
+//! Services apply everything pending on startup unless
+//! `UCHAT_AUTO_MIGRATE=false`, in which case operators run
+//! `uchat-admin migrate` themselves. Applied migrations are checksummed,
+//! so schema changes always go in a new file rather than an edit to an
+//! existing one.
+
+use std::collections::HashMap;
+
+use sqlx::migrate::{AppliedMigration, Migrate, MigrateError, Migration, Migrator};
+use sqlx::PgConnection;
+use sqlx::postgres::PgPool;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Whether services should migrate on startup; on unless
+/// `UCHAT_AUTO_MIGRATE` is `false` or `0`.
+pub fn auto_migrate_from_env() -> bool {
+    !matches!(std::env::var("UCHAT_AUTO_MIGRATE").as_deref(), Ok("false") | Ok("0"))
+}
+
+/// Migrations not yet applied to the database, oldest first. Read-only:
+/// unlike `run_to` this doesn't create the bookkeeping table.
+pub async fn pending(pool: &PgPool) -> Result<Vec<&'static Migration>, MigrateError> {
+    let mut conn = pool.acquire().await?;
+    let tracked: bool = sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+        .fetch_one(&mut *conn)
+        .await?;
+    let applied = if tracked { checked_applied(&mut conn).await? } else { HashMap::new() };
+    Ok(up_migrations().filter(|m| !applied.contains_key(&m.version)).collect())
+}
+
+/// Applies pending migrations up to and including `target` (all of them
+/// when `None`) and returns the ones it ran. Holds the migration lock
+/// throughout, so it is safe alongside services migrating on startup.
+pub async fn run_to(pool: &PgPool, target: Option<i64>) -> Result<Vec<&'static Migration>, MigrateError> {
+    if let Some(version) = target.filter(|v| !MIGRATOR.version_exists(*v)) {
+        return Err(MigrateError::VersionMissing(version));
+    }
+
+    let mut conn = pool.acquire().await?;
+    conn.lock().await?;
+    let result = apply_pending(&mut conn, target).await;
+    conn.unlock().await?;
+    result
+}
+
+async fn apply_pending(conn: &mut PgConnection, target: Option<i64>) -> Result<Vec<&'static Migration>, MigrateError> {
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(MigrateError::Dirty(version));
+    }
+    let applied = checked_applied(conn).await?;
+
+    let mut ran = Vec::new();
+    for migration in up_migrations() {
+        if target.is_some_and(|t| migration.version > t) {
+            break;
+        }
+        if !applied.contains_key(&migration.version) {
+            conn.apply(migration).await?;
+            ran.push(migration);
+        }
+    }
+    Ok(ran)
+}
+
+/// Applied migrations by version, after checking each still exists here
+/// with the checksum it was applied with.
+async fn checked_applied(conn: &mut PgConnection) -> Result<HashMap<i64, AppliedMigration>, MigrateError> {
+    let applied = conn.list_applied_migrations().await?;
+    for migration in &applied {
+        match up_migrations().find(|m| m.version == migration.version) {
+            None => return Err(MigrateError::VersionMissing(migration.version)),
+            Some(m) if m.checksum != migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(applied.into_iter().map(|m| (m.version, m)).collect())
+}
+
+fn up_migrations() -> impl Iterator<Item = &'static Migration> {
+    MIGRATOR.iter().filter(|m| !m.migration_type.is_down_migration())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use sqlx::{ConnectOptions, Executor};
+
+    /// An empty database next to `TEST_DATABASE_URL`'s, dropped by the
+    /// caller via `drop_db`. `None` when the URL is unset.
+    async fn fresh_db() -> Option<(PgPool, PgConnectOptions, String)> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let url = crate::test_database_url().await?;
+        let options = PgConnectOptions::from_str(&url).expect("parse the test database URL");
+        let name = format!("uchat_migrate_{}_{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+
+        let mut admin = options.connect().await.expect("connect to the test database");
+        admin.execute(format!("CREATE DATABASE {}", name).as_str()).await.unwrap();
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .connect_with(options.clone().database(&name))
+            .await
+            .unwrap();
+        Some((pool, options, name))
+    }
+
+    async fn drop_db(pool: PgPool, options: PgConnectOptions, name: String) {
+        pool.close().await;
+        let mut admin = options.connect().await.unwrap();
+        admin.execute(format!("DROP DATABASE {} WITH (FORCE)", name).as_str()).await.unwrap();
+    }
+
+    /// Touches every column the services read or write, so a migration
+    /// that drops or renames one fails here rather than at runtime.
+    const QUERIES: &[&str] = &[
+        "SELECT id, username, created_at, is_admin, suspended, suspended_until, admin_notes,
+                is_compliance, display_name, avatar_version, bio, deleted_at
+         FROM users WHERE id = $1",
+        "SELECT id, name, description, channel_type, created_by, created_at, restrict_file_types,
+                retention_days, archived_at, allow_markdown_formatting, link_previews, e2ee_required
+         FROM channels WHERE created_by = $1 AND lower(name) = lower($2)",
+        "INSERT INTO channel_members (channel_id, user_id, role) VALUES ($1, $2, $3)
+         ON CONFLICT (channel_id, user_id) DO UPDATE SET role = EXCLUDED.role",
+        "SELECT user_id, identity_key, signed_prekey, signed_prekey_sig, one_time_prekeys_json
+         FROM user_keys WHERE user_id = $1",
+        "SELECT id, channel_id, sender_id, content, created_at, deleted_at, encrypted, edited_at
+         FROM messages WHERE channel_id = $1 ORDER BY created_at DESC, id DESC LIMIT $2",
+        "SELECT id, ts_headline('english', content, q) FROM messages, websearch_to_tsquery('english', $1) q
+         WHERE search_vector @@ q",
+        "INSERT INTO message_edits (message_id, content) VALUES ($1, $2) RETURNING id, replaced_at",
+        "SELECT message_id, emoji, COUNT(*), bool_or(user_id = $2)
+         FROM message_reactions WHERE message_id = ANY($1) GROUP BY message_id, emoji",
+        "INSERT INTO channel_read_markers (user_id, channel_id, last_read_message_id, last_read_at)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (user_id, channel_id) DO UPDATE SET updated_at = now()",
+        "INSERT INTO message_audit (message_id, channel_id, actor_id, action, before_hash, after_hash)
+         VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, created_at",
+        "SELECT id, channel_id, uploader_id, filename, mime_type, size_bytes, checksum,
+                storage_backend, storage_path, created_at, detected_mime_type, thumbnail_mime_type,
+                scan_status, scan_detail
+         FROM file_uploads WHERE uploader_id = $1",
+        "INSERT INTO file_audit (file_id, channel_id, uploader_id, action, detail)
+         VALUES ($1, $2, $3, $4, $5) RETURNING id, created_at",
+        "SELECT id, channel_id, token_hash, created_by, created_at, expires_at, max_uses,
+                remaining_uses, revoked_at
+         FROM channel_invites WHERE token_hash = $1 FOR UPDATE",
+        "SELECT id, user_id, status, storage_path, error, created_at, completed_at, expires_at
+         FROM data_exports WHERE user_id = $1 ORDER BY created_at DESC",
+        "SELECT url, secret FROM webhooks WHERE $1 = ANY(events)",
+        "SELECT user_id, created_at FROM refresh_tokens WHERE token_hash = $1 AND expires_at > now()",
+        "UPDATE messages SET unfurled_at = now() WHERE id = $1 AND unfurled_at IS NULL",
+        "SELECT url, title, description, image_url, site_name FROM link_previews
+         WHERE url_hash = $1 AND fetched_at > $2",
+        "INSERT INTO moderation_audit (user_id, actor_id, action, detail)
+         VALUES ($1, $2, $3, $4) RETURNING id, created_at",
+        "SELECT blob, version, updated_at FROM e2ee_backups WHERE user_id = $1",
+        "SELECT id, channel_id, pattern, is_regex, replacement, created_by, created_at
+         FROM content_filter_rules WHERE channel_id = $1 ORDER BY id",
+        "UPDATE messages SET content = $2, redacted = true WHERE id = $1",
+        "SELECT id, channel_id, author_id, content, reply_to, send_at, created_at FROM scheduled_messages
+         WHERE sent_at IS NULL AND cancelled_at IS NULL AND send_at <= now()
+         ORDER BY send_at LIMIT $1 FOR UPDATE SKIP LOCKED",
+        "UPDATE scheduled_messages SET sent_at = now(), message_id = $2 WHERE id = $1",
+    ];
+
+    #[tokio::test]
+    async fn migrates_a_clean_database() {
+        let Some((pool, options, name)) = fresh_db().await else { return };
+
+        assert_eq!(pending(&pool).await.unwrap().len(), MIGRATOR.iter().count());
+        let ran = run_to(&pool, None).await.unwrap();
+        assert_eq!(ran.len(), MIGRATOR.iter().count());
+        assert!(pending(&pool).await.unwrap().is_empty());
+        assert!(run_to(&pool, None).await.unwrap().is_empty());
+        // Startup migrations agree with the CLI's bookkeeping.
+        MIGRATOR.run(&pool).await.unwrap();
+
+        for query in QUERIES {
+            if let Err(e) = pool.prepare(query).await {
+                panic!("{}\n{}", e, query);
+            }
+        }
+        drop_db(pool, options, name).await;
+    }
+
+    #[tokio::test]
+    async fn migrates_up_to_a_target_version() {
+        let Some((pool, options, name)) = fresh_db().await else { return };
+
+        let ran = run_to(&pool, Some(2)).await.unwrap();
+        assert_eq!(ran.iter().map(|m| m.version).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(pending(&pool).await.unwrap()[0].version, 3);
+        assert!(pool.prepare("SELECT id FROM channels").await.is_ok());
+        assert!(pool.prepare("SELECT id FROM messages").await.is_err());
+
+        assert!(matches!(run_to(&pool, Some(9999)).await, Err(MigrateError::VersionMissing(9999))));
+        run_to(&pool, None).await.unwrap();
+        assert!(pending(&pool).await.unwrap().is_empty());
+        drop_db(pool, options, name).await;
+    }
+
+    #[tokio::test]
+    async fn renames_clashing_channels_before_enforcing_unique_names() {
+        let Some((pool, options, name)) = fresh_db().await else { return };
+
+        pool.execute(
+            "CREATE TABLE channels (
+                 id TEXT PRIMARY KEY, name TEXT NOT NULL, description TEXT NOT NULL DEFAULT '',
+                 channel_type TEXT NOT NULL DEFAULT 'public', created_by TEXT NOT NULL,
+                 created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+             );
+             INSERT INTO channels (id, name, created_by, created_at) VALUES
+                 ('c1', 'General', 'u1', now() - interval '1 day'),
+                 ('c2', 'general', 'u1', now()),
+                 ('c3', 'general', 'u2', now());",
+        )
+        .await
+        .unwrap();
+        run_to(&pool, None).await.unwrap();
+
+        let names: Vec<(String, String)> = sqlx::query_as("SELECT id, name FROM channels ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let names: Vec<(&str, &str)> = names.iter().map(|(id, name)| (id.as_str(), name.as_str())).collect();
+        assert_eq!(names, [("c1", "General"), ("c2", "general-c2"), ("c3", "general")]);
+        drop_db(pool, options, name).await;
+    }
+
+    #[tokio::test]
+    async fn adopts_a_schema_created_before_migrations() {
+        let Some((pool, options, name)) = fresh_db().await else { return };
+
+        // Databases predating the migrations table already have every
+        // table; the migrations are idempotent, so they just get recorded.
+        for migration in MIGRATOR.iter() {
+            pool.execute(&*migration.sql).await.unwrap();
+        }
+        run_to(&pool, None).await.unwrap();
+        assert!(pending(&pool).await.unwrap().is_empty());
+        drop_db(pool, options, name).await;
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::Message;
+
+use uchat_proto::events::{ClientEvent, ClientFrame, ServerEvent, CURRENT_SCHEMA_VERSION};
+use uchat_proto::ids::ChannelId;
+
+use crate::stats::Latencies;
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Marks the messages this tool sends, so other traffic in a room is
+/// ignored.
+const PAYLOAD_PREFIX: &str = "loadgen";
+
+/// What one simulated client does.
+pub struct Plan {
+    pub index: u32,
+    /// WebSocket URL including the client's token.
+    pub url: String,
+    pub room: ChannelId,
+    /// Time between messages; `None` to only listen.
+    pub send_every: Option<Duration>,
+    /// When to stop sending.
+    pub stop_at: Instant,
+    /// When to stop listening for messages still in flight.
+    pub close_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    /// When the handshake finished; `None` if it failed.
+    pub connected_at: Option<Instant>,
+    /// The gateway closed the socket before `close_at`.
+    pub disconnected: bool,
+    pub sent: u64,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub errors: u64,
+    pub latencies: Latencies,
+}
+
+pub fn unix_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64)
+}
+
+/// Message content carrying who sent it, its sequence number and when,
+/// for the receivers to measure.
+pub fn payload(sender: u32, seq: u64, sent_micros: u64) -> String {
+    format!("{}:{}:{}:{}", PAYLOAD_PREFIX, sender, seq, sent_micros)
+}
+
+pub fn parse_payload(content: &str) -> Option<(u32, u64, u64)> {
+    let mut parts = content.strip_prefix(PAYLOAD_PREFIX)?.strip_prefix(':')?.split(':');
+    let sender = parts.next()?.parse().ok()?;
+    let seq = parts.next()?.parse().ok()?;
+    let sent = parts.next()?.parse().ok()?;
+    parts.next().is_none().then_some((sender, seq, sent))
+}
+
+/// The last sequence number seen from each sender, to spot messages the
+/// gateway skipped. It doesn't say when a subscriber lags, so gaps are
+/// the only sign.
+#[derive(Default)]
+struct Gaps {
+    last: HashMap<u32, u64>,
+}
+
+impl Gaps {
+    /// How many of `sender`'s messages were missed before `seq`. The first
+    /// message seen from a sender only sets the baseline.
+    fn observe(&mut self, sender: u32, seq: u64) -> u64 {
+        match self.last.insert(sender, seq) {
+            Some(last) if seq > last => seq - last - 1,
+            Some(last) => {
+                // Out of order or repeated; keep the furthest point.
+                self.last.insert(sender, last);
+                0
+            }
+            None => 0,
+        }
+    }
+}
+
+pub fn frame(event: ClientEvent) -> Message {
+    let frame = ClientFrame { schema_version: CURRENT_SCHEMA_VERSION, cid: None, ts_gateway: None, correlation_id: None, event };
+    Message::Text(serde_json::to_string(&frame).unwrap())
+}
+
+/// Connects, joins the plan's room and sends on schedule until `stop_at`,
+/// measuring every broadcast it receives until `close_at`.
+pub async fn run(plan: Plan) -> Outcome {
+    let mut outcome = Outcome::default();
+    let socket = match tokio::time::timeout(CONNECT_TIMEOUT, tokio_tungstenite::connect_async(&plan.url)).await {
+        Ok(Ok((socket, _))) => socket,
+        Ok(Err(_)) | Err(_) => return outcome,
+    };
+    outcome.connected_at = Some(Instant::now());
+    let (mut write, mut read) = socket.split();
+
+    if write.send(frame(ClientEvent::Subscribe { room_id: plan.room.clone().into() })).await.is_err() {
+        outcome.disconnected = true;
+        return outcome;
+    }
+
+    // Listeners never tick; the period only has to be valid.
+    let mut ticker = tokio::time::interval(plan.send_every.unwrap_or(Duration::from_secs(3600)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let sending = plan.send_every.is_some();
+    let mut gaps = Gaps::default();
+    let close = tokio::time::sleep_until(plan.close_at.into());
+    tokio::pin!(close);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if sending && Instant::now() < plan.stop_at => {
+                let event = ClientEvent::SendMessage {
+                    room_id: plan.room.clone(),
+                    content: payload(plan.index, outcome.sent, unix_micros()),
+                    encrypted: false,
+                    content_type: "text/plain".into(),
+                    thread_id: None,
+                };
+                if write.send(frame(event)).await.is_err() {
+                    outcome.disconnected = true;
+                    break;
+                }
+                outcome.sent += 1;
+            }
+
+            msg = read.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        outcome.disconnected = true;
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                match serde_json::from_str::<ServerEvent>(&text) {
+                    Ok(ServerEvent::MessageBroadcast { content, .. }) => {
+                        let Some((sender, seq, sent)) = parse_payload(&content) else { continue };
+                        outcome.delivered += 1;
+                        outcome.dropped += gaps.observe(sender, seq);
+                        outcome.latencies.record(unix_micros().saturating_sub(sent));
+                    }
+                    Ok(ServerEvent::Error { .. } | ServerEvent::Nack { .. }) => outcome.errors += 1,
+                    _ => {}
+                }
+            }
+
+            _ = &mut close => break,
+        }
+    }
+
+    let _ = write.send(Message::Close(None)).await;
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_roundtrip() {
+        assert_eq!(parse_payload(&payload(7, 42, 1_700_000_000_000_000)), Some((7, 42, 1_700_000_000_000_000)));
+        for other in ["hello", "loadgen:1:2", "loadgen:1:2:3:4", "loadgen:a:2:3", "xloadgen:1:2:3"] {
+            assert_eq!(parse_payload(other), None, "{}", other);
+        }
+    }
+
+    #[test]
+    fn counts_gaps_per_sender() {
+        let mut gaps = Gaps::default();
+        assert_eq!(gaps.observe(1, 5), 0);
+        assert_eq!(gaps.observe(1, 6), 0);
+        assert_eq!(gaps.observe(2, 0), 0);
+        assert_eq!(gaps.observe(1, 9), 2);
+        assert_eq!(gaps.observe(1, 8), 0);
+        assert_eq!(gaps.observe(1, 10), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! A stand-in for the gateway's room fan-out, for tests.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_tungstenite::tungstenite::Message;
+
+use uchat_proto::events::{ClientEvent, ClientFrame, ServerEvent};
+use uchat_proto::ids::{RoomId, UserId};
+
+use crate::soak::Gateway;
+
+type Members = Arc<Mutex<Vec<(RoomId, mpsc::UnboundedSender<Message>)>>>;
+
+/// Answers `Hello` with an empty `MessageBatch`, announces each
+/// `Subscribe` with a `Presence` to the room, and broadcasts every
+/// `SendMessage` to the room's members, numbered like the gateway does.
+/// A restart forgets every socket and number.
+pub struct FakeGateway {
+    addr: SocketAddr,
+    server: JoinHandle<()>,
+}
+
+impl FakeGateway {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        Self { addr, server: tokio::spawn(serve(listener)) }
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://{}/ws", self.addr)
+    }
+}
+
+impl Drop for FakeGateway {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl Gateway for FakeGateway {
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+
+    async fn restart(&mut self) -> io::Result<()> {
+        self.server.abort();
+        let _ = (&mut self.server).await;
+        self.server = tokio::spawn(serve(TcpListener::bind(self.addr).await?));
+        Ok(())
+    }
+}
+
+async fn serve(listener: TcpListener) {
+    let members = Members::default();
+    let seq = Arc::new(AtomicU64::new(1));
+    // Dropped with this task, taking every connection with it.
+    let mut connections = JoinSet::new();
+    while let Ok((stream, _)) = listener.accept().await {
+        connections.spawn(handle(stream, members.clone(), seq.clone()));
+    }
+}
+
+async fn handle(stream: TcpStream, members: Members, seq: Arc<AtomicU64>) {
+    let Ok(socket) = tokio_tungstenite::accept_async(stream).await else { return };
+    let (mut write, mut read) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let to_room = |room_id: &RoomId, event: &ServerEvent| {
+        let json = serde_json::to_string(event).unwrap();
+        for (room, member) in members.lock().unwrap().iter() {
+            if room == room_id {
+                let _ = member.send(Message::Text(json.clone()));
+            }
+        }
+    };
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                if write.send(msg.unwrap()).await.is_err() {
+                    return;
+                }
+            }
+
+            msg = read.next() => {
+                let Some(Ok(Message::Text(text))) = msg else { return };
+                let Ok(frame) = serde_json::from_str::<ClientFrame>(&text) else { continue };
+                match frame.event {
+                    ClientEvent::Hello { last_seq: Some(_), .. } => {
+                        let batch = ServerEvent::MessageBatch { messages: Vec::new(), truncated: true };
+                        let _ = tx.send(Message::Text(serde_json::to_string(&batch).unwrap()));
+                    }
+                    ClientEvent::Subscribe { room_id } => {
+                        members.lock().unwrap().push((room_id.clone(), tx.clone()));
+                        let presence = ServerEvent::Presence { room_id: room_id.channel.clone(), user_id: UserId::new(), online: true };
+                        to_room(&room_id, &presence);
+                    }
+                    ClientEvent::SendMessage { room_id, content, .. } => {
+                        let room_id = RoomId::from(room_id);
+                        let event = ServerEvent::MessageBroadcast {
+                            room_id: room_id.clone(),
+                            from: UserId::new(),
+                            content,
+                            encrypted: false,
+                            content_type: "text/plain".into(),
+                            seq: Some(seq.fetch_add(1, Ordering::Relaxed)),
+                            bridged_from: None,
+                            sender_name: None,
+                            message_id: None,
+                            sender_role: None,
+                            redacted: false,
+                            traceparent: None,
+                        };
+                        to_room(&room_id, &event);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
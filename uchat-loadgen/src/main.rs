@@ -0,0 +1,337 @@
+//! Load generator for gateway-service.
+//!
+//!     uchat-loadgen [--url <ws-url>] [--connections <n>] [--rooms <n>]
+//!                   [--rate <msgs/sec>] [--ramp <secs>] [--duration <secs>]
+//!                   [--out <path>]
+//!
+//! Opens `connections` WebSockets, spread evenly over `ramp` seconds and
+//! over `rooms` fresh channels, each with its own token signed with
+//! `JWT_SECRET`. Every connection sends its share of `rate` messages a
+//! second until the soak `duration` after the ramp ends. Each message
+//! carries its send time, so receivers measure delivery latency on the
+//! machine's own clock.
+//!
+//! The JSON report goes to `--out`, or stdout. `--url` picks the handler
+//! to load, `ws://127.0.0.1:9000/ws` by default.
+//!
+//!     uchat-loadgen soak --gateway <binary> [--addr <host:port>] ...
+//!
+//! Runs the gateway binary itself, listening on `--addr`, and rehearses a
+//! reconnect storm: once every client is connected and warmed up, the
+//! gateway is killed and restarted. Each client must be back within
+//! `--deadline` seconds, resuming with `Hello`, and the messages sent
+//! afterwards must reach every room member exactly once. The report also
+//! samples the gateway's open files and memory before and after.
+
+mod conn;
+#[cfg(test)]
+mod fake;
+mod soak;
+mod stats;
+
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::jwt::{create_token_with_rooms, secret_from_env};
+use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+use stats::{Latencies, Report};
+
+const USAGE: &str = "usage: uchat-loadgen [--url <ws-url>] [--connections <n>] [--rooms <n>] \
+                     [--rate <msgs/sec>] [--ramp <secs>] [--duration <secs>] [--out <path>]";
+/// How long receivers keep listening after sending stops.
+const DRAIN: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Config {
+    url: String,
+    connections: usize,
+    rooms: usize,
+    /// Messages per second across all connections.
+    rate: f64,
+    ramp: Duration,
+    duration: Duration,
+    out: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            url: "ws://127.0.0.1:9000/ws".into(),
+            connections: 100,
+            rooms: 10,
+            rate: 50.0,
+            ramp: Duration::from_secs(10),
+            duration: Duration::from_secs(60),
+            out: None,
+        }
+    }
+}
+
+fn value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} needs a number, got {:?}", flag, value))
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let Some(arg) = args.next() else {
+            return Err(USAGE.into());
+        };
+        match flag.as_str() {
+            "--url" => config.url = arg.clone(),
+            "--connections" => config.connections = value(flag, arg)?,
+            "--rooms" => config.rooms = value(flag, arg)?,
+            "--rate" => config.rate = value(flag, arg)?,
+            "--ramp" => config.ramp = Duration::from_secs(value(flag, arg)?),
+            "--duration" => config.duration = Duration::from_secs(value(flag, arg)?),
+            "--out" => config.out = Some(arg.clone()),
+            _ => return Err(format!("unknown flag {:?}\n{}", flag, USAGE)),
+        }
+    }
+
+    if config.connections == 0 || config.rooms == 0 {
+        return Err("--connections and --rooms must be at least 1".into());
+    }
+    if !(config.rate >= 0.0 && config.rate.is_finite()) {
+        return Err("--rate must be a non-negative number".into());
+    }
+    Ok(config)
+}
+
+/// Runs the load described by `config` and sums up what each connection
+/// saw.
+async fn run(config: &Config, secret: &str) -> Report {
+    let rooms: Vec<ChannelId> = (0..config.rooms).map(|_| ChannelId::new()).collect();
+    let mut perms = RoomPermissions::new();
+    for room in &rooms {
+        perms.grant(room.as_str(), RoomRole::Write);
+    }
+
+    let start = Instant::now();
+    let stop_at = start + config.ramp + config.duration;
+    let send_every = (config.rate > 0.0).then(|| Duration::from_secs_f64(config.connections as f64 / config.rate));
+    let separator = if config.url.contains('?') { '&' } else { '?' };
+
+    let mut tasks = Vec::with_capacity(config.connections);
+    for i in 0..config.connections {
+        let token = create_token_with_rooms(secret, UserId::new().as_str(), perms.clone());
+        let plan = conn::Plan {
+            index: i as u32,
+            url: format!("{}{}token={}", config.url, separator, token),
+            room: rooms[i % rooms.len()].clone(),
+            send_every,
+            stop_at,
+            close_at: stop_at + DRAIN,
+        };
+        let begin_at = start + config.ramp.mul_f64(i as f64 / config.connections as f64);
+        tasks.push(tokio::spawn(async move {
+            tokio::time::sleep_until(begin_at.into()).await;
+            conn::run(plan).await
+        }));
+    }
+
+    let mut report = Report {
+        url: config.url.clone(),
+        connections: config.connections,
+        rooms: config.rooms,
+        target_rate: config.rate,
+        ramp_secs: config.ramp.as_secs(),
+        duration_secs: config.duration.as_secs(),
+        connected: 0,
+        connect_failures: 0,
+        connects_per_sec: 0.0,
+        disconnects: 0,
+        sent: 0,
+        sent_per_sec: 0.0,
+        delivered: 0,
+        dropped: 0,
+        errors: 0,
+        latency: Latencies::default().summary(),
+    };
+    let mut latencies = Latencies::default();
+    let mut last_connect = start;
+    for task in tasks {
+        let outcome = task.await.unwrap_or_default();
+        match outcome.connected_at {
+            Some(at) => {
+                report.connected += 1;
+                last_connect = last_connect.max(at);
+            }
+            None => report.connect_failures += 1,
+        }
+        report.disconnects += outcome.disconnected as u64;
+        report.sent += outcome.sent;
+        report.delivered += outcome.delivered;
+        report.dropped += outcome.dropped;
+        report.errors += outcome.errors;
+        latencies.merge(&outcome.latencies);
+    }
+
+    let connecting = last_connect.duration_since(start).as_secs_f64();
+    report.connects_per_sec = if connecting > 0.0 { report.connected as f64 / connecting } else { 0.0 };
+    let sending = (config.ramp + config.duration).as_secs_f64();
+    report.sent_per_sec = if sending > 0.0 { report.sent as f64 / sending } else { 0.0 };
+    report.latency = latencies.summary();
+    report
+}
+
+/// Writes the report to `out`, or stdout.
+async fn write_report(report: &impl serde::Serialize, out: Option<&str>) -> Result<(), ExitCode> {
+    let json = serde_json::to_string_pretty(report).unwrap();
+    match out {
+        Some(path) => tokio::fs::write(path, json + "\n").await.map_err(|e| {
+            eprintln!("writing {} failed: {}", path, e);
+            ExitCode::FAILURE
+        }),
+        None => {
+            println!("{}", json);
+            Ok(())
+        }
+    }
+}
+
+async fn soak_main(args: &[String]) -> ExitCode {
+    let config = match soak::parse_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut gateway = match soak::GatewayProcess::spawn(&config.gateway, &config.addr).await {
+        Ok(gateway) => gateway,
+        Err(e) => {
+            eprintln!("starting {} failed: {}", config.gateway.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!(
+        "soaking {} on {} with {} connections in {} rooms ({}s warmup, {}s to reconnect)",
+        config.gateway.display(),
+        config.addr,
+        config.connections,
+        config.rooms,
+        config.warmup.as_secs(),
+        config.deadline.as_secs()
+    );
+    let report = soak::run(&config, &secret_from_env(), &mut gateway).await;
+    if let Err(code) = write_report(&report, config.out.as_deref()).await {
+        return code;
+    }
+
+    if !report.passed() {
+        eprintln!(
+            "soak failed: {} of {} clients back, {} messages lost, {} duplicated",
+            report.reconnected, report.connected, report.lost, report.duplicated
+        );
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("soak") {
+        return soak_main(&args[1..]).await;
+    }
+    let config = match parse_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    eprintln!(
+        "loading {} with {} connections in {} rooms at {} msgs/sec ({}s ramp, {}s soak)",
+        config.url,
+        config.connections,
+        config.rooms,
+        config.rate,
+        config.ramp.as_secs(),
+        config.duration.as_secs()
+    );
+    let report = run(&config, &secret_from_env()).await;
+    if let Err(code) = write_report(&report, config.out.as_deref()).await {
+        return code;
+    }
+
+    if report.connected == 0 {
+        eprintln!("no connection succeeded");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeGateway;
+    use tokio::net::TcpListener;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_flags_over_defaults() {
+        assert_eq!(parse_args(&[]), Ok(Config::default()));
+
+        let config = parse_args(&args(&["--connections", "5000", "--rate", "2.5", "--ramp", "0", "--out", "r.json"])).unwrap();
+        assert_eq!(config.connections, 5000);
+        assert_eq!(config.rate, 2.5);
+        assert_eq!(config.ramp, Duration::ZERO);
+        assert_eq!(config.out.as_deref(), Some("r.json"));
+        assert_eq!(config.rooms, Config::default().rooms);
+
+        assert!(parse_args(&args(&["--rooms"])).is_err());
+        assert!(parse_args(&args(&["--rooms", "0"])).is_err());
+        assert!(parse_args(&args(&["--rate", "-1"])).is_err());
+        assert!(parse_args(&args(&["--duration", "soon"])).is_err());
+        assert!(parse_args(&args(&["--bogus", "1"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn measures_deliveries_through_a_gateway() {
+        let gateway = FakeGateway::start().await;
+        let config = Config {
+            url: gateway.url(),
+            connections: 4,
+            rooms: 2,
+            rate: 40.0,
+            ramp: Duration::ZERO,
+            duration: Duration::from_secs(1),
+            out: None,
+        };
+
+        let report = run(&config, "test-secret").await;
+        assert_eq!(report.connected, 4);
+        assert_eq!(report.connect_failures, 0);
+        assert_eq!(report.disconnects, 0);
+        assert!(report.sent >= 20, "{:?}", report);
+        // Two members per room, the sender included; the first messages
+        // may beat the other member's subscription.
+        assert!(report.delivered > report.sent && report.delivered <= report.sent * 2, "{:?}", report);
+        assert_eq!(report.dropped, 0);
+        assert_eq!(report.latency.samples, report.delivered);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json["latency"]["p99_ms"].is_number());
+    }
+
+    #[tokio::test]
+    async fn counts_connect_failures() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        drop(listener);
+
+        let config = Config { url, connections: 2, ramp: Duration::ZERO, duration: Duration::ZERO, ..Config::default() };
+        let report = run(&config, "test-secret").await;
+        assert_eq!((report.connected, report.connect_failures), (0, 2));
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::jwt::create_token_with_rooms;
+use uchat_proto::permissions::{RoomPermissions, RoomRole};
+
+use crate::conn::{frame, parse_payload, payload, unix_micros, CONNECT_TIMEOUT};
+use crate::stats::{Latencies, ProcessSample, SoakReport};
+use crate::value;
+
+pub const USAGE: &str = "usage: uchat-loadgen soak --gateway <binary> [--addr <host:port>] [--connections <n>] \
+                         [--rooms <n>] [--rate <msgs/sec>] [--warmup <secs>] [--deadline <secs>] \
+                         [--verify-messages <n>] [--out <path>]";
+
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+/// How long the gateway gets to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Between telling clients to stop sending and killing the gateway, so
+/// nothing is half-written when it dies.
+const QUIESCE: Duration = Duration::from_millis(200);
+/// How long receivers keep listening after the last verification message.
+const DRAIN: Duration = Duration::from_secs(2);
+/// How long the restarted gateway gets to clean up after everyone leaves.
+const SETTLE: Duration = Duration::from_secs(1);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakConfig {
+    /// Gateway binary to run, kill and restart.
+    pub gateway: PathBuf,
+    /// Where the gateway is told to listen, through `GATEWAY_ADDR`.
+    pub addr: String,
+    pub connections: usize,
+    pub rooms: usize,
+    /// Messages per second across all connections while warming up and
+    /// verifying.
+    pub rate: f64,
+    pub warmup: Duration,
+    /// How long after the kill every client has to be back.
+    pub deadline: Duration,
+    /// Messages each client sends once everyone is back.
+    pub verify_messages: u64,
+    pub out: Option<String>,
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            gateway: PathBuf::new(),
+            addr: "127.0.0.1:9000".into(),
+            connections: 100,
+            rooms: 10,
+            rate: 50.0,
+            warmup: Duration::from_secs(5),
+            deadline: Duration::from_secs(30),
+            verify_messages: 20,
+            out: None,
+        }
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<SoakConfig, String> {
+    let mut config = SoakConfig::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let Some(arg) = args.next() else {
+            return Err(USAGE.into());
+        };
+        match flag.as_str() {
+            "--gateway" => config.gateway = arg.into(),
+            "--addr" => config.addr = arg.clone(),
+            "--connections" => config.connections = value(flag, arg)?,
+            "--rooms" => config.rooms = value(flag, arg)?,
+            "--rate" => config.rate = value(flag, arg)?,
+            "--warmup" => config.warmup = Duration::from_secs(value(flag, arg)?),
+            "--deadline" => config.deadline = Duration::from_secs(value(flag, arg)?),
+            "--verify-messages" => config.verify_messages = value(flag, arg)?,
+            "--out" => config.out = Some(arg.clone()),
+            _ => return Err(format!("unknown flag {:?}\n{}", flag, USAGE)),
+        }
+    }
+
+    if config.gateway.as_os_str().is_empty() {
+        return Err(format!("--gateway is required\n{}", USAGE));
+    }
+    if config.connections == 0 || config.rooms == 0 || config.verify_messages == 0 {
+        return Err("--connections, --rooms and --verify-messages must be at least 1".into());
+    }
+    if !(config.rate > 0.0 && config.rate.is_finite()) {
+        return Err("--rate must be a positive number".into());
+    }
+    Ok(config)
+}
+
+/// The gateway under test, which the soak kills and restarts.
+pub trait Gateway {
+    /// Its process, for sampling; `None` when it isn't one.
+    fn pid(&self) -> Option<u32>;
+    /// Stops it abruptly and starts it again.
+    async fn restart(&mut self) -> io::Result<()>;
+}
+
+/// A gateway binary run as a child process, killed with the soak.
+pub struct GatewayProcess {
+    binary: PathBuf,
+    addr: String,
+    child: Child,
+}
+
+impl GatewayProcess {
+    pub async fn spawn(binary: &Path, addr: &str) -> io::Result<Self> {
+        let child = start(binary, addr).await?;
+        Ok(Self { binary: binary.into(), addr: addr.into(), child })
+    }
+}
+
+/// Starts the gateway and waits until it accepts connections.
+async fn start(binary: &Path, addr: &str) -> io::Result<Child> {
+    let mut child = Command::new(binary).env("GATEWAY_ADDR", addr).stdout(Stdio::null()).kill_on_drop(true).spawn()?;
+    let ready_by = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect(addr).await.is_err() {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!("gateway exited with {}", status)));
+        }
+        if Instant::now() > ready_by {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("gateway isn't listening on {}", addr)));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(child)
+}
+
+impl Gateway for GatewayProcess {
+    fn pid(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    async fn restart(&mut self) -> io::Result<()> {
+        // SIGKILL, as in a crash: no socket gets a clean close.
+        self.child.kill().await?;
+        self.child = start(&self.binary, &self.addr).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Warmup,
+    /// Stop sending; the gateway is about to go.
+    Storm,
+    /// Everyone is back; send the messages that are checked.
+    Verify,
+    Close,
+}
+
+#[derive(Default)]
+struct Progress {
+    connected: AtomicUsize,
+    reconnected: AtomicUsize,
+}
+
+struct Client {
+    index: u32,
+    url: String,
+    room: ChannelId,
+    send_every: Duration,
+    verify_messages: u64,
+    deadline: Duration,
+}
+
+#[derive(Debug, Default)]
+struct ClientOutcome {
+    connected: bool,
+    /// From losing the socket to being subscribed again.
+    reconnect_time: Option<Duration>,
+    resumed: bool,
+    verify_sent: u64,
+    /// Verification messages received, by sender: distinct sequence
+    /// numbers and the total.
+    received: HashMap<u32, (HashSet<u64>, u64)>,
+    errors: u64,
+}
+
+/// A fraction in `0..1` that differs between clients and attempts.
+fn jitter(index: u32) -> f64 {
+    let mixed = unix_micros() ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    (mixed % 1000) as f64 / 1000.0
+}
+
+/// Connects, retrying with jittered exponential backoff until `deadline`.
+async fn connect(url: &str, index: u32, deadline: Instant) -> Option<Socket> {
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        if let Ok(Ok((socket, _))) = tokio::time::timeout(CONNECT_TIMEOUT, tokio_tungstenite::connect_async(url)).await {
+            return Some(socket);
+        }
+        // Half to all of the backoff, so a storm of clients spreads out.
+        let wait = backoff.mul_f64(0.5 + jitter(index) / 2.0);
+        if Instant::now() + wait >= deadline {
+            return None;
+        }
+        tokio::time::sleep(wait).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Says `Hello` and joins the client's room, reading until a `Presence`
+/// for the room shows the subscription took; the client's own always
+/// comes.
+async fn join(socket: &mut Socket, client: &Client, last_seq: Option<u64>, outcome: &mut ClientOutcome) -> bool {
+    let hello = ClientEvent::Hello { last_seq, capabilities: None, format: None };
+    let subscribe = ClientEvent::Subscribe { room_id: client.room.clone().into() };
+    if socket.send(frame(hello)).await.is_err() || socket.send(frame(subscribe)).await.is_err() {
+        return false;
+    }
+
+    loop {
+        let text = match tokio::time::timeout(CONNECT_TIMEOUT, socket.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => text,
+            Ok(Some(Ok(_))) => continue,
+            _ => return false,
+        };
+        match serde_json::from_str::<ServerEvent>(&text) {
+            Ok(ServerEvent::MessageBatch { .. }) => outcome.resumed = true,
+            Ok(ServerEvent::Presence { room_id, .. }) if room_id == client.room => return true,
+            Ok(ServerEvent::Error { .. } | ServerEvent::Nack { .. }) => outcome.errors += 1,
+            _ => {}
+        }
+    }
+}
+
+async fn run_client(client: Client, mut phase: watch::Receiver<Phase>, progress: Arc<Progress>) -> ClientOutcome {
+    let mut outcome = ClientOutcome::default();
+    let Some(mut socket) = connect(&client.url, client.index, Instant::now() + STARTUP_TIMEOUT).await else {
+        return outcome;
+    };
+    if !join(&mut socket, &client, None, &mut outcome).await {
+        return outcome;
+    }
+    outcome.connected = true;
+    progress.connected.fetch_add(1, Ordering::Relaxed);
+
+    let mut ticker = tokio::time::interval(client.send_every);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut sent = 0;
+    let mut last_seq = None;
+    // Warm up until the gateway drops the socket.
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if *phase.borrow() == Phase::Warmup => {
+                let event = send_message(&client, sent);
+                if socket.send(frame(event)).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+
+            msg = socket.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(ServerEvent::MessageBroadcast { seq: Some(seq), .. }) = serde_json::from_str(&text) {
+                        last_seq = Some(seq);
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+
+            changed = phase.changed() => {
+                if changed.is_err() || *phase.borrow() == Phase::Close {
+                    return outcome;
+                }
+            }
+        }
+    }
+    // Lost before the restart; not what is being measured.
+    if *phase.borrow() == Phase::Warmup {
+        return outcome;
+    }
+
+    let lost_at = Instant::now();
+    let Some(mut socket) = connect(&client.url, client.index, lost_at + client.deadline).await else {
+        return outcome;
+    };
+    // A clean restart can't replay anything, but the batch must come.
+    if !join(&mut socket, &client, Some(last_seq.unwrap_or(0)), &mut outcome).await {
+        return outcome;
+    }
+    outcome.reconnect_time = Some(lost_at.elapsed());
+    progress.reconnected.fetch_add(1, Ordering::Relaxed);
+
+    // Nothing is sent between the kill and `Verify`, so every message
+    // from here on is one being checked.
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if *phase.borrow() == Phase::Verify && outcome.verify_sent < client.verify_messages => {
+                let event = send_message(&client, outcome.verify_sent);
+                if socket.send(frame(event)).await.is_err() {
+                    break;
+                }
+                outcome.verify_sent += 1;
+            }
+
+            msg = socket.next() => match msg {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<ServerEvent>(&text) {
+                    Ok(ServerEvent::MessageBroadcast { content, .. }) => {
+                        let Some((sender, seq, _)) = parse_payload(&content) else { continue };
+                        let (seen, total) = outcome.received.entry(sender).or_default();
+                        seen.insert(seq);
+                        *total += 1;
+                    }
+                    Ok(ServerEvent::Error { .. } | ServerEvent::Nack { .. }) => outcome.errors += 1,
+                    _ => {}
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+
+            changed = phase.changed() => {
+                if changed.is_err() || *phase.borrow() == Phase::Close {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = socket.send(Message::Close(None)).await;
+    outcome
+}
+
+fn send_message(client: &Client, seq: u64) -> ClientEvent {
+    ClientEvent::SendMessage {
+        room_id: client.room.clone(),
+        content: payload(client.index, seq, unix_micros()),
+        encrypted: false,
+        content_type: "text/plain".into(),
+        thread_id: None,
+    }
+}
+
+/// Polls `done` until it holds or `timeout` passes.
+async fn wait_for(timeout: Duration, done: impl Fn() -> bool) {
+    let until = Instant::now() + timeout;
+    while !done() && Instant::now() < until {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Connects every client, kills and restarts the gateway once they are
+/// warmed up, and checks that they all come back and that what they send
+/// afterwards reaches each room member exactly once.
+pub async fn run(config: &SoakConfig, secret: &str, gateway: &mut impl Gateway) -> SoakReport {
+    let rooms: Vec<ChannelId> = (0..config.rooms).map(|_| ChannelId::new()).collect();
+    let mut perms = RoomPermissions::new();
+    for room in &rooms {
+        perms.grant(room.as_str(), RoomRole::Write);
+    }
+
+    let (phase_tx, phase_rx) = watch::channel(Phase::Warmup);
+    let progress = Arc::new(Progress::default());
+    let send_every = Duration::from_secs_f64(config.connections as f64 / config.rate);
+    let mut tasks = Vec::with_capacity(config.connections);
+    for i in 0..config.connections {
+        let token = create_token_with_rooms(secret, UserId::new().as_str(), perms.clone());
+        let client = Client {
+            index: i as u32,
+            url: format!("ws://{}/ws?token={}", config.addr, token),
+            room: rooms[i % rooms.len()].clone(),
+            send_every,
+            verify_messages: config.verify_messages,
+            deadline: config.deadline,
+        };
+        tasks.push(tokio::spawn(run_client(client, phase_rx.clone(), progress.clone())));
+    }
+
+    let connections = config.connections;
+    wait_for(STARTUP_TIMEOUT, || progress.connected.load(Ordering::Relaxed) == connections).await;
+    tokio::time::sleep(config.warmup).await;
+    let gateway_before = gateway.pid().and_then(ProcessSample::of);
+
+    let _ = phase_tx.send(Phase::Storm);
+    tokio::time::sleep(QUIESCE).await;
+    let killed_at = Instant::now();
+    if let Err(e) = gateway.restart().await {
+        eprintln!("restarting the gateway failed: {}", e);
+    }
+    let connected = progress.connected.load(Ordering::Relaxed);
+    let remaining = config.deadline.saturating_sub(killed_at.elapsed());
+    wait_for(remaining, || progress.reconnected.load(Ordering::Relaxed) == connected).await;
+    let gateway_after_storm = gateway.pid().and_then(ProcessSample::of);
+
+    let _ = phase_tx.send(Phase::Verify);
+    tokio::time::sleep(send_every * config.verify_messages as u32 + DRAIN).await;
+    let _ = phase_tx.send(Phase::Close);
+    let mut outcomes = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        outcomes.push(task.await.unwrap_or_default());
+    }
+    tokio::time::sleep(SETTLE).await;
+    let gateway_after_close = gateway.pid().and_then(ProcessSample::of);
+
+    let room_of = |index: usize| index % rooms.len();
+    let mut report = SoakReport {
+        connections: config.connections,
+        rooms: config.rooms,
+        connected: 0,
+        reconnected: 0,
+        reconnect_failures: 0,
+        resumed: 0,
+        reconnect: Latencies::default().summary(),
+        verify_sent: 0,
+        delivered: 0,
+        lost: 0,
+        duplicated: 0,
+        errors: 0,
+        gateway_before,
+        gateway_after_storm,
+        gateway_after_close,
+    };
+    let mut reconnect_times = Latencies::default();
+    for outcome in &outcomes {
+        report.connected += outcome.connected as u64;
+        report.resumed += outcome.resumed as u64;
+        report.verify_sent += outcome.verify_sent;
+        report.errors += outcome.errors;
+        if let Some(time) = outcome.reconnect_time {
+            report.reconnected += 1;
+            reconnect_times.record(time.as_micros() as u64);
+        }
+    }
+    report.reconnect_failures = report.connected - report.reconnected;
+    report.reconnect = reconnect_times.summary();
+
+    // Each reconnected member of a room should have every message each
+    // reconnected member sent, its own included, exactly once.
+    let back: Vec<usize> = (0..outcomes.len()).filter(|&i| outcomes[i].reconnect_time.is_some()).collect();
+    for &receiver in &back {
+        for &sender in back.iter().filter(|&&s| room_of(s) == room_of(receiver)) {
+            let sent = outcomes[sender].verify_sent;
+            let (seen, total) = outcomes[receiver].received.get(&(sender as u32)).cloned().unwrap_or_default();
+            let delivered = seen.iter().filter(|&&seq| seq < sent).count() as u64;
+            report.delivered += delivered;
+            report.lost += sent - delivered;
+            report.duplicated += total - seen.len() as u64;
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeGateway;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_soak_flags() {
+        let config = parse_args(&args(&["--gateway", "target/release/gateway-service", "--deadline", "10"])).unwrap();
+        assert_eq!(config.gateway, PathBuf::from("target/release/gateway-service"));
+        assert_eq!(config.deadline, Duration::from_secs(10));
+        assert_eq!(config.addr, SoakConfig::default().addr);
+
+        assert!(parse_args(&[]).is_err());
+        assert!(parse_args(&args(&["--gateway", "g", "--rate", "0"])).is_err());
+        assert!(parse_args(&args(&["--gateway", "g", "--verify-messages", "0"])).is_err());
+        assert!(parse_args(&args(&["--gateway", "g", "--connections", "lots"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn clients_ride_out_a_restart() {
+        let mut gateway = FakeGateway::start().await;
+        let config = SoakConfig {
+            addr: gateway.addr(),
+            connections: 6,
+            rooms: 2,
+            rate: 60.0,
+            warmup: Duration::from_millis(500),
+            deadline: Duration::from_secs(10),
+            verify_messages: 5,
+            ..SoakConfig::default()
+        };
+
+        let report = run(&config, "test-secret", &mut gateway).await;
+        assert_eq!((report.connected, report.reconnected, report.resumed), (6, 6, 6), "{:?}", report);
+        assert!(report.passed(), "{:?}", report);
+        assert_eq!(report.verify_sent, 30);
+        // Three members per room, each getting every member's messages.
+        assert_eq!(report.delivered, 90);
+        assert_eq!(report.gateway_before, None);
+    }
+}
@@ -0,0 +1,219 @@
+use serde::Serialize;
+
+/// Exact buckets below this; above it each power of two is split into
+/// this many, so a percentile is off by at most 1/16th.
+const SUB_BUCKETS: u64 = 16;
+
+/// Delivery latencies in microseconds, bucketed so a long soak doesn't
+/// keep every sample.
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let shift = 63 - micros.leading_zeros() as u64 - SUB_BUCKETS.trailing_zeros() as u64;
+    let top = micros >> shift;
+    (SUB_BUCKETS + shift * SUB_BUCKETS + (top - SUB_BUCKETS)) as usize
+}
+
+/// Largest value that lands in bucket `index`.
+fn bucket_max(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let shift = (index - SUB_BUCKETS) / SUB_BUCKETS;
+    let top = SUB_BUCKETS + (index - SUB_BUCKETS) % SUB_BUCKETS;
+    // The last buckets end past `u64::MAX`.
+    (((top + 1) as u128) << shift).saturating_sub(1).min(u64::MAX as u128) as u64
+}
+
+impl Latencies {
+    pub fn record(&mut self, micros: u64) {
+        let index = bucket(micros);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+        self.max = self.max.max(micros);
+    }
+
+    pub fn merge(&mut self, other: &Latencies) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (mine, theirs) in self.counts.iter_mut().zip(&other.counts) {
+            *mine += theirs;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    /// The `q`-th quantile (0.0 to 1.0) in microseconds, or 0 with no
+    /// samples.
+    pub fn percentile(&self, q: f64) -> u64 {
+        let rank = ((q * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_max(index).min(self.max);
+            }
+        }
+        0
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        LatencySummary {
+            samples: self.total,
+            p50_ms: ms(self.percentile(0.50)),
+            p90_ms: ms(self.percentile(0.90)),
+            p99_ms: ms(self.percentile(0.99)),
+            max_ms: ms(self.max),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatencySummary {
+    pub samples: u64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+/// What one run measured, written out as JSON so CI can track it across
+/// builds.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub url: String,
+    pub connections: usize,
+    pub rooms: usize,
+    /// Messages per second asked for, across all connections.
+    pub target_rate: f64,
+    pub ramp_secs: u64,
+    pub duration_secs: u64,
+    pub connected: u64,
+    pub connect_failures: u64,
+    /// Connections established per second over the ramp.
+    pub connects_per_sec: f64,
+    /// Sockets the gateway closed before the run ended.
+    pub disconnects: u64,
+    pub sent: u64,
+    pub sent_per_sec: f64,
+    /// Broadcasts received, counting each receiver separately.
+    pub delivered: u64,
+    /// Broadcasts a receiver never saw, from gaps in each sender's
+    /// sequence numbers.
+    pub dropped: u64,
+    /// `Error` and `Nack` frames received.
+    pub errors: u64,
+    pub latency: LatencySummary,
+}
+
+/// Open file descriptors and resident memory of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ProcessSample {
+    pub open_fds: u64,
+    pub rss_bytes: u64,
+}
+
+impl ProcessSample {
+    /// Reads `/proc`; `None` without one, or once the process is gone.
+    pub fn of(pid: u32) -> Option<Self> {
+        let open_fds = std::fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count() as u64;
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+        let rss_kb: u64 = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?.trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(Self { open_fds, rss_bytes: rss_kb * 1024 })
+    }
+}
+
+/// What a reconnect-storm soak measured.
+#[derive(Debug, Serialize)]
+pub struct SoakReport {
+    pub connections: usize,
+    pub rooms: usize,
+    pub connected: u64,
+    /// Clients back and subscribed within the deadline after the restart.
+    pub reconnected: u64,
+    pub reconnect_failures: u64,
+    /// Reconnected clients whose `Hello` got a `MessageBatch` back.
+    pub resumed: u64,
+    /// From losing the socket to being subscribed again.
+    pub reconnect: LatencySummary,
+    /// Messages sent once every client was back.
+    pub verify_sent: u64,
+    /// Of those, deliveries to each room member, counted once each.
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    /// `Error` and `Nack` frames received.
+    pub errors: u64,
+    /// The gateway with every client connected, before it was killed.
+    pub gateway_before: Option<ProcessSample>,
+    /// The restarted gateway with every client back.
+    pub gateway_after_storm: Option<ProcessSample>,
+    /// The restarted gateway once every client disconnected; well above
+    /// `gateway_before` points at a leak on the disconnect path.
+    pub gateway_after_close: Option<ProcessSample>,
+}
+
+impl SoakReport {
+    pub fn passed(&self) -> bool {
+        self.connected > 0 && self.reconnect_failures == 0 && self.lost == 0 && self.duplicated == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_values_in_order() {
+        let mut last = 0;
+        for micros in (0..100_000).chain([u32::MAX as u64, u64::MAX]) {
+            let index = bucket(micros);
+            assert!(index >= last, "{}", micros);
+            assert!(bucket_max(index) >= micros, "{}", micros);
+            last = index;
+        }
+        for index in 1..200 {
+            assert_eq!(bucket(bucket_max(index)), index);
+            assert_eq!(bucket(bucket_max(index - 1) + 1), index);
+        }
+    }
+
+    #[test]
+    fn percentiles_are_within_a_sixteenth() {
+        let mut a = Latencies::default();
+        let mut b = Latencies::default();
+        for micros in 1..=10_000 {
+            if micros % 2 == 0 { a.record(micros) } else { b.record(micros) }
+        }
+        a.merge(&b);
+
+        assert_eq!(a.summary().samples, 10_000);
+        for (q, exact) in [(0.5, 5_000.0), (0.9, 9_000.0), (0.99, 9_900.0)] {
+            let got = a.percentile(q) as f64;
+            assert!(got >= exact && got <= exact * (1.0 + 1.0 / 16.0), "{} -> {}", q, got);
+        }
+        assert_eq!(a.percentile(1.0), 10_000);
+        assert_eq!(Latencies::default().percentile(0.5), 0);
+    }
+
+    #[test]
+    fn samples_this_process() {
+        let Some(sample) = ProcessSample::of(std::process::id()) else { return };
+        assert!(sample.open_fds >= 3 && sample.rss_bytes > 0, "{:?}", sample);
+        assert_eq!(ProcessSample::of(u32::MAX), None);
+    }
+}
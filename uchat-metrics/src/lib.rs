@@ -0,0 +1,407 @@
+//! Prometheus metrics shared by the services, so the same thing is called
+//! the same name with the same labels wherever it is counted.
+//!
+//! A service calls `init` once at startup, which serves `GET /metrics`
+//! on its own port, then records through the typed helpers here. Every
+//! sample carries a `service` label. Families are listed even before
+//! anything is recorded, so dashboards and alerts can rely on them.
+//!
+//! Long-lived tasks are started with `spawn_task`, which counts them by
+//! kind so runaway task growth shows up in production, where nobody has
+//! tokio-console attached.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::http::header::CONTENT_TYPE;
+use axum::routing::get;
+use axum::Router;
+use http_body_util::BodyExt;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::service::TowerToHyperService;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Upper bounds of the query duration buckets, in seconds.
+const DB_QUERY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+/// How long the exporter waits for a request head before dropping the
+/// connection.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the exporter backs off after a failed accept, such as when the
+/// process is out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// What `/proc` counts CPU time in; fixed at 100 on Linux regardless of
+/// the kernel's tick rate.
+const USER_HZ: f64 = 100.0;
+
+type Collector = Box<dyn Fn(&mut String) + Send + Sync>;
+
+static SERVICE: OnceLock<&'static str> = OnceLock::new();
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+#[derive(Default)]
+struct Registry {
+    started: OnceLock<SystemTime>,
+    messages_relayed: AtomicU64,
+    /// By reason.
+    auth_failures: Mutex<BTreeMap<&'static str, u64>>,
+    /// By query name.
+    db_queries: Mutex<BTreeMap<&'static str, Histogram>>,
+    /// Running tasks from `spawn_task`, by kind.
+    tasks: Mutex<BTreeMap<&'static str, u64>>,
+    collectors: Mutex<Vec<Collector>>,
+}
+
+/// A Prometheus histogram over fixed buckets, for services that keep their
+/// own families and append them through `add_collector` or an endpoint of
+/// their own.
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Per-bucket counts, not cumulative; the last is `+Inf`.
+    buckets: Vec<AtomicU64>,
+    /// The `f64` sum, as bits.
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|b| value <= *b).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Appends the `_bucket`, `_sum` and `_count` samples of `name`;
+    /// `labels` is the rendered label list without braces.
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let le = self.bounds.get(i).map_or("+Inf".to_string(), f64::to_string);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+        }
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count.load(Ordering::Relaxed));
+    }
+}
+
+/// Names this process's samples and serves `GET /metrics` on `bind_addr`
+/// in the background, returning the address it listens on. Call it once;
+/// later calls still start an exporter but keep the first service name.
+pub async fn init(service: &'static str, bind_addr: &str) -> io::Result<SocketAddr> {
+    set_service(service);
+
+    let listener = TcpListener::bind(bind_addr).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(serve(listener));
+    tracing::info!("metrics on http://{}/metrics", addr);
+    Ok(addr)
+}
+
+/// Names this process's samples without serving them, for services that
+/// expose `render_tasks` on an endpoint of their own.
+pub fn set_service(service: &'static str) {
+    let _ = SERVICE.set(service);
+    let _ = REGISTRY.started.set(SystemTime::now());
+}
+
+/// `METRICS_ADDR`, or `default` when unset.
+pub fn addr_from_env(default: &str) -> String {
+    std::env::var("METRICS_ADDR").ok().filter(|v| !v.is_empty()).unwrap_or_else(|| default.into())
+}
+
+/// Counts messages handed on to other connections or services.
+pub fn messages_relayed(count: u64) {
+    REGISTRY.messages_relayed.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Counts a rejected login or token. `reason` is a short fixed string,
+/// such as `invalid_token` or `suspended`, never anything from the
+/// request.
+pub fn auth_failure(reason: &'static str) {
+    *REGISTRY.auth_failures.lock().unwrap().entry(reason).or_default() += 1;
+}
+
+/// Records how long the query called `query` took.
+pub fn observe_db_query(query: &'static str, elapsed: Duration) {
+    REGISTRY
+        .db_queries
+        .lock()
+        .unwrap()
+        .entry(query)
+        .or_insert_with(|| Histogram::new(DB_QUERY_BUCKETS))
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Runs `fut`, recording its duration as the query called `query`.
+pub async fn time_db_query<F: Future>(query: &'static str, fut: F) -> F::Output {
+    let started = Instant::now();
+    let output = fut.await;
+    observe_db_query(query, started.elapsed());
+    output
+}
+
+/// Spawns `fut` as a task of the given `kind`, such as `room_forward`,
+/// counted in `uchat_tasks` until it finishes or is aborted. With the
+/// `console` feature and `--cfg tokio_unstable`, tokio-console shows the
+/// task under that name.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+/// let task = uchat_metrics::spawn_task("example", async move { rx.await.is_ok() });
+/// assert!(uchat_metrics::render().contains("uchat_tasks{service=\"unknown\",kind=\"example\"} 1\n"));
+///
+/// tx.send(()).unwrap();
+/// assert!(task.await.unwrap());
+/// assert!(uchat_metrics::render().contains("kind=\"example\"} 0\n"));
+/// # }
+/// ```
+pub fn spawn_task<F>(kind: &'static str, fut: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let running = Running::start(kind);
+    let fut = async move {
+        let _running = running;
+        fut.await
+    };
+
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    return tokio::task::Builder::new().name(kind).spawn(fut).expect("failed to spawn task");
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    tokio::spawn(fut)
+}
+
+/// One task counted in `uchat_tasks`, until dropped along with the task's
+/// future.
+struct Running(&'static str);
+
+impl Running {
+    fn start(kind: &'static str) -> Self {
+        *REGISTRY.tasks.lock().unwrap().entry(kind).or_default() += 1;
+        Running(kind)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(count) = REGISTRY.tasks.lock().unwrap().get_mut(self.0) {
+            *count -= 1;
+        }
+    }
+}
+
+/// Appends the `uchat_tasks` family.
+pub fn render_tasks(out: &mut String) {
+    let service = escape(SERVICE.get().copied().unwrap_or("unknown"));
+    family(out, "uchat_tasks", "gauge", "Running long-lived tasks, by kind.");
+    for (kind, count) in REGISTRY.tasks.lock().unwrap().iter() {
+        let _ = writeln!(out, "uchat_tasks{{service=\"{}\",kind=\"{}\"}} {}", service, escape(kind), count);
+    }
+}
+
+/// Adds service-specific samples to every scrape. `collect` appends
+/// complete families, `# HELP` and `# TYPE` included.
+pub fn add_collector(collect: impl Fn(&mut String) + Send + Sync + 'static) {
+    REGISTRY.collectors.lock().unwrap().push(Box::new(collect));
+}
+
+/// Everything recorded so far, in the Prometheus text format.
+pub fn render() -> String {
+    let service = escape(SERVICE.get().copied().unwrap_or("unknown"));
+    let mut out = String::new();
+
+    family(&mut out, "uchat_messages_relayed_total", "counter", "Messages relayed to other connections or services.");
+    let relayed = REGISTRY.messages_relayed.load(Ordering::Relaxed);
+    let _ = writeln!(out, "uchat_messages_relayed_total{{service=\"{}\"}} {}", service, relayed);
+
+    family(&mut out, "uchat_auth_failures_total", "counter", "Rejected logins and tokens, by reason.");
+    for (reason, count) in REGISTRY.auth_failures.lock().unwrap().iter() {
+        let _ = writeln!(out, "uchat_auth_failures_total{{service=\"{}\",reason=\"{}\"}} {}", service, escape(reason), count);
+    }
+
+    family(&mut out, "uchat_db_query_duration_seconds", "histogram", "Database query latency, by query.");
+    for (query, histogram) in REGISTRY.db_queries.lock().unwrap().iter() {
+        let labels = format!("service=\"{}\",query=\"{}\"", service, escape(query));
+        histogram.render(&mut out, "uchat_db_query_duration_seconds", &labels);
+    }
+
+    render_tasks(&mut out);
+    render_process(&mut out);
+    for collect in REGISTRY.collectors.lock().unwrap().iter() {
+        collect(&mut out);
+    }
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// The standard `process_*` families, from `/proc` where there is one.
+fn render_process(out: &mut String) {
+    if let Some(started) = REGISTRY.started.get() {
+        let secs = started.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        family(out, "process_start_time_seconds", "gauge", "Start time of the process since the unix epoch.");
+        let _ = writeln!(out, "process_start_time_seconds {}", secs);
+    }
+
+    // utime and stime are the 14th and 15th fields; the 2nd, the command
+    // name, may itself contain spaces, so count from after it.
+    let stat = std::fs::read_to_string("/proc/self/stat").unwrap_or_default();
+    let fields: Vec<&str> = stat.rsplit(')').next().unwrap_or_default().split_whitespace().collect();
+    if let (Some(Ok(utime)), Some(Ok(stime))) =
+        (fields.get(11).map(|f| f.parse::<f64>()), fields.get(12).map(|f| f.parse::<f64>()))
+    {
+        family(out, "process_cpu_seconds_total", "counter", "User and system CPU time spent.");
+        let _ = writeln!(out, "process_cpu_seconds_total {}", (utime + stime) / USER_HZ);
+    }
+
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let rss_kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok());
+    if let Some(kb) = rss_kb {
+        family(out, "process_resident_memory_bytes", "gauge", "Resident memory size.");
+        let _ = writeln!(out, "process_resident_memory_bytes {}", kb * 1024);
+    }
+
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        family(out, "process_open_fds", "gauge", "Open file descriptors.");
+        let _ = writeln!(out, "process_open_fds {}", fds.count());
+    }
+}
+
+async fn serve(listener: TcpListener) {
+    let app = Router::new().route(
+        "/metrics",
+        get(|| async { ([(CONTENT_TYPE, "text/plain; version=0.0.4")], render()) }),
+    );
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!(error = %e, "metrics accept failed");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        tokio::spawn(async move {
+            let _ = hyper::server::conn::http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(HEADER_READ_TIMEOUT)
+                .serve_connection(TokioIo::new(stream), TowerToHyperService::new(app))
+                .await;
+        });
+    }
+}
+
+/// Fetches `/metrics` from an exporter and returns the body; for smoke
+/// tests and health checks.
+pub async fn scrape(addr: SocketAddr) -> io::Result<String> {
+    let (status, body) = get_path(addr, "/metrics").await?;
+    if status != hyper::StatusCode::OK {
+        return Err(io::Error::other(format!("unexpected {} from {}", status, addr)));
+    }
+    String::from_utf8(body).map_err(io::Error::other)
+}
+
+async fn get_path(addr: SocketAddr, path: &str) -> io::Result<(hyper::StatusCode, Vec<u8>)> {
+    let stream = TcpStream::connect(addr).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.map_err(io::Error::other)?;
+    tokio::spawn(conn);
+
+    let request = hyper::Request::get(path)
+        .header(hyper::header::HOST, "metrics")
+        .body(http_body_util::Empty::<axum::body::Bytes>::new())
+        .map_err(io::Error::other)?;
+    let response = sender.send_request(request).await.map_err(io::Error::other)?;
+    let status = response.status();
+    let body = response.into_body().collect().await.map_err(io::Error::other)?.to_bytes();
+    Ok((status, body.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_standard_and_domain_families() {
+        let addr = init("test-service", "127.0.0.1:0").await.unwrap();
+        messages_relayed(3);
+        auth_failure("invalid_token");
+        auth_failure("invalid_token");
+        observe_db_query("load_user", Duration::from_millis(3));
+        let rows = time_db_query("load_user", async { 7 }).await;
+        assert_eq!(rows, 7);
+        add_collector(|out| out.push_str("custom_total 1\n"));
+
+        let body = scrape(addr).await.unwrap();
+        for family in [
+            "# TYPE uchat_messages_relayed_total counter",
+            "# TYPE uchat_auth_failures_total counter",
+            "# TYPE uchat_db_query_duration_seconds histogram",
+            "# TYPE process_start_time_seconds gauge",
+            "# TYPE process_resident_memory_bytes gauge",
+            "# TYPE process_cpu_seconds_total counter",
+            "# TYPE uchat_tasks gauge",
+        ] {
+            assert!(body.contains(family), "{} missing from\n{}", family, body);
+        }
+        assert!(body.contains("uchat_messages_relayed_total{service=\"test-service\"} 3\n"));
+        assert!(body.contains("uchat_auth_failures_total{service=\"test-service\",reason=\"invalid_token\"} 2\n"));
+        assert!(body.contains(
+            "uchat_db_query_duration_seconds_bucket{service=\"test-service\",query=\"load_user\",le=\"0.005\"} 2\n"
+        ));
+        assert!(body.contains("uchat_db_query_duration_seconds_count{service=\"test-service\",query=\"load_user\"} 2\n"));
+        assert!(body.ends_with("custom_total 1\n"));
+
+        let (status, _) = get_path(addr, "/other").await.unwrap();
+        assert_eq!(status, hyper::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_clients_that_never_finish_the_request() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(!String::from_utf8_lossy(&rest).contains("200 OK"));
+    }
+}
@@ -0,0 +1,72 @@
+//! What zstd buys per frame: each case prints the encoded size against
+//! the original, then times encoding and decoding it.
+//!   cargo bench -p uchat-proto --bench compression
+//!
+//! Short chat lines are left as they are (zstd's own header would make
+//! them bigger); anything pasted, like code or logs, shrinks several
+//! times over.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use uchat_proto::compression::{decode, encode};
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::{ChannelId, RoomId, UserId};
+
+fn frame(content: String) -> Vec<u8> {
+    let event = ServerEvent::MessageBroadcast {
+        room_id: RoomId::from(ChannelId::new()),
+        from: UserId::new(),
+        content,
+        encrypted: false,
+        content_type: "text/plain".into(),
+        seq: Some(1_024),
+        bridged_from: None,
+        sender_name: Some("alice".into()),
+        message_id: None,
+        sender_role: None,
+        redacted: false,
+        traceparent: None,
+    };
+    serde_json::to_vec(&event).unwrap()
+}
+
+fn cases() -> Vec<(&'static str, Vec<u8>)> {
+    let paste = r#"impl Hub {
+    pub async fn broadcast(&self, room_id: &RoomId, json: String) {
+        if let Some(room) = self.rooms.read().await.get(room_id) {
+            let _ = room.send(RoomMessage::json(&json, None));
+        }
+    }
+}
+"#
+    .repeat(8);
+    let log = (0..40)
+        .map(|i| format!("2026-10-16T09:14:{:02}Z INFO gateway connected user_id=7f3e{:04} room=general\n", i, i * 7))
+        .collect();
+    vec![
+        ("short_line", frame("lunch at noon?".into())),
+        ("sentence", frame("Deploy went out at 9:14; rollback plan is in the runbook if the error rate climbs.".into())),
+        ("code_paste", frame(paste)),
+        ("log_paste", frame(log)),
+    ]
+}
+
+fn compression(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compression");
+    for (name, payload) in cases() {
+        let encoded = encode(&payload);
+        println!(
+            "{:<10} {:>5} bytes -> {:>5} bytes ({:.2}x)",
+            name,
+            payload.len(),
+            encoded.len(),
+            payload.len() as f64 / encoded.len() as f64
+        );
+        group.bench_function(format!("encode_{}", name), |b| b.iter(|| encode(&payload)));
+        group.bench_function(format!("decode_{}", name), |b| b.iter(|| decode(&encoded).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+//! CPU time for 10k serialize-and-parse cycles of a room message, JSON
+//! against MessagePack:
+//!   cargo bench -p uchat-proto --bench serialization
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use uchat_proto::events::ServerEvent;
+use uchat_proto::format::{from_msgpack, to_msgpack};
+use uchat_proto::ids::{ChannelId, RoomId, UserId};
+
+const CYCLES: usize = 10_000;
+
+fn message() -> ServerEvent {
+    ServerEvent::MessageBroadcast {
+        room_id: RoomId::from(ChannelId::new()),
+        from: UserId::new(),
+        content: r#"{"sensor":"boiler-3","temp_c":71.5,"pressure_kpa":212}"#.into(),
+        encrypted: false,
+        content_type: "application/json".into(),
+        seq: Some(1_024),
+        bridged_from: None,
+        sender_name: None,
+        message_id: None,
+        sender_role: None,
+        redacted: false,
+        traceparent: None,
+    }
+}
+
+fn cycles(c: &mut Criterion) {
+    let event = message();
+    let mut group = c.benchmark_group("10k_cycles");
+    group.bench_function("json", |b| {
+        b.iter(|| {
+            for _ in 0..CYCLES {
+                let bytes = serde_json::to_vec(&event).unwrap();
+                let _: ServerEvent = serde_json::from_slice(&bytes).unwrap();
+            }
+        })
+    });
+    group.bench_function("msgpack", |b| {
+        b.iter(|| {
+            for _ in 0..CYCLES {
+                let bytes = to_msgpack(&event).unwrap();
+                let _: ServerEvent = from_msgpack(&bytes).unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, cycles);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use uchat_proto::events::{ClientEvent, ClientFrame};
+
+// Clients send `ClientFrame`s; the bare `ClientEvent` form is what
+// chat-service reads. Both must reject garbage without panicking and
+// round-trip whatever they accept.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(event) = serde_json::from_slice::<ClientEvent>(data) {
+        let json = serde_json::to_string(&event).expect("serialize parsed event");
+        serde_json::from_str::<ClientEvent>(&json).expect("reparse serialized event");
+    }
+    if let Ok(frame) = serde_json::from_slice::<ClientFrame>(data) {
+        let json = serde_json::to_string(&frame).expect("serialize parsed frame");
+        serde_json::from_str::<ClientFrame>(&json).expect("reparse serialized frame");
+    }
+});
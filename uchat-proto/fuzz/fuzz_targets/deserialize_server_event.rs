@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use uchat_proto::events::ServerEvent;
+
+// Arbitrary bytes must never panic the parser, and anything it accepts
+// must survive a serialize/parse round trip.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(event) = serde_json::from_slice::<ServerEvent>(data) {
+        let json = serde_json::to_string(&event).expect("serialize parsed event");
+        serde_json::from_str::<ServerEvent>(&json).expect("reparse serialized event");
+    }
+});
@@ -0,0 +1,161 @@
+//! Client-side bookkeeping for events awaiting an `Ack`/`Nack`.
+//!
+//! Shared by every client so a missing ack times out the same way
+//! everywhere: insert the event under its `cid` with a timeout, resolve it
+//! when the server answers, and periodically collect whatever expired.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Time source, swappable in tests.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug)]
+pub struct PendingAcks<T, C = SystemClock> {
+    clock: C,
+    pending: HashMap<String, (Instant, T)>,
+}
+
+impl<T> PendingAcks<T> {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<T> Default for PendingAcks<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, C: Clock> PendingAcks<T, C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self { clock, pending: HashMap::new() }
+    }
+
+    /// Tracks `item` until `timeout` from now. Re-inserting a `cid`
+    /// replaces the earlier entry.
+    pub fn insert(&mut self, cid: impl Into<String>, timeout: Duration, item: T) {
+        let deadline = self.clock.now() + timeout;
+        self.pending.insert(cid.into(), (deadline, item));
+    }
+
+    /// Removes and returns the entry for `cid` if it is still within its
+    /// deadline. Late answers return `None` and leave the entry for
+    /// [`expire`](Self::expire), so a timeout is reported exactly once.
+    pub fn resolve(&mut self, cid: &str) -> Option<T> {
+        let now = self.clock.now();
+        match self.pending.get(cid) {
+            Some((deadline, _)) if *deadline > now => self.pending.remove(cid).map(|(_, item)| item),
+            _ => None,
+        }
+    }
+
+    /// Removes and returns every entry whose deadline has passed.
+    pub fn expire(&mut self) -> Vec<(String, T)> {
+        let now = self.clock.now();
+        let expired: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(cid, _)| cid.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|cid| self.pending.remove(&cid).map(|(_, item)| (cid, item)))
+            .collect()
+    }
+
+    /// Earliest deadline still pending, for scheduling the next `expire`.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct MockClock {
+        base: Instant,
+        offset: Rc<Cell<Duration>>,
+    }
+
+    impl MockClock {
+        fn new() -> Self {
+            Self { base: Instant::now(), offset: Rc::new(Cell::new(Duration::ZERO)) }
+        }
+
+        fn advance(&self, by: Duration) {
+            self.offset.set(self.offset.get() + by);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.base + self.offset.get()
+        }
+    }
+
+    #[test]
+    fn resolve_before_deadline() {
+        let clock = MockClock::new();
+        let mut acks = PendingAcks::with_clock(clock.clone());
+        acks.insert("c1", Duration::from_secs(5), "hello");
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(acks.resolve("c1"), Some("hello"));
+        assert!(acks.is_empty());
+        assert_eq!(acks.resolve("c1"), None);
+    }
+
+    #[test]
+    fn late_ack_is_reported_by_expire_only() {
+        let clock = MockClock::new();
+        let mut acks = PendingAcks::with_clock(clock.clone());
+        acks.insert("c1", Duration::from_secs(5), 1);
+        acks.insert("c2", Duration::from_secs(10), 2);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(acks.resolve("c1"), None);
+
+        let expired = acks.expire();
+        assert_eq!(expired, vec![("c1".to_string(), 1)]);
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks.resolve("c2"), Some(2));
+    }
+
+    #[test]
+    fn next_deadline_tracks_earliest() {
+        let clock = MockClock::new();
+        let mut acks = PendingAcks::with_clock(clock.clone());
+        assert!(acks.next_deadline().is_none());
+
+        acks.insert("slow", Duration::from_secs(30), ());
+        acks.insert("fast", Duration::from_secs(2), ());
+        assert_eq!(acks.next_deadline(), Some(clock.now() + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{ChannelId, MessageId, UserId};
+
+/// What happened to a message, as recorded in its audit trail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageAction {
+    Created,
+    Edited,
+    Deleted,
+    FileAttached,
+}
+
+impl MessageAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageAction::Created => "created",
+            MessageAction::Edited => "edited",
+            MessageAction::Deleted => "deleted",
+            MessageAction::FileAttached => "file_attached",
+        }
+    }
+}
+
+impl fmt::Display for MessageAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MessageAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(MessageAction::Created),
+            "edited" => Ok(MessageAction::Edited),
+            "deleted" => Ok(MessageAction::Deleted),
+            "file_attached" => Ok(MessageAction::FileAttached),
+            other => Err(format!("unknown message action {:?}", other)),
+        }
+    }
+}
+
+/// What happened to an upload, as recorded in `file_audit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileAction {
+    Quarantined,
+}
+
+impl FileAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileAction::Quarantined => "quarantined",
+        }
+    }
+}
+
+/// What happened to a user's spam hold or to a message a content filter
+/// caught, as recorded in `moderation_audit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// The gateway started holding the user's messages.
+    Flagged,
+    Cleared,
+    Confirmed,
+    /// A content filter rewrote a message.
+    Redacted,
+    /// A content filter refused a message.
+    Blocked,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Flagged => "flagged",
+            ModerationAction::Cleared => "cleared",
+            ModerationAction::Confirmed => "confirmed",
+            ModerationAction::Redacted => "redacted",
+            ModerationAction::Blocked => "blocked",
+        }
+    }
+}
+
+/// One row of `message_audit`. Content is never stored, only SHA-256
+/// hashes (hex) of the text before and after the change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageAuditEntry {
+    pub id: i64,
+    pub message_id: MessageId,
+    pub channel_id: ChannelId,
+    pub actor_id: UserId,
+    pub action: MessageAction,
+    #[serde(default)]
+    pub before_hash: Option<String>,
+    #[serde(default)]
+    pub after_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
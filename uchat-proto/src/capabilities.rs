@@ -0,0 +1,37 @@
+use std::collections::HashSet;
+
+/// Client supports encrypted messages.
+pub const E2EE: &str = "e2ee";
+/// Client can upload and download attachments.
+pub const FILE_TRANSFER: &str = "file-transfer";
+pub const VOICE: &str = "voice";
+/// Client can show thread rooms.
+pub const THREADING: &str = "threading";
+/// Client reads and writes frames flagged and zstd-compressed as in
+/// `compression`.
+pub const COMPRESSION_ZSTD: &str = "compression:zstd";
+
+/// The features a client and server both support, out of what the client
+/// declared in `Hello`. Names either side doesn't know are dropped, so
+/// new ones can be added without breaking older peers.
+pub fn negotiate(client: &HashSet<String>, server: &[&str]) -> HashSet<String> {
+    server.iter().filter(|cap| client.contains(**cap)).map(|cap| cap.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(caps: &[&str]) -> HashSet<String> {
+        caps.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_what_both_sides_support() {
+        let client = set(&[E2EE, VOICE, "holograms"]);
+        assert_eq!(negotiate(&client, &[E2EE, THREADING, FILE_TRANSFER]), set(&[E2EE]));
+        assert_eq!(negotiate(&client, &[]), set(&[]));
+        assert_eq!(negotiate(&set(&[]), &[E2EE, THREADING]), set(&[]));
+        assert_eq!(negotiate(&set(&[THREADING, E2EE]), &[E2EE, THREADING]), set(&[E2EE, THREADING]));
+    }
+}
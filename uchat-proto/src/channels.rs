@@ -0,0 +1,293 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{ChannelId, InviteId, RoomId, UserId, WebhookId};
+use crate::permissions::RoomRole;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum ChannelType {
+    /// Listed to everyone.
+    #[default]
+    Public,
+    /// Visible to members only.
+    Private,
+}
+
+impl ChannelType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelType::Public => "public",
+            ChannelType::Private => "private",
+        }
+    }
+}
+
+impl fmt::Display for ChannelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ChannelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(ChannelType::Public),
+            "private" => Ok(ChannelType::Private),
+            other => Err(format!("unknown channel type {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Channel {
+    pub id: ChannelId,
+    pub name: String,
+    pub description: String,
+    pub channel_type: ChannelType,
+    pub created_by: UserId,
+    pub created_at: DateTime<Utc>,
+    /// Refuse uploads whose sniffed type is on the server's deny-list.
+    #[serde(default)]
+    pub restrict_file_types: bool,
+    /// Messages and files older than this many days are purged; `None`
+    /// keeps everything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<i32>,
+    /// Set while the channel is archived: readable, but nobody can post.
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
+    /// Let markdown formatting tags through the gateway's HTML
+    /// sanitizer; otherwise messages are stripped to plain text.
+    #[serde(default)]
+    pub allow_markdown_formatting: bool,
+    /// Unfurl links in the channel's messages into previews.
+    #[serde(default = "yes")]
+    pub link_previews: bool,
+    /// Only end-to-end encrypted messages may be sent; clients should
+    /// encrypt every send.
+    #[serde(default)]
+    pub e2ee_required: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateChannel {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, alias = "type")]
+    pub channel_type: ChannelType,
+    #[serde(default)]
+    pub restrict_file_types: bool,
+    #[serde(default)]
+    pub allow_markdown_formatting: bool,
+    #[serde(default)]
+    pub e2ee_required: bool,
+    /// Added as writers alongside the creator, who is the admin.
+    #[serde(default)]
+    pub member_ids: Vec<UserId>,
+}
+
+/// PATCH body; absent fields are left unchanged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateChannel {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub restrict_file_types: Option<bool>,
+    #[serde(default)]
+    pub allow_markdown_formatting: Option<bool>,
+    #[serde(default)]
+    pub link_previews: Option<bool>,
+    /// Fixed when the channel is created; only its current value is
+    /// accepted.
+    #[serde(default)]
+    pub e2ee_required: Option<bool>,
+    /// `0` turns retention off.
+    #[serde(default)]
+    pub retention_days: Option<i32>,
+}
+
+/// What the retention job would delete from a channel if it ran now.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPreview {
+    pub channel_id: ChannelId,
+    pub retention_days: Option<i32>,
+    /// Anything created before this goes; `None` when retention is off.
+    pub cutoff: Option<DateTime<Utc>>,
+    pub messages: i64,
+    pub files: i64,
+    pub file_bytes: i64,
+}
+
+/// A member's role in a channel, stored in `channel_members.role`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    Read,
+    #[default]
+    Write,
+    /// Can post, and manage the channel and its members.
+    Admin,
+}
+
+impl MemberRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemberRole::Read => "read",
+            MemberRole::Write => "write",
+            MemberRole::Admin => "admin",
+        }
+    }
+
+    /// What the role allows in the channel's realtime room.
+    pub fn room_role(&self) -> RoomRole {
+        match self {
+            MemberRole::Read => RoomRole::Read,
+            MemberRole::Write | MemberRole::Admin => RoomRole::Write,
+        }
+    }
+}
+
+impl fmt::Display for MemberRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for MemberRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(MemberRole::Read),
+            "write" => Ok(MemberRole::Write),
+            "admin" => Ok(MemberRole::Admin),
+            other => Err(format!("unknown member role {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub user_id: UserId,
+    pub role: MemberRole,
+}
+
+/// POST body for adding a member. Without `user_id` the caller joins
+/// the channel themselves.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddMember {
+    #[serde(default)]
+    pub user_id: Option<UserId>,
+    #[serde(default)]
+    pub role: Option<MemberRole>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateMember {
+    pub role: MemberRole,
+}
+
+/// POST body for an invite link; without limits it works until revoked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateInvite {
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub max_uses: Option<i32>,
+}
+
+/// An invite link to a channel. Only a hash of the token is stored, so
+/// `token` is present in the response that creates the invite and
+/// nowhere else.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: InviteId,
+    pub channel_id: ChannelId,
+    pub created_by: UserId,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_uses: Option<i32>,
+    /// `None` when the invite has no use limit.
+    pub remaining_uses: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// POST body for an incoming webhook; `display_name` is who its
+/// messages appear to be from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateIncomingWebhook {
+    pub display_name: String,
+}
+
+/// A channel's incoming webhook. `secret` authenticates posts to it and
+/// is only in the response that creates the hook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IncomingWebhook {
+    pub id: WebhookId,
+    pub channel_id: ChannelId,
+    pub display_name: String,
+    pub created_by: UserId,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+/// What someone holding an invite token sees before accepting it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvitePreview {
+    pub channel_id: ChannelId,
+    pub name: String,
+    pub description: String,
+    pub channel_type: ChannelType,
+    pub member_count: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Pushed from channels-api to the gateway whenever a membership changes,
+/// so connected sockets pick up the new role without a fresh token.
+/// `role: None` means the user was removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MembershipChange {
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub role: Option<MemberRole>,
+}
+
+/// A room the gateway has open, as `/internal/rooms` lists them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomSubscribers {
+    pub room_id: RoomId,
+    /// Sockets, polling sessions and streams receiving the room.
+    pub subscribers: u32,
+}
+
+/// Pushed from channels-api to the gateway when a channel is archived
+/// (`archived_at` set) or reactivated (`archived_at: None`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelArchiveChanged {
+    pub channel_id: ChannelId,
+    pub archived_at: Option<DateTime<Utc>>,
+}
+
+/// Pushed from channels-api to the gateway after a channel is created, so
+/// the members it was created with hear about it right away.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelCreated {
+    pub channel: Channel,
+    pub member_ids: Vec<UserId>,
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{InvocationId, RoomId, UserId};
+
+/// A slash command as registered with the gateway's
+/// `POST /internal/commands`. Messages starting with `/{name}` go to
+/// `target` instead of the room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlashCommand {
+    /// Lowercase letters, digits, `-` and `_`, without the slash.
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Positional arguments, split on whitespace; the last one takes the
+    /// rest of the line.
+    #[serde(default)]
+    pub args: Vec<CommandArg>,
+    pub target: CommandTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandArg {
+    pub name: String,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CommandTarget {
+    /// POSTed a `CommandInvocation`, answering with a `CommandResponse`
+    /// or an empty body.
+    Webhook { url: String },
+    /// Sent `ServerEvent::CommandInvoked` on its sockets, answering with
+    /// `ClientEvent::CommandResponse`.
+    Bot { user_id: UserId },
+}
+
+/// One use of a slash command, as its target receives it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandInvocation {
+    pub id: InvocationId,
+    pub command: String,
+    /// By argument name; optional arguments left out are absent.
+    pub args: BTreeMap<String, String>,
+    /// Everything after the command name, as typed.
+    pub text: String,
+    /// Who typed the command, and where.
+    pub user_id: UserId,
+    pub room_id: RoomId,
+    /// The `role` claim of the invoker's token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_role: Option<String>,
+}
+
+/// A webhook's answer to a `CommandInvocation`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub text: String,
+    /// Show `text` to the invoker only, rather than post it to the room.
+    #[serde(default)]
+    pub ephemeral: bool,
+}
+
+impl SlashCommand {
+    /// Why the registration can't be used, if it can't.
+    pub fn validate(&self) -> Result<(), String> {
+        let name_ok = !self.name.is_empty()
+            && self.name.len() <= 32
+            && self.name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+        if !name_ok {
+            return Err("name must be 1-32 lowercase letters, digits, '-' or '_'".into());
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            if arg.name.is_empty() || self.args[..i].iter().any(|a| a.name == arg.name) {
+                return Err(format!("argument {} needs a unique name", i + 1));
+            }
+            if arg.required && i > 0 && !self.args[i - 1].required {
+                return Err(format!("required argument {} follows an optional one", arg.name));
+            }
+        }
+        if let CommandTarget::Webhook { url } = &self.target {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                return Err("webhook url must be http(s)".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches `text` (what follows the command name) against the
+    /// arguments, or returns a usage line when a required one is missing.
+    pub fn parse_args(&self, text: &str) -> Result<BTreeMap<String, String>, String> {
+        let mut args = BTreeMap::new();
+        let mut rest = text.trim();
+        for (i, arg) in self.args.iter().enumerate() {
+            let value = if i + 1 == self.args.len() {
+                std::mem::take(&mut rest)
+            } else {
+                let (word, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                rest = tail.trim_start();
+                word
+            };
+            if !value.is_empty() {
+                args.insert(arg.name.clone(), value.to_string());
+            } else if arg.required {
+                return Err(self.usage());
+            }
+        }
+        Ok(args)
+    }
+
+    /// `/name <required> [optional]`.
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in &self.args {
+            let arg = if arg.required { format!(" <{}>", arg.name) } else { format!(" [{}]", arg.name) };
+            usage.push_str(&arg);
+        }
+        format!("usage: {}", usage)
+    }
+}
+
+/// Splits `/name rest` into the command name and the rest, or `None`
+/// when `content` isn't a command. `//` at the start escapes a message
+/// that should go out with its slash.
+pub fn split_command(content: &str) -> Option<(&str, &str)> {
+    let line = content.strip_prefix('/')?;
+    if line.starts_with('/') || line.starts_with(char::is_whitespace) || line.is_empty() {
+        return None;
+    }
+    Some(line.split_once(char::is_whitespace).unwrap_or((line, "")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remind() -> SlashCommand {
+        SlashCommand {
+            name: "remind".into(),
+            description: String::new(),
+            args: vec![
+                CommandArg { name: "who".into(), required: true },
+                CommandArg { name: "when".into(), required: true },
+                CommandArg { name: "what".into(), required: false },
+            ],
+            target: CommandTarget::Webhook { url: "https://example.com/remind".into() },
+        }
+    }
+
+    #[test]
+    fn splits_commands_from_messages() {
+        assert_eq!(split_command("/remind me in 10m"), Some(("remind", "me in 10m")));
+        assert_eq!(split_command("/ping"), Some(("ping", "")));
+        assert_eq!(split_command("//not a command"), None);
+        assert_eq!(split_command("/ spaced"), None);
+        assert_eq!(split_command("/"), None);
+        assert_eq!(split_command("hello /ping"), None);
+    }
+
+    #[test]
+    fn last_argument_takes_the_rest() {
+        let args = remind().parse_args("me  in 10m  to stretch ").unwrap();
+        assert_eq!(args["who"], "me");
+        assert_eq!(args["when"], "in");
+        assert_eq!(args["what"], "10m  to stretch");
+
+        let args = remind().parse_args("me 10m").unwrap();
+        assert_eq!(args.len(), 2);
+        assert_eq!(remind().parse_args("me"), Err("usage: /remind <who> <when> [what]".into()));
+    }
+
+    #[test]
+    fn rejects_bad_registrations() {
+        assert!(remind().validate().is_ok());
+        let bad = [
+            SlashCommand { name: "Remind".into(), ..remind() },
+            SlashCommand { name: String::new(), ..remind() },
+            SlashCommand { args: vec![CommandArg { name: "a".into(), required: false }, CommandArg { name: "b".into(), required: true }], ..remind() },
+            SlashCommand { args: vec![CommandArg { name: "a".into(), required: true }, CommandArg { name: "a".into(), required: true }], ..remind() },
+            SlashCommand { target: CommandTarget::Webhook { url: "ftp://example.com".into() }, ..remind() },
+        ];
+        for command in bad {
+            assert!(command.validate().is_err(), "{:?}", command);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Per-frame compression for sockets that negotiated
+//! `capabilities::COMPRESSION_ZSTD`. From the `Capabilities` answer on,
+//! every frame is binary: a flag byte, then the frame in the socket's
+//! format, zstd-compressed when the flag says so.
+//!
+//! Compression only pays off for longer frames; see the `compression`
+//! bench. Short chat lines go as they are, flagged `UNCOMPRESSED`.
+
+use std::fmt;
+use std::io::Read;
+
+/// Flag for a frame sent as it is.
+pub const UNCOMPRESSED: u8 = 0x00;
+/// Flag for a zstd-compressed frame.
+pub const ZSTD: u8 = 0x01;
+/// zstd's default level: most of the ratio for a fraction of the time.
+pub const LEVEL: i32 = 3;
+/// Frames shorter than this aren't worth compressing.
+pub const MIN_COMPRESSED_LEN: usize = 256;
+/// Largest frame a compressed one may expand to, so a small frame can't
+/// inflate into gigabytes.
+pub const MAX_DECOMPRESSED_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    UnknownFlag(u8),
+    /// The zstd stream is damaged or expands past `MAX_DECOMPRESSED_LEN`.
+    Corrupt,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => f.write_str("empty frame"),
+            DecodeError::UnknownFlag(flag) => write!(f, "unknown compression flag {:#04x}", flag),
+            DecodeError::Corrupt => f.write_str("corrupt or oversized compressed frame"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Flags `payload` and compresses it, unless it is short or doesn't
+/// shrink.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    if payload.len() >= MIN_COMPRESSED_LEN {
+        if let Ok(compressed) = zstd::encode_all(payload, LEVEL) {
+            if compressed.len() < payload.len() {
+                return flagged(ZSTD, &compressed);
+            }
+        }
+    }
+    flagged(UNCOMPRESSED, payload)
+}
+
+fn flagged(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 1);
+    frame.push(flag);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// The payload of a frame made by `encode`.
+pub fn decode(frame: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let (&flag, payload) = frame.split_first().ok_or(DecodeError::Empty)?;
+    match flag {
+        UNCOMPRESSED => Ok(payload.to_vec()),
+        ZSTD => {
+            let decoder = zstd::stream::read::Decoder::new(payload).map_err(|_| DecodeError::Corrupt)?;
+            let mut decoded = Vec::new();
+            decoder
+                .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+                .read_to_end(&mut decoded)
+                .map_err(|_| DecodeError::Corrupt)?;
+            if decoded.len() > MAX_DECOMPRESSED_LEN {
+                return Err(DecodeError::Corrupt);
+            }
+            Ok(decoded)
+        }
+        other => Err(DecodeError::UnknownFlag(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_only_when_it_pays() {
+        let short = br#"{"type":"SendMessage","content":"hi"}"#;
+        let frame = encode(short);
+        assert_eq!((frame[0], &frame[1..]), (UNCOMPRESSED, &short[..]));
+        assert_eq!(decode(&frame).unwrap(), short);
+
+        let paste = "fn main() {\n    println!(\"hello\");\n}\n".repeat(20);
+        let frame = encode(paste.as_bytes());
+        assert_eq!(frame[0], ZSTD);
+        assert!(frame.len() < paste.len() / 4);
+        assert_eq!(decode(&frame).unwrap(), paste.as_bytes());
+
+        // Random bytes don't shrink, so they go as they are.
+        let mut x = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..1024)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        assert_eq!(encode(&noise)[0], UNCOMPRESSED);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        assert_eq!(decode(&[]), Err(DecodeError::Empty));
+        assert_eq!(decode(&[0x02, b'x']), Err(DecodeError::UnknownFlag(0x02)));
+        assert_eq!(decode(&[ZSTD, 1, 2, 3]), Err(DecodeError::Corrupt));
+
+        let bomb = zstd::encode_all(&vec![0u8; MAX_DECOMPRESSED_LEN + 1][..], LEVEL).unwrap();
+        assert!(bomb.len() < 1024);
+        assert_eq!(decode(&flagged(ZSTD, &bomb)), Err(DecodeError::Corrupt));
+    }
+}
@@ -1,6 +1,127 @@
 use serde::{Serialize, Deserialize};
 
+/// JSON error body returned by the REST services.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiError {
+    pub code: ErrorCode,
     pub message: String,
 }
+
+/// Machine-readable failure reasons shared by the services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidEvent,
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    PayloadTooLarge,
+    ChecksumMismatch,
+    FileTypeDenied,
+    RateLimited,
+    /// The channel is archived and read-only.
+    ChannelArchived,
+    /// The invite link is past its expiry.
+    InviteExpired,
+    /// The invite link has no uses left.
+    InviteExhausted,
+    /// A signed download link is past its expiry.
+    LinkExpired,
+    /// The event needs a capability the client didn't declare in `Hello`.
+    MissingCapability,
+    /// The event is about a room the socket hasn't subscribed to.
+    NotSubscribed,
+    /// A relayed message has been passed on too many times.
+    HopLimitReached,
+    /// The feature isn't running on this server.
+    Unavailable,
+    /// The account is suspended; `until` on the error says when it ends.
+    AccountSuspended,
+    AccountDeleted,
+    /// No slash command is registered under that name.
+    UnknownCommand,
+    /// A content filter refused the message.
+    ContentBlocked,
+    /// The channel only takes end-to-end encrypted messages.
+    E2eeRequired,
+    Internal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// No wildcard arm, so a new code doesn't compile until it is named
+    /// here and in `ALL`.
+    fn wire_name(code: ErrorCode) -> &'static str {
+        match code {
+            ErrorCode::InvalidEvent => "invalid_event",
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::ChecksumMismatch => "checksum_mismatch",
+            ErrorCode::FileTypeDenied => "file_type_denied",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::ChannelArchived => "channel_archived",
+            ErrorCode::InviteExpired => "invite_expired",
+            ErrorCode::InviteExhausted => "invite_exhausted",
+            ErrorCode::LinkExpired => "link_expired",
+            ErrorCode::MissingCapability => "missing_capability",
+            ErrorCode::NotSubscribed => "not_subscribed",
+            ErrorCode::HopLimitReached => "hop_limit_reached",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::AccountSuspended => "account_suspended",
+            ErrorCode::AccountDeleted => "account_deleted",
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::ContentBlocked => "content_blocked",
+            ErrorCode::E2eeRequired => "e2ee_required",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    const ALL: [ErrorCode; 24] = [
+        ErrorCode::InvalidEvent,
+        ErrorCode::InvalidRequest,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::NotFound,
+        ErrorCode::Conflict,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::ChecksumMismatch,
+        ErrorCode::FileTypeDenied,
+        ErrorCode::RateLimited,
+        ErrorCode::ChannelArchived,
+        ErrorCode::InviteExpired,
+        ErrorCode::InviteExhausted,
+        ErrorCode::LinkExpired,
+        ErrorCode::MissingCapability,
+        ErrorCode::NotSubscribed,
+        ErrorCode::HopLimitReached,
+        ErrorCode::Unavailable,
+        ErrorCode::AccountSuspended,
+        ErrorCode::AccountDeleted,
+        ErrorCode::UnknownCommand,
+        ErrorCode::ContentBlocked,
+        ErrorCode::E2eeRequired,
+        ErrorCode::Internal,
+    ];
+
+    #[test]
+    fn codes_round_trip_as_snake_case() {
+        for code in ALL {
+            let json = serde_json::to_string(&code).unwrap();
+            assert_eq!(json, format!("\"{}\"", wire_name(code)));
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), code);
+        }
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        assert!(serde_json::from_str::<ErrorCode>(r#""content_filtered""#).is_err());
+    }
+}
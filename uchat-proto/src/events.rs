@@ -1,14 +1,359 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 
+use crate::channels::Channel;
+use crate::commands::CommandInvocation;
+use crate::errors::ErrorCode;
+use crate::format::SerializationFormat;
+use crate::ids::{ChannelId, InvocationId, MessageId, RoomId, UserId};
+use crate::messages::LinkPreview;
+use crate::moderation::SpamReason;
+
+/// The `schema_version` current clients put on every frame. Frames
+/// without one are v0 and need upgrading before they parse.
+pub const CURRENT_SCHEMA_VERSION: u32 = 1;
+
+/// New variants may be added in minor releases, so matches outside this
+/// crate need a wildcard arm; the same goes for `ServerEvent`.
 #[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum ClientEvent {
     Login { username: String, password: String },
-    SendMessage { content: String },
+    /// Join a channel room, or a thread room as `{channel}:thread:{id}`.
+    Subscribe { room_id: RoomId },
+    /// Post to a channel, or to one of its threads when `thread_id` is set.
+    SendMessage {
+        room_id: ChannelId,
+        content: String,
+        /// Whether `content` is ciphertext the server can't read.
+        encrypted: bool,
+        /// MIME type of `content`, e.g. `text/plain` or `text/markdown`.
+        content_type: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        thread_id: Option<MessageId>,
+    },
+    /// Advance the sender's read marker in `room_id` to `message_id`.
+    MarkRead { room_id: ChannelId, message_id: MessageId },
+    /// The sender is typing in `room_id`; repeat every few seconds while
+    /// they keep typing.
+    Typing { room_id: RoomId },
+    /// Ask who is online and typing in `room_id`; answered with `Who`.
+    Who { room_id: ChannelId },
+    /// First frame of a socket. `last_seq`, the highest `seq` the client
+    /// saw before reconnecting, asks for a `MessageBatch` of what it
+    /// missed. `capabilities` lists the features it supports (see
+    /// `capabilities`), answered with `Capabilities`; clients that leave
+    /// it out are assumed to support everything. `format` switches the
+    /// socket's encoding from JSON, starting with that answer.
+    Hello {
+        #[serde(default)]
+        last_seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<HashSet<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<SerializationFormat>,
+    },
+    /// Repost `payload` from `source_room` to `target_room`, for bridges
+    /// to other chat platforms; only tokens with the `bridge` role may.
+    /// The message being relayed is named by its `seq` or `message_id`
+    /// and must still be in the gateway's recent history; its hop count
+    /// comes from there, and relays stop after `MAX_RELAY_HOPS`.
+    RelayMessage {
+        source_room: RoomId,
+        target_room: RoomId,
+        payload: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<MessageId>,
+    },
+    /// A bot's answer to the `CommandInvoked` it got as `invocation_id`:
+    /// posted to the invocation's room as the bot, or with `ephemeral`
+    /// shown only to whoever typed the command.
+    CommandResponse {
+        invocation_id: InvocationId,
+        text: String,
+        #[serde(default)]
+        ephemeral: bool,
+    },
+}
+
+/// Most times one message may be relayed between rooms, so bridges
+/// pointed at each other don't echo it forever.
+pub const MAX_RELAY_HOPS: u32 = 3;
+
+/// A client event plus its optional correlation id.
+///
+/// Any client-originated event may carry a `cid`; the server echoes it
+/// verbatim as `client_id` in the matching `Ack`/`Nack`. On the wire the
+/// id sits next to the event tag and the frame's schema version:
+/// `{"schema_version":1,"cid":"c-17","SendMessage":{…}}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientFrame {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+    /// `ts_gateway` of the `MessageTimestamp` this frame replies to, so
+    /// the gateway can measure the round trip.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts_gateway: Option<u128>,
+    /// Id of the user action this frame is part of, tagging the server's
+    /// logs and the requests it makes on the frame's behalf. Frames
+    /// without one belong to their connection's correlation id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(flatten)]
+    pub event: ClientEvent,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum ServerEvent {
-    LoginOk { token: String },
-    MessageBroadcast { from: String, content: String },
-    Error { details: String },
+    /// `token` is the access token; `refresh_token` trades for a new one
+    /// at `/refresh` once it expires, and comes only from `/login`.
+    LoginOk {
+        token: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        refresh_token: Option<String>,
+    },
+    /// `room_id` is the thread room for thread messages. `seq` orders
+    /// client messages across rooms, for `Hello` after a reconnect.
+    MessageBroadcast {
+        room_id: RoomId,
+        from: UserId,
+        content: String,
+        encrypted: bool,
+        content_type: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// Set when a bridge relayed the message here; `from` is then the
+        /// bridge.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bridged_from: Option<BridgedFrom>,
+        /// Who to show the message as from when `from` isn't a user, such
+        /// as an incoming webhook.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sender_name: Option<String>,
+        /// The stored message, for messages channels-api keeps; what
+        /// reactions and edits refer to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<MessageId>,
+        /// The `role` claim of the sender's token, for bots that gate
+        /// commands on it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sender_role: Option<String>,
+        /// Set when a content filter rewrote the message.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        redacted: bool,
+        /// W3C trace context of the request the message was sent in, so
+        /// services reading broadcasts can join its trace.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        traceparent: Option<String>,
+    },
+    /// Answers `Hello` with the messages missed since `last_seq`, oldest
+    /// first. `truncated` means some could not be replayed, and the client
+    /// should reload its rooms' history from channels-api.
+    MessageBatch { messages: Vec<SequencedMessage>, truncated: bool },
+    /// Answers `Hello` with the capabilities this socket gets: those both
+    /// the client and the server support, and the encoding it now uses.
+    Capabilities {
+        capabilities: HashSet<String>,
+        #[serde(default)]
+        format: SerializationFormat,
+    },
+    /// A request or frame failed: `code` for programs, `message` for
+    /// people.
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// When the condition ends, for time-limited errors such as
+        /// `account_suspended` (RFC 3339).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<String>,
+    },
+    /// The event identified by `client_id` was accepted and stored as
+    /// `server_id`, the `seq`-th message in its room. `ts` is unix millis.
+    Ack { client_id: Option<String>, server_id: MessageId, seq: u64, ts: i64 },
+    /// The event identified by `client_id` was rejected. `retryable` says
+    /// whether resending the same event later may succeed.
+    Nack { client_id: Option<String>, code: ErrorCode, retryable: bool },
+    /// The user lost access to `room_id` and was unsubscribed from it.
+    Removed { room_id: ChannelId },
+    /// The channel's retention policy deleted these messages; clients
+    /// should drop them from their caches.
+    MessagesExpired { room_id: ChannelId, message_ids: Vec<MessageId> },
+    /// New content for a message clients may already be showing.
+    MessageEdited { id: MessageId, channel_id: ChannelId, content: String, edited_at: DateTime<Utc> },
+    ReactionAdded { room_id: ChannelId, message_id: MessageId, user_id: UserId, emoji: String },
+    ReactionRemoved { room_id: ChannelId, message_id: MessageId, user_id: UserId, emoji: String },
+    /// The channel became read-only; `SendMessage` to it is nacked with
+    /// `channel_archived` until it is unarchived.
+    ChannelArchived { room_id: ChannelId, archived_at: DateTime<Utc> },
+    ChannelUnarchived { room_id: ChannelId },
+    /// Sent to each member a channel was created with.
+    ChannelCreated { channel: Channel },
+    /// Tombstone for a deleted message; clients should blank it out.
+    MessageDeleted { id: MessageId, channel_id: ChannelId },
+    /// A link in a stored message, unfurled; one event per link.
+    LinkPreview { message_id: MessageId, preview: LinkPreview },
+    /// `user_id` came online in, or went offline from, `room_id`.
+    Presence { room_id: ChannelId, user_id: UserId, online: bool },
+    Typing { room_id: RoomId, user_id: UserId },
+    Who { room_id: ChannelId, online: Vec<UserId>, typing: Vec<UserId> },
+    /// `user_id` has read `room_id` up to and including `message_id`.
+    ReadReceipt { room_id: ChannelId, user_id: UserId, message_id: MessageId },
+    /// Sent to a bot's sockets when someone uses a slash command
+    /// registered to it; answer with `ClientEvent::CommandResponse`.
+    CommandInvoked { invocation: CommandInvocation },
+    /// An answer to the recipient's own slash command in `room_id`, which
+    /// nobody else sees.
+    CommandResult { room_id: RoomId, command: String, text: String },
+    /// A notice from the server about the recipient's own activity in
+    /// `room_id`, such as an upload being quarantined, which nobody else
+    /// sees.
+    SystemMessage { room_id: ChannelId, text: String },
+    /// Sent to the moderation room when the gateway starts holding
+    /// `user_id`'s messages; `room_id` is where the message that tripped
+    /// the check was sent.
+    SpamFlagged { user_id: UserId, room_id: ChannelId, reasons: Vec<SpamReason> },
+}
+
+/// A `MessageBroadcast` as kept for replay.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SequencedMessage {
+    pub seq: u64,
+    pub room_id: RoomId,
+    pub from: UserId,
+    pub content: String,
+    pub encrypted: bool,
+    pub content_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bridged_from: Option<BridgedFrom>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<MessageId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_role: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+}
+
+/// Where a relayed message came from, and how many relays it has been
+/// through.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BridgedFrom {
+    pub room_id: RoomId,
+    pub hop_count: u32,
+}
+
+/// An outgoing message wrapped with when it entered the gateway's
+/// broadcast path, for sockets that opted in with `?timestamps=true`.
+/// `ts_gateway` is nanoseconds on the gateway's own clock; it only means
+/// something when echoed back to the same gateway. `payload` is the
+/// `ServerEvent` JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageTimestamp {
+    pub ts_gateway: u128,
+    pub payload: String,
+}
+
+impl ServerEvent {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerEvent::Error { code, message: message.into(), until: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_without_cid_is_a_bare_event() {
+        let room = ChannelId::new();
+        let json = format!(
+            r#"{{"schema_version":1,"SendMessage":{{"room_id":"{}","content":"hi","encrypted":false,"content_type":"text/plain"}}}}"#,
+            room
+        );
+
+        let frame: ClientFrame = serde_json::from_str(&json).unwrap();
+        assert!(frame.cid.is_none());
+        assert!(matches!(frame.event, ClientEvent::SendMessage { .. }));
+    }
+
+    #[test]
+    fn frames_need_a_schema_version() {
+        let json = format!(r#"{{"Subscribe":{{"room_id":"{}"}}}}"#, ChannelId::new());
+        assert!(serde_json::from_str::<ClientFrame>(&json).is_err());
+    }
+
+    #[test]
+    fn frame_cid_roundtrips() {
+        let frame = ClientFrame {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            cid: Some("c-17".into()),
+            ts_gateway: None,
+            correlation_id: Some("req-42".into()),
+            event: ClientEvent::Subscribe { room_id: ChannelId::new().into() },
+        };
+
+        let json = serde_json::to_string(&frame).unwrap();
+        let back: ClientFrame = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.cid.as_deref(), Some("c-17"));
+        assert_eq!(back.correlation_id.as_deref(), Some("req-42"));
+    }
+
+    #[test]
+    fn frames_echo_gateway_timestamps() {
+        let json = format!(
+            r#"{{"schema_version":1,"ts_gateway":18446744073709551616,"Subscribe":{{"room_id":"{}"}}}}"#,
+            ChannelId::new()
+        );
+        let frame: ClientFrame = serde_json::from_str(&json).unwrap();
+        assert_eq!(frame.ts_gateway, Some(u64::MAX as u128 + 1));
+    }
+
+    #[test]
+    fn hello_and_broadcast_seq_are_optional() {
+        let frame: ClientFrame = serde_json::from_str(r#"{"schema_version":1,"Hello":{}}"#).unwrap();
+        assert!(matches!(frame.event, ClientEvent::Hello { last_seq: None, capabilities: None, format: None }));
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"schema_version":1,"Hello":{"last_seq":41,"capabilities":["e2ee"]}}"#).unwrap();
+        let ClientEvent::Hello { last_seq, capabilities, .. } = frame.event else { panic!() };
+        assert_eq!(last_seq, Some(41));
+        assert_eq!(capabilities, Some(HashSet::from(["e2ee".to_string()])));
+
+        let json = format!(
+            r#"{{"MessageBroadcast":{{"room_id":"{}","from":"{}","content":"hi","encrypted":false,"content_type":"text/plain"}}}}"#,
+            ChannelId::new(),
+            UserId::new()
+        );
+        let event: ServerEvent = serde_json::from_str(&json).unwrap();
+        assert!(matches!(event, ServerEvent::MessageBroadcast { seq: None, .. }));
+        assert_eq!(serde_json::to_string(&event).unwrap(), json);
+    }
+
+    #[test]
+    fn nack_uses_snake_case_codes() {
+        let nack = ServerEvent::Nack {
+            client_id: Some("c-1".into()),
+            code: ErrorCode::RateLimited,
+            retryable: true,
+        };
+
+        let json = serde_json::to_string(&nack).unwrap();
+        assert_eq!(json, r#"{"Nack":{"client_id":"c-1","code":"rate_limited","retryable":true}}"#);
+    }
+
+    #[test]
+    fn errors_carry_a_code_and_a_message() {
+        let error = ServerEvent::error(ErrorCode::NotSubscribed, "not subscribed to the source room");
+
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(json, r#"{"Error":{"code":"not_subscribed","message":"not subscribed to the source room"}}"#);
+        let back: ServerEvent = serde_json::from_str(&json).unwrap();
+        assert!(matches!(back, ServerEvent::Error { code: ErrorCode::NotSubscribed, until: None, .. }));
+    }
 }
@@ -0,0 +1,94 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{ChannelId, FileId, UserId};
+
+/// Metadata for a stored file, as recorded in `file_uploads`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileUpload {
+    pub id: FileId,
+    pub channel_id: ChannelId,
+    pub uploader_id: UserId,
+    pub filename: String,
+    /// Content type declared by the uploader.
+    pub mime_type: String,
+    /// Content type sniffed from the file's magic bytes, when recognized.
+    #[serde(default)]
+    pub detected_mime_type: Option<String>,
+    pub size_bytes: i64,
+    /// Lowercase hex SHA-256 of the content.
+    pub checksum: String,
+    pub created_at: DateTime<Utc>,
+    /// Set once `GET /api/files/{id}/thumbnail` has something to serve;
+    /// only images get thumbnails, shortly after they are uploaded.
+    #[serde(default)]
+    pub has_thumbnail: bool,
+    #[serde(default)]
+    pub scan_status: ScanStatus,
+}
+
+/// Where an upload is in malware scanning. Only `Available` files can be
+/// downloaded by everyone in the channel; `PendingScan` ones only by
+/// their uploader, and `Quarantined` ones by nobody.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    PendingScan,
+    #[default]
+    Available,
+    Quarantined,
+}
+
+impl ScanStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanStatus::PendingScan => "pending_scan",
+            ScanStatus::Available => "available",
+            ScanStatus::Quarantined => "quarantined",
+        }
+    }
+}
+
+impl fmt::Display for ScanStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ScanStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending_scan" => Ok(ScanStatus::PendingScan),
+            "available" => Ok(ScanStatus::Available),
+            "quarantined" => Ok(ScanStatus::Quarantined),
+            other => Err(format!("unknown scan status {:?}", other)),
+        }
+    }
+}
+
+/// Sent to gateway-service's `/internal/file-quarantined` when an upload
+/// is quarantined, so its uploader hears why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileQuarantined {
+    pub file_id: FileId,
+    pub channel_id: ChannelId,
+    pub uploader_id: UserId,
+    pub filename: String,
+    /// The signature the scanner found, or why the file couldn't be
+    /// scanned.
+    pub reason: String,
+}
+
+/// A short-lived link to a file that works without a bearer token, so the
+/// token never ends up in a URL or an access log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileDownloadUrl {
+    /// `/api/files/{id}?token=..`, relative to channels-api.
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
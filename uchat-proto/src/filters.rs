@@ -0,0 +1,95 @@
+//! Content filters: deployment policy checks the gateway runs on every
+//! message that isn't end-to-end encrypted. Channel admins keep a list of
+//! word and regex rules per channel in channels-api, and a deployment can
+//! add an external webhook; each filter lets a message through, rewrites
+//! it, or refuses it.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ids::{ChannelId, MessageId, UserId};
+
+/// One of a channel's filter rules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterRule {
+    pub id: i64,
+    pub channel_id: ChannelId,
+    /// A word or phrase, matched case-insensitively on word boundaries,
+    /// or a regex when `regex` is set.
+    pub pattern: String,
+    #[serde(default)]
+    pub regex: bool,
+    /// What matches are replaced with; messages matching a rule without
+    /// one are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replacement: Option<String>,
+    pub created_by: UserId,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateFilterRule {
+    pub pattern: String,
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub replacement: Option<String>,
+}
+
+/// A channel's whole rule list, as pushed to the gateway on each change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelFilterRules {
+    pub channel_id: ChannelId,
+    pub rules: Vec<FilterRule>,
+}
+
+/// What a filter webhook is sent for each message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterRequest {
+    pub channel_id: ChannelId,
+    pub user_id: UserId,
+    pub content: String,
+}
+
+/// A filter's answer for one message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum FilterVerdict {
+    Allow,
+    /// Send `content` in place of what the user wrote.
+    Redact { content: String },
+    /// Refuse the message; `reason` is for the audit trail, not the sender.
+    Block { reason: String },
+}
+
+/// Sent by the gateway to channels-api for each message a filter
+/// redacted or blocked, for the moderation audit trail.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContentFiltered {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    /// Set for messages channels-api stored, which are updated to match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<MessageId>,
+    /// Which filter decided, e.g. `rules` or `webhook`.
+    pub filter: String,
+    pub verdict: FilterVerdict,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdicts_are_tagged_by_decision() {
+        let verdicts = [
+            (FilterVerdict::Allow, r#"{"decision":"allow"}"#),
+            (FilterVerdict::Redact { content: "a *** b".into() }, r#"{"decision":"redact","content":"a *** b"}"#),
+            (FilterVerdict::Block { reason: "export control".into() }, r#"{"decision":"block","reason":"export control"}"#),
+        ];
+        for (verdict, json) in verdicts {
+            assert_eq!(serde_json::to_string(&verdict).unwrap(), json);
+            assert_eq!(serde_json::from_str::<FilterVerdict>(json).unwrap(), verdict);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// How a socket's frames are encoded, negotiated in `Hello`. JSON goes in
+/// text frames and MessagePack in binary ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SerializationFormat {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl SerializationFormat {
+    /// The byte in front of a payload encoded this way, where one channel
+    /// carries both.
+    pub fn tag(self) -> u8 {
+        match self {
+            SerializationFormat::Json => 0,
+            SerializationFormat::Msgpack => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(SerializationFormat::Json),
+            1 => Some(SerializationFormat::Msgpack),
+            _ => None,
+        }
+    }
+}
+
+/// MessagePack with field names, so optional fields can be left out and
+/// added the way they are in JSON.
+pub fn to_msgpack<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    rmp_serde::to_vec_named(value)
+}
+
+pub fn from_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, rmp_serde::decode::Error> {
+    rmp_serde::from_slice(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{ClientEvent, ClientFrame, ServerEvent, CURRENT_SCHEMA_VERSION};
+    use crate::ids::{ChannelId, RoomId, UserId};
+
+    #[test]
+    fn events_round_trip_through_msgpack() {
+        let frame = ClientFrame {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            cid: Some("c-1".into()),
+            ts_gateway: None,
+            correlation_id: None,
+            event: ClientEvent::Hello { last_seq: Some(7), capabilities: None, format: Some(SerializationFormat::Msgpack) },
+        };
+        let decoded: ClientFrame = from_msgpack(&to_msgpack(&frame).unwrap()).unwrap();
+        assert_eq!(decoded.cid.as_deref(), Some("c-1"));
+        assert!(matches!(
+            decoded.event,
+            ClientEvent::Hello { last_seq: Some(7), capabilities: None, format: Some(SerializationFormat::Msgpack) }
+        ));
+
+        let room = RoomId::from(ChannelId::new());
+        let event = ServerEvent::MessageBroadcast {
+            room_id: room.clone(),
+            from: UserId::new(),
+            content: "hi".into(),
+            encrypted: false,
+            content_type: "text/plain".into(),
+            seq: None,
+            bridged_from: None,
+            sender_name: None,
+            message_id: None,
+            sender_role: None,
+            redacted: false,
+            traceparent: None,
+        };
+        let packed = to_msgpack(&event).unwrap();
+        assert!(packed.len() < serde_json::to_vec(&event).unwrap().len());
+        let ServerEvent::MessageBroadcast { room_id, content, seq, .. } = from_msgpack(&packed).unwrap() else { panic!() };
+        assert_eq!((room_id, content.as_str(), seq), (room, "hi", None));
+    }
+
+    #[test]
+    fn tags_name_their_format() {
+        for format in [SerializationFormat::Json, SerializationFormat::Msgpack] {
+            assert_eq!(SerializationFormat::from_tag(format.tag()), Some(format));
+        }
+        assert_eq!(SerializationFormat::from_tag(9), None);
+        assert_eq!(serde_json::to_string(&SerializationFormat::Msgpack).unwrap(), r#""msgpack""#);
+    }
+}
@@ -0,0 +1,233 @@
+//! Strongly-typed identifiers.
+//!
+//! Each id wraps a UUID string in canonical (lowercase, hyphenated) form.
+//! They serialize as plain strings so the wire format is unchanged, but
+//! parsing rejects anything that is not a UUID and the types keep a
+//! channel id from being passed where a user id is expected.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidId {
+    pub kind: &'static str,
+    pub value: String,
+}
+
+impl fmt::Display for InvalidId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {:?} is not a UUID", self.kind, self.value)
+    }
+}
+
+impl std::error::Error for InvalidId {}
+
+macro_rules! uuid_id {
+    ($name:ident, $kind:literal) => {
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+        #[serde(try_from = "String", into = "String")]
+        #[cfg_attr(feature = "postgres", derive(sqlx::Type), sqlx(transparent))]
+        pub struct $name(String);
+
+        impl $name {
+            /// A fresh random (v4) id.
+            pub fn new() -> Self {
+                Self(Uuid::new_v4().hyphenated().to_string())
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn as_uuid(&self) -> Uuid {
+                Uuid::parse_str(&self.0).expect("validated on construction")
+            }
+
+            pub fn into_inner(self) -> String {
+                self.0
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = InvalidId;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Uuid::parse_str(s)
+                    .map(|uuid| Self(uuid.hyphenated().to_string()))
+                    .map_err(|_| InvalidId { kind: $kind, value: s.to_string() })
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = InvalidId;
+
+            fn try_from(s: String) -> Result<Self, Self::Error> {
+                s.parse()
+            }
+        }
+
+        impl From<Uuid> for $name {
+            fn from(uuid: Uuid) -> Self {
+                Self(uuid.hyphenated().to_string())
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+    };
+}
+
+uuid_id!(UserId, "user id");
+uuid_id!(ChannelId, "channel id");
+uuid_id!(MessageId, "message id");
+uuid_id!(DeviceId, "device id");
+uuid_id!(FileId, "file id");
+uuid_id!(InviteId, "invite id");
+uuid_id!(ExportId, "export id");
+uuid_id!(WebhookId, "webhook id");
+uuid_id!(PollSessionId, "poll session id");
+uuid_id!(InvocationId, "invocation id");
+uuid_id!(ScheduledMessageId, "scheduled message id");
+
+impl UserId {
+    /// Stands in as the sender of messages whose author deleted their
+    /// account. It is the nil UUID, which `new` never returns.
+    pub fn deleted() -> Self {
+        Self::from(Uuid::nil())
+    }
+}
+
+const THREAD_SEPARATOR: &str = ":thread:";
+
+/// A broadcast room: a channel, or one thread inside it.
+///
+/// On the wire a room is the channel id, or `{channel}:thread:{thread}`
+/// for a thread rooted at message `thread`, so plain channel rooms
+/// serialize exactly like a `ChannelId`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RoomId {
+    pub channel: ChannelId,
+    pub thread: Option<MessageId>,
+}
+
+impl RoomId {
+    pub fn thread(channel: ChannelId, thread: MessageId) -> Self {
+        Self { channel, thread: Some(thread) }
+    }
+}
+
+impl From<ChannelId> for RoomId {
+    fn from(channel: ChannelId) -> Self {
+        Self { channel, thread: None }
+    }
+}
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.thread {
+            Some(thread) => write!(f, "{}{}{}", self.channel, THREAD_SEPARATOR, thread),
+            None => write!(f, "{}", self.channel),
+        }
+    }
+}
+
+impl FromStr for RoomId {
+    type Err = InvalidId;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidId { kind: "room id", value: s.to_string() };
+        match s.split_once(THREAD_SEPARATOR) {
+            Some((channel, thread)) => Ok(Self::thread(
+                channel.parse().map_err(|_| invalid())?,
+                thread.parse().map_err(|_| invalid())?,
+            )),
+            None => Ok(Self::from(s.parse::<ChannelId>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl TryFrom<String> for RoomId {
+    type Error = InvalidId;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RoomId> for String {
+    fn from(room: RoomId) -> Self {
+        room.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_canonicalizes() {
+        let id: UserId = "6F9619FF-8B86-D011-B42D-00CF4FC964FF".parse().unwrap();
+        assert_eq!(id.as_str(), "6f9619ff-8b86-d011-b42d-00cf4fc964ff");
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let err = "general".parse::<ChannelId>().unwrap_err();
+        assert_eq!(err.kind, "channel id");
+        assert!("".parse::<UserId>().is_err());
+    }
+
+    #[test]
+    fn serde_is_a_plain_string() {
+        let id = MessageId::new();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id));
+
+        let back: MessageId = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, id);
+
+        assert!(serde_json::from_str::<MessageId>("\"not-a-uuid\"").is_err());
+    }
+
+    #[test]
+    fn room_ids() {
+        let channel = ChannelId::new();
+        let room: RoomId = channel.as_str().parse().unwrap();
+        assert_eq!(room, RoomId::from(channel.clone()));
+        assert_eq!(room.to_string(), channel.as_str());
+
+        let thread = MessageId::new();
+        let room: RoomId = format!("{}:thread:{}", channel, thread).parse().unwrap();
+        assert_eq!(room, RoomId::thread(channel.clone(), thread.clone()));
+        assert_eq!(room.to_string(), format!("{}:thread:{}", channel, thread));
+
+        for bad in [format!("{}:thread:", channel), format!("{}:thread:general", channel), "general".into()] {
+            assert_eq!(bad.parse::<RoomId>().unwrap_err().kind, "room id");
+        }
+    }
+}
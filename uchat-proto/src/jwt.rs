@@ -1,28 +1,137 @@
-use chrono::{Utc, Duration};
-use jsonwebtoken::{encode, decode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc, Duration};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse,
+};
+use jsonwebtoken::{encode, decode, decode_header, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{Ed25519KeyPair, KeyPair};
 use serde::{Serialize, Deserialize};
 
+pub use jsonwebtoken::jwk::{Jwk, JwkSet};
+
+use crate::permissions::RoomPermissions;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    #[serde(default, skip_serializing_if = "RoomPermissions::is_empty")]
+    pub rooms: RoomPermissions,
+    /// Set for non-human clients, such as devices authenticated by a
+    /// client certificate.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
+    /// What else the token's holder may do; see `BRIDGE_ROLE`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    /// When the account was created, as a Unix timestamp; set on the
+    /// access tokens auth-api issues.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub joined: Option<i64>,
+}
+
+/// Role on the access tokens of users with `users.is_admin`.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Role of bots bridging other chat platforms, which may relay messages
+/// between rooms.
+pub const BRIDGE_ROLE: &str = "bridge";
+
+impl Claims {
+    pub fn is_bridge(&self) -> bool {
+        self.role.as_deref() == Some(BRIDGE_ROLE)
+    }
+}
+
+/// Shared signing secret, taken from `JWT_SECRET` when set.
+pub fn secret_from_env() -> String {
+    std::env::var("JWT_SECRET").unwrap_or_else(|_| "MY_SECRET_KEY".into())
 }
 
 pub fn create_token(secret: &str, username: &str) -> String {
+    create_token_with_rooms(secret, username, RoomPermissions::default())
+}
+
+pub fn create_token_with_rooms(secret: &str, username: &str, rooms: RoomPermissions) -> String {
+    create_token_expiring(secret, username, rooms, Duration::hours(12))
+}
+
+/// A user token that lapses after `ttl`.
+pub fn create_token_expiring(secret: &str, username: &str, rooms: RoomPermissions, ttl: Duration) -> String {
+    let expiration = Utc::now() + ttl;
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: expiration.timestamp() as usize,
+        rooms,
+        bot: false,
+        role: None,
+        joined: None,
+    };
+    sign_claims(secret, &claims)
+}
+
+/// An access token as auth-api issues them alongside a refresh token:
+/// lapsing after `ttl`, with `ADMIN_ROLE` for admins and the account's
+/// creation time.
+pub fn create_access_token(
+    secret: &str,
+    username: &str,
+    rooms: RoomPermissions,
+    ttl: Duration,
+    admin: bool,
+    joined: DateTime<Utc>,
+) -> String {
+    let expiration = Utc::now() + ttl;
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: expiration.timestamp() as usize,
+        rooms,
+        bot: false,
+        role: admin.then(|| ADMIN_ROLE.into()),
+        joined: Some(joined.timestamp()),
+    };
+    sign_claims(secret, &claims)
+}
+
+/// A user token carrying `role`, which the gateway passes on to bots as
+/// `sender_role`.
+pub fn create_token_with_role(secret: &str, username: &str, rooms: RoomPermissions, role: &str) -> String {
+    let expiration = Utc::now() + Duration::hours(12);
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: expiration.timestamp() as usize,
+        rooms,
+        bot: false,
+        role: Some(role.into()),
+        joined: None,
+    };
+    sign_claims(secret, &claims)
+}
+
+/// A token for a bridge bot, which may relay between `rooms`.
+pub fn create_bridge_token(secret: &str, username: &str, rooms: RoomPermissions) -> String {
     let expiration = Utc::now() + Duration::hours(12);
     let claims = Claims {
         sub: username.to_string(),
         exp: expiration.timestamp() as usize,
+        rooms,
+        bot: true,
+        role: Some(BRIDGE_ROLE.into()),
+        joined: None,
     };
+    sign_claims(secret, &claims)
+}
 
+fn sign_claims(secret: &str, claims: &Claims) -> String {
     encode(
         &Header::default(),
-        &claims,
+        claims,
         &EncodingKey::from_secret(secret.as_bytes()),
     ).unwrap()
 }
 
-pub fn verify_token(secret: &str, token: &str) -> Option<String> {
+pub fn verify_claims(secret: &str, token: &str) -> Option<Claims> {
     let validation = Validation::new(Algorithm::HS256);
     let decoded = decode::<Claims>(
         token,
@@ -30,5 +139,107 @@ pub fn verify_token(secret: &str, token: &str) -> Option<String> {
         &validation,
     ).ok()?;
 
-    Some(decoded.claims.sub)
+    Some(decoded.claims)
+}
+
+pub fn verify_token(secret: &str, token: &str) -> Option<String> {
+    verify_claims(secret, token).map(|claims| claims.sub)
+}
+
+/// An Ed25519 key for tokens checked against a published JWKS instead of
+/// the shared secret, as auth-api's OIDC provider signs them.
+pub struct SigningKey {
+    pkcs8: Vec<u8>,
+    public: Vec<u8>,
+    kid: String,
+}
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).expect("generate an Ed25519 key");
+        Self::from_pkcs8(pkcs8.as_ref()).expect("a generated key parses")
+    }
+
+    /// A key from its PKCS#8 DER encoding.
+    pub fn from_pkcs8(der: &[u8]) -> Option<Self> {
+        let public = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).ok()?.public_key().as_ref().to_vec();
+        // Derived from the key, so it stays the same across restarts.
+        let digest = ring::digest::digest(&ring::digest::SHA256, &public);
+        let kid = URL_SAFE_NO_PAD.encode(&digest.as_ref()[..12]);
+        Some(Self { pkcs8: der.to_vec(), public, kid })
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The public half, as published in the JWKS.
+    pub fn jwk(&self) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::EdDSA),
+                key_id: Some(self.kid.clone()),
+                ..CommonParameters::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(&self.public),
+            }),
+        }
+    }
+
+    /// An EdDSA token for `claims`, naming this key in its header.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &EncodingKey::from_ed_der(&self.pkcs8)).expect("sign with an Ed25519 key")
+    }
+}
+
+/// The `kid` a token's header names, if any.
+pub fn key_id(token: &str) -> Option<String> {
+    decode_header(token).ok()?.kid
+}
+
+/// Claims of an EdDSA token signed by one of `jwks`'s keys.
+pub fn verify_claims_jwks(jwks: &JwkSet, token: &str) -> Option<Claims> {
+    let jwk = jwks.find(&key_id(token)?)?;
+    let key = DecodingKey::from_jwk(jwk).ok()?;
+    let decoded = decode::<Claims>(token, &key, &Validation::new(Algorithm::EdDSA)).ok()?;
+    Some(decoded.claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(sub: &str) -> Claims {
+        let exp = (Utc::now() + Duration::hours(1)).timestamp() as usize;
+        Claims { sub: sub.into(), exp, rooms: RoomPermissions::default(), bot: true, role: None, joined: None }
+    }
+
+    #[test]
+    fn jwks_tokens_verify_against_the_published_key_only() {
+        let (key, other) = (SigningKey::generate(), SigningKey::generate());
+        let jwks = JwkSet { keys: vec![key.jwk()] };
+
+        let token = key.sign(&claims("svc"));
+        assert_eq!(key_id(&token).as_deref(), Some(key.kid()));
+        assert_eq!(verify_claims_jwks(&jwks, &token).unwrap().sub, "svc");
+        assert!(verify_claims_jwks(&jwks, &other.sign(&claims("svc"))).is_none());
+        // Shared-secret tokens aren't JWKS tokens, and the reverse.
+        assert!(verify_claims_jwks(&jwks, &create_token("secret", "svc")).is_none());
+        assert!(verify_claims("secret", &token).is_none());
+    }
+
+    #[test]
+    fn key_ids_follow_the_key() {
+        let key = SigningKey::generate();
+        let again = SigningKey::from_pkcs8(&key.pkcs8).unwrap();
+        assert_eq!(again.kid(), key.kid());
+        assert_ne!(SigningKey::generate().kid(), key.kid());
+        assert!(SigningKey::from_pkcs8(b"not a key").is_none());
+    }
 }
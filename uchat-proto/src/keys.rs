@@ -0,0 +1,18 @@
+use serde::{Serialize, Deserialize};
+
+/// Public prekey bundle served by auth-api for E2EE session setup.
+///
+/// Keys are base64 strings exactly as the owning client uploaded them.
+/// Each fetch hands out at most one one-time prekey; `low_prekeys` tells
+/// the owner to upload more.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrekeyBundleJson {
+    pub user_id: String,
+    pub identity_key: String,
+    pub signed_prekey: String,
+    pub signed_prekey_sig: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_time_prekey: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub low_prekeys: bool,
+}
@@ -1,3 +1,21 @@
 pub mod jwt;
+pub mod acks;
+pub mod audit;
+pub mod capabilities;
+pub mod channels;
+pub mod commands;
+pub mod compression;
 pub mod events;
 pub mod errors;
+pub mod files;
+pub mod filters;
+pub mod format;
+pub mod ids;
+pub mod keys;
+pub mod messages;
+pub mod moderation;
+pub mod permissions;
+pub mod poll;
+pub mod push;
+pub mod signing;
+pub mod users;
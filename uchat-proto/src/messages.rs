@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{ChannelId, MessageId, ScheduledMessageId, UserId};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+    pub sender_id: UserId,
+    /// Who the message is shown as from when the sender isn't a user,
+    /// as for incoming webhooks; `sender_id` is then the hook's id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    /// Empty once the message is deleted.
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+    /// Set on soft-deleted messages, which only admins can list.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// When the content was last edited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Set when a content filter rewrote the message.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+    /// Reactions by emoji, as seen by the user who fetched the message.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, ReactionSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub count: i64,
+    /// Whether the requesting user is among those who reacted.
+    pub me: bool,
+}
+
+/// PATCH body replacing a message's content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditMessage {
+    pub content: String,
+}
+
+/// A prior version of an edited message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageVersion {
+    pub content: String,
+    /// When this version was replaced by an edit.
+    pub replaced_at: DateTime<Utc>,
+}
+
+/// One page of a cursor-paginated listing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Opaque cursor for the next page in the same direction, `None` once
+    /// there is nothing further.
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+}
+
+/// Pushed from channels-api to the gateway after the retention job
+/// purges a batch of a channel's messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessagesExpired {
+    pub channel_id: ChannelId,
+    pub message_ids: Vec<MessageId>,
+}
+
+/// Pushed from channels-api to the gateway when a message is soft
+/// deleted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageDeleted {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+}
+
+/// Pushed from channels-api to the gateway when a message is edited.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageEdited {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+    pub content: String,
+    pub edited_at: DateTime<Utc>,
+}
+
+/// What a URL in a message unfurls to, from its OpenGraph tags or, failing
+/// those, its `<title>` and description.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_name: Option<String>,
+}
+
+/// Pushed from channels-api to the gateway once a link in a stored
+/// message has been unfurled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LinkPreviewReady {
+    pub message_id: MessageId,
+    pub channel_id: ChannelId,
+    pub preview: LinkPreview,
+}
+
+/// Body an external system posts to an incoming webhook. `username`
+/// replaces the hook's display name for this message; attachments are
+/// appended to `text`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebhookPost {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<WebhookAttachment>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebhookAttachment {
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Link for the title.
+    #[serde(default)]
+    pub title_link: Option<String>,
+    #[serde(default)]
+    pub text: Option<String>,
+}
+
+/// Pushed from channels-api to the gateway after it stores a message that
+/// didn't come through a socket, such as an incoming webhook's.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessagePosted {
+    pub id: MessageId,
+    pub channel_id: ChannelId,
+    pub sender_id: UserId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    pub content: String,
+    /// Posts into this message's thread rather than the channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<MessageId>,
+}
+
+/// Body of `POST /api/channels/{id}/scheduled_messages`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleMessage {
+    pub content: String,
+    pub send_at: DateTime<Utc>,
+    /// Posts into this message's thread.
+    #[serde(default)]
+    pub reply_to: Option<MessageId>,
+}
+
+/// A message its author scheduled that hasn't been sent yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: ScheduledMessageId,
+    pub channel_id: ChannelId,
+    pub author_id: UserId,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+    pub send_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Pushed from channels-api to the gateway when a scheduled message is
+/// dropped at send time, so its author hears why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledMessageCancelled {
+    pub id: ScheduledMessageId,
+    pub channel_id: ChannelId,
+    pub author_id: UserId,
+    pub reason: String,
+}
+
+/// Pushed from channels-api to the gateway when a user adds or removes
+/// a reaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReactionChanged {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub user_id: UserId,
+    pub emoji: String,
+    pub added: bool,
+}
+
+/// PUT body advancing the caller's read marker in a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkRead {
+    pub message_id: MessageId,
+}
+
+/// How far a user has read in a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadMarker {
+    pub channel_id: ChannelId,
+    pub last_read_message_id: MessageId,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Unread messages in one of the caller's channels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnreadCount {
+    pub channel_id: ChannelId,
+    pub unread: i64,
+    #[serde(default)]
+    pub last_read_message_id: Option<MessageId>,
+}
+
+/// A message matching a full-text search.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub message: Message,
+    /// Excerpt around the matched terms, each wrapped in `**`.
+    pub headline: String,
+    /// Relevance, discounted by age; hits are ordered by it.
+    pub rank: f64,
+}
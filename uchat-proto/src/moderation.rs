@@ -0,0 +1,113 @@
+//! Spam holds: the gateway shadow-throttles users whose messages look like
+//! flooding, and moderators clear or confirm them through channels-api.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::ids::{ChannelId, UserId};
+
+/// Which check a user tripped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamReason {
+    /// The same text posted to several channels.
+    Duplicates,
+    /// Several messages that are mostly links.
+    LinkDensity,
+    /// A burst of messages from an account created recently.
+    NewAccountBurst,
+}
+
+impl SpamReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpamReason::Duplicates => "duplicates",
+            SpamReason::LinkDensity => "link_density",
+            SpamReason::NewAccountBurst => "new_account_burst",
+        }
+    }
+}
+
+impl fmt::Display for SpamReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A moderator's call on a held user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamDecision {
+    /// Not spam: the held messages go out and the hold is lifted.
+    Clear,
+    /// Spam: the held messages are discarded, and later ones stay held
+    /// until the user is cleared.
+    Confirm,
+}
+
+impl SpamDecision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpamDecision::Clear => "clear",
+            SpamDecision::Confirm => "confirm",
+        }
+    }
+}
+
+impl fmt::Display for SpamDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SpamDecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clear" => Ok(SpamDecision::Clear),
+            "confirm" => Ok(SpamDecision::Confirm),
+            other => Err(format!("unknown spam decision {:?}", other)),
+        }
+    }
+}
+
+/// A user the gateway is holding messages from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeldUser {
+    pub user_id: UserId,
+    pub reasons: Vec<SpamReason>,
+    pub since: DateTime<Utc>,
+    /// Messages waiting for a decision.
+    pub held: u32,
+    /// Set once a moderator confirmed the user as a spammer.
+    pub confirmed: bool,
+}
+
+/// Sent by the gateway to channels-api when it starts holding a user's
+/// messages, for the moderation audit trail.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamFlagged {
+    pub user_id: UserId,
+    /// Where the message that tripped the check was sent.
+    pub channel_id: ChannelId,
+    pub reasons: Vec<SpamReason>,
+}
+
+/// Body of a decision on a held user.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamDecisionRequest {
+    pub decision: SpamDecision,
+}
+
+/// What a decision did with the held messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamDecided {
+    pub user_id: UserId,
+    pub decision: SpamDecision,
+    /// Messages sent on for `Clear`, discarded for `Confirm`.
+    pub messages: u32,
+}
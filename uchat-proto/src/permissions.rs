@@ -0,0 +1,166 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+/// Access level a token grants on a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomRole {
+    Read,
+    Write,
+}
+
+/// Per-room permissions carried in the token claims.
+///
+/// Keys are room patterns: an exact room id (`general`) or a prefix
+/// wildcard ending in `*` (`ops-*`, or `*` for every room). Lookups are
+/// deny-by-default; an exact entry always beats a wildcard, and among
+/// wildcards the longest prefix wins.
+///
+/// On the wire the map is inverted into one pattern list per role
+/// (`{"r":["ops-*"],"w":["general"]}`) so tokens stay small.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "CompactPermissions", into = "CompactPermissions")]
+pub struct RoomPermissions {
+    rules: BTreeMap<String, RoomRole>,
+}
+
+impl RoomPermissions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grants `role` on `pattern`. A pattern granted twice keeps the
+    /// higher role.
+    pub fn grant(&mut self, pattern: &str, role: RoomRole) {
+        let entry = self.rules.entry(pattern.to_string()).or_insert(role);
+        if role > *entry {
+            *entry = role;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Effective role for `room`, or `None` if nothing matches.
+    pub fn role_for(&self, room: &str) -> Option<RoomRole> {
+        if let Some(role) = self.rules.get(room) {
+            return Some(*role);
+        }
+
+        self.rules
+            .iter()
+            .filter_map(|(pattern, role)| {
+                let prefix = pattern.strip_suffix('*')?;
+                room.starts_with(prefix).then_some((prefix.len(), *role))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, role)| role)
+    }
+
+    pub fn can_join(&self, room: &str) -> bool {
+        self.role_for(room).is_some()
+    }
+
+    pub fn can_post(&self, room: &str) -> bool {
+        self.role_for(room) == Some(RoomRole::Write)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompactPermissions {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    r: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    w: Vec<String>,
+}
+
+impl From<CompactPermissions> for RoomPermissions {
+    fn from(compact: CompactPermissions) -> Self {
+        let mut perms = RoomPermissions::new();
+        for pattern in &compact.r {
+            perms.grant(pattern, RoomRole::Read);
+        }
+        for pattern in &compact.w {
+            perms.grant(pattern, RoomRole::Write);
+        }
+        perms
+    }
+}
+
+impl From<RoomPermissions> for CompactPermissions {
+    fn from(perms: RoomPermissions) -> Self {
+        let mut compact = CompactPermissions { r: Vec::new(), w: Vec::new() };
+        for (pattern, role) in perms.rules {
+            match role {
+                RoomRole::Read => compact.r.push(pattern),
+                RoomRole::Write => compact.w.push(pattern),
+            }
+        }
+        compact
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_by_default() {
+        let perms = RoomPermissions::new();
+        assert!(!perms.can_join("general"));
+        assert!(!perms.can_post("general"));
+    }
+
+    #[test]
+    fn exact_beats_wildcard() {
+        let mut perms = RoomPermissions::new();
+        perms.grant("ops-*", RoomRole::Write);
+        perms.grant("ops-audit", RoomRole::Read);
+
+        assert!(perms.can_post("ops-deploy"));
+        assert!(perms.can_join("ops-audit"));
+        assert!(!perms.can_post("ops-audit"));
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let mut perms = RoomPermissions::new();
+        perms.grant("*", RoomRole::Write);
+        perms.grant("ops-*", RoomRole::Read);
+
+        assert!(perms.can_post("general"));
+        assert!(!perms.can_post("ops-deploy"));
+        assert!(perms.can_join("ops-deploy"));
+    }
+
+    #[test]
+    fn wildcard_does_not_leak_to_other_rooms() {
+        let mut perms = RoomPermissions::new();
+        perms.grant("ops-*", RoomRole::Write);
+
+        assert!(!perms.can_join("general"));
+        assert!(!perms.can_join("ops"));
+    }
+
+    #[test]
+    fn compact_roundtrip() {
+        let mut perms = RoomPermissions::new();
+        perms.grant("general", RoomRole::Write);
+        perms.grant("ops-*", RoomRole::Read);
+        perms.grant("random", RoomRole::Write);
+
+        let json = serde_json::to_string(&perms).unwrap();
+        assert_eq!(json, r#"{"r":["ops-*"],"w":["general","random"]}"#);
+
+        let back: RoomPermissions = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, perms);
+    }
+
+    #[test]
+    fn duplicate_pattern_keeps_higher_role() {
+        let perms: RoomPermissions =
+            serde_json::from_str(r#"{"r":["general"],"w":["general"]}"#).unwrap();
+        assert!(perms.can_post("general"));
+    }
+}
@@ -0,0 +1,27 @@
+//! Bodies of the gateway's long-polling transport, for clients that
+//! can't keep a WebSocket open. Frames travel as the same JSON text a
+//! socket would carry.
+
+use serde::{Serialize, Deserialize};
+
+use crate::ids::PollSessionId;
+
+/// Answer to `POST /poll/connect`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PollSession {
+    pub sid: PollSessionId,
+    /// The session ends once this long passes without a poll or a send.
+    pub idle_timeout_secs: u64,
+}
+
+/// Answer to `GET /poll/{sid}`: the frames queued since the last poll,
+/// oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PollBatch {
+    pub frames: Vec<String>,
+    /// Frames that didn't fit the session's queue and were dropped after
+    /// the last one in `frames`. A client that sees this has missed
+    /// events, and should catch up as it would after a reconnect.
+    #[serde(default)]
+    pub dropped: u64,
+}
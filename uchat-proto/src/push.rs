@@ -0,0 +1,112 @@
+//! Mobile push notifications: devices registering for them, and what the
+//! gateway hands channels-api about each message so members who weren't
+//! online for it hear about it on their phones.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{ChannelId, UserId};
+
+/// Which push service a device token belongs to, stored in
+/// `push_tokens.platform`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushPlatform {
+    /// Firebase Cloud Messaging, for Android.
+    Fcm,
+    /// Apple Push Notification service.
+    Apns,
+}
+
+impl PushPlatform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PushPlatform::Fcm => "fcm",
+            PushPlatform::Apns => "apns",
+        }
+    }
+}
+
+impl fmt::Display for PushPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PushPlatform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fcm" => Ok(PushPlatform::Fcm),
+            "apns" => Ok(PushPlatform::Apns),
+            other => Err(format!("unknown push platform {:?}", other)),
+        }
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// One device's notification settings; both default to on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PushPrefs {
+    /// Off to keep the registration but send this device nothing.
+    #[serde(default = "yes")]
+    pub enabled: bool,
+    /// Off to show "New message" instead of who said what.
+    #[serde(default = "yes")]
+    pub show_previews: bool,
+}
+
+impl Default for PushPrefs {
+    fn default() -> Self {
+        Self { enabled: true, show_previews: true }
+    }
+}
+
+/// POST body for `/api/push/register`. Registering a token again updates
+/// its settings, and moves it to the caller if another account had it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterPushToken {
+    pub platform: PushPlatform,
+    pub token: String,
+    #[serde(default)]
+    pub prefs: PushPrefs,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushRegistration {
+    pub platform: PushPlatform,
+    pub token: String,
+    pub prefs: PushPrefs,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A member's notification settings for one channel, at
+/// `/api/channels/{id}/notifications`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelNotifications {
+    /// No pushes for this channel on any of the member's devices.
+    pub muted: bool,
+}
+
+/// Handed from the gateway to channels-api for each message sent to a
+/// channel, naming who was online in it and so got it live.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PushMessage {
+    pub channel_id: ChannelId,
+    pub sender_id: UserId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    /// `None` for encrypted messages, whose ciphertext never leaves the
+    /// room.
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub delivered_to: Vec<UserId>,
+}
@@ -0,0 +1,338 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::ids::{ExportId, UserId};
+
+/// Square sizes every avatar is stored in, smallest first.
+pub const AVATAR_SIZES: &[u32] = &[64, 256];
+
+/// Where channels-api serves the largest size of an avatar; `version`
+/// changes on every upload, so the URL can be cached forever.
+pub fn avatar_url(user_id: &UserId, version: &str) -> String {
+    format!("/api/users/{}/avatar?size={}&v={}", user_id, AVATAR_SIZES[AVATAR_SIZES.len() - 1], version)
+}
+
+/// The storage key of one size of an avatar.
+pub fn avatar_path(user_id: &UserId, version: &str, size: u32) -> String {
+    format!("avatars/{}/{}-{}.png", user_id, version, size)
+}
+
+/// Fields of a user that any signed-in user may see.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub id: UserId,
+    pub username: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// Relative URL of the largest avatar size; append or replace `size`
+    /// for the others.
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Whether the user has a live connection; absent when the server
+    /// can't tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub online: Option<bool>,
+    /// Where notification emails go; only on the caller's own profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Only on the caller's own profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_notifications: Option<EmailNotifications>,
+}
+
+/// Fields of a user anyone may see, signed in or not.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserPublicInfo {
+    pub user_id: UserId,
+    pub username: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    /// Absent when the server can't tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub online_status: Option<OnlineStatus>,
+}
+
+impl From<UserProfile> for UserPublicInfo {
+    fn from(profile: UserProfile) -> Self {
+        UserPublicInfo {
+            user_id: profile.id,
+            username: profile.username,
+            display_name: profile.display_name,
+            avatar_url: profile.avatar_url,
+            bio: profile.bio,
+            online_status: profile.online.map(OnlineStatus::from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum OnlineStatus {
+    Online,
+    Offline,
+}
+
+impl From<bool> for OnlineStatus {
+    fn from(online: bool) -> Self {
+        if online {
+            OnlineStatus::Online
+        } else {
+            OnlineStatus::Offline
+        }
+    }
+}
+
+/// PATCH body for the caller's own profile. An empty `display_name`,
+/// `bio` or `email` clears it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateProfile {
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_notifications: Option<EmailNotifications>,
+}
+
+/// Which messages a user is emailed about while away, stored in
+/// `users.email_notifications`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailNotifications {
+    /// Mentions and direct messages.
+    All,
+    #[default]
+    Mentions,
+    None,
+}
+
+impl EmailNotifications {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailNotifications::All => "all",
+            EmailNotifications::Mentions => "mentions",
+            EmailNotifications::None => "none",
+        }
+    }
+}
+
+impl fmt::Display for EmailNotifications {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EmailNotifications {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(EmailNotifications::All),
+            "mentions" => Ok(EmailNotifications::Mentions),
+            "none" => Ok(EmailNotifications::None),
+            other => Err(format!("unknown email notification setting {:?}", other)),
+        }
+    }
+}
+
+/// What an account may do beyond chatting, stored as `users.is_admin`
+/// and `users.is_compliance`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    #[default]
+    User,
+    /// Manages users and channels through the admin APIs.
+    Admin,
+    /// Reads the message audit trail.
+    Compliance,
+}
+
+impl UserRole {
+    /// An account with both flags counts as an admin.
+    pub fn from_flags(is_admin: bool, is_compliance: bool) -> Self {
+        match (is_admin, is_compliance) {
+            (true, _) => UserRole::Admin,
+            (false, true) => UserRole::Compliance,
+            (false, false) => UserRole::User,
+        }
+    }
+
+    /// `(is_admin, is_compliance)`.
+    pub fn flags(&self) -> (bool, bool) {
+        (*self == UserRole::Admin, *self == UserRole::Compliance)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::User => "user",
+            UserRole::Admin => "admin",
+            UserRole::Compliance => "compliance",
+        }
+    }
+}
+
+impl fmt::Display for UserRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for UserRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(UserRole::User),
+            "admin" => Ok(UserRole::Admin),
+            "compliance" => Ok(UserRole::Compliance),
+            other => Err(format!("unknown user role {:?}", other)),
+        }
+    }
+}
+
+/// A user as operators see them, from auth-api's `/admin/users`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminUser {
+    pub id: UserId,
+    pub username: String,
+    pub role: UserRole,
+    pub suspended: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended_until: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// Set while the account waits to be purged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// An account made for someone else, with the one-time token they set its
+/// password with at auth-api's `POST /activate`. Only its hash is kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProvisionedUser {
+    #[serde(flatten)]
+    pub user: AdminUser,
+    pub activation_token: String,
+}
+
+/// What auth-api's `POST /admin/users/import` made of a CSV of users.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserImport {
+    pub imported: u64,
+    pub failed: u64,
+    /// The first rows that failed, in file order.
+    pub errors: Vec<UserImportError>,
+    /// Set when `fail_on_error` undid the whole import.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rolled_back: bool,
+    /// Every account imported, for handing each its activation token.
+    #[serde(default)]
+    pub users: Vec<ImportedUser>,
+}
+
+/// An imported account, with the one-time token its owner sets a password
+/// with at auth-api's `POST /activate`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportedUser {
+    pub id: UserId,
+    pub username: String,
+    pub activation_token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserImportError {
+    /// Line in the CSV file, counting the header as line 1.
+    pub line: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub error: String,
+}
+
+/// Which of the requested users are online, as reported by the gateway.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub online: Vec<UserId>,
+}
+
+/// auth-api -> gateway: an account was deleted, so tokens already issued
+/// to it must stop working.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserDeleted {
+    pub user_id: UserId,
+}
+
+/// Where a personal data export is, stored in `data_exports.status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportStatus {
+    Pending,
+    Running,
+    Ready,
+    Failed,
+    /// Ready once, but the archive has since been deleted.
+    Expired,
+}
+
+impl ExportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportStatus::Pending => "pending",
+            ExportStatus::Running => "running",
+            ExportStatus::Ready => "ready",
+            ExportStatus::Failed => "failed",
+            ExportStatus::Expired => "expired",
+        }
+    }
+}
+
+impl fmt::Display for ExportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ExportStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ExportStatus::Pending),
+            "running" => Ok(ExportStatus::Running),
+            "ready" => Ok(ExportStatus::Ready),
+            "failed" => Ok(ExportStatus::Failed),
+            "expired" => Ok(ExportStatus::Expired),
+            other => Err(format!("unknown export status {:?}", other)),
+        }
+    }
+}
+
+/// A copy of everything the server holds about the requesting user, as
+/// a zip archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataExport {
+    pub id: ExportId,
+    pub status: ExportStatus,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+    /// When the archive is deleted; set once it is ready.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Signed, short-lived link to the archive; only while it is ready.
+    /// Fetch the export again for a fresh one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+}
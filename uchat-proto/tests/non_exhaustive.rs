@@ -0,0 +1,38 @@
+//! Matches written the way client crates must write them: with a wildcard
+//! arm for variants added after they were compiled.
+
+use uchat_proto::channels::ChannelType;
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::ids::ChannelId;
+
+fn describe_server(event: &ServerEvent) -> &'static str {
+    match event {
+        ServerEvent::LoginOk { .. } => "login",
+        ServerEvent::Error { .. } => "error",
+        _ => "other",
+    }
+}
+
+fn describe_client(event: &ClientEvent) -> &'static str {
+    match event {
+        ClientEvent::Subscribe { .. } => "subscribe",
+        _ => "other",
+    }
+}
+
+fn describe_channel(channel_type: ChannelType) -> &'static str {
+    match channel_type {
+        ChannelType::Public => "public",
+        _ => "other",
+    }
+}
+
+#[test]
+fn wildcard_arms_cover_unknown_variants() {
+    assert_eq!(describe_server(&ServerEvent::error(ErrorCode::Internal, "boom")), "error");
+    assert_eq!(describe_server(&ServerEvent::Removed { room_id: ChannelId::new() }), "other");
+    assert_eq!(describe_client(&ClientEvent::Subscribe { room_id: ChannelId::new().into() }), "subscribe");
+    assert_eq!(describe_client(&ClientEvent::Who { room_id: ChannelId::new() }), "other");
+    assert_eq!(describe_channel(ChannelType::Private), "other");
+}
@@ -0,0 +1,309 @@
+//! Logging shared by the services: one JSON object per line, each
+//! carrying the service's name, version and environment, plus the
+//! correlation id of the request or connection it happened in, so one
+//! user action can be followed from auth-api through the gateway to
+//! channels-api in a log aggregator.
+//!
+//! Services call `init` first thing in `main`. `RUST_LOG` picks the
+//! level (`info` by default) and `UCHAT_ENV` names the environment
+//! (`development` by default). With `OTEL_EXPORTER_OTLP_ENDPOINT` set,
+//! spans are also exported as traces; see `trace`. Panics are logged too,
+//! with their backtrace, in the span they happened in.
+//!
+//! With the `console` feature, `init` also serves tokio-console. To watch
+//! the gateway's tasks locally:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg tokio_unstable" cargo run -p gateway-service --features console
+//! tokio-console http://127.0.0.1:6669
+//! ```
+
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::fmt;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Span, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields, JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer};
+
+pub mod trace;
+
+pub use trace::{current_traceparent, TRACEPARENT_HEADER};
+
+/// Header a caller may set to name its request. Honoured when there is
+/// no `x-correlation-id`.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Header carrying the id of the user action a request belongs to.
+/// Services pass it on to every request they make on the action's
+/// behalf, and echo it in responses.
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+/// Longest correlation id taken from a caller; longer ones are replaced.
+const MAX_CORRELATION_ID_LEN: usize = 128;
+
+/// Installs the JSON subscriber on stdout. Later calls, as from tests,
+/// leave the first one in place.
+pub fn init(service: &'static str, version: &'static str) {
+    let env = std::env::var("UCHAT_ENV").ok().filter(|v| !v.is_empty()).unwrap_or_else(|| "development".into());
+    let logs = json_layer(service, version, &env, std::io::stdout);
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|v| !v.is_empty());
+    let resource = vec![
+        KeyValue::new("service.name", service),
+        KeyValue::new("service.version", version),
+        KeyValue::new("deployment.environment", env),
+    ];
+    let provider = trace::provider(endpoint.as_deref(), resource);
+    let traces = tracing_opentelemetry::layer().with_tracer(provider.tracer(service)).with_filter(LevelFilter::INFO);
+    opentelemetry::global::set_tracer_provider(provider);
+    // The console sees every task event; `RUST_LOG` only filters the logs.
+    #[cfg(feature = "console")]
+    let _ = tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(console_subscriber::spawn()).with(traces).with(logs),
+    );
+    #[cfg(not(feature = "console"))]
+    let _ = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(traces).with(logs));
+
+    // Instead of the default hook's plain text on stderr, which the log
+    // aggregator would split line by line.
+    std::panic::set_hook(Box::new(|info| {
+        let location = info.location().map(|l| l.to_string()).unwrap_or_default();
+        let backtrace = Backtrace::force_capture();
+        tracing::error!(panic = panic_message(info.payload()), location, backtrace = %backtrace, "panicked");
+    }));
+}
+
+/// The message a panic was raised with, as caught by `catch_unwind` or a
+/// failed task's `JoinError`.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&'static str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+#[cfg(test)]
+fn subscriber<W>(service: &str, version: &str, env: &str, writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::registry().with(json_layer(service, version, env, writer))
+}
+
+fn json_layer<S, W>(service: &str, version: &str, env: &str, writer: W) -> impl Layer<S> + Send + Sync
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = format::Format::default().json().flatten_event(true).with_current_span(false).with_span_list(true);
+    let prefix = format!(
+        "\"service\":{},\"version\":{},\"env\":{},",
+        serde_json::Value::from(service),
+        serde_json::Value::from(version),
+        serde_json::Value::from(env),
+    );
+
+    tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .fmt_fields(JsonFields::new())
+        .event_format(WithService { inner: json, prefix })
+        .with_filter(filter)
+}
+
+/// Adds the service fields to the front of each JSON line.
+struct WithService<F> {
+    inner: F,
+    /// Already-encoded `"key":value,` pairs.
+    prefix: String,
+}
+
+impl<S, N, F> FormatEvent<S, N> for WithService<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut line = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut line), event)?;
+        match line.strip_prefix('{') {
+            Some(rest) => write!(writer, "{{{}{}", self.prefix, rest),
+            None => writer.write_str(&line),
+        }
+    }
+}
+
+/// The caller's correlation id when it is usable, otherwise a new one.
+/// Usable ids are short and stick to letters, digits and `-_.:`, so they
+/// can't forge log fields or bloat every line.
+pub fn correlation_id(incoming: Option<&str>) -> String {
+    match incoming.map(str::trim) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_CORRELATION_ID_LEN
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)) =>
+        {
+            id.to_string()
+        }
+        _ => uuid::Uuid::new_v4().to_string(),
+    }
+}
+
+/// Span for handling one HTTP request; everything logged inside it
+/// carries the correlation id. With the caller's `traceparent`, it joins
+/// the caller's trace.
+pub fn request_span(correlation_id: &str, method: &str, path: &str, traceparent: Option<&str>) -> Span {
+    let span = tracing::info_span!("request", correlation_id, method, path);
+    if let Some(traceparent) = traceparent {
+        trace::join(&span, traceparent);
+    }
+    span
+}
+
+/// Correlation id of the request being handled, left in the request's
+/// extensions by `propagate` for handlers that call other services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorrelationId(pub String);
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// The correlation id of the request this task is handling, for clients
+/// that pass it on without each handler threading it through. Tasks
+/// spawned from a handler don't inherit it.
+pub fn current_correlation_id() -> Option<String> {
+    CURRENT.try_with(String::clone).ok()
+}
+
+/// Middleware running each request in a `request_span` for the caller's
+/// correlation id (or a new one) and trace, and echoing the id in the
+/// response.
+#[cfg(feature = "axum")]
+pub async fn propagate(mut req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    use tracing::Instrument;
+
+    let incoming = [CORRELATION_ID_HEADER, REQUEST_ID_HEADER]
+        .iter()
+        .find_map(|name| req.headers().get(*name)?.to_str().ok());
+    let id = correlation_id(incoming);
+    let traceparent = req.headers().get(TRACEPARENT_HEADER).and_then(|v| v.to_str().ok());
+    let span = request_span(&id, req.method().as_str(), req.uri().path(), traceparent);
+    req.extensions_mut().insert(CorrelationId(id.clone()));
+
+    let mut resp = CURRENT.scope(id.clone(), next.run(req).instrument(span)).await;
+    if let Ok(value) = axum::http::HeaderValue::from_str(&id) {
+        resp.headers_mut().insert(CORRELATION_ID_HEADER, value);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'w> MakeWriter<'w> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'w self) -> Buffer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn logs_json_lines_with_service_and_span_fields() {
+        let buffer = Buffer::default();
+        let subscriber = subscriber("gateway-service", "0.1.0", "staging", buffer.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _request = request_span("c-17", "GET", "/ws", None).entered();
+            tracing::info!(user_id = "u1", "connected");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["service"], "gateway-service");
+        assert_eq!(line["version"], "0.1.0");
+        assert_eq!(line["env"], "staging");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "connected");
+        assert_eq!(line["user_id"], "u1");
+        assert_eq!(line["spans"][0]["correlation_id"], "c-17");
+        assert_eq!(line["spans"][0]["path"], "/ws");
+    }
+
+    #[test]
+    fn keeps_well_formed_ids_and_replaces_the_rest() {
+        assert_eq!(correlation_id(Some("req-42:a_b.c")), "req-42:a_b.c");
+        assert_eq!(correlation_id(Some(" req-42 ")), "req-42");
+
+        for bad in [None, Some(""), Some("a\"b"), Some("line\nbreak")] {
+            let id = correlation_id(bad);
+            assert!(uuid::Uuid::parse_str(&id).is_ok(), "{:?} -> {}", bad, id);
+        }
+        let long = "a".repeat(MAX_CORRELATION_ID_LEN + 1);
+        assert_ne!(correlation_id(Some(&long)), long);
+    }
+
+    #[test]
+    fn reads_panic_messages() {
+        let formatted = std::panic::catch_unwind(|| panic!("bad frame {}", 7)).unwrap_err();
+        assert_eq!(panic_message(&*formatted), "bad frame 7");
+        let literal = std::panic::catch_unwind(|| panic!("bad frame")).unwrap_err();
+        assert_eq!(panic_message(&*literal), "bad frame");
+    }
+
+    #[cfg(feature = "axum")]
+    #[tokio::test]
+    async fn propagates_ids_through_axum() {
+        use axum::{body::Body, extract::Extension, http::Request, middleware, routing::get, Router};
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(id): Extension<CorrelationId>| async move {
+                    assert_eq!(current_correlation_id().as_ref(), Some(&id.0));
+                    id.0
+                }),
+            )
+            .layer(middleware::from_fn(propagate));
+
+        let req = Request::get("/").header(CORRELATION_ID_HEADER, "c-1").header(REQUEST_ID_HEADER, "r-1");
+        let resp = app.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(resp.headers()[CORRELATION_ID_HEADER], "c-1");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"c-1");
+
+        let req = Request::get("/").header(REQUEST_ID_HEADER, "r-1").body(Body::empty()).unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.headers()[CORRELATION_ID_HEADER], "r-1");
+
+        let resp = app.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        let generated = resp.headers()[CORRELATION_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+        assert!(current_correlation_id().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+//! Distributed traces: W3C trace context carried between services in the
+//! `traceparent` header, and spans exported over OTLP/HTTP (JSON) to the
+//! collector at `OTEL_EXPORTER_OTLP_ENDPOINT`, such as Jaeger's on port
+//! 4318.
+//!
+//! Spans at `info` or above become OpenTelemetry spans through
+//! `tracing-opentelemetry`, so each gets a trace and span id: from its
+//! parent span, from the caller's `traceparent`, or new. The SDK's batch
+//! processor exports them when they close, dropping what the collector
+//! can't keep up with rather than slow the service down.
+
+use std::collections::HashMap;
+
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Header carrying the caller's trace context.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// The `traceparent` to send with requests made from the current span, so
+/// the services they reach join its trace.
+pub fn current_traceparent() -> Option<String> {
+    let context = Span::current().context();
+    if !context.span().span_context().is_valid() {
+        return None;
+    }
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&context, &mut carrier);
+    carrier.remove(TRACEPARENT_HEADER)
+}
+
+/// Makes `span` a child of the caller's span named in `traceparent`.
+/// Malformed values are ignored and the trace starts at `span`.
+pub(crate) fn join(span: &Span, traceparent: &str) {
+    let carrier = HashMap::from([(TRACEPARENT_HEADER.to_string(), traceparent.to_string())]);
+    let parent = TraceContextPropagator::new().extract(&carrier);
+    if parent.span().span_context().is_remote() {
+        let _ = span.set_parent(parent);
+    }
+}
+
+/// Spans for a service with `resource` as its attributes, exported to
+/// `{endpoint}/v1/traces`, the path OTLP/HTTP collectors take traces on.
+/// Without an endpoint spans still get ids to pass on, and go nowhere.
+pub(crate) fn provider(endpoint: Option<&str>, resource: Vec<KeyValue>) -> SdkTracerProvider {
+    let builder = SdkTracerProvider::builder().with_resource(Resource::builder_empty().with_attributes(resource).build());
+    let Some(endpoint) = endpoint else { return builder.build() };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpJson)
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build();
+    match exporter {
+        Ok(exporter) => builder.with_batch_exporter(exporter).build(),
+        Err(e) => {
+            // Logging isn't set up yet.
+            eprintln!("not exporting spans to {}: {}", endpoint, e);
+            builder.build()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use opentelemetry::trace::TracerProvider as _;
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const INCOMING: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[tokio::test]
+    async fn exports_spans_joined_to_the_callers_trace() {
+        use axum::{routing::post, Json, Router};
+
+        let received = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+        let (got_tx, mut got_rx) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = Router::new().route(
+            "/v1/traces",
+            post({
+                let received = received.clone();
+                move |Json(body): Json<serde_json::Value>| async move {
+                    received.lock().unwrap().push(body);
+                    let _ = got_tx.send(());
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, collector).await.unwrap() });
+
+        let provider = provider(Some(&endpoint), vec![KeyValue::new("service.name", "gateway-service")]);
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("uchat-telemetry"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let (traceparent, ignored) = tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", path = "/ws");
+            join(&request, INCOMING);
+            let upgrade = tracing::info_span!(parent: &request, "ws.upgrade");
+            let traceparent = upgrade.in_scope(current_traceparent).unwrap();
+
+            let garbled = tracing::info_span!("garbled");
+            join(&garbled, "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7");
+            let ignored = garbled.in_scope(current_traceparent).unwrap();
+            (traceparent, ignored)
+        });
+        // The batch processor's thread posts to the collector this
+        // runtime serves.
+        tokio::task::spawn_blocking(move || provider.force_flush().unwrap()).await.unwrap();
+
+        let mut spans = Vec::new();
+        while spans.len() < 3 {
+            tokio::time::timeout(Duration::from_secs(5), got_rx.recv()).await.unwrap();
+            for body in received.lock().unwrap().drain(..) {
+                assert_eq!(body["resourceSpans"][0]["resource"]["attributes"][0]["key"], "service.name");
+                for scope in body["resourceSpans"][0]["scopeSpans"].as_array().unwrap() {
+                    spans.extend(scope["spans"].as_array().unwrap().clone());
+                }
+            }
+        }
+        let span = |name: &str| spans.iter().find(|s| s["name"] == name).unwrap().clone();
+        let (request, upgrade) = (span("request"), span("ws.upgrade"));
+
+        assert_eq!(request["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        let path = request["attributes"].as_array().unwrap().iter().find(|a| a["key"] == "path").unwrap();
+        assert_eq!(path["value"], serde_json::json!({ "stringValue": "/ws" }));
+        assert_eq!(upgrade["traceId"], request["traceId"]);
+        assert_eq!(upgrade["parentSpanId"], request["spanId"]);
+        assert_eq!(traceparent, format!("00-{}-{}-01", request["traceId"].as_str().unwrap(), upgrade["spanId"].as_str().unwrap()));
+        assert!(!ignored.contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+    }
+}
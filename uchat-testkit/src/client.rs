@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use uchat_proto::capabilities::COMPRESSION_ZSTD;
+use uchat_proto::compression;
+use uchat_proto::events::{ClientEvent, ClientFrame, ServerEvent, CURRENT_SCHEMA_VERSION};
+use uchat_proto::format::{from_msgpack, to_msgpack, SerializationFormat};
+use uchat_proto::ids::ChannelId;
+
+/// How long `recv` waits before failing the test.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A gateway WebSocket that speaks in `ClientEvent`s and `ServerEvent`s.
+/// The `recv` family panics on timeouts and closed sockets, so tests
+/// read like the flow they drive.
+pub struct TestWsClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    next_cid: u64,
+    /// What frames are sent in; a `Hello` naming a format switches it.
+    format: SerializationFormat,
+    /// Whether frames are flagged and compressed, as they are once a
+    /// `Hello` offering compression is answered.
+    compressed: bool,
+    /// Events read while waiting for a different one, oldest first.
+    skipped: VecDeque<ServerEvent>,
+}
+
+impl TestWsClient {
+    /// Connects with `token` in an `Authorization: Bearer` header. A
+    /// refused token is an `Err` carrying the HTTP response.
+    pub async fn connect(url: &str, token: &str) -> Result<Self, tungstenite::Error> {
+        let mut request = url.into_client_request()?;
+        let bearer = format!("Bearer {}", token).parse().expect("token is a valid header value");
+        request.headers_mut().insert("Authorization", bearer);
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(Self { socket, next_cid: 1, format: SerializationFormat::Json, compressed: false, skipped: VecDeque::new() })
+    }
+
+    /// Sends `event` under a fresh `cid`, which it returns.
+    pub async fn send(&mut self, event: ClientEvent) -> String {
+        let (switch_to, compress) = match &event {
+            ClientEvent::Hello { format, capabilities, .. } => {
+                (*format, capabilities.as_ref().is_some_and(|caps| caps.contains(COMPRESSION_ZSTD)))
+            }
+            _ => (None, false),
+        };
+        let cid = format!("c-{}", self.next_cid);
+        self.next_cid += 1;
+        let frame = ClientFrame {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            cid: Some(cid.clone()),
+            ts_gateway: None,
+            correlation_id: None,
+            event,
+        };
+        let msg = match (self.format, self.compressed) {
+            (SerializationFormat::Json, false) => Message::Text(serde_json::to_string(&frame).unwrap()),
+            (SerializationFormat::Msgpack, false) => Message::Binary(to_msgpack(&frame).unwrap()),
+            (SerializationFormat::Json, true) => Message::Binary(compression::encode(&serde_json::to_vec(&frame).unwrap())),
+            (SerializationFormat::Msgpack, true) => Message::Binary(compression::encode(&to_msgpack(&frame).unwrap())),
+        };
+        self.socket.send(msg).await.expect("send frame");
+        self.format = switch_to.unwrap_or(self.format);
+        self.compressed |= compress;
+        cid
+    }
+
+    /// Sends `event` and waits for the `Ack` or `Nack` naming it. The
+    /// gateway doesn't ack messages it accepts, so for a `SendMessage` to
+    /// a room this socket has joined, the message's own broadcast coming
+    /// back counts too; that needs content sanitizing leaves alone.
+    pub async fn send_and_ack(&mut self, event: ClientEvent) -> ServerEvent {
+        let echo = match &event {
+            ClientEvent::SendMessage { room_id, content, .. } => Some((room_id.clone(), content.clone())),
+            _ => None,
+        };
+        let cid = self.send(event).await;
+        self.recv_until(|event| match event {
+            ServerEvent::Ack { client_id, .. } | ServerEvent::Nack { client_id, .. } => {
+                client_id.as_deref() == Some(cid.as_str())
+            }
+            ServerEvent::MessageBroadcast { room_id, content, .. } => {
+                echo.as_ref().is_some_and(|(r, c)| room_id.channel == *r && content == c)
+            }
+            _ => false,
+        })
+        .await
+    }
+
+    /// Subscribes to `room_id`, returning once the gateway announces the
+    /// join to the room, which only happens after the subscription is in
+    /// place.
+    pub async fn join(&mut self, room_id: &ChannelId) {
+        self.send(ClientEvent::Subscribe { room_id: room_id.clone().into() }).await;
+        self.recv_until(|event| matches!(event, ServerEvent::Presence { room_id: r, online: true, .. } if r == room_id))
+            .await;
+    }
+
+    /// The next event, skipped ones first.
+    pub async fn recv(&mut self) -> ServerEvent {
+        if let Some(event) = self.skipped.pop_front() {
+            return event;
+        }
+        self.read().await
+    }
+
+    /// The first event `wanted` accepts. Events before it are kept for
+    /// later `recv` calls.
+    pub async fn recv_until(&mut self, mut wanted: impl FnMut(&ServerEvent) -> bool) -> ServerEvent {
+        if let Some(index) = self.skipped.iter().position(&mut wanted) {
+            return self.skipped.remove(index).unwrap();
+        }
+        loop {
+            let event = self.read().await;
+            if wanted(&event) {
+                return event;
+            }
+            self.skipped.push_back(event);
+        }
+    }
+
+    /// Closes the socket and waits for the gateway to finish the close.
+    pub async fn close(mut self) {
+        let _ = self.socket.close(None).await;
+        while let Ok(Some(Ok(_))) = tokio::time::timeout(RECV_TIMEOUT, self.socket.next()).await {}
+    }
+
+    async fn read(&mut self) -> ServerEvent {
+        loop {
+            let msg = tokio::time::timeout(RECV_TIMEOUT, self.socket.next())
+                .await
+                .expect("timed out waiting for the gateway")
+                .expect("gateway closed the socket")
+                .expect("read from gateway");
+            match msg {
+                Message::Text(text) => {
+                    assert_eq!(self.format, SerializationFormat::Json, "text frame on a MessagePack socket");
+                    assert!(!self.compressed, "text frame on a compressed socket");
+                    return serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", e, text));
+                }
+                Message::Binary(bytes) if self.compressed => {
+                    let payload = compression::decode(&bytes).expect("binary frames are flagged");
+                    return match self.format {
+                        SerializationFormat::Json => serde_json::from_slice(&payload).expect("frames are JSON"),
+                        SerializationFormat::Msgpack => from_msgpack(&payload).expect("frames are MessagePack"),
+                    };
+                }
+                Message::Binary(bytes) => {
+                    assert_eq!(self.format, SerializationFormat::Msgpack, "binary frame on a JSON socket");
+                    return from_msgpack(&bytes).expect("binary frames are MessagePack");
+                }
+                Message::Close(frame) => panic!("gateway closed the socket: {:?}", frame),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Runs the gateway and auth-api in-process on random local ports, for
+//! integration tests that drive whole flows over real sockets: log in,
+//! connect, join, send, receive.
+//!
+//! The gateway runs with nothing outside the process. auth-api still
+//! needs Postgres, so `spawn_auth` returns `None` (and the test skips)
+//! when `TEST_DATABASE_URL` is unset, like the services' own DB tests.
+
+mod client;
+
+use std::net::SocketAddr;
+
+use tokio::task::JoinHandle;
+
+use uchat_proto::events::ServerEvent;
+use uchat_proto::ids::UserId;
+use uchat_proto::jwt::create_token_with_rooms;
+use uchat_proto::permissions::RoomPermissions;
+
+pub use client::TestWsClient;
+
+pub struct GatewayConfig {
+    pub jwt_secret: String,
+    /// Shared secret for `/internal/*` and `/admin/*`; refused when unset.
+    pub internal_token: Option<String>,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self { jwt_secret: "test-secret".into(), internal_token: Some("internal-secret".into()) }
+    }
+}
+
+/// A gateway serving on a random port, stopped when dropped.
+pub struct TestGateway {
+    addr: SocketAddr,
+    jwt_secret: String,
+    server: JoinHandle<()>,
+}
+
+/// Starts a gateway with in-memory state: no event hub, channels-api or
+/// Redis.
+pub async fn spawn_gateway(config: GatewayConfig) -> TestGateway {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind gateway");
+    let addr = listener.local_addr().unwrap();
+    let state = gateway_service::AppState::local(&config.jwt_secret, config.internal_token.as_deref());
+    let server = tokio::spawn(gateway_service::serve(listener, state));
+    TestGateway { addr, jwt_secret: config.jwt_secret, server }
+}
+
+impl TestGateway {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}/ws", self.addr)
+    }
+
+    /// A token for `user_id` with access to `rooms`, as auth-api would
+    /// issue it.
+    pub fn token(&self, user_id: &UserId, rooms: RoomPermissions) -> String {
+        create_token_with_rooms(&self.jwt_secret, user_id.as_str(), rooms)
+    }
+
+    /// Connects a socket for `user_id` with access to `rooms`.
+    pub async fn connect(&self, user_id: &UserId, rooms: RoomPermissions) -> TestWsClient {
+        TestWsClient::connect(&self.ws_url(), &self.token(user_id, rooms)).await.expect("connect to gateway")
+    }
+}
+
+impl Drop for TestGateway {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+pub struct AuthConfig {
+    /// Must match the gateway's for its tokens to be accepted there.
+    pub jwt_secret: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self { jwt_secret: "test-secret".into() }
+    }
+}
+
+/// An auth-api serving on a random port against `TEST_DATABASE_URL`,
+/// stopped when dropped.
+pub struct TestAuth {
+    addr: SocketAddr,
+    client: reqwest::Client,
+    server: JoinHandle<()>,
+}
+
+/// Starts auth-api against `TEST_DATABASE_URL`, or returns `None` when it
+/// is unset.
+pub async fn spawn_auth(config: AuthConfig) -> Option<TestAuth> {
+    let db = uchat_db::connect_test().await?;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind auth-api");
+    let addr = listener.local_addr().unwrap();
+    let state = auth_api::AppState::new(db, config.jwt_secret);
+    let server = tokio::spawn(async move {
+        if let Err(e) = auth_api::serve(listener, state).await {
+            panic!("auth-api stopped: {}", e);
+        }
+    });
+    Some(TestAuth { addr, client: reqwest::Client::new(), server })
+}
+
+impl TestAuth {
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Logs in as `username`, registering them on first use. `Err` holds
+    /// the event auth-api refused with.
+    pub async fn login(&self, username: &str) -> Result<String, ServerEvent> {
+        let body = serde_json::json!({ "username": username, "password": "x" });
+        let resp = self
+            .client
+            .post(format!("{}/login", self.url()))
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .expect("reach auth-api");
+        let bytes = resp.bytes().await.expect("read login response");
+        match serde_json::from_slice(&bytes).expect("login response is a ServerEvent") {
+            ServerEvent::LoginOk { token, .. } => Ok(token),
+            other => Err(other),
+        }
+    }
+}
+
+impl Drop for TestAuth {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
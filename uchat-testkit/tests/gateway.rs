@@ -0,0 +1,262 @@
+//! WebSocket auth and room fan-out, against a gateway running in-process.
+
+use std::collections::HashSet;
+
+use tokio_tungstenite::tungstenite;
+
+use uchat_proto::capabilities::COMPRESSION_ZSTD;
+use uchat_proto::commands::{CommandArg, CommandTarget, SlashCommand};
+use uchat_proto::errors::ErrorCode;
+use uchat_proto::events::{BridgedFrom, ClientEvent, ServerEvent, MAX_RELAY_HOPS};
+use uchat_proto::format::SerializationFormat;
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::jwt::{create_bridge_token, create_token};
+use uchat_proto::permissions::{RoomPermissions, RoomRole};
+use uchat_testkit::{spawn_gateway, GatewayConfig, TestWsClient};
+
+fn rooms(grants: &[(&ChannelId, RoomRole)]) -> RoomPermissions {
+    let mut rooms = RoomPermissions::new();
+    for (room, role) in grants {
+        rooms.grant(room.as_str(), *role);
+    }
+    rooms
+}
+
+fn text(room_id: &ChannelId, content: &str) -> ClientEvent {
+    ClientEvent::SendMessage {
+        room_id: room_id.clone(),
+        content: content.into(),
+        encrypted: false,
+        content_type: "text/plain".into(),
+        thread_id: None,
+    }
+}
+
+fn status(err: tungstenite::Error) -> u16 {
+    match err {
+        tungstenite::Error::Http(resp) => resp.status().as_u16(),
+        other => panic!("expected an HTTP refusal, got {}", other),
+    }
+}
+
+#[tokio::test]
+async fn refuses_missing_and_forged_tokens() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let user = UserId::new();
+
+    let err = TestWsClient::connect(&gateway.ws_url(), "").await.err().unwrap();
+    assert_eq!(status(err), 401);
+    let forged = create_token("not-the-secret", user.as_str());
+    let err = TestWsClient::connect(&gateway.ws_url(), &forged).await.err().unwrap();
+    assert_eq!(status(err), 401);
+    let not_a_user = create_token("test-secret", "alice");
+    let err = TestWsClient::connect(&gateway.ws_url(), &not_a_user).await.err().unwrap();
+    assert_eq!(status(err), 401);
+
+    let room = ChannelId::new();
+    let mut client = gateway.connect(&user, rooms(&[(&room, RoomRole::Write)])).await;
+    client.join(&room).await;
+    client.close().await;
+}
+
+#[tokio::test]
+async fn fans_room_messages_out_to_members_only() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let (room, elsewhere) = (ChannelId::new(), ChannelId::new());
+    let mut alice = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Write)])).await;
+    let mut bob = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Read)])).await;
+    let mut carol = gateway.connect(&UserId::new(), rooms(&[(&elsewhere, RoomRole::Write)])).await;
+    alice.join(&room).await;
+    bob.join(&room).await;
+    carol.join(&elsewhere).await;
+
+    carol.send(ClientEvent::Subscribe { room_id: room.clone().into() }).await;
+    assert!(matches!(carol.recv().await, ServerEvent::Error { code: ErrorCode::Forbidden, .. }));
+
+    let sent = alice.send_and_ack(text(&room, "hello")).await;
+    let ServerEvent::MessageBroadcast { seq: Some(seq), .. } = sent else { panic!("expected the echo, got {:?}", sent) };
+    let event = bob.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { .. })).await;
+    let ServerEvent::MessageBroadcast { room_id, content, seq: got, .. } = event else { unreachable!() };
+    assert_eq!((room_id.channel, content.as_str(), got), (room.clone(), "hello", Some(seq)));
+
+    // Readers can't post.
+    bob.send(text(&room, "me too")).await;
+    assert!(matches!(bob.recv().await, ServerEvent::Error { code: ErrorCode::Forbidden, .. }));
+
+    // The first broadcast carol sees is from her own room.
+    carol.send(text(&elsewhere, "over here")).await;
+    let event = carol.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { .. })).await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == "over here"));
+}
+
+#[tokio::test]
+async fn msgpack_and_json_sockets_share_a_room() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let room = ChannelId::new();
+    let mut packed = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Write)])).await;
+    let mut plain = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Write)])).await;
+
+    let hello = ClientEvent::Hello { last_seq: None, capabilities: None, format: Some(SerializationFormat::Msgpack) };
+    packed.send(hello).await;
+    let reply = packed.recv().await;
+    assert!(matches!(reply, ServerEvent::Capabilities { format: SerializationFormat::Msgpack, .. }), "{:?}", reply);
+    packed.join(&room).await;
+    plain.join(&room).await;
+
+    packed.send_and_ack(text(&room, "packed")).await;
+    let event = plain.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { .. })).await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == "packed"));
+
+    plain.send_and_ack(text(&room, "plain")).await;
+    let event = packed.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { content, .. } if content != "packed")).await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == "plain"));
+}
+
+#[tokio::test]
+async fn compressed_and_plain_sockets_share_a_room() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let room = ChannelId::new();
+    let mut zipped = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Write)])).await;
+    let mut plain = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Write)])).await;
+
+    let offered = HashSet::from([COMPRESSION_ZSTD.to_string()]);
+    zipped.send(ClientEvent::Hello { last_seq: None, capabilities: Some(offered), format: None }).await;
+    let reply = zipped.recv().await;
+    assert!(
+        matches!(&reply, ServerEvent::Capabilities { capabilities, .. } if capabilities.contains(COMPRESSION_ZSTD)),
+        "{:?}",
+        reply
+    );
+    zipped.join(&room).await;
+    plain.join(&room).await;
+
+    let paste = "let total: u64 = items.iter().map(|item| item.price).sum();\n".repeat(30);
+    zipped.send_and_ack(text(&room, &paste)).await;
+    let event = plain.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { .. })).await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == paste));
+
+    plain.send_and_ack(text(&room, "short")).await;
+    let event = zipped.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { content, .. } if *content != paste)).await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == "short"));
+}
+
+#[tokio::test]
+async fn bridges_relay_between_rooms_until_the_hop_limit() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let (bridged, general, empty) = (ChannelId::new(), ChannelId::new(), ChannelId::new());
+    let grants = rooms(&[(&bridged, RoomRole::Write), (&general, RoomRole::Write), (&empty, RoomRole::Write)]);
+    let bot = UserId::new();
+    let token = create_bridge_token("test-secret", bot.as_str(), grants.clone());
+    let mut bridge = TestWsClient::connect(&gateway.ws_url(), &token).await.unwrap();
+    let mut alice = gateway.connect(&UserId::new(), grants).await;
+    alice.join(&bridged).await;
+    alice.join(&general).await;
+
+    let relay = |source: &ChannelId, target: &ChannelId, seq| ClientEvent::RelayMessage {
+        source_room: source.clone().into(),
+        target_room: target.clone().into(),
+        payload: "from elsewhere".into(),
+        seq: Some(seq),
+        message_id: None,
+    };
+    let refusal = |code: ErrorCode| move |e: &ServerEvent| matches!(e, ServerEvent::Error { code: c, .. } if *c == code);
+    let arrival = |room: &ChannelId| {
+        let room = room.clone();
+        move |e: &ServerEvent| matches!(e, ServerEvent::MessageBroadcast { room_id, .. } if room_id.channel == room)
+    };
+
+    bridge.send(relay(&bridged, &general, 1)).await;
+    bridge.recv_until(refusal(ErrorCode::NotSubscribed)).await;
+    bridge.join(&bridged).await;
+    bridge.join(&general).await;
+    bridge.send(relay(&bridged, &empty, 1)).await;
+    bridge.recv_until(refusal(ErrorCode::NotFound)).await;
+    // Only messages the gateway has seen can be relayed.
+    bridge.send(relay(&bridged, &general, 999)).await;
+    bridge.recv_until(refusal(ErrorCode::NotFound)).await;
+
+    alice.send(text(&bridged, "hello")).await;
+    let ServerEvent::MessageBroadcast { seq, .. } = alice.recv_until(arrival(&bridged)).await else { unreachable!() };
+    let mut seq = seq.unwrap();
+
+    // Bounce it between the two rooms; each relay adds a hop.
+    let (mut source, mut target) = (&bridged, &general);
+    for hop_count in 1..=MAX_RELAY_HOPS {
+        bridge.send(relay(source, target, seq)).await;
+        let event = alice.recv_until(arrival(target)).await;
+        let ServerEvent::MessageBroadcast { from, content, bridged_from, seq: relayed, .. } = event else { unreachable!() };
+        assert_eq!((&from, content.as_str()), (&bot, "from elsewhere"));
+        assert_eq!(bridged_from, Some(BridgedFrom { room_id: source.clone().into(), hop_count }));
+        seq = relayed.unwrap();
+        (source, target) = (target, source);
+    }
+    bridge.send(relay(source, target, seq)).await;
+    bridge.recv_until(refusal(ErrorCode::HopLimitReached)).await;
+
+    // The same grants don't make a user a bridge.
+    alice.send(relay(&bridged, &general, seq)).await;
+    alice.recv_until(refusal(ErrorCode::Forbidden)).await;
+}
+
+#[tokio::test]
+async fn slash_commands_go_to_their_bot_instead_of_the_room() {
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let room = ChannelId::new();
+    let (alice_id, bot_id) = (UserId::new(), UserId::new());
+    let mut alice = gateway.connect(&alice_id, rooms(&[(&room, RoomRole::Write)])).await;
+    let mut bob = gateway.connect(&UserId::new(), rooms(&[(&room, RoomRole::Write)])).await;
+    let mut bot = gateway.connect(&bot_id, rooms(&[(&room, RoomRole::Write)])).await;
+    alice.join(&room).await;
+    bob.join(&room).await;
+    bot.join(&room).await;
+
+    let command = SlashCommand {
+        name: "remind".into(),
+        description: "Set a reminder".into(),
+        args: vec![CommandArg { name: "who".into(), required: true }, CommandArg { name: "when".into(), required: true }],
+        target: CommandTarget::Bot { user_id: bot_id.clone() },
+    };
+    let register = || async {
+        reqwest::Client::new()
+            .post(format!("http://{}/internal/commands", gateway.addr()))
+            .header("x-internal-token", "internal-secret")
+            .json(&command)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    };
+    assert_eq!(register().await, 201);
+    assert_eq!(register().await, 409);
+
+    alice.send(text(&room, "/nope")).await;
+    let error = alice.recv_until(|e| matches!(e, ServerEvent::Error { .. })).await;
+    assert!(matches!(error, ServerEvent::Error { code: ErrorCode::UnknownCommand, .. }));
+    alice.send(text(&room, "/remind me")).await;
+    let error = alice.recv_until(|e| matches!(e, ServerEvent::Error { .. })).await;
+    assert!(matches!(error, ServerEvent::Error { code: ErrorCode::InvalidRequest, .. }));
+
+    alice.send(text(&room, "/remind me in 10m")).await;
+    let event = bot.recv_until(|e| matches!(e, ServerEvent::CommandInvoked { .. })).await;
+    let ServerEvent::CommandInvoked { invocation } = event else { unreachable!() };
+    assert_eq!((invocation.user_id.clone(), invocation.room_id.channel.clone()), (alice_id, room.clone()));
+    assert_eq!((invocation.args["who"].as_str(), invocation.args["when"].as_str()), ("me", "in 10m"));
+
+    bot.send(ClientEvent::CommandResponse { invocation_id: invocation.id.clone(), text: "Will do.".into(), ephemeral: true }).await;
+    let event = alice.recv_until(|e| matches!(e, ServerEvent::CommandResult { .. })).await;
+    assert!(matches!(event, ServerEvent::CommandResult { command, text, .. } if command == "remind" && text == "Will do."));
+    // Each invocation is answered once.
+    bot.send(ClientEvent::CommandResponse { invocation_id: invocation.id, text: "again".into(), ephemeral: false }).await;
+    let error = bot.recv_until(|e| matches!(e, ServerEvent::Error { .. })).await;
+    assert!(matches!(error, ServerEvent::Error { code: ErrorCode::NotFound, .. }));
+
+    // Nothing above reached the room; bob's next message is alice's.
+    alice.send_and_ack(text(&room, "//not a command")).await;
+    let event = bob
+        .recv_until(|e| {
+            assert!(!matches!(e, ServerEvent::CommandResult { .. }), "bob saw alice's result");
+            matches!(e, ServerEvent::MessageBroadcast { .. })
+        })
+        .await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == "//not a command"));
+}
@@ -0,0 +1,46 @@
+//! Log in through auth-api, then chat through the gateway with the token
+//! it issued. Skips when `TEST_DATABASE_URL` is unset.
+
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::ids::{ChannelId, UserId};
+use uchat_proto::jwt::verify_claims;
+use uchat_testkit::{spawn_auth, spawn_gateway, AuthConfig, GatewayConfig, TestAuth, TestWsClient};
+
+/// Registers a fresh user as a writer in `channel`, and logs them in
+/// again so their token carries the membership.
+async fn member(auth: &TestAuth, db: &sqlx::PgPool, channel: &ChannelId) -> String {
+    let name = format!("user-{}", UserId::new());
+    let token = auth.login(&name).await.unwrap();
+    let user_id = verify_claims("test-secret", &token).unwrap().sub;
+    sqlx::query("INSERT INTO channel_members (channel_id, user_id, role) VALUES ($1, $2, 'write')")
+        .bind(channel.as_str())
+        .bind(&user_id)
+        .execute(db)
+        .await
+        .unwrap();
+    auth.login(&name).await.unwrap()
+}
+
+#[tokio::test]
+async fn logged_in_members_chat_through_the_gateway() {
+    let Some(auth) = spawn_auth(AuthConfig::default()).await else { return };
+    let db = uchat_db::connect_test().await.unwrap();
+    let gateway = spawn_gateway(GatewayConfig::default()).await;
+    let channel = ChannelId::new();
+
+    let mut alice = TestWsClient::connect(&gateway.ws_url(), &member(&auth, &db, &channel).await).await.unwrap();
+    let mut bob = TestWsClient::connect(&gateway.ws_url(), &member(&auth, &db, &channel).await).await.unwrap();
+    alice.join(&channel).await;
+    bob.join(&channel).await;
+
+    let hello = ClientEvent::SendMessage {
+        room_id: channel.clone(),
+        content: "hi bob".into(),
+        encrypted: false,
+        content_type: "text/plain".into(),
+        thread_id: None,
+    };
+    alice.send_and_ack(hello).await;
+    let event = bob.recv_until(|e| matches!(e, ServerEvent::MessageBroadcast { .. })).await;
+    assert!(matches!(event, ServerEvent::MessageBroadcast { content, .. } if content == "hi bob"));
+}